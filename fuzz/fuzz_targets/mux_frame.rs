@@ -0,0 +1,16 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use wstunnel::tunnel::protocol::mux_frame::{MuxFrame, parse_open_payload};
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(frame)) = MuxFrame::decode(&mut buf) {
+        // What is decoded must be encoded back to the same bytes
+        let mut encoded = BytesMut::new();
+        frame.encode(&mut encoded);
+        assert_eq!(MuxFrame::decode(&mut encoded), Ok(Some(frame.clone())));
+        let _ = parse_open_payload(&frame.payload);
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use wstunnel::tunnel::protocol::probe_record::ProbeRecord;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(record)) = ProbeRecord::decode(&mut buf) {
+        let mut encoded = BytesMut::new();
+        record.encode(&mut encoded);
+        assert_eq!(ProbeRecord::decode(&mut encoded), Ok(Some(record)));
+    }
+});
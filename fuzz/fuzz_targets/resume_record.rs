@@ -0,0 +1,15 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use wstunnel::tunnel::protocol::resume_record::ResumeRecord;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(record)) = ResumeRecord::decode(&mut buf) {
+        // What is decoded must be encoded back to the same record, `fin received` being any non zero byte
+        let mut encoded = BytesMut::new();
+        record.encode(&mut encoded);
+        assert_eq!(ResumeRecord::decode(&mut encoded), Ok(Some(record)));
+    }
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wstunnel::tunnel::RemoteAddr;
+use wstunnel::tunnel::protocol::negotiate_version;
+use wstunnel::tunnel::transport::jwt_token_to_tunnel;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(token) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(jwt) = jwt_token_to_tunnel(token) else {
+        return;
+    };
+    let _ = negotiate_version(jwt.claims.v.as_deref());
+    let _ = RemoteAddr::try_from(jwt.claims);
+});
@@ -0,0 +1,530 @@
+//! `wstunnel bench`, measure the tunnels of a client and a server run in this process on localhost
+use anyhow::{Context, anyhow};
+use clap::{Args, FromArgMatches};
+use std::fmt::Write as _;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use wstunnel::config::parsers::{parse_byte_size, parse_duration_sec};
+use wstunnel::config::{Client, Server};
+use wstunnel::executor::JoinSetTokioExecutor;
+use wstunnel::{run_client, run_server};
+
+/// How long the server and the tunnels of the client are waited for before giving up
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+const READY_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+/// How long the echo of a tunnel being set up is waited for
+const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Datagrams of the udp throughput, kept under the usual MTU
+const UDP_DATAGRAM_SIZE: usize = 1400;
+/// Datagrams sent without waiting for their echo, past it the sender waits for the tunnel to catch up
+const UDP_WINDOW: usize = 256;
+/// Datagrams in flight for longer are counted as lost
+const UDP_LOSS_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Measure the tunnels of a client and a server run in this process on localhost: how many tunnels are set up per second,
+/// the throughput of the tcp, udp and socks5 tunnels, and the cpu used, to compare the settings of the buffers
+/// and of the multiplexing. The cpu is the one of the whole process, client, server and load together
+#[derive(clap::Args, Debug)]
+pub struct BenchCommand {
+    /// Transport between the client and the server
+    #[arg(
+        long,
+        value_name = "ws|wss|http|https",
+        default_value = "wss",
+        value_parser = ["ws", "wss", "http", "https"],
+        verbatim_doc_comment
+    )]
+    transport: String,
+
+    /// Protocols of the tunnels to measure, separated by commas
+    #[arg(
+        long,
+        value_name = "tcp,udp,socks5",
+        value_delimiter = ',',
+        default_value = "tcp,udp,socks5",
+        verbatim_doc_comment
+    )]
+    protocols: Vec<BenchProtocol>,
+
+    /// Tunnels set up one after the other, to measure the setup rate
+    #[arg(long, value_name = "INT", default_value = "200", verbatim_doc_comment)]
+    tunnels: usize,
+
+    /// How long the throughput of each protocol is measured
+    #[arg(long, value_name = "DURATION", default_value = "5s", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    duration: Duration,
+
+    /// Size of the writes into the tcp and socks5 tunnels
+    #[arg(long, value_name = "SIZE", default_value = "64k", value_parser = parse_byte_size, verbatim_doc_comment)]
+    write_size: u64,
+
+    /// Options given to the server, i.e: --server-options="--websocket-ping-frequency-sec 0"
+    #[arg(long, value_name = "SERVER_OPTIONS", default_value = "", verbatim_doc_comment)]
+    server_options: String,
+
+    /// Options given to the client, i.e: --client-options="--connection-min-idle 10"
+    #[arg(long, value_name = "CLIENT_OPTIONS", default_value = "", verbatim_doc_comment)]
+    client_options: String,
+
+    /// Print the results in json, i.e: to keep them and compare the next runs with them
+    #[arg(long, verbatim_doc_comment)]
+    json: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BenchProtocol {
+    Tcp,
+    Udp,
+    Socks5,
+}
+
+impl BenchProtocol {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+            Self::Socks5 => "socks5",
+        }
+    }
+}
+
+/// Measures of the tunnels of a protocol
+#[derive(Debug)]
+struct BenchResult {
+    protocol: BenchProtocol,
+    tunnels_per_sec: f64,
+    setup_p50: Duration,
+    setup_p99: Duration,
+    /// Bytes per second echoed through the tunnel, so that went through it in both directions
+    throughput: f64,
+    /// Datagrams not echoed back, for udp
+    loss: Option<f64>,
+    /// Cpu time of the process per second of throughput, 1.0 being a full cpu
+    cpu: Option<f64>,
+}
+
+pub async fn run(args: BenchCommand) -> anyhow::Result<()> {
+    let results = bench(&args).await?;
+    if args.json {
+        println!("{}", render_json(&args, &results));
+    } else {
+        print!("{}", render_table(&args, &results));
+    }
+    Ok(())
+}
+
+async fn bench(args: &BenchCommand) -> anyhow::Result<Vec<BenchResult>> {
+    let tcp_echo = TcpEcho::start().await?;
+    let udp_echo = UdpEcho::start().await?;
+    let executor = JoinSetTokioExecutor::default();
+
+    let server_url = format!("{}://{}", args.transport, local_addr()?);
+    let server = parse_args::<Server>(
+        "wstunnel server",
+        args.server_options.split_whitespace().chain([server_url.as_str()]),
+    )?;
+    let server_task = tokio::spawn(run_server(server, executor.clone()));
+    let server_addr = server_url.rsplit_once('/').map(|(_, addr)| addr).unwrap_or_default();
+    connect_when_ready(server_addr.parse()?, &server_task)
+        .await
+        .context("Cannot start the server")?;
+
+    let mut tunnels = Vec::new();
+    let mut client_args = args
+        .client_options
+        .split_whitespace()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    for protocol in &args.protocols {
+        let bind = local_addr()?;
+        let tunnel = match protocol {
+            BenchProtocol::Tcp => format!("tcp://{bind}:{}", tcp_echo.addr),
+            BenchProtocol::Udp => format!("udp://{bind}:{}?timeout_sec=0", udp_echo.addr),
+            BenchProtocol::Socks5 => format!("socks5://{bind}"),
+        };
+        client_args.extend(["-L".to_string(), tunnel]);
+        tunnels.push((*protocol, bind));
+    }
+    client_args.push(server_url.clone());
+    let client = parse_args::<Client>("wstunnel client", client_args.iter().map(String::as_str))?;
+    let client_task = tokio::spawn(run_client(client, executor.clone()));
+
+    let mut results = Vec::with_capacity(tunnels.len());
+    for (protocol, bind) in tunnels {
+        let ready = match protocol {
+            BenchProtocol::Tcp | BenchProtocol::Socks5 => connect_when_ready(bind, &client_task).await,
+            BenchProtocol::Udp => echo_when_ready(bind, &client_task).await,
+        };
+        ready.with_context(|| format!("Cannot start the {} tunnel", protocol.name()))?;
+        let result = bench_protocol(args, protocol, bind, &tcp_echo)
+            .await
+            .with_context(|| format!("Cannot measure the {} tunnels", protocol.name()))?;
+        results.push(result);
+    }
+
+    client_task.abort();
+    server_task.abort();
+    Ok(results)
+}
+
+async fn bench_protocol(
+    args: &BenchCommand,
+    protocol: BenchProtocol,
+    bind: SocketAddr,
+    tcp_echo: &TcpEcho,
+) -> anyhow::Result<BenchResult> {
+    // Setup of the tunnels, one after the other, each one done once a byte went through it
+    let mut setups = Vec::with_capacity(args.tunnels);
+    let started = Instant::now();
+    for _ in 0..args.tunnels {
+        let setup = Instant::now();
+        match protocol {
+            BenchProtocol::Tcp | BenchProtocol::Socks5 => {
+                let mut stream = open_stream(protocol, bind, tcp_echo.addr).await?;
+                stream.write_all(b"x").await?;
+                timeout(SETUP_TIMEOUT, stream.read_exact(&mut [0u8; 1]))
+                    .await
+                    .context("No echo through the tunnel")??;
+            }
+            BenchProtocol::Udp => {
+                let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+                socket.connect(bind).await?;
+                socket.send(b"x").await?;
+                timeout(SETUP_TIMEOUT, socket.recv(&mut [0u8; 1]))
+                    .await
+                    .context("No echo through the tunnel")??;
+            }
+        }
+        setups.push(setup.elapsed());
+    }
+    let tunnels_per_sec = args.tunnels as f64 / started.elapsed().as_secs_f64();
+    setups.sort_unstable();
+
+    let cpu_started = cpu_time();
+    let started = Instant::now();
+    let (bytes, loss) = match protocol {
+        BenchProtocol::Tcp | BenchProtocol::Socks5 => {
+            let stream = open_stream(protocol, bind, tcp_echo.addr).await?;
+            (tcp_throughput(stream, args.duration, args.write_size as usize).await?, None)
+        }
+        BenchProtocol::Udp => {
+            let (bytes, loss) = udp_throughput(bind, args.duration).await?;
+            (bytes, Some(loss))
+        }
+    };
+    let elapsed = started.elapsed().as_secs_f64();
+    let cpu = cpu_started
+        .zip(cpu_time())
+        .map(|(started, ended)| (ended - started).as_secs_f64() / elapsed);
+
+    Ok(BenchResult {
+        protocol,
+        tunnels_per_sec,
+        setup_p50: percentile(&setups, 50),
+        setup_p99: percentile(&setups, 99),
+        throughput: bytes as f64 / elapsed,
+        loss,
+        cpu,
+    })
+}
+
+/// Connection through the tunnel to the tcp echo server
+async fn open_stream(protocol: BenchProtocol, bind: SocketAddr, echo: SocketAddr) -> anyhow::Result<TcpStream> {
+    let mut stream = TcpStream::connect(bind).await?;
+    stream.set_nodelay(true)?;
+    if protocol == BenchProtocol::Socks5 {
+        socks5_connect(&mut stream, echo).await?;
+    }
+    Ok(stream)
+}
+
+/// Ask the socks5 tunnel for `dest`, without authentication
+async fn socks5_connect(stream: &mut TcpStream, dest: SocketAddr) -> anyhow::Result<()> {
+    let SocketAddr::V4(dest) = dest else {
+        return Err(anyhow!("Socks5 destination must be ipv4"));
+    };
+    stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [0x05, 0x00] {
+        return Err(anyhow!("Socks5 tunnel refused the method without authentication"));
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    request.extend_from_slice(&dest.ip().octets());
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 10];
+    timeout(SETUP_TIMEOUT, stream.read_exact(&mut reply))
+        .await
+        .context("No reply of the socks5 tunnel")??;
+    if reply[1] != 0x00 {
+        return Err(anyhow!("Socks5 tunnel refused the connection with the reply {}", reply[1]));
+    }
+    Ok(())
+}
+
+/// Bytes echoed back through the tunnel while writing into it for `duration`
+async fn tcp_throughput(stream: TcpStream, duration: Duration, write_size: usize) -> anyhow::Result<u64> {
+    let deadline = tokio::time::Instant::now() + duration;
+    let (mut rx, mut tx) = stream.into_split();
+    let writer = tokio::spawn(async move {
+        let buf = vec![0u8; write_size];
+        while let Ok(Ok(())) = tokio::time::timeout_at(deadline, tx.write_all(&buf)).await {}
+    });
+
+    let mut buf = vec![0u8; write_size];
+    let mut bytes = 0;
+    while let Ok(read) = tokio::time::timeout_at(deadline, rx.read(&mut buf)).await {
+        match read? {
+            0 => return Err(anyhow!("Tunnel closed while measuring its throughput")),
+            n => bytes += n as u64,
+        }
+    }
+    writer.abort();
+    Ok(bytes)
+}
+
+/// Bytes echoed back through the tunnel while sending datagrams into it for `duration`, and the share of datagrams lost
+async fn udp_throughput(bind: SocketAddr, duration: Duration) -> anyhow::Result<(u64, f64)> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    socket.connect(bind).await?;
+    let deadline = Instant::now() + duration;
+    let datagram = [0u8; UDP_DATAGRAM_SIZE];
+    let mut buf = [0u8; UDP_DATAGRAM_SIZE];
+    let (mut sent, mut received, mut in_flight, mut bytes) = (0u64, 0u64, 0, 0u64);
+    while Instant::now() < deadline {
+        while in_flight < UDP_WINDOW {
+            socket.send(&datagram).await?;
+            sent += 1;
+            in_flight += 1;
+        }
+        match timeout(UDP_LOSS_TIMEOUT, socket.recv(&mut buf)).await {
+            Ok(read) => {
+                bytes += read? as u64;
+                received += 1;
+                in_flight -= 1;
+            }
+            // Everything still in flight is lost
+            Err(_) => in_flight = 0,
+        }
+    }
+    let loss = 1.0 - received as f64 / sent.max(1) as f64;
+    Ok((bytes, loss.max(0.0)))
+}
+
+fn parse_args<'a, T: Args + FromArgMatches>(
+    name: &'static str,
+    args: impl Iterator<Item = &'a str>,
+) -> anyhow::Result<T> {
+    T::augment_args(clap::Command::new(name))
+        .try_get_matches_from(std::iter::once(name).chain(args))
+        .and_then(|matches| T::from_arg_matches(&matches))
+        .map_err(|err| anyhow!("Invalid options for {name}: {}", err.render()))
+}
+
+/// Address of localhost with a port nobody listens on
+fn local_addr() -> io::Result<SocketAddr> {
+    std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()
+}
+
+/// Wait until something listens on `addr`
+async fn connect_when_ready(addr: SocketAddr, task: &JoinHandle<anyhow::Result<()>>) -> anyhow::Result<()> {
+    let deadline = Instant::now() + READY_TIMEOUT;
+    loop {
+        match TcpStream::connect(addr).await {
+            Ok(_) => return Ok(()),
+            Err(_) if task.is_finished() => return Err(anyhow!("Stopped before listening on {addr}")),
+            Err(err) if Instant::now() >= deadline => return Err(err.into()),
+            Err(_) => tokio::time::sleep(READY_RETRY_INTERVAL).await,
+        }
+    }
+}
+
+/// Wait until a datagram sent to the udp tunnel on `addr` is echoed back
+async fn echo_when_ready(addr: SocketAddr, task: &JoinHandle<anyhow::Result<()>>) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    socket.connect(addr).await?;
+    let deadline = Instant::now() + READY_TIMEOUT;
+    loop {
+        // Nobody listening yet makes the next recv fail with connection refused
+        let _ = socket.send(b"x").await;
+        match timeout(UDP_LOSS_TIMEOUT, socket.recv(&mut [0u8; 1])).await {
+            Ok(Ok(_)) => return Ok(()),
+            _ if task.is_finished() => return Err(anyhow!("Stopped before listening on {addr}")),
+            _ if Instant::now() >= deadline => return Err(anyhow!("No echo through {addr} after {READY_TIMEOUT:?}")),
+            Ok(Err(_)) => tokio::time::sleep(READY_RETRY_INTERVAL).await,
+            Err(_) => {}
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], percentile: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(sorted.len() - 1) * percentile / 100]
+}
+
+/// Cpu time used by the process, user and system
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    use nix::sys::resource::{UsageWho, getrusage};
+
+    let usage = getrusage(UsageWho::RUSAGE_SELF).ok()?;
+    let to_duration = |time: nix::sys::time::TimeVal| {
+        Duration::from_secs(time.tv_sec() as u64) + Duration::from_micros(time.tv_usec() as u64)
+    };
+    Some(to_duration(usage.user_time()) + to_duration(usage.system_time()))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+fn render_table(args: &BenchCommand, results: &[BenchResult]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{} tunnels set up and {:?} of throughput per protocol, over {}",
+        args.tunnels, args.duration, args.transport
+    );
+    let _ = writeln!(
+        out,
+        "{:<8} {:>10} {:>10} {:>10} {:>12} {:>6} {:>6}",
+        "PROTOCOL", "TUNNELS/S", "SETUP P50", "SETUP P99", "THROUGHPUT", "LOSS", "CPU"
+    );
+    for result in results {
+        let _ = writeln!(
+            out,
+            "{:<8} {:>10.1} {:>10} {:>10} {:>12} {:>6} {:>6}",
+            result.protocol.name(),
+            result.tunnels_per_sec,
+            format!("{:.2?}", result.setup_p50),
+            format!("{:.2?}", result.setup_p99),
+            format!("{:.1} MiB/s", result.throughput / (1024.0 * 1024.0)),
+            result
+                .loss
+                .map_or("-".to_string(), |loss| format!("{:.1}%", loss * 100.0)),
+            result.cpu.map_or("-".to_string(), |cpu| format!("{:.0}%", cpu * 100.0)),
+        );
+    }
+    out
+}
+
+fn render_json(args: &BenchCommand, results: &[BenchResult]) -> serde_json::Value {
+    serde_json::json!({
+        "transport": args.transport,
+        "tunnels": args.tunnels,
+        "duration_sec": args.duration.as_secs_f64(),
+        "write_size": args.write_size,
+        "server_options": args.server_options,
+        "client_options": args.client_options,
+        "results": results.iter().map(|result| serde_json::json!({
+            "protocol": result.protocol.name(),
+            "tunnels_per_sec": result.tunnels_per_sec,
+            "setup_p50_sec": result.setup_p50.as_secs_f64(),
+            "setup_p99_sec": result.setup_p99.as_secs_f64(),
+            "throughput_bytes_per_sec": result.throughput,
+            "loss": result.loss,
+            "cpu": result.cpu,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+/// Tcp server sending back everything it receives, as the destination of the tunnels
+struct TcpEcho {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl TcpEcho {
+    async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let _ = stream.set_nodelay(true);
+                    let (mut rx, mut tx) = stream.into_split();
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+}
+
+impl Drop for TcpEcho {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Udp server sending back every datagram it receives
+struct UdpEcho {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl UdpEcho {
+    async fn start() -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = socket.local_addr()?;
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let _ = socket.send_to(&buf[..len], peer).await;
+            }
+        });
+        Ok(Self { addr, task })
+    }
+}
+
+impl Drop for UdpEcho {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let sorted = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&sorted, 50), Duration::from_millis(50));
+        assert_eq!(percentile(&sorted, 99), Duration::from_millis(99));
+        assert_eq!(percentile(&[], 99), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_bench() {
+        let args = BenchCommand {
+            transport: "ws".to_string(),
+            protocols: vec![BenchProtocol::Tcp, BenchProtocol::Udp, BenchProtocol::Socks5],
+            tunnels: 5,
+            duration: Duration::from_millis(200),
+            write_size: 16 * 1024,
+            server_options: String::new(),
+            client_options: "--connection-min-idle 1".to_string(),
+            json: false,
+        };
+        let results = bench(&args).await.unwrap();
+
+        assert_eq!(results.iter().map(|result| result.protocol).collect::<Vec<_>>(), args.protocols);
+        for result in &results {
+            assert!(result.tunnels_per_sec > 0.0, "{result:?}");
+            assert!(result.throughput > 0.0, "{result:?}");
+            assert!(result.setup_p50 <= result.setup_p99, "{result:?}");
+        }
+        assert!(render_table(&args, &results).contains("socks5"));
+    }
+}
@@ -0,0 +1,164 @@
+//! `--log-file`, write the logs to a file rotated over time or when it grows too big, instead of to stdout.
+//! So the logs can be kept without redirecting stdout, which the stdio tunnels use for their data
+use anyhow::Context;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use wstunnel::config::parsers::parse_byte_size;
+
+#[derive(Clone, Debug)]
+pub enum LogRotation {
+    /// Start a new file every minute, hour, day or week, suffixed with its date. i.e: wstunnel.log.2025-01-31
+    Time(Rotation),
+    /// Start a new file once the current one reaches this size in bytes. The previous ones are suffixed with their
+    /// rank, from the most recent. i.e: wstunnel.log.1
+    Size(u64),
+}
+
+pub fn parse_log_rotation(arg: &str) -> Result<LogRotation, io::Error> {
+    match arg {
+        "minutely" => Ok(LogRotation::Time(Rotation::MINUTELY)),
+        "hourly" => Ok(LogRotation::Time(Rotation::HOURLY)),
+        "daily" => Ok(LogRotation::Time(Rotation::DAILY)),
+        "weekly" => Ok(LogRotation::Time(Rotation::WEEKLY)),
+        "never" => Ok(LogRotation::Time(Rotation::NEVER)),
+        size => parse_byte_size(size).map(LogRotation::Size).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid log rotation {arg}, expected minutely, hourly, daily, weekly, never or a size i.e: 100m"
+                ),
+            )
+        }),
+    }
+}
+
+/// Writer of the logs to `path`, keeping at most `max_files` files, the current one included
+pub fn log_file_writer(path: &Path, rotation: LogRotation, max_files: usize) -> anyhow::Result<Box<dyn Write + Send>> {
+    let directory = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("invalid log file {}", path.display()))?;
+
+    match rotation {
+        LogRotation::Time(Rotation::NEVER) => Ok(Box::new(open_log_file(path)?)),
+        LogRotation::Time(rotation) => {
+            let appender = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(file_name)
+                .max_log_files(max_files)
+                .build(directory)
+                .with_context(|| format!("Cannot open log file {}", path.display()))?;
+            Ok(Box::new(appender))
+        }
+        LogRotation::Size(max_size) => Ok(Box::new(SizeRotatingFile::new(path.to_path_buf(), max_size, max_files)?)),
+    }
+}
+
+fn open_log_file(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Cannot open log file {}", path.display()))
+}
+
+struct SizeRotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl SizeRotatingFile {
+    fn new(path: PathBuf, max_size: u64, max_files: usize) -> anyhow::Result<Self> {
+        let file = open_log_file(&path)?;
+        let size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        Ok(Self {
+            path,
+            max_size,
+            max_files: max_files.max(1),
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, rank: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{rank}"));
+        PathBuf::from(path)
+    }
+
+    /// Shift the previous files by one rank, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let _ = std::fs::remove_file(self.rotated_path(self.max_files - 1));
+        for rank in (1..self.max_files - 1).rev() {
+            let _ = std::fs::rename(self.rotated_path(rank), self.rotated_path(rank + 1));
+        }
+        if self.max_files > 1 {
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A log line is never split between two files
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_on_size() {
+        let dir = std::env::temp_dir().join(format!("wstunnel-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wstunnel.log");
+
+        let mut writer = log_file_writer(&path, LogRotation::Size(10), 3).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            writer.write_all(line.as_bytes()).unwrap();
+        }
+        drop(writer);
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(std::fs::read_to_string(dir.join("wstunnel.log.1")).unwrap(), "third\n");
+        assert_eq!(std::fs::read_to_string(dir.join("wstunnel.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("wstunnel.log.3").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_log_rotation() {
+        assert!(matches!(parse_log_rotation("daily"), Ok(LogRotation::Time(Rotation::DAILY))));
+        assert!(matches!(parse_log_rotation("100m"), Ok(LogRotation::Size(104_857_600))));
+        assert!(parse_log_rotation("monthly").is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! `--log-output journald`, send the logs to the systemd journal with the fields of the tunnels kept apart from the
+//! message, so they can be queried, i.e: `journalctl -u wstunnel TUNNEL_ID=0192...` or `journalctl -u wstunnel -o json`
+use std::fmt;
+use std::fmt::Write as _;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Journal field of a field of the spans of the tunnels
+fn journal_field(span_field: &str) -> Option<&'static str> {
+    match span_field {
+        "id" => Some("TUNNEL_ID"),
+        "remote" => Some("DEST"),
+        "peer" => Some("PEER"),
+        "label" => Some("TUNNEL_LABEL"),
+        "forwarded_for" => Some("FORWARDED_FOR"),
+        _ => None,
+    }
+}
+
+pub struct JournaldLayer {
+    socket: UnixDatagram,
+}
+
+impl JournaldLayer {
+    pub fn new() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+        Ok(Self { socket })
+    }
+}
+
+/// Journal fields of a span, to add to the entries of its events
+struct SpanFields(Vec<(&'static str, String)>);
+
+impl Visit for SpanFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if let Some(name) = journal_field(field.name()) {
+            self.0.retain(|(field, _)| *field != name);
+            self.0.push((name, value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+/// Message of an event, with its other fields appended as key=value like in the other outputs
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{value:?}");
+        } else {
+            let _ = write!(self.0, " {}={value:?}", field.name());
+        }
+    }
+}
+
+impl<S> Layer<S> for JournaldLayer
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        // Only the spans of wstunnel, the ones of its dependencies may use the same field names for other things
+        if !span.metadata().target().starts_with("wstunnel") {
+            return;
+        }
+        let mut fields = SpanFields(Vec::new());
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+            values.record(fields);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        let mut message = Message::default();
+        event.record(&mut message);
+
+        let mut entry = Vec::with_capacity(256);
+        put_field(&mut entry, "MESSAGE", message.0.trim_start());
+        put_field(&mut entry, "PRIORITY", priority(meta.level()));
+        put_field(&mut entry, "SYSLOG_IDENTIFIER", "wstunnel");
+        put_field(&mut entry, "TARGET", meta.target());
+        if let Some(file) = meta.file() {
+            put_field(&mut entry, "CODE_FILE", file);
+        }
+        if let Some(line) = meta.line() {
+            put_field(&mut entry, "CODE_LINE", &line.to_string());
+        }
+        for span in ctx.event_scope(event).into_iter().flat_map(|scope| scope.from_root()) {
+            if let Some(fields) = span.extensions().get::<SpanFields>() {
+                for (name, value) in &fields.0 {
+                    put_field(&mut entry, name, value);
+                }
+            }
+        }
+
+        // Nowhere to report the error of a log
+        let _ = self.socket.send(&entry);
+    }
+}
+
+const fn priority(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        Level::DEBUG | Level::TRACE => "7",
+    }
+}
+
+/// Field of the native protocol of journald, the values with new lines are prefixed by their length instead
+fn put_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{info, info_span};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_tunnel_fields() {
+        let (socket, journald) = UnixDatagram::pair().unwrap();
+        let subscriber = tracing_subscriber::registry().with(JournaldLayer { socket });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let cnx = info_span!(target: "wstunnel::server", "cnx", peer = "10.0.0.2:41000");
+            let tunnel = info_span!(
+                target: "wstunnel::server",
+                parent: &cnx,
+                "tunnel",
+                id = tracing::field::Empty,
+                remote = tracing::field::Empty
+            );
+            tunnel.record("id", "0192");
+            tunnel.record("remote", "google.com:443");
+            let _enter = tunnel.enter();
+            info!(target: "wstunnel::server", "connecting\nto the destination");
+        });
+
+        let mut buf = [0; 4096];
+        let len = journald.recv(&mut buf).unwrap();
+        let entry = &buf[..len];
+        let mut message = b"MESSAGE\n".to_vec();
+        message.extend_from_slice(&29u64.to_le_bytes());
+        message.extend_from_slice(b"connecting\nto the destination\n");
+        assert!(entry.starts_with(&message));
+        let entry = String::from_utf8_lossy(entry);
+        assert!(entry.contains("\nPRIORITY=6\n"), "{entry}");
+        assert!(entry.contains("\nPEER=10.0.0.2:41000\n"), "{entry}");
+        assert!(entry.contains("\nTUNNEL_ID=0192\n"), "{entry}");
+        assert!(entry.contains("\nDEST=google.com:443\n"), "{entry}");
+    }
+
+    #[test]
+    fn test_other_spans_are_ignored() {
+        let (socket, journald) = UnixDatagram::pair().unwrap();
+        let subscriber = tracing_subscriber::registry().with(JournaldLayer { socket });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _enter = info_span!(target: "h2::proto", "stream", id = 3).entered();
+            info!(target: "h2::proto", "stream closed");
+        });
+
+        let mut buf = [0; 4096];
+        let len = journald.recv(&mut buf).unwrap();
+        assert!(!String::from_utf8_lossy(&buf[..len]).contains("TUNNEL_ID"));
+    }
+}
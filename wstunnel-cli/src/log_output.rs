@@ -0,0 +1,377 @@
+//! `--log-output`, send the logs to syslog or to the Windows Event Log instead of stdout, for the environments where
+//! the logs must be collected by the system and cannot be kept in files
+use anyhow::Context;
+use std::io;
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+use std::sync::Mutex;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+const APP_NAME: &str = "wstunnel";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LogOutput {
+    /// RFC5424 messages sent to a local or remote syslog daemon, with the facility code
+    Syslog(SyslogTarget, u8),
+    /// Entries of the systemd journal, with the fields of the tunnels apart from the message
+    Journald,
+    /// Events of the Application log of Windows, with the source wstunnel
+    EventLog,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SyslogTarget {
+    /// The unix socket of the local daemon, i.e: /dev/log
+    Local,
+    Udp(String),
+    Tcp(String),
+}
+
+const FACILITIES: [(&str, u8); 12] = [
+    ("user", 1),
+    ("daemon", 3),
+    ("auth", 4),
+    ("authpriv", 10),
+    ("local0", 16),
+    ("local1", 17),
+    ("local2", 18),
+    ("local3", 19),
+    ("local4", 20),
+    ("local5", 21),
+    ("local6", 22),
+    ("local7", 23),
+];
+
+pub fn parse_log_output(arg: &str) -> Result<LogOutput, io::Error> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+    if arg == "eventlog" {
+        return if cfg!(windows) {
+            Ok(LogOutput::EventLog)
+        } else {
+            Err(invalid("eventlog log output is only available on Windows".to_string()))
+        };
+    }
+
+    if arg == "journald" {
+        return if cfg!(target_os = "linux") {
+            Ok(LogOutput::Journald)
+        } else {
+            Err(invalid("journald log output is only available on Linux".to_string()))
+        };
+    }
+
+    let (target, options) = arg.split_once('?').unwrap_or((arg, ""));
+    let target = match target {
+        "syslog" if cfg!(unix) => SyslogTarget::Local,
+        "syslog" => {
+            return Err(invalid(
+                "local syslog is only available on unix, use syslog+udp:// or syslog+tcp://".to_string(),
+            ));
+        }
+        _ => match target.split_once("://") {
+            Some(("syslog+udp", addr)) if !addr.is_empty() => SyslogTarget::Udp(addr.to_string()),
+            Some(("syslog+tcp", addr)) if !addr.is_empty() => SyslogTarget::Tcp(addr.to_string()),
+            _ => {
+                return Err(invalid(format!(
+                    "invalid log output {arg}, expected syslog, syslog+udp://HOST:PORT, syslog+tcp://HOST:PORT, journald or eventlog"
+                )));
+            }
+        },
+    };
+
+    let mut facility = 3; // daemon
+    for option in options.split('&').filter(|opt| !opt.is_empty()) {
+        match option.split_once('=') {
+            Some(("facility", name)) => {
+                facility = FACILITIES
+                    .iter()
+                    .find(|(facility, _)| *facility == name)
+                    .map(|(_, code)| *code)
+                    .ok_or_else(|| {
+                        invalid(format!(
+                            "invalid syslog facility {name}, expected user, daemon, auth, authpriv or local0 to local7"
+                        ))
+                    })?;
+            }
+            _ => return Err(invalid(format!("invalid log output option {option}, expected facility=NAME"))),
+        }
+    }
+
+    Ok(LogOutput::Syslog(target, facility))
+}
+
+pub fn log_output_writer(output: &LogOutput) -> anyhow::Result<LogOutputWriter> {
+    match output {
+        LogOutput::Syslog(target, facility) => {
+            Ok(LogOutputWriter::Syslog(SyslogWriter::new(target.clone(), *facility)?))
+        }
+        LogOutput::Journald => unreachable!("the journald logs are sent by their own layer, not a writer"),
+        #[cfg(windows)]
+        LogOutput::EventLog => Ok(LogOutputWriter::EventLog(event_log::EventLogWriter::new()?)),
+        #[cfg(not(windows))]
+        LogOutput::EventLog => anyhow::bail!("eventlog log output is only available on Windows"),
+    }
+}
+
+pub enum LogOutputWriter {
+    Syslog(SyslogWriter),
+    #[cfg(windows)]
+    EventLog(event_log::EventLogWriter),
+}
+
+/// Writer of a single log line, with the level of its event
+pub struct LogLineWriter<'a> {
+    output: &'a LogOutputWriter,
+    level: Level,
+}
+
+impl<'a> MakeWriter<'a> for LogOutputWriter {
+    type Writer = LogLineWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogLineWriter {
+            output: self,
+            level: Level::INFO,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        LogLineWriter {
+            output: self,
+            level: *meta.level(),
+        }
+    }
+}
+
+impl Write for LogLineWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The subscriber writes each log line at once, so each write is a message
+        let line = String::from_utf8_lossy(buf);
+        let line = line.trim();
+        match self.output {
+            LogOutputWriter::Syslog(syslog) => syslog.send(self.level, line)?,
+            #[cfg(windows)]
+            LogOutputWriter::EventLog(event_log) => event_log.report(self.level, line)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum SyslogSocket {
+    #[cfg(unix)]
+    Local(std::os::unix::net::UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+pub struct SyslogWriter {
+    target: SyslogTarget,
+    facility: u8,
+    hostname: String,
+    socket: Mutex<SyslogSocket>,
+}
+
+impl SyslogWriter {
+    fn new(target: SyslogTarget, facility: u8) -> anyhow::Result<Self> {
+        let socket = Self::connect(&target).with_context(|| format!("Cannot connect to syslog {target:?}"))?;
+        Ok(Self {
+            target,
+            facility,
+            hostname: hostname(),
+            socket: Mutex::new(socket),
+        })
+    }
+
+    fn connect(target: &SyslogTarget) -> io::Result<SyslogSocket> {
+        match target {
+            #[cfg(unix)]
+            SyslogTarget::Local => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket
+                    .connect("/dev/log")
+                    .or_else(|_| socket.connect("/var/run/syslog"))?;
+                Ok(SyslogSocket::Local(socket))
+            }
+            #[cfg(not(unix))]
+            SyslogTarget::Local => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "local syslog is only available on unix",
+            )),
+            SyslogTarget::Udp(addr) => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0))
+                    .and_then(|socket| socket.connect(addr).map(|_| socket))
+                    .or_else(|_| {
+                        let socket = UdpSocket::bind(("::", 0))?;
+                        socket.connect(addr)?;
+                        Ok::<_, io::Error>(socket)
+                    })?;
+                Ok(SyslogSocket::Udp(socket))
+            }
+            SyslogTarget::Tcp(addr) => Ok(SyslogSocket::Tcp(TcpStream::connect(addr)?)),
+        }
+    }
+
+    fn send(&self, level: Level, line: &str) -> io::Result<()> {
+        let msg = format_rfc5424(self.facility, level, &self.hostname, std::process::id(), line);
+        let mut socket = self.socket.lock().unwrap_or_else(|err| err.into_inner());
+
+        // The daemon may have been restarted in the meantime, so reconnect once before giving up on the message
+        if Self::send_to(&mut socket, &msg).is_err() {
+            *socket = Self::connect(&self.target)?;
+            Self::send_to(&mut socket, &msg)?;
+        }
+        Ok(())
+    }
+
+    fn send_to(socket: &mut SyslogSocket, msg: &str) -> io::Result<()> {
+        match socket {
+            #[cfg(unix)]
+            SyslogSocket::Local(socket) => socket.send(msg.as_bytes()).map(|_| ()),
+            SyslogSocket::Udp(socket) => socket.send(msg.as_bytes()).map(|_| ()),
+            // Octet counting framing of RFC6587, as the messages may contain new lines
+            SyslogSocket::Tcp(socket) => socket.write_all(format!("{} {}", msg.len(), msg).as_bytes()),
+        }
+    }
+}
+
+/// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG`, without MSGID nor STRUCTURED-DATA
+fn format_rfc5424(facility: u8, level: Level, hostname: &str, pid: u32, line: &str) -> String {
+    let severity = match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    };
+    let timestamp = time::OffsetDateTime::now_utc()
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_else(|_| "-".to_string());
+    format!(
+        "<{}>1 {timestamp} {hostname} {APP_NAME} {pid} - - {line}",
+        facility * 8 + severity
+    )
+}
+
+fn hostname() -> String {
+    #[cfg(unix)]
+    let hostname = nix::unistd::gethostname().ok().and_then(|name| name.into_string().ok());
+    #[cfg(not(unix))]
+    let hostname = std::env::var("COMPUTERNAME").ok();
+
+    // The fields of the header are printable ascii without spaces, the nil value - when it cannot be found
+    hostname
+        .filter(|name| !name.is_empty() && name.bytes().all(|c| c.is_ascii_graphic()))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(windows)]
+mod event_log {
+    use std::io;
+    use tracing::Level;
+    use windows_sys::Win32::Foundation::HANDLE;
+    use windows_sys::Win32::System::EventLog::{
+        DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+        RegisterEventSourceW, ReportEventW,
+    };
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+
+    pub struct EventLogWriter {
+        source: HANDLE,
+    }
+
+    // The handle of an event source can be used from any thread
+    unsafe impl Send for EventLogWriter {}
+    unsafe impl Sync for EventLogWriter {}
+
+    impl EventLogWriter {
+        pub fn new() -> anyhow::Result<Self> {
+            let name = wide(super::APP_NAME);
+            let source = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+            if source.is_null() {
+                anyhow::bail!("Cannot register the event source wstunnel: {}", io::Error::last_os_error());
+            }
+            Ok(Self { source })
+        }
+
+        pub fn report(&self, level: Level, line: &str) -> io::Result<()> {
+            let event_type = match level {
+                Level::ERROR => EVENTLOG_ERROR_TYPE,
+                Level::WARN => EVENTLOG_WARNING_TYPE,
+                _ => EVENTLOG_INFORMATION_TYPE,
+            };
+            let line = wide(line);
+            let strings = [line.as_ptr()];
+            let reported = unsafe {
+                ReportEventW(
+                    self.source,
+                    event_type,
+                    0,
+                    0,
+                    std::ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    std::ptr::null(),
+                )
+            };
+            if reported == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for EventLogWriter {
+        fn drop(&mut self) {
+            unsafe { DeregisterEventSource(self.source) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_output() {
+        assert_eq!(
+            parse_log_output("syslog+udp://10.0.0.1:514").unwrap(),
+            LogOutput::Syslog(SyslogTarget::Udp("10.0.0.1:514".to_string()), 3)
+        );
+        assert_eq!(
+            parse_log_output("syslog+tcp://logs.lan:601?facility=local3").unwrap(),
+            LogOutput::Syslog(SyslogTarget::Tcp("logs.lan:601".to_string()), 19)
+        );
+        #[cfg(unix)]
+        assert_eq!(parse_log_output("syslog").unwrap(), LogOutput::Syslog(SyslogTarget::Local, 3));
+        assert!(parse_log_output("syslog?facility=kern").is_err());
+        assert!(parse_log_output("syslog+udp://").is_err());
+        assert!(parse_log_output("journal").is_err());
+    }
+
+    #[test]
+    fn test_syslog_udp() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = SyslogTarget::Udp(daemon.local_addr().unwrap().to_string());
+        let syslog = SyslogWriter::new(target, 16).unwrap();
+        syslog.send(Level::WARN, "WARN cannot connect").unwrap();
+
+        let mut buf = [0; 1024];
+        let len = daemon.recv(&mut buf).unwrap();
+        let msg = std::str::from_utf8(&buf[..len]).unwrap();
+        // local0 * 8 + warning
+        assert!(msg.starts_with("<132>1 "), "{msg}");
+        assert!(
+            msg.ends_with(&format!(" wstunnel {} - - WARN cannot connect", std::process::id())),
+            "{msg}"
+        );
+    }
+}
@@ -1,13 +1,33 @@
-use clap::Parser;
+use crate::log_file::LogRotation;
+use crate::log_output::LogOutput;
+use anyhow::{Context, anyhow};
+use clap::{Args, FromArgMatches, Parser};
 use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
 use std::str::FromStr;
-use tracing::warn;
+use std::sync::Mutex;
+use tracing::{Level, error, warn};
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::filter::Directive;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriterExt};
+#[cfg(target_os = "linux")]
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use wstunnel::LocalProtocol;
-use wstunnel::config::{Client, Server};
-use wstunnel::executor::DefaultTokioExecutor;
-use wstunnel::{run_client, run_server};
+use wstunnel::config::{Client, LocalToRemote, Nc, OidcLogin, Server};
+use wstunnel::executor::{CpuAffinity, DefaultTokioExecutor, RuntimeConfig};
+use wstunnel::tunnel::AccessList;
+use wstunnel::tunnel::client::AcceptLimits;
+use wstunnel::{CryptoProviderKind, FailureKind, run_client, run_oidc_login, run_server};
+
+mod bench;
+mod log_file;
+#[cfg(target_os = "linux")]
+mod log_journald;
+mod log_output;
+mod status;
+#[cfg(feature = "tui")]
+mod top;
 
 #[cfg(feature = "jemalloc")]
 use tikv_jemallocator::Jemalloc;
@@ -28,9 +48,8 @@ pub struct Wstunnel {
     #[arg(long, global = true, verbatim_doc_comment, env = "NO_COLOR")]
     no_color: Option<String>,
 
-    /// *WARNING* The flag does nothing, you need to set the env variable *WARNING*
     /// Control the number of threads that will be used.
-    /// By default, it is equal the number of cpus
+    /// By default, it is equal the number of cpus. With 0, everything runs on the main thread
     #[arg(
         long,
         global = true,
@@ -38,7 +57,30 @@ pub struct Wstunnel {
         verbatim_doc_comment,
         env = "TOKIO_WORKER_THREADS"
     )]
-    nb_worker_threads: Option<u32>,
+    nb_worker_threads: Option<usize>,
+
+    /// (linux only) Pin each worker thread to a cpu, to keep the tunnels and their crypto work on the cache of a cpu.
+    /// With auto, one worker thread per cpu the process is allowed to run on, or a list of cpus i.e: 0-7,16-23
+    /// The number of worker threads defaults to the number of cpus, else the threads are pinned round-robin over them
+    #[arg(
+        long,
+        global = true,
+        value_name = "auto|CPUS",
+        value_parser = wstunnel::config::parsers::parse_cpu_affinity,
+        verbatim_doc_comment
+    )]
+    cpu_affinity: Option<CpuAffinity>,
+
+    /// Crypto library used for TLS. aws-lc-rs is the faster one on cpus with AES/SHA extensions (i.e: ARMv8),
+    /// ring is there when wstunnel is built with its feature. By default, aws-lc-rs when it is built in
+    #[arg(
+        long,
+        global = true,
+        value_name = "ring|aws-lc-rs",
+        value_parser = wstunnel::config::parsers::parse_crypto_provider,
+        verbatim_doc_comment
+    )]
+    crypto_provider: Option<CryptoProviderKind>,
 
     /// Control the log verbosity. i.e: TRACE, DEBUG, INFO, WARN, ERROR, OFF
     /// for more details: https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#example-syntax
@@ -51,20 +93,114 @@ pub struct Wstunnel {
         default_value = "INFO"
     )]
     log_lvl: String,
+
+    /// Write the logs to this file instead of stdout, i.e: /var/log/wstunnel.log
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE_PATH",
+        group = "log_sink",
+        verbatim_doc_comment,
+        env = "WSTUNNEL_LOG_FILE"
+    )]
+    log_file: Option<PathBuf>,
+
+    /// When to start a new log file: minutely, hourly, daily, weekly, never, or once it reaches a size, i.e: 100m.
+    /// The files of the time based rotations are suffixed with their date, the other ones with their rank, .1 being the most recent
+    #[arg(
+        long,
+        global = true,
+        value_name = "ROTATION",
+        value_parser = log_file::parse_log_rotation,
+        default_value = "never",
+        requires = "log_file",
+        verbatim_doc_comment
+    )]
+    log_file_rotation: LogRotation,
+
+    /// Number of log files kept by the rotation, the current one included
+    #[arg(
+        long,
+        global = true,
+        value_name = "INT",
+        default_value = "7",
+        requires = "log_file",
+        verbatim_doc_comment
+    )]
+    log_file_max_files: usize,
+
+    /// Send the logs to the system instead of stdout, for the environments where they cannot be kept in files
+    /// syslog                              => RFC5424 messages to the local daemon on /dev/log, unix only
+    /// syslog+udp://10.0.0.1:514           => to a remote daemon over udp
+    /// syslog+tcp://10.0.0.1:601           => to a remote daemon over tcp
+    /// syslog?facility=local0              => with the facility user, daemon (default), auth, authpriv or local0 to local7
+    /// journald                            => entries of the systemd journal, with the TUNNEL_ID, PEER and DEST fields, Linux only
+    /// eventlog                            => events of the Application log with the source wstunnel, Windows only
+    #[arg(
+        long,
+        global = true,
+        value_name = "OUTPUT",
+        value_parser = log_output::parse_log_output,
+        group = "log_sink",
+        verbatim_doc_comment,
+        env = "WSTUNNEL_LOG_OUTPUT"
+    )]
+    log_output: Option<LogOutput>,
+
+    /// Also write the logs to stderr when writing them to --log-file or --log-output, i.e: to watch them from a terminal
+    #[arg(long, global = true, requires = "log_sink", verbatim_doc_comment)]
+    log_stderr: bool,
 }
 
 #[derive(clap::Subcommand, Debug)]
 pub enum Commands {
-    Client(Box<Client>),
+    Client(Box<ClientCommand>),
     Server(Box<Server>),
+    Nc(Box<Nc>),
+    Status(status::StatusCommand),
+    Bench(bench::BenchCommand),
+    #[cfg(feature = "tui")]
+    Top(top::Top),
+}
+
+#[derive(clap::Args, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct ClientCommand {
+    #[command(subcommand)]
+    command: Option<ClientSubCommands>,
+
+    #[command(flatten)]
+    args: Option<Client>,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+#[derive(clap::Subcommand, Debug)]
+pub enum ClientSubCommands {
+    /// Login to an OpenID Connect provider and cache the token for the `--oidc` option
+    Login(OidcLogin),
+}
+
+fn main() -> anyhow::Result<()> {
     let args = Wstunnel::parse();
+    let runtime = RuntimeConfig {
+        current_thread: args.nb_worker_threads == Some(0),
+        worker_threads: args.nb_worker_threads,
+        cpu_affinity: args.cpu_affinity.clone(),
+        ..Default::default()
+    }
+    .build()
+    .context("Cannot start the tokio runtime")?;
 
+    runtime.block_on(run(args))
+}
+
+async fn run(args: Wstunnel) -> anyhow::Result<()> {
     // Setup logging
-    let mut env_filter = EnvFilter::builder().parse(&args.log_lvl).expect("Invalid log level");
+    // The logger is not set up yet, so the error is printed on stderr
+    let mut env_filter = EnvFilter::builder()
+        .parse(&args.log_lvl)
+        // The error repeats its source in its message
+        .map_err(|err| anyhow!("{err}").context(FailureKind::Config))
+        .unwrap_or_else(|err| exit_with_error("Invalid log level", err));
     if !(args.log_lvl.contains("h2::") || args.log_lvl.contains("h2=")) {
         env_filter = env_filter.add_directive(Directive::from_str("h2::codec=off").expect("Invalid log directive"));
     }
@@ -72,42 +208,155 @@ async fn main() -> anyhow::Result<()> {
         .with_ansi(args.no_color.is_none())
         .with_env_filter(env_filter);
 
-    // stdio tunnel capture stdio, so need to log into stderr
-    if let Commands::Client(args) = &args.commands {
-        if args
-            .local_to_remote
-            .iter()
-            .filter(|x| matches!(x.local_protocol, LocalProtocol::Stdio { .. }))
-            .count()
-            > 0
+    let control_socket = matches!(&args.commands, Commands::Client(client)
+        if client.args.as_ref().is_some_and(|args| args.control_socket.is_some()));
+    let sink = match (&args.log_output, &args.log_file) {
+        // The entries are sent by their own layer, the writer only gets the copies for stderr and the control socket
+        (Some(LogOutput::Journald), _) => Some(BoxMakeWriter::new(io::sink)),
+        (Some(output), _) => Some(BoxMakeWriter::new(log_output::log_output_writer(output)?)),
+        (None, Some(path)) => {
+            let file = log_file::log_file_writer(path, args.log_file_rotation.clone(), args.log_file_max_files)?;
+            Some(BoxMakeWriter::new(Mutex::new(file)))
+        }
+        (None, None) => None,
+    };
+    if let Some(mut writer) = sink {
+        // The logs are read by other programs, not displayed in a terminal
+        let logger = logger.with_ansi(false);
+        if args.log_stderr {
+            writer = BoxMakeWriter::new(writer.and(io::stderr));
+        }
+        if control_socket {
+            writer = BoxMakeWriter::new(writer.and(wstunnel::control::log_stream));
+        }
+        match &args.log_output {
+            #[cfg(target_os = "linux")]
+            Some(LogOutput::Journald) => {
+                let journald = log_journald::JournaldLayer::new().context("Cannot connect to journald")?;
+                logger.with_writer(writer).finish().with(journald).init();
+            }
+            // The messages carry their own timestamp
+            Some(_) => logger.without_time().with_writer(writer).init(),
+            None => logger.with_writer(writer).init(),
+        }
+    } else if let Commands::Client(client) = &args.commands {
+        // stdio tunnel capture stdio, so need to log into stderr
+        if let Some(args) = &client.args
+            && args
+                .local_to_remote
+                .iter()
+                .filter(|x| matches!(x.local_protocol, LocalProtocol::Stdio { .. } | LocalProtocol::StdioUdp { .. }))
+                .count()
+                > 0
         {
             logger.with_writer(io::stderr).init();
+        } else if control_socket {
+            // Stream the logs to the programs controlling the client
+            logger.with_writer(io::stdout.and(wstunnel::control::log_stream)).init();
         } else {
             logger.init()
         }
+    } else if let Commands::Nc(_) | Commands::Bench(_) = &args.commands {
+        logger.with_writer(io::stderr).init();
     } else {
         logger.init();
     };
     if let Err(err) = fdlimit::raise_fd_limit() {
         warn!("Failed to set soft filelimit to hard file limit: {}", err)
     }
+    if matches!(
+        args.commands,
+        Commands::Client(_) | Commands::Nc(_) | Commands::Server(_) | Commands::Bench(_)
+    ) {
+        args.crypto_provider
+            .unwrap_or_default()
+            .install()
+            .context(FailureKind::Config)
+            .unwrap_or_else(|err| exit_with_error("Cannot set up TLS", err));
+    }
 
     match args.commands {
-        Commands::Client(args) => {
-            run_client(*args, DefaultTokioExecutor::default())
+        Commands::Client(client) => match (client.command, client.args) {
+            (Some(ClientSubCommands::Login(args)), _) => {
+                run_oidc_login(args)
+                    .await
+                    .unwrap_or_else(|err| exit_with_error("Cannot login", err));
+            }
+            (None, Some(args)) => {
+                run_client(args, DefaultTokioExecutor::default())
+                    .await
+                    .unwrap_or_else(|err| exit_with_error("Cannot start wstunnel client", err));
+            }
+            (None, None) => unreachable!("clap requires either the client arguments or a subcommand"),
+        },
+        Commands::Nc(nc) => {
+            let target = nc.target().unwrap_or_else(|err| {
+                exit_with_error(
+                    "Cannot find where to connect",
+                    anyhow::Error::new(err).context(FailureKind::Config),
+                )
+            });
+            let client_args = std::iter::once("wstunnel nc".to_string())
+                .chain(nc.client_args)
+                .chain(std::iter::once(nc.server.to_string()));
+            let mut args = Client::augment_args(clap::Command::new("wstunnel nc"))
+                .try_get_matches_from(client_args)
+                .and_then(|matches| Client::from_arg_matches(&matches))
+                .unwrap_or_else(|err| err.exit());
+            args.local_to_remote.push(LocalToRemote {
+                local_protocol: LocalProtocol::Stdio { proxy_protocol: false },
+                local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+                remote: target,
+                label: None,
+                dscp: None,
+                v6only: None,
+                access: AccessList::default(),
+                accept_limits: AcceptLimits::default(),
+                port_count: 1,
+            });
+            run_client(args, DefaultTokioExecutor::default())
+                .await
+                .unwrap_or_else(|err| exit_with_error("Cannot start wstunnel client", err));
+        }
+        Commands::Bench(args) => {
+            bench::run(args)
                 .await
+                .unwrap_or_else(|err| exit_with_error("Cannot run the benchmark", err));
+        }
+        Commands::Status(args) => {
+            tokio::task::spawn_blocking(move || status::run(args))
+                .await?
                 .unwrap_or_else(|err| {
-                    panic!("Cannot start wstunnel client: {err:?}");
+                    eprintln!("Cannot get the status of wstunnel: {err:#}");
+                    std::process::exit(1);
                 });
         }
+        #[cfg(feature = "tui")]
+        Commands::Top(args) => {
+            tokio::task::spawn_blocking(move || top::run(args))
+                .await?
+                .context(FailureKind::Config)
+                .unwrap_or_else(|err| exit_with_error("Cannot run wstunnel top", err));
+        }
         Commands::Server(args) => {
             run_server(*args, DefaultTokioExecutor::default())
                 .await
-                .unwrap_or_else(|err| {
-                    panic!("Cannot start wstunnel server: {err:?}");
-                });
+                .unwrap_or_else(|err| exit_with_error("Cannot start wstunnel server", err));
         }
     }
 
     Ok(())
 }
+
+/// Log the error that stopped wstunnel on a single line, and exit with the code of its kind so a supervisor can tell
+/// a configuration error (2), a listener that cannot be bound (3), a TLS error (4) and refused credentials (5) apart
+fn exit_with_error(msg: &str, err: anyhow::Error) -> ! {
+    let kind = FailureKind::of(&err);
+    let exit_code = kind.map_or(1, FailureKind::exit_code);
+    if tracing::enabled!(Level::ERROR) {
+        error!(failure = kind.map_or("other", FailureKind::name), exit_code, "{msg}: {err:#}");
+    } else {
+        eprintln!("{msg}: {err:#}");
+    }
+    std::process::exit(exit_code)
+}
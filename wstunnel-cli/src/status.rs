@@ -0,0 +1,57 @@
+//! `wstunnel status`, dump the status of a wstunnel client or server through its --admin-listen api
+use anyhow::{Context, anyhow};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use wstunnel::stats::Status;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Dump the tunnels, the connection pool, the reconnect counters and the memory of a wstunnel client or server,
+/// through the api it serves on --admin-listen. Without it, `kill -USR1` the process to have its status logged
+#[derive(clap::Args, Debug)]
+pub struct StatusCommand {
+    /// Address of the admin api of the wstunnel client or server, as given to its --admin-listen
+    #[arg(long, value_name = "IP:PORT", verbatim_doc_comment)]
+    admin: SocketAddr,
+
+    /// Print the status as returned by the api, in json
+    #[arg(long, verbatim_doc_comment)]
+    json: bool,
+}
+
+pub fn run(args: StatusCommand) -> anyhow::Result<()> {
+    let body = get(args.admin, "/status")?;
+    if args.json {
+        println!("{}", String::from_utf8_lossy(&body));
+        return Ok(());
+    }
+    let status: Status = serde_json::from_slice(&body).context("Invalid status returned by the admin api")?;
+    print!("{status}");
+    Ok(())
+}
+
+/// Body of a GET on the admin api
+pub fn get(admin: SocketAddr, path: &str) -> anyhow::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&admin, TIMEOUT)
+        .with_context(|| format!("Cannot connect to the admin api on {admin}"))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(stream, "GET {path} HTTP/1.0\r\nHost: {admin}\r\n\r\n")?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .context("Invalid http response from the admin api")?;
+    let status_line = response[..split].split(|b| *b == b'\n').next().unwrap_or_default();
+    if !status_line.starts_with(b"HTTP/1.1 200") && !status_line.starts_with(b"HTTP/1.0 200") {
+        return Err(anyhow!(
+            "Admin api answered {}",
+            String::from_utf8_lossy(status_line).trim_end()
+        ));
+    }
+    response.drain(..split + 4);
+    Ok(response)
+}
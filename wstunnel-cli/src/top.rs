@@ -0,0 +1,270 @@
+//! `wstunnel top`, a terminal UI watching the tunnels of a wstunnel client or server through its --admin-listen api
+use crate::status;
+use anyhow::Context;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use wstunnel::stats::{Side, Snapshot, TunnelSnapshot};
+
+/// Watch the live tunnels of a wstunnel client or server, through the api it serves on --admin-listen
+#[derive(clap::Args, Debug)]
+pub struct Top {
+    /// Address of the admin api of the wstunnel client or server to watch, as given to its --admin-listen
+    #[arg(long, value_name = "IP:PORT", verbatim_doc_comment)]
+    admin: SocketAddr,
+
+    /// Interval between two refreshes of the statistics, in seconds
+    #[arg(long, value_name = "INT", default_value = "1", verbatim_doc_comment)]
+    refresh_sec: u64,
+}
+
+/// Throughput of a tunnel, or of all of them, in bytes per second since the previous snapshot
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Rate {
+    tx: f64,
+    rx: f64,
+}
+
+struct State {
+    snapshot: Snapshot,
+    taken_at: Instant,
+    total_rate: Rate,
+    rates: HashMap<(Side, String), Rate>,
+    error: Option<String>,
+}
+
+impl State {
+    fn update(&mut self, snapshot: Snapshot, taken_at: Instant) {
+        let elapsed = taken_at.duration_since(self.taken_at).as_secs_f64();
+        let previous: HashMap<(Side, &str), &TunnelSnapshot> = self
+            .snapshot
+            .tunnels
+            .iter()
+            .map(|tunnel| ((tunnel.side, tunnel.id.as_str()), tunnel))
+            .collect();
+        self.rates = snapshot
+            .tunnels
+            .iter()
+            .map(|tunnel| {
+                let rate = previous
+                    .get(&(tunnel.side, tunnel.id.as_str()))
+                    .map(|prev| rate((prev.tx_bytes, prev.rx_bytes), (tunnel.tx_bytes, tunnel.rx_bytes), elapsed))
+                    .unwrap_or_default();
+                ((tunnel.side, tunnel.id.clone()), rate)
+            })
+            .collect();
+        self.total_rate = rate(
+            (self.snapshot.tx_bytes, self.snapshot.rx_bytes),
+            (snapshot.tx_bytes, snapshot.rx_bytes),
+            elapsed,
+        );
+        self.snapshot = snapshot;
+        self.taken_at = taken_at;
+        self.error = None;
+    }
+
+    fn rate_of(&self, tunnel: &TunnelSnapshot) -> Rate {
+        self.rates
+            .get(&(tunnel.side, tunnel.id.clone()))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+fn rate((prev_tx, prev_rx): (u64, u64), (tx, rx): (u64, u64), elapsed: f64) -> Rate {
+    if elapsed <= 0.0 {
+        return Rate::default();
+    }
+    Rate {
+        tx: tx.saturating_sub(prev_tx) as f64 / elapsed,
+        rx: rx.saturating_sub(prev_rx) as f64 / elapsed,
+    }
+}
+
+pub fn run(args: Top) -> anyhow::Result<()> {
+    // Fail before taking over the terminal, if the api cannot be reached
+    let snapshot = fetch(args.admin)?;
+    let state = State {
+        snapshot,
+        taken_at: Instant::now(),
+        total_rate: Rate::default(),
+        rates: HashMap::new(),
+        error: None,
+    };
+
+    let terminal = ratatui::try_init().context("Cannot setup the terminal")?;
+    let ret = run_ui(terminal, state, &args);
+    ratatui::restore();
+    ret
+}
+
+fn run_ui(mut terminal: DefaultTerminal, mut state: State, args: &Top) -> anyhow::Result<()> {
+    let refresh = Duration::from_secs(args.refresh_sec.max(1));
+    let mut next_refresh = Instant::now() + refresh;
+    loop {
+        terminal.draw(|frame| draw(frame, &state, args.admin))?;
+
+        let timeout = next_refresh.saturating_duration_since(Instant::now());
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+                && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+            {
+                return Ok(());
+            }
+            continue;
+        }
+
+        match fetch(args.admin) {
+            Ok(snapshot) => state.update(snapshot, Instant::now()),
+            Err(err) => state.error = Some(format!("{err:#}")),
+        }
+        next_refresh = Instant::now() + refresh;
+    }
+}
+
+fn fetch(admin: SocketAddr) -> anyhow::Result<Snapshot> {
+    let body = status::get(admin, "/tunnels")?;
+    serde_json::from_slice(&body).context("Invalid statistics returned by the admin api")
+}
+
+fn draw(frame: &mut Frame, state: &State, admin: SocketAddr) {
+    let [summary, tunnels] = Layout::vertical([Constraint::Length(4), Constraint::Min(0)]).areas(frame.area());
+    let snapshot = &state.snapshot;
+
+    let mut lines = vec![
+        Line::from(format!(
+            "wstunnel on {admin}, up {}   tunnels: {} live, {} opened   reconnects: {}",
+            human_duration(snapshot.uptime_secs),
+            snapshot.tunnels.len(),
+            snapshot.tunnels_opened,
+            snapshot.reconnects,
+        )),
+        Line::from(format!(
+            "tx: {}/s ({} total)   rx: {}/s ({} total)",
+            human_bytes(state.total_rate.tx),
+            human_bytes(snapshot.tx_bytes as f64),
+            human_bytes(state.total_rate.rx),
+            human_bytes(snapshot.rx_bytes as f64),
+        )),
+    ];
+    if let Some(err) = &state.error {
+        lines.push(Line::styled(format!("error: {err}"), Style::new().add_modifier(Modifier::BOLD)));
+    }
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" wstunnel top - q to quit ")),
+        summary,
+    );
+
+    // The busiest tunnels first
+    let mut sorted: Vec<(&TunnelSnapshot, Rate)> = snapshot
+        .tunnels
+        .iter()
+        .map(|tunnel| (tunnel, state.rate_of(tunnel)))
+        .collect();
+    sorted.sort_by(|(a, a_rate), (b, b_rate)| {
+        (b_rate.tx + b_rate.rx)
+            .total_cmp(&(a_rate.tx + a_rate.rx))
+            .then_with(|| (b.tx_bytes + b.rx_bytes).cmp(&(a.tx_bytes + a.rx_bytes)))
+    });
+    let rows = sorted.into_iter().map(|(tunnel, rate)| {
+        Row::new(vec![
+            tunnel.id.clone(),
+            format!("{:?}", tunnel.side).to_lowercase(),
+            tunnel.protocol.clone(),
+            tunnel.remote.clone(),
+            tunnel.peer.map(|peer| peer.to_string()).unwrap_or_default(),
+            tunnel.label.clone().unwrap_or_default(),
+            tunnel.device.clone().unwrap_or_default(),
+            human_duration(tunnel.age_secs),
+            format!("{}/s", human_bytes(rate.tx)),
+            format!("{}/s", human_bytes(rate.rx)),
+            human_bytes(tunnel.tx_bytes as f64),
+            human_bytes(tunnel.rx_bytes as f64),
+            tunnel
+                .srtt_us
+                .or(tunnel.rtt_us)
+                .map(|rtt| format!("{:.1}ms", rtt as f64 / 1000.0))
+                .unwrap_or_else(|| "-".to_string()),
+            tunnel.reconnects.to_string(),
+        ])
+    });
+    let header = Row::new(vec![
+        "ID", "SIDE", "PROTO", "REMOTE", "PEER", "LABEL", "DEVICE", "AGE", "TX/s", "RX/s", "TX", "RX", "RTT", "RECO",
+    ])
+    .style(Style::new().add_modifier(Modifier::BOLD));
+    let widths = [
+        Constraint::Length(36),
+        Constraint::Length(6),
+        Constraint::Length(12),
+        Constraint::Min(20),
+        Constraint::Length(21),
+        Constraint::Length(12),
+        Constraint::Length(16),
+        Constraint::Length(8),
+        Constraint::Length(11),
+        Constraint::Length(11),
+        Constraint::Length(9),
+        Constraint::Length(9),
+        Constraint::Length(8),
+        Constraint::Length(4),
+    ];
+    frame.render_widget(
+        Table::new(rows, widths)
+            .header(header)
+            .block(Block::bordered().title(" tunnels ")),
+        tunnels,
+    );
+}
+
+fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{value:.0}{}", UNITS[unit])
+    } else {
+        format!("{value:.1}{}", UNITS[unit])
+    }
+}
+
+fn human_duration(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate() {
+        assert_eq!(rate((100, 0), (300, 50), 2.0), Rate { tx: 100.0, rx: 25.0 });
+        // The counters of a restarted process start again from 0
+        assert_eq!(rate((300, 50), (100, 0), 2.0), Rate::default());
+        assert_eq!(rate((0, 0), (100, 100), 0.0), Rate::default());
+    }
+
+    #[test]
+    fn test_human_units() {
+        assert_eq!(human_bytes(512.0), "512B");
+        assert_eq!(human_bytes(1536.0), "1.5KiB");
+        assert_eq!(human_bytes(3.0 * 1024.0 * 1024.0 * 1024.0), "3.0GiB");
+        assert_eq!(human_duration(42), "42s");
+        assert_eq!(human_duration(125), "2m05s");
+        assert_eq!(human_duration(7260), "2h01m");
+    }
+}
@@ -0,0 +1,810 @@
+//! Configure a client or a server from code, without formatting and parsing the strings of the command line.
+//!
+//! ```no_run
+//! use std::net::SocketAddr;
+//! use url::{Host, Url};
+//! use wstunnel::executor::DefaultTokioExecutor;
+//! use wstunnel::{ClientBuilder, LocalProtocol};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let client = ClientBuilder::new(Url::parse("wss://wstunnel.example.com")?)
+//!     .add_local_tunnel(
+//!         LocalProtocol::Tcp {
+//!             proxy_protocol: false,
+//!             resume: None,
+//!             idle_timeout: None,
+//!             mirror: None,
+//!             balancing: None,
+//!             keepalive: None,
+//!         },
+//!         SocketAddr::from(([127, 0, 0, 1], 1212)),
+//!         (Host::Domain("google.com".to_string()), 443),
+//!     )
+//!     .build()?;
+//! wstunnel::run_client(client, DefaultTokioExecutor::default()).await
+//! # }
+//! ```
+use crate::config::parsers::parse_server_url;
+use crate::config::{
+    Client, DEFAULT_CLIENT_UPGRADE_PATH_PREFIX, HeaderName, HeaderValue, LocalToRemote, Secret, Server,
+};
+use crate::protocols::tls::TlsFingerprint;
+use crate::tunnel::client::{AcceptLimits, Browser, NetworkSim, OnListenerError, RedirectPolicy, SplitRequests};
+use crate::tunnel::noise::NoiseKey;
+use crate::tunnel::protocol::client_version::ClientVersion;
+use crate::tunnel::protocol::close_reason::{CloseReason, WsCloseCodes};
+use crate::tunnel::server::{ProtocolHandler, SniffedProtocol};
+use crate::tunnel::{AccessList, LocalProtocol, is_valid_label};
+use anyhow::anyhow;
+use hyper::StatusCode;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio_rustls::rustls::pki_types::DnsName;
+use url::{Host, Url};
+
+// Same defaults as the command line
+const DEFAULT_WEBSOCKET_PING_FREQUENCY: Duration = Duration::from_secs(30);
+const DEFAULT_WEBSOCKET_MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+const DEFAULT_WEBSOCKET_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_INFLIGHT_PER_TUNNEL: usize = 4 * 1024 * 1024;
+
+/// Build the configuration of a client, to give to [`crate::run_client`]. Every option not set keeps the default of the
+/// command line. The options without a method can be changed on the [`Client`] returned by [`ClientBuilder::build`]
+#[derive(Clone, Debug)]
+pub struct ClientBuilder {
+    client: Client,
+}
+
+impl ClientBuilder {
+    /// Client of the server at `server_url`, i.e: wss://wstunnel.example.com
+    pub fn new(server_url: Url) -> Self {
+        Self {
+            client: Client {
+                local_to_remote: vec![],
+                remote_to_local: vec![],
+                socket_so_mark: None,
+                tcp_fastopen: false,
+                dscp: None,
+                bind_interface: None,
+                bind_address: None,
+                connection_min_idle: 0,
+                connection_health_check_interval: None,
+                connection_max_idle_age: None,
+                connection_warmup_timeout: Duration::from_secs(30),
+                connection_retry_max_backoff: Duration::from_secs(5 * 60),
+                reverse_tunnel_connection_retry_max_backoff: Duration::from_secs(1),
+                reverse_tunnel_connection_max_retries: None,
+                reverse_tunnel_hook: None,
+                reverse_tunnel_probe_interval: None,
+                exit_if_disconnected_for: None,
+                on_listener_error: OnListenerError::Fail,
+                health_listen: None,
+                liveness_file: None,
+                admin_listen: None,
+                on_tunnel_connect: None,
+                on_tunnel_close: None,
+                control_socket: None,
+                tls_sni_override: None,
+                tls_sni_disable: false,
+                tls_ech_enable: false,
+                tls_fingerprint: TlsFingerprint::default(),
+                tls_verify_certificate: false,
+                http_proxy: None,
+                http_proxy_login: None,
+                http_proxy_password: None,
+                http_upgrade_path_prefix: DEFAULT_CLIENT_UPGRADE_PATH_PREFIX.to_string(),
+                device_id: None,
+                device_id_file: None,
+                emit_restrictions: None,
+                dump_config: false,
+                show_secrets: false,
+                http_upgrade_credentials: None,
+                oidc: false,
+                oidc_token_cache: None,
+                psk: None,
+                noise_private_key: None,
+                noise_server_public_key: None,
+                websocket_ping_frequency: Some(DEFAULT_WEBSOCKET_PING_FREQUENCY),
+                websocket_mask_frame: false,
+                websocket_max_frame_size: DEFAULT_WEBSOCKET_MAX_FRAME_SIZE,
+                websocket_close_timeout: DEFAULT_WEBSOCKET_CLOSE_TIMEOUT,
+                websocket_close_code: vec![],
+                max_inflight_per_tunnel: DEFAULT_MAX_INFLIGHT_PER_TUNNEL,
+                max_memory_buffers: None,
+                http_split_requests: SplitRequests::default(),
+                mux: false,
+                transport_max_lifetime: None,
+                transport_max_bytes: None,
+                early_data: false,
+                random_upgrade_path: false,
+                rotate_host: vec![],
+                camouflage: None,
+                upgrade_max_redirects: 5,
+                upgrade_redirect_policy: RedirectPolicy::default(),
+                standby_server: vec![],
+                affinity_token: None,
+                pcap_dir: None,
+                integrity_check_interval: None,
+                sim_latency: None,
+                sim_loss: None,
+                sim_rate: None,
+                sim_seed: None,
+                http_headers: vec![],
+                http_headers_file: None,
+                remote_addr: server_url,
+                tls_certificate: None,
+                tls_private_key: None,
+                dns_resolver: vec![],
+                dns_resolver_prefer_ipv4: false,
+                #[cfg(feature = "dns-transport")]
+                dns_transport_resolver: None,
+            },
+        }
+    }
+
+    /// Listen on `bind` and forward the traffic to `dest` through the server, like -L
+    pub fn add_local_tunnel(mut self, protocol: LocalProtocol, bind: SocketAddr, dest: (Host, u16)) -> Self {
+        self.client.local_to_remote.push(LocalToRemote {
+            local_protocol: protocol,
+            local: bind,
+            remote: dest,
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        });
+        self
+    }
+
+    /// Listen on `bind` on the server and forward the traffic to `dest` from the client, like -R.
+    /// `protocol` is one of the reverse ones, i.e: `LocalProtocol::ReverseTcp`
+    pub fn add_reverse_tunnel(mut self, protocol: LocalProtocol, bind: SocketAddr, dest: (Host, u16)) -> Self {
+        self.client.remote_to_local.push(LocalToRemote {
+            local_protocol: protocol,
+            local: bind,
+            remote: dest,
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        });
+        self
+    }
+
+    /// Add a tunnel as -R if its protocol is a reverse one, as -L otherwise. i.e: to set its label
+    pub fn add_tunnel(mut self, tunnel: LocalToRemote) -> Self {
+        if tunnel.local_protocol.is_reverse_tunnel() {
+            self.client.remote_to_local.push(tunnel);
+        } else {
+            self.client.local_to_remote.push(tunnel);
+        }
+        self
+    }
+
+    pub fn tls_verify_certificate(mut self, verify: bool) -> Self {
+        self.client.tls_verify_certificate = verify;
+        self
+    }
+
+    pub fn tls_sni_override(mut self, sni: DnsName<'static>) -> Self {
+        self.client.tls_sni_override = Some(sni);
+        self
+    }
+
+    /// Offer the cipher suites and key exchange groups of a browser during the tls handshake
+    pub fn tls_fingerprint(mut self, fingerprint: TlsFingerprint) -> Self {
+        self.client.tls_fingerprint = fingerprint;
+        self
+    }
+
+    /// Client certificate and private key, to authenticate with mTLS
+    pub fn tls_client_certificate(mut self, certificate: PathBuf, private_key: PathBuf) -> Self {
+        self.client.tls_certificate = Some(certificate);
+        self.client.tls_private_key = Some(private_key);
+        self
+    }
+
+    pub fn http_upgrade_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.client.http_upgrade_path_prefix = prefix.into();
+        self
+    }
+
+    /// Stable name of the client, told to the server with each tunnel
+    pub fn device_id(mut self, id: impl Into<String>) -> Self {
+        self.client.device_id = Some(id.into());
+        self
+    }
+
+    /// File holding the device id of the client, generated on the first run
+    pub fn device_id_file(mut self, path: PathBuf) -> Self {
+        self.client.device_id_file = Some(path);
+        self
+    }
+
+    /// Value of the Authorization header of the upgrade request, i.e: `Basic dXNlcjpwYXNz`
+    pub fn http_upgrade_credentials(mut self, credentials: HeaderValue) -> Self {
+        self.client.http_upgrade_credentials = Some(credentials);
+        self
+    }
+
+    pub fn add_http_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.client.http_headers.push((name, value));
+        self
+    }
+
+    /// i.e: user:pass@proxy.lan:8080
+    pub fn http_proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.client.http_proxy = Some(Secret::new(proxy.into()));
+        self
+    }
+
+    pub fn psk(mut self, psk: impl Into<String>) -> Self {
+        self.client.psk = Some(Secret::new(psk.into()));
+        self
+    }
+
+    /// Encrypt the tunnels end to end with Noise, with the private key of the client and the public key of the server
+    pub fn noise(mut self, private_key: NoiseKey, server_public_key: NoiseKey) -> Self {
+        self.client.noise_private_key = Some(private_key);
+        self.client.noise_server_public_key = Some(server_public_key);
+        self
+    }
+
+    /// Carry all the tunnels over a single connection with the server
+    pub fn mux(mut self, mux: bool) -> Self {
+        self.client.mux = mux;
+        self
+    }
+
+    /// Replace the connection of the mux and the ones of the resumable tunnels after this long, and/or this many bytes
+    pub fn transport_limits(mut self, max_lifetime: Option<Duration>, max_bytes: Option<u64>) -> Self {
+        self.client.transport_max_lifetime = max_lifetime;
+        self.client.transport_max_bytes = max_bytes;
+        self
+    }
+
+    /// Send the first bytes of each tunnel along with the request opening it, to save a round trip
+    pub fn early_data(mut self, early_data: bool) -> Self {
+        self.client.early_data = early_data;
+        self
+    }
+
+    /// Add a random segment to the path of each request to the server
+    pub fn random_upgrade_path(mut self, random_upgrade_path: bool) -> Self {
+        self.client.random_upgrade_path = random_upgrade_path;
+        self
+    }
+
+    /// Domains to use as sni and host of the connections to the server, one is picked at random for each of them
+    pub fn rotate_host(mut self, hosts: Vec<DnsName<'static>>) -> Self {
+        self.client.rotate_host = hosts;
+        self
+    }
+
+    /// Send the headers of this browser with the requests to the server
+    pub fn camouflage(mut self, browser: Option<Browser>) -> Self {
+        self.client.camouflage = browser;
+        self
+    }
+
+    /// Follow up to `max_redirects` redirects of the upgrade requests, to where the policy allows
+    pub fn upgrade_redirects(mut self, max_redirects: u8, policy: RedirectPolicy) -> Self {
+        self.client.upgrade_max_redirects = max_redirects;
+        self.client.upgrade_redirect_policy = policy;
+        self
+    }
+
+    pub fn connection_min_idle(mut self, count: u32) -> Self {
+        self.client.connection_min_idle = count;
+        self
+    }
+
+    /// None to never ping the server
+    pub fn websocket_ping_frequency(mut self, frequency: Option<Duration>) -> Self {
+        self.client.websocket_ping_frequency = frequency;
+        self
+    }
+
+    /// Zero to not wait for the server to answer the close frames
+    pub fn websocket_close_timeout(mut self, timeout: Duration) -> Self {
+        self.client.websocket_close_timeout = timeout;
+        self
+    }
+
+    pub fn add_websocket_close_code(mut self, reason: CloseReason, code: u16) -> Self {
+        self.client.websocket_close_code.push((reason, code));
+        self
+    }
+
+    /// i.e: dns+https://1.1.1.1?sni=cloudflare-dns.com
+    pub fn add_dns_resolver(mut self, resolver: Url) -> Self {
+        self.client.dns_resolver.push(resolver);
+        self
+    }
+
+    pub fn socket_so_mark(mut self, mark: u32) -> Self {
+        self.client.socket_so_mark = Some(mark);
+        self
+    }
+
+    /// Compare the checksums of the data of each tunnel with the server every `interval` bytes, to find out corruption
+    pub fn integrity_check_interval(mut self, interval: u64) -> Self {
+        self.client.integrity_check_interval = Some(interval);
+        self
+    }
+
+    /// Degrade the connections to the server like a bad network would, to test the tunnels on it
+    pub fn network_sim(mut self, sim: NetworkSim) -> Self {
+        self.client.sim_latency = Some(sim.latency);
+        self.client.sim_loss = Some(sim.loss);
+        self.client.sim_rate = sim.rate;
+        self.client.sim_seed = sim.seed;
+        self
+    }
+
+    /// i.e: 46 to mark the connections to the server as Expedited Forwarding
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        self.client.dscp = Some(dscp);
+        self
+    }
+
+    /// (linux only) i.e: eth1 to connect to the server through this uplink
+    pub fn bind_interface(mut self, interface: impl Into<String>) -> Self {
+        self.client.bind_interface = Some(interface.into());
+        self
+    }
+
+    /// Connect to the server from this source ip
+    pub fn bind_address(mut self, address: IpAddr) -> Self {
+        self.client.bind_address = Some(address);
+        self
+    }
+
+    /// Check the configuration like the command line does
+    pub fn build(self) -> anyhow::Result<Client> {
+        let client = self.client;
+        parse_server_url(client.remote_addr.as_str())?;
+        for tunnel in &client.local_to_remote {
+            if tunnel.local_protocol.is_reverse_tunnel() {
+                return Err(anyhow!("{:?} is not a protocol of local tunnels", tunnel.local_protocol));
+            }
+        }
+        for tunnel in &client.remote_to_local {
+            if !tunnel.local_protocol.is_reverse_tunnel() {
+                return Err(anyhow!("{:?} is not a protocol of reverse tunnels", tunnel.local_protocol));
+            }
+        }
+        for tunnel in client.local_to_remote.iter().chain(&client.remote_to_local) {
+            if let Some(label) = &tunnel.label
+                && !is_valid_label(label)
+            {
+                return Err(anyhow!(
+                    "invalid label {label} of tunnel to {}:{}",
+                    tunnel.remote.0,
+                    tunnel.remote.1
+                ));
+            }
+        }
+        if let Some(id) = &client.device_id
+            && !is_valid_label(id)
+        {
+            return Err(anyhow!("invalid device id {id}"));
+        }
+        if client.device_id.is_some() && client.device_id_file.is_some() {
+            return Err(anyhow!("the device id and the device id file cannot be set together"));
+        }
+        if client.tls_certificate.is_some() != client.tls_private_key.is_some() {
+            return Err(anyhow!("the client certificate and its private key must be set together"));
+        }
+        if client.noise_private_key.is_some() != client.noise_server_public_key.is_some() {
+            return Err(anyhow!("the noise private key and the server public key must be set together"));
+        }
+        if client.oidc && client.http_upgrade_credentials.is_some() {
+            return Err(anyhow!("oidc and http upgrade credentials cannot be used together"));
+        }
+        check_websocket_close_codes(&client.websocket_close_code)?;
+
+        Ok(client)
+    }
+}
+
+/// Build the configuration of a server, to give to [`crate::run_server`]. Every option not set keeps the default of the
+/// command line. The options without a method can be changed on the [`Server`] returned by [`ServerBuilder::build`]
+#[derive(Debug)]
+pub struct ServerBuilder {
+    server: Server,
+}
+
+impl ServerBuilder {
+    /// Server listening on `bind_url`, i.e: wss://0.0.0.0:443
+    pub fn new(bind_url: Url) -> Self {
+        Self {
+            server: Server {
+                remote_addr: bind_url,
+                socket_so_mark: None,
+                tcp_fastopen: false,
+                dscp: None,
+                bind_interface: None,
+                bind_address: None,
+                tcp_defer_accept: None,
+                accept_shards: 1,
+                websocket_ping_frequency: Some(DEFAULT_WEBSOCKET_PING_FREQUENCY),
+                websocket_mask_frame: false,
+                websocket_max_frame_size: DEFAULT_WEBSOCKET_MAX_FRAME_SIZE,
+                websocket_close_timeout: DEFAULT_WEBSOCKET_CLOSE_TIMEOUT,
+                websocket_close_code: vec![],
+                traffic_padding: None,
+                traffic_jitter: None,
+                reject_status: None,
+                reject_body: None,
+                reject_header: vec![],
+                reject_redirect: None,
+                standby_file: None,
+                cluster_peer: vec![],
+                cluster_advertise: None,
+                cluster_secret: None,
+                sticky_session_secret: None,
+                sticky_session_instance: None,
+                max_inflight_per_tunnel: DEFAULT_MAX_INFLIGHT_PER_TUNNEL,
+                max_memory_buffers: None,
+                pcap_dir: None,
+                dns_resolver: vec![],
+                dns_resolver_prefer_ipv4: false,
+                restrict_to: None,
+                restrict_http_upgrade_path_prefix: None,
+                restrict_config: None,
+                check_restrictions: false,
+                dump_config: false,
+                show_secrets: false,
+                auth_hook: None,
+                auth_hook_timeout: Duration::from_secs(5),
+                oidc_issuer: None,
+                oidc_audience: None,
+                psk: None,
+                noise_private_key: None,
+                noise_client_public_key: vec![],
+                #[cfg(feature = "dns-transport")]
+                dns_transport_listen: None,
+                #[cfg(feature = "dns-transport")]
+                dns_transport_domain: None,
+                #[cfg(feature = "icmp-transport")]
+                icmp_transport_listen: None,
+                #[cfg(feature = "ssh-transport")]
+                ssh_transport: false,
+                #[cfg(feature = "ssh-transport")]
+                ssh_host_key: None,
+                protocol_handler: vec![],
+                tls_sni_passthrough: None,
+                tls_server_name: vec![],
+                tls_certificate: None,
+                tls_private_key: None,
+                tls_client_ca_certs: None,
+                tls_ktls: false,
+                http_proxy: None,
+                http_proxy_login: None,
+                http_proxy_password: None,
+                remote_to_local_server_idle_timeout: Duration::from_secs(3 * 60),
+                http_ingress_domain: None,
+                http_ingress_reserve: vec![],
+                tunnel_resume_max_timeout: Duration::from_secs(5 * 60),
+                tunnel_idle_timeout: None,
+                metrics_listen: None,
+                admin_listen: None,
+                on_tunnel_connect: None,
+                on_tunnel_close: None,
+                max_clients: None,
+                max_tunnels_per_client: None,
+                require_min_client_version: None,
+            },
+        }
+    }
+
+    /// Certificate and private key served for wss/https, instead of the embedded self-signed one
+    pub fn tls_certificate(mut self, certificate: PathBuf, private_key: PathBuf) -> Self {
+        self.server.tls_certificate = Some(certificate);
+        self.server.tls_private_key = Some(private_key);
+        self
+    }
+
+    /// Forward the TLS connections for other server names than `server_names` untouched to `backend`, i.e: an https site
+    /// sharing port 443 with the server. `*.example.com` matches the subdomains of example.com
+    pub fn tls_sni_passthrough(mut self, server_names: Vec<String>, backend: (Host, u16)) -> Self {
+        self.server.tls_server_name = server_names;
+        self.server.tls_sni_passthrough = Some(backend);
+        self
+    }
+
+    /// Only accept the clients with a certificate signed by these CAs (mTLS)
+    pub fn tls_client_ca_certs(mut self, ca_certs: PathBuf) -> Self {
+        self.server.tls_client_ca_certs = Some(ca_certs);
+        self
+    }
+
+    /// Let the kernel encrypt the TLS connections of the clients once their handshake is done (kTLS), on linux
+    pub fn tls_ktls(mut self, enabled: bool) -> Self {
+        self.server.tls_ktls = enabled;
+        self
+    }
+
+    /// Only allow the tunnels to this destination, i.e: google.com:443. Can be called multiple times
+    pub fn add_restrict_to(mut self, dest: impl Into<String>) -> Self {
+        self.server.restrict_to.get_or_insert_default().push(dest.into());
+        self
+    }
+
+    /// Only allow the clients using this http upgrade path prefix. Can be called multiple times
+    pub fn add_restrict_http_upgrade_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.server
+            .restrict_http_upgrade_path_prefix
+            .get_or_insert_default()
+            .push(prefix.into());
+        self
+    }
+
+    /// Restrictions file, reloaded when it changes
+    pub fn restrict_config(mut self, path: PathBuf) -> Self {
+        self.server.restrict_config = Some(path);
+        self
+    }
+
+    pub fn psk(mut self, psk: impl Into<String>) -> Self {
+        self.server.psk = Some(Secret::new(psk.into()));
+        self
+    }
+
+    /// Encrypt the tunnels end to end with Noise, only accepting the clients with one of `client_public_keys`.
+    /// No key accepts any client
+    pub fn noise(mut self, private_key: NoiseKey, client_public_keys: Vec<NoiseKey>) -> Self {
+        self.server.noise_private_key = Some(private_key);
+        self.server.noise_client_public_key = client_public_keys;
+        self
+    }
+
+    /// None to never ping the clients
+    pub fn websocket_ping_frequency(mut self, frequency: Option<Duration>) -> Self {
+        self.server.websocket_ping_frequency = frequency;
+        self
+    }
+
+    /// Zero to not wait for the clients to answer the close frames
+    pub fn websocket_close_timeout(mut self, timeout: Duration) -> Self {
+        self.server.websocket_close_timeout = timeout;
+        self
+    }
+
+    pub fn add_websocket_close_code(mut self, reason: CloseReason, code: u16) -> Self {
+        self.server.websocket_close_code.push((reason, code));
+        self
+    }
+
+    pub fn metrics_listen(mut self, bind: SocketAddr) -> Self {
+        self.server.metrics_listen = Some(bind);
+        self
+    }
+
+    pub fn admin_listen(mut self, bind: SocketAddr) -> Self {
+        self.server.admin_listen = Some(bind);
+        self
+    }
+
+    pub fn max_clients(mut self, max: usize) -> Self {
+        self.server.max_clients = Some(max);
+        self
+    }
+
+    /// Reject the tunnels of the clients older than this version
+    pub fn require_min_client_version(mut self, version: ClientVersion) -> Self {
+        self.server.require_min_client_version = Some(version);
+        self
+    }
+
+    /// Handle the connections of this protocol, recognized on the listening port, instead of the default. Can be called multiple times
+    pub fn add_protocol_handler(mut self, protocol: SniffedProtocol, handler: ProtocolHandler) -> Self {
+        self.server.protocol_handler.push((protocol, handler));
+        self
+    }
+
+    /// Allocate a subdomain of this domain to the reverse http ingress of the clients asking for one
+    pub fn http_ingress_domain(mut self, domain: impl Into<String>) -> Self {
+        self.server.http_ingress_domain = Some(domain.into());
+        self
+    }
+
+    /// Reserve a subdomain of the http ingress domain for the clients of this identity
+    pub fn add_http_ingress_reserve(mut self, name: impl Into<String>, identity: impl Into<String>) -> Self {
+        self.server.http_ingress_reserve.push((name.into(), identity.into()));
+        self
+    }
+
+    /// Pad the websocket traffic of the tunnels with random frames, up to this percentage of its size
+    pub fn traffic_padding(mut self, percent: u8) -> Self {
+        self.server.traffic_padding = Some(percent);
+        self
+    }
+
+    /// Delay each websocket write of the tunnels by a random time, up to this duration
+    pub fn traffic_jitter(mut self, max_jitter: Duration) -> Self {
+        self.server.traffic_jitter = Some(max_jitter);
+        self
+    }
+
+    /// Answer the requests the server refuses with this status, instead of the plain errors of wstunnel
+    pub fn reject_status(mut self, status: StatusCode) -> Self {
+        self.server.reject_status = Some(status);
+        self
+    }
+
+    /// Body of the response to the requests the server refuses, read from this file
+    pub fn reject_body(mut self, path: impl Into<PathBuf>) -> Self {
+        self.server.reject_body = Some(path.into());
+        self
+    }
+
+    pub fn add_reject_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.server.reject_header.push((name, value));
+        self
+    }
+
+    /// Redirect the requests the server refuses to this location
+    pub fn reject_redirect(mut self, location: impl Into<String>) -> Self {
+        self.server.reject_redirect = Some(location.into());
+        self
+    }
+
+    /// Check the configuration like the command line does
+    pub fn build(self) -> anyhow::Result<Server> {
+        let server = self.server;
+        parse_server_url(server.remote_addr.as_str())?;
+        if server.restrict_config.is_some()
+            && (server.restrict_to.is_some() || server.restrict_http_upgrade_path_prefix.is_some())
+        {
+            return Err(anyhow!(
+                "the restrictions file cannot be used along restrictions on destinations or path prefixes"
+            ));
+        }
+        if server.tls_certificate.is_some() != server.tls_private_key.is_some() {
+            return Err(anyhow!("the certificate and its private key must be set together"));
+        }
+        check_websocket_close_codes(&server.websocket_close_code)?;
+
+        Ok(server)
+    }
+}
+
+fn check_websocket_close_codes(codes: &[(CloseReason, u16)]) -> anyhow::Result<()> {
+    for (reason, code) in codes {
+        if *reason == CloseReason::Normal {
+            return Err(anyhow!("the normal close is always told with the normal websocket close code"));
+        }
+        if !WsCloseCodes::VALID_CODES.contains(code) {
+            return Err(anyhow!(
+                "invalid websocket close code {code} for {reason}, expected a code between {} and {}",
+                WsCloseCodes::VALID_CODES.start(),
+                WsCloseCodes::VALID_CODES.end()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp() -> LocalProtocol {
+        LocalProtocol::Tcp {
+            proxy_protocol: false,
+            resume: None,
+            idle_timeout: None,
+            mirror: None,
+            balancing: None,
+            keepalive: None,
+        }
+    }
+
+    #[test]
+    fn test_client_builder() {
+        let client = ClientBuilder::new(Url::parse("wss://wstunnel.example.com").unwrap())
+            .add_local_tunnel(
+                tcp(),
+                "127.0.0.1:1212".parse().unwrap(),
+                (Host::Domain("google.com".to_string()), 443),
+            )
+            .add_reverse_tunnel(
+                LocalProtocol::ReverseTcp {
+                    resume: None,
+                    idle_timeout: None,
+                    v6only: None,
+                    access: AccessList::default(),
+                    keepalive: None,
+                },
+                "[::]:2222".parse().unwrap(),
+                (Host::Domain("localhost".to_string()), 22),
+            )
+            .build()
+            .unwrap();
+        assert_eq!(client.local_to_remote.len(), 1);
+        assert_eq!(client.remote_to_local.len(), 1);
+
+        let reverse_as_local = ClientBuilder::new(Url::parse("wss://wstunnel.example.com").unwrap())
+            .add_reverse_tunnel(tcp(), "[::]:2222".parse().unwrap(), (Host::Domain("localhost".to_string()), 22))
+            .build();
+        assert!(reverse_as_local.is_err());
+
+        let invalid_label = ClientBuilder::new(Url::parse("wss://wstunnel.example.com").unwrap())
+            .add_tunnel(LocalToRemote {
+                local_protocol: tcp(),
+                local: "127.0.0.1:1212".parse().unwrap(),
+                remote: (Host::Domain("google.com".to_string()), 443),
+                label: Some("not a label".to_string()),
+                dscp: None,
+                v6only: None,
+                access: AccessList::default(),
+                accept_limits: AcceptLimits::default(),
+                port_count: 1,
+            })
+            .build();
+        assert!(invalid_label.is_err());
+
+        assert!(
+            ClientBuilder::new(Url::parse("ftp://wstunnel.example.com").unwrap())
+                .build()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_server_builder() {
+        let server = ServerBuilder::new(Url::parse("wss://0.0.0.0:443").unwrap())
+            .add_restrict_to("google.com:443")
+            .add_restrict_to("localhost:22")
+            .build()
+            .unwrap();
+        assert_eq!(
+            server.restrict_to,
+            Some(vec!["google.com:443".to_string(), "localhost:22".to_string()])
+        );
+
+        let conflicting_restrictions = ServerBuilder::new(Url::parse("wss://0.0.0.0:443").unwrap())
+            .add_restrict_to("google.com:443")
+            .restrict_config(PathBuf::from("restrictions.yaml"))
+            .build();
+        assert!(conflicting_restrictions.is_err());
+
+        let invalid_close_code = ServerBuilder::new(Url::parse("wss://0.0.0.0:443").unwrap())
+            .add_websocket_close_code(CloseReason::Idle, 1001)
+            .build();
+        assert!(invalid_close_code.is_err());
+    }
+
+    // The builders must not drift from the defaults of the command line
+    #[cfg(feature = "clap")]
+    #[test]
+    fn test_builders_defaults_match_command_line() {
+        use clap::{Args, FromArgMatches};
+
+        let matches = Client::augment_args(clap::Command::new("client"))
+            .try_get_matches_from(["client", "wss://wstunnel.example.com"])
+            .unwrap();
+        let client = Client::from_arg_matches(&matches).unwrap();
+        let built = ClientBuilder::new(Url::parse("wss://wstunnel.example.com").unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(format!("{built:?}"), format!("{client:?}"));
+
+        let matches = Server::augment_args(clap::Command::new("server"))
+            .try_get_matches_from(["server", "wss://0.0.0.0:443"])
+            .unwrap();
+        let server = Server::from_arg_matches(&matches).unwrap();
+        let built = ServerBuilder::new(Url::parse("wss://0.0.0.0:443").unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(format!("{built:?}"), format!("{server:?}"));
+    }
+}
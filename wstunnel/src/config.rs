@@ -1,6 +1,14 @@
-use crate::tunnel::LocalProtocol;
+use crate::protocols::tls::TlsFingerprint;
+use crate::tunnel::client::{AcceptLimits, Browser, OnListenerError, ReconnectHook, RedirectPolicy, SplitRequests};
+use crate::tunnel::noise::NoiseKey;
+use crate::tunnel::protocol::client_version::ClientVersion;
+use crate::tunnel::protocol::close_reason::CloseReason;
+use crate::tunnel::server::{AuthHook, ProtocolHandler, SniffedProtocol};
+use crate::tunnel::{AccessList, LocalProtocol};
+use hyper::http::StatusCode;
 pub use hyper::http::{HeaderName, HeaderValue};
-use std::net::SocketAddr;
+pub use secret::Secret;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::time::Duration;
 use tokio_rustls::rustls::pki_types::DnsName;
@@ -16,33 +24,123 @@ pub struct Client {
     /// 'tcp://1212:google.com:443'      =>       listen locally on tcp on port 1212 and forward to google.com on port 443
     /// 'tcp://2:n.lan:4?proxy_protocol' =>       listen locally on tcp on port 2 and forward to n.lan on port 4
     ///                                           Send a proxy protocol header v2 when establishing connection to n.lan
+    /// 'tcp://2:n.lan:4?resume_buffer=1048576&resume_timeout_sec=60'
+    ///                                           keep the tunnel open when the connection with the server drops, and resume it once reconnected.
+    ///                                           resume_buffer is the max number of bytes kept in each direction until acknowledged by the peer
+    ///                                           resume_timeout_sec is how long the tunnel can stay disconnected before being closed [default: 60]
+    ///                                           The client can reconnect from another network/ip. Also available for socks5 and http proxy
+    /// 'tcp://2:n.lan:4?idle_timeout_sec=600'    close the tunnel once no data went through it for 600sec. The server may enforce a lower one
+    /// 'tcp://2:n.lan:4?mirror=n2.lan:4'         the server also sends a copy of the traffic going to n.lan to n2.lan:4, and ignores its responses.
+    ///                                           Useful to test a new backend with real traffic. The server restrictions must allow n2.lan:4 too
+    /// 'tcp://2:n.lan:4?keepalive_idle_sec=300&keepalive_interval_sec=30&keepalive_count=5'
+    ///                                           tcp keepalive of the cnx accepted locally and of the one of the server to n.lan, to keep long idle
+    ///                                           cnx, i.e: of a database, open through the middleboxes dropping the silent ones [default: 60, 10 and 3]
+    /// 'tcp://2:n.lan:4?label=ci-job-1234'      tag the tunnel, the server shows the label in its logs and metrics. Available for every protocol
+    ///                                           at most 64 letters, digits, '.', '_' or '-'
+    /// 'tcp://0:n.lan:4'                =>       listen locally on a free port picked by the OS, and print it on stdout as a json line
+    ///                                           {"event":"listening","local":"127.0.0.1:41235","protocol":"tcp","remote":"n.lan:4"}
+    /// 'tcp://[::]:2:n.lan:4?v6only=false'      listen on both ipv4 and ipv6, or only on ipv6 with v6only=true, instead of the default of the OS.
+    ///                                           Linux is dual stack by default, Windows and the BSDs are not. Also available for udp and http proxy
+    /// 'tcp://2:n.lan:4?max_conns=100&accept_rate=50/s'
+    ///                                           at most 100 tunnels open at the same time and 50 new ones per second for this listener. Over them,
+    ///                                           the cnx waits with overflow=queue [default], or is closed with overflow=reject. Available for every protocol but stdio
+    /// 'tcp://2000-2100:n.lan:3000-3100'        listen locally on the ports 2000 to 2100 and forward each one to the matching port of 3000 to 3100 on n.lan
+    ///                                           i.e: for passive ftp or rtp. Both ranges must have as many ports. Also available for udp
+    /// 'tcp://8080:b1.lan:80,b2.lan:80?lb=round_robin&health_check_sec=30'
+    ///                                           spread the connections over b1.lan and b2.lan, in turn with lb=round_robin or at random with lb=random.
+    ///                                           The server fails over to the next destination when one is unreachable, and skips it for
+    ///                                           health_check_sec. The server restrictions must allow every destination
     ///
     /// 'udp://1212:1.1.1.1:53'          =>       listen locally on udp on port 1212 and forward to cloudflare dns 1.1.1.1 on port 53
     /// 'udp://1212:1.1.1.1:53?timeout_sec=10'    timeout_sec on udp force close the tunnel after 10sec. Set it to 0 to disable the timeout [default: 30]
+    /// 'udp://5060:pbx.lan:5060?dscp=46'        mark the packets of the connection to the server carrying the tunnel with this DSCP codepoint,
+    ///                                           instead of the one of --dscp. i.e: 46 (EF) for VoIP. Available for every protocol
     ///
     /// 'socks5://[::1]:1212'            =>       listen locally with socks5 on port 1212 and forward dynamically requested tunnel
     /// 'socks5://[::1]:1212?login=admin&password=admin' => listen locally with socks5 on port 1212 and only accept connection with login=admin and password=admin
+    /// 'socks5://0.0.0.0:1212?allow=10.0.0.0/8&deny=0.0.0.0/0'
+    ///                                           only accept the cnx of the peers in allow, and refuse the ones in deny. Ips or cidrs separated by ','
+    ///                                           Checked when the cnx is accepted, before any handshake. Also available for tcp and http proxy
+    /// 'socks5://[::1]:1212?resolve=local'        resolve the hostnames of the socks5 requests on the client instead of on the server [default: remote]
+    /// 'socks5://[::1]:1212?resolve=fake_ip&fake_dns=127.0.0.1:5353'
+    ///                                           answer the dns queries received on 127.0.0.1:5353 with fake ips of 198.18.0.0/15, and turn them back
+    ///                                           into their hostname when an app connects to them through the socks5 server, so the server still
+    ///                                           receives the hostname from the apps that resolve it before connecting
     ///
     /// 'http://[::1]:1212'              =>       start a http proxy on port 1212 and forward dynamically requested tunnel
     /// 'http://[::1]:1212?login=admin&password=admin' => start a http proxy on port 1212 and only accept connection with login=admin and password=admin
+    /// 'http://[::1]:1212?connect_ports=443,8000-8999'
+    ///                                           only accept the CONNECT requests to these ports, with a 403 for the others [default: any port]
+    ///                                           Plain http requests, i.e: GET http://n.lan/, are rewritten for n.lan and closed after its response
+    /// 'http://[::1]:1212?path_headers'
+    ///                                           answer the CONNECT requests with the Via and X-Wstunnel-RTT headers, the round trip time of the
+    ///                                           websocket pings with the server smoothed over all the tunnels, i.e: X-Wstunnel-RTT: 23ms
     ///
     /// 'tproxy+tcp://[::1]:1212'        =>       listen locally on tcp on port 1212 as a *transparent proxy* and forward dynamically requested tunnel
     /// 'tproxy+udp://[::1]:1212?timeout_sec=10'  listen locally on udp on port 1212 as a *transparent proxy* and forward dynamically requested tunnel
     ///                                           linux only and requires sudo/CAP_NET_ADMIN
     ///
     /// 'stdio://google.com:443'         =>       listen for data from stdio, mainly for `ssh -o ProxyCommand="wstunnel client -L stdio://%h:%p ws://localhost:8080" my-server`
+    /// 'stdio+udp://1.1.1.1:53?timeout_sec=10'   forward datagrams read from stdio to udp 1.1.1.1:53. Each datagram is prefixed by its length, as a 2 bytes big endian integer.
+    ///                                           Datagrams received back are written to stdout with the same framing. timeout_sec behave like for udp [default: 30]
     ///
     /// 'unix:///tmp/wstunnel.sock:g.com:443' =>  listen for data from unix socket of path /tmp/wstunnel.sock and forward to g.com:443
-    #[cfg_attr(feature = "clap", arg(short='L', long, value_name = "{tcp,udp,socks5,stdio,unix}://[BIND:]PORT:HOST:PORT", value_parser = parsers::parse_tunnel_arg, verbatim_doc_comment))]
+    /// 'unix:///tmp/w.sock:g.com:443?allowed_uids=0,1000'  only accept the connections of the processes run by the users 0 and 1000
+    ///                                           the uid, gid and pid of the connecting processes are logged
+    /// 'unix:///tmp/w.sock:g.com:443?mode=660&owner=app&group=app'  set the permissions of the socket file
+    /// 'unix:///run/app/w.sock:g.com:443?remove_stale&recreate'  remove the socket left by a dead process instead of failing to bind,
+    ///                                           and create the socket again when it or its directory is removed, i.e: by a tmpfs cleanup
+    /// 'unix://@wstunnel:g.com:443'    listen on the abstract unix socket wstunnel, Linux only
+    ///
+    /// 'sctp://3868:10.0.0.2:3868'      =>       listen locally for sctp associations on port 3868 and forward them to 10.0.0.2:3868 over sctp. linux only
+    ///
+    /// 'vsock://any:1212:g.com:443'    =>       listen for virtio-vsock cnx on port 1212 and forward to g.com:443. The cid to bind can be a number or any
+    ///                                           linux only and requires wstunnel to be built with the vsock feature
+    #[cfg_attr(feature = "clap", arg(short='L', long, value_name = "{tcp,udp,sctp,socks5,stdio,unix,vsock}://[BIND:]PORT:HOST:PORT", value_parser = parsers::parse_tunnel_arg, verbatim_doc_comment))]
     pub local_to_remote: Vec<LocalToRemote>,
 
     /// Listen on remote and forwards traffic from local. Can be specified multiple times. Only tcp is supported
     /// examples:
     /// 'tcp://1212:google.com:443'      =>     listen on server for incoming tcp cnx on port 1212 and forward to google.com on port 443 from local machine
+    /// 'tcp://1212:localhost:22?resume_buffer=1048576&resume_timeout_sec=60'
+    ///                                         keep the connections open when the client loses its connection with the server, even if it comes back from another network
+    /// 'tcp://1212:localhost:22?idle_timeout_sec=600'
+    ///                                         close the connections once no data went through them for 600sec
+    /// 'tcp://1212:localhost:22?keepalive_idle_sec=300&keepalive_interval_sec=30&keepalive_count=5'
+    ///                                         tcp keepalive of the cnx accepted by the server and of the one of the client to localhost:22, see -L
+    /// 'tcp://127.0.0.1:1212:localhost:22'
+    ///                                         listen on the loopback of the server only. The restrictions of the server can force the address, see its bind
+    /// 'tcp://1212:localhost:22?label=ci-job-1234'
+    ///                                         tag the tunnel, the server shows the label in its logs and metrics
+    /// 'tcp://1212:localhost:22?dscp=46'
+    ///                                         mark the packets of the connection to the server carrying the tunnel with this DSCP codepoint
+    /// 'tcp://[::]:1212:localhost:22?v6only=false'
+    ///                                         listen on both ipv4 and ipv6 on the server, or only on ipv6 with v6only=true. Also available for udp and http proxy
+    /// 'tcp://0.0.0.0:1212:localhost:22?allow=10.0.0.0/8'
+    ///                                         only accept the cnx of these peers on the server, see -L. Also available for socks5 and http proxy
+    /// 'tcp://2000-2100:localhost:3000-3100'
+    ///                                         listen on server on the ports 2000 to 2100 and forward each one to the matching port of 3000 to 3100. Also available for udp
     /// 'udp://1212:1.1.1.1:53'          =>     listen on server for incoming udp on port 1212 and forward to cloudflare dns 1.1.1.1 on port 53 from local machine
+    /// 'udp://1212:1.1.1.1:53?timeout_sec=10&max_flows=100&flow_eviction=evict_idlest'
+    ///                                         timeout_sec close a flow after 10sec of inactivity. Set it to 0 to disable the timeout [default: 30]
+    ///                                         max_flows limit the number of concurrent flows (one per peer) on the server [default: unlimited]
+    ///                                         flow_eviction choose what to do when max_flows is reached: drop_new or evict_idlest [default: drop_new]
     /// 'socks5://[::1]:1212'            =>     listen on server for incoming socks5 request on port 1212 and forward dynamically request from local machine (login/password is supported)
     /// 'http://[::1]:1212'         =>     listen on server for incoming http proxy request on port 1212 and forward dynamically request from local machine (login/password is supported)
+    /// 'http://myapp.example.com:localhost:3000'  =>  forward the http requests received by the server for the virtual host myapp.example.com
+    ///                                         to the local web app on localhost:3000, websockets included. X-Forwarded-* headers are added
+    /// 'http://*.example.com:localhost:3000'      =>  same with a random subdomain of example.com allocated by the server for the session
+    /// 'http://myapp.example.com:localhost:3000?basic_auth=alice:s3cret'  the server asks the visitors for these credentials
+    /// 'http://myapp.example.com:localhost:3000?auth_url=https://auth.lan/oauth2/auth'
+    ///                                         the server asks this url, i.e: oauth2-proxy, if the cookies of the visitor are valid
+    /// 'doh://[::]:443'                 =>     listen on server for DNS-over-HTTPS queries on port 443, on the path /dns-query, and answer them with the dns resolvers of the local machine
+    /// 'dot://853'                      =>     same with DNS-over-TLS. A self-signed certificate is used unless tls_certificate=PATH&tls_private_key=PATH are given
     /// 'unix://wstunnel.sock:g.com:443' =>     listen on server for incoming data from unix socket of path wstunnel.sock and forward to g.com:443 from local machine
+    /// 'unix://w.sock:g.com:443?allowed_uids=1000'  only accept the connections of the processes run by the user 1000 on the server
+    /// 'unix://w.sock:g.com:443?mode=600&owner=app'  set the permissions of the socket file created on the server
+    /// 'unix:///run/app/w.sock:g.com:443?remove_stale&recreate'  remove the socket left by a dead process instead of failing to bind,
+    ///                                         and create the socket again when it or its directory is removed, i.e: by a tmpfs cleanup
+    /// 'unix://@wstunnel:g.com:443'    listen on the abstract unix socket wstunnel of the server, Linux only
     #[cfg_attr(feature = "clap", arg(short='R', long, value_name = "{tcp,udp,socks5,unix}://[BIND:]PORT:HOST:PORT", value_parser = parsers::parse_reverse_tunnel_arg, verbatim_doc_comment))]
     pub remote_to_local: Vec<LocalToRemote>,
 
@@ -51,6 +149,26 @@ pub struct Client {
     #[cfg_attr(feature = "clap", arg(long, value_name = "INT", verbatim_doc_comment))]
     pub socket_so_mark: Option<u32>,
 
+    /// Mark the packets of the connections to the server with this DSCP codepoint, for the QoS of the network.
+    /// i.e: 46 (Expedited Forwarding) for VoIP. A tunnel can use another one with its dscp option
+    #[cfg_attr(feature = "clap", arg(long, value_name = "0-63", value_parser = parsers::parse_dscp, verbatim_doc_comment))]
+    pub dscp: Option<u8>,
+
+    /// (linux only) Connect to the server through this network interface, with SO_BINDTODEVICE. i.e: eth1
+    /// For multi-homed hosts to choose the uplink without policy routing. Require CAP_NET_RAW on old kernels
+    #[cfg_attr(feature = "clap", arg(long, value_name = "INTERFACE", verbatim_doc_comment))]
+    pub bind_interface: Option<String>,
+
+    /// Connect to the server from this source ip. i.e: 10.0.0.5
+    /// The addresses of the server of the other ip family are not tried
+    #[cfg_attr(feature = "clap", arg(long, value_name = "IP", verbatim_doc_comment))]
+    pub bind_address: Option<IpAddr>,
+
+    /// (linux only) Enable TCP Fast Open when connecting to the server, to save a round trip when opening new connections.
+    /// Require the server to also enable TCP Fast Open, and net.ipv4.tcp_fastopen sysctl to allow it on client side.
+    #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
+    pub tcp_fastopen: bool,
+
     /// Client will maintain a pool of open connection to the server, in order to speed up the connection process.
     /// This option set the maximum number of connection that will be kept open.
     /// This is useful if you plan to create/destroy a lot of tunnel (i.e: with socks5 to navigate with a browser)
@@ -61,6 +179,39 @@ pub struct Client {
     )]
     pub connection_min_idle: u32,
 
+    /// Frequency at which idle connections of the pool are checked to be still alive.
+    /// Connections closed by the server or by a middlebox are discarded and re-opened, so a new tunnel never picks a stale one
+    /// Connections are also always checked right before being used. Disabled by default
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub connection_health_check_interval: Option<Duration>,
+
+    /// Maximum time a connection can stay idle in the pool before being closed and replaced by a fresh one.
+    /// Useful if a middlebox (NAT, firewall, CDN) silently drops idle connections after some time. 30s by default
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub connection_max_idle_age: Option<Duration>,
+
+    /// On startup, maximum time to wait for the pool to open its `connection-min-idle` connections before binding local listeners.
+    /// If the pool is not ready after this delay, the client starts anyway and keeps filling the pool in the background.
+    /// Set it to 0 to not wait for the pool
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "30s",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub connection_warmup_timeout: Duration,
+
     /// The maximum of time in seconds while we are going to try to connect to the server before failing the connection/tunnel request
     #[cfg_attr(feature = "clap", arg(
         long,
@@ -76,6 +227,7 @@ pub struct Client {
     /// This delay is the maximum of time the client will wait before trying to reconnect to the server in case of failure.
     /// The client follows an exponential backoff strategy until it reaches this maximum delay
     /// By default, the client tries to reconnect every 1 second
+    /// The delays are randomized, so the clients disconnected at the same time do not all reconnect at the same time
     #[cfg_attr(feature = "clap", arg(
         long,
         value_name = "DURATION(s|m|h)",
@@ -86,6 +238,94 @@ pub struct Client {
     ))]
     pub reverse_tunnel_connection_retry_max_backoff: Duration,
 
+    /// Exit with an error once a reverse tunnel failed to connect to the server this many times in a row
+    /// Let systemd/kubernetes restart the client instead of retrying forever. Disabled by default
+    #[cfg_attr(feature = "clap", arg(long, value_name = "INT", verbatim_doc_comment))]
+    pub reverse_tunnel_connection_max_retries: Option<u32>,
+
+    /// Program or http(s) url told when a reverse tunnel loses (disconnected) or recovers (reconnected) its connection
+    /// with the server, or gives up (gave_up) after --reverse-tunnel-connection-max-retries. i.e: to alert when a device loses its tunnel
+    /// A program gets the event as json on its stdin and its kind in the WSTUNNEL_EVENT env var, an url gets it POSTed as json
+    #[cfg_attr(feature = "clap", arg(long, value_name = "PATH|URL", value_parser = parsers::parse_reconnect_hook, verbatim_doc_comment))]
+    pub reverse_tunnel_hook: Option<ReconnectHook>,
+
+    /// Ask the server to probe the reverse tunnels waiting for a connection at this interval, i.e: 10s
+    /// The server unbinds the listener of a client that missed 3 probes, instead of waiting for its idle timeout.
+    /// Only for the reverse tunnels that are not resumable, and ignored by the servers not supporting it. Disabled by default
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub reverse_tunnel_probe_interval: Option<Duration>,
+
+    /// Exit with an error once the server has been unreachable for this long, i.e: 5m
+    /// Let systemd/kubernetes restart the client instead of retrying silently forever.
+    /// The server is only probed when connecting to it, use --connection-min-idle or reverse tunnels to keep probing it. Disabled by default
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub exit_if_disconnected_for: Option<Duration>,
+
+    /// What to do when a -L listener cannot be bound, i.e: because its port is already in use
+    ///   - fail: exit with the code 3
+    ///   - skip: start the other tunnels without it, and exit if none could be started
+    ///   - retry: start the other tunnels, and try again to bind it every 10s. Exit if none could be started after a minute
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "fail|skip|retry",
+        default_value = "fail",
+        value_parser = parsers::parse_on_listener_error,
+        verbatim_doc_comment
+    ))]
+    pub on_listener_error: OnListenerError,
+
+    /// Serve health probes on http://<IP:PORT>, i.e: 127.0.0.1:9090
+    /// /healthz answers as long as the client runs, /readyz fails while the server is unreachable
+    #[cfg_attr(feature = "clap", arg(long, value_name = "IP:PORT", verbatim_doc_comment))]
+    pub health_listen: Option<SocketAddr>,
+
+    /// Touch this file, i.e: /tmp/healthy, each time the server is reached: a connection to it succeeds, it answers a websocket ping,
+    /// or the idle connections pass their --connection-health-check-interval. The file is removed while the server is unreachable.
+    /// For the exec health checks of the scratch/distroless containers without curl. Set --connection-health-check-interval or
+    /// --connection-min-idle for the server to be checked while no tunnel is used
+    #[cfg_attr(feature = "clap", arg(long, value_name = "FILE_PATH", verbatim_doc_comment))]
+    pub liveness_file: Option<PathBuf>,
+
+    /// Serve the live statistics of the tunnels as json on http://<IP:PORT>/tunnels, i.e: 127.0.0.1:9091
+    /// Watch them with `wstunnel top --admin 127.0.0.1:9091`. Bind it on localhost, the api has no authentication
+    #[cfg_attr(feature = "clap", arg(long, value_name = "IP:PORT", verbatim_doc_comment))]
+    pub admin_listen: Option<SocketAddr>,
+
+    /// Shell command run when a tunnel is opened, i.e: to punch a firewall or update a dynamic dns
+    /// It gets the metadata of the tunnel in the env vars WSTUNNEL_EVENT, WSTUNNEL_SIDE, WSTUNNEL_TUNNEL_ID, WSTUNNEL_PROTOCOL,
+    /// WSTUNNEL_REMOTE, WSTUNNEL_PEER and WSTUNNEL_LABEL, and as json on its stdin. The tunnel does not wait for it
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "CMD", env = "WSTUNNEL_ON_TUNNEL_CONNECT", verbatim_doc_comment)
+    )]
+    pub on_tunnel_connect: Option<String>,
+
+    /// Shell command run when a tunnel is closed, i.e: to send a notification
+    /// Same as --on-tunnel-connect, with WSTUNNEL_DURATION_SECS, WSTUNNEL_TX_BYTES, WSTUNNEL_RX_BYTES and
+    /// WSTUNNEL_CLOSE_REASON (normal, reset, timeout, error, corrupted or idle) in addition
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "CMD", env = "WSTUNNEL_ON_TUNNEL_CLOSE", verbatim_doc_comment)
+    )]
+    pub on_tunnel_close: Option<String>,
+
+    /// Keep running and let other programs (i.e: a tray app) control the client through a JSON-RPC 2.0 api on this local socket.
+    /// A unix socket path, or a named pipe on windows (i.e: \\.\pipe\wstunnel). One json message per line.
+    /// Tunnels can be started and stopped, the status and the logs of the client watched.
+    /// Call the `rpc.discover` method to get the OpenRPC schema of the api
+    #[cfg_attr(feature = "clap", arg(long, value_name = "PATH", verbatim_doc_comment))]
+    pub control_socket: Option<PathBuf>,
+
     /// Domain name that will be used as SNI during TLS handshake
     /// Warning: If you are behind a CDN (i.e: Cloudflare) you must set this domain also in the http HOST header.
     ///          or it will be flagged as fishy and your request rejected
@@ -110,6 +350,12 @@ pub struct Client {
     #[cfg_attr(feature = "clap", arg(long, verbatim_doc_comment))]
     pub tls_ech_enable: bool,
 
+    /// Offer the cipher suites and key exchange groups of this browser during TLS handshake, in the order it does.
+    /// The extensions of the handshake stay the ones of rustls, so the JA3/JA4 fingerprint gets closer to the one of the browser,
+    /// but does not match it. Use it with --camouflage to look like the same browser in the http requests
+    #[cfg_attr(feature = "clap", arg(long, value_name = "rustls|chrome|firefox", default_value = "rustls", value_parser = parsers::parse_tls_fingerprint, verbatim_doc_comment))]
+    pub tls_fingerprint: TlsFingerprint,
+
     /// Enable TLS certificate verification.
     /// Disabled by default. The client will happily connect to any server with self-signed certificate.
     #[cfg_attr(feature = "clap", arg(long, verbatim_doc_comment))]
@@ -122,11 +368,12 @@ pub struct Client {
             short = 'p',
             long,
             value_name = "USER:PASS@HOST:PORT",
+            value_parser = parsers::parse_secret,
             verbatim_doc_comment,
             env = "HTTP_PROXY"
         )
     )]
-    pub http_proxy: Option<String>,
+    pub http_proxy: Option<Secret<String>>,
 
     /// If set, will use this login to connect to the http proxy. Override the one from --http-proxy
     #[cfg_attr(
@@ -136,35 +383,134 @@ pub struct Client {
     pub http_proxy_login: Option<String>,
 
     /// If set, will use this password to connect to the http proxy. Override the one from --http-proxy
+    /// Secrets can be read with file:PATH, env:NAME, exec:COMMAND or cred:NAME (systemd credential)
     #[cfg_attr(
         feature = "clap",
         arg(
             long,
             value_name = "PASSWORD",
+            value_parser = parsers::parse_secret,
             verbatim_doc_comment,
             env = "WSTUNNEL_HTTP_PROXY_PASSWORD"
         )
     )]
-    pub http_proxy_password: Option<String>,
+    pub http_proxy_password: Option<Secret<String>>,
 
     /// Use a specific prefix that will show up in the http path during the upgrade request.
     /// Useful if you need to route requests server side but don't have vhosts
     /// When using mTLS this option overrides the default behavior of using the common name of the
     /// client's certificate. This will likely result in the wstunnel server rejecting the connection.
+    /// Secrets can be read with file:PATH, env:NAME, exec:COMMAND or cred:NAME (systemd credential)
     #[cfg_attr(feature = "clap", arg(
         short = 'P',
         long,
         default_value = DEFAULT_CLIENT_UPGRADE_PATH_PREFIX,
+        value_parser = parsers::resolve_secret,
         verbatim_doc_comment,
         env = "WSTUNNEL_HTTP_UPGRADE_PATH_PREFIX"
     ))]
     pub http_upgrade_path_prefix: String,
 
+    /// Stable name of this client, i.e: the serial number of the device it runs on. The server shows it in its logs,
+    /// metrics and admin api. It is not authenticated, the owned ports and reserved subdomains of the reverse tunnels
+    /// are reserved for the path prefix followed by the device id, i.e: team-a/sensor-42.
+    /// At most 64 letters, digits, '.', '_' or '-'
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "NAME",
+        value_parser = parsers::parse_device_id,
+        env = "WSTUNNEL_DEVICE_ID",
+        verbatim_doc_comment
+    ))]
+    pub device_id: Option<String>,
+
+    /// Read the device id from this file. A random one is written to it on the first run, so each device keeps its own
+    /// across restarts without being configured one by one
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "FILE_PATH", conflicts_with = "device_id", verbatim_doc_comment)
+    )]
+    pub device_id_file: Option<PathBuf>,
+
+    /// Write to this file the restrictions the server needs to accept the tunnels of this client (-L/-R) and its
+    /// path prefix, then exit without connecting. Load it on the server with --restrict-config
+    #[cfg_attr(feature = "clap", arg(long, value_name = "FILE_PATH", verbatim_doc_comment))]
+    pub emit_restrictions: Option<PathBuf>,
+
+    /// Print the configuration parsed from the arguments and exit. Passwords and keys are redacted
+    #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
+    pub dump_config: bool,
+
+    /// Print the passwords and keys too with --dump-config
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, default_value = "false", requires = "dump_config", verbatim_doc_comment)
+    )]
+    pub show_secrets: bool,
+
     /// Pass authorization header with basic auth credentials during the upgrade request.
     /// If you need more customization, you can use the http_headers option.
+    /// Secrets can be read with file:PATH, env:NAME, exec:COMMAND or cred:NAME (systemd credential)
     #[cfg_attr(feature = "clap", arg(long, value_name = "USER[:PASS]", value_parser = parsers::parse_http_credentials, verbatim_doc_comment))]
     pub http_upgrade_credentials: Option<HeaderValue>,
 
+    /// Authenticate the upgrade requests with the OpenID Connect token obtained with `wstunnel client login`.
+    /// The token is sent as a bearer in the authorization header, and refreshed automatically when it expires.
+    #[cfg_attr(
+        feature = "clap",
+        arg(
+            long,
+            default_value = "false",
+            conflicts_with = "http_upgrade_credentials",
+            verbatim_doc_comment
+        )
+    )]
+    pub oidc: bool,
+
+    /// Path of the OpenID Connect token cache written by `wstunnel client login`
+    /// Default is $XDG_CACHE_HOME/wstunnel/oidc_token.json
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "FILE_PATH", requires = "oidc", verbatim_doc_comment)
+    )]
+    pub oidc_token_cache: Option<PathBuf>,
+
+    /// Sign the upgrade requests with this pre-shared key, and check the server proves it knows it too.
+    /// Use it when TLS is terminated before the server (i.e: a CDN), so the path prefix alone cannot be trusted.
+    /// Requests cannot be replayed, but the clocks of the client and the server must be within 60s of each other.
+    /// Only the requests opening the tunnels are authenticated, not their data: add --noise-private-key for it.
+    /// Not supported by the dns and icmp transports
+    /// Secrets can be read with file:PATH, env:NAME, exec:COMMAND or cred:NAME (systemd credential)
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "SECRET", value_parser = parsers::parse_secret, env = "WSTUNNEL_PSK", verbatim_doc_comment)
+    )]
+    pub psk: Option<Secret<String>>,
+
+    /// Encrypt the data of the tunnels end to end with the server (Noise_IK), with this private key.
+    /// Keeps the tunnels confidential when TLS is terminated before the server (i.e: a CDN).
+    /// Keys are base64 x25519 keys, generate them with `wg genkey` and `wg pubkey`
+    /// Secrets can be read with file:PATH, env:NAME, exec:COMMAND or cred:NAME (systemd credential)
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BASE64_KEY",
+        value_parser = parsers::parse_noise_private_key,
+        requires = "noise_server_public_key",
+        env = "WSTUNNEL_NOISE_PRIVATE_KEY",
+        verbatim_doc_comment
+    ))]
+    pub noise_private_key: Option<NoiseKey>,
+
+    /// Public key of the server for the noise encryption of the tunnels, the server logs it on startup
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BASE64_KEY",
+        value_parser = parsers::parse_noise_key,
+        requires = "noise_private_key",
+        verbatim_doc_comment
+    ))]
+    pub noise_server_public_key: Option<NoiseKey>,
+
     /// Frequency at which the client will send websocket pings to the server.
     /// Set to zero to disable.
     #[cfg_attr(feature = "clap", arg(
@@ -182,6 +528,198 @@ pub struct Client {
     #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
     pub websocket_mask_frame: bool,
 
+    /// Maximum size in bytes of the websocket frames the client accepts to receive. Accept k and m suffixes (KiB, MiB)
+    /// The client and the server advertise their maximum during the upgrade request, and each side never sends frames bigger than what its peer accepts.
+    /// Peers running older versions do not advertise it and are still accepted frames up to 64m.
+    /// With http2 transport, it is advertised as the max frame size of the connection, within the 16k..16m bounds of http2.
+    /// Lower it if a CDN or a proxy in the middle fragments or rejects big websocket messages. Minimum is 64k
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BYTES",
+        default_value = "32m",
+        value_parser = parsers::parse_frame_size,
+        verbatim_doc_comment
+    ))]
+    pub websocket_max_frame_size: usize,
+
+    /// How long to wait for the peer to answer the close frame of a websocket tunnel, before dropping its connection.
+    /// The close handshake lets the proxies in between see the websocket end cleanly. Set to zero to not wait for it
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(ms|s|m)",
+        default_value = "1s",
+        value_parser = parsers::parse_duration_ms,
+        verbatim_doc_comment
+    ))]
+    pub websocket_close_timeout: Duration,
+
+    /// Websocket close code telling a close reason, when both sides have the error codes, i.e: 'idle=4408'. Can be specified multiple times
+    /// The reasons are reset, timeout and error for the failures of the destination, corrupted and idle. They are told with the codes 4001 to 4005 by default,
+    /// set the ones the proxies in between or the monitoring expect, between 3000 and 4999. The name of the reason is sent along with the code
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "REASON=CODE",
+        value_parser = parsers::parse_websocket_close_code,
+        verbatim_doc_comment
+    ))]
+    pub websocket_close_code: Vec<(CloseReason, u16)>,
+
+    /// Maximum number of bytes read from the local side of a tunnel and not yet sent to the server, when using http2 transport.
+    /// Reading the local side pauses once it is reached, so a slow peer does not make the tunnel buffer unboundedly in memory.
+    /// Websocket transport writes directly to the connection, and is only bounded by the socket buffers. Accept k and m suffixes (KiB, MiB). Minimum is 64k
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BYTES",
+        default_value = "4m",
+        value_parser = parsers::parse_frame_size,
+        verbatim_doc_comment
+    ))]
+    pub max_inflight_per_tunnel: usize,
+
+    /// Maximum memory taken by the copy buffers of all the tunnels. Accept k, m and g suffixes (KiB, MiB, GiB)
+    /// Each tunnel gets a buffer of 64KiB, reused from the ones of the closed tunnels, and the websocket tunnels grow theirs
+    /// while they keep filling it, up to the max frame size. Once the maximum is reached the buffers stop growing, with a lower
+    /// throughput for the busy tunnels, and the buffers of the closed tunnels are freed instead of being kept. Unlimited by default
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parsers::parse_byte_size,
+        verbatim_doc_comment
+    ))]
+    pub max_memory_buffers: Option<u64>,
+
+    /// When using http2 or http1 transport, send the data of a tunnel in a series of short requests, separate from the request
+    /// receiving the data of the server. Needed when a proxy/CDN in the middle buffers the request bodies, but streams the responses.
+    /// Websocket transport is not affected.
+    ///   - never: http2 uses a single bidirectional stream per tunnel, http1 a single streamed upload request
+    ///   - always: always split the requests
+    ///   - auto: split the requests of the http2 tunnels once the server did not answer a tunnel request after 15s
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "never|always|auto",
+        default_value = "auto",
+        value_parser = parsers::parse_split_requests,
+        verbatim_doc_comment
+    ))]
+    pub http_split_requests: SplitRequests,
+
+    /// Carry the tunnels over a single connection with the server, instead of opening a new one for each tunnel.
+    /// Saves the tcp/tls/http handshakes when many short connections go through the tunnels, i.e: with socks5, and stays
+    /// under the limits of the proxies/firewalls on the number of connections. The tunnels share the fate of the connection though.
+    /// Only the -L tunnels are multiplexed, the reverse and resumable ones keep a connection of their own
+    #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
+    pub mux: bool,
+
+    /// Replace the connection with the server once it is this old, before a CDN that limits the life of websockets kills it.
+    /// Set it a bit below the limit of the CDN. Only the connections that tunnels can move away from are replaced,
+    /// the other tunnels have a connection of their own. The new connection is opened beforehand, and the old one is drained:
+    ///   - with --mux, the new tunnels go to the new connection, and the old one closes once its tunnels are done
+    ///   - resumable tunnels (-L tcp://...?resume_buffer=) move to the new connection without losing any byte
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub transport_max_lifetime: Option<Duration>,
+
+    /// Same as --transport-max-lifetime, once the connection carried this many bytes, both directions included.
+    /// Accept k, m and g suffixes (KiB, MiB, GiB)
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parsers::parse_byte_size,
+        verbatim_doc_comment
+    ))]
+    pub transport_max_bytes: Option<u64>,
+
+    /// Send the first bytes of a new tunnel along with the request opening it, instead of waiting for the server to answer.
+    /// Saves a round trip with the server for each tunnel where the client speaks first, i.e: tls or http through socks5.
+    /// The client waits up to 10ms for these bytes, so protocols where the destination speaks first (i.e: ssh) are delayed by it.
+    /// The -L tunnels over websocket and http transports only, and not with --mux which already opens the tunnels without a new connection
+    #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
+    pub early_data: bool,
+
+    /// Add a random segment to the path of each request to the server, i.e: /v1/k3x9qz0p/events instead of /v1/events.
+    /// So the connections do not all ask for the same url. Servers accept it since the path prefix and the suffix are unchanged
+    #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
+    pub random_upgrade_path: bool,
+
+    /// Domain to use as SNI and http HOST header of the connections to the server, one is picked at random for each new connection.
+    /// For a server reachable under several names, i.e: behind a CDN. Takes precedence over --tls-sni-override and the HOST header
+    /// Can be specified multiple time
+    #[cfg_attr(feature = "clap", arg(long, value_name = "DOMAIN_NAME", value_parser = parsers::parse_sni_override, verbatim_doc_comment))]
+    pub rotate_host: Vec<DnsName<'static>>,
+
+    /// Send the headers of a browser with the requests to the server, i.e: user agent, languages, origin and client hints.
+    /// Headers set with -H or --http-headers-file take precedence. Only the http requests look like the ones of the browser,
+    /// the tls handshake stays the one of wstunnel
+    #[cfg_attr(feature = "clap", arg(long, value_name = "browser-chrome|browser-firefox", value_parser = parsers::parse_camouflage, verbatim_doc_comment))]
+    pub camouflage: Option<Browser>,
+
+    /// Maximum number of redirects (301, 302, 307, 308) followed when the server, or a load balancer in front of it, answers one to
+    /// an upgrade request. Permanent redirects are remembered for the next tunnels. Set to zero to never follow them
+    /// Independently of it, a server answering 429 or 503 with a Retry-After header is tried again after the delay it asks for, up to 3 times and 60s
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "NUMBER", default_value = "5", verbatim_doc_comment)
+    )]
+    pub upgrade_max_redirects: u8,
+
+    /// Where the upgrade requests can be redirected to. When redirected from plain text to tls, the certificate of the server is not verified
+    ///   - same-origin: only to another path of the server
+    ///   - same-host: to another scheme or port of the server too, i.e: ws:// to wss://. Never from tls to plain text
+    ///   - any: to any server. The credentials and headers of the requests are sent to it too
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "same-origin|same-host|any",
+        default_value = "same-host",
+        value_parser = parsers::parse_redirect_policy,
+        verbatim_doc_comment
+    ))]
+    pub upgrade_redirect_policy: RedirectPolicy,
+
+    /// Server to switch to when the current one cannot be reached or answers that it is a standby (see --standby-file of the server),
+    /// i.e: the other server of an active-standby pair. Can be specified multiple times, they are tried in order after the main one.
+    /// The credentials and headers of the requests are sent to them too
+    #[cfg_attr(feature = "clap", arg(long, value_name = "ws[s]|http[s]|http[s]1|grpc[s]://wstunnel.server.com[:port]", value_parser = parsers::parse_server_url, verbatim_doc_comment))]
+    pub standby_server: Vec<Url>,
+
+    /// Token sent in the x-wstunnel-affinity header of the requests, so a load balancer in front of the servers keeps the tunnels
+    /// of this client on the same server. A random one is used when --standby-server is given without it
+    #[cfg_attr(feature = "clap", arg(long, value_name = "TOKEN", verbatim_doc_comment))]
+    pub affinity_token: Option<String>,
+
+    /// Debug: record the traffic of each tunnel in a pcap file named after the tunnel id, in this directory.
+    /// Ip and tcp/udp headers are made up from the addresses of both ends of the tunnel. Open the files with wireshark
+    #[cfg_attr(feature = "clap", arg(long, value_name = "DIR_PATH", verbatim_doc_comment))]
+    pub pcap_dir: Option<PathBuf>,
+
+    /// Debug: checksum the data of each tunnel at both ends, and compare the checksums every this many bytes, i.e: 1m.
+    /// To find out whether a proxy/middlebox in between corrupts the tunnels: a tunnel whose data differs at the other end
+    /// is aborted, with an error in the logs and the `corrupted` close reason. Not for the udp tunnels, the resumable ones,
+    /// nor the ones multiplexed with --mux. Ignored by the servers not supporting it
+    #[cfg_attr(feature = "clap", arg(long, value_name = "SIZE", value_parser = parsers::parse_byte_size, verbatim_doc_comment))]
+    pub integrity_check_interval: Option<u64>,
+
+    /// Debug: delay the traffic with the server by this much in each direction, like a distant server would.
+    /// To test how the tunnels behave on a bad network without tc/netem. Only the tcp connections to the server are degraded
+    #[cfg_attr(feature = "clap", arg(long, value_name = "DURATION(ms|s)", value_parser = parsers::parse_duration_ms, verbatim_doc_comment))]
+    pub sim_latency: Option<Duration>,
+
+    /// Debug: lose this percentage of the tcp segments exchanged with the server, i.e: 0.5%.
+    /// The lost segments are retransmitted after a timeout, and hold back the ones behind them, like tcp does
+    #[cfg_attr(feature = "clap", arg(long, value_name = "PERCENT", value_parser = parsers::parse_loss_rate, verbatim_doc_comment))]
+    pub sim_loss: Option<f64>,
+
+    /// Debug: limit the rate of the traffic with the server in each direction, i.e: 10mbit. Accept bit, kbit, mbit and gbit suffixes
+    #[cfg_attr(feature = "clap", arg(long, value_name = "RATE", value_parser = parsers::parse_bit_rate, verbatim_doc_comment))]
+    pub sim_rate: Option<u64>,
+
+    /// Debug: seed of the simulated losses, so they hit the same segments on every run, for reproducible tests
+    #[cfg_attr(feature = "clap", arg(long, value_name = "SEED", verbatim_doc_comment))]
+    pub sim_seed: Option<u64>,
+
     /// Send custom headers in the upgrade request
     /// Can be specified multiple time
     #[cfg_attr(feature = "clap", arg(short='H', long, value_name = "HEADER_NAME: HEADER_VALUE", value_parser = parsers::parse_http_headers, verbatim_doc_comment))]
@@ -197,6 +735,13 @@ pub struct Client {
     /// You can either use websocket or http2 as transport protocol. Use websocket if you are unsure.
     /// Example: For websocket with TLS wss://wstunnel.example.com or without ws://wstunnel.example.com
     ///          For http2 with TLS https://wstunnel.example.com or without http://wstunnel.example.com
+    ///          For the http1 fallback with TLS https1://wstunnel.example.com or without http1://wstunnel.example.com
+    ///            Last resort, slower, that uses plain http1 requests when websocket upgrades and http2 are blocked
+    ///          For gRPC with TLS grpcs://wstunnel.example.com or without grpc://wstunnel.example.com
+    ///            For the proxies that only let gRPC through, the tunnels are multiplexed as streams of one connection
+    ///          For the experimental dns transport dns://tunnel.example.com (needs the dns-transport feature)
+    ///          For the experimental icmp transport icmp://wstunnel.example.com (needs the icmp-transport feature and root)
+    ///          For the experimental ssh transport ssh://wstunnel.example.com (needs the ssh-transport feature)
     ///
     /// *WARNING* HTTP2 as transport protocol is harder to make it works because:
     ///   - If you are behind a (reverse) proxy/CDN they are going to buffer the whole request before forwarding it to the server
@@ -204,7 +749,7 @@ pub struct Client {
     ///   - if you have wstunnel behind a reverse proxy, most of them (i.e: nginx) are going to turn http2 request into http1
     ///     This is not going to work, because http1 does not support streaming naturally
     ///   - The only way to make it works with http2 is to have wstunnel directly exposed to the internet without any reverse proxy in front of it
-    #[cfg_attr(feature = "clap", arg(value_name = "ws[s]|http[s]://wstunnel.server.com[:port]", value_parser = parsers::parse_server_url, verbatim_doc_comment))]
+    #[cfg_attr(feature = "clap", arg(value_name = "ws[s]|http[s]|http[s]1|grpc[s]://wstunnel.server.com[:port]", value_parser = parsers::parse_server_url, verbatim_doc_comment))]
     pub remote_addr: Url,
 
     /// [Optional] Certificate (pem) to present to the server when connecting over TLS (HTTPS).
@@ -245,6 +790,12 @@ pub struct Client {
         )
     )]
     pub dns_resolver_prefer_ipv4: bool,
+
+    /// Dns resolver to send the tunnel queries to, when using the experimental dns transport (dns://tunnel.example.com)
+    /// Default is the first nameserver of the system configuration
+    #[cfg(feature = "dns-transport")]
+    #[cfg_attr(feature = "clap", arg(long, value_name = "IP:PORT", verbatim_doc_comment))]
+    pub dns_transport_resolver: Option<SocketAddr>,
 }
 
 #[derive(Debug)]
@@ -253,7 +804,7 @@ pub struct Server {
     /// Address of the wstunnel server to bind to
     /// Example: With TLS wss://0.0.0.0:8080 or without ws://[::]:8080
     ///
-    /// The server is capable of detecting by itself if the request is websocket or http2. So you don't need to specify it.
+    /// The server is capable of detecting by itself if the request is websocket, http1 or http2. So you don't need to specify it.
     #[cfg_attr(feature = "clap", arg(value_name = "ws[s]://0.0.0.0[:port]", value_parser = parsers::parse_server_url, verbatim_doc_comment))]
     pub remote_addr: Url,
 
@@ -262,6 +813,47 @@ pub struct Server {
     #[cfg_attr(feature = "clap", arg(long, value_name = "INT", verbatim_doc_comment))]
     pub socket_so_mark: Option<u32>,
 
+    /// Mark the packets of the connections to the clients with this DSCP codepoint, for the QoS of the network.
+    /// i.e: 46 (Expedited Forwarding) for VoIP
+    #[cfg_attr(feature = "clap", arg(long, value_name = "0-63", value_parser = parsers::parse_dscp, verbatim_doc_comment))]
+    pub dscp: Option<u8>,
+
+    /// (linux only) Connect to the destinations of the tcp and udp tunnels through this network interface,
+    /// with SO_BINDTODEVICE. i.e: eth1. Require CAP_NET_RAW on old kernels
+    #[cfg_attr(feature = "clap", arg(long, value_name = "INTERFACE", verbatim_doc_comment))]
+    pub bind_interface: Option<String>,
+
+    /// Connect to the destinations of the tcp and udp tunnels from this source ip. i.e: 10.0.0.5
+    /// The addresses of the destinations of the other ip family are not tried
+    #[cfg_attr(feature = "clap", arg(long, value_name = "IP", verbatim_doc_comment))]
+    pub bind_address: Option<IpAddr>,
+
+    /// (linux, macos and freebsd only) Enable TCP Fast Open on the server listener, to save a round trip for clients that also enable it.
+    /// Require net.ipv4.tcp_fastopen sysctl on linux, net.inet.tcp.fastopen on macos or net.inet.tcp.fastopen.server_enable on freebsd to allow it on server side.
+    /// Rejected on the other platforms.
+    #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
+    pub tcp_fastopen: bool,
+
+    /// (linux only) Only accept a new connection once the client has sent some data, or after this delay has elapsed.
+    /// Avoid waking up the server for connections that never send anything, at the cost of delaying idle connections of the client pool.
+    /// Rejected on the other platforms.
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub tcp_defer_accept: Option<Duration>,
+
+    /// (unix only) Bind the listening port this many times with SO_REUSEPORT, each listener with its own accept task.
+    /// The kernel spreads the new connections between them, so the accepts and the TLS handshakes are not all handled by one task.
+    /// 0 for one listener per worker thread, see --nb-worker-threads and --cpu-affinity
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "INT", default_value = "1", verbatim_doc_comment)
+    )]
+    pub accept_shards: usize,
+
     /// Frequency at which the server will send websocket ping to client.
     /// Set to zero to disable.
     #[cfg_attr(feature = "clap", arg(
@@ -279,6 +871,159 @@ pub struct Server {
     #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
     pub websocket_mask_frame: bool,
 
+    /// Maximum size in bytes of the websocket frames the server accepts to receive. Accept k and m suffixes (KiB, MiB)
+    /// The client and the server advertise their maximum during the upgrade request, and each side never sends frames bigger than what its peer accepts.
+    /// Peers running older versions do not advertise it and are still accepted frames up to 64m.
+    /// With http2 transport, it is advertised as the max frame size of the connection, within the 16k..16m bounds of http2.
+    /// Lower it if a CDN or a proxy in the middle fragments or rejects big websocket messages. Minimum is 64k
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BYTES",
+        default_value = "32m",
+        value_parser = parsers::parse_frame_size,
+        verbatim_doc_comment
+    ))]
+    pub websocket_max_frame_size: usize,
+
+    /// How long to wait for the peer to answer the close frame of a websocket tunnel, before dropping its connection.
+    /// The close handshake lets the proxies in between see the websocket end cleanly. Set to zero to not wait for it
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(ms|s|m)",
+        default_value = "1s",
+        value_parser = parsers::parse_duration_ms,
+        verbatim_doc_comment
+    ))]
+    pub websocket_close_timeout: Duration,
+
+    /// Websocket close code telling a close reason, when both sides have the error codes, i.e: 'idle=4408'. Can be specified multiple times
+    /// The reasons are reset, timeout and error for the failures of the destination, corrupted and idle. They are told with the codes 4001 to 4005 by default,
+    /// set the ones the proxies in between or the monitoring expect, between 3000 and 4999. The name of the reason is sent along with the code
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "REASON=CODE",
+        value_parser = parsers::parse_websocket_close_code,
+        verbatim_doc_comment
+    ))]
+    pub websocket_close_code: Vec<(CloseReason, u16)>,
+
+    /// Pad the websocket traffic of the tunnels with random frames, up to this percentage of its size, i.e: 10
+    /// Blurs the sizes of the frames that traffic analysis relies on to classify the flows, i.e: in censored networks.
+    /// The padding is sent in ping frames that the clients echo back, so it costs this overhead in both directions. Http transports are not padded
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "PERCENT",
+        value_parser = parsers::parse_percent,
+        verbatim_doc_comment
+    ))]
+    pub traffic_padding: Option<u8>,
+
+    /// Delay each write of the websocket tunnels to the clients by a random time up to this duration, i.e: 20ms
+    /// Blurs the timings of the frames, at the cost of latency and of throughput. Http transports are not delayed
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(ms|s)",
+        value_parser = parsers::parse_duration_ms,
+        verbatim_doc_comment
+    ))]
+    pub traffic_jitter: Option<Duration>,
+
+    /// Status code of the response to the requests the server refuses: invalid upgrade requests, tunnels denied by the restrictions, too many tunnels...
+    /// Set it along --reject-body to answer like the website the server is hidden behind, i.e: 404, instead of with an error of wstunnel.
+    /// Default is 302 with --reject-redirect, 404 with the other --reject options, and the plain errors of wstunnel without any
+    #[cfg_attr(feature = "clap", arg(long, value_name = "CODE", value_parser = parsers::parse_http_status, verbatim_doc_comment))]
+    pub reject_status: Option<StatusCode>,
+
+    /// Body of the response to the requests the server refuses, read from this file at startup, i.e: the 404 page of your website.
+    /// It is sent as text/html unless --reject-header sets a Content-Type
+    #[cfg_attr(feature = "clap", arg(long, value_name = "FILE_PATH", verbatim_doc_comment))]
+    pub reject_body: Option<PathBuf>,
+
+    /// Add this header to the response to the requests the server refuses, i.e: 'Server: nginx'
+    /// Can be specified multiple time
+    #[cfg_attr(feature = "clap", arg(long, value_name = "HEADER_NAME: HEADER_VALUE", value_parser = parsers::parse_http_headers, verbatim_doc_comment))]
+    pub reject_header: Vec<(HeaderName, HeaderValue)>,
+
+    /// Redirect the requests the server refuses to this location, i.e: https://example.com/
+    #[cfg_attr(feature = "clap", arg(long, value_name = "URL", verbatim_doc_comment))]
+    pub reject_redirect: Option<String>,
+
+    /// Be the standby server of an active-standby pair while this file exists, i.e: created and removed by the notify scripts of keepalived.
+    /// The tunnels are refused with a 503 the clients understand as a signal to switch to their next --standby-server,
+    /// and the reverse tunnels are closed so their clients reconnect to the active server
+    #[cfg_attr(feature = "clap", arg(long, value_name = "FILE_PATH", verbatim_doc_comment))]
+    pub standby_file: Option<PathBuf>,
+
+    /// Url of another server of the cluster, i.e: http://10.0.0.2:8080 for servers scaled horizontally behind a load balancer.
+    /// The servers announce the reverse tcp ports and http ingress virtual hosts of their clients to each other, and forward
+    /// the connections of the ones a peer holds to it, so any of them can serve them. Can be specified multiple times
+    #[cfg_attr(feature = "clap", arg(long, value_name = "URL", verbatim_doc_comment))]
+    pub cluster_peer: Vec<Url>,
+
+    /// Url the other servers of the cluster reach this one with, i.e: http://10.0.0.1:8080
+    /// Over https, its certificate must be trusted by the system of the peers
+    #[cfg_attr(feature = "clap", arg(long, value_name = "URL", verbatim_doc_comment))]
+    pub cluster_advertise: Option<Url>,
+
+    /// Secret shared by the servers of the cluster, authenticating their announcements
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "SECRET", env = "WSTUNNEL_CLUSTER_SECRET", verbatim_doc_comment)
+    )]
+    pub cluster_secret: Option<String>,
+
+    /// Sign a token naming this server in the x-wstunnel-sticky header of the response to the upgrade requests, that the clients
+    /// send back with all their next requests. A round-robin load balancer can route them to the same server with it, i.e: with
+    /// `stick on req.hdr(x-wstunnel-sticky)` and `stick store-response res.hdr(x-wstunnel-sticky)` in haproxy.
+    /// The secret is shared by all the servers behind the load balancer. The token is valid for a day
+    #[cfg_attr(
+        feature = "clap",
+        arg(
+            long,
+            value_name = "SECRET",
+            env = "WSTUNNEL_STICKY_SESSION_SECRET",
+            verbatim_doc_comment
+        )
+    )]
+    pub sticky_session_secret: Option<String>,
+
+    /// Name of this server in the sticky session tokens. Random by default
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "NAME", requires = "sticky_session_secret", verbatim_doc_comment)
+    )]
+    pub sticky_session_instance: Option<String>,
+
+    /// Maximum number of bytes read from the local side of a tunnel and not yet sent to the client, when using http2 transport.
+    /// Reading the local side pauses once it is reached, so a slow peer does not make the tunnel buffer unboundedly in memory.
+    /// The server also advertises it as the http2 flow control window of its connections, the most the client can send before being acknowledged.
+    /// Websocket transport writes directly to the connection, and is only bounded by the socket buffers. Accept k and m suffixes (KiB, MiB). Minimum is 64k
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BYTES",
+        default_value = "4m",
+        value_parser = parsers::parse_frame_size,
+        verbatim_doc_comment
+    ))]
+    pub max_inflight_per_tunnel: usize,
+
+    /// Maximum memory taken by the copy buffers of all the tunnels. Accept k, m and g suffixes (KiB, MiB, GiB)
+    /// Each tunnel gets a buffer of 64KiB, reused from the ones of the closed tunnels, and the websocket tunnels grow theirs
+    /// while they keep filling it, up to the max frame size. Once the maximum is reached the buffers stop growing, with a lower
+    /// throughput for the busy tunnels, and the buffers of the closed tunnels are freed instead of being kept. Unlimited by default
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parsers::parse_byte_size,
+        verbatim_doc_comment
+    ))]
+    pub max_memory_buffers: Option<u64>,
+
+    /// Debug: record the traffic of each tunnel in a pcap file named after the tunnel id, in this directory.
+    /// Ip and tcp/udp headers are made up from the addresses of both ends of the tunnel. Open the files with wireshark
+    #[cfg_attr(feature = "clap", arg(long, value_name = "DIR_PATH", verbatim_doc_comment))]
+    pub pcap_dir: Option<PathBuf>,
+
     /// Dns resolver to use to lookup ips of domain name
     /// This option is not going to work if you use transparent proxy
     /// Can be specified multiple time
@@ -322,6 +1067,7 @@ pub struct Server {
     /// Server will only accept connection from if this specific path prefix is used during websocket upgrade.
     /// Useful if you specify in the client a custom path prefix, and you want the server to only allow this one.
     /// The path prefix act as a secret to authenticate clients
+    /// Secrets can be read with file:PATH, env:NAME, exec:COMMAND or cred:NAME (systemd credential)
     /// Disabled by default. Accept all path prefix. Can be specified multiple time
     #[cfg_attr(
         feature = "clap",
@@ -330,16 +1076,182 @@ pub struct Server {
             long,
             verbatim_doc_comment,
             conflicts_with = "restrict_config",
+            value_parser = parsers::resolve_secret,
             env = "WSTUNNEL_RESTRICT_HTTP_UPGRADE_PATH_PREFIX"
         )
     )]
     pub restrict_http_upgrade_path_prefix: Option<Vec<String>>,
 
     /// Path to the location of the restriction yaml config file.
-    /// Restriction file is automatically reloaded if it changes
+    /// Restriction file is automatically reloaded if it changes, as well as the files it includes
     #[cfg_attr(feature = "clap", arg(long, verbatim_doc_comment))]
     pub restrict_config: Option<PathBuf>,
 
+    /// Validate the restriction config file and the files it includes, print a summary of its rules and exit.
+    /// Exit with an error if the file is invalid
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, default_value = "false", requires = "restrict_config", verbatim_doc_comment)
+    )]
+    pub check_restrictions: bool,
+
+    /// Print the configuration parsed from the arguments and exit. Passwords and keys are redacted
+    #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
+    pub dump_config: bool,
+
+    /// Print the passwords and keys too with --dump-config
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, default_value = "false", requires = "dump_config", verbatim_doc_comment)
+    )]
+    pub show_secrets: bool,
+
+    /// Delegate the validation of each upgrade request to an external authority, after the restrictions rules matched.
+    /// The request metadata (client ip, path prefix, headers, mTLS certificate CN, requested destination) are sent as json:
+    /// 'https://auth.lan/wstunnel'   =>  POST the json to this url. A 2xx response accepts the tunnel
+    /// '/usr/local/bin/wstunnel-auth' => run this program with the json on its stdin. A 0 exit code accepts the tunnel
+    #[cfg_attr(feature = "clap", arg(long, value_name = "URL|FILE_PATH", value_parser = parsers::parse_auth_hook, verbatim_doc_comment))]
+    pub auth_hook: Option<AuthHook>,
+
+    /// Maximum time to wait for the auth hook to answer, before rejecting the request
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "5s",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub auth_hook_timeout: Duration,
+
+    /// Require upgrade requests to carry a bearer token issued by this OpenID Connect provider.
+    /// Tokens are validated against the signing keys the provider publishes, after the restrictions rules matched.
+    /// Clients obtain a token with `wstunnel client login`
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "ISSUER_URL", requires = "oidc_audience", verbatim_doc_comment)
+    )]
+    pub oidc_issuer: Option<Url>,
+
+    /// Audience the OpenID Connect tokens must be issued for. Usually the client id used by `wstunnel client login`.
+    /// Required with --oidc-issuer, so the tokens the provider issues to its other applications do not open tunnels
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "STRING", requires = "oidc_issuer", verbatim_doc_comment)
+    )]
+    pub oidc_audience: Option<String>,
+
+    /// Only accept upgrade requests signed with this pre-shared key, and prove to the clients the server knows it.
+    /// Use it when TLS is terminated before the server (i.e: a CDN), so the path prefix alone cannot be trusted.
+    /// Requests cannot be replayed, but the clocks of the client and the server must be within 60s of each other.
+    /// Only the requests opening the tunnels are authenticated, not their data: add --noise-private-key for it.
+    /// Tunnels of the dns and icmp transports are rejected
+    /// Secrets can be read with file:PATH, env:NAME, exec:COMMAND or cred:NAME (systemd credential)
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "SECRET", value_parser = parsers::parse_secret, env = "WSTUNNEL_PSK", verbatim_doc_comment)
+    )]
+    pub psk: Option<Secret<String>>,
+
+    /// Require the data of the tunnels to be encrypted end to end with the clients (Noise_IK), with this private key.
+    /// Keeps the tunnels confidential when TLS is terminated before the server (i.e: a CDN).
+    /// Clients need the public key of the server, which is logged on startup.
+    /// Keys are base64 x25519 keys, generate them with `wg genkey` and `wg pubkey`
+    /// Secrets can be read with file:PATH, env:NAME, exec:COMMAND or cred:NAME (systemd credential)
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BASE64_KEY",
+        value_parser = parsers::parse_noise_private_key,
+        env = "WSTUNNEL_NOISE_PRIVATE_KEY",
+        verbatim_doc_comment
+    ))]
+    pub noise_private_key: Option<NoiseKey>,
+
+    /// Public key of a client allowed to open tunnels, when noise encryption is enabled. Can be specified multiple times.
+    /// Any client knowing the public key of the server is accepted if not set
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "BASE64_KEY",
+        value_parser = parsers::parse_noise_key,
+        requires = "noise_private_key",
+        verbatim_doc_comment
+    ))]
+    pub noise_client_public_key: Vec<NoiseKey>,
+
+    /// [Experimental] Listen for tunnels carried inside dns queries on this udp address. i.e: 0.0.0.0:53
+    /// The domain given with --dns-transport-domain must be delegated (NS record) to this server
+    /// This transport is very slow and only meant for networks where nothing but dns gets out
+    #[cfg(feature = "dns-transport")]
+    #[cfg_attr(feature = "clap", arg(long, value_name = "IP:PORT", verbatim_doc_comment))]
+    pub dns_transport_listen: Option<SocketAddr>,
+
+    /// Domain delegated to this server, under which the clients encode their tunnel queries. i.e: tunnel.example.com
+    #[cfg(feature = "dns-transport")]
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "DOMAIN", requires = "dns_transport_listen", verbatim_doc_comment)
+    )]
+    pub dns_transport_domain: Option<String>,
+
+    /// [Experimental] Accept tunnels carried inside ICMP echo requests (ping) received on this IPv4 address. i.e: 0.0.0.0
+    /// It requires root or CAP_NET_RAW. This transport is slow and only meant for networks where nothing but ping gets out
+    #[cfg(feature = "icmp-transport")]
+    #[cfg_attr(feature = "clap", arg(long, value_name = "IPv4", verbatim_doc_comment))]
+    pub icmp_transport_listen: Option<std::net::Ipv4Addr>,
+
+    /// [Experimental] Also accept tunnels carried inside ssh channels on the listening port, for the clients using ssh://
+    /// The clients are recognized by their ssh banner, so the same port serves both. i.e: listen on port 22 when only
+    /// ssh gets out of the network of the clients. The clients do not check the host key, use --psk to authenticate it
+    #[cfg(feature = "ssh-transport")]
+    #[cfg_attr(feature = "clap", arg(long, default_value_t = false, verbatim_doc_comment))]
+    pub ssh_transport: bool,
+
+    /// [Optional] Openssh private key file of the host key of the ssh transport. A new key is generated at start otherwise
+    #[cfg(feature = "ssh-transport")]
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "FILE_PATH", requires = "ssh_transport", verbatim_doc_comment)
+    )]
+    pub ssh_host_key: Option<PathBuf>,
+
+    /// What to do with the connections of a protocol, recognized from the first bytes the clients send on the listening port.
+    /// PROTOCOL is one of tls, http, h2 (http2 without tls), ssh or unknown.
+    /// HANDLER is wstunnel, reject, or HOST:PORT to forward the connections as is to another server. Can be specified multiple times
+    /// By default, wstunnel serves tls if the server uses wss://, http and h2 if it uses ws://, and ssh with --ssh-transport.
+    /// The other connections are closed. i.e:
+    /// 'ssh=127.0.0.1:22'   =>  share the listening port with the ssh daemon of the machine
+    /// 'http=wstunnel'      =>  also serve the plain http clients of a wss:// server. They present no client certificate (mTLS)
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "PROTOCOL=HANDLER",
+        value_parser = parsers::parse_protocol_handler,
+        verbatim_doc_comment,
+    ))]
+    pub protocol_handler: Vec<(SniffedProtocol, ProtocolHandler)>,
+
+    /// Forward the TLS connections asking for another server name (SNI) than the ones of --tls-server-name untouched to this server.
+    /// i.e: 127.0.0.1:8443 to share port 443 with an existing https site. The connections without server name stay with wstunnel
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "HOST:PORT",
+        value_parser = parsers::parse_backend,
+        requires = "tls_server_name",
+        verbatim_doc_comment,
+    ))]
+    pub tls_sni_passthrough: Option<(Host, u16)>,
+
+    /// Server name (SNI) of the TLS connections wstunnel serves itself, when --tls-sni-passthrough is used.
+    /// '*.example.com' matches the subdomains of example.com. Can be specified multiple times
+    #[cfg_attr(
+        feature = "clap",
+        arg(
+            long,
+            value_name = "DOMAIN_NAME",
+            requires = "tls_sni_passthrough",
+            verbatim_doc_comment
+        )
+    )]
+    pub tls_server_name: Vec<String>,
+
     /// [Optional] Use custom certificate (pem) instead of the default embedded self-signed certificate.
     /// The certificate will be automatically reloaded if it changes
     #[cfg_attr(feature = "clap", arg(long, value_name = "FILE_PATH", verbatim_doc_comment))]
@@ -356,6 +1268,12 @@ pub struct Server {
     #[cfg_attr(feature = "clap", arg(long, value_name = "FILE_PATH", verbatim_doc_comment))]
     pub tls_client_ca_certs: Option<PathBuf>,
 
+    /// (linux only) Once the TLS handshake with a client is done, let the kernel encrypt and decrypt the data of its connection (kTLS),
+    /// saving a copy of every byte of the tunnels. Needs linux >= 5.16 with the tls module, only the ciphers it supports are offered to the clients.
+    /// The connections stay encrypted by wstunnel if the kernel does not support it
+    #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
+    pub tls_ktls: bool,
+
     /// If set, will use this http proxy to connect to the client
     #[cfg_attr(
         feature = "clap",
@@ -363,11 +1281,12 @@ pub struct Server {
             short = 'p',
             long,
             value_name = "USER:PASS@HOST:PORT",
+            value_parser = parsers::parse_secret,
             verbatim_doc_comment,
             env = "HTTP_PROXY"
         )
     )]
-    pub http_proxy: Option<String>,
+    pub http_proxy: Option<Secret<String>>,
 
     /// If set, will use this login to connect to the http proxy. Override the one from --http-proxy
     #[cfg_attr(
@@ -377,16 +1296,18 @@ pub struct Server {
     pub http_proxy_login: Option<String>,
 
     /// If set, will use this password to connect to the http proxy. Override the one from --http-proxy
+    /// Secrets can be read with file:PATH, env:NAME, exec:COMMAND or cred:NAME (systemd credential)
     #[cfg_attr(
         feature = "clap",
         arg(
             long,
             value_name = "PASSWORD",
+            value_parser = parsers::parse_secret,
             verbatim_doc_comment,
             env = "WSTUNNEL_HTTP_PROXY_PASSWORD"
         )
     )]
-    pub http_proxy_password: Option<String>,
+    pub http_proxy_password: Option<Secret<String>>,
 
     /// Configure how much time a remote-to-local server is going to wait idle (without any new ws clients) before unbinding itself/stopping the server
     /// Default is 190 seconds/3min
@@ -399,409 +1320,217 @@ pub struct Server {
         verbatim_doc_comment,
     ))]
     pub remote_to_local_server_idle_timeout: Duration,
-}
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct LocalToRemote {
-    pub local_protocol: LocalProtocol,
-    pub local: SocketAddr,
-    pub remote: (Host, u16),
-}
+    /// Wildcard domain of the server, i.e: tunnels.example.com, under which the clients using -R 'http://*.tunnels.example.com:localhost:3000'
+    /// get a random subdomain for the session. Its dns must have a wildcard record pointing to the server.
+    /// The other subdomains can only be exposed by the clients they are reserved for with --http-ingress-reserve
+    #[cfg_attr(feature = "clap", arg(long, value_name = "DOMAIN", verbatim_doc_comment))]
+    pub http_ingress_domain: Option<String>,
 
-#[cfg(feature = "clap")]
-mod parsers {
-    use super::LocalToRemote;
-    use crate::tunnel::LocalProtocol;
-    use crate::tunnel::transport::TransportScheme;
-    use base64::Engine;
-    use hyper::http::{HeaderName, HeaderValue};
-    use std::cmp::max;
-    use std::collections::BTreeMap;
-    use std::io;
-    use std::io::ErrorKind;
-    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-    use std::path::PathBuf;
-    use std::str::FromStr;
-    use std::time::Duration;
-    use tokio_rustls::rustls::pki_types::DnsName;
-    use url::{Host, Url};
-
-    pub fn parse_duration_sec(arg: &str) -> Result<Duration, io::Error> {
-        use std::io::Error;
-
-        let (arg, multiplier) = match &arg[max(0, arg.len() - 1)..] {
-            "s" => (&arg[..arg.len() - 1], 1),
-            "m" => (&arg[..arg.len() - 1], 60),
-            "h" => (&arg[..arg.len() - 1], 3600),
-            _ => (arg, 1),
-        };
-
-        let Ok(secs) = arg.parse::<u64>() else {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!("cannot parse duration of seconds from {arg}"),
-            ));
-        };
-
-        Ok(Duration::from_secs(secs * multiplier))
-    }
+    /// Reserve a subdomain of --http-ingress-domain for a client identity, its upgrade path prefix or mTLS certificate common name
+    /// followed by its device id if it has one. i.e: 'myapp=team-a' lets only the clients of the team-a path prefix without a device id
+    /// expose myapp.tunnels.example.com, and 'myapp=team-a/sensor-42' only the device sensor-42 of team-a. Can be specified multiple times
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "NAME=IDENTITY",
+        value_parser = parsers::parse_http_ingress_reserve,
+        requires = "http_ingress_domain",
+        verbatim_doc_comment,
+    ))]
+    pub http_ingress_reserve: Vec<(String, String)>,
 
-    pub fn parse_local_bind(arg: &str) -> Result<(SocketAddr, &str), io::Error> {
-        use std::io::Error;
-
-        let (bind, remaining) = if arg.starts_with('[') {
-            // ipv6 bind
-            let Some((ipv6_str, remaining)) = arg.split_once(']') else {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("cannot parse IPv6 bind from {arg}"),
-                ));
-            };
-            let Ok(ipv6_addr) = Ipv6Addr::from_str(&ipv6_str[1..]) else {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("cannot parse IPv6 bind from {ipv6_str}"),
-                ));
-            };
-
-            (IpAddr::V6(ipv6_addr), remaining)
-        } else {
-            // Maybe ipv4 addr
-            let (ipv4_str, remaining) = arg.split_once(':').unwrap_or((arg, ""));
-            Ipv4Addr::from_str(ipv4_str).map_or_else(
-                |_| (IpAddr::V4(Ipv4Addr::from_str("127.0.0.1").unwrap()), arg),
-                |ip4_addr| (IpAddr::V4(ip4_addr), remaining),
-            )
-        };
-
-        let remaining = remaining.trim_start_matches(':');
-        let (port_str, remaining) = remaining.split_once([':', '?']).unwrap_or((remaining, ""));
-
-        let Ok(bind_port): Result<u16, _> = port_str.parse() else {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!("cannot parse bind port from {port_str}"),
-            ));
-        };
-
-        Ok((SocketAddr::new(bind, bind_port), remaining))
-    }
+    /// Maximum time a resumable tcp tunnel (-L tcp://...?resume_buffer=) is kept open on the server while its client is disconnected.
+    /// The timeout requested by the client is used if it is lower
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "5m",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment,
+    ))]
+    pub tunnel_resume_max_timeout: Duration,
 
-    #[allow(clippy::type_complexity)]
-    pub fn parse_tunnel_dest(remaining: &str) -> Result<(Host<String>, u16, BTreeMap<String, String>), io::Error> {
-        use std::io::Error;
-
-        // Using http or else the URL lib don't try to fully parse the host into an IPv4/IPv6
-        let Ok(remote) = Url::parse(&format!("https://{remaining}")) else {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!("cannot parse remote from {remaining}"),
-            ));
-        };
-
-        let Some(remote_host) = remote.host() else {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!("cannot parse remote host from {remaining}"),
-            ));
-        };
-
-        let remote_port = match remote.port() {
-            Some(remote_port) => remote_port,
-            // the url lib does not parse the port if it is the default one
-            None if remaining.ends_with(":443") || remaining.contains(":443?") => 443,
-            _ => {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("cannot parse remote port from {remaining}"),
-                ));
-            }
-        };
+    /// Close tcp tunnels once no data went through them for this duration, so abandoned connections do not pile up.
+    /// The timeout requested by the client (-L tcp://...?idle_timeout_sec=) is used if it is lower. Disabled by default
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment,
+    ))]
+    pub tunnel_idle_timeout: Option<Duration>,
+
+    /// Serve prometheus metrics on http://<IP:PORT>/metrics, i.e: 127.0.0.1:9090
+    /// The /healthz and /readyz probes are served there too, as well as on the server bind.
+    /// /readyz fails until the server listens, and reports the status of the last restrictions reload
+    #[cfg_attr(feature = "clap", arg(long, value_name = "IP:PORT", verbatim_doc_comment))]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Serve the live statistics of the tunnels as json on http://<IP:PORT>/tunnels, i.e: 127.0.0.1:9091
+    /// Watch them with `wstunnel top --admin 127.0.0.1:9091`. Bind it on localhost, the api has no authentication
+    #[cfg_attr(feature = "clap", arg(long, value_name = "IP:PORT", verbatim_doc_comment))]
+    pub admin_listen: Option<SocketAddr>,
+
+    /// Shell command run when a tunnel is opened, i.e: to punch a firewall or update a dynamic dns
+    /// It gets the metadata of the tunnel in the env vars WSTUNNEL_EVENT, WSTUNNEL_SIDE, WSTUNNEL_TUNNEL_ID, WSTUNNEL_PROTOCOL,
+    /// WSTUNNEL_REMOTE, WSTUNNEL_PEER and WSTUNNEL_LABEL, and as json on its stdin. The tunnel does not wait for it
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "CMD", env = "WSTUNNEL_ON_TUNNEL_CONNECT", verbatim_doc_comment)
+    )]
+    pub on_tunnel_connect: Option<String>,
 
-        let options: BTreeMap<String, String> = remote.query_pairs().into_owned().collect();
-        Ok((remote_host.to_owned(), remote_port, options))
-    }
+    /// Shell command run when a tunnel is closed, i.e: to send a notification
+    /// Same as --on-tunnel-connect, with WSTUNNEL_DURATION_SECS, WSTUNNEL_TX_BYTES, WSTUNNEL_RX_BYTES and
+    /// WSTUNNEL_CLOSE_REASON (normal, reset, timeout, error, corrupted or idle) in addition
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "CMD", env = "WSTUNNEL_ON_TUNNEL_CLOSE", verbatim_doc_comment)
+    )]
+    pub on_tunnel_close: Option<String>,
 
-    pub fn parse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
-        use std::io::Error;
-        let get_timeout = |options: &BTreeMap<String, String>| {
-            options
-                .get("timeout_sec")
-                .and_then(|x| x.parse::<u64>().ok())
-                .map(|d| if d == 0 { None } else { Some(Duration::from_secs(d)) })
-                .unwrap_or(Some(Duration::from_secs(30)))
-        };
-        let get_credentials = |options: &BTreeMap<String, String>| {
-            options
-                .get("login")
-                .and_then(|login| options.get("password").map(|p| (login.to_string(), p.to_string())))
-        };
-        let get_proxy_protocol = |options: &BTreeMap<String, String>| options.contains_key("proxy_protocol");
-
-        let Some((proto, tunnel_info)) = arg.split_once("://") else {
-            return Err(Error::new(ErrorKind::InvalidInput, format!("cannot parse protocol from {arg}")));
-        };
-
-        match proto {
-            "tcp" => {
-                let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
-                let (dest_host, dest_port, options) = parse_tunnel_dest(remaining)?;
-                Ok(LocalToRemote {
-                    local_protocol: LocalProtocol::Tcp {
-                        proxy_protocol: get_proxy_protocol(&options),
-                    },
-                    local: local_bind,
-                    remote: (dest_host, dest_port),
-                })
-            }
-            "udp" => {
-                let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
-                let (dest_host, dest_port, options) = parse_tunnel_dest(remaining)?;
-
-                Ok(LocalToRemote {
-                    local_protocol: LocalProtocol::Udp {
-                        timeout: get_timeout(&options),
-                    },
-                    local: local_bind,
-                    remote: (dest_host, dest_port),
-                })
-            }
-            "unix" => {
-                let Some((path, remote)) = tunnel_info.split_once(':') else {
-                    return Err(Error::new(
-                        ErrorKind::InvalidInput,
-                        format!("cannot parse unix socket path from {arg}"),
-                    ));
-                };
-                let (dest_host, dest_port, options) = parse_tunnel_dest(remote)?;
-                Ok(LocalToRemote {
-                    local_protocol: LocalProtocol::Unix {
-                        path: PathBuf::from(path),
-                        proxy_protocol: get_proxy_protocol(&options),
-                    },
-                    local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
-                    remote: (dest_host, dest_port),
-                })
-            }
-            "http" => {
-                let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
-                let x = format!("0.0.0.0:0?{remaining}");
-                let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
-                Ok(LocalToRemote {
-                    local_protocol: LocalProtocol::HttpProxy {
-                        timeout: get_timeout(&options),
-                        credentials: get_credentials(&options),
-                        proxy_protocol: get_proxy_protocol(&options),
-                    },
-                    local: local_bind,
-                    remote: (dest_host, dest_port),
-                })
-            }
-            "socks5" => {
-                let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
-                let x = format!("0.0.0.0:0?{remaining}");
-                let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
-                Ok(LocalToRemote {
-                    local_protocol: LocalProtocol::Socks5 {
-                        timeout: get_timeout(&options),
-                        credentials: get_credentials(&options),
-                    },
-                    local: local_bind,
-                    remote: (dest_host, dest_port),
-                })
-            }
-            "stdio" => {
-                let (dest_host, dest_port, options) = parse_tunnel_dest(tunnel_info)?;
-                Ok(LocalToRemote {
-                    local_protocol: LocalProtocol::Stdio {
-                        proxy_protocol: get_proxy_protocol(&options),
-                    },
-                    local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(0), 0)),
-                    remote: (dest_host, dest_port),
-                })
-            }
-            "tproxy+tcp" => {
-                let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
-                let x = format!("0.0.0.0:0?{remaining}");
-                let (dest_host, dest_port, _options) = parse_tunnel_dest(&x)?;
-                Ok(LocalToRemote {
-                    local_protocol: LocalProtocol::TProxyTcp,
-                    local: local_bind,
-                    remote: (dest_host, dest_port),
-                })
-            }
-            "tproxy+udp" => {
-                let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
-                let x = format!("0.0.0.0:0?{remaining}");
-                let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
-                Ok(LocalToRemote {
-                    local_protocol: LocalProtocol::TProxyUdp {
-                        timeout: get_timeout(&options),
-                    },
-                    local: local_bind,
-                    remote: (dest_host, dest_port),
-                })
-            }
-            _ => Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!("Invalid local protocol for tunnel {arg}"),
-            )),
-        }
-    }
+    /// Maximum number of clients (distinct ip addresses) that can have tunnels opened at the same time.
+    /// New clients are rejected with an HTTP 429 Too Many Requests once it is reached
+    #[cfg_attr(feature = "clap", arg(long, value_name = "INT", verbatim_doc_comment))]
+    pub max_clients: Option<usize>,
 
-    pub fn parse_reverse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
-        let proto = parse_tunnel_arg(arg)?;
-        let local_protocol = match proto.local_protocol {
-            LocalProtocol::Tcp { .. } => LocalProtocol::ReverseTcp {},
-            LocalProtocol::Udp { timeout } => LocalProtocol::ReverseUdp { timeout },
-            LocalProtocol::Socks5 { timeout, credentials } => LocalProtocol::ReverseSocks5 { timeout, credentials },
-            LocalProtocol::HttpProxy {
-                timeout,
-                credentials,
-                proxy_protocol: _proxy_protocol,
-            } => LocalProtocol::ReverseHttpProxy { timeout, credentials },
-            LocalProtocol::Unix { path, .. } => LocalProtocol::ReverseUnix { path },
-            LocalProtocol::ReverseTcp
-            | LocalProtocol::ReverseUdp { .. }
-            | LocalProtocol::ReverseSocks5 { .. }
-            | LocalProtocol::ReverseHttpProxy { .. }
-            | LocalProtocol::ReverseUnix { .. }
-            | LocalProtocol::TProxyTcp
-            | LocalProtocol::TProxyUdp { .. }
-            | LocalProtocol::Stdio { .. } => {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("Cannot use {:?} as reverse tunnels {}", proto.local_protocol, arg),
-                ));
-            }
-        };
+    /// Maximum number of tunnels that a single client (ip address) can have opened at the same time.
+    /// New tunnels of the client are rejected with an HTTP 429 Too Many Requests once it is reached
+    #[cfg_attr(feature = "clap", arg(long, value_name = "INT", verbatim_doc_comment))]
+    pub max_tunnels_per_client: Option<usize>,
 
-        Ok(LocalToRemote {
-            local_protocol,
-            local: proto.local,
-            remote: proto.remote,
-        })
-    }
+    /// Reject the tunnels of the clients older than this version, i.e: 10.5.2 to force their upgrade after a security fix.
+    /// They get an HTTP 426 Upgrade Required telling the version to upgrade to, not replaced by --reject-*.
+    /// The clients tell their version in the x-wstunnel-client-version header, the ones not sending it are rejected too,
+    /// as are the ones of the dns and icmp transports that have no headers
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "MAJOR.MINOR.PATCH",
+        value_parser = parsers::parse_client_version,
+        verbatim_doc_comment,
+    ))]
+    pub require_min_client_version: Option<ClientVersion>,
+}
 
-    pub fn parse_sni_override(arg: &str) -> Result<DnsName<'static>, io::Error> {
-        match DnsName::try_from(arg.to_string()) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(io::Error::new(ErrorKind::InvalidInput, format!("Invalid sni override: {err}"))),
-        }
-    }
+/// Login to an OpenID Connect provider with the device authorization flow, and cache the token for the client
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "clap", derive(clap::Args))]
+pub struct OidcLogin {
+    /// Url of the OpenID Connect provider. Its configuration is discovered from ISSUER_URL/.well-known/openid-configuration
+    #[cfg_attr(feature = "clap", arg(long, value_name = "ISSUER_URL", verbatim_doc_comment))]
+    pub oidc_issuer: Url,
 
-    pub fn parse_http_headers(arg: &str) -> Result<(HeaderName, HeaderValue), io::Error> {
-        let Some((key, value)) = arg.split_once(':') else {
-            return Err(io::Error::new(
-                ErrorKind::InvalidInput,
-                format!("cannot parse http header from {arg}"),
-            ));
-        };
-
-        let value = match HeaderValue::from_str(value.trim()) {
-            Ok(value) => value,
-            Err(err) => {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("cannot parse http header value from {value} due to {err:?}"),
-                ));
-            }
-        };
+    /// Client id registered at the provider for wstunnel. It must be allowed to use the device authorization grant
+    #[cfg_attr(feature = "clap", arg(long, value_name = "STRING", verbatim_doc_comment))]
+    pub oidc_client_id: String,
 
-        Ok((HeaderName::from_str(key).unwrap(), value))
-    }
+    /// Scopes to request. Keep offline_access to get a refresh token, so the client does not need to login again when the token expires
+    #[cfg_attr(
+        feature = "clap",
+        arg(
+            long,
+            value_name = "STRING",
+            default_value = "openid offline_access",
+            verbatim_doc_comment
+        )
+    )]
+    pub oidc_scope: String,
 
-    pub fn parse_http_credentials(arg: &str) -> Result<HeaderValue, io::Error> {
-        let encoded = base64::engine::general_purpose::STANDARD.encode(arg.trim().as_bytes());
-        let Ok(header) = HeaderValue::from_str(&format!("Basic {encoded}")) else {
-            return Err(io::Error::new(
-                ErrorKind::InvalidInput,
-                format!("cannot parse http credentials {arg}"),
-            ));
-        };
+    /// Path where to store the token
+    /// Default is $XDG_CACHE_HOME/wstunnel/oidc_token.json
+    #[cfg_attr(feature = "clap", arg(long, value_name = "FILE_PATH", verbatim_doc_comment))]
+    pub oidc_token_cache: Option<PathBuf>,
+}
 
-        Ok(header)
-    }
+/// Connect stdio to HOST:PORT through the wstunnel server, like netcat.
+/// Meant for ssh, i.e: export WSTUNNEL_SERVER=wss://wstunnel.example.com; ssh -o ProxyCommand="wstunnel nc %h %p" my-server
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "clap", derive(clap::Args))]
+pub struct Nc {
+    /// Host to connect to, through the server.
+    /// When HOST and PORT are omitted, they are read from the SSH_CONNECTION environment variable
+    /// ("client_ip client_port server_ip server_port"), and the connection is made to server_ip:server_port
+    #[cfg_attr(feature = "clap", arg(value_name = "HOST", requires = "port", verbatim_doc_comment))]
+    pub host: Option<String>,
+
+    /// Port to connect to, through the server
+    #[cfg_attr(feature = "clap", arg(value_name = "PORT", verbatim_doc_comment))]
+    pub port: Option<u16>,
+
+    /// Address of the wstunnel server. i.e: wss://wstunnel.example.com
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "ws[s]|http[s]://wstunnel.server.com[:port]",
+        env = "WSTUNNEL_SERVER",
+        value_parser = parsers::parse_server_url,
+        verbatim_doc_comment
+    ))]
+    pub server: Url,
 
-    pub fn parse_server_url(arg: &str) -> Result<Url, io::Error> {
-        let Ok(url) = Url::parse(arg) else {
-            return Err(io::Error::new(
-                ErrorKind::InvalidInput,
-                format!("cannot parse server url {arg}"),
-            ));
-        };
-
-        if !TransportScheme::values().iter().any(|x| x.to_str() == url.scheme()) {
-            return Err(io::Error::new(
-                ErrorKind::InvalidInput,
-                format!("invalid scheme {}", url.scheme()),
-            ));
-        }
+    /// Any other option of the client, after a --
+    /// i.e: wstunnel nc %h %p -- --tls-sni-override=example.com --http-upgrade-path-prefix=secret
+    #[cfg_attr(
+        feature = "clap",
+        arg(last = true, value_name = "CLIENT_OPTIONS", verbatim_doc_comment)
+    )]
+    pub client_args: Vec<String>,
+}
 
-        if url.host().is_none() {
-            return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid server host {arg}")));
+#[cfg(feature = "clap")]
+impl Nc {
+    /// Destination of the connection, given as arguments or read from the SSH_CONNECTION environment variable
+    pub fn target(&self) -> Result<(Host, u16), std::io::Error> {
+        match (&self.host, self.port) {
+            (Some(host), Some(port)) => parsers::parse_host_port(host, port),
+            _ => {
+                let ssh_connection = std::env::var("SSH_CONNECTION").map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "no HOST and PORT given, and no SSH_CONNECTION environment variable to read them from",
+                    )
+                })?;
+                parsers::parse_ssh_connection(&ssh_connection)
+            }
         }
-
-        Ok(url)
     }
+}
 
-    #[cfg(test)]
-    mod test {
-        use super::{LocalToRemote, parse_local_bind, parse_tunnel_arg, parse_tunnel_dest};
-        use crate::tunnel::LocalProtocol;
-        use collection_macros::btreemap;
-        use std::collections::BTreeMap;
-        use std::io;
-        use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-        use test_case::test_case;
-        use url::Host;
-
-        #[test_case("localhost:443" => (Host::Domain("localhost".to_string()), 443, BTreeMap::new()) ; "with domain")]
-        #[test_case("localhost:443?timeout_sec=0" => (Host::Domain("localhost".to_string()), 443, btreemap! { "timeout_sec".to_string() => "0".to_string() } ) ; "with domain and options")]
-        #[test_case("127.0.0.1:443" => (Host::Ipv4(Ipv4Addr::new(127, 0, 0, 1)), 443, BTreeMap::new()) ; "with IPv4")]
-        #[test_case("[::1]:8080" => (Host::Ipv6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8080, BTreeMap::new()) ; "with IpV6")]
-        #[test_case("a:1?timeout_sec=30&b=5" => (Host::Domain("a".to_string()), 1, btreemap! { "b".to_string() => "5".to_string(), "timeout_sec".to_string() => "30".to_string() }) ; "with options")]
-        fn test_parse_tunnel_dest(input: &str) -> (Host<String>, u16, BTreeMap<String, String>) {
-            parse_tunnel_dest(input).unwrap()
-        }
-
-        const LOCALHOST_IP4: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 443);
-        const LOCALHOST_IP6: SocketAddrV6 = SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 443, 0, 0);
-
-        #[test_case("domain.com:443" => matches Err(_) ; "with domain")]
-        #[test_case("127.0.0.1" => matches Err(_) ; "with no port")]
-        #[test_case("127.0.0.1:444444443" => matches Err(_) ; "with too long port")]
-        #[test_case("127.0.0.1:443" => matches Ok((SocketAddr::V4(LOCALHOST_IP4), _)) ; "with ipv4")]
-        #[test_case("[::1]:443" => matches Ok((SocketAddr::V6(LOCALHOST_IP6), _)) ; "with ipv6")]
-        fn test_parse_local_bind(input: &str) -> Result<(SocketAddr, &str), io::Error> {
-            parse_local_bind(input)
-        }
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalToRemote {
+    pub local_protocol: LocalProtocol,
+    pub local: SocketAddr,
+    pub remote: (Host, u16),
+    /// Tag sent to the server along the tunnel, to identify it in the server logs and metrics
+    pub label: Option<String>,
+    /// DSCP codepoint of the packets of the connection to the server carrying the tunnel, instead of the one of --dscp
+    pub dscp: Option<u8>,
+    /// When listening on an ipv6 address, only accept ipv6 (true) or also ipv4 (false) connections. None keeps the
+    /// default of the OS, dual stack on Linux but not on Windows and the BSDs
+    pub v6only: Option<bool>,
+    /// Peers allowed to connect to the listener of a tcp, http proxy or socks5 tunnel, i.e: allow=10.0.0.0/8&deny=0.0.0.0/0
+    pub access: AccessList,
+    /// Connections accepted by the listener of a -L tunnel, i.e: max_conns=100&accept_rate=50/s&overflow=reject
+    pub accept_limits: AcceptLimits,
+    /// Number of consecutive ports forwarded from the local port to the remote one, more than 1 for a port range
+    /// i.e: tcp://2000-2100:host:3000-3100
+    pub port_count: u16,
+}
 
-        #[test_case("domain.com:443" => panics ""; "with no protocol")]
-        #[test_case("sdsf://443:domain.com:443" => panics ""; "with invalid protocol")]
-        #[test_case("tcp://443:domain.com:4443" =>
-            LocalToRemote {
-                local_protocol: LocalProtocol::Tcp { proxy_protocol: false },
-                local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 443)),
-                remote: (Host::Domain("domain.com".to_string()), 4443),
-            }
-        ; "with no local bind")]
-        #[test_case("udp://[::1]:443:toto.com:4443?timeout_sec=30" =>
-            LocalToRemote {
-                local_protocol: LocalProtocol::Udp { timeout: Some(std::time::Duration::from_secs(30)) },
-                local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 443, 0, 0)),
-                remote: (Host::Domain("toto.com".to_string()), 4443),
-            }
-        ; "with fully defined tunnel")]
-        #[test_case("udp://[::1]:443:[::1]:4443?timeout_sec=30" =>
-            LocalToRemote {
-                local_protocol: LocalProtocol::Udp { timeout: Some(std::time::Duration::from_secs(30)) },
-                local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 443, 0, 0)),
-                remote: (Host::Ipv6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 4443),
-            }
-        ; "with full ipv6 tunnel")]
-        fn test_parse_tunnel_arg(input: &str) -> LocalToRemote {
-            parse_tunnel_arg(input).unwrap()
-        }
+impl LocalToRemote {
+    /// The tunnel of each port of the port range
+    pub fn split_port_range(self) -> impl Iterator<Item = LocalToRemote> {
+        (0..self.port_count).map(move |offset| {
+            let mut tunnel = self.clone();
+            tunnel.local.set_port(self.local.port() + offset);
+            tunnel.remote.1 = self.remote.1 + offset;
+            tunnel.port_count = 1;
+            tunnel
+        })
     }
 }
+
+/// Parsers of the values given on the command line, also usable without the clap feature
+pub mod parsers;
+
+/// Wrapper redacting the secrets of the configuration from the logs
+pub mod secret;
@@ -0,0 +1,2319 @@
+use super::LocalToRemote;
+use super::secret::{Secret, mark_sensitive_header};
+use crate::dscp::MAX_DSCP;
+use crate::executor::CpuAffinity;
+use crate::protocols;
+use crate::protocols::tls::{CryptoProviderKind, TlsFingerprint};
+use crate::tunnel::client::{
+    AcceptLimits, AcceptOverflow, Browser, OnListenerError, ReconnectHook, RedirectPolicy, SplitRequests,
+};
+use crate::tunnel::noise::NoiseKey;
+use crate::tunnel::protocol::client_version::ClientVersion;
+use crate::tunnel::protocol::close_reason::{CloseReason, WsCloseCodes};
+use crate::tunnel::server::{AuthHook, ProtocolHandler, SniffedProtocol};
+use crate::tunnel::transport::TransportScheme;
+use crate::tunnel::transport::websocket::MIN_MAX_FRAME_SIZE;
+use crate::tunnel::{
+    AccessList, EncryptedDns, HttpIngressAuth, LoadBalancing, LoadBalancingStrategy, LocalProtocol, MAX_LABEL_LEN,
+    Socks5Resolve, TunnelKeepalive, TunnelResume, UdpFlowEviction, UnixSocketLifecycle, UnixSocketPermissions,
+    is_valid_label,
+};
+use base64::Engine;
+use hyper::http::{HeaderName, HeaderValue, StatusCode};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, de};
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_rustls::rustls::pki_types::DnsName;
+use url::{Host, Url};
+
+pub fn parse_duration_sec(arg: &str) -> Result<Duration, io::Error> {
+    use std::io::Error;
+
+    let (arg, multiplier) = match &arg[max(0, arg.len() - 1)..] {
+        "s" => (&arg[..arg.len() - 1], 1),
+        "m" => (&arg[..arg.len() - 1], 60),
+        "h" => (&arg[..arg.len() - 1], 3600),
+        _ => (arg, 1),
+    };
+
+    let Ok(secs) = arg.parse::<u64>() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse duration of seconds from {arg}"),
+        ));
+    };
+
+    Ok(Duration::from_secs(secs * multiplier))
+}
+
+/// Same as parse_duration_sec, but also accept milliseconds with the ms suffix
+pub fn parse_duration_ms(arg: &str) -> Result<Duration, io::Error> {
+    let Some(millis) = arg.strip_suffix("ms") else {
+        return parse_duration_sec(arg);
+    };
+
+    match millis.parse::<u64>() {
+        Ok(millis) => Ok(Duration::from_millis(millis)),
+        Err(_) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse duration of milliseconds from {arg}"),
+        )),
+    }
+}
+
+pub fn parse_auth_hook(arg: &str) -> Result<AuthHook, io::Error> {
+    if arg.starts_with("http://") || arg.starts_with("https://") {
+        let url = Url::parse(arg).map_err(|err| {
+            io::Error::new(ErrorKind::InvalidInput, format!("cannot parse auth hook url {arg}: {err}"))
+        })?;
+        return Ok(AuthHook::Webhook(url));
+    }
+
+    Ok(AuthHook::Command(PathBuf::from(arg)))
+}
+
+pub fn parse_reconnect_hook(arg: &str) -> Result<ReconnectHook, io::Error> {
+    if arg.starts_with("http://") || arg.starts_with("https://") {
+        let url = Url::parse(arg).map_err(|err| {
+            io::Error::new(ErrorKind::InvalidInput, format!("cannot parse reconnect hook url {arg}: {err}"))
+        })?;
+        return Ok(ReconnectHook::Webhook(url));
+    }
+
+    Ok(ReconnectHook::Command(PathBuf::from(arg)))
+}
+
+pub fn parse_frame_size(arg: &str) -> Result<usize, io::Error> {
+    let (size, multiplier) = if let Some(size) = arg.strip_suffix('k') {
+        (size, 1024)
+    } else if let Some(size) = arg.strip_suffix('m') {
+        (size, 1024 * 1024)
+    } else {
+        (arg, 1)
+    };
+
+    let Some(size) = size.parse::<usize>().ok().and_then(|s| s.checked_mul(multiplier)) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse frame size in bytes from {arg}"),
+        ));
+    };
+
+    if size < MIN_MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("frame size must be at least {MIN_MAX_FRAME_SIZE} bytes, got {size}"),
+        ));
+    }
+
+    Ok(size)
+}
+
+pub fn parse_byte_size(arg: &str) -> Result<u64, io::Error> {
+    let (size, multiplier) = match arg.char_indices().last() {
+        Some((idx, 'k')) => (&arg[..idx], 1024),
+        Some((idx, 'm')) => (&arg[..idx], 1024 * 1024),
+        Some((idx, 'g')) => (&arg[..idx], 1024 * 1024 * 1024),
+        _ => (arg, 1),
+    };
+
+    match size.parse::<u64>().ok().and_then(|s| s.checked_mul(multiplier)) {
+        Some(size) if size > 0 => Ok(size),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse size in bytes from {arg}, expected i.e: 100m"),
+        )),
+    }
+}
+
+/// Parse a rate in bits per second like tc does, i.e: 10mbit, and return it in bytes per second
+pub fn parse_bit_rate(arg: &str) -> Result<u64, io::Error> {
+    let (rate, multiplier) = [
+        ("gbit", 1_000_000_000),
+        ("mbit", 1_000_000),
+        ("kbit", 1_000),
+        ("bit", 1),
+    ]
+    .into_iter()
+    .find_map(|(suffix, multiplier)| arg.strip_suffix(suffix).map(|rate| (rate, multiplier)))
+    .unwrap_or((arg, 1));
+
+    match rate.parse::<u64>().ok().and_then(|r| r.checked_mul(multiplier)) {
+        Some(bits) if bits >= 8 => Ok(bits / 8),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse rate from {arg}, expected i.e: 10mbit"),
+        )),
+    }
+}
+
+/// Parse a percentage of losses, i.e: 0.5%, and return it as a ratio
+pub fn parse_loss_rate(arg: &str) -> Result<f64, io::Error> {
+    match arg.strip_suffix('%').unwrap_or(arg).parse::<f64>() {
+        Ok(percent) if (0.0..100.0).contains(&percent) => Ok(percent / 100.0),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid loss percentage {arg}, expected a number from 0 to less than 100, i.e: 0.5%"),
+        )),
+    }
+}
+
+/// Parse the `[BIND:]PORT[-PORT]` a tunnel listens on, with the number of ports of its range
+pub fn parse_local_bind_range(arg: &str) -> Result<(SocketAddr, u16, &str), io::Error> {
+    use std::io::Error;
+
+    let (bind, remaining) = if arg.starts_with('[') {
+        // ipv6 bind
+        let Some((ipv6_str, remaining)) = arg.split_once(']') else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot parse IPv6 bind from {arg}"),
+            ));
+        };
+        let Ok(ipv6_addr) = Ipv6Addr::from_str(&ipv6_str[1..]) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot parse IPv6 bind from {ipv6_str}"),
+            ));
+        };
+
+        (IpAddr::V6(ipv6_addr), remaining)
+    } else {
+        // Maybe ipv4 addr
+        let (ipv4_str, remaining) = arg.split_once(':').unwrap_or((arg, ""));
+        Ipv4Addr::from_str(ipv4_str).map_or_else(
+            |_| (IpAddr::V4(Ipv4Addr::from_str("127.0.0.1").unwrap()), arg),
+            |ip4_addr| (IpAddr::V4(ip4_addr), remaining),
+        )
+    };
+
+    let remaining = remaining.trim_start_matches(':');
+    let (port_str, remaining) = remaining.split_once([':', '?']).unwrap_or((remaining, ""));
+
+    let (bind_port, port_count) = parse_port_range(port_str)?;
+
+    Ok((SocketAddr::new(bind, bind_port), port_count, remaining))
+}
+
+pub fn parse_local_bind(arg: &str) -> Result<(SocketAddr, &str), io::Error> {
+    match parse_local_bind_range(arg)? {
+        (bind, 1, remaining) => Ok((bind, remaining)),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot listen on the port range of {arg}, only tcp and udp tunnels support them"),
+        )),
+    }
+}
+
+/// Parse `PORT` or `PORT-PORT`, into the first port and the number of ports
+fn parse_port_range(arg: &str) -> Result<(u16, u16), io::Error> {
+    let invalid = || io::Error::new(ErrorKind::InvalidInput, format!("cannot parse port from {arg}"));
+    let Some((first, last)) = arg.split_once('-') else {
+        return Ok((arg.parse().map_err(|_| invalid())?, 1));
+    };
+
+    let first: u16 = first.parse().map_err(|_| invalid())?;
+    let last: u16 = last.parse().map_err(|_| invalid())?;
+    if first == 0 || last <= first {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid port range {arg}, expected FIRST-LAST with 0 < FIRST < LAST"),
+        ));
+    }
+    Ok((first, last - first + 1))
+}
+
+/// Parse the `cid:port` a vsock tunnel listens on. `any` stands for VMADDR_CID_ANY
+pub fn parse_vsock_bind(arg: &str) -> Result<(u32, u32, &str), io::Error> {
+    use std::io::Error;
+
+    let mut parts = arg.splitn(3, ':');
+    let (Some(cid_str), Some(port_str), Some(remaining)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse vsock cid:port from {arg}"),
+        ));
+    };
+
+    let cid = match cid_str {
+        "any" => u32::MAX,
+        cid_str => cid_str
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("cannot parse vsock cid from {cid_str}")))?,
+    };
+    let Ok(port): Result<u32, _> = port_str.parse() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse vsock port from {port_str}"),
+        ));
+    };
+
+    Ok((cid, port, remaining))
+}
+
+#[allow(clippy::type_complexity)]
+pub fn parse_tunnel_dest(remaining: &str) -> Result<(Host<String>, u16, BTreeMap<String, String>), io::Error> {
+    use std::io::Error;
+
+    // Using http or else the URL lib don't try to fully parse the host into an IPv4/IPv6
+    let Ok(remote) = Url::parse(&format!("https://{remaining}")) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse remote from {remaining}"),
+        ));
+    };
+
+    let Some(remote_host) = remote.host() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse remote host from {remaining}"),
+        ));
+    };
+
+    let remote_port = match remote.port() {
+        Some(remote_port) => remote_port,
+        // the url lib does not parse the port if it is the default one
+        None if remaining.ends_with(":443") || remaining.contains(":443?") => 443,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot parse remote port from {remaining}"),
+            ));
+        }
+    };
+
+    let options: BTreeMap<String, String> = remote.query_pairs().into_owned().collect();
+    Ok((remote_host.to_owned(), remote_port, options))
+}
+
+/// Same as [`parse_tunnel_dest`], with a port range of `port_count` ports instead of a single port if more than 1
+#[allow(clippy::type_complexity)]
+pub fn parse_tunnel_dest_range(
+    remaining: &str,
+    port_count: u16,
+) -> Result<(Host<String>, u16, BTreeMap<String, String>), io::Error> {
+    let (dest, query) = remaining.split_once('?').unwrap_or((remaining, ""));
+    let (dest, dest_port_count) = match dest.rsplit_once(':') {
+        Some((host, ports)) if ports.contains('-') => {
+            let (port, count) = parse_port_range(ports)?;
+            (format!("{host}:{port}?{query}"), count)
+        }
+        _ => (remaining.to_string(), 1),
+    };
+    if dest_port_count != port_count {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("the destination {remaining} must have as many ports as the local port range, {port_count}"),
+        ));
+    }
+
+    parse_tunnel_dest(&dest)
+}
+
+/// Split the `HOST:PORT,HOST:PORT?OPTIONS` destinations of a load balanced tunnel into the first one, still followed
+/// by the options, and the others
+#[allow(clippy::type_complexity)]
+fn split_tunnel_dests(remaining: &str) -> Result<(String, Vec<(Host<String>, u16)>), io::Error> {
+    let (dests, query) = remaining.split_once('?').unwrap_or((remaining, ""));
+    let mut dests = dests.split(',');
+    let first = dests.next().unwrap_or_default();
+    let others = dests
+        .map(|dest| parse_tunnel_dest(dest).map(|(host, port, _)| (host, port)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if query.is_empty() {
+        Ok((first.to_string(), others))
+    } else {
+        Ok((format!("{first}?{query}"), others))
+    }
+}
+
+pub fn parse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
+    use std::io::Error;
+    let get_timeout = |options: &BTreeMap<String, String>| {
+        options
+            .get("timeout_sec")
+            .and_then(|x| x.parse::<u64>().ok())
+            .map(|d| if d == 0 { None } else { Some(Duration::from_secs(d)) })
+            .unwrap_or(Some(Duration::from_secs(30)))
+    };
+    let get_credentials = |options: &BTreeMap<String, String>| {
+        options
+            .get("login")
+            .and_then(|login| options.get("password").map(|p| (login.to_string(), p.to_string())))
+    };
+    let get_proxy_protocol = |options: &BTreeMap<String, String>| options.contains_key("proxy_protocol");
+    let get_idle_timeout = |options: &BTreeMap<String, String>| -> Result<Option<Duration>, io::Error> {
+        match options.get("idle_timeout_sec").map(|t| t.parse::<u64>()) {
+            None | Some(Ok(0)) => Ok(None),
+            Some(Ok(timeout)) => Ok(Some(Duration::from_secs(timeout))),
+            Some(Err(_)) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid idle_timeout_sec, expected seconds",
+            )),
+        }
+    };
+    let get_keepalive = |options: &BTreeMap<String, String>| -> Result<Option<TunnelKeepalive>, io::Error> {
+        let get = |name: &str| match options.get(name).map(|value| value.parse::<u32>()) {
+            None => Ok(None),
+            Some(Ok(value)) if value > 0 => Ok(Some(value)),
+            Some(_) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid {name}, expected a number greater than 0"),
+            )),
+        };
+        let (idle, interval, count) = (
+            get("keepalive_idle_sec")?,
+            get("keepalive_interval_sec")?,
+            get("keepalive_count")?,
+        );
+        if idle.is_none() && interval.is_none() && count.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(TunnelKeepalive {
+            idle: idle.map_or(protocols::tcp::DEFAULT_KEEPALIVE_IDLE, |idle| Duration::from_secs(idle.into())),
+            interval: interval.map_or(protocols::tcp::DEFAULT_KEEPALIVE_INTERVAL, |interval| {
+                Duration::from_secs(interval.into())
+            }),
+            count: count.unwrap_or(protocols::tcp::DEFAULT_KEEPALIVE_COUNT),
+        }))
+    };
+    let get_mirror = |options: &BTreeMap<String, String>| -> Result<Option<(Host, u16)>, io::Error> {
+        match options.get("mirror") {
+            None => Ok(None),
+            Some(mirror) => parse_tunnel_dest(mirror).map(|(host, port, _)| Some((host, port))),
+        }
+    };
+    let get_balancing = |options: &BTreeMap<String, String>,
+                         fallbacks: Vec<(Host, u16)>,
+                         port_count: u16|
+     -> Result<Option<LoadBalancing>, io::Error> {
+        if fallbacks.is_empty() {
+            if options.contains_key("lb") || options.contains_key("health_check_sec") {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "lb and health_check_sec need several destinations, i.e: tcp://8080:backend1:80,backend2:80",
+                ));
+            }
+            return Ok(None);
+        }
+        if port_count > 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "cannot load balance a port range over several destinations",
+            ));
+        }
+        let strategy = match options.get("lb").map(String::as_str) {
+            None | Some("round_robin") => LoadBalancingStrategy::RoundRobin,
+            Some("random") => LoadBalancingStrategy::Random,
+            Some(lb) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid lb {lb}, must be one of round_robin or random"),
+                ));
+            }
+        };
+        let health_check = match options.get("health_check_sec").map(|t| t.parse::<u64>()) {
+            None | Some(Ok(0)) => None,
+            Some(Ok(health_check)) => Some(Duration::from_secs(health_check)),
+            Some(Err(_)) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "invalid health_check_sec, expected seconds",
+                ));
+            }
+        };
+
+        Ok(Some(LoadBalancing {
+            fallbacks,
+            strategy,
+            health_check,
+        }))
+    };
+    let get_resolve = |options: &BTreeMap<String, String>| -> Result<Socks5Resolve, io::Error> {
+        let fake_dns = options
+            .get("fake_dns")
+            .map(|addr| {
+                SocketAddr::from_str(addr).map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid fake_dns {addr}, expected IP:PORT i.e: 127.0.0.1:5353"),
+                    )
+                })
+            })
+            .transpose()?;
+        match (options.get("resolve").map(String::as_str), fake_dns) {
+            (None | Some("remote"), None) => Ok(Socks5Resolve::Remote),
+            (Some("local"), None) => Ok(Socks5Resolve::Local),
+            (Some("fake_ip"), Some(fake_dns)) => Ok(Socks5Resolve::FakeIp(fake_dns)),
+            (Some("fake_ip"), None) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "resolve=fake_ip needs the address of its dns server, i.e: fake_dns=127.0.0.1:5353",
+            )),
+            (_, Some(_)) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "fake_dns is only used with resolve=fake_ip",
+            )),
+            (Some(resolve), None) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid resolve {resolve}, must be one of remote, local or fake_ip"),
+            )),
+        }
+    };
+    let get_connect_ports = |options: &BTreeMap<String, String>| -> Result<Vec<RangeInclusive<u16>>, io::Error> {
+        let Some(ports) = options.get("connect_ports") else {
+            return Ok(vec![]);
+        };
+        ports
+            .split(',')
+            .map(|port| {
+                let (first, count) = parse_port_range(port).map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid connect_ports {ports}, expected ports or port ranges separated by ',' i.e: 443,8000-8999"),
+                    )
+                })?;
+                Ok(first..=first + (count - 1))
+            })
+            .collect()
+    };
+    let get_label = |options: &BTreeMap<String, String>| -> Result<Option<String>, io::Error> {
+        match options.get("label") {
+            None => Ok(None),
+            Some(label) if is_valid_label(label) => Ok(Some(label.clone())),
+            Some(label) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid label {label}, expected at most {MAX_LABEL_LEN} letters, digits, '.', '_' or '-'"),
+            )),
+        }
+    };
+    let get_allowed_uids = |options: &BTreeMap<String, String>| -> Result<Vec<u32>, io::Error> {
+        let Some(uids) = options.get("allowed_uids") else {
+            return Ok(vec![]);
+        };
+        uids.split(',')
+            .map(|uid| {
+                uid.parse::<u32>().map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid allowed_uids {uids}, expected user ids separated by ','"),
+                    )
+                })
+            })
+            .collect()
+    };
+    let get_permissions = |options: &BTreeMap<String, String>| -> Result<UnixSocketPermissions, io::Error> {
+        let mode = match options.get("mode") {
+            None => None,
+            Some(mode) => match u32::from_str_radix(mode, 8) {
+                Ok(mode) if mode <= 0o7777 => Some(mode),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid mode {mode}, expected octal permissions i.e: 660"),
+                    ));
+                }
+            },
+        };
+
+        Ok(UnixSocketPermissions {
+            mode,
+            owner: options.get("owner").cloned(),
+            group: options.get("group").cloned(),
+        })
+    };
+    let get_lifecycle = |options: &BTreeMap<String, String>| UnixSocketLifecycle {
+        remove_stale: options.contains_key("remove_stale"),
+        recreate: options.contains_key("recreate"),
+    };
+    let get_access = |options: &BTreeMap<String, String>| -> Result<AccessList, io::Error> {
+        let get_nets = |name: &str| -> Result<Vec<IpNet>, io::Error> {
+            let Some(nets) = options.get(name) else {
+                return Ok(vec![]);
+            };
+            nets.split(',')
+                .map(|net| {
+                    net.parse::<IpNet>()
+                        .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                        .map_err(|_| {
+                            Error::new(
+                                ErrorKind::InvalidInput,
+                                format!("invalid {name} {nets}, expected ips or cidrs separated by ',' i.e: 10.0.0.0/8,192.168.1.1"),
+                            )
+                        })
+                })
+                .collect()
+        };
+        Ok(AccessList {
+            allow: get_nets("allow")?,
+            deny: get_nets("deny")?,
+        })
+    };
+    let get_accept_limits = |options: &BTreeMap<String, String>| -> Result<AcceptLimits, io::Error> {
+        let max_conns = match options.get("max_conns") {
+            None => None,
+            Some(max_conns) => match max_conns.parse::<usize>() {
+                Ok(max_conns) if max_conns > 0 => Some(max_conns),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid max_conns {max_conns}, must be a positive integer"),
+                    ));
+                }
+            },
+        };
+        let accept_rate = match options.get("accept_rate") {
+            None => None,
+            Some(rate) => match rate.strip_suffix("/s").unwrap_or(rate).parse::<u32>() {
+                Ok(rate) if rate > 0 => Some(rate),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid accept_rate {rate}, expected a number of cnx per second i.e: 50/s"),
+                    ));
+                }
+            },
+        };
+        let overflow = match options.get("overflow").map(String::as_str) {
+            None | Some("queue") => AcceptOverflow::Queue,
+            Some("reject") => AcceptOverflow::Reject,
+            Some(overflow) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid overflow {overflow}, must be one of queue or reject"),
+                ));
+            }
+        };
+        Ok(AcceptLimits {
+            max_conns,
+            accept_rate,
+            overflow,
+        })
+    };
+    let get_dscp = |options: &BTreeMap<String, String>| -> Result<Option<u8>, io::Error> {
+        options.get("dscp").map(|dscp| parse_dscp(dscp)).transpose()
+    };
+    let get_v6only = |options: &BTreeMap<String, String>, local: &SocketAddr| -> Result<Option<bool>, io::Error> {
+        let v6only = match options.get("v6only").map(String::as_str) {
+            None => return Ok(None),
+            Some("true") => true,
+            Some("false") => false,
+            Some(v6only) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid v6only {v6only}, must be true or false"),
+                ));
+            }
+        };
+        if local.is_ipv4() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("v6only is only valid when listening on an ipv6 address, not {local}"),
+            ));
+        }
+        Ok(Some(v6only))
+    };
+    let get_resume = |options: &BTreeMap<String, String>| -> Result<Option<TunnelResume>, io::Error> {
+        let Some(buffer_size) = options.get("resume_buffer") else {
+            return Ok(None);
+        };
+        let buffer_size = match buffer_size.parse::<usize>() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid resume_buffer {buffer_size}, expected a number of bytes"),
+                ));
+            }
+        };
+        let timeout = match options.get("resume_timeout_sec").map(|t| t.parse::<u64>()) {
+            None => Duration::from_secs(60),
+            Some(Ok(timeout)) => Duration::from_secs(timeout),
+            Some(Err(_)) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "invalid resume_timeout_sec, expected seconds",
+                ));
+            }
+        };
+
+        Ok(Some(TunnelResume {
+            buffer_size,
+            timeout,
+            session: None,
+        }))
+    };
+
+    let Some((proto, tunnel_info)) = arg.split_once("://") else {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("cannot parse protocol from {arg}")));
+    };
+
+    match proto {
+        "tcp" => {
+            let (local_bind, port_count, remaining) = parse_local_bind_range(tunnel_info)?;
+            let (remaining, fallbacks) = split_tunnel_dests(remaining)?;
+            let (dest_host, dest_port, options) = parse_tunnel_dest_range(&remaining, port_count)?;
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::Tcp {
+                    proxy_protocol: get_proxy_protocol(&options),
+                    resume: get_resume(&options)?,
+                    idle_timeout: get_idle_timeout(&options)?,
+                    mirror: get_mirror(&options)?,
+                    balancing: get_balancing(&options, fallbacks, port_count)?,
+                    keepalive: get_keepalive(&options)?,
+                },
+                local: local_bind,
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: get_v6only(&options, &local_bind)?,
+                access: get_access(&options)?,
+                accept_limits: get_accept_limits(&options)?,
+                port_count,
+            })
+        }
+        "udp" => {
+            let (local_bind, port_count, remaining) = parse_local_bind_range(tunnel_info)?;
+            let (dest_host, dest_port, options) = parse_tunnel_dest_range(remaining, port_count)?;
+
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::Udp {
+                    timeout: get_timeout(&options),
+                },
+                local: local_bind,
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: get_v6only(&options, &local_bind)?,
+                access: AccessList::default(),
+                accept_limits: get_accept_limits(&options)?,
+                port_count,
+            })
+        }
+        "unix" => {
+            let Some((path, remote)) = tunnel_info.split_once(':') else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("cannot parse unix socket path from {arg}"),
+                ));
+            };
+            let (dest_host, dest_port, options) = parse_tunnel_dest(remote)?;
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::Unix {
+                    path: PathBuf::from(path),
+                    proxy_protocol: get_proxy_protocol(&options),
+                    allowed_uids: get_allowed_uids(&options)?,
+                    permissions: get_permissions(&options)?,
+                    lifecycle: get_lifecycle(&options),
+                },
+                local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: None,
+                access: AccessList::default(),
+                accept_limits: get_accept_limits(&options)?,
+                port_count: 1,
+            })
+        }
+        "sctp" => {
+            let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
+            let (dest_host, dest_port, options) = parse_tunnel_dest(remaining)?;
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::Sctp,
+                local: local_bind,
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: None,
+                access: AccessList::default(),
+                accept_limits: get_accept_limits(&options)?,
+                port_count: 1,
+            })
+        }
+        "vsock" => {
+            let (cid, port, remote) = parse_vsock_bind(tunnel_info)?;
+            let (dest_host, dest_port, options) = parse_tunnel_dest(remote)?;
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::Vsock { cid, port },
+                local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(0), 0)),
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: None,
+                access: AccessList::default(),
+                accept_limits: get_accept_limits(&options)?,
+                port_count: 1,
+            })
+        }
+        "http" => {
+            let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
+            let x = format!("0.0.0.0:0?{remaining}");
+            let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::HttpProxy {
+                    timeout: get_timeout(&options),
+                    credentials: get_credentials(&options),
+                    proxy_protocol: get_proxy_protocol(&options),
+                    resume: get_resume(&options)?,
+                    connect_ports: get_connect_ports(&options)?,
+                    path_headers: options.contains_key("path_headers"),
+                },
+                local: local_bind,
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: get_v6only(&options, &local_bind)?,
+                access: get_access(&options)?,
+                accept_limits: get_accept_limits(&options)?,
+                port_count: 1,
+            })
+        }
+        "socks5" => {
+            let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
+            let x = format!("0.0.0.0:0?{remaining}");
+            let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::Socks5 {
+                    timeout: get_timeout(&options),
+                    credentials: get_credentials(&options),
+                    resume: get_resume(&options)?,
+                    resolve: get_resolve(&options)?,
+                },
+                local: local_bind,
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: None,
+                access: get_access(&options)?,
+                accept_limits: get_accept_limits(&options)?,
+                port_count: 1,
+            })
+        }
+        "stdio" => {
+            let (dest_host, dest_port, options) = parse_tunnel_dest(tunnel_info)?;
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::Stdio {
+                    proxy_protocol: get_proxy_protocol(&options),
+                },
+                local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(0), 0)),
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: None,
+                access: AccessList::default(),
+                accept_limits: AcceptLimits::default(),
+                port_count: 1,
+            })
+        }
+        "stdio+udp" => {
+            let (dest_host, dest_port, options) = parse_tunnel_dest(tunnel_info)?;
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::StdioUdp {
+                    timeout: get_timeout(&options),
+                },
+                local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(0), 0)),
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: None,
+                access: AccessList::default(),
+                accept_limits: AcceptLimits::default(),
+                port_count: 1,
+            })
+        }
+        "tproxy+tcp" => {
+            let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
+            let x = format!("0.0.0.0:0?{remaining}");
+            let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::TProxyTcp,
+                local: local_bind,
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: None,
+                access: AccessList::default(),
+                accept_limits: get_accept_limits(&options)?,
+                port_count: 1,
+            })
+        }
+        "tproxy+udp" => {
+            let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
+            let x = format!("0.0.0.0:0?{remaining}");
+            let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
+            Ok(LocalToRemote {
+                local_protocol: LocalProtocol::TProxyUdp {
+                    timeout: get_timeout(&options),
+                },
+                local: local_bind,
+                remote: (dest_host, dest_port),
+                label: get_label(&options)?,
+                dscp: get_dscp(&options)?,
+                v6only: None,
+                access: AccessList::default(),
+                accept_limits: get_accept_limits(&options)?,
+                port_count: 1,
+            })
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid local protocol for tunnel {arg}"),
+        )),
+    }
+}
+
+pub fn parse_reverse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
+    // http://myapp.example.com:localhost:3000 exposes a local web app on a virtual host of the server,
+    // or on a subdomain allocated to the client with http://*.example.com:localhost:3000,
+    // while http://[BIND:]PORT is a reverse http proxy
+    if let Some(tunnel_info) = arg.strip_prefix("http://")
+        && let Some((vhost, dest)) = tunnel_info.split_once(':')
+        && let Ok(Host::Domain(domain)) = Host::parse(vhost.strip_prefix("*.").unwrap_or(vhost))
+    {
+        let vhost = if vhost.starts_with("*.") {
+            format!("*.{domain}")
+        } else {
+            domain
+        };
+        let proto = parse_tunnel_arg(&format!("tcp://0:{dest}"))?;
+        let (_, _, options) = parse_tunnel_dest(dest)?;
+        let auth = match (options.get("basic_auth"), options.get("auth_url")) {
+            (None, None) => None,
+            (Some(credentials), None) if credentials.contains(':') => Some(HttpIngressAuth::Basic(credentials.clone())),
+            (Some(_), None) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "invalid basic_auth, must be in the form user:password",
+                ));
+            }
+            (None, Some(url)) => match Url::parse(url) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Some(HttpIngressAuth::Forward(url)),
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid auth_url {url}, must be an http(s) url"),
+                    ));
+                }
+            },
+            (Some(_), Some(_)) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "basic_auth and auth_url cannot be used together",
+                ));
+            }
+        };
+        return Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseHttpIngress {
+                vhost,
+                session: None,
+                auth,
+            },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+            remote: proto.remote,
+            label: proto.label,
+            dscp: proto.dscp,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        });
+    }
+
+    // doh://[BIND:]PORT and dot://[BIND:]PORT expose the resolvers of the client through the server
+    let encrypted_dns = match arg.split_once("://") {
+        Some(("doh", tunnel_info)) => Some((EncryptedDns::Https, tunnel_info)),
+        Some(("dot", tunnel_info)) => Some((EncryptedDns::Tls, tunnel_info)),
+        _ => None,
+    };
+    if let Some((transport, tunnel_info)) = encrypted_dns {
+        let proto = parse_tunnel_arg(&format!("http://{tunnel_info}"))?;
+        let (_, remaining) = parse_local_bind(tunnel_info)?;
+        let (_, _, options) = parse_tunnel_dest(&format!("0.0.0.0:0?{remaining}"))?;
+        let (tls_certificate, tls_private_key) = match (options.get("tls_certificate"), options.get("tls_private_key"))
+        {
+            (Some(certificate), Some(private_key)) => {
+                (Some(PathBuf::from(certificate)), Some(PathBuf::from(private_key)))
+            }
+            (None, None) => (None, None),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("tls_certificate and tls_private_key must be given together for {arg}"),
+                ));
+            }
+        };
+        return Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseEncryptedDns {
+                transport,
+                tls_certificate,
+                tls_private_key,
+            },
+            local: proto.local,
+            remote: proto.remote,
+            label: proto.label,
+            dscp: proto.dscp,
+            v6only: proto.v6only,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        });
+    }
+
+    let proto = parse_tunnel_arg(arg)?;
+    if !proto.accept_limits.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("max_conns and accept_rate are only available for -L tunnels, not for {arg}"),
+        ));
+    }
+    let local_protocol = match proto.local_protocol {
+        LocalProtocol::Tcp { balancing: Some(_), .. } => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "cannot load balance the reverse tunnel {arg}, only -L tcp tunnels support several destinations"
+                ),
+            ));
+        }
+        LocalProtocol::Tcp {
+            resume,
+            idle_timeout,
+            keepalive,
+            ..
+        } => LocalProtocol::ReverseTcp {
+            resume,
+            idle_timeout,
+            v6only: proto.v6only,
+            access: proto.access.clone(),
+            keepalive,
+        },
+        LocalProtocol::Udp { timeout } => {
+            // parse_tunnel_arg already validated the arg, we only need to extract the reverse only options
+            let tunnel_info = arg.split_once("://").map_or("", |(_, info)| info);
+            let (_, port_count, remaining) = parse_local_bind_range(tunnel_info)?;
+            let (_, _, options) = parse_tunnel_dest_range(remaining, port_count)?;
+            let max_flows = match options.get("max_flows") {
+                None => None,
+                Some(max_flows) => match max_flows.parse::<usize>() {
+                    Ok(0) | Err(_) => {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("cannot parse max_flows from {max_flows}, must be a positive integer"),
+                        ));
+                    }
+                    Ok(max_flows) => Some(max_flows),
+                },
+            };
+            let flow_eviction = match options.get("flow_eviction").map(String::as_str) {
+                None | Some("drop_new") => UdpFlowEviction::DropNew,
+                Some("evict_idlest") => UdpFlowEviction::EvictIdlest,
+                Some(policy) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid flow_eviction {policy}, must be one of drop_new or evict_idlest"),
+                    ));
+                }
+            };
+
+            LocalProtocol::ReverseUdp {
+                timeout,
+                max_flows,
+                flow_eviction,
+                v6only: proto.v6only,
+            }
+        }
+        LocalProtocol::Socks5 { resolve, .. } if resolve != Socks5Resolve::Remote => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot resolve the requests of the reverse socks5 tunnel {arg} on the client"),
+            ));
+        }
+        LocalProtocol::Socks5 {
+            timeout, credentials, ..
+        } => LocalProtocol::ReverseSocks5 {
+            timeout,
+            credentials,
+            access: proto.access.clone(),
+        },
+        LocalProtocol::HttpProxy { ref connect_ports, .. } if !connect_ports.is_empty() => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("connect_ports is only available for -L http proxies, not for {arg}"),
+            ));
+        }
+        LocalProtocol::HttpProxy { path_headers: true, .. } => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("path_headers is only available for -L http proxies, not for {arg}"),
+            ));
+        }
+        LocalProtocol::HttpProxy {
+            timeout, credentials, ..
+        } => LocalProtocol::ReverseHttpProxy {
+            timeout,
+            credentials,
+            v6only: proto.v6only,
+            access: proto.access.clone(),
+        },
+        LocalProtocol::Unix {
+            path,
+            allowed_uids,
+            permissions,
+            lifecycle,
+            ..
+        } => LocalProtocol::ReverseUnix {
+            path,
+            allowed_uids,
+            permissions,
+            lifecycle,
+        },
+        LocalProtocol::ReverseTcp { .. }
+        | LocalProtocol::ReverseUdp { .. }
+        | LocalProtocol::ReverseSocks5 { .. }
+        | LocalProtocol::ReverseHttpProxy { .. }
+        | LocalProtocol::ReverseUnix { .. }
+        | LocalProtocol::ReverseHttpIngress { .. }
+        | LocalProtocol::ReverseEncryptedDns { .. }
+        | LocalProtocol::TProxyTcp
+        | LocalProtocol::TProxyUdp { .. }
+        | LocalProtocol::Stdio { .. }
+        | LocalProtocol::StdioUdp { .. }
+        | LocalProtocol::Vsock { .. }
+        | LocalProtocol::Sctp
+        | LocalProtocol::Mux => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Cannot use {:?} as reverse tunnels {}", proto.local_protocol, arg),
+            ));
+        }
+    };
+
+    Ok(LocalToRemote {
+        local_protocol,
+        local: proto.local,
+        remote: proto.remote,
+        label: proto.label,
+        dscp: proto.dscp,
+        v6only: proto.v6only,
+        access: proto.access,
+        accept_limits: AcceptLimits::default(),
+        port_count: proto.port_count,
+    })
+}
+
+pub fn parse_device_id(arg: &str) -> Result<String, io::Error> {
+    if is_valid_label(arg) {
+        Ok(arg.to_string())
+    } else {
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid device id {arg}, expected at most {MAX_LABEL_LEN} letters, digits, '.', '_' or '-'"),
+        ))
+    }
+}
+
+pub fn parse_http_ingress_reserve(arg: &str) -> Result<(String, String), io::Error> {
+    match arg.split_once('=') {
+        Some((name, identity)) if !name.is_empty() && !name.contains('.') && !identity.is_empty() => {
+            Ok((name.to_ascii_lowercase(), identity.to_string()))
+        }
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid http ingress reservation {arg}, expected NAME=IDENTITY i.e: myapp=team-a"),
+        )),
+    }
+}
+
+/// Websocket close code telling a close reason, i.e: idle=4408
+pub fn parse_websocket_close_code(arg: &str) -> Result<(CloseReason, u16), io::Error> {
+    let parsed = arg.split_once('=').and_then(|(reason, code)| {
+        let reason = CloseReason::from_str(reason)
+            .ok()
+            .filter(|reason| *reason != CloseReason::Normal)?;
+        let code = code
+            .parse::<u16>()
+            .ok()
+            .filter(|code| WsCloseCodes::VALID_CODES.contains(code))?;
+        Some((reason, code))
+    });
+
+    parsed.ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "invalid websocket close code {arg}, expected REASON=CODE with a reason among reset, timeout, error, corrupted or idle, and a code between {} and {} i.e: idle=4408",
+                WsCloseCodes::VALID_CODES.start(),
+                WsCloseCodes::VALID_CODES.end()
+            ),
+        )
+    })
+}
+
+/// Address of a server connections are forwarded to, i.e: 127.0.0.1:8443
+pub fn parse_backend(arg: &str) -> Result<(Host, u16), io::Error> {
+    match parse_tunnel_dest(arg) {
+        Ok((host, port, options)) if options.is_empty() => Ok((host, port)),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid address {arg}, expected HOST:PORT i.e: 127.0.0.1:8443"),
+        )),
+    }
+}
+
+pub fn parse_protocol_handler(arg: &str) -> Result<(SniffedProtocol, ProtocolHandler), io::Error> {
+    let invalid = || {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "invalid protocol handler {arg}, expected PROTOCOL=HANDLER with PROTOCOL one of tls, http, h2, ssh or unknown \
+                 and HANDLER one of wstunnel, reject or HOST:PORT, i.e: ssh=127.0.0.1:22"
+            ),
+        )
+    };
+    let (protocol, handler) = arg.split_once('=').ok_or_else(invalid)?;
+    let protocol = SniffedProtocol::from_str(protocol).map_err(|_| invalid())?;
+    let handler = match handler {
+        "wstunnel" => ProtocolHandler::Wstunnel,
+        "reject" => ProtocolHandler::Reject,
+        backend => {
+            let (host, port) = parse_backend(backend).map_err(|_| invalid())?;
+            ProtocolHandler::Passthrough(host, port)
+        }
+    };
+    Ok((protocol, handler))
+}
+
+pub fn parse_noise_key(arg: &str) -> Result<NoiseKey, io::Error> {
+    NoiseKey::from_base64(arg)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, format!("invalid noise key: {err:#}")))
+}
+
+pub fn parse_dscp(arg: &str) -> Result<u8, io::Error> {
+    match arg.parse::<u8>() {
+        Ok(dscp) if dscp <= MAX_DSCP => Ok(dscp),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid dscp {arg}, expected a number between 0 and {MAX_DSCP}, i.e: 46"),
+        )),
+    }
+}
+
+pub fn parse_percent(arg: &str) -> Result<u8, io::Error> {
+    match arg.strip_suffix('%').unwrap_or(arg).parse::<u8>() {
+        Ok(percent) if (1..=100).contains(&percent) => Ok(percent),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid percentage {arg}, expected a number between 1 and 100, i.e: 10"),
+        )),
+    }
+}
+
+pub fn parse_noise_private_key(arg: &str) -> Result<NoiseKey, io::Error> {
+    parse_noise_key(&resolve_secret(arg)?)
+}
+
+/// Resolve an option holding a secret, so it does not have to appear in the arguments of the process:
+/// - file:PATH       the content of the file, without its trailing newline
+/// - env:NAME        the value of the environment variable
+/// - exec:COMMAND    the output of the command, run with `sh -c`, without its trailing newline
+/// - cred:NAME       the systemd credential NAME (LoadCredential=), read from $CREDENTIALS_DIRECTORY
+///
+/// Any other value is the secret itself
+pub fn resolve_secret(arg: &str) -> Result<String, io::Error> {
+    let trim_newline = |secret: String| secret.trim_end_matches(['\r', '\n']).to_string();
+    let invalid = |msg: String| io::Error::new(ErrorKind::InvalidInput, msg);
+
+    if let Some(path) = arg.strip_prefix("file:") {
+        return std::fs::read_to_string(path)
+            .map(trim_newline)
+            .map_err(|err| invalid(format!("cannot read secret from file {path}: {err}")));
+    }
+
+    if let Some(name) = arg.strip_prefix("env:") {
+        return std::env::var(name).map_err(|err| invalid(format!("cannot read secret from env var {name}: {err}")));
+    }
+
+    if let Some(name) = arg.strip_prefix("cred:") {
+        let Some(dir) = std::env::var_os("CREDENTIALS_DIRECTORY") else {
+            return Err(invalid(format!(
+                "cannot read secret from credential {name}: CREDENTIALS_DIRECTORY is not set, run with systemd LoadCredential={name}"
+            )));
+        };
+        let path = PathBuf::from(dir).join(name);
+        return std::fs::read_to_string(&path)
+            .map(trim_newline)
+            .map_err(|err| invalid(format!("cannot read secret from credential {}: {err}", path.display())));
+    }
+
+    if let Some(command) = arg.strip_prefix("exec:") {
+        #[cfg(windows)]
+        let output = std::process::Command::new("cmd").args(["/C", command]).output();
+        #[cfg(not(windows))]
+        let output = std::process::Command::new("sh").args(["-c", command]).output();
+
+        let output = output.map_err(|err| invalid(format!("cannot run secret command {command}: {err}")))?;
+        if !output.status.success() {
+            return Err(invalid(format!("secret command {command} failed with {}", output.status)));
+        }
+        return String::from_utf8(output.stdout)
+            .map(trim_newline)
+            .map_err(|_| invalid(format!("secret command {command} did not output utf-8")));
+    }
+
+    Ok(arg.to_string())
+}
+
+/// Same as [`resolve_secret`], wrapped so the secret is redacted from the logs
+pub fn parse_secret(arg: &str) -> Result<Secret<String>, io::Error> {
+    resolve_secret(arg).map(Secret::new)
+}
+
+pub fn parse_split_requests(arg: &str) -> Result<SplitRequests, io::Error> {
+    SplitRequests::from_str(arg).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value {arg}, expected one of never, always or auto"),
+        )
+    })
+}
+
+pub fn parse_on_listener_error(arg: &str) -> Result<OnListenerError, io::Error> {
+    OnListenerError::from_str(arg).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value {arg}, expected one of fail, skip or retry"),
+        )
+    })
+}
+
+pub fn parse_cpu_affinity(arg: &str) -> Result<CpuAffinity, io::Error> {
+    CpuAffinity::from_str(arg).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value {arg}, expected auto or a list of cpus, i.e: 0-7,16-23"),
+        )
+    })
+}
+
+pub fn parse_crypto_provider(arg: &str) -> Result<CryptoProviderKind, io::Error> {
+    CryptoProviderKind::from_str(arg).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value {arg}, expected one of ring or aws-lc-rs"),
+        )
+    })
+}
+
+pub fn parse_tls_fingerprint(arg: &str) -> Result<TlsFingerprint, io::Error> {
+    TlsFingerprint::from_str(arg).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value {arg}, expected one of rustls, chrome or firefox"),
+        )
+    })
+}
+
+pub fn parse_redirect_policy(arg: &str) -> Result<RedirectPolicy, io::Error> {
+    RedirectPolicy::from_str(arg).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value {arg}, expected one of same-origin, same-host or any"),
+        )
+    })
+}
+
+pub fn parse_camouflage(arg: &str) -> Result<Browser, io::Error> {
+    Browser::from_str(arg).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value {arg}, expected one of browser-chrome or browser-firefox"),
+        )
+    })
+}
+
+pub fn parse_client_version(arg: &str) -> Result<ClientVersion, io::Error> {
+    ClientVersion::from_str(arg).map_err(|err| io::Error::new(ErrorKind::InvalidInput, err.to_string()))
+}
+
+pub fn parse_http_status(arg: &str) -> Result<StatusCode, io::Error> {
+    match StatusCode::from_str(arg) {
+        Ok(status) if (200..600).contains(&status.as_u16()) => Ok(status),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid http status {arg}, expected a code between 200 and 599"),
+        )),
+    }
+}
+
+pub fn parse_sni_override(arg: &str) -> Result<DnsName<'static>, io::Error> {
+    match DnsName::try_from(arg.to_string()) {
+        Ok(val) => Ok(val),
+        Err(err) => Err(io::Error::new(ErrorKind::InvalidInput, format!("Invalid sni override: {err}"))),
+    }
+}
+
+pub fn parse_http_headers(arg: &str) -> Result<(HeaderName, HeaderValue), io::Error> {
+    let Some((key, value)) = arg.split_once(':') else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse http header from {arg}"),
+        ));
+    };
+
+    let mut value = match HeaderValue::from_str(value.trim()) {
+        Ok(value) => value,
+        Err(err) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot parse http header value of {key} due to {err:?}"),
+            ));
+        }
+    };
+
+    let name = HeaderName::from_str(key).unwrap();
+    mark_sensitive_header(&name, &mut value);
+    Ok((name, value))
+}
+
+pub fn parse_http_credentials(arg: &str) -> Result<HeaderValue, io::Error> {
+    let credentials = resolve_secret(arg)?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(credentials.trim().as_bytes());
+    let Ok(mut header) = HeaderValue::from_str(&format!("Basic {encoded}")) else {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "cannot parse http credentials"));
+    };
+    header.set_sensitive(true);
+
+    Ok(header)
+}
+
+pub fn parse_host_port(host: &str, port: u16) -> Result<(Host, u16), io::Error> {
+    let (host, port, _) = if host.contains(':') && !host.starts_with('[') {
+        parse_tunnel_dest(&format!("[{host}]:{port}"))?
+    } else {
+        parse_tunnel_dest(&format!("{host}:{port}"))?
+    };
+    Ok((host, port))
+}
+
+/// Parse the destination out of a SSH_CONNECTION like value: "client_ip client_port server_ip server_port"
+pub fn parse_ssh_connection(arg: &str) -> Result<(Host, u16), io::Error> {
+    let fields: Vec<&str> = arg.split_whitespace().collect();
+    let [_, _, host, port] = fields.as_slice() else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("expected 'client_ip client_port server_ip server_port' in SSH_CONNECTION, got {arg}"),
+        ));
+    };
+    let Ok(port) = port.parse::<u16>() else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse port from {port} in SSH_CONNECTION"),
+        ));
+    };
+
+    parse_host_port(host, port)
+}
+
+pub fn parse_server_url(arg: &str) -> Result<Url, io::Error> {
+    let Ok(url) = Url::parse(arg) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse server url {arg}"),
+        ));
+    };
+
+    if !TransportScheme::values().iter().any(|x| x.to_str() == url.scheme()) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid scheme {}", url.scheme()),
+        ));
+    }
+
+    if url.host().is_none() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid server host {arg}")));
+    }
+
+    Ok(url)
+}
+
+// Deserializers of the same values, to load them from a configuration file with serde.
+// i.e: #[serde(deserialize_with = "wstunnel::config::parsers::deserialize_tunnels")]
+
+/// A duration in seconds, or with a unit like on the command line, i.e: 30, "30s", "5m" or "1h"
+pub fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum DurationArg {
+        Secs(u64),
+        Arg(String),
+    }
+
+    match DurationArg::deserialize(deserializer)? {
+        DurationArg::Secs(secs) => Ok(Duration::from_secs(secs)),
+        DurationArg::Arg(arg) => parse_duration_sec(&arg).map_err(de::Error::custom),
+    }
+}
+
+pub fn deserialize_optional_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "deserialize_duration")] Duration);
+
+    Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(duration)| duration))
+}
+
+pub fn deserialize_sni<'de, D>(deserializer: D) -> Result<DnsName<'static>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let sni = String::deserialize(deserializer)?;
+    parse_sni_override(&sni).map_err(de::Error::custom)
+}
+
+/// A secret, or where to read it from like on the command line, i.e: "file:/run/secrets/psk" or "env:WSTUNNEL_PSK"
+pub fn deserialize_secret<'de, D>(deserializer: D) -> Result<Secret<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secret = String::deserialize(deserializer)?;
+    parse_secret(&secret).map_err(de::Error::custom)
+}
+
+/// Tunnels written like the values of -L, i.e: ["tcp://1212:google.com:443", "socks5://[::1]:1080"]
+pub fn deserialize_tunnels<'de, D>(deserializer: D) -> Result<Vec<LocalToRemote>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tunnels: Vec<String> = Deserialize::deserialize(deserializer)?;
+    tunnels
+        .iter()
+        .map(|tunnel| parse_tunnel_arg(tunnel).map_err(de::Error::custom))
+        .collect()
+}
+
+/// Tunnels written like the values of -R, i.e: ["tcp://2222:localhost:22"]
+pub fn deserialize_reverse_tunnels<'de, D>(deserializer: D) -> Result<Vec<LocalToRemote>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tunnels: Vec<String> = Deserialize::deserialize(deserializer)?;
+    tunnels
+        .iter()
+        .map(|tunnel| parse_reverse_tunnel_arg(tunnel).map_err(de::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        LocalToRemote, parse_bit_rate, parse_byte_size, parse_camouflage, parse_device_id, parse_duration_ms,
+        parse_frame_size, parse_http_credentials, parse_http_ingress_reserve, parse_http_status, parse_local_bind,
+        parse_loss_rate, parse_percent, parse_protocol_handler, parse_redirect_policy, parse_reverse_tunnel_arg,
+        parse_ssh_connection, parse_tls_fingerprint, parse_tunnel_arg, parse_tunnel_dest, parse_websocket_close_code,
+        resolve_secret,
+    };
+    use crate::protocols::tls::TlsFingerprint;
+    use crate::tunnel::client::{AcceptLimits, AcceptOverflow, Browser, RedirectPolicy};
+    use crate::tunnel::protocol::close_reason::CloseReason;
+    use crate::tunnel::server::{ProtocolHandler, SniffedProtocol};
+    use crate::tunnel::{
+        AccessList, EncryptedDns, HttpIngressAuth, LoadBalancing, LoadBalancingStrategy, LocalProtocol, Socks5Resolve,
+        TunnelKeepalive, TunnelResume, UdpFlowEviction, UnixSocketLifecycle, UnixSocketPermissions,
+    };
+    use collection_macros::btreemap;
+    use hyper::StatusCode;
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use test_case::test_case;
+    use url::Host;
+
+    #[test_case("localhost:443" => (Host::Domain("localhost".to_string()), 443, BTreeMap::new()) ; "with domain")]
+    #[test_case("localhost:443?timeout_sec=0" => (Host::Domain("localhost".to_string()), 443, btreemap! { "timeout_sec".to_string() => "0".to_string() } ) ; "with domain and options")]
+    #[test_case("127.0.0.1:443" => (Host::Ipv4(Ipv4Addr::new(127, 0, 0, 1)), 443, BTreeMap::new()) ; "with IPv4")]
+    #[test_case("[::1]:8080" => (Host::Ipv6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8080, BTreeMap::new()) ; "with IpV6")]
+    #[test_case("a:1?timeout_sec=30&b=5" => (Host::Domain("a".to_string()), 1, btreemap! { "b".to_string() => "5".to_string(), "timeout_sec".to_string() => "30".to_string() }) ; "with options")]
+    fn test_parse_tunnel_dest(input: &str) -> (Host<String>, u16, BTreeMap<String, String>) {
+        parse_tunnel_dest(input).unwrap()
+    }
+
+    const LOCALHOST_IP4: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 443);
+    const LOCALHOST_IP6: SocketAddrV6 = SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 443, 0, 0);
+
+    #[test_case("domain.com:443" => matches Err(_) ; "with domain")]
+    #[test_case("127.0.0.1" => matches Err(_) ; "with no port")]
+    #[test_case("127.0.0.1:444444443" => matches Err(_) ; "with too long port")]
+    #[test_case("127.0.0.1:443" => matches Ok((SocketAddr::V4(LOCALHOST_IP4), _)) ; "with ipv4")]
+    #[test_case("[::1]:443" => matches Ok((SocketAddr::V6(LOCALHOST_IP6), _)) ; "with ipv6")]
+    fn test_parse_local_bind(input: &str) -> Result<(SocketAddr, &str), io::Error> {
+        parse_local_bind(input)
+    }
+
+    #[test_case("65536" => matches Ok(65536) ; "with bytes")]
+    #[test_case("64k" => matches Ok(65536) ; "with kilobytes")]
+    #[test_case("32m" => matches Ok(33554432) ; "with megabytes")]
+    #[test_case("1k" => matches Err(_) ; "with too small size")]
+    #[test_case("abc" => matches Err(_) ; "with invalid size")]
+    fn test_parse_frame_size(input: &str) -> Result<usize, io::Error> {
+        parse_frame_size(input)
+    }
+
+    #[test_case("20ms" => matches Ok(d) if d == Duration::from_millis(20) ; "with milliseconds")]
+    #[test_case("2s" => matches Ok(d) if d == Duration::from_secs(2) ; "with seconds")]
+    #[test_case("2" => matches Ok(d) if d == Duration::from_secs(2) ; "without unit")]
+    #[test_case("fastms" => matches Err(_) ; "with invalid milliseconds")]
+    fn test_parse_duration_ms(input: &str) -> Result<Duration, io::Error> {
+        parse_duration_ms(input)
+    }
+
+    #[test_case("10" => matches Ok(10) ; "with number")]
+    #[test_case("25%" => matches Ok(25) ; "with percent sign")]
+    #[test_case("0" => matches Err(_) ; "with zero")]
+    #[test_case("150" => matches Err(_) ; "with more than 100")]
+    fn test_parse_percent(input: &str) -> Result<u8, io::Error> {
+        parse_percent(input)
+    }
+
+    #[test_case("browser-chrome" => matches Ok(Browser::Chrome) ; "with chrome")]
+    #[test_case("browser-firefox" => matches Ok(Browser::Firefox) ; "with firefox")]
+    #[test_case("chrome" => matches Err(_) ; "without prefix")]
+    fn test_parse_camouflage(input: &str) -> Result<Browser, io::Error> {
+        parse_camouflage(input)
+    }
+
+    #[test_case("same-host" => matches Ok(RedirectPolicy::SameHost) ; "with same host")]
+    #[test_case("any" => matches Ok(RedirectPolicy::Any) ; "with any")]
+    #[test_case("same_origin" => matches Err(_) ; "with underscore")]
+    fn test_parse_redirect_policy(input: &str) -> Result<RedirectPolicy, io::Error> {
+        parse_redirect_policy(input)
+    }
+
+    #[test_case("rustls" => matches Ok(TlsFingerprint::Rustls) ; "with rustls")]
+    #[test_case("chrome" => matches Ok(TlsFingerprint::Chrome) ; "with chrome")]
+    #[test_case("safari" => matches Err(_) ; "with unknown browser")]
+    fn test_parse_tls_fingerprint(input: &str) -> Result<TlsFingerprint, io::Error> {
+        parse_tls_fingerprint(input)
+    }
+
+    #[test_case("404" => matches Ok(StatusCode::NOT_FOUND) ; "with not found")]
+    #[test_case("302" => matches Ok(StatusCode::FOUND) ; "with redirect")]
+    #[test_case("101" => matches Err(_) ; "with switching protocols")]
+    #[test_case("abc" => matches Err(_) ; "with text")]
+    fn test_parse_http_status(input: &str) -> Result<StatusCode, io::Error> {
+        parse_http_status(input)
+    }
+
+    #[test_case("MyApp=team-a" => matches Ok((ref name, ref identity)) if name == "myapp" && identity == "team-a" ; "with reservation")]
+    #[test_case("my.app=team-a" => matches Err(_) ; "with nested name")]
+    #[test_case("myapp" => matches Err(_) ; "without identity")]
+    fn test_parse_http_ingress_reserve(input: &str) -> Result<(String, String), io::Error> {
+        parse_http_ingress_reserve(input)
+    }
+
+    #[test_case("sensor-42.lab_1" => matches Ok(ref id) if id == "sensor-42.lab_1" ; "with device id")]
+    #[test_case("sensor 42" => matches Err(_) ; "with space")]
+    #[test_case("" => matches Err(_) ; "empty")]
+    fn test_parse_device_id(input: &str) -> Result<String, io::Error> {
+        parse_device_id(input)
+    }
+
+    #[test_case("idle=4408" => matches Ok((CloseReason::Idle, 4408)) ; "with idle")]
+    #[test_case("timeout=3008" => matches Ok((CloseReason::TimedOut, 3008)) ; "with library code")]
+    #[test_case("normal=4000" => matches Err(_) ; "with normal close")]
+    #[test_case("reset=1011" => matches Err(_) ; "with reserved code")]
+    #[test_case("unknown=4001" => matches Err(_) ; "with unknown reason")]
+    #[test_case("reset" => matches Err(_) ; "without code")]
+    fn test_parse_websocket_close_code(input: &str) -> Result<(CloseReason, u16), io::Error> {
+        parse_websocket_close_code(input)
+    }
+
+    #[test_case("512" => matches Ok(512) ; "with bytes")]
+    #[test_case("100m" => matches Ok(104_857_600) ; "with mebibytes")]
+    #[test_case("2g" => matches Ok(2_147_483_648) ; "with gibibytes")]
+    #[test_case("0" => matches Err(_) ; "with zero")]
+    #[test_case("10mb" => matches Err(_) ; "with unknown suffix")]
+    fn test_parse_byte_size(input: &str) -> Result<u64, io::Error> {
+        parse_byte_size(input)
+    }
+
+    #[test_case("10mbit" => matches Ok(1_250_000) ; "with megabits")]
+    #[test_case("512kbit" => matches Ok(64_000) ; "with kilobits")]
+    #[test_case("1gbit" => matches Ok(125_000_000) ; "with gigabits")]
+    #[test_case("800" => matches Ok(100) ; "with bits")]
+    #[test_case("4bit" => matches Err(_) ; "with less than a byte")]
+    #[test_case("10mb" => matches Err(_) ; "with unknown suffix")]
+    fn test_parse_bit_rate(input: &str) -> Result<u64, io::Error> {
+        parse_bit_rate(input)
+    }
+
+    #[test_case("0.5%" => matches Ok(0.005) ; "with percent sign")]
+    #[test_case("2" => matches Ok(0.02) ; "without percent sign")]
+    #[test_case("0" => matches Ok(0.0) ; "without losses")]
+    #[test_case("100%" => matches Err(_) ; "with every segment lost")]
+    #[test_case("-1%" => matches Err(_) ; "with negative percentage")]
+    fn test_parse_loss_rate(input: &str) -> Result<f64, io::Error> {
+        parse_loss_rate(input)
+    }
+
+    #[test_case("ssh=127.0.0.1:22" => matches Ok((SniffedProtocol::Ssh, ProtocolHandler::Passthrough(Host::Ipv4(_), 22))) ; "with passthrough")]
+    #[test_case("http=wstunnel" => matches Ok((SniffedProtocol::Http, ProtocolHandler::Wstunnel)) ; "with wstunnel")]
+    #[test_case("unknown=reject" => matches Ok((SniffedProtocol::Unknown, ProtocolHandler::Reject)) ; "with reject")]
+    #[test_case("quic=wstunnel" => matches Err(_) ; "with unknown protocol")]
+    #[test_case("ssh=localhost" => matches Err(_) ; "without port")]
+    fn test_parse_protocol_handler(input: &str) -> Result<(SniffedProtocol, ProtocolHandler), io::Error> {
+        parse_protocol_handler(input)
+    }
+
+    #[test_case("10.0.0.2 51234 10.0.0.1 22" => (Host::Ipv4(Ipv4Addr::new(10, 0, 0, 1)), 22) ; "with ipv4")]
+    #[test_case("::2 51234 ::1 2222" => (Host::Ipv6(Ipv6Addr::LOCALHOST), 2222) ; "with ipv6")]
+    #[test_case("10.0.0.2 51234 10.0.0.1" => panics "" ; "with missing port")]
+    #[test_case("10.0.0.2 51234 10.0.0.1 ssh" => panics "" ; "with invalid port")]
+    fn test_parse_ssh_connection(input: &str) -> (Host, u16) {
+        parse_ssh_connection(input).unwrap()
+    }
+
+    #[test_case("domain.com:443" => panics ""; "with no protocol")]
+    #[test_case("sdsf://443:domain.com:443" => panics ""; "with invalid protocol")]
+    #[test_case("tcp://443:domain.com:4443" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 443)),
+            remote: (Host::Domain("domain.com".to_string()), 4443),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with no local bind")]
+    #[test_case("tcp://443:domain.com:4443?idle_timeout_sec=600" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: Some(Duration::from_secs(600)),
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 443)),
+            remote: (Host::Domain("domain.com".to_string()), 4443),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with idle timeout")]
+    #[test_case("tcp://443:domain.com:4443?keepalive_idle_sec=300&keepalive_count=5" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: Some(TunnelKeepalive {
+                    idle: Duration::from_secs(300),
+                    interval: Duration::from_secs(10),
+                    count: 5,
+                }),
+            },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 443)),
+            remote: (Host::Domain("domain.com".to_string()), 4443),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with keepalive")]
+    #[test_case("tcp://443:domain.com:4443?mirror=[::1]:4444" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: Some((Host::Ipv6(Ipv6Addr::LOCALHOST), 4444)),
+                balancing: None,
+                keepalive: None,
+            },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 443)),
+            remote: (Host::Domain("domain.com".to_string()), 4443),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with mirror")]
+    #[test_case("tcp://8080:backend1:80,[::1]:8080?lb=random&health_check_sec=10" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: Some(LoadBalancing {
+                    fallbacks: vec![(Host::Ipv6(Ipv6Addr::LOCALHOST), 8080)],
+                    strategy: LoadBalancingStrategy::Random,
+                    health_check: Some(Duration::from_secs(10)),
+                }),
+                keepalive: None,
+            },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8080)),
+            remote: (Host::Domain("backend1".to_string()), 80),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with load balancing")]
+    #[test_case("socks5://[::1]:1080?resolve=fake_ip&fake_dns=127.0.0.1:5353" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Socks5 {
+                timeout: Some(Duration::from_secs(30)),
+                credentials: None,
+                resume: None,
+                resolve: Socks5Resolve::FakeIp(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 5353))),
+            },
+            local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 1080, 0, 0)),
+            remote: (Host::Ipv4(Ipv4Addr::UNSPECIFIED), 0),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with socks5 fake ip")]
+    #[test_case("http://1080?connect_ports=443,8000-8999" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::HttpProxy {
+                timeout: Some(Duration::from_secs(30)),
+                credentials: None,
+                proxy_protocol: false,
+                resume: None,
+                connect_ports: vec![443..=443, 8000..=8999],
+                path_headers: false,
+            },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1080)),
+            remote: (Host::Ipv4(Ipv4Addr::UNSPECIFIED), 0),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with http proxy connect ports")]
+    #[test_case("socks5://0.0.0.0:1080?allow=10.0.0.0/8,192.168.1.1&deny=0.0.0.0/0" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Socks5 {
+                timeout: Some(Duration::from_secs(30)),
+                credentials: None,
+                resume: None,
+                resolve: Socks5Resolve::Remote,
+            },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 1080)),
+            remote: (Host::Ipv4(Ipv4Addr::UNSPECIFIED), 0),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList {
+                allow: vec!["10.0.0.0/8".parse().unwrap(), "192.168.1.1/32".parse().unwrap()],
+                deny: vec!["0.0.0.0/0".parse().unwrap()],
+            },
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with socks5 access list")]
+    #[test_case("http://1080?path_headers" =>
+        matches LocalToRemote { local_protocol: LocalProtocol::HttpProxy { path_headers: true, .. }, .. }
+    ; "with http proxy path headers")]
+    #[test_case("http://1080?connect_ports=443,https" => panics ""; "with invalid http proxy connect ports")]
+    #[test_case("tcp://8080:localhost:80?allow=10.0.0.0/33" => panics ""; "with invalid access list")]
+    #[test_case("socks5://1080?max_conns=0" => panics ""; "with invalid max_conns")]
+    #[test_case("socks5://1080?accept_rate=50/m" => panics ""; "with invalid accept_rate")]
+    #[test_case("socks5://1080?overflow=drop" => panics ""; "with invalid overflow")]
+    #[test_case("socks5://1080?resolve=fake_ip" => panics ""; "with socks5 fake ip without dns")]
+    #[test_case("socks5://1080?resolve=local&fake_dns=127.0.0.1:5353" => panics ""; "with socks5 fake dns without fake ip")]
+    #[test_case("socks5://1080?resolve=server" => panics ""; "with invalid socks5 resolve")]
+    #[test_case("tcp://443:domain.com:4443?keepalive_count=0" => panics ""; "with zero keepalive count")]
+    #[test_case("tcp://443:domain.com:4443?keepalive_idle_sec=5m" => panics ""; "with invalid keepalive idle")]
+    #[test_case("tcp://8080:backend1:80?lb=round_robin" => panics ""; "with load balancing of a single destination")]
+    #[test_case("tcp://8080:backend1:80,backend2:80?lb=least_conn" => panics ""; "with invalid load balancing")]
+    #[test_case("tcp://8080-8081:backend1:80-81,backend2:80" => panics ""; "with load balancing of a port range")]
+    #[test_case("udp://1053:1.1.1.1:53?label=ci-job-1234" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Udp { timeout: Some(Duration::from_secs(30)) },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1053)),
+            remote: (Host::Ipv4(Ipv4Addr::new(1, 1, 1, 1)), 53),
+            label: Some("ci-job-1234".to_string()),
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with label")]
+    #[test_case("tcp://443:domain.com:4443?label=ci%20job" => panics ""; "with invalid label")]
+    #[test_case("tcp://0:domain.com:4443" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0)),
+            remote: (Host::Domain("domain.com".to_string()), 4443),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with random local port")]
+    #[test_case("tcp://443:domain.com:4443?resume_buffer=65536&resume_timeout_sec=10" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: Some(TunnelResume {
+                    buffer_size: 65536,
+                    timeout: Duration::from_secs(10),
+                    session: None,
+                }),
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 443)),
+            remote: (Host::Domain("domain.com".to_string()), 4443),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with resume")]
+    #[test_case("tcp://443:domain.com:4443?resume_buffer=0" => panics ""; "with empty resume buffer")]
+    #[test_case("udp://[::1]:443:toto.com:4443?timeout_sec=30" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Udp { timeout: Some(std::time::Duration::from_secs(30)) },
+            local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 443, 0, 0)),
+            remote: (Host::Domain("toto.com".to_string()), 4443),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with fully defined tunnel")]
+    #[test_case("udp://[::1]:443:[::1]:4443?timeout_sec=30" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Udp { timeout: Some(std::time::Duration::from_secs(30)) },
+            local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 443, 0, 0)),
+            remote: (Host::Ipv6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 4443),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with full ipv6 tunnel")]
+    #[test_case("sctp://3868:10.0.0.2:3868" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Sctp,
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 3868)),
+            remote: (Host::Ipv4(Ipv4Addr::new(10, 0, 0, 2)), 3868),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with sctp")]
+    #[test_case("vsock://any:1212:localhost:22" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Vsock { cid: u32::MAX, port: 1212 },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(0), 0)),
+            remote: (Host::Domain("localhost".to_string()), 22),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with vsock any cid")]
+    #[test_case("vsock://3:1212:[::1]:22" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Vsock { cid: 3, port: 1212 },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(0), 0)),
+            remote: (Host::Ipv6(Ipv6Addr::LOCALHOST), 22),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with vsock cid")]
+    #[test_case("vsock://guest:1212:localhost:22" => panics ""; "with invalid vsock cid")]
+    #[test_case("unix:///tmp/app.sock:localhost:22?allowed_uids=0,1000" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Unix {
+                path: PathBuf::from("/tmp/app.sock"),
+                proxy_protocol: false,
+                allowed_uids: vec![0, 1000],
+                permissions: UnixSocketPermissions::default(),
+                lifecycle: UnixSocketLifecycle::default(),
+            },
+            local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+            remote: (Host::Domain("localhost".to_string()), 22),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with unix allowed uids")]
+    #[test_case("unix:///tmp/app.sock:localhost:22?allowed_uids=root" => panics ""; "with invalid unix allowed uids")]
+    #[test_case("unix:///tmp/app.sock:localhost:22?mode=0660&owner=app&group=1000" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Unix {
+                path: PathBuf::from("/tmp/app.sock"),
+                proxy_protocol: false,
+                allowed_uids: vec![],
+                permissions: UnixSocketPermissions {
+                    mode: Some(0o660),
+                    owner: Some("app".to_string()),
+                    group: Some("1000".to_string()),
+                },
+                lifecycle: UnixSocketLifecycle::default(),
+            },
+            local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+            remote: (Host::Domain("localhost".to_string()), 22),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with unix permissions")]
+    #[test_case("unix://@wstunnel:localhost:22" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Unix {
+                path: PathBuf::from("@wstunnel"),
+                proxy_protocol: false,
+                allowed_uids: vec![],
+                permissions: UnixSocketPermissions::default(),
+                lifecycle: UnixSocketLifecycle::default(),
+            },
+            local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+            remote: (Host::Domain("localhost".to_string()), 22),
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with abstract unix socket")]
+    #[test_case("udp://5060:pbx.lan:5060?dscp=46" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Udp { timeout: Some(Duration::from_secs(30)) },
+            local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 5060)),
+            remote: (Host::Domain("pbx.lan".to_string()), 5060),
+            label: None,
+            dscp: Some(46),
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with dscp")]
+    #[test_case("udp://5060:pbx.lan:5060?dscp=64" => panics ""; "with too large dscp")]
+    #[test_case("tcp://[::]:8080:localhost:80?v6only=false" =>
+        LocalToRemote {
+            local_protocol: LocalProtocol::Tcp { proxy_protocol: false, resume: None, idle_timeout: None, mirror: None, balancing: None, keepalive: None },
+            local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 8080, 0, 0)),
+            remote: (Host::Domain("localhost".to_string()), 80),
+            label: None,
+            dscp: None,
+            v6only: Some(false),
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    ; "with dual stack")]
+    #[test_case("tcp://[::]:8080:localhost:80?v6only=yes" => panics ""; "with invalid v6only")]
+    #[test_case("tcp://0.0.0.0:8080:localhost:80?v6only=true" => panics ""; "with v6only on ipv4")]
+    #[test_case("unix:///tmp/app.sock:localhost:22?mode=rw" => panics ""; "with invalid unix mode")]
+    #[test_case("unix:///tmp/app.sock:localhost:22?mode=17777" => panics ""; "with too large unix mode")]
+    fn test_parse_tunnel_arg(input: &str) -> LocalToRemote {
+        parse_tunnel_arg(input).unwrap()
+    }
+
+    #[test_case("udp://443:domain.com:4443?max_flows=0" => matches Err(_) ; "with zero max flows")]
+    #[test_case("udp://443:domain.com:4443?max_flows=abc" => matches Err(_) ; "with invalid max flows")]
+    #[test_case("udp://443:domain.com:4443?flow_eviction=random" => matches Err(_) ; "with invalid flow eviction")]
+    #[test_case("udp://443:domain.com:4443" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseUdp { max_flows: None, flow_eviction: UdpFlowEviction::DropNew, .. },
+            ..
+        })
+    ; "with default flow options")]
+    #[test_case("udp://443:domain.com:4443?timeout_sec=10&max_flows=100&flow_eviction=evict_idlest" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseUdp { max_flows: Some(100), flow_eviction: UdpFlowEviction::EvictIdlest, .. },
+            ..
+        })
+    ; "with flow options")]
+    #[test_case("tcp://[::]:8080:localhost:80?v6only=true" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseTcp { v6only: Some(true), .. },
+            ..
+        })
+    ; "with v6only")]
+    #[test_case("tcp://8080:localhost:5432?keepalive_interval_sec=30" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseTcp { keepalive: Some(TunnelKeepalive { count: 3, .. }), .. },
+            ..
+        })
+    ; "with keepalive")]
+    #[test_case("tcp://8080:backend1:80,backend2:80" => matches Err(_) ; "with load balancing")]
+    #[test_case("tcp://0.0.0.0:8080:localhost:80?allow=10.0.0.0/8" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseTcp { access: AccessList { ref allow, .. }, .. },
+            ..
+        }) if allow == &["10.0.0.0/8".parse::<ipnet::IpNet>().unwrap()]
+    ; "with access list")]
+    #[test_case("socks5://1080?resolve=local" => matches Err(_) ; "with socks5 local resolve")]
+    #[test_case("http://MyApp.example.com:localhost:3000?label=myapp" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseHttpIngress { ref vhost, session: None, auth: None },
+            remote: (Host::Domain(ref host), 3000),
+            label: Some(_),
+            ..
+        }) if vhost == "myapp.example.com" && host == "localhost"
+    ; "with http ingress")]
+    #[test_case("http://myapp.example.com:localhost:3000?basic_auth=alice:s3cret" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseHttpIngress { auth: Some(HttpIngressAuth::Basic(ref credentials)), .. },
+            ..
+        }) if credentials == "alice:s3cret"
+    ; "with http ingress basic auth")]
+    #[test_case("http://myapp.example.com:localhost:3000?auth_url=https://auth.example.com/oauth2/auth" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseHttpIngress { auth: Some(HttpIngressAuth::Forward(ref url)), .. },
+            ..
+        }) if url.as_str() == "https://auth.example.com/oauth2/auth"
+    ; "with http ingress forward auth")]
+    #[test_case("http://myapp.example.com:localhost:3000?basic_auth=alice" => matches Err(_) ; "with http ingress basic auth without password")]
+    #[test_case("http://*.Tunnels.example.com:localhost:3000" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseHttpIngress { ref vhost, .. },
+            ..
+        }) if vhost == "*.tunnels.example.com"
+    ; "with http ingress subdomain")]
+    #[test_case("http://127.0.0.1:8080" =>
+        matches Ok(LocalToRemote { local_protocol: LocalProtocol::ReverseHttpProxy { .. }, .. })
+    ; "with http proxy")]
+    #[test_case("http://8080" =>
+        matches Ok(LocalToRemote { local_protocol: LocalProtocol::ReverseHttpProxy { .. }, .. })
+    ; "with http proxy on port")]
+    #[test_case("http://8080?connect_ports=443" => matches Err(_) ; "with http proxy connect ports")]
+    #[test_case("http://8080?path_headers" => matches Err(_) ; "with http proxy path headers")]
+    #[test_case("doh://[::]:443?v6only=false" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseEncryptedDns { transport: EncryptedDns::Https, tls_certificate: None, tls_private_key: None },
+            local: SocketAddr::V6(_),
+            v6only: Some(false),
+            ..
+        })
+    ; "with dns over https")]
+    #[test_case("dot://853?tls_certificate=/etc/dns.crt&tls_private_key=/etc/dns.key" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseEncryptedDns { transport: EncryptedDns::Tls, tls_certificate: Some(_), tls_private_key: Some(_) },
+            ..
+        })
+    ; "with dns over tls")]
+    #[test_case("dot://853?tls_certificate=/etc/dns.crt" => matches Err(_) ; "with dns over tls without private key")]
+    #[test_case("unix:///run/app/w.sock:localhost:22?remove_stale&recreate" =>
+        matches Ok(LocalToRemote {
+            local_protocol: LocalProtocol::ReverseUnix { lifecycle: UnixSocketLifecycle { remove_stale: true, recreate: true }, .. },
+            ..
+        })
+    ; "with unix socket lifecycle")]
+    fn test_parse_reverse_tunnel_arg(input: &str) -> Result<LocalToRemote, io::Error> {
+        parse_reverse_tunnel_arg(input)
+    }
+
+    #[test_case("tcp://2000-2002:localhost:3000-3002" => matches Ok(LocalToRemote { port_count: 3, remote: (_, 3000), .. }) ; "with tcp range")]
+    #[test_case("udp://[::1]:2000-2001:[::1]:3000-3001?timeout_sec=5" => matches Ok(LocalToRemote { port_count: 2, remote: (Host::Ipv6(_), 3000), .. }) ; "with udp range")]
+    #[test_case("tcp://2000-2002:localhost:3000" => matches Err(_) ; "with single destination port")]
+    #[test_case("tcp://2000:localhost:3000-3002" => matches Err(_) ; "with single local port")]
+    #[test_case("tcp://2000-2002:localhost:3000-3001" => matches Err(_) ; "with ranges of different sizes")]
+    #[test_case("tcp://2002-2000:localhost:3002-3000" => matches Err(_) ; "with reversed range")]
+    #[test_case("sctp://2000-2002:localhost:3000-3002" => matches Err(_) ; "with sctp range")]
+    fn test_parse_port_range(input: &str) -> Result<LocalToRemote, io::Error> {
+        parse_tunnel_arg(input)
+    }
+
+    #[test]
+    fn test_access_list() {
+        let access = parse_tunnel_arg("tcp://0.0.0.0:8080:localhost:80?allow=10.0.0.0/8,::1&deny=0.0.0.0/0")
+            .unwrap()
+            .access;
+        assert!(access.accepts("10.1.2.3".parse().unwrap()));
+        assert!(access.accepts("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(access.accepts("::1".parse().unwrap()));
+        assert!(!access.accepts("192.168.1.1".parse().unwrap()));
+
+        let access = parse_tunnel_arg("tcp://0.0.0.0:8080:localhost:80?deny=192.168.0.0/16")
+            .unwrap()
+            .access;
+        assert!(access.accepts("10.1.2.3".parse().unwrap()));
+        assert!(!access.accepts("192.168.1.1".parse().unwrap()));
+        assert!(AccessList::default().accepts("192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_accept_limits() {
+        let tunnel = parse_tunnel_arg("socks5://1080?max_conns=100&accept_rate=50/s&overflow=reject").unwrap();
+        assert_eq!(
+            tunnel.accept_limits,
+            AcceptLimits {
+                max_conns: Some(100),
+                accept_rate: Some(50),
+                overflow: AcceptOverflow::Reject,
+            }
+        );
+        let tunnel = parse_tunnel_arg("tcp://8080:localhost:80?accept_rate=10").unwrap();
+        assert_eq!(tunnel.accept_limits.accept_rate, Some(10));
+        assert_eq!(tunnel.accept_limits.overflow, AcceptOverflow::Queue);
+        assert!(parse_reverse_tunnel_arg("tcp://8080:localhost:80?max_conns=10").is_err());
+    }
+
+    #[test]
+    fn test_split_port_range() {
+        let tunnel = parse_reverse_tunnel_arg("tcp://[::]:2000-2002:localhost:3000-3002?label=ftp").unwrap();
+        assert!(matches!(tunnel.local_protocol, LocalProtocol::ReverseTcp { .. }));
+        let tunnels: Vec<_> = tunnel.split_port_range().collect();
+        assert_eq!(tunnels.len(), 3);
+        assert_eq!(tunnels[2].local.port(), 2002);
+        assert_eq!(tunnels[2].remote.1, 3002);
+        assert_eq!(tunnels[2].label.as_deref(), Some("ftp"));
+        assert!(tunnels.iter().all(|tunnel| tunnel.port_count == 1));
+    }
+
+    #[test]
+    fn test_resolve_secret() {
+        let path = std::env::temp_dir().join(format!("wstunnel-secret-{}", std::process::id()));
+        std::fs::write(&path, "s3cr3t\n").unwrap();
+
+        assert_eq!(resolve_secret("s3cr3t").unwrap(), "s3cr3t");
+        assert_eq!(resolve_secret(&format!("file:{}", path.display())).unwrap(), "s3cr3t");
+        assert_eq!(resolve_secret("env:PATH").unwrap(), std::env::var("PATH").unwrap());
+        #[cfg(unix)]
+        assert_eq!(resolve_secret("exec:echo s3cr3t").unwrap(), "s3cr3t");
+        #[cfg(unix)]
+        assert!(resolve_secret("exec:false").is_err());
+        assert!(resolve_secret("env:WSTUNNEL_SECRET_NOT_SET").is_err());
+        assert!(resolve_secret("file:/nonexistent/secret").is_err());
+
+        let header = parse_http_credentials(&format!("file:{}", path.display())).unwrap();
+        assert_eq!(header, "Basic czNjcjN0");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_deserialize_config() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            #[serde(deserialize_with = "super::deserialize_tunnels")]
+            local_to_remote: Vec<LocalToRemote>,
+            #[serde(deserialize_with = "super::deserialize_reverse_tunnels")]
+            remote_to_local: Vec<LocalToRemote>,
+            #[serde(deserialize_with = "super::deserialize_duration")]
+            connection_warmup_timeout: Duration,
+            #[serde(default, deserialize_with = "super::deserialize_optional_duration")]
+            exit_if_disconnected_for: Option<Duration>,
+            #[serde(default, deserialize_with = "super::deserialize_optional_duration")]
+            connection_max_idle_age: Option<Duration>,
+            #[serde(deserialize_with = "super::deserialize_sni")]
+            tls_sni_override: tokio_rustls::rustls::pki_types::DnsName<'static>,
+        }
+
+        let config: Config = serde_yaml::from_str(
+            r#"
+local_to_remote:
+  - tcp://1212:google.com:443
+remote_to_local:
+  - tcp://2222:localhost:22
+connection_warmup_timeout: 5m
+exit_if_disconnected_for: 90
+tls_sni_override: cdn.example.com
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.local_to_remote,
+            vec![parse_tunnel_arg("tcp://1212:google.com:443").unwrap()]
+        );
+        assert!(matches!(
+            config.remote_to_local[0].local_protocol,
+            LocalProtocol::ReverseTcp { .. }
+        ));
+        assert_eq!(config.connection_warmup_timeout, Duration::from_secs(300));
+        assert_eq!(config.exit_if_disconnected_for, Some(Duration::from_secs(90)));
+        assert_eq!(config.connection_max_idle_age, None);
+        assert_eq!(config.tls_sni_override.as_ref(), "cdn.example.com");
+
+        let invalid = serde_yaml::from_str::<Config>(
+            "local_to_remote: [\"tcp://google.com\"]\nremote_to_local: []\nconnection_warmup_timeout: 1s\ntls_sni_override: a.b",
+        );
+        assert!(invalid.is_err());
+    }
+}
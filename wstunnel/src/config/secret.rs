@@ -0,0 +1,94 @@
+use hyper::header::{AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use hyper::http::{HeaderName, HeaderValue};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const REDACTED: &str = "****";
+
+static SHOW_SECRETS: AtomicBool = AtomicBool::new(false);
+
+/// Print the secrets in the Debug and Serialize output instead of redacting them, for `--dump-config --show-secrets`
+pub fn show_secrets(show: bool) {
+    SHOW_SECRETS.store(show, Ordering::Relaxed);
+}
+
+pub(crate) fn secrets_shown() -> bool {
+    SHOW_SECRETS.load(Ordering::Relaxed)
+}
+
+/// A password, key or credentials, that is redacted from the Debug and Serialize output so it does not leak in the
+/// logs. Use [`Secret::expose`] to read it
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub const fn new(secret: T) -> Self {
+        Self(secret)
+    }
+
+    pub const fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(secret: T) -> Self {
+        Self(secret)
+    }
+}
+
+impl<T: Debug> Debug for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if secrets_shown() {
+            self.0.fmt(f)
+        } else {
+            f.write_str(REDACTED)
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if secrets_shown() {
+            self.0.serialize(serializer)
+        } else {
+            serializer.serialize_str(REDACTED)
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self)
+    }
+}
+
+/// Hide the value of the headers carrying credentials when the requests are logged
+pub fn mark_sensitive_header(name: &HeaderName, value: &mut HeaderValue) {
+    if name == AUTHORIZATION || name == PROXY_AUTHORIZATION || name == COOKIE {
+        value.set_sensitive(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let secret = Secret::new("hunter2".to_string());
+        assert_eq!(format!("{secret:?}"), "****");
+        assert_eq!(format!("{:?}", Some(&secret)), "Some(****)");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), "\"****\"");
+        assert_eq!(secret.expose(), "hunter2");
+
+        let mut header = HeaderValue::from_static("Basic aHVudGVyMg==");
+        mark_sensitive_header(&AUTHORIZATION, &mut header);
+        assert_eq!(format!("{header:?}"), "Sensitive");
+    }
+}
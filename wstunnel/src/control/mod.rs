@@ -0,0 +1,473 @@
+//! JSON-RPC 2.0 api of the client on a local socket (`--control-socket`), so other programs (i.e: a tray app) can start
+//! and stop its tunnels, and watch its status and logs. Requests and responses are json messages, one per line.
+//! The OpenRPC schema of the api, `openrpc.json`, is answered to the `rpc.discover` method.
+use crate::config::LocalToRemote;
+use crate::config::parsers::{parse_reverse_tunnel_arg, parse_tunnel_arg};
+use crate::executor::{AbortHandle, TokioExecutorRef};
+use crate::stats::{STATS, Side, Snapshot};
+use crate::tunnel::LocalProtocol;
+use crate::tunnel::client::WsClient;
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, warn};
+
+pub const OPENRPC_SCHEMA: &str = include_str!("openrpc.json");
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const TUNNEL_ERROR: i64 = -32000;
+
+/// Log lines of the process, for the control connections that subscribed to them
+static LOGS: LazyLock<broadcast::Sender<String>> = LazyLock::new(|| broadcast::channel(1024).0);
+
+/// Writer of the logs, to give to the tracing subscriber so the logs can be streamed to the control connections.
+/// i.e: `tracing_subscriber::fmt().with_writer(std::io::stdout.and(wstunnel::control::log_stream))`
+pub fn log_stream() -> LogStream {
+    LogStream
+}
+
+pub struct LogStream;
+
+impl io::Write for LogStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The subscriber writes each log line at once
+        if LOGS.receiver_count() > 0 {
+            let _ = LOGS.send(strip_colors(&String::from_utf8_lossy(buf)).trim_end().to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Remove the ansi color codes of a log line, as the programs reading the logs do not display them in a terminal
+fn strip_colors(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // i.e: \x1b[2m or \x1b[0m
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+            continue;
+        }
+        stripped.push(c);
+    }
+    stripped
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    LocalToRemote,
+    RemoteToLocal,
+}
+
+/// A tunnel of the client, as reported by the `status` method
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    pub id: u64,
+    pub direction: Direction,
+    pub protocol: String,
+    pub local: SocketAddr,
+    pub remote: String,
+    pub label: Option<String>,
+}
+
+struct Tunnel {
+    info: TunnelInfo,
+    tasks: Vec<AbortHandle>,
+}
+
+/// Tunnels of the client, that can be started and stopped while it runs
+pub struct Controller<E: TokioExecutorRef> {
+    client: WsClient<E>,
+    tunnels: Mutex<BTreeMap<u64, Tunnel>>,
+    next_id: AtomicU64,
+}
+
+impl<E: TokioExecutorRef> Controller<E> {
+    pub fn new(client: WsClient<E>) -> Arc<Self> {
+        Arc::new(Self {
+            client,
+            tunnels: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    /// Start a tunnel, as if given with -L or -R (`direction`) on the command line
+    pub async fn start_tunnel(self: &Arc<Self>, tunnel: LocalToRemote, direction: Direction) -> anyhow::Result<u64> {
+        // They are tied to the stdio of the process, and exit it when closed
+        if matches!(
+            tunnel.local_protocol,
+            LocalProtocol::Stdio { .. } | LocalProtocol::StdioUdp { .. }
+        ) {
+            return Err(anyhow!("stdio tunnels cannot be started along the control socket"));
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = TunnelInfo {
+            id,
+            direction,
+            protocol: crate::stats::protocol_name(&tunnel.local_protocol).to_string(),
+            local: tunnel.local,
+            remote: format!("{}:{}", tunnel.remote.0, tunnel.remote.1),
+            label: tunnel.label.clone(),
+        };
+        let futures = match direction {
+            Direction::LocalToRemote => crate::client_tunnels(self.client.clone(), vec![], vec![tunnel], None).await?,
+            Direction::RemoteToLocal => crate::client_tunnels(self.client.clone(), vec![tunnel], vec![], None).await?,
+        };
+
+        // Hold the lock while spawning, so a tunnel that stops right away is removed after being added
+        let mut tunnels = self.tunnels.lock();
+        let tasks = futures
+            .into_iter()
+            .map(|tunnel| {
+                let controller = self.clone();
+                self.client.executor.spawn(async move {
+                    tunnel.await;
+                    controller.tunnels.lock().remove(&id);
+                })
+            })
+            .collect();
+        info!("Started tunnel {id}: {info:?}");
+        tunnels.insert(id, Tunnel { info, tasks });
+
+        Ok(id)
+    }
+
+    /// Stop listening for the tunnel. Its connections already established are left to finish
+    pub fn stop_tunnel(&self, id: u64) -> anyhow::Result<()> {
+        let tunnel = self
+            .tunnels
+            .lock()
+            .remove(&id)
+            .ok_or_else(|| anyhow!("no tunnel with id {id}"))?;
+        for task in tunnel.tasks {
+            task.abort();
+        }
+        info!("Stopped tunnel {id}");
+        Ok(())
+    }
+
+    pub fn tunnels(&self) -> Vec<TunnelInfo> {
+        self.tunnels.lock().values().map(|tunnel| tunnel.info.clone()).collect()
+    }
+
+    fn status(&self) -> Value {
+        let mut stats: Snapshot = STATS.snapshot();
+        stats.tunnels.retain(|tunnel| tunnel.side == Side::Client);
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "server": format!("{:?}", self.client.config.remote_addr),
+            "tunnels": self.tunnels(),
+            "stats": stats,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: Option<String>,
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StartTunnelParams {
+    local_to_remote: Option<String>,
+    remote_to_local: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StopTunnelParams {
+    id: u64,
+}
+
+/// Error answered to a request, as a JSON-RPC error object
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    // Methods without parameters can be called with either no params, or empty ones
+    let params = match params {
+        Value::Null => json!({}),
+        params => params,
+    };
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err))
+}
+
+/// Answer the requests of a control connection, until it is closed
+async fn serve_connection<E: TokioExecutorRef>(
+    controller: Arc<Controller<E>>,
+    stream: impl AsyncRead + AsyncWrite + Send + 'static,
+) {
+    let (rx, mut tx) = tokio::io::split(stream);
+    let (messages_tx, mut messages_rx) = mpsc::channel::<Value>(64);
+    controller.client.executor.spawn(async move {
+        while let Some(message) = messages_rx.recv().await {
+            let mut line = message.to_string();
+            line.push('\n');
+            if tx.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut logs: Option<AbortHandle> = None;
+    let mut lines = BufReader::new(rx).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                let code = if serde_json::from_str::<Value>(&line).is_ok() {
+                    INVALID_REQUEST
+                } else {
+                    PARSE_ERROR
+                };
+                let _ = messages_tx
+                    .send(error_response(Value::Null, RpcError::new(code, err)))
+                    .await;
+                continue;
+            }
+        };
+        if request.jsonrpc.as_deref() != Some("2.0") {
+            let err = RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"");
+            let _ = messages_tx
+                .send(error_response(request.id.unwrap_or(Value::Null), err))
+                .await;
+            continue;
+        }
+
+        let result = match request.method.as_str() {
+            "rpc.discover" => {
+                serde_json::from_str::<Value>(OPENRPC_SCHEMA).map_err(|err| RpcError::new(TUNNEL_ERROR, err))
+            }
+            "status" => params::<BTreeMap<String, Value>>(request.params).map(|_| controller.status()),
+            "start_tunnel" => match params::<StartTunnelParams>(request.params) {
+                Ok(params) => start_tunnel(&controller, params).await,
+                Err(err) => Err(err),
+            },
+            "stop_tunnel" => params::<StopTunnelParams>(request.params).and_then(|params| {
+                controller
+                    .stop_tunnel(params.id)
+                    .map(|_| json!(true))
+                    .map_err(|err| RpcError::new(TUNNEL_ERROR, err))
+            }),
+            "subscribe_logs" => params::<BTreeMap<String, Value>>(request.params).map(|_| {
+                if logs.is_none() {
+                    let mut log_lines = LOGS.subscribe();
+                    let messages_tx = messages_tx.clone();
+                    logs = Some(controller.client.executor.spawn(async move {
+                        loop {
+                            let line = match log_lines.recv().await {
+                                Ok(line) => line,
+                                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                                    format!("{skipped} log lines skipped, the control connection is too slow")
+                                }
+                                Err(broadcast::error::RecvError::Closed) => break,
+                            };
+                            let notification = json!({"jsonrpc": "2.0", "method": "log", "params": {"line": line}});
+                            if messages_tx.send(notification).await.is_err() {
+                                break;
+                            }
+                        }
+                    }));
+                }
+                json!(true)
+            }),
+            method => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {method}"))),
+        };
+
+        // Requests without id are notifications, they get no response
+        let Some(id) = request.id else {
+            continue;
+        };
+        let response = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(err) => error_response(id, err),
+        };
+        if messages_tx.send(response).await.is_err() {
+            break;
+        }
+    }
+
+    // The writer stops once it sent the last responses, and all the senders are dropped
+    if let Some(logs) = logs {
+        logs.abort();
+    }
+}
+
+async fn start_tunnel<E: TokioExecutorRef>(
+    controller: &Arc<Controller<E>>,
+    params: StartTunnelParams,
+) -> Result<Value, RpcError> {
+    let (tunnel, direction) = match (params.local_to_remote, params.remote_to_local) {
+        (Some(arg), None) => (parse_tunnel_arg(&arg), Direction::LocalToRemote),
+        (None, Some(arg)) => (parse_reverse_tunnel_arg(&arg), Direction::RemoteToLocal),
+        _ => {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                "exactly one of local_to_remote or remote_to_local is required",
+            ));
+        }
+    };
+    let tunnel = tunnel.map_err(|err| RpcError::new(INVALID_PARAMS, err))?;
+    let id = controller
+        .start_tunnel(tunnel, direction)
+        .await
+        .map_err(|err| RpcError::new(TUNNEL_ERROR, format!("{err:#}")))?;
+    Ok(json!({ "id": id }))
+}
+
+fn error_response(id: Value, err: RpcError) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": err.code, "message": err.message}})
+}
+
+/// Accept the control connections on the socket, until an error stops it
+#[cfg(unix)]
+pub async fn serve<E: TokioExecutorRef>(path: &Path, controller: Arc<Controller<E>>) -> anyhow::Result<()> {
+    use crate::tunnel::{UnixSocketLifecycle, UnixSocketPermissions};
+    use tokio_stream::StreamExt;
+
+    // Whoever can connect to the socket controls the client
+    let permissions = UnixSocketPermissions {
+        mode: Some(0o600),
+        ..Default::default()
+    };
+    // The socket left behind by a client that was killed, nothing listens on it anymore
+    let lifecycle = UnixSocketLifecycle {
+        remove_stale: true,
+        recreate: false,
+    };
+    let mut listener = crate::protocols::unix_sock::run_server(path, &permissions, lifecycle).await?;
+    info!("Serving control api on unix socket {}", path.display());
+
+    while let Some(stream) = listener.next().await {
+        match stream {
+            Ok(stream) => {
+                controller
+                    .client
+                    .executor
+                    .spawn(serve_connection(controller.clone(), stream));
+            }
+            Err(err) => warn!("Error while accepting control connection {err:?}"),
+        }
+    }
+    Ok(())
+}
+
+/// Accept the control connections on the named pipe, until an error stops it
+#[cfg(windows)]
+pub async fn serve<E: TokioExecutorRef>(path: &Path, controller: Arc<Controller<E>>) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut pipe = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(path)
+        .with_context(|| format!("Cannot create control named pipe {}", path.display()))?;
+    info!("Serving control api on named pipe {}", path.display());
+
+    loop {
+        pipe.connect().await?;
+        // A pipe instance serves a single client, the next one waits on a new instance
+        let stream = std::mem::replace(&mut pipe, ServerOptions::new().create(path)?);
+        controller
+            .client
+            .executor
+            .spawn(serve_connection(controller.clone(), stream));
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub async fn serve<E: TokioExecutorRef>(_path: &Path, _controller: Arc<Controller<E>>) -> anyhow::Result<()> {
+    Err(anyhow!("The control socket is not available on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_lists_methods() {
+        let schema: Value = serde_json::from_str(OPENRPC_SCHEMA).unwrap();
+        let methods: Vec<&str> = schema["methods"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|method| method["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            methods,
+            vec![
+                "status",
+                "start_tunnel",
+                "stop_tunnel",
+                "subscribe_logs",
+                "rpc.discover"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_params() {
+        assert!(params::<BTreeMap<String, Value>>(Value::Null).is_ok());
+        let start: StartTunnelParams = params(json!({"local_to_remote": "tcp://1212:google.com:443"})).unwrap();
+        assert_eq!(start.local_to_remote.as_deref(), Some("tcp://1212:google.com:443"));
+        assert!(start.remote_to_local.is_none());
+        assert_eq!(params::<StopTunnelParams>(json!({"id": 3})).unwrap().id, 3);
+
+        let err = params::<StopTunnelParams>(json!({"id": 3, "force": true}))
+            .err()
+            .unwrap();
+        assert_eq!(err.code, INVALID_PARAMS);
+        assert!(params::<StopTunnelParams>(json!({})).is_err());
+    }
+
+    #[test]
+    fn test_strip_colors() {
+        assert_eq!(
+            strip_colors("\x1b[2m2024-01-01\x1b[0m \x1b[32m INFO\x1b[0m Started tunnel 1"),
+            "2024-01-01  INFO Started tunnel 1"
+        );
+        assert_eq!(strip_colors("no colors"), "no colors");
+    }
+}
@@ -0,0 +1,61 @@
+//! dscp - mark the packets sent on a socket with a DSCP codepoint, for the QoS of the network
+//!
+//! the traffic class of ipv6 sockets can only be set on linux, android, macos and the BSDs
+
+use socket2::SockRef;
+use std::io;
+
+/// DSCP is 6 bits long
+pub const MAX_DSCP: u8 = 63;
+
+/// i.e: 46 (Expedited Forwarding) for the latency sensitive traffic like VoIP
+pub fn set_dscp(socket: SockRef, dscp: u8) -> io::Result<()> {
+    // The DSCP is in the 6 upper bits of the TOS/traffic class byte, the 2 lower ones are for ECN
+    let tos = u32::from(dscp) << 2;
+    if socket.local_addr()?.is_ipv6() {
+        set_tclass_v6(socket, tos)
+    } else {
+        socket.set_tos_v4(tos)
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_tclass_v6(socket: SockRef, tclass: u32) -> io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_tclass_v6(_socket: SockRef, _tclass: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DSCP marking of ipv6 sockets is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_dscp() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_dscp(SockRef::from(&socket), 46).unwrap();
+        assert_eq!(SockRef::from(&socket).tos_v4().unwrap(), 46 << 2);
+    }
+}
@@ -1,7 +1,198 @@
+//! Where the tasks of the tunnels are spawned. The sockets are tokio ones, so a tokio runtime must be entered when the
+//! tasks are polled, but the tasks themselves can be scheduled by any runtime.
+//!
+//! i.e: a current thread tokio runtime
+//! ```no_run
+//! # fn run(client: wstunnel::config::Client) -> anyhow::Result<()> {
+//! let runtime = wstunnel::executor::RuntimeConfig {
+//!     current_thread: true,
+//!     ..Default::default()
+//! }
+//! .build()?;
+//! let executor = wstunnel::executor::DefaultTokioExecutor::new(runtime.handle().clone());
+//! runtime.block_on(wstunnel::run_client(client, executor))
+//! # }
+//! ```
+//!
+//! or smol, with async-compat to provide the tokio runtime
+//! ```ignore
+//! let executor = SpawnFnExecutor::new(|task| smol::spawn(async_compat::Compat::new(task)).detach());
+//! smol::block_on(async_compat::Compat::new(wstunnel::run_client(client, executor)))
+//! ```
+use futures_util::future::{Abortable, BoxFuture};
 use parking_lot::Mutex;
+use std::io;
+use std::str::FromStr;
 use std::sync::{Arc, Weak};
-use tokio::runtime::Handle;
-use tokio::task::{AbortHandle, JoinSet};
+use tokio::runtime::{Handle, Runtime};
+use tokio::task::JoinSet;
+
+/// Stop a task spawned by an executor. The task is dropped the next time it is polled
+pub use futures_util::future::AbortHandle;
+
+/// Tokio runtime to run the client or the server on
+#[derive(Clone, Debug, Default)]
+pub struct RuntimeConfig {
+    /// Run everything on the thread calling `block_on`, instead of a pool of worker threads
+    pub current_thread: bool,
+    /// Number of worker threads. By default, one per cpu
+    pub worker_threads: Option<usize>,
+    /// Name of the worker threads. By default, tokio-runtime-worker
+    pub thread_name: Option<String>,
+    /// Stack size of the worker threads, in bytes. By default, 2MiB
+    pub thread_stack_size: Option<usize>,
+    /// (linux only) Pin each worker thread to a cpu, round-robin over them. By default, one worker per pinned cpu
+    pub cpu_affinity: Option<CpuAffinity>,
+}
+
+/// Cpus to pin the worker threads of the runtime to
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CpuAffinity {
+    /// The cpus the process is allowed to run on
+    Auto,
+    /// i.e: 0-7,16-23
+    Cpus(Vec<usize>),
+}
+
+impl FromStr for CpuAffinity {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+
+        let mut cpus = Vec::new();
+        for range in s.split(',') {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (start.trim().parse().map_err(|_| ())?, end.trim().parse().map_err(|_| ())?),
+                None => {
+                    let cpu = range.trim().parse().map_err(|_| ())?;
+                    (cpu, cpu)
+                }
+            };
+            if start > end {
+                return Err(());
+            }
+            for cpu in start..=end {
+                if !cpus.contains(&cpu) {
+                    cpus.push(cpu);
+                }
+            }
+        }
+        Ok(Self::Cpus(cpus))
+    }
+}
+
+impl CpuAffinity {
+    #[cfg(target_os = "linux")]
+    fn cpus(&self) -> io::Result<Vec<usize>> {
+        use nix::sched::{CpuSet, sched_getaffinity};
+        use nix::unistd::Pid;
+
+        let allowed = sched_getaffinity(Pid::from_raw(0))?;
+        let is_allowed = |cpu: usize| allowed.is_set(cpu).unwrap_or(false);
+        let cpus = match self {
+            Self::Auto => (0..CpuSet::count()).filter(|cpu| is_allowed(*cpu)).collect(),
+            Self::Cpus(cpus) => {
+                if let Some(cpu) = cpus.iter().find(|cpu| !is_allowed(**cpu)) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("cannot pin the worker threads to cpu {cpu}, the process is not allowed to run on it"),
+                    ));
+                }
+                cpus.clone()
+            }
+        };
+        Ok(cpus)
+    }
+}
+
+impl RuntimeConfig {
+    pub fn build(&self) -> io::Result<Runtime> {
+        let mut builder = if self.current_thread {
+            tokio::runtime::Builder::new_current_thread()
+        } else {
+            tokio::runtime::Builder::new_multi_thread()
+        };
+        builder.enable_all();
+        if let Some(worker_threads) = self.worker_threads
+            && !self.current_thread
+        {
+            if worker_threads == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the number of worker threads must be at least 1",
+                ));
+            }
+            builder.worker_threads(worker_threads);
+        }
+        if let Some(thread_name) = &self.thread_name {
+            builder.thread_name(thread_name);
+        }
+        if let Some(thread_stack_size) = self.thread_stack_size {
+            builder.thread_stack_size(thread_stack_size);
+        }
+        if let Some(cpu_affinity) = &self.cpu_affinity {
+            self.pin_worker_threads(&mut builder, cpu_affinity)?;
+        }
+        builder.build()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn pin_worker_threads(&self, builder: &mut tokio::runtime::Builder, cpu_affinity: &CpuAffinity) -> io::Result<()> {
+        use nix::sched::{CpuSet, sched_setaffinity};
+        use nix::unistd::Pid;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        if self.current_thread {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot pin the worker threads of a current thread runtime",
+            ));
+        }
+        let cpus = cpu_affinity.cpus()?;
+        if cpus.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no cpu to pin the worker threads to",
+            ));
+        }
+        let worker_threads = self.worker_threads.unwrap_or(cpus.len());
+        builder.worker_threads(worker_threads);
+
+        // The worker threads are the first ones started by the runtime, the threads of the blocking pool are left
+        // free to run anywhere
+        let started = AtomicUsize::new(0);
+        builder.on_thread_start(move || {
+            let ix = started.fetch_add(1, Ordering::Relaxed);
+            if ix >= worker_threads {
+                return;
+            }
+            let cpu = cpus[ix % cpus.len()];
+            let mut cpu_set = CpuSet::new();
+            if let Err(err) = cpu_set
+                .set(cpu)
+                .and_then(|_| sched_setaffinity(Pid::from_raw(0), &cpu_set))
+            {
+                tracing::warn!("Cannot pin worker thread {ix} to cpu {cpu}: {err}");
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn pin_worker_threads(
+        &self,
+        _builder: &mut tokio::runtime::Builder,
+        _cpu_affinity: &CpuAffinity,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pinning the worker threads to cpus is only supported on linux",
+        ))
+    }
+}
 
 pub trait TokioExecutorRef: Clone + Send + Sync + 'static {
     fn spawn<F>(&self, f: F) -> AbortHandle
@@ -40,7 +231,9 @@ impl TokioExecutorRef for DefaultTokioExecutor {
         F: Future + Send + 'static,
         F::Output: Send + 'static,
     {
-        self.handle.spawn(f).abort_handle()
+        let (abort_handle, registration) = AbortHandle::new_pair();
+        self.handle.spawn(Abortable::new(f, registration));
+        abort_handle
     }
 }
 
@@ -89,9 +282,11 @@ impl TokioExecutorRef for JoinSetTokioExecutor {
         F: Future + Send + 'static,
         F::Output: Send + 'static,
     {
+        let (abort_handle, registration) = AbortHandle::new_pair();
         self.join_set.lock().spawn(async {
-            f.await;
-        })
+            let _ = Abortable::new(f, registration).await;
+        });
+        abort_handle
     }
 }
 
@@ -106,32 +301,190 @@ impl TokioExecutor for JoinSetTokioExecutor {
 #[derive(Clone)]
 pub struct JoinSetTokioExecutorRef {
     join_set: Weak<Mutex<JoinSet<()>>>,
-    default_abort_handle: AbortHandle,
 }
 impl JoinSetTokioExecutorRef {
     fn new(exec: &JoinSetTokioExecutor) -> Self {
-        let default_abort_handle = exec.join_set.lock().spawn(futures_util::future::pending());
         let join_set = Arc::downgrade(&exec.join_set);
+        Self { join_set }
+    }
+}
+
+impl TokioExecutorRef for JoinSetTokioExecutorRef {
+    fn spawn<F>(&self, f: F) -> AbortHandle
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (abort_handle, registration) = AbortHandle::new_pair();
+        // The executor is gone, the task is never run
+        if let Some(join_set) = self.join_set.upgrade() {
+            join_set.lock().spawn(async {
+                let _ = Abortable::new(f, registration).await;
+            });
+        }
+        abort_handle
+    }
+}
+
+// ///////////////////////////////
+// SpawnFnExecutor
+// ///////////////////////////////
+
+/// Executor handing the tasks to a function, to schedule them on another runtime than tokio
+#[derive(Clone)]
+pub struct SpawnFnExecutor {
+    spawn_fn: Arc<dyn Fn(BoxFuture<'static, ()>) + Send + Sync>,
+}
+
+impl SpawnFnExecutor {
+    pub fn new(spawn_fn: impl Fn(BoxFuture<'static, ()>) + Send + Sync + 'static) -> Self {
         Self {
-            join_set,
-            default_abort_handle,
+            spawn_fn: Arc::new(spawn_fn),
         }
     }
 }
 
-impl TokioExecutorRef for JoinSetTokioExecutorRef {
+impl TokioExecutorRef for SpawnFnExecutor {
     fn spawn<F>(&self, f: F) -> AbortHandle
     where
         F: Future + Send + 'static,
         F::Output: Send + 'static,
     {
-        self.join_set
-            .upgrade()
-            .map(|l| {
-                l.lock().spawn(async {
-                    f.await;
-                })
-            })
-            .unwrap_or_else(|| self.default_abort_handle.clone())
+        let (abort_handle, registration) = AbortHandle::new_pair();
+        (self.spawn_fn)(Box::pin(async {
+            let _ = Abortable::new(f, registration).await;
+        }));
+        abort_handle
+    }
+}
+
+impl TokioExecutor for SpawnFnExecutor {
+    type Ref = SpawnFnExecutor;
+
+    fn ref_clone(&self) -> SpawnFnExecutor {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    async fn assert_abort_drops_task(executor: impl TokioExecutorRef) {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(dropped.clone());
+        let task = executor.spawn(async move {
+            let _flag = flag;
+            futures_util::future::pending::<()>().await;
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!dropped.load(Ordering::Relaxed));
+
+        task.abort();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_abort() {
+        assert_abort_drops_task(DefaultTokioExecutor::default()).await;
+
+        let executor = JoinSetTokioExecutor::default();
+        assert_abort_drops_task(executor.clone()).await;
+        assert_abort_drops_task(executor.ref_clone()).await;
+
+        let handle = Handle::current();
+        assert_abort_drops_task(SpawnFnExecutor::new(move |task| {
+            handle.spawn(task);
+        }))
+        .await;
+    }
+
+    #[test]
+    fn test_runtime_config() {
+        let runtime = RuntimeConfig {
+            worker_threads: Some(2),
+            thread_name: Some("wstunnel-worker".to_string()),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 2);
+        let thread_name = runtime
+            .block_on(runtime.spawn(async { std::thread::current().name().map(str::to_string) }))
+            .unwrap();
+        assert_eq!(thread_name.as_deref(), Some("wstunnel-worker"));
+
+        let runtime = RuntimeConfig {
+            current_thread: true,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 1);
+
+        let no_worker = RuntimeConfig {
+            worker_threads: Some(0),
+            ..Default::default()
+        };
+        assert!(no_worker.build().is_err());
+    }
+
+    #[test]
+    fn test_cpu_affinity() {
+        assert_eq!(CpuAffinity::from_str("auto"), Ok(CpuAffinity::Auto));
+        assert_eq!(
+            CpuAffinity::from_str("0-2,8, 5-5,1"),
+            Ok(CpuAffinity::Cpus(vec![0, 1, 2, 8, 5]))
+        );
+        assert_eq!(CpuAffinity::from_str("3-1"), Err(()));
+        assert_eq!(CpuAffinity::from_str("0,"), Err(()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pin_worker_threads() {
+        use nix::sched::sched_getaffinity;
+        use nix::unistd::Pid;
+
+        let cpus = CpuAffinity::Auto.cpus().unwrap();
+        let runtime = RuntimeConfig {
+            worker_threads: Some(2),
+            cpu_affinity: Some(CpuAffinity::Cpus(vec![cpus[0]])),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 2);
+        let pinned = runtime
+            .block_on(runtime.spawn(async { sched_getaffinity(Pid::from_raw(0)).unwrap() }))
+            .unwrap();
+        assert!(pinned.is_set(cpus[0]).unwrap());
+        assert!(cpus[1..].iter().all(|cpu| !pinned.is_set(*cpu).unwrap()));
+
+        let runtime = RuntimeConfig {
+            cpu_affinity: Some(CpuAffinity::Auto),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        assert_eq!(runtime.metrics().num_workers(), cpus.len());
+
+        let current_thread = RuntimeConfig {
+            current_thread: true,
+            cpu_affinity: Some(CpuAffinity::Auto),
+            ..Default::default()
+        };
+        assert!(current_thread.build().is_err());
     }
 }
@@ -0,0 +1,63 @@
+//! failure - kind of the errors that stop the client or the server, attached as context to the errors returned by
+//! [`crate::run_client`] and [`crate::run_server`], so the cli exits with a code a supervisor can act upon.
+//! i.e: not restarting a client whose credentials are refused, or alerting about a port already in use
+use derive_more::{Display, Error};
+
+#[derive(Clone, Copy, Debug, Display, Error, Eq, PartialEq)]
+pub enum FailureKind {
+    /// The options or the files they point to are invalid
+    #[display("Invalid configuration")]
+    Config,
+    /// A listener cannot be bound, i.e: the port is already in use or needs more privileges
+    #[display("Cannot bind a listener")]
+    Bind,
+    /// The certificates or keys cannot be loaded, or the TLS connector or acceptor cannot be created from them
+    #[display("Cannot set up TLS")]
+    Tls,
+    /// The credentials are refused, by the OpenID Connect provider or by the server
+    #[display("Authentication failed")]
+    Auth,
+}
+
+impl FailureKind {
+    /// Kind of the failure of an error, if it was attached to it
+    pub fn of(err: &anyhow::Error) -> Option<Self> {
+        err.downcast_ref::<Self>().copied()
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Config => "config",
+            Self::Bind => "bind",
+            Self::Tls => "tls",
+            Self::Auth => "auth",
+        }
+    }
+
+    /// Exit code of the process, 1 being left for the other errors
+    pub const fn exit_code(self) -> i32 {
+        match self {
+            Self::Config => 2,
+            Self::Bind => 3,
+            Self::Tls => 4,
+            Self::Auth => 5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, anyhow};
+
+    #[test]
+    fn test_failure_kind_of() {
+        let err = Err::<(), _>(anyhow!("Address already in use"))
+            .context("Failed to bind to socket on 0.0.0.0:8080")
+            .context(FailureKind::Bind)
+            .context("Cannot start the tunnel")
+            .unwrap_err();
+        assert_eq!(FailureKind::of(&err), Some(FailureKind::Bind));
+        assert_eq!(FailureKind::of(&anyhow!("Address already in use")), None);
+    }
+}
@@ -0,0 +1,243 @@
+//! Liveness and readiness of the server, answered on `/healthz` and `/readyz` of its bind and of `--metrics-listen`,
+//! and of the client, answered on its `--health-listen` and told by its `--liveness-file`
+use crate::metrics;
+use anyhow::Context;
+use hyper::StatusCode;
+use parking_lot::Mutex;
+use std::fmt::Write;
+use std::fs::File;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+pub struct Health {
+    /// The server accepts connections on its bind address
+    listening: AtomicBool,
+    /// Error of the last reload of the restrictions config file, if it failed
+    restrictions_reload_error: Mutex<Option<String>>,
+}
+
+pub static HEALTH: Health = Health::new();
+
+impl Health {
+    const fn new() -> Self {
+        Self {
+            listening: AtomicBool::new(false),
+            restrictions_reload_error: Mutex::new(None),
+        }
+    }
+
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::Relaxed);
+    }
+
+    pub fn set_restrictions_reload(&self, result: Result<(), String>) {
+        *self.restrictions_reload_error.lock() = result.err();
+    }
+
+    /// Answer to a probe, or None if the path is not one of them
+    pub fn probe(&self, path: &str) -> Option<(StatusCode, String)> {
+        match path {
+            "/healthz" => Some((StatusCode::OK, "ok\n".to_string())),
+            "/readyz" => Some(self.readiness()),
+            _ => None,
+        }
+    }
+
+    fn readiness(&self) -> (StatusCode, String) {
+        let mut out = String::new();
+        let listening = self.listening.load(Ordering::Relaxed);
+        let _ = writeln!(out, "listener: {}", if listening { "ok" } else { "not listening" });
+
+        // The previous rules stay enforced when a reload fails, so it is reported without failing the readiness
+        match &*self.restrictions_reload_error.lock() {
+            None => out.push_str("restrictions: ok\n"),
+            Some(err) => {
+                let _ = writeln!(out, "restrictions: last reload failed, previous rules kept: {err}");
+            }
+        }
+
+        let status = if listening {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, out)
+    }
+}
+
+/// Whether the client manages to connect to its server
+pub struct ServerReachability {
+    /// Since when every connection attempt failed, and the error of the last one
+    unreachable: Mutex<Option<(Instant, String)>>,
+    /// File touched each time the server is reached, and removed while it is not, for the health checks of the
+    /// containers without an http client
+    liveness_file: OnceLock<PathBuf>,
+}
+
+pub static SERVER_REACHABILITY: ServerReachability = ServerReachability::new();
+
+impl ServerReachability {
+    const fn new() -> Self {
+        Self {
+            unreachable: Mutex::new(None),
+            liveness_file: OnceLock::new(),
+        }
+    }
+
+    /// The file left by a previous run is removed, it is only created once the server is reached
+    pub fn set_liveness_file(&self, path: PathBuf) {
+        remove_liveness_file(&path);
+        let _ = self.liveness_file.set(path);
+    }
+
+    /// A connection to the server succeeded, or the server answered a check of one
+    pub fn connected(&self) {
+        *self.unreachable.lock() = None;
+        if let Some(path) = self.liveness_file.get()
+            && let Err(err) = touch(path)
+        {
+            warn!("Cannot touch liveness file {}: {err}", path.display());
+        }
+    }
+
+    pub fn failed(&self, err: &anyhow::Error) {
+        let mut unreachable = self.unreachable.lock();
+        let since = unreachable.as_ref().map_or_else(Instant::now, |(since, _)| *since);
+        *unreachable = Some((since, format!("{err:#}")));
+        if let Some(path) = self.liveness_file.get() {
+            remove_liveness_file(path);
+        }
+    }
+
+    /// For how long the server has been unreachable, None if the last connection attempt succeeded
+    pub fn unreachable_for(&self) -> Option<Duration> {
+        self.unreachable.lock().as_ref().map(|(since, _)| since.elapsed())
+    }
+
+    pub fn probe(&self, path: &str) -> Option<(StatusCode, String)> {
+        match path {
+            "/healthz" => Some((StatusCode::OK, "ok\n".to_string())),
+            "/readyz" => Some(match &*self.unreachable.lock() {
+                None => (StatusCode::OK, "server: ok\n".to_string()),
+                Some((since, err)) => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("server: unreachable for {}s: {err}\n", since.elapsed().as_secs()),
+                ),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Create the file, or update its modification time, so the health checks can tell how recently the server was reached
+fn touch(path: &Path) -> io::Result<()> {
+    let file = File::options().create(true).append(true).open(path)?;
+    file.set_modified(SystemTime::now())
+}
+
+fn remove_liveness_file(path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => warn!("Cannot remove liveness file {}: {err}", path.display()),
+    }
+}
+
+pub async fn run_client_health_server(bind: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind)
+        .await
+        .with_context(|| format!("Cannot bind health server on {bind}"))?;
+    info!("Serving health probes on http://{bind}/healthz and http://{bind}/readyz");
+
+    metrics::serve_http(listener, |path| match SERVER_REACHABILITY.probe(path) {
+        Some((status, body)) => metrics::probe_response(status, body),
+        None => metrics::not_found(),
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probes() {
+        let health = Health::new();
+        assert_eq!(health.probe("/metrics"), None);
+        assert_eq!(health.probe("/healthz"), Some((StatusCode::OK, "ok\n".to_string())));
+        assert_eq!(
+            health.probe("/readyz"),
+            Some((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "listener: not listening\nrestrictions: ok\n".to_string()
+            ))
+        );
+
+        health.set_listening(true);
+        health.set_restrictions_reload(Err("invalid yaml".to_string()));
+        assert_eq!(
+            health.probe("/readyz"),
+            Some((
+                StatusCode::OK,
+                "listener: ok\nrestrictions: last reload failed, previous rules kept: invalid yaml\n".to_string()
+            ))
+        );
+
+        health.set_restrictions_reload(Ok(()));
+        assert_eq!(
+            health.probe("/readyz"),
+            Some((StatusCode::OK, "listener: ok\nrestrictions: ok\n".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_reachability() {
+        let reachability = ServerReachability::new();
+        assert_eq!(reachability.unreachable_for(), None);
+        assert_eq!(
+            reachability.probe("/readyz"),
+            Some((StatusCode::OK, "server: ok\n".to_string()))
+        );
+
+        reachability.failed(&anyhow::anyhow!("connection refused"));
+        assert!(reachability.unreachable_for().is_some());
+        assert_eq!(
+            reachability.probe("/readyz"),
+            Some((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server: unreachable for 0s: connection refused\n".to_string()
+            ))
+        );
+        assert_eq!(reachability.probe("/healthz"), Some((StatusCode::OK, "ok\n".to_string())));
+
+        reachability.connected();
+        assert_eq!(reachability.unreachable_for(), None);
+    }
+
+    #[test]
+    fn test_liveness_file() {
+        let path = std::env::temp_dir().join(format!("wstunnel-liveness-{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let reachability = ServerReachability::new();
+        reachability.set_liveness_file(path.clone());
+        assert!(!path.exists());
+
+        reachability.connected();
+        let touched_at = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        reachability.connected();
+        assert!(std::fs::metadata(&path).unwrap().modified().unwrap() > touched_at);
+
+        reachability.failed(&anyhow::anyhow!("connection refused"));
+        assert!(!path.exists());
+        reachability.failed(&anyhow::anyhow!("connection refused"));
+        assert!(!path.exists());
+    }
+}
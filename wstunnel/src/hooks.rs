@@ -0,0 +1,191 @@
+//! Commands run when a tunnel is opened or closed, i.e: to punch a firewall, update a dynamic dns or send a
+//! notification. They get the metadata of the tunnel in WSTUNNEL_* env vars and as json on their stdin
+use crate::stats::Side;
+use crate::tunnel::protocol::close_reason::CloseReason;
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+
+/// Longest time a hook can run before being killed
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shell commands run on the events of the tunnels of one side
+#[derive(Clone, Debug, Default)]
+pub struct TunnelHooks {
+    pub on_connect: Option<String>,
+    pub on_close: Option<String>,
+}
+
+/// A client and a server can run in the same process, each with its own hooks
+static HOOKS: LazyLock<RwLock<[TunnelHooks; 2]>> = LazyLock::new(Default::default);
+
+fn index(side: Side) -> usize {
+    match side {
+        Side::Client => 0,
+        Side::Server => 1,
+    }
+}
+
+pub fn set_tunnel_hooks(side: Side, hooks: TunnelHooks) {
+    HOOKS.write()[index(side)] = hooks;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelEventKind {
+    Connect,
+    Close,
+}
+
+impl TunnelEventKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::Close => "close",
+        }
+    }
+}
+
+/// Metadata of a tunnel given to the hooks. The duration, the bytes and the reason are only known once it is closed
+#[derive(Debug, Serialize)]
+pub struct TunnelEvent {
+    pub event: TunnelEventKind,
+    pub side: Side,
+    pub id: String,
+    pub protocol: &'static str,
+    pub remote: String,
+    pub peer: Option<SocketAddr>,
+    pub label: Option<String>,
+    pub duration_secs: Option<u64>,
+    pub tx_bytes: Option<u64>,
+    pub rx_bytes: Option<u64>,
+    /// Why the local side of the tunnel ended: normal, reset, timeout or error
+    pub close_reason: Option<CloseReason>,
+}
+
+impl TunnelEvent {
+    fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            ("WSTUNNEL_EVENT", self.event.as_str().to_string()),
+            ("WSTUNNEL_SIDE", format!("{:?}", self.side).to_lowercase()),
+            ("WSTUNNEL_TUNNEL_ID", self.id.clone()),
+            ("WSTUNNEL_PROTOCOL", self.protocol.to_string()),
+            ("WSTUNNEL_REMOTE", self.remote.clone()),
+        ];
+        let optionals = [
+            ("WSTUNNEL_PEER", self.peer.map(|peer| peer.to_string())),
+            ("WSTUNNEL_LABEL", self.label.clone()),
+            ("WSTUNNEL_DURATION_SECS", self.duration_secs.map(|secs| secs.to_string())),
+            ("WSTUNNEL_TX_BYTES", self.tx_bytes.map(|bytes| bytes.to_string())),
+            ("WSTUNNEL_RX_BYTES", self.rx_bytes.map(|bytes| bytes.to_string())),
+            ("WSTUNNEL_CLOSE_REASON", self.close_reason.map(|reason| reason.to_string())),
+        ];
+        vars.extend(optionals.into_iter().filter_map(|(name, value)| Some((name, value?))));
+        vars
+    }
+}
+
+/// Whether a hook is set for this event, to not gather the metadata of the tunnels for nothing
+pub fn has_hook(side: Side, kind: TunnelEventKind) -> bool {
+    hook_command(side, kind).is_some()
+}
+
+fn hook_command(side: Side, kind: TunnelEventKind) -> Option<String> {
+    let hooks = HOOKS.read();
+    let hooks = &hooks[index(side)];
+    match kind {
+        TunnelEventKind::Connect => hooks.on_connect.clone(),
+        TunnelEventKind::Close => hooks.on_close.clone(),
+    }
+}
+
+/// Run the hook of the event in the background, if there is one. The tunnel does not wait for it
+pub fn run_hook(event: TunnelEvent) {
+    let Some(command) = hook_command(event.side, event.event) else {
+        return;
+    };
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        debug!("Not running hook of tunnel {}, the runtime is shutting down", event.id);
+        return;
+    };
+    runtime.spawn(async move {
+        if let Err(err) = run_command(&command, &event).await {
+            warn!("Hook of tunnel {} {} failed: {err:?}", event.id, event.event.as_str());
+        }
+    });
+}
+
+async fn run_command(command: &str, event: &TunnelEvent) -> anyhow::Result<()> {
+    #[cfg(windows)]
+    let mut cmd = tokio::process::Command::new("cmd");
+    #[cfg(windows)]
+    cmd.args(["/C", command]);
+    #[cfg(not(windows))]
+    let mut cmd = tokio::process::Command::new("sh");
+    #[cfg(not(windows))]
+    cmd.args(["-c", command]);
+
+    let mut child = cmd
+        .envs(event.env_vars())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let payload = serde_json::to_vec(event)?;
+    if let Some(mut stdin) = child.stdin.take()
+        && let Err(err) = stdin.write_all(&payload).await
+    {
+        // The command may exit without reading its stdin
+        debug!("cannot write event to hook stdin: {err}");
+    }
+
+    let status = tokio::time::timeout(HOOK_TIMEOUT, child.wait())
+        .await
+        .map_err(|_| anyhow::anyhow!("still running after {}s, killed", HOOK_TIMEOUT.as_secs()))??;
+    if !status.success() {
+        return Err(anyhow::anyhow!("{command} exited with {status}"));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hook_gets_the_tunnel_metadata() {
+        let out = std::env::temp_dir().join(format!("wstunnel-tunnel-hook-{}", std::process::id()));
+        let command = format!(
+            "echo $WSTUNNEL_EVENT $WSTUNNEL_REMOTE $WSTUNNEL_TX_BYTES $WSTUNNEL_CLOSE_REASON > {0} && cat >> {0}",
+            out.display()
+        );
+        let event = TunnelEvent {
+            event: TunnelEventKind::Close,
+            side: Side::Server,
+            id: "1234".to_string(),
+            protocol: "tcp",
+            remote: "localhost:22".to_string(),
+            peer: Some("127.0.0.1:4567".parse().unwrap()),
+            label: None,
+            duration_secs: Some(3),
+            tx_bytes: Some(42),
+            rx_bytes: Some(24),
+            close_reason: Some(CloseReason::Reset),
+        };
+        run_command(&command, &event).await.unwrap();
+
+        let out_content = std::fs::read_to_string(&out).unwrap();
+        assert!(out_content.starts_with("close localhost:22 42 reset\n"));
+        assert!(out_content.contains(r#""peer":"127.0.0.1:4567""#));
+        assert!(out_content.contains(r#""close_reason":"reset""#));
+        std::fs::remove_file(&out).unwrap();
+
+        assert!(run_command("exit 1", &event).await.is_err());
+    }
+}
@@ -1,44 +1,135 @@
+pub mod builder;
 pub mod config;
+pub mod control;
+mod dscp;
 mod embedded_certificate;
 pub mod executor;
+mod failure;
+mod health;
+mod hooks;
+mod metrics;
+mod oidc;
 mod protocols;
 mod restrictions;
 mod somark;
+mod source_bind;
+pub mod stats;
 #[cfg(test)]
 mod test_integrations;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 pub mod tunnel;
 
-use crate::config::{Client, DEFAULT_CLIENT_UPGRADE_PATH_PREFIX, Server};
+pub use crate::builder::{ClientBuilder, ServerBuilder};
+use crate::config::{Client, DEFAULT_CLIENT_UPGRADE_PATH_PREFIX, LocalToRemote, OidcLogin, Secret, Server};
 use crate::executor::{TokioExecutor, TokioExecutorRef};
+pub use crate::failure::FailureKind;
+use crate::hooks::TunnelHooks;
+use crate::oidc::OidcValidator;
 use crate::protocols::dns::DnsResolver;
+use crate::protocols::http_client::HttpClientConfig;
+use crate::protocols::socks5::{FakeIps, Socks5Resolver, run_fake_dns_server};
 use crate::protocols::tls;
+pub use crate::protocols::tls::{CryptoProviderKind, TlsFingerprint};
 use crate::restrictions::types::RestrictionsRules;
 use crate::somark::SoMark;
+use crate::source_bind::SourceBind;
+use crate::stats::Side;
 pub use crate::tunnel::LocalProtocol;
+use crate::tunnel::client::{
+    AFFINITY_HEADER, Camouflage, NetworkSim, OnListenerError, RECONNECT_GAVE_UP, RECONNECT_GAVE_UP_UNAUTHORIZED,
+    load_or_generate_device_id,
+};
 pub use crate::tunnel::client::{TlsClientConfig, WsClient, WsClientConfig};
-use crate::tunnel::connectors::{Socks5TunnelConnector, TcpTunnelConnector, UdpTunnelConnector};
+use crate::tunnel::connectors::{EncryptedDnsConnector, Socks5TunnelConnector, TcpTunnelConnector, UdpTunnelConnector};
 use crate::tunnel::listeners::{
     HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, UdpTunnelListener, new_stdio_listener,
+    new_stdio_udp_listener,
+};
+use crate::tunnel::noise::{NoiseClientConfig, NoiseServerConfig};
+#[cfg(feature = "dns-transport")]
+use crate::tunnel::server::DnsTransportConfig;
+#[cfg(feature = "icmp-transport")]
+use crate::tunnel::server::IcmpTransportConfig;
+#[cfg(feature = "ssh-transport")]
+use crate::tunnel::server::SshTransportConfig;
+use crate::tunnel::server::{
+    ClusterConfig, HttpIngressDomain, RejectResponse, SniPassthrough, TlsServerConfig, WsServer, WsServerConfig,
 };
-use crate::tunnel::server::{TlsServerConfig, WsServer, WsServerConfig};
-use crate::tunnel::transport::{TransportAddr, TransportScheme};
-use crate::tunnel::{RemoteAddr, to_host_port};
+use crate::tunnel::transport::buffer_pool::BUFFER_POOL;
+use crate::tunnel::transport::obfuscation::TrafficObfuscation;
+use crate::tunnel::transport::{PreSharedKey, StickySession, TransportAddr, TransportScheme};
+use crate::tunnel::{RemoteAddr, Socks5Resolve, UdpFlowEviction, http_ingress_subdomain, to_host_port};
 use anyhow::{Context, anyhow};
-use futures_util::future::BoxFuture;
+use futures_util::future::{BoxFuture, join_all};
 use hyper::header::HOST;
-use hyper::http::HeaderValue;
+use hyper::http::{HeaderValue, StatusCode};
 use log::debug;
 use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 use tokio::select;
 use tokio::sync::oneshot;
 use tokio::task::JoinSet;
-use tracing::{error, info};
-use url::Url;
+use tracing::{error, info, warn};
+use url::{Host, Url};
+use uuid::Uuid;
+
+/// Interval at which the client tries again to bind the listeners that failed, with --on-listener-error retry
+const LISTENER_REBIND_INTERVAL: Duration = Duration::from_secs(10);
+/// How many times the client tries again to bind its listeners when none of its tunnels could be started, before exiting
+const LISTENER_REBIND_ATTEMPTS: u32 = 6;
 
 pub async fn run_client(args: Client, executor: impl TokioExecutor) -> anyhow::Result<()> {
+    if args.dump_config {
+        config::secret::show_secrets(args.show_secrets);
+        println!("{args:#?}");
+        return Ok(());
+    }
+
+    if let Some(path) = &args.emit_restrictions {
+        restrictions::emit::write_client_restrictions(
+            path,
+            &args.http_upgrade_path_prefix,
+            &args.local_to_remote,
+            &args.remote_to_local,
+        )
+        .with_context(|| format!("Cannot write restrictions to {}", path.display()))?;
+        info!("Restrictions for the server written to {}", path.display());
+        return Ok(());
+    }
+
+    if let Some(path) = &args.liveness_file {
+        health::SERVER_REACHABILITY.set_liveness_file(path.clone());
+    }
+    if let Some(health_listen) = args.health_listen {
+        executor.spawn(async move {
+            if let Err(err) = health::run_client_health_server(health_listen).await {
+                error!("Health server stopped: {err:?}");
+            }
+        });
+    }
+    if let Some(admin_listen) = args.admin_listen {
+        executor.spawn(run_admin_server(admin_listen));
+    }
+    #[cfg(unix)]
+    executor.spawn(stats::log_status_on_signal());
+    if let Some(max_memory_buffers) = args.max_memory_buffers {
+        BUFFER_POOL.set_max_bytes(max_memory_buffers);
+    }
+    hooks::set_tunnel_hooks(
+        Side::Client,
+        TunnelHooks {
+            on_connect: args.on_tunnel_connect.clone(),
+            on_close: args.on_tunnel_close.clone(),
+        },
+    );
+
+    let exit_if_disconnected_for = args.exit_if_disconnected_for;
     let tunnels = create_client_tunnels(args, executor.ref_clone()).await?;
 
     // Start all tunnels
@@ -48,24 +139,79 @@ pub async fn run_client(args: Client, executor: impl TokioExecutor) -> anyhow::R
         let _ = tx.send(());
     });
 
-    // wait for all tunnels to finish
-    rx.await?;
+    // wait for all tunnels to finish, for the server to be unreachable for too long,
+    // or for a reverse tunnel to give up reconnecting to it
+    select! {
+        ret = rx => ret?,
+        err = wait_server_unreachable_for(exit_if_disconnected_for) => return Err(err),
+        _ = RECONNECT_GAVE_UP.notified() => {
+            let err = anyhow!("A reverse tunnel gave up reconnecting to the server, exiting");
+            if RECONNECT_GAVE_UP_UNAUTHORIZED.load(Ordering::Relaxed) {
+                return Err(err.context(FailureKind::Auth));
+            }
+            return Err(err);
+        }
+    }
     Ok(())
 }
 
+async fn wait_server_unreachable_for(max_disconnection: Option<Duration>) -> anyhow::Error {
+    let Some(max_disconnection) = max_disconnection else {
+        return std::future::pending().await;
+    };
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        if let Some(unreachable_for) = health::SERVER_REACHABILITY.unreachable_for()
+            && unreachable_for >= max_disconnection
+        {
+            return anyhow!("Server has been unreachable for {}s, exiting", unreachable_for.as_secs());
+        }
+    }
+}
+
+async fn exit_once_stdio_closed(mut handle: oneshot::Sender<()>) -> ! {
+    // We need to wait for either a ctrl+c of that the stdio tunnel is closed
+    // to force exit the program
+    select! {
+       _ = handle.closed() => {},
+       _ = tokio::signal::ctrl_c() => {}
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    std::process::exit(0);
+}
+
+/// Print, as a json line, the address picked by the OS for a tunnel requested to listen on port 0.
+/// i.e: {"event":"listening","local":"127.0.0.1:41235","protocol":"tcp","remote":"google.com:443"}
+fn announce_listen_addr(protocol: &str, local: SocketAddr, remote: &(Host, u16), to_stderr: bool) {
+    let announce = serde_json::json!({
+        "event": "listening",
+        "protocol": protocol,
+        "local": local.to_string(),
+        "remote": format!("{}:{}", remote.0, remote.1),
+    });
+    if to_stderr {
+        eprintln!("{announce}");
+    } else {
+        println!("{announce}");
+    }
+}
+
 pub async fn create_client(
     args: Client,
     executor: impl TokioExecutorRef,
 ) -> anyhow::Result<WsClient<impl TokioExecutorRef>> {
-    let (tls_certificate, tls_key) = if let (Some(cert), Some(key)) =
-        (args.tls_certificate.as_ref(), args.tls_private_key.as_ref())
-    {
-        let tls_certificate = tls::load_certificates_from_pem(cert).expect("Cannot load client TLS certificate (mTLS)");
-        let tls_key = tls::load_private_key_from_file(key).expect("Cannot load client TLS private key (mTLS)");
-        (Some(tls_certificate), Some(tls_key))
-    } else {
-        (None, None)
-    };
+    let (tls_certificate, tls_key) =
+        if let (Some(cert), Some(key)) = (args.tls_certificate.as_ref(), args.tls_private_key.as_ref()) {
+            let tls_certificate = tls::load_certificates_from_pem(cert)
+                .context("Cannot load client TLS certificate (mTLS)")
+                .context(FailureKind::Tls)?;
+            let tls_key = tls::load_private_key_from_file(key)
+                .context("Cannot load client TLS private key (mTLS)")
+                .context(FailureKind::Tls)?;
+            (Some(tls_certificate), Some(tls_key))
+        } else {
+            (None, None)
+        };
 
     let http_upgrade_path_prefix = if args.http_upgrade_path_prefix.eq(DEFAULT_CLIENT_UPGRADE_PATH_PREFIX) {
         // When using mTLS and no manual http upgrade path is specified configure the HTTP upgrade path
@@ -79,24 +225,41 @@ pub async fn create_client(
         args.http_upgrade_path_prefix
     };
 
-    let http_proxy = mk_http_proxy(args.http_proxy, args.http_proxy_login, args.http_proxy_password)?;
+    let device_id = match (args.device_id, &args.device_id_file) {
+        (Some(id), _) => Some(id),
+        (None, Some(path)) => Some(load_or_generate_device_id(path).context(FailureKind::Config)?),
+        (None, None) => None,
+    };
+
+    let http_proxy =
+        mk_http_proxy(args.http_proxy, args.http_proxy_login, args.http_proxy_password).context(FailureKind::Config)?;
     let dns_resolver = DnsResolver::new_from_urls(
         &args.dns_resolver,
         http_proxy.clone(),
         SoMark::new(args.socket_so_mark),
         !args.dns_resolver_prefer_ipv4,
     )
-    .expect("cannot create dns resolver");
+    .context("cannot create dns resolver")
+    .context(FailureKind::Config)?;
 
-    let transport_scheme = TransportScheme::from_str(args.remote_addr.scheme()).expect("invalid scheme in server url");
+    let transport_scheme = TransportScheme::from_str(args.remote_addr.scheme())
+        .map_err(|_| anyhow!("invalid scheme in server url: {}", args.remote_addr.scheme()))
+        .context(FailureKind::Config)?;
     let tls = match transport_scheme {
-        TransportScheme::Ws | TransportScheme::Http => None,
-        TransportScheme::Wss | TransportScheme::Https => {
+        TransportScheme::Ws | TransportScheme::Http | TransportScheme::Http1 | TransportScheme::Grpc => None,
+        #[cfg(feature = "dns-transport")]
+        TransportScheme::Dns => None,
+        #[cfg(feature = "icmp-transport")]
+        TransportScheme::Icmp => None,
+        #[cfg(feature = "ssh-transport")]
+        TransportScheme::Ssh => None,
+        TransportScheme::Wss | TransportScheme::Https | TransportScheme::Https1 | TransportScheme::Grpcs => {
             let ech_config = if args.tls_ech_enable {
                 #[cfg(not(feature = "aws-lc-rs"))]
-                return Err(anyhow!(
-                    "Your current build does not support ECH. You need to use aws-lc crypto provider"
-                ));
+                return Err(
+                    anyhow!("Your current build does not support ECH. You need to use aws-lc crypto provider")
+                        .context(FailureKind::Config),
+                );
 
                 #[cfg(feature = "aws-lc-rs")]
                 dns_resolver
@@ -110,17 +273,20 @@ pub async fn create_client(
                 args.tls_verify_certificate,
                 transport_scheme.alpn_protocols(),
                 !args.tls_sni_disable,
+                args.tls_fingerprint,
                 ech_config,
                 tls_certificate,
                 tls_key,
             )
-            .expect("Cannot create tls connector");
+            .context("Cannot create tls connector")
+            .context(FailureKind::Tls)?;
 
             Some(TlsClientConfig {
                 tls_connector: Arc::new(RwLock::new(tls_connector)),
                 tls_sni_override: args.tls_sni_override,
                 tls_verify_certificate: args.tls_verify_certificate,
                 tls_sni_disabled: args.tls_sni_disable,
+                tls_fingerprint: args.tls_fingerprint,
                 tls_certificate_path: args.tls_certificate.clone(),
                 tls_key_path: args.tls_private_key.clone(),
             })
@@ -135,41 +301,158 @@ pub async fn create_client(
             None | Some(80) | Some(443) => args.remote_addr.host().unwrap().to_string(),
             Some(port) => format!("{}:{}", args.remote_addr.host().unwrap(), port),
         };
-        HeaderValue::from_str(&host)?
+        HeaderValue::from_str(&host).context(FailureKind::Config)?
+    };
+    // The tunnels of the client are kept on the same server of the pair by the load balancer in front of them
+    let mut http_headers: HashMap<_, _> = args.http_headers.into_iter().filter(|(k, _)| k != HOST).collect();
+    let affinity_token = match args.affinity_token {
+        Some(token) => Some(token),
+        None if !args.standby_server.is_empty() => Some(Uuid::new_v4().to_string()),
+        None => None,
     };
+    if let Some(token) = affinity_token {
+        http_headers.insert(
+            AFFINITY_HEADER,
+            HeaderValue::from_str(&token)
+                .context("invalid --affinity-token")
+                .context(FailureKind::Config)?,
+        );
+    }
     if let Some(path) = &args.http_headers_file
         && !path.exists()
     {
-        panic!("http headers file does not exists: {}", path.display());
+        return Err(anyhow!("http headers file does not exists: {}", path.display()).context(FailureKind::Config));
     }
+    let oidc_token_cache = match (args.oidc, args.oidc_token_cache) {
+        (false, _) => None,
+        (true, Some(path)) => Some(path),
+        (true, None) => Some(oidc::default_token_cache_path()?),
+    };
+
+    // Dns and icmp transports have no http request to carry the pre-shared key proofs
+    let psk = match transport_scheme {
+        #[cfg(feature = "dns-transport")]
+        TransportScheme::Dns if args.psk.is_some() => {
+            return Err(anyhow!("--psk is not supported by the dns transport").context(FailureKind::Config));
+        }
+        #[cfg(feature = "icmp-transport")]
+        TransportScheme::Icmp if args.psk.is_some() => {
+            return Err(anyhow!("--psk is not supported by the icmp transport").context(FailureKind::Config));
+        }
+        _ => args.psk.map(|psk| PreSharedKey::new(psk.expose().as_bytes())),
+    };
+
+    #[cfg(feature = "dns-transport")]
+    let dns_transport_resolver = match (transport_scheme, args.dns_transport_resolver) {
+        (TransportScheme::Dns, None) => {
+            let (resolver_cfg, _) = hickory_resolver::system_conf::read_system_conf()
+                .context("cannot read system dns configuration to find a resolver for the dns transport")?;
+            let resolver = resolver_cfg
+                .name_servers()
+                .first()
+                .map(|ns| ns.socket_addr)
+                .ok_or_else(|| anyhow!("no dns resolver configured on the system, use --dns-transport-resolver"))?;
+            Some(resolver)
+        }
+        (_, resolver) => resolver,
+    };
 
     let client_config = WsClientConfig {
         remote_addr: TransportAddr::new(
             TransportScheme::from_str(args.remote_addr.scheme()).unwrap(),
             args.remote_addr.host().unwrap().to_owned(),
-            args.remote_addr.port_or_known_default().unwrap(),
+            // http1://, https1://, grpc://, grpcs://, ssh://, dns:// and icmp:// are not special schemes for the url crate, so they have no known
+            // default port. The port is not used by the icmp transport
+            args.remote_addr
+                .port_or_known_default()
+                .unwrap_or(match transport_scheme {
+                    TransportScheme::Http1 | TransportScheme::Grpc => 80,
+                    TransportScheme::Https1 | TransportScheme::Grpcs => 443,
+                    #[cfg(feature = "ssh-transport")]
+                    TransportScheme::Ssh => 22,
+                    _ => 53,
+                }),
             tls,
         )
         .unwrap(),
         socket_so_mark: SoMark::new(args.socket_so_mark),
         http_upgrade_path_prefix,
+        device_id,
         http_upgrade_credentials: args.http_upgrade_credentials,
-        http_headers: args.http_headers.into_iter().filter(|(k, _)| k != HOST).collect(),
+        oidc_token_cache,
+        psk,
+        noise: args
+            .noise_private_key
+            .zip(args.noise_server_public_key)
+            .map(|(private_key, server_public_key)| NoiseClientConfig {
+                private_key,
+                server_public_key,
+            }),
+        http_headers,
         http_headers_file: args.http_headers_file,
         http_header_host: host_header,
         timeout_connect: Duration::from_secs(10),
+        connection_health_check_interval: args.connection_health_check_interval.filter(|d| !d.is_zero()),
+        connection_max_idle_age: args.connection_max_idle_age.filter(|d| !d.is_zero()),
+        connection_warmup_timeout: args.connection_warmup_timeout,
         websocket_ping_frequency: args
             .websocket_ping_frequency
             .or(Some(Duration::from_secs(30)))
             .filter(|d| d.as_secs() > 0),
         websocket_mask_frame: args.websocket_mask_frame,
+        websocket_max_frame_size: args.websocket_max_frame_size,
+        websocket_close_timeout: args.websocket_close_timeout,
+        websocket_close_codes: args.websocket_close_code.iter().copied().collect(),
+        max_inflight_per_tunnel: args.max_inflight_per_tunnel,
+        http_split_requests: args.http_split_requests,
+        mux: args.mux,
+        transport_max_lifetime: args.transport_max_lifetime,
+        transport_max_bytes: args.transport_max_bytes,
+        early_data: args.early_data,
+        camouflage: Camouflage {
+            random_path: args.random_upgrade_path,
+            hosts: args.rotate_host,
+            browser: args.camouflage,
+        },
+        upgrade_max_redirects: args.upgrade_max_redirects,
+        upgrade_redirect_policy: args.upgrade_redirect_policy,
+        standby_servers: args.standby_server,
+        pcap_dir: args.pcap_dir,
+        integrity_check_interval: args.integrity_check_interval,
+        network_sim: (args.sim_latency.is_some() || args.sim_loss.is_some() || args.sim_rate.is_some()).then(|| {
+            NetworkSim {
+                latency: args.sim_latency.unwrap_or_default(),
+                loss: args.sim_loss.unwrap_or_default(),
+                rate: args.sim_rate,
+                seed: args.sim_seed,
+            }
+        }),
+        tcp_fastopen: args.tcp_fastopen,
+        dscp: args.dscp,
+        source_bind: SourceBind::new(args.bind_interface.clone(), args.bind_address),
         dns_resolver,
-        http_proxy,
+        http_proxy: http_proxy.map(Secret::new),
+        reverse_tunnel_max_retries: args.reverse_tunnel_connection_max_retries,
+        reverse_tunnel_hook: args.reverse_tunnel_hook,
+        reverse_tunnel_probe_interval: args.reverse_tunnel_probe_interval,
+        #[cfg(feature = "dns-transport")]
+        dns_transport_resolver,
     };
 
+    // Dns and icmp transports do not keep connections open to the server, so there is nothing to pool. The ssh one
+    // keeps a single connection, opened with the first tunnel
+    let connection_min_idle = match transport_scheme {
+        #[cfg(feature = "ssh-transport")]
+        TransportScheme::Ssh => 0,
+        #[cfg(feature = "dns-transport")]
+        TransportScheme::Dns => 0,
+        #[cfg(feature = "icmp-transport")]
+        TransportScheme::Icmp => 0,
+        _ => args.connection_min_idle,
+    };
     let client = WsClient::new(
         client_config,
-        args.connection_min_idle,
+        connection_min_idle,
         args.connection_retry_max_backoff,
         args.reverse_tunnel_connection_retry_max_backoff,
         executor,
@@ -186,8 +469,135 @@ async fn create_client_tunnels(
 ) -> anyhow::Result<Vec<BoxFuture<'static, ()>>> {
     let remote_to_local = std::mem::take(&mut args.remote_to_local);
     let local_to_remote = std::mem::take(&mut args.local_to_remote);
+    let control_socket = args.control_socket.take();
+    let on_listener_error = args.on_listener_error;
     let client = create_client(args, executor).await?;
+    let Some(control_socket) = control_socket else {
+        return start_tunnels(
+            client,
+            remote_to_local,
+            local_to_remote,
+            on_listener_error,
+            LISTENER_REBIND_INTERVAL,
+        )
+        .await;
+    };
+
+    // The tunnels of the command line are controlled like the ones started through the socket, which keeps the client running
+    let controller = control::Controller::new(client);
+    for tunnel in remote_to_local {
+        controller
+            .start_tunnel(tunnel, control::Direction::RemoteToLocal)
+            .await?;
+    }
+    for tunnel in local_to_remote {
+        controller
+            .start_tunnel(tunnel, control::Direction::LocalToRemote)
+            .await?;
+    }
+    Ok(vec![Box::pin(async move {
+        if let Err(err) = control::serve(&control_socket, controller).await {
+            error!("Control socket stopped: {err:?}");
+        }
+    })])
+}
+
+/// Futures driving the tunnels of the command line, doing with the listeners that cannot be bound what
+/// `on_listener_error` says. When none of the tunnels could be started, retrying only goes on for
+/// [`LISTENER_REBIND_ATTEMPTS`], so a client with nothing to do still exits
+pub(crate) async fn start_tunnels(
+    client: WsClient<impl TokioExecutorRef>,
+    remote_to_local: Vec<LocalToRemote>,
+    local_to_remote: Vec<LocalToRemote>,
+    on_listener_error: OnListenerError,
+    rebind_interval: Duration,
+) -> anyhow::Result<Vec<BoxFuture<'static, ()>>> {
+    if on_listener_error == OnListenerError::Fail {
+        return client_tunnels(client, remote_to_local, local_to_remote, None).await;
+    }
 
+    let mut failed_listeners = vec![];
+    let mut tunnels =
+        client_tunnels(client.clone(), remote_to_local, local_to_remote, Some(&mut failed_listeners)).await?;
+    if on_listener_error == OnListenerError::Skip {
+        for (tunnel, err) in &failed_listeners {
+            error!(
+                "Skipping the tunnel to {}:{}, its listener cannot be bound: {err:#}",
+                tunnel.remote.0, tunnel.remote.1
+            );
+        }
+        if let Some((_, err)) = failed_listeners.pop()
+            && tunnels.is_empty()
+        {
+            return Err(err.context("None of the tunnels could be started"));
+        }
+        return Ok(tunnels);
+    }
+
+    let mut attempt = 0;
+    while tunnels.is_empty() && !failed_listeners.is_empty() {
+        if attempt == LISTENER_REBIND_ATTEMPTS
+            && let Some((_, err)) = failed_listeners.pop()
+        {
+            return Err(err.context("None of the tunnels could be started"));
+        }
+        attempt += 1;
+        for (tunnel, err) in &failed_listeners {
+            warn!(
+                "Cannot bind the listener of the tunnel to {}:{}: {err:#}",
+                tunnel.remote.0, tunnel.remote.1
+            );
+        }
+        warn!(
+            "None of the tunnels could be started, retrying in {rebind_interval:?} ({attempt}/{LISTENER_REBIND_ATTEMPTS})"
+        );
+        tokio::time::sleep(rebind_interval).await;
+        let retried = failed_listeners.drain(..).map(|(tunnel, _)| tunnel).collect();
+        tunnels = client_tunnels(client.clone(), vec![], retried, Some(&mut failed_listeners)).await?;
+    }
+    tunnels.extend(
+        failed_listeners
+            .into_iter()
+            .map(|(tunnel, err)| rebind_listener(client.clone(), tunnel, err, rebind_interval)),
+    );
+    Ok(tunnels)
+}
+
+/// Tunnel whose listener could not be bound, trying again to bind it every `rebind_interval`
+fn rebind_listener(
+    client: WsClient<impl TokioExecutorRef>,
+    tunnel: LocalToRemote,
+    mut err: anyhow::Error,
+    rebind_interval: Duration,
+) -> BoxFuture<'static, ()> {
+    Box::pin(async move {
+        loop {
+            warn!(
+                "Cannot bind the listener of the tunnel to {}:{}, retrying in {:?}: {err:#}",
+                tunnel.remote.0, tunnel.remote.1, rebind_interval
+            );
+            tokio::time::sleep(rebind_interval).await;
+            match client_tunnels(client.clone(), vec![], vec![tunnel.clone()], None).await {
+                Ok(tunnels) => {
+                    info!("Listener of the tunnel to {}:{} is bound", tunnel.remote.0, tunnel.remote.1);
+                    join_all(tunnels).await;
+                    return;
+                }
+                Err(retry_err) => err = retry_err,
+            }
+        }
+    })
+}
+
+/// Futures driving the given tunnels. Stdio tunnels are run in place, and exit the process once closed.
+/// With `failed_listeners`, the -L tunnels whose listener cannot be bound are put in it with their error, instead of
+/// failing all the tunnels
+pub(crate) async fn client_tunnels(
+    client: WsClient<impl TokioExecutorRef>,
+    remote_to_local: Vec<LocalToRemote>,
+    local_to_remote: Vec<LocalToRemote>,
+    mut failed_listeners: Option<&mut Vec<(LocalToRemote, anyhow::Error)>>,
+) -> anyhow::Result<Vec<BoxFuture<'static, ()>>> {
     // Keep track of all spawned tunnels
     let mut tunnels: Vec<BoxFuture<()>> = Vec::with_capacity(remote_to_local.len() + local_to_remote.len());
     macro_rules! spawn_tunnel {
@@ -199,10 +609,21 @@ async fn create_client_tunnels(
     }
 
     // Start tunnels
-    for tunnel in remote_to_local.into_iter() {
-        let client = client.clone();
+    for tunnel in remote_to_local.into_iter().flat_map(LocalToRemote::split_port_range) {
+        let client = client
+            .clone()
+            .with_label(tunnel.label.as_deref())
+            .with_dscp(tunnel.dscp);
         match &tunnel.local_protocol {
-            LocalProtocol::ReverseTcp => {
+            LocalProtocol::ReverseTcp {
+                resume,
+                idle_timeout,
+                v6only,
+                access,
+                keepalive,
+            } => {
+                let (resume, idle_timeout, v6only, access, keepalive) =
+                    (*resume, *idle_timeout, *v6only, access.clone(), *keepalive);
                 spawn_tunnel! {
                     let cfg = client.config.clone();
                     let tcp_connector = TcpTunnelConnector::new(
@@ -211,10 +632,17 @@ async fn create_client_tunnels(
                         cfg.socket_so_mark,
                         cfg.timeout_connect,
                         &cfg.dns_resolver,
-                    );
+                    )
+                    .with_keepalive(keepalive);
                     let (host, port) = to_host_port(tunnel.local);
                     let remote = RemoteAddr {
-                        protocol: LocalProtocol::ReverseTcp,
+                        protocol: LocalProtocol::ReverseTcp {
+                            resume,
+                            idle_timeout,
+                            v6only,
+                            access,
+                            keepalive,
+                        },
                         host,
                         port,
                     };
@@ -223,13 +651,23 @@ async fn create_client_tunnels(
                     }
                 }
             }
-            LocalProtocol::ReverseUdp { timeout } => {
-                let timeout = *timeout;
+            LocalProtocol::ReverseUdp {
+                timeout,
+                max_flows,
+                flow_eviction,
+                v6only,
+            } => {
+                let (timeout, max_flows, flow_eviction, v6only) = (*timeout, *max_flows, *flow_eviction, *v6only);
                 spawn_tunnel! {
                     let cfg = client.config.clone();
                     let (host, port) = to_host_port(tunnel.local);
                     let remote = RemoteAddr {
-                        protocol: LocalProtocol::ReverseUdp { timeout },
+                        protocol: LocalProtocol::ReverseUdp {
+                            timeout,
+                            max_flows,
+                            flow_eviction,
+                            v6only,
+                        },
                         host,
                         port,
                     };
@@ -246,14 +684,22 @@ async fn create_client_tunnels(
                     }
                 }
             }
-            LocalProtocol::ReverseSocks5 { timeout, credentials } => {
-                let credentials = credentials.clone();
+            LocalProtocol::ReverseSocks5 {
+                timeout,
+                credentials,
+                access,
+            } => {
+                let (credentials, access) = (credentials.clone(), access.clone());
                 let timeout = *timeout;
                 spawn_tunnel! {
                     let cfg = client.config.clone();
                     let (host, port) = to_host_port(tunnel.local);
                     let remote = RemoteAddr {
-                        protocol: LocalProtocol::ReverseSocks5 { timeout, credentials },
+                        protocol: LocalProtocol::ReverseSocks5 {
+                            timeout,
+                            credentials,
+                            access,
+                        },
                         host,
                         port,
                     };
@@ -265,14 +711,24 @@ async fn create_client_tunnels(
                     }
                 }
             }
-            LocalProtocol::ReverseHttpProxy { timeout, credentials } => {
-                let credentials = credentials.clone();
-                let timeout = *timeout;
+            LocalProtocol::ReverseHttpProxy {
+                timeout,
+                credentials,
+                v6only,
+                access,
+            } => {
+                let (credentials, access) = (credentials.clone(), access.clone());
+                let (timeout, v6only) = (*timeout, *v6only);
                 spawn_tunnel! {
                     let cfg = client.config.clone();
                     let (host, port) = to_host_port(tunnel.local);
                     let remote = RemoteAddr {
-                        protocol: LocalProtocol::ReverseHttpProxy { timeout, credentials },
+                        protocol: LocalProtocol::ReverseHttpProxy {
+                            timeout,
+                            credentials,
+                            v6only,
+                            access,
+                        },
                         host,
                         port,
                     };
@@ -289,8 +745,16 @@ async fn create_client_tunnels(
                     }
                 }
             }
-            LocalProtocol::ReverseUnix { path } => {
+            LocalProtocol::ReverseUnix {
+                path,
+                allowed_uids,
+                permissions,
+                lifecycle,
+            } => {
                 let path = path.clone();
+                let allowed_uids = allowed_uids.clone();
+                let permissions = permissions.clone();
+                let lifecycle = *lifecycle;
                 info!("Connecting to unix socket {:?}", tunnel);
                 spawn_tunnel! {
                     let cfg = client.config.clone();
@@ -304,7 +768,12 @@ async fn create_client_tunnels(
 
                     let (host, port) = to_host_port(tunnel.local);
                     let remote = RemoteAddr {
-                        protocol: LocalProtocol::ReverseUnix { path },
+                        protocol: LocalProtocol::ReverseUnix {
+                            path,
+                            allowed_uids,
+                            permissions,
+                            lifecycle,
+                        },
                         host,
                         port,
                     };
@@ -313,25 +782,137 @@ async fn create_client_tunnels(
                     }
                 }
             }
+            LocalProtocol::ReverseHttpIngress { vhost, auth, .. } => {
+                let auth = auth.clone();
+                // A subdomain of the ingress domain of the server is allocated to the client for the whole run
+                let (vhost, session) = match vhost.strip_prefix("*.") {
+                    Some(domain) => {
+                        let session = Uuid::new_v4();
+                        (format!("{}.{domain}", http_ingress_subdomain(&session)), Some(session))
+                    }
+                    None => (vhost.clone(), None),
+                };
+                info!("Exposing {}:{} on http://{vhost}", tunnel.remote.0, tunnel.remote.1);
+                spawn_tunnel! {
+                    let cfg = client.config.clone();
+                    let tcp_connector = TcpTunnelConnector::new(
+                        &tunnel.remote.0,
+                        tunnel.remote.1,
+                        cfg.socket_so_mark,
+                        cfg.timeout_connect,
+                        &cfg.dns_resolver,
+                    );
+
+                    let remote = RemoteAddr {
+                        host: Host::Domain(vhost.clone()),
+                        protocol: LocalProtocol::ReverseHttpIngress { vhost, session, auth: auth.clone() },
+                        port: 0,
+                    };
+                    if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
+                        error!("{:?}", err);
+                    }
+                }
+            }
+            LocalProtocol::ReverseEncryptedDns {
+                transport,
+                tls_certificate,
+                tls_private_key,
+            } => {
+                let dns_connector = EncryptedDnsConnector::new(
+                    *transport,
+                    tls_certificate.as_deref(),
+                    tls_private_key.as_deref(),
+                    client.config.dns_resolver.clone(),
+                )
+                .context(FailureKind::Tls)?;
+                let (v6only, access) = (tunnel.v6only, tunnel.access.clone());
+                spawn_tunnel! {
+                    let (host, port) = to_host_port(tunnel.local);
+                    let remote = RemoteAddr {
+                        protocol: LocalProtocol::ReverseTcp {
+                            resume: None,
+                            idle_timeout: None,
+                            v6only,
+                            access,
+                            keepalive: None,
+                        },
+                        host,
+                        port,
+                    };
+                    if let Err(err) = client.run_reverse_tunnel(remote, dns_connector).await {
+                        error!("{:?}", err);
+                    }
+                }
+            }
             LocalProtocol::Stdio { .. }
+            | LocalProtocol::StdioUdp { .. }
             | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::Tcp { .. }
             | LocalProtocol::Udp { .. }
             | LocalProtocol::Socks5 { .. }
             | LocalProtocol::HttpProxy { .. } => {}
-            LocalProtocol::Unix { .. } => {
+            LocalProtocol::Unix { .. } | LocalProtocol::Vsock { .. } | LocalProtocol::Sctp | LocalProtocol::Mux => {
                 panic!("Invalid protocol for reverse tunnel");
             }
         }
     }
 
-    for tunnel in local_to_remote.into_iter() {
-        let client = client.clone();
+    macro_rules! bind_listener {
+        ($tunnel:ident, $listener:expr) => {
+            match ($listener.context(FailureKind::Bind), failed_listeners.as_deref_mut()) {
+                (Ok(listener), _) => listener,
+                (Err(err), Some(failed_listeners)) => {
+                    failed_listeners.push(($tunnel.clone(), err));
+                    continue;
+                }
+                (Err(err), None) => return Err(err),
+            }
+        };
+    }
+
+    // stdio tunnels use stdout, so the announcements must not get mixed with their data
+    let announce_to_stderr = local_to_remote.iter().any(|tunnel| {
+        matches!(
+            tunnel.local_protocol,
+            LocalProtocol::Stdio { .. } | LocalProtocol::StdioUdp { .. }
+        )
+    });
+    for tunnel in local_to_remote.into_iter().flat_map(LocalToRemote::split_port_range) {
+        let client = client
+            .clone()
+            .with_label(tunnel.label.as_deref())
+            .with_dscp(tunnel.dscp)
+            .with_accept_limits(tunnel.accept_limits);
 
         match &tunnel.local_protocol {
-            LocalProtocol::Tcp { proxy_protocol } => {
-                let server = TcpTunnelListener::new(tunnel.local, tunnel.remote.clone(), *proxy_protocol).await?;
+            LocalProtocol::Tcp {
+                proxy_protocol,
+                resume,
+                idle_timeout,
+                mirror,
+                balancing,
+                keepalive,
+            } => {
+                let server = bind_listener!(
+                    tunnel,
+                    TcpTunnelListener::new(
+                        tunnel.local,
+                        tunnel.v6only,
+                        tunnel.remote.clone(),
+                        *proxy_protocol,
+                        *resume,
+                        *idle_timeout,
+                        mirror.clone(),
+                        balancing.clone(),
+                        *keepalive,
+                        tunnel.access.clone(),
+                    )
+                    .await
+                );
+                if tunnel.local.port() == 0 {
+                    announce_listen_addr("tcp", server.local_addr()?, &tunnel.remote, announce_to_stderr);
+                }
                 spawn_tunnel! {
                     if let Err(err) = client.run_tunnel(server).await {
                         error!("{:?}", err);
@@ -341,7 +922,7 @@ async fn create_client_tunnels(
             #[cfg(target_os = "linux")]
             LocalProtocol::TProxyTcp => {
                 use crate::tunnel::listeners::TproxyTcpTunnelListener;
-                let server = TproxyTcpTunnelListener::new(tunnel.local, false).await?;
+                let server = bind_listener!(tunnel, TproxyTcpTunnelListener::new(tunnel.local, false).await);
 
                 spawn_tunnel! {
                     if let Err(err) = client.run_tunnel(server).await {
@@ -350,9 +931,26 @@ async fn create_client_tunnels(
                 }
             }
             #[cfg(unix)]
-            LocalProtocol::Unix { path, proxy_protocol } => {
+            LocalProtocol::Unix {
+                path,
+                proxy_protocol,
+                allowed_uids,
+                permissions,
+                lifecycle,
+            } => {
                 use crate::tunnel::listeners::UnixTunnelListener;
-                let server = UnixTunnelListener::new(path, tunnel.remote.clone(), *proxy_protocol).await?;
+                let server = bind_listener!(
+                    tunnel,
+                    UnixTunnelListener::new(
+                        path,
+                        tunnel.remote.clone(),
+                        *proxy_protocol,
+                        allowed_uids.clone(),
+                        permissions,
+                        *lifecycle,
+                    )
+                    .await
+                );
                 spawn_tunnel! {
                     if let Err(err) = client.run_tunnel(server).await {
                         error!("{:?}", err);
@@ -363,11 +961,39 @@ async fn create_client_tunnels(
             LocalProtocol::Unix { .. } => {
                 panic!("Unix socket is not available for non Unix platform")
             }
+            #[cfg(target_os = "linux")]
+            LocalProtocol::Sctp => {
+                use crate::tunnel::listeners::SctpTunnelListener;
+                let server = bind_listener!(tunnel, SctpTunnelListener::new(tunnel.local, tunnel.remote.clone()).await);
+                spawn_tunnel! {
+                    if let Err(err) = client.run_tunnel(server).await {
+                        error!("{:?}", err);
+                    }
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            LocalProtocol::Sctp => {
+                panic!("SCTP is only available on Linux")
+            }
+            #[cfg(all(target_os = "linux", feature = "vsock"))]
+            LocalProtocol::Vsock { cid, port } => {
+                use crate::tunnel::listeners::VsockTunnelListener;
+                let server = bind_listener!(tunnel, VsockTunnelListener::new(*cid, *port, tunnel.remote.clone()));
+                spawn_tunnel! {
+                    if let Err(err) = client.run_tunnel(server).await {
+                        error!("{:?}", err);
+                    }
+                }
+            }
+            #[cfg(not(all(target_os = "linux", feature = "vsock")))]
+            LocalProtocol::Vsock { .. } => {
+                panic!("Vsock is only available on Linux, when wstunnel is built with the vsock feature")
+            }
 
             #[cfg(target_os = "linux")]
             LocalProtocol::TProxyUdp { timeout } => {
                 use crate::tunnel::listeners::new_tproxy_udp;
-                let server = new_tproxy_udp(tunnel.local, *timeout).await?;
+                let server = bind_listener!(tunnel, new_tproxy_udp(tunnel.local, *timeout).await);
                 spawn_tunnel! {
                     if let Err(err) = client.run_tunnel(server).await {
                         error!("{:?}", err);
@@ -379,15 +1005,56 @@ async fn create_client_tunnels(
                 panic!("Transparent proxy is not available for non Linux platform")
             }
             LocalProtocol::Udp { timeout } => {
-                let server = UdpTunnelListener::new(tunnel.local, tunnel.remote.clone(), *timeout).await?;
+                let server = bind_listener!(
+                    tunnel,
+                    UdpTunnelListener::new(
+                        tunnel.local,
+                        tunnel.v6only,
+                        tunnel.remote.clone(),
+                        *timeout,
+                        None,
+                        UdpFlowEviction::DropNew,
+                    )
+                    .await
+                );
                 spawn_tunnel! {
                     if let Err(err) = client.run_tunnel(server).await {
                         error!("{:?}", err);
                     }
                 }
             }
-            LocalProtocol::Socks5 { timeout, credentials } => {
-                let server = Socks5TunnelListener::new(tunnel.local, *timeout, credentials.clone()).await?;
+            LocalProtocol::Socks5 {
+                timeout,
+                credentials,
+                resume,
+                resolve,
+            } => {
+                let resolver = match resolve {
+                    Socks5Resolve::Remote => Socks5Resolver::Remote,
+                    Socks5Resolve::Local => Socks5Resolver::Local(client.config.dns_resolver.clone()),
+                    Socks5Resolve::FakeIp(dns_bind) => {
+                        let (dns_bind, fake_ips) = (*dns_bind, Arc::new(FakeIps::default()));
+                        let resolver = Socks5Resolver::FakeIp(fake_ips.clone());
+                        spawn_tunnel! {
+                            if let Err(err) = run_fake_dns_server(dns_bind, fake_ips).await {
+                                error!("{:?}", err);
+                            }
+                        }
+                        resolver
+                    }
+                };
+                let server = bind_listener!(
+                    tunnel,
+                    Socks5TunnelListener::new(
+                        tunnel.local,
+                        *timeout,
+                        credentials.clone(),
+                        *resume,
+                        resolver,
+                        tunnel.access.clone(),
+                    )
+                    .await
+                );
                 spawn_tunnel! {
                     if let Err(err) = client.run_tunnel(server).await {
                         error!("{:?}", err);
@@ -398,9 +1065,25 @@ async fn create_client_tunnels(
                 timeout,
                 credentials,
                 proxy_protocol,
+                resume,
+                connect_ports,
+                path_headers,
             } => {
-                let server =
-                    HttpProxyTunnelListener::new(tunnel.local, *timeout, credentials.clone(), *proxy_protocol).await?;
+                let server = bind_listener!(
+                    tunnel,
+                    HttpProxyTunnelListener::new(
+                        tunnel.local,
+                        tunnel.v6only,
+                        *timeout,
+                        credentials.clone(),
+                        *proxy_protocol,
+                        *resume,
+                        connect_ports.clone(),
+                        *path_headers,
+                        tunnel.access.clone(),
+                    )
+                    .await
+                );
                 spawn_tunnel! {
                     if let Err(err) = client.run_tunnel(server).await {
                         error!("{:?}", err);
@@ -409,32 +1092,79 @@ async fn create_client_tunnels(
             }
 
             LocalProtocol::Stdio { proxy_protocol } => {
-                let (server, mut handle) = new_stdio_listener(tunnel.remote.clone(), *proxy_protocol).await?;
+                let (server, handle) = new_stdio_listener(tunnel.remote.clone(), *proxy_protocol).await?;
                 if let Err(err) = client.run_tunnel(server).await {
                     error!("{:?}", err);
                 }
-
-                // We need to wait for either a ctrl+c of that the stdio tunnel is closed
-                // to force exit the program
-                select! {
-                   _ = handle.closed() => {},
-                   _ = tokio::signal::ctrl_c() => {}
+                exit_once_stdio_closed(handle).await;
+            }
+            LocalProtocol::StdioUdp { timeout } => {
+                let (server, handle) = new_stdio_udp_listener(tunnel.remote.clone(), *timeout).await?;
+                if let Err(err) = client.run_tunnel(server).await {
+                    error!("{:?}", err);
                 }
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                std::process::exit(0);
+                exit_once_stdio_closed(handle).await;
             }
-            LocalProtocol::ReverseTcp => {}
+            LocalProtocol::ReverseTcp { .. } => {}
             LocalProtocol::ReverseUdp { .. } => {}
             LocalProtocol::ReverseSocks5 { .. } => {}
             LocalProtocol::ReverseUnix { .. } => {}
             LocalProtocol::ReverseHttpProxy { .. } => {}
+            LocalProtocol::ReverseHttpIngress { .. } => {}
+            LocalProtocol::ReverseEncryptedDns { .. } => {}
+            LocalProtocol::Mux => panic!("Invalid protocol for local tunnel"),
         }
     }
 
     Ok(tunnels)
 }
 
+pub async fn run_oidc_login(args: OidcLogin) -> anyhow::Result<()> {
+    let cache_path = match args.oidc_token_cache {
+        Some(path) => path,
+        None => oidc::default_token_cache_path()?,
+    };
+    let http_cfg = HttpClientConfig {
+        so_mark: SoMark::new(None),
+        timeout: Duration::from_secs(30),
+        dns_resolver: DnsResolver::System,
+    };
+
+    oidc::login(
+        &args.oidc_issuer,
+        &args.oidc_client_id,
+        &args.oidc_scope,
+        &cache_path,
+        &http_cfg,
+    )
+    .await
+    .context(FailureKind::Auth)
+}
+
 pub async fn run_server(args: Server, executor: impl TokioExecutor) -> anyhow::Result<()> {
+    if args.dump_config {
+        config::secret::show_secrets(args.show_secrets);
+        println!("{args:#?}");
+        return Ok(());
+    }
+
+    if args.check_restrictions {
+        let path = args
+            .restrict_config
+            .as_deref()
+            .context("--check-restrictions requires --restrict-config")
+            .context(FailureKind::Config)?;
+        let rules = RestrictionsRules::from_config_file(path)
+            .with_context(|| format!("Invalid restriction config file {}", path.display()))
+            .context(FailureKind::Config)?;
+        for restriction in &rules.restrictions {
+            let description = restriction.description.as_deref().unwrap_or("");
+            println!("{}: {} allow rules. {description}", restriction.name, restriction.allow.len());
+        }
+        println!("Restriction config file {} is valid", path.display());
+        return Ok(());
+    }
+
     let (tx, rx) = oneshot::channel();
     let exec = executor.ref_clone();
     executor.spawn(async move {
@@ -446,24 +1176,49 @@ pub async fn run_server(args: Server, executor: impl TokioExecutor) -> anyhow::R
 }
 
 async fn run_server_impl(args: Server, executor: impl TokioExecutorRef) -> anyhow::Result<()> {
+    if let Some(admin_listen) = args.admin_listen {
+        executor.spawn(run_admin_server(admin_listen));
+    }
+    #[cfg(unix)]
+    executor.spawn(stats::log_status_on_signal());
+    if let Some(max_memory_buffers) = args.max_memory_buffers {
+        BUFFER_POOL.set_max_bytes(max_memory_buffers);
+    }
+    hooks::set_tunnel_hooks(
+        Side::Server,
+        TunnelHooks {
+            on_connect: args.on_tunnel_connect.clone(),
+            on_close: args.on_tunnel_close.clone(),
+        },
+    );
+
     let tls_config = if args.remote_addr.scheme() == "wss" {
         let tls_certificate = if let Some(cert_path) = &args.tls_certificate {
-            tls::load_certificates_from_pem(cert_path).expect("Cannot load tls certificate")
+            tls::load_certificates_from_pem(cert_path)
+                .context("Cannot load tls certificate")
+                .context(FailureKind::Tls)?
         } else {
             embedded_certificate::TLS_CERTIFICATE.0.clone()
         };
 
         let tls_key = if let Some(key_path) = &args.tls_private_key {
-            tls::load_private_key_from_file(key_path).expect("Cannot load tls private key")
+            tls::load_private_key_from_file(key_path)
+                .context("Cannot load tls private key")
+                .context(FailureKind::Tls)?
         } else {
             embedded_certificate::TLS_CERTIFICATE.1.clone_key()
         };
 
-        let tls_client_ca_certificates = args.tls_client_ca_certs.as_ref().map(|tls_client_ca| {
-            Mutex::new(
-                tls::load_certificates_from_pem(tls_client_ca).expect("Cannot load client CA certificate (mTLS)"),
-            )
-        });
+        let tls_client_ca_certificates = args
+            .tls_client_ca_certs
+            .as_ref()
+            .map(|tls_client_ca| {
+                tls::load_certificates_from_pem(tls_client_ca)
+                    .context("Cannot load client CA certificate (mTLS)")
+                    .context(FailureKind::Tls)
+            })
+            .transpose()?
+            .map(Mutex::new);
 
         Some(TlsServerConfig {
             tls_certificate: Mutex::new(tls_certificate),
@@ -472,13 +1227,16 @@ async fn run_server_impl(args: Server, executor: impl TokioExecutorRef) -> anyho
             tls_certificate_path: args.tls_certificate,
             tls_key_path: args.tls_private_key,
             tls_client_ca_certs_path: args.tls_client_ca_certs,
+            ktls: args.tls_ktls,
         })
     } else {
         None
     };
 
     let restrictions = if let Some(path) = &args.restrict_config {
-        RestrictionsRules::from_config_file(path).expect("Cannot parse restriction file")
+        RestrictionsRules::from_config_file(path)
+            .context("Cannot parse restriction file")
+            .context(FailureKind::Config)?
     } else {
         let restrict_to: Vec<(String, u16)> = args
             .restrict_to
@@ -486,22 +1244,117 @@ async fn run_server_impl(args: Server, executor: impl TokioExecutorRef) -> anyho
             .unwrap_or(&[])
             .iter()
             .map(|x| {
-                let (host, port) = x.rsplit_once(':').expect("Invalid restrict-to format");
-                (
-                    host.trim_matches(['[', ']']).to_string(),
-                    port.parse::<u16>().expect("Invalid restrict-to port format"),
-                )
+                let (host, port) = x
+                    .rsplit_once(':')
+                    .with_context(|| format!("Invalid restrict-to format {x}"))?;
+                let port = port
+                    .parse::<u16>()
+                    .with_context(|| format!("Invalid restrict-to port format {x}"))?;
+                Ok((host.trim_matches(['[', ']']).to_string(), port))
             })
-            .collect();
+            .collect::<anyhow::Result<_>>()
+            .context(FailureKind::Config)?;
 
         RestrictionsRules::from_path_prefix(
             args.restrict_http_upgrade_path_prefix.as_deref().unwrap_or(&[]),
             &restrict_to,
         )
-        .expect("Cannot convert restriction rules from path-prefix and restric-to")
+        .context("Cannot convert restriction rules from path-prefix and restric-to")
+        .context(FailureKind::Config)?
     };
 
-    let http_proxy = mk_http_proxy(args.http_proxy, args.http_proxy_login, args.http_proxy_password)?;
+    let http_proxy =
+        mk_http_proxy(args.http_proxy, args.http_proxy_login, args.http_proxy_password).context(FailureKind::Config)?;
+    let dns_resolver = DnsResolver::new_from_urls(
+        &args.dns_resolver,
+        None,
+        SoMark::new(args.socket_so_mark),
+        !args.dns_resolver_prefer_ipv4,
+    )
+    .context("Cannot create DNS resolver")
+    .context(FailureKind::Config)?;
+    let oidc = match (args.oidc_issuer, args.oidc_audience) {
+        (None, _) => None,
+        (Some(issuer), Some(audience)) => {
+            let http_cfg = HttpClientConfig {
+                so_mark: SoMark::new(args.socket_so_mark),
+                timeout: Duration::from_secs(10),
+                dns_resolver: dns_resolver.clone(),
+            };
+            Some(OidcValidator::new(issuer, audience, http_cfg))
+        }
+        (Some(_), None) => {
+            return Err(anyhow!("--oidc-issuer needs --oidc-audience").context(FailureKind::Config));
+        }
+    };
+    #[cfg(feature = "dns-transport")]
+    let dns_transport = match (args.dns_transport_listen, args.dns_transport_domain) {
+        (Some(bind), Some(domain)) => {
+            let mut domain = hickory_resolver::proto::rr::Name::from_ascii(&domain)
+                .with_context(|| format!("invalid dns transport domain {domain}"))
+                .context(FailureKind::Config)?;
+            domain.set_fqdn(true);
+            Some(DnsTransportConfig { bind, domain })
+        }
+        (Some(_), None) => {
+            return Err(
+                anyhow!("--dns-transport-domain is required to enable the dns transport").context(FailureKind::Config)
+            );
+        }
+        (None, _) => None,
+    };
+    let reject_response = if args.reject_status.is_some()
+        || args.reject_body.is_some()
+        || !args.reject_header.is_empty()
+        || args.reject_redirect.is_some()
+    {
+        let redirect = args
+            .reject_redirect
+            .map(|location| HeaderValue::from_str(&location))
+            .transpose()
+            .context("invalid --reject-redirect location")
+            .context(FailureKind::Config)?;
+        let body = match &args.reject_body {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("cannot read the reject body from {}", path.display()))
+                .context(FailureKind::Config)?,
+            None => String::new(),
+        };
+        Some(RejectResponse {
+            status: args.reject_status.unwrap_or(if redirect.is_some() {
+                StatusCode::FOUND
+            } else {
+                StatusCode::NOT_FOUND
+            }),
+            headers: args.reject_header,
+            body,
+            redirect,
+        })
+    } else {
+        None
+    };
+    let cluster = match (args.cluster_peer.is_empty(), args.cluster_advertise, args.cluster_secret) {
+        (true, _, _) => None,
+        (false, Some(advertise), Some(secret)) => Some(ClusterConfig {
+            advertise,
+            peers: args.cluster_peer,
+            secret,
+        }),
+        (false, _, _) => {
+            return Err(
+                anyhow!("--cluster-peer needs --cluster-advertise and --cluster-secret").context(FailureKind::Config)
+            );
+        }
+    };
+    if args.tcp_fastopen && !protocols::tcp::TCP_FASTOPEN_LISTENER_SUPPORTED {
+        return Err(
+            anyhow!("--tcp-fastopen is only supported on linux, macos and freebsd").context(FailureKind::Config)
+        );
+    }
+    let tcp_defer_accept = args.tcp_defer_accept.filter(|d| !d.is_zero());
+    if tcp_defer_accept.is_some() && !protocols::tcp::TCP_DEFER_ACCEPT_SUPPORTED {
+        return Err(anyhow!("--tcp-defer-accept is only supported on linux").context(FailureKind::Config));
+    }
     let server_config = WsServerConfig {
         socket_so_mark: SoMark::new(args.socket_so_mark),
         bind: args.remote_addr.socket_addrs(|| Some(8080))?[0],
@@ -511,17 +1364,73 @@ async fn run_server_impl(args: Server, executor: impl TokioExecutorRef) -> anyho
             .filter(|d| d.as_secs() > 0),
         timeout_connect: Duration::from_secs(10),
         websocket_mask_frame: args.websocket_mask_frame,
+        websocket_max_frame_size: args.websocket_max_frame_size,
+        websocket_close_timeout: args.websocket_close_timeout,
+        websocket_close_codes: args.websocket_close_code.iter().copied().collect(),
+        max_inflight_per_tunnel: args.max_inflight_per_tunnel,
+        pcap_dir: args.pcap_dir,
+        tcp_fastopen: args.tcp_fastopen,
+        dscp: args.dscp,
+        source_bind: SourceBind::new(args.bind_interface.clone(), args.bind_address),
+        tcp_defer_accept,
+        accept_shards: args.accept_shards,
+        auth_hook: args.auth_hook,
+        auth_hook_timeout: args.auth_hook_timeout,
+        oidc,
+        psk: args.psk.map(|psk| PreSharedKey::new(psk.expose().as_bytes())),
+        noise: args.noise_private_key.map(|private_key| NoiseServerConfig {
+            private_key,
+            client_public_keys: args.noise_client_public_key,
+        }),
+        #[cfg(feature = "dns-transport")]
+        dns_transport,
+        #[cfg(feature = "icmp-transport")]
+        icmp_transport: args.icmp_transport_listen.map(|bind| IcmpTransportConfig { bind }),
+        #[cfg(feature = "ssh-transport")]
+        ssh_transport: if args.ssh_transport {
+            Some(SshTransportConfig::new(args.ssh_host_key.as_deref())?)
+        } else {
+            None
+        },
         tls: tls_config,
-        dns_resolver: DnsResolver::new_from_urls(
-            &args.dns_resolver,
-            None,
-            SoMark::new(args.socket_so_mark),
-            !args.dns_resolver_prefer_ipv4,
-        )
-        .expect("Cannot create DNS resolver"),
+        protocol_handlers: args.protocol_handler,
+        tls_sni_passthrough: args.tls_sni_passthrough.map(|backend| SniPassthrough {
+            server_names: args
+                .tls_server_name
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            backend,
+        }),
+        dns_resolver,
         restriction_config: args.restrict_config,
         http_proxy,
         remote_server_idle_timeout: args.remote_to_local_server_idle_timeout,
+        tunnel_resume_max_timeout: args.tunnel_resume_max_timeout,
+        tunnel_idle_timeout: args.tunnel_idle_timeout,
+        metrics_listen: args.metrics_listen,
+        max_clients: args.max_clients,
+        max_tunnels_per_client: args.max_tunnels_per_client,
+        require_min_client_version: args.require_min_client_version,
+        http_ingress: args.http_ingress_domain.map(|domain| HttpIngressDomain {
+            domain: domain.to_ascii_lowercase(),
+            reserved: args.http_ingress_reserve,
+        }),
+        traffic_obfuscation: (args.traffic_padding.is_some() || args.traffic_jitter.is_some()).then(|| {
+            TrafficObfuscation {
+                padding_percent: args.traffic_padding.unwrap_or(0),
+                max_jitter: args.traffic_jitter.unwrap_or_default(),
+            }
+        }),
+        reject_response,
+        standby_file: args.standby_file,
+        cluster,
+        sticky_session: args.sticky_session_secret.map(|secret| {
+            let instance = args
+                .sticky_session_instance
+                .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+            StickySession::new(&instance, secret.as_bytes(), Duration::from_secs(24 * 3600))
+        }),
     };
     let server = WsServer::new(server_config, executor);
 
@@ -534,12 +1443,18 @@ async fn run_server_impl(args: Server, executor: impl TokioExecutorRef) -> anyho
     server.serve(restrictions).await
 }
 
+async fn run_admin_server(bind: SocketAddr) {
+    if let Err(err) = stats::run_admin_server(bind).await {
+        error!("Admin server stopped: {err:?}");
+    }
+}
+
 fn mk_http_proxy(
-    http_proxy: Option<String>,
+    http_proxy: Option<Secret<String>>,
     proxy_login: Option<String>,
-    proxy_password: Option<String>,
+    proxy_password: Option<Secret<String>>,
 ) -> anyhow::Result<Option<Url>> {
-    let Some(proxy) = http_proxy else {
+    let Some(proxy) = http_proxy.map(Secret::into_inner) else {
         return Ok(None);
     };
 
@@ -557,7 +1472,7 @@ fn mk_http_proxy(
 
     if let Some(password) = proxy_password {
         proxy
-            .set_password(Some(password.as_str()))
+            .set_password(Some(password.expose().as_str()))
             .map_err(|_| anyhow!("Cannot set http proxy password"))?;
     }
 
@@ -0,0 +1,379 @@
+//! Counters of wstunnel, exposed by the server in the prometheus text format on `--metrics-listen`
+use crate::health::HEALTH;
+use anyhow::Context;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+pub struct Metrics {
+    /// Tunnels closed because no data went through them for their idle timeout
+    pub tunnels_reaped_idle: AtomicU64,
+    /// Tunnels closed because their peer stopped answering (websocket pings, tcp keepalive)
+    pub tunnels_reaped_dead_peer: AtomicU64,
+    /// Bytes read from the local side of the tunnels, waiting for the flow control of the transport to be sent
+    pub tunnel_buffered_bytes: AtomicU64,
+    /// Capacity of the copy buffers of the tunnels, including the ones kept in the pool for the next tunnels
+    pub tunnel_buffer_bytes: AtomicU64,
+    /// Copy buffers given to the tunnels, newly allocated or reused from the pool
+    pub tunnel_buffers_allocated: AtomicU64,
+    pub tunnel_buffers_reused: AtomicU64,
+    /// Copy buffers not grown to keep up with the throughput of their tunnel, because of --max-memory-buffers
+    pub tunnel_buffer_grows_denied: AtomicU64,
+    /// Packets of new UDP peers dropped because their server already had its max number of flows
+    pub udp_packets_dropped: AtomicU64,
+    /// Tunnels accepted by the server, by the label their client gave them
+    pub tunnels_opened_by_label: Mutex<BTreeMap<String, u64>>,
+    /// Tunnels accepted by the server, by the device id of their client
+    pub tunnels_opened_by_device: Mutex<BTreeMap<String, u64>>,
+    /// TLS handshakes done, and the time spent in them, by negotiated cipher suite
+    pub tls_handshakes_by_cipher_suite: Mutex<BTreeMap<String, TlsHandshakes>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TlsHandshakes {
+    pub count: u64,
+    pub duration: Duration,
+}
+
+/// Labels and device ids are chosen by the clients, past this number of distinct ones they are all counted as `OVERFLOW_LABEL`
+const MAX_LABELS: usize = 1024;
+const OVERFLOW_LABEL: &str = "_overflow";
+
+pub static METRICS: Metrics = Metrics {
+    tunnels_reaped_idle: AtomicU64::new(0),
+    tunnels_reaped_dead_peer: AtomicU64::new(0),
+    tunnel_buffered_bytes: AtomicU64::new(0),
+    tunnel_buffer_bytes: AtomicU64::new(0),
+    tunnel_buffers_allocated: AtomicU64::new(0),
+    tunnel_buffers_reused: AtomicU64::new(0),
+    tunnel_buffer_grows_denied: AtomicU64::new(0),
+    udp_packets_dropped: AtomicU64::new(0),
+    tunnels_opened_by_label: Mutex::new(BTreeMap::new()),
+    tunnels_opened_by_device: Mutex::new(BTreeMap::new()),
+    tls_handshakes_by_cipher_suite: Mutex::new(BTreeMap::new()),
+};
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(gauge: &AtomicU64, value: u64) {
+        gauge.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn sub(gauge: &AtomicU64, value: u64) {
+        gauge.fetch_sub(value, Ordering::Relaxed);
+    }
+
+    pub fn inc_label(&self, label: &str) {
+        Self::inc_bounded(&self.tunnels_opened_by_label, label);
+    }
+
+    pub fn inc_device(&self, device: &str) {
+        Self::inc_bounded(&self.tunnels_opened_by_device, device);
+    }
+
+    fn inc_bounded(counters: &Mutex<BTreeMap<String, u64>>, label: &str) {
+        let mut labels = counters.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(count) = labels.get_mut(label) {
+            *count += 1;
+        } else if labels.len() < MAX_LABELS {
+            labels.insert(label.to_string(), 1);
+        } else {
+            *labels.entry(OVERFLOW_LABEL.to_string()).or_default() += 1;
+        }
+    }
+
+    /// The cipher suites are the ones of the crypto provider, their number is bounded
+    pub fn tls_handshake_done(&self, cipher_suite: &str, duration: Duration) {
+        let mut suites = self
+            .tls_handshakes_by_cipher_suite
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let handshakes = suites.entry(cipher_suite.to_string()).or_default();
+        handshakes.count += 1;
+        handshakes.duration += duration;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP wstunnel_tunnels_reaped_total Tunnels closed by the server without being closed by their ends"
+        );
+        let _ = writeln!(out, "# TYPE wstunnel_tunnels_reaped_total counter");
+        for (reason, value) in [
+            ("idle", &self.tunnels_reaped_idle),
+            ("dead_peer", &self.tunnels_reaped_dead_peer),
+        ] {
+            let _ = writeln!(
+                out,
+                "wstunnel_tunnels_reaped_total{{reason=\"{reason}\"}} {}",
+                value.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP wstunnel_tunnel_buffered_bytes Bytes read from the local side of tunnels and not yet sent to their peer"
+        );
+        let _ = writeln!(out, "# TYPE wstunnel_tunnel_buffered_bytes gauge");
+        let _ = writeln!(
+            out,
+            "wstunnel_tunnel_buffered_bytes {}",
+            self.tunnel_buffered_bytes.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP wstunnel_tunnel_buffer_bytes Memory of the copy buffers of tunnels, including the ones kept for reuse"
+        );
+        let _ = writeln!(out, "# TYPE wstunnel_tunnel_buffer_bytes gauge");
+        let _ = writeln!(
+            out,
+            "wstunnel_tunnel_buffer_bytes {}",
+            self.tunnel_buffer_bytes.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP wstunnel_tunnel_buffers_total Copy buffers given to tunnels, by whether they were reused from the pool"
+        );
+        let _ = writeln!(out, "# TYPE wstunnel_tunnel_buffers_total counter");
+        for (source, value) in [
+            ("allocated", &self.tunnel_buffers_allocated),
+            ("reused", &self.tunnel_buffers_reused),
+        ] {
+            let _ = writeln!(
+                out,
+                "wstunnel_tunnel_buffers_total{{source=\"{source}\"}} {}",
+                value.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP wstunnel_tunnel_buffer_grows_denied_total Copy buffers not grown because of --max-memory-buffers"
+        );
+        let _ = writeln!(out, "# TYPE wstunnel_tunnel_buffer_grows_denied_total counter");
+        let _ = writeln!(
+            out,
+            "wstunnel_tunnel_buffer_grows_denied_total {}",
+            self.tunnel_buffer_grows_denied.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP wstunnel_udp_packets_dropped_total Packets of new UDP peers dropped because max_flows was reached"
+        );
+        let _ = writeln!(out, "# TYPE wstunnel_udp_packets_dropped_total counter");
+        let _ = writeln!(
+            out,
+            "wstunnel_udp_packets_dropped_total {}",
+            self.udp_packets_dropped.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP wstunnel_tunnels_opened_total Tunnels accepted by the server, by label given by their client"
+        );
+        let _ = writeln!(out, "# TYPE wstunnel_tunnels_opened_total counter");
+        let labels = self
+            .tunnels_opened_by_label
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        for (label, value) in labels.iter() {
+            let _ = writeln!(out, "wstunnel_tunnels_opened_total{{label=\"{label}\"}} {value}");
+        }
+        drop(labels);
+        let _ = writeln!(
+            out,
+            "# HELP wstunnel_device_tunnels_opened_total Tunnels accepted by the server, by device id of their client"
+        );
+        let _ = writeln!(out, "# TYPE wstunnel_device_tunnels_opened_total counter");
+        let devices = self
+            .tunnels_opened_by_device
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        for (device, value) in devices.iter() {
+            let _ = writeln!(out, "wstunnel_device_tunnels_opened_total{{device=\"{device}\"}} {value}");
+        }
+        drop(devices);
+        let _ = writeln!(
+            out,
+            "# HELP wstunnel_tls_handshake_seconds Time spent in TLS handshakes, by negotiated cipher suite"
+        );
+        let _ = writeln!(out, "# TYPE wstunnel_tls_handshake_seconds summary");
+        let suites = self
+            .tls_handshakes_by_cipher_suite
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        for (suite, handshakes) in suites.iter() {
+            let _ = writeln!(
+                out,
+                "wstunnel_tls_handshake_seconds_sum{{cipher_suite=\"{suite}\"}} {}",
+                handshakes.duration.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "wstunnel_tls_handshake_seconds_count{{cipher_suite=\"{suite}\"}} {}",
+                handshakes.count
+            );
+        }
+
+        out
+    }
+}
+
+pub async fn run_metrics_server(bind: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind)
+        .await
+        .with_context(|| format!("Cannot bind metrics server on {bind}"))?;
+    info!("Serving metrics on http://{bind}/metrics");
+
+    serve_http(listener, |path| {
+        if path == "/metrics" {
+            Response::builder()
+                .header("content-type", "text/plain; version=0.0.4")
+                .body(Full::new(Bytes::from(METRICS.render())))
+        } else if let Some((status, body)) = HEALTH.probe(path) {
+            probe_response(status, body)
+        } else {
+            not_found()
+        }
+    })
+    .await
+}
+
+pub fn probe_response(status: StatusCode, body: String) -> hyper::http::Result<Response<Full<Bytes>>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain")
+        .body(Full::new(Bytes::from(body)))
+}
+
+pub fn not_found() -> hyper::http::Result<Response<Full<Bytes>>> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Full::new(Bytes::new()))
+}
+
+/// Answer the http requests of the listener, with the response given by `route` for their path
+pub async fn serve_http(
+    listener: TcpListener,
+    route: fn(&str) -> hyper::http::Result<Response<Full<Bytes>>>,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(ret) => ret,
+            Err(err) => {
+                warn!("Error while accepting http connection {:?}", err);
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            let service = service_fn(|req: Request<hyper::body::Incoming>| async move { route(req.uri().path()) });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                warn!("Error while serving http connection {:?}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics {
+            tunnels_reaped_idle: AtomicU64::new(2),
+            tunnels_reaped_dead_peer: AtomicU64::new(0),
+            tunnel_buffered_bytes: AtomicU64::new(4096),
+            tunnel_buffer_bytes: AtomicU64::new(131072),
+            tunnel_buffers_allocated: AtomicU64::new(3),
+            tunnel_buffers_reused: AtomicU64::new(1),
+            tunnel_buffer_grows_denied: AtomicU64::new(0),
+            udp_packets_dropped: AtomicU64::new(7),
+            tunnels_opened_by_label: Mutex::new(BTreeMap::new()),
+            tunnels_opened_by_device: Mutex::new(BTreeMap::new()),
+            tls_handshakes_by_cipher_suite: Mutex::new(BTreeMap::new()),
+        };
+        metrics.inc_label("ci-job-2");
+        metrics.inc_label("ci-job-1");
+        metrics.inc_label("ci-job-2");
+        metrics.inc_device("sensor-42");
+        metrics.tls_handshake_done("TLS13_AES_128_GCM_SHA256", Duration::from_micros(1500));
+        metrics.tls_handshake_done("TLS13_AES_128_GCM_SHA256", Duration::from_micros(500));
+        assert_eq!(
+            metrics.render(),
+            "# HELP wstunnel_tunnels_reaped_total Tunnels closed by the server without being closed by their ends\n\
+             # TYPE wstunnel_tunnels_reaped_total counter\n\
+             wstunnel_tunnels_reaped_total{reason=\"idle\"} 2\n\
+             wstunnel_tunnels_reaped_total{reason=\"dead_peer\"} 0\n\
+             # HELP wstunnel_tunnel_buffered_bytes Bytes read from the local side of tunnels and not yet sent to their peer\n\
+             # TYPE wstunnel_tunnel_buffered_bytes gauge\n\
+             wstunnel_tunnel_buffered_bytes 4096\n\
+             # HELP wstunnel_tunnel_buffer_bytes Memory of the copy buffers of tunnels, including the ones kept for reuse\n\
+             # TYPE wstunnel_tunnel_buffer_bytes gauge\n\
+             wstunnel_tunnel_buffer_bytes 131072\n\
+             # HELP wstunnel_tunnel_buffers_total Copy buffers given to tunnels, by whether they were reused from the pool\n\
+             # TYPE wstunnel_tunnel_buffers_total counter\n\
+             wstunnel_tunnel_buffers_total{source=\"allocated\"} 3\n\
+             wstunnel_tunnel_buffers_total{source=\"reused\"} 1\n\
+             # HELP wstunnel_tunnel_buffer_grows_denied_total Copy buffers not grown because of --max-memory-buffers\n\
+             # TYPE wstunnel_tunnel_buffer_grows_denied_total counter\n\
+             wstunnel_tunnel_buffer_grows_denied_total 0\n\
+             # HELP wstunnel_udp_packets_dropped_total Packets of new UDP peers dropped because max_flows was reached\n\
+             # TYPE wstunnel_udp_packets_dropped_total counter\n\
+             wstunnel_udp_packets_dropped_total 7\n\
+             # HELP wstunnel_tunnels_opened_total Tunnels accepted by the server, by label given by their client\n\
+             # TYPE wstunnel_tunnels_opened_total counter\n\
+             wstunnel_tunnels_opened_total{label=\"ci-job-1\"} 1\n\
+             wstunnel_tunnels_opened_total{label=\"ci-job-2\"} 2\n\
+             # HELP wstunnel_device_tunnels_opened_total Tunnels accepted by the server, by device id of their client\n\
+             # TYPE wstunnel_device_tunnels_opened_total counter\n\
+             wstunnel_device_tunnels_opened_total{device=\"sensor-42\"} 1\n\
+             # HELP wstunnel_tls_handshake_seconds Time spent in TLS handshakes, by negotiated cipher suite\n\
+             # TYPE wstunnel_tls_handshake_seconds summary\n\
+             wstunnel_tls_handshake_seconds_sum{cipher_suite=\"TLS13_AES_128_GCM_SHA256\"} 0.002\n\
+             wstunnel_tls_handshake_seconds_count{cipher_suite=\"TLS13_AES_128_GCM_SHA256\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn test_label_cardinality_is_bounded() {
+        let metrics = Metrics {
+            tunnels_reaped_idle: AtomicU64::new(0),
+            tunnels_reaped_dead_peer: AtomicU64::new(0),
+            tunnel_buffered_bytes: AtomicU64::new(0),
+            tunnel_buffer_bytes: AtomicU64::new(0),
+            tunnel_buffers_allocated: AtomicU64::new(0),
+            tunnel_buffers_reused: AtomicU64::new(0),
+            tunnel_buffer_grows_denied: AtomicU64::new(0),
+            udp_packets_dropped: AtomicU64::new(0),
+            tunnels_opened_by_label: Mutex::new(BTreeMap::new()),
+            tunnels_opened_by_device: Mutex::new(BTreeMap::new()),
+            tls_handshakes_by_cipher_suite: Mutex::new(BTreeMap::new()),
+        };
+        for i in 0..MAX_LABELS + 10 {
+            metrics.inc_label(&format!("job-{i}"));
+        }
+        metrics.inc_label("job-0");
+
+        let labels = metrics.tunnels_opened_by_label.lock().unwrap();
+        assert_eq!(labels.len(), MAX_LABELS + 1);
+        assert_eq!(labels.get("job-0"), Some(&2));
+        assert_eq!(labels.get(OVERFLOW_LABEL), Some(&10));
+    }
+}
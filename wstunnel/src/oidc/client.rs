@@ -0,0 +1,349 @@
+use super::discover;
+use crate::protocols::http_client;
+use crate::protocols::http_client::HttpClientConfig;
+use anyhow::{Context, anyhow};
+use base64::Engine;
+use bytes::Bytes;
+use hyper::Method;
+use hyper::header::{ACCEPT, CONTENT_TYPE, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+use url::Url;
+
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Refresh the token a bit before it expires, to account for clock skew and in-flight requests
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// Tokens already read from their cache file, so connecting only hits the disk once they expire
+static TOKENS: Mutex<BTreeMap<PathBuf, CachedToken>> = Mutex::const_new(BTreeMap::new());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedToken {
+    issuer: Url,
+    client_id: String,
+    token: String,
+    refresh_token: Option<String>,
+    expires_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Location of the token cache when none is specified: $XDG_CACHE_HOME/wstunnel/oidc_token.json
+pub fn default_token_cache_path() -> anyhow::Result<PathBuf> {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .ok_or_else(|| anyhow!("cannot find a cache directory to store the oidc token, please specify one"))?;
+
+    Ok(cache_dir.join("wstunnel").join("oidc_token.json"))
+}
+
+/// Run the OAuth2 device authorization flow against the issuer, and store the obtained token in the cache
+pub async fn login(
+    issuer: &Url,
+    client_id: &str,
+    scope: &str,
+    cache_path: &Path,
+    http_cfg: &HttpClientConfig,
+) -> anyhow::Result<()> {
+    let metadata = discover(issuer, http_cfg).await?;
+    let Some(device_endpoint) = &metadata.device_authorization_endpoint else {
+        return Err(anyhow!("oidc provider {issuer} does not support the device authorization flow"));
+    };
+
+    let (status, body) = post_form(device_endpoint, &[("client_id", client_id), ("scope", scope)], http_cfg).await?;
+    if !status.is_success() {
+        return Err(token_error(device_endpoint, &body));
+    }
+    let device: DeviceAuthorization = serde_json::from_slice(&body)
+        .with_context(|| format!("invalid device authorization response from {device_endpoint}"))?;
+
+    match &device.verification_uri_complete {
+        Some(uri) => println!("To login, open {uri} and check that the code is {}", device.user_code),
+        None => println!(
+            "To login, open {} and enter the code {}",
+            device.verification_uri, device.user_code
+        ),
+    }
+
+    let mut interval = Duration::from_secs(device.interval.unwrap_or(5));
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(device.expires_in);
+    let params = [
+        ("grant_type", DEVICE_CODE_GRANT_TYPE),
+        ("device_code", device.device_code.as_str()),
+        ("client_id", client_id),
+    ];
+    let token = loop {
+        if tokio::time::Instant::now() >= deadline {
+            return Err(anyhow!("device code expired before the login was completed"));
+        }
+        tokio::time::sleep(interval).await;
+
+        let (status, body) = post_form(&metadata.token_endpoint, &params, http_cfg).await?;
+        if status.is_success() {
+            break serde_json::from_slice::<TokenResponse>(&body)
+                .with_context(|| format!("invalid token response from {}", metadata.token_endpoint))?;
+        }
+
+        let err: TokenError =
+            serde_json::from_slice(&body).map_err(|_| token_error(&metadata.token_endpoint, &body))?;
+        match err.error.as_str() {
+            "authorization_pending" => continue,
+            "slow_down" => interval += Duration::from_secs(5),
+            "access_denied" => return Err(anyhow!("login was denied")),
+            "expired_token" => return Err(anyhow!("device code expired before the login was completed")),
+            _ => return Err(token_error(&metadata.token_endpoint, &body)),
+        }
+    };
+
+    let token = cached_token(issuer.clone(), client_id.to_string(), token, None);
+    write_cache(cache_path, &token).await?;
+    TOKENS.lock().await.remove(cache_path);
+    println!("Login successful, token stored in {}", cache_path.display());
+    Ok(())
+}
+
+/// Returns the Authorization header to use for the upgrade request, refreshing the cached token if it expired
+pub async fn bearer_from_cache(cache_path: &Path, http_cfg: &HttpClientConfig) -> anyhow::Result<HeaderValue> {
+    // Serialize refreshes, so concurrent tunnels do not burn the refresh token several times
+    let mut tokens = TOKENS.lock().await;
+    if tokens.get(cache_path).is_none_or(is_expired) {
+        // A new login, or another wstunnel sharing the cache, may have stored a fresh token meanwhile
+        let mut token = read_cache(cache_path).await?;
+        if is_expired(&token) {
+            token = refresh(token, http_cfg)
+                .await
+                .context("oidc token expired and cannot be refreshed, please run `wstunnel client login` again")?;
+            write_cache(cache_path, &token).await?;
+        }
+        tokens.insert(cache_path.to_path_buf(), token);
+    }
+
+    let token = &tokens[cache_path];
+    let mut bearer =
+        HeaderValue::from_str(&format!("Bearer {}", token.token)).context("invalid oidc token in cache")?;
+    bearer.set_sensitive(true);
+    Ok(bearer)
+}
+
+fn is_expired(token: &CachedToken) -> bool {
+    Duration::from_secs(token.expires_at) <= now() + EXPIRY_MARGIN
+}
+
+async fn refresh(token: CachedToken, http_cfg: &HttpClientConfig) -> anyhow::Result<CachedToken> {
+    let Some(refresh_token) = &token.refresh_token else {
+        return Err(anyhow!("no refresh token available"));
+    };
+
+    info!("Refreshing oidc token from {}", token.issuer);
+    let metadata = discover(&token.issuer, http_cfg).await?;
+    let params = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token.as_str()),
+        ("client_id", token.client_id.as_str()),
+    ];
+    let (status, body) = post_form(&metadata.token_endpoint, &params, http_cfg).await?;
+    if !status.is_success() {
+        return Err(token_error(&metadata.token_endpoint, &body));
+    }
+
+    let response: TokenResponse = serde_json::from_slice(&body)
+        .with_context(|| format!("invalid token response from {}", metadata.token_endpoint))?;
+    Ok(cached_token(token.issuer, token.client_id, response, token.refresh_token))
+}
+
+fn cached_token(
+    issuer: Url,
+    client_id: String,
+    response: TokenResponse,
+    previous_refresh_token: Option<String>,
+) -> CachedToken {
+    // The id token is the one meant for us, the access token is only meant for the provider apis
+    let token = response.id_token.unwrap_or(response.access_token);
+    let expires_at = jwt_expiration(&token)
+        .or_else(|| response.expires_in.map(|secs| now().as_secs() + secs))
+        .unwrap_or_default();
+
+    CachedToken {
+        issuer,
+        client_id,
+        token,
+        refresh_token: response.refresh_token.or(previous_refresh_token),
+        expires_at,
+    }
+}
+
+fn jwt_expiration(token: &str) -> Option<u64> {
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    claims.get("exp")?.as_u64()
+}
+
+async fn post_form(
+    url: &Url,
+    params: &[(&str, &str)],
+    http_cfg: &HttpClientConfig,
+) -> anyhow::Result<(hyper::StatusCode, Bytes)> {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+    let headers = [
+        (CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded")),
+        (ACCEPT, HeaderValue::from_static("application/json")),
+    ];
+    http_client::request(http_cfg, Method::POST, url, &headers, Bytes::from(body)).await
+}
+
+fn token_error(url: &Url, body: &[u8]) -> anyhow::Error {
+    match serde_json::from_slice::<TokenError>(body) {
+        Ok(err) => anyhow!(
+            "{url} returned error {}: {}",
+            err.error,
+            err.error_description.unwrap_or_default()
+        ),
+        Err(_) => anyhow!("{url} returned an unexpected response: {}", String::from_utf8_lossy(body)),
+    }
+}
+
+async fn read_cache(path: &Path) -> anyhow::Result<CachedToken> {
+    let content = tokio::fs::read(path).await.with_context(|| {
+        format!(
+            "cannot read oidc token from {}, please run `wstunnel client login` first",
+            path.display()
+        )
+    })?;
+    serde_json::from_slice(&content).with_context(|| format!("invalid oidc token cache {}", path.display()))
+}
+
+async fn write_cache(path: &Path, token: &CachedToken) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("cannot create directory {}", parent.display()))?;
+    }
+
+    let content = serde_json::to_vec(token)?;
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // The token grants access to the tunnel, do not let other users read it
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    debug!("Writing oidc token to {}", path.display());
+    let mut file = options
+        .open(path)
+        .await
+        .with_context(|| format!("cannot write oidc token to {}", path.display()))?;
+    file.write_all(&content).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::dns::DnsResolver;
+    use crate::somark::SoMark;
+
+    #[test]
+    fn test_jwt_expiration() {
+        let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(r#"{"sub":"erebe","exp":1700000000}"#);
+        assert_eq!(jwt_expiration(&format!("eyJhbGciOiJSUzI1NiJ9.{claims}.sig")), Some(1700000000));
+        assert_eq!(jwt_expiration("not-a-jwt"), None);
+    }
+
+    #[test]
+    fn test_cached_token_prefers_id_token() {
+        let response = TokenResponse {
+            access_token: "access".to_string(),
+            id_token: Some("id".to_string()),
+            refresh_token: None,
+            expires_in: Some(60),
+        };
+        let token = cached_token(
+            Url::parse("https://idp.lan").unwrap(),
+            "wstunnel".to_string(),
+            response,
+            Some("refresh".to_string()),
+        );
+
+        assert_eq!(token.token, "id");
+        assert_eq!(token.refresh_token.as_deref(), Some("refresh"));
+        assert!(token.expires_at > now().as_secs());
+    }
+
+    #[tokio::test]
+    async fn test_bearer_from_memory() {
+        let path = std::env::temp_dir()
+            .join(format!("wstunnel-oidc-{}", std::process::id()))
+            .join("token.json");
+        let http_cfg = HttpClientConfig {
+            so_mark: SoMark::new(None),
+            timeout: Duration::from_secs(1),
+            dns_resolver: DnsResolver::new_from_urls(&[], None, SoMark::new(None), true).unwrap(),
+        };
+        let token = |token: &str, expires_at: u64| CachedToken {
+            issuer: Url::parse("https://idp.lan").unwrap(),
+            client_id: "wstunnel".to_string(),
+            token: token.to_string(),
+            refresh_token: None,
+            expires_at,
+        };
+        let valid = now().as_secs() + 3600;
+
+        write_cache(&path, &token("first", valid)).await.unwrap();
+        assert_eq!(bearer_from_cache(&path, &http_cfg).await.unwrap(), "Bearer first");
+
+        // A valid token is not read again from the disk
+        write_cache(&path, &token("second", valid)).await.unwrap();
+        assert_eq!(bearer_from_cache(&path, &http_cfg).await.unwrap(), "Bearer first");
+
+        // An expired one is, and the disk may already hold a fresh one
+        TOKENS.lock().await.get_mut(path.as_path()).unwrap().expires_at = 0;
+        assert_eq!(bearer_from_cache(&path, &http_cfg).await.unwrap(), "Bearer second");
+
+        // Without a refresh token, an expired token cannot be renewed
+        write_cache(&path, &token("third", 0)).await.unwrap();
+        TOKENS.lock().await.get_mut(path.as_path()).unwrap().expires_at = 0;
+        assert!(bearer_from_cache(&path, &http_cfg).await.is_err());
+
+        tokio::fs::remove_dir_all(path.parent().unwrap()).await.unwrap();
+    }
+}
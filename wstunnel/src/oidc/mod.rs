@@ -0,0 +1,64 @@
+mod client;
+mod server;
+
+pub use client::{bearer_from_cache, default_token_cache_path, login};
+pub use server::OidcValidator;
+
+use crate::protocols::http_client;
+use crate::protocols::http_client::HttpClientConfig;
+use anyhow::{Context, anyhow};
+use bytes::Bytes;
+use hyper::Method;
+use hyper::header::{ACCEPT, HeaderValue};
+use serde::Deserialize;
+use url::Url;
+
+/// Subset of the OpenID provider metadata wstunnel needs
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    device_authorization_endpoint: Option<Url>,
+    token_endpoint: Url,
+    jwks_uri: Url,
+}
+
+fn discovery_url(issuer: &Url) -> anyhow::Result<Url> {
+    let url = format!("{}/.well-known/openid-configuration", issuer.as_str().trim_end_matches('/'));
+    Url::parse(&url).with_context(|| format!("invalid oidc issuer {issuer}"))
+}
+
+async fn discover(issuer: &Url, http_cfg: &HttpClientConfig) -> anyhow::Result<ProviderMetadata> {
+    let url = discovery_url(issuer)?;
+    let metadata: ProviderMetadata = get_json(&url, http_cfg).await?;
+    if metadata.issuer.trim_end_matches('/') != issuer.as_str().trim_end_matches('/') {
+        return Err(anyhow!(
+            "oidc provider advertises issuer {} but {issuer} was configured",
+            metadata.issuer
+        ));
+    }
+
+    Ok(metadata)
+}
+
+async fn get_json<T: for<'de> Deserialize<'de>>(url: &Url, http_cfg: &HttpClientConfig) -> anyhow::Result<T> {
+    let headers = [(ACCEPT, HeaderValue::from_static("application/json"))];
+    let (status, body) = http_client::request(http_cfg, Method::GET, url, &headers, Bytes::new()).await?;
+    if !status.is_success() {
+        return Err(anyhow!("GET {url} failed with status {status}"));
+    }
+
+    serde_json::from_slice(&body).with_context(|| format!("invalid json response from {url}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("https://idp.lan" => "https://idp.lan/.well-known/openid-configuration" ; "without path")]
+    #[test_case("https://idp.lan/" => "https://idp.lan/.well-known/openid-configuration" ; "with trailing slash")]
+    #[test_case("https://idp.lan/realms/corp" => "https://idp.lan/realms/corp/.well-known/openid-configuration" ; "with realm path")]
+    fn test_discovery_url(issuer: &str) -> String {
+        discovery_url(&Url::parse(issuer).unwrap()).unwrap().to_string()
+    }
+}
@@ -0,0 +1,161 @@
+use super::{ProviderMetadata, discover, get_json};
+use crate::protocols::http_client::HttpClientConfig;
+use anyhow::{Context, anyhow};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, info};
+use url::Url;
+
+/// Do not hammer the identity provider if clients present tokens signed by unknown keys
+const MIN_JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+struct ProviderKeys {
+    metadata: ProviderMetadata,
+    jwks: JwkSet,
+    fetched_at: Instant,
+}
+
+/// Validate bearer tokens issued by an OpenID Connect provider, against its published signing keys
+pub struct OidcValidator {
+    issuer: Url,
+    /// Tokens issued by the provider to its other applications are not accepted
+    audience: String,
+    http_cfg: HttpClientConfig,
+    keys: Mutex<Option<ProviderKeys>>,
+}
+
+impl OidcValidator {
+    pub fn new(issuer: Url, audience: String, http_cfg: HttpClientConfig) -> Self {
+        Self {
+            issuer,
+            audience,
+            http_cfg,
+            keys: Mutex::new(None),
+        }
+    }
+
+    pub fn issuer(&self) -> &Url {
+        &self.issuer
+    }
+
+    /// Returns the subject of the token if it is valid
+    pub async fn validate(&self, token: &str) -> anyhow::Result<String> {
+        let header = jsonwebtoken::decode_header(token).context("invalid bearer token")?;
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(anyhow!("symmetric algorithm {:?} is not accepted for oidc tokens", header.alg));
+        }
+
+        let (issuer, key) = self.decoding_key(header.kid.as_deref()).await?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[issuer]);
+        validation.set_audience(&[&self.audience]);
+
+        let token = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)?;
+        Ok(token
+            .claims
+            .get("sub")
+            .and_then(|sub| sub.as_str())
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    async fn decoding_key(&self, kid: Option<&str>) -> anyhow::Result<(String, DecodingKey)> {
+        let mut keys = self.keys.lock().await;
+
+        let must_refresh = match keys.as_ref() {
+            None => true,
+            Some(keys) => find_jwk(&keys.jwks, kid).is_none() && keys.fetched_at.elapsed() >= MIN_JWKS_REFRESH_INTERVAL,
+        };
+        if must_refresh {
+            let metadata = match keys.take() {
+                Some(keys) => keys.metadata,
+                None => discover(&self.issuer, &self.http_cfg).await?,
+            };
+            info!("Fetching oidc signing keys from {}", metadata.jwks_uri);
+            let jwks: JwkSet = get_json(&metadata.jwks_uri, &self.http_cfg).await?;
+            *keys = Some(ProviderKeys {
+                metadata,
+                jwks,
+                fetched_at: Instant::now(),
+            });
+        }
+
+        let Some(keys) = keys.as_ref() else {
+            return Err(anyhow!("no oidc signing keys available"));
+        };
+        let Some(jwk) = find_jwk(&keys.jwks, kid) else {
+            debug!("no oidc signing key found for kid {kid:?}");
+            return Err(anyhow!("token is signed with an unknown key {kid:?}"));
+        };
+
+        Ok((keys.metadata.issuer.clone(), DecodingKey::from_jwk(jwk)?))
+    }
+}
+
+fn find_jwk<'a>(jwks: &'a JwkSet, kid: Option<&str>) -> Option<&'a jsonwebtoken::jwk::Jwk> {
+    match kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::dns::DnsResolver;
+    use crate::somark::SoMark;
+    use jsonwebtoken::jwk::Jwk;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const ISSUER: &str = "https://idp.lan";
+
+    fn validator(audience: &str, signing_key: &EncodingKey) -> OidcValidator {
+        let http_cfg = HttpClientConfig {
+            so_mark: SoMark::new(None),
+            timeout: Duration::from_secs(1),
+            dns_resolver: DnsResolver::new_from_urls(&[], None, SoMark::new(None), true).unwrap(),
+        };
+        let mut validator = OidcValidator::new(Url::parse(ISSUER).unwrap(), audience.to_string(), http_cfg);
+        let mut jwk = Jwk::from_encoding_key(signing_key, Algorithm::ES256).unwrap();
+        jwk.common.key_id = Some("key-1".to_string());
+        *validator.keys.get_mut() = Some(ProviderKeys {
+            metadata: ProviderMetadata {
+                issuer: ISSUER.to_string(),
+                device_authorization_endpoint: None,
+                token_endpoint: Url::parse("https://idp.lan/token").unwrap(),
+                jwks_uri: Url::parse("https://idp.lan/jwks").unwrap(),
+            },
+            jwks: JwkSet { keys: vec![jwk] },
+            fetched_at: Instant::now(),
+        });
+        validator
+    }
+
+    fn token(audience: &str, signing_key: &EncodingKey) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("key-1".to_string());
+        let claims = json!({
+            "iss": ISSUER,
+            "sub": "alice",
+            "aud": audience,
+            "exp": jsonwebtoken::get_current_timestamp() + 60,
+        });
+        jsonwebtoken::encode(&header, &claims, signing_key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_validate_audience() {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let signing_key = EncodingKey::from_ec_der(&key_pair.serialize_der());
+        let validator = validator("wstunnel", &signing_key);
+
+        assert_eq!(validator.validate(&token("wstunnel", &signing_key)).await.unwrap(), "alice");
+        // Signed by the same provider, but for another of its applications
+        assert!(validator.validate(&token("grafana", &signing_key)).await.is_err());
+    }
+}
@@ -1,3 +1,5 @@
 mod resolver;
+mod server;
 
 pub use resolver::DnsResolver;
+pub use server::{alpn_protocols, serve_encrypted_dns};
@@ -1,5 +1,6 @@
 use crate::protocols;
 use crate::somark::SoMark;
+use crate::source_bind::UNBOUND;
 use anyhow::{Context, anyhow};
 use futures_util::{FutureExt, TryFutureExt};
 use hickory_resolver::Resolver;
@@ -264,6 +265,7 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
                     &host,
                     server_addr.port(),
                     so_mark,
+                    &UNBOUND,
                     timeout.unwrap_or(Duration::from_secs(10)),
                     &DnsResolver::System, // not going to be used as host is directly an ip address
                 )
@@ -275,6 +277,8 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
                     &host,
                     server_addr.port(),
                     so_mark,
+                    &UNBOUND,
+                    false,
                     timeout.unwrap_or(Duration::from_secs(10)),
                     &DnsResolver::System, // not going to be used as host is directly an ip address
                 )
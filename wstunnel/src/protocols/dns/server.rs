@@ -0,0 +1,261 @@
+//! DNS-over-HTTPS and DNS-over-TLS endpoint answering the queries with the resolvers of wstunnel, so a client can
+//! expose its trusted resolver through the server with a reverse tunnel
+use super::DnsResolver;
+use crate::tunnel::EncryptedDns;
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use bytes::Bytes;
+use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::rdata::{A, AAAA};
+use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::header::{CONTENT_TYPE, HeaderValue};
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+const DOH_PATH: &str = "/dns-query";
+/// Largest dns message, as its length is sent on 2 bytes with dns over tcp/tls
+const MAX_DNS_MESSAGE_LEN: usize = u16::MAX as usize;
+/// The system resolver does not give the ttl of the records
+const SYSTEM_RESOLVER_TTL: u32 = 60;
+
+/// Protocols negotiated in the tls handshake of the endpoint
+pub fn alpn_protocols(transport: EncryptedDns) -> Vec<Vec<u8>> {
+    match transport {
+        EncryptedDns::Https => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        EncryptedDns::Tls => vec![b"dot".to_vec()],
+    }
+}
+
+/// Serve the dns queries of a connection, i.e: of a roaming device reaching the server of a reverse tunnel
+pub async fn serve_encrypted_dns<S>(
+    transport: EncryptedDns,
+    tls_acceptor: TlsAcceptor,
+    dns_resolver: DnsResolver,
+    stream: S,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stream = tls_acceptor
+        .accept(stream)
+        .await
+        .with_context(|| "tls handshake of dns connection failed")?;
+
+    match transport {
+        EncryptedDns::Tls => serve_dns_over_tls(stream, &dns_resolver).await,
+        EncryptedDns::Https => {
+            let service = service_fn(move |req| handle_doh_request(dns_resolver.clone(), req));
+            hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .map_err(|err| anyhow::anyhow!("dns over https connection failed: {err:?}"))
+        }
+    }
+}
+
+async fn serve_dns_over_tls(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    dns_resolver: &DnsResolver,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; MAX_DNS_MESSAGE_LEN];
+    loop {
+        let len = match stream.read_u16().await {
+            Ok(len) => len as usize,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        stream.read_exact(&mut buf[..len]).await?;
+
+        let Some(response) = answer_query(dns_resolver, &buf[..len]).await else {
+            return Ok(());
+        };
+        stream.write_u16(response.len() as u16).await?;
+        stream.write_all(&response).await?;
+    }
+}
+
+async fn handle_doh_request(
+    dns_resolver: DnsResolver,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    let error = |status: StatusCode| {
+        let mut response = Response::new(Full::default());
+        *response.status_mut() = status;
+        Ok(response)
+    };
+    if req.uri().path() != DOH_PATH {
+        return error(StatusCode::NOT_FOUND);
+    }
+
+    let query = match *req.method() {
+        Method::GET => {
+            let query = req
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="))
+                .and_then(|query| URL_SAFE_NO_PAD.decode(query).ok());
+            match query {
+                Some(query) => Bytes::from(query),
+                None => return error(StatusCode::BAD_REQUEST),
+            }
+        }
+        Method::POST => {
+            if req.headers().get(CONTENT_TYPE).map(HeaderValue::as_bytes) != Some(DNS_MESSAGE_CONTENT_TYPE.as_bytes()) {
+                return error(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            match Limited::new(req.into_body(), MAX_DNS_MESSAGE_LEN).collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(_) => return error(StatusCode::PAYLOAD_TOO_LARGE),
+            }
+        }
+        _ => return error(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let Some(answer) = answer_query(&dns_resolver, &query).await else {
+        return error(StatusCode::BAD_REQUEST);
+    };
+    let mut response = Response::new(Full::new(Bytes::from(answer)));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE_CONTENT_TYPE));
+    Ok(response)
+}
+
+/// Answer of the resolver to a dns query, none if the query cannot be parsed
+async fn answer_query(dns_resolver: &DnsResolver, query: &[u8]) -> Option<Vec<u8>> {
+    let query = Message::from_vec(query).ok()?;
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_recursion_available(true)
+        .add_queries(query.queries().iter().cloned());
+
+    match query.queries().first() {
+        None => {
+            response.set_response_code(ResponseCode::FormErr);
+        }
+        Some(question) => match lookup(dns_resolver, question.name(), question.query_type()).await {
+            Ok(records) => {
+                response.add_answers(records);
+            }
+            Err(response_code) => {
+                response.set_response_code(response_code);
+            }
+        },
+    }
+
+    match response.to_vec() {
+        Ok(response) => Some(response),
+        Err(err) => {
+            warn!("Cannot encode dns response: {err}");
+            None
+        }
+    }
+}
+
+async fn lookup(dns_resolver: &DnsResolver, name: &Name, record_type: RecordType) -> Result<Vec<Record>, ResponseCode> {
+    match dns_resolver {
+        DnsResolver::TrustDns { resolver, .. } => match resolver.lookup(name.clone(), record_type).await {
+            Ok(lookup) => Ok(lookup.records().to_vec()),
+            Err(err) if err.is_nx_domain() => Err(ResponseCode::NXDomain),
+            Err(err) if err.is_no_records_found() => Ok(vec![]),
+            Err(err) => {
+                warn!("Cannot resolve {name} {record_type}: {err}");
+                Err(ResponseCode::ServFail)
+            }
+        },
+        // The system resolver only knows about ips
+        DnsResolver::System if matches!(record_type, RecordType::A | RecordType::AAAA) => {
+            // the hosts file of the system does not know the fully qualified names, ending with a dot
+            let host = name.to_utf8();
+            let addrs = dns_resolver
+                .lookup_host(host.trim_end_matches('.'), 0)
+                .await
+                .map_err(|err| {
+                    warn!("Cannot resolve {name} {record_type}: {err}");
+                    ResponseCode::ServFail
+                })?;
+            let records = addrs
+                .into_iter()
+                .filter_map(|addr| match (addr, record_type) {
+                    (SocketAddr::V4(addr), RecordType::A) => Some(RData::A(A(*addr.ip()))),
+                    (SocketAddr::V6(addr), RecordType::AAAA) => Some(RData::AAAA(AAAA(*addr.ip()))),
+                    _ => None,
+                })
+                .map(|rdata| Record::from_rdata(name.clone(), SYSTEM_RESOLVER_TTL, rdata))
+                .collect();
+            Ok(records)
+        }
+        DnsResolver::System => Err(ResponseCode::NotImp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::Query;
+    use std::str::FromStr;
+
+    fn query(name: &str, record_type: RecordType) -> Vec<u8> {
+        let mut query = Message::new();
+        query
+            .set_id(1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_str(name).unwrap(), record_type));
+        query.to_vec().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_answer_query_with_system_resolver() {
+        let response = answer_query(&DnsResolver::System, &query("localhost.", RecordType::A))
+            .await
+            .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 1234);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(
+            response
+                .answers()
+                .iter()
+                .all(|record| record.record_type() == RecordType::A)
+        );
+
+        let response = answer_query(&DnsResolver::System, &query("localhost.", RecordType::TXT))
+            .await
+            .unwrap();
+        assert_eq!(Message::from_vec(&response).unwrap().response_code(), ResponseCode::NotImp);
+
+        assert!(answer_query(&DnsResolver::System, b"not a dns query").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dns_over_tls_framing() {
+        let (mut client, server) = tokio::io::duplex(MAX_DNS_MESSAGE_LEN);
+        tokio::spawn(async move { serve_dns_over_tls(server, &DnsResolver::System).await });
+
+        let query = query("localhost.", RecordType::AAAA);
+        client.write_u16(query.len() as u16).await.unwrap();
+        client.write_all(&query).await.unwrap();
+
+        let len = client.read_u16().await.unwrap() as usize;
+        let mut response = vec![0u8; len];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(Message::from_vec(&response).unwrap().id(), 1234);
+    }
+}
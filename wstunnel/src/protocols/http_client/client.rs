@@ -0,0 +1,116 @@
+use crate::protocols;
+use crate::protocols::dns::DnsResolver;
+use crate::protocols::tls;
+use crate::protocols::tls::TlsFingerprint;
+use crate::somark::SoMark;
+use crate::source_bind::UNBOUND;
+use anyhow::{Context, anyhow};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::HOST;
+use hyper::http::{HeaderName, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tracing::{debug, error};
+use url::{Host, Url};
+
+/// How to reach the http servers wstunnel needs to talk to (webhook, identity provider, ...)
+#[derive(Clone, Debug)]
+pub struct HttpClientConfig {
+    pub so_mark: SoMark,
+    pub timeout: Duration,
+    pub dns_resolver: DnsResolver,
+}
+
+/// Do a single HTTP/1.1 request, and return the status and the whole body of the response
+pub async fn request(
+    cfg: &HttpClientConfig,
+    method: Method,
+    url: &Url,
+    headers: &[(HeaderName, HeaderValue)],
+    body: Bytes,
+) -> anyhow::Result<(StatusCode, Bytes)> {
+    let response = fetch(cfg, method, url, headers, body).await?;
+    Ok((response.status(), response.into_body()))
+}
+
+/// Like [`request`], but keep the headers of the response
+pub async fn fetch(
+    cfg: &HttpClientConfig,
+    method: Method,
+    url: &Url,
+    headers: &[(HeaderName, HeaderValue)],
+    body: Bytes,
+) -> anyhow::Result<Response<Bytes>> {
+    tokio::time::timeout(cfg.timeout, do_request(cfg, method, url, headers, body))
+        .await
+        .map_err(|_| anyhow!("request to {url} did not complete after {}s", cfg.timeout.as_secs()))?
+}
+
+async fn do_request(
+    cfg: &HttpClientConfig,
+    method: Method,
+    url: &Url,
+    headers: &[(HeaderName, HeaderValue)],
+    body: Bytes,
+) -> anyhow::Result<Response<Bytes>> {
+    let host = url.host().with_context(|| format!("url {url} has no host"))?.to_owned();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let tcp_stream =
+        protocols::tcp::connect(&host, port, cfg.so_mark, &UNBOUND, false, cfg.timeout, &cfg.dns_resolver).await?;
+
+    let mut req = Request::builder()
+        .method(method)
+        .uri(&url[url::Position::BeforePath..])
+        .header(HOST, &url[url::Position::BeforeHost..url::Position::AfterPort]);
+    for (k, v) in headers {
+        req = req.header(k, v);
+    }
+    let req = req
+        .body(Full::new(body))
+        .with_context(|| format!("cannot build http request for {url}"))?;
+
+    match url.scheme() {
+        "https" => {
+            let tls_connector = tls::tls_connector(
+                true,
+                vec![b"http/1.1".to_vec()],
+                true,
+                TlsFingerprint::default(),
+                None,
+                None,
+                None,
+            )?;
+            let server_name = match &host {
+                Host::Domain(domain) => ServerName::try_from(domain.clone())?,
+                Host::Ipv4(ip) => ServerName::from(IpAddr::V4(*ip)),
+                Host::Ipv6(ip) => ServerName::from(IpAddr::V6(*ip)),
+            };
+            let tls_stream = tls_connector.connect(server_name, tcp_stream).await?;
+            send_request(tls_stream, req).await
+        }
+        "http" => send_request(tcp_stream, req).await,
+        scheme => Err(anyhow!("unsupported scheme {scheme} for url {url}")),
+    }
+}
+
+async fn send_request(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    req: Request<Full<Bytes>>,
+) -> anyhow::Result<Response<Bytes>> {
+    let (mut sender, cnx) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = cnx.await {
+            error!("http client connection error: {err:?}");
+        }
+    });
+
+    debug!("sending http request {} {}", req.method(), req.uri());
+    let (parts, body) = sender.send_request(req).await?.into_parts();
+    let body = body.collect().await?.to_bytes();
+    Ok(Response::from_parts(parts, body))
+}
@@ -0,0 +1,5 @@
+mod client;
+
+pub use client::HttpClientConfig;
+pub use client::fetch;
+pub use client::request;
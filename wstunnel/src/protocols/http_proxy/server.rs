@@ -1,17 +1,21 @@
 use anyhow::Context;
-use bytes::Bytes;
-use log::{debug, error};
+use bytes::{Bytes, BytesMut};
+use log::{debug, error, warn};
 use std::future::Future;
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::pin::Pin;
 use std::sync::Arc;
 
 use crate::protocols::tcp;
 use crate::somark::SoMark;
+use crate::stats;
+use crate::tunnel::AccessList;
 use base64::Engine;
 use futures_util::{Stream, future, stream};
 use http_body_util::Empty;
 use hyper::body::Incoming;
+use hyper::header::{HeaderName, VIA};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response};
@@ -19,34 +23,66 @@ use hyper_util::rt::TokioTimer;
 use parking_lot::Mutex;
 use socket2::SockRef;
 use std::time::Duration;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio::select;
 use tokio::task::JoinSet;
 use tracing::log::info;
-use url::{Host, Url};
+use url::{Host, Position, Url};
+
+/// Longest head of a plain http request the proxy reads before giving up on it
+const MAX_REQUEST_HEAD_LEN: usize = 64 * 1024;
+const MAX_REQUEST_HEADERS: usize = 100;
+
+/// Connection accepted by the proxy, with the bytes to send to its destination before the ones of the connection,
+/// i.e: the rewritten head of a plain http request
+pub type HttpProxyConnection = (TcpStream, Bytes, (Host, u16));
 
 #[allow(clippy::type_complexity)]
 pub struct HttpProxyListener {
-    listener: Pin<Box<dyn Stream<Item = anyhow::Result<(TcpStream, (Host, u16))>> + Send>>,
+    listener: Pin<Box<dyn Stream<Item = anyhow::Result<HttpProxyConnection>> + Send>>,
+}
+
+struct HttpProxyConfig {
+    auth_header: Option<String>,
+    http1: http1::Builder,
+    /// Ports CONNECT requests may reach, all of them when empty
+    connect_ports: Vec<RangeInclusive<u16>>,
+    /// Answer the CONNECT requests with the X-Wstunnel-RTT and Via headers
+    path_headers: bool,
+    timeout: Option<Duration>,
+    access: AccessList,
 }
 
 impl Stream for HttpProxyListener {
-    type Item = anyhow::Result<(TcpStream, (Host, u16))>;
+    type Item = anyhow::Result<HttpProxyConnection>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
         unsafe { self.map_unchecked_mut(|x| &mut x.listener) }.poll_next(cx)
     }
 }
 
+/// Smoothed round trip time of the client with the server, to tell the apps whether the slowness is on the path to the
+/// server or after it. Unknown until a websocket ping of a tunnel is answered
+const RTT_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-rtt");
+
 fn handle_http_connect_request(
-    credentials: &Option<String>,
+    proxy_cfg: &HttpProxyConfig,
     dest: &Mutex<Option<(Host, u16)>>,
     req: Request<Incoming>,
 ) -> impl Future<Output = Result<Response<Empty<Bytes>>, &'static str>> {
     let ok_response = |forward_to: (Host, u16)| -> Result<Response<Empty<Bytes>>, _> {
         *dest.lock() = Some(forward_to);
-        Ok(Response::builder().status(200).body(Empty::new()).unwrap())
+        let mut response = Response::builder().status(200);
+        if proxy_cfg.path_headers {
+            response = response.header(VIA, concat!("1.1 wstunnel/", env!("CARGO_PKG_VERSION")));
+            if let Some(rtt) = stats::server_rtt() {
+                response = response.header(RTT_HEADER, format!("{}ms", rtt.as_millis()));
+            }
+        }
+        Ok(response.body(Empty::new()).unwrap())
     };
+    let (credentials, connect_ports) = (&proxy_cfg.auth_header, &proxy_cfg.connect_ports);
     fn err_response() -> Result<Response<Empty<Bytes>>, &'static str> {
         info!("Un-authorized connection to http proxy");
         Ok(Response::builder().status(401).body(Empty::new()).unwrap())
@@ -74,6 +110,11 @@ fn handle_http_connect_request(
         return future::ready(err_response());
     }
 
+    if !connect_ports.is_empty() && !connect_ports.iter().any(|ports| ports.contains(&forward_to.1)) {
+        info!("Rejecting http proxy CONNECT request to not allowed port {}", forward_to.1);
+        return future::ready(Ok(Response::builder().status(403).body(Empty::new()).unwrap()));
+    }
+
     future::ready(ok_response(forward_to))
 }
 
@@ -94,51 +135,35 @@ fn verify_credentials(credentials: &Option<String>, header_value: &Option<&str>)
     auth.starts_with(PROXY_AUTHORIZATION_PREFIX) && &auth[PROXY_AUTHORIZATION_PREFIX.len()..] == token
 }
 
-async fn handle_new_connection(
-    proxy_cfg: Arc<(Option<String>, http1::Builder)>,
-    mut stream: TcpStream,
-) -> Option<(TcpStream, (Host, u16))> {
+async fn handle_new_connection(proxy_cfg: Arc<HttpProxyConfig>, mut stream: TcpStream) -> Option<HttpProxyConnection> {
     // We need to know if the http request if a CONNECT method or a regular one.
     // HTTP CONNECT requires doing a handshake with client (which is easier)
-    // While for regular method, we need to replay the request as if it was done by the client.
+    // While for regular method, we need to rewrite the request as if it was done by the client to the destination.
     // Non HTTP CONNECT method only works for non TLS connection/request.
-
-    // to drop the request_buf early when not needed anymore
-    {
-        // Get a pick at data to analyze http request
-        const CONNECT_METHOD: &[u8] = b"CONNECT ";
-        let mut request_buf = [0; 512];
-
-        // it is possible that the data is not yet available to us.
-        // ideally, we should delay and retry the call until we have read enough bytes, or deadline elapsed.
-        // But in practice and for the case of wstunnel, it is an edge case not worth handling.
-        // So we parse what we have and reject the request if not enough bytes already.
-        let buf_size = stream.peek(&mut request_buf).await.ok()?;
-
-        if request_buf[..CONNECT_METHOD.len()] != *CONNECT_METHOD {
-            // If no creds/auth is expected don't bother with headers
-            let mut headers = {
-                let headers_len = if proxy_cfg.0.is_some() { 32 } else { 0 };
-                vec![httparse::EMPTY_HEADER; headers_len]
-            };
-            let mut http_parser = httparse::Request::new(&mut headers);
-            let _ = http_parser.parse(&request_buf[..buf_size]);
-
-            // if it is not an HTTP CONNECT request handle it directly
-            return handle_regular_http_request(&http_parser, &proxy_cfg.0).map(|x| (stream, x));
-        }
+    const CONNECT_METHOD: &[u8] = b"CONNECT ";
+    let mut method_buf = [0; CONNECT_METHOD.len()];
+    let buf_size = stream.peek(&mut method_buf).await.ok()?;
+
+    if method_buf[..buf_size] != *CONNECT_METHOD {
+        let request = handle_regular_http_request(&mut stream, &proxy_cfg.auth_header);
+        let request = match proxy_cfg.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, request).await.ok()?,
+            None => request.await,
+        };
+        return request.map(|(head, forward_to)| (stream, head, forward_to));
     }
 
     // Handle HTTP CONNECT request
-    let (auth_header, http1) = proxy_cfg.as_ref();
     let forward_to = Mutex::new(None);
-    let conn_fut = http1.serve_connection(
+    let conn_fut = proxy_cfg.http1.serve_connection(
         hyper_util::rt::TokioIo::new(&mut stream),
-        service_fn(|req| handle_http_connect_request(auth_header, &forward_to, req)),
+        service_fn(|req| handle_http_connect_request(&proxy_cfg, &forward_to, req)),
     );
 
     match conn_fut.await {
-        Ok(_) => forward_to.into_inner().map(|forward_to| (stream, forward_to)),
+        Ok(_) => forward_to
+            .into_inner()
+            .map(|forward_to| (stream, Bytes::new(), forward_to)),
         Err(err) => {
             info!("Error while serving connection: {err}");
             None
@@ -148,14 +173,16 @@ async fn handle_new_connection(
 
 pub async fn run_server(
     bind: SocketAddr,
+    v6only: Option<bool>,
     timeout: Option<Duration>,
     credentials: Option<(String, String)>,
+    connect_ports: Vec<RangeInclusive<u16>>,
+    path_headers: bool,
+    access: AccessList,
 ) -> Result<HttpProxyListener, anyhow::Error> {
     info!("Starting http proxy server listening cnx on {bind} with credentials {credentials:?}");
 
-    let listener = TcpListener::bind(bind)
-        .await
-        .with_context(|| format!("Cannot create TCP server {bind:?}"))?;
+    let listener = tcp::bind_listener(bind, v6only).with_context(|| format!("Cannot create TCP server {bind:?}"))?;
 
     let http1 = {
         let mut builder = http1::Builder::new();
@@ -167,9 +194,16 @@ pub async fn run_server(
     };
     let auth_header =
         credentials.map(|(user, pass)| base64::engine::general_purpose::STANDARD.encode(format!("{user}:{pass}")));
-    let tasks = JoinSet::<Option<(TcpStream, (Host, u16))>>::new();
-
-    let proxy_cfg = Arc::new((auth_header, http1));
+    let tasks = JoinSet::<Option<HttpProxyConnection>>::new();
+
+    let proxy_cfg = Arc::new(HttpProxyConfig {
+        auth_header,
+        http1,
+        connect_ports,
+        path_headers,
+        timeout,
+        access,
+    });
     let listener = stream::unfold((listener, tasks, proxy_cfg), |(listener, mut tasks, proxy_cfg)| async {
         loop {
             let (stream, forward_to) = select! {
@@ -177,7 +211,7 @@ pub async fn run_server(
 
                 cnx = tasks.join_next(), if !tasks.is_empty() => {
                     match cnx {
-                        Some(Ok(Some((stream, f)))) => (stream, Some(f)),
+                        Some(Ok(Some((stream, head, f)))) => (stream, Some((head, f))),
                         None | Some(Ok(None)) => continue,
                         Some(Err(err)) => {
                             error!("Error while joinning tasks {err:?}");
@@ -188,6 +222,10 @@ pub async fn run_server(
 
                 stream = listener.accept() => {
                     match stream {
+                        Ok((_, peer)) if !proxy_cfg.access.accepts(peer.ip()) => {
+                            warn!("Rejecting http proxy cnx of {peer}, it is not allowed by the access list of the listener");
+                            continue;
+                        }
                         Ok((stream, _)) => (stream, None),
                         Err(err) => {
                             error!("Error while accepting connection {err:?}");
@@ -198,9 +236,9 @@ pub async fn run_server(
             };
 
             // We have a new connection to forward
-            if let Some(forward_to) = forward_to {
+            if let Some((head, forward_to)) = forward_to {
                 let _ = tcp::configure_socket(SockRef::from(&stream), SoMark::new(None));
-                return Some((Ok((stream, forward_to)), (listener, tasks, proxy_cfg)));
+                return Some((Ok((stream, head, forward_to)), (listener, tasks, proxy_cfg)));
             }
 
             // New incoming connection, parse and route the http request
@@ -215,26 +253,90 @@ pub async fn run_server(
     })
 }
 
-fn handle_regular_http_request(http_parser: &httparse::Request, auth_header: &Option<String>) -> Option<(Host, u16)> {
-    const DEFAULT_HTTP_PORT: u16 = 80;
+/// Read the head of a proxy-style request, i.e: GET http://google.com/ HTTP/1.1, and rewrite it in the form expected
+/// by the destination, i.e: GET / HTTP/1.1. The destination closes the connection after its response, so the next
+/// request of the app, maybe for another host, goes through a new tunnel
+async fn handle_regular_http_request(
+    stream: &mut TcpStream,
+    auth_header: &Option<String>,
+) -> Option<(Bytes, (Host, u16))> {
+    let mut buf = BytesMut::with_capacity(4096);
+    loop {
+        if stream.read_buf(&mut buf).await.ok()? == 0 {
+            return None;
+        }
 
-    let header = http_parser.headers.iter().find_map(|h| {
-        if h.name == hyper::header::PROXY_AUTHORIZATION {
-            Some(String::from_utf8_lossy(h.value))
-        } else {
-            None
+        let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let head_len = match request.parse(&buf) {
+            Ok(httparse::Status::Complete(head_len)) => head_len,
+            Ok(httparse::Status::Partial) if buf.len() < MAX_REQUEST_HEAD_LEN => continue,
+            _ => return None,
+        };
+
+        let header = request.headers.iter().find_map(|h| {
+            if h.name.eq_ignore_ascii_case(hyper::header::PROXY_AUTHORIZATION.as_str()) {
+                Some(String::from_utf8_lossy(h.value))
+            } else {
+                None
+            }
+        });
+        if !verify_credentials(auth_header, &header.as_deref()) {
+            info!("Un-authorized connection to http proxy");
+            let _ = stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic\r\n\r\n")
+                .await;
+            return None;
         }
-    });
 
-    if !verify_credentials(auth_header, &header.as_deref()) {
-        return None;
+        let url = Url::parse(request.path.unwrap_or("")).ok()?;
+        if url.scheme() == "ftp" {
+            info!("Rejecting http proxy request to {url}, ftp over http is not supported");
+            let _ = stream.write_all(b"HTTP/1.1 501 Not Implemented\r\n\r\n").await;
+            return None;
+        }
+        let (head, forward_to) = rewrite_request_head(&request, &url)?;
+
+        let mut prefix = BytesMut::from(head.as_slice());
+        prefix.extend_from_slice(&buf[head_len..]);
+        return Some((prefix.freeze(), forward_to));
     }
+}
 
-    let url = Url::parse(http_parser.path.unwrap_or("")).ok()?;
+fn rewrite_request_head(request: &httparse::Request, url: &Url) -> Option<(Vec<u8>, (Host, u16))> {
+    const DEFAULT_HTTP_PORT: u16 = 80;
+    const HOP_BY_HOP_HEADERS: [&str; 4] = ["proxy-authorization", "proxy-connection", "connection", "keep-alive"];
+
+    if url.scheme() != "http" {
+        return None;
+    }
     let host = url.host()?.to_owned();
     let port = url.port_or_known_default().unwrap_or(DEFAULT_HTTP_PORT);
 
-    Some((host, port))
+    let mut head = format!(
+        "{} {} HTTP/1.{}\r\n",
+        request.method?,
+        &url[Position::BeforePath..Position::AfterQuery],
+        request.version?
+    )
+    .into_bytes();
+    let mut has_host = false;
+    for header in request.headers.iter() {
+        if HOP_BY_HOP_HEADERS.iter().any(|h| header.name.eq_ignore_ascii_case(h)) {
+            continue;
+        }
+        has_host |= header.name.eq_ignore_ascii_case("host");
+        head.extend_from_slice(header.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header.value);
+        head.extend_from_slice(b"\r\n");
+    }
+    if !has_host {
+        head.extend_from_slice(format!("Host: {}\r\n", &url[Position::BeforeHost..Position::AfterPort]).as_bytes());
+    }
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+
+    Some((head, (host, port)))
 }
 
 #[cfg(test)]
@@ -244,6 +346,7 @@ mod tests {
     use std::net::{Ipv4Addr, SocketAddrV4};
     use tokio::io::AsyncReadExt;
     use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
 
     #[fixture]
     async fn connected_client() -> (TcpStream, TcpStream) {
@@ -255,6 +358,19 @@ mod tests {
         (client, stream)
     }
 
+    fn proxy_cfg(auth: Option<&str>, connect_ports: Vec<RangeInclusive<u16>>) -> Arc<HttpProxyConfig> {
+        let mut http1 = http1::Builder::new();
+        http1.keep_alive(false);
+        Arc::new(HttpProxyConfig {
+            auth_header: auth.map(|x| x.to_string()),
+            http1,
+            connect_ports,
+            path_headers: false,
+            timeout: Some(Duration::from_secs(1)),
+            access: AccessList::default(),
+        })
+    }
+
     #[rstest]
     // No host available, it should fail
     #[case("GET / HTTP/1.1\r\n\r\n", None, None)]
@@ -279,13 +395,11 @@ mod tests {
         #[case] expected_result: Option<(Host, u16)>,
     ) {
         let (mut client, stream) = connected_client;
-        let auth_header = auth.map(|x| x.to_string());
-        let proxy_cfg = Arc::new((auth_header, http1::Builder::new()));
 
         client.write_all(input.as_ref()).await.unwrap();
 
-        let ret = handle_new_connection(proxy_cfg.clone(), stream).await;
-        assert_eq!(ret.map(|(_, x)| x), expected_result);
+        let ret = handle_new_connection(proxy_cfg(auth, vec![]), stream).await;
+        assert_eq!(ret.map(|(_, _, x)| x), expected_result);
     }
 
     #[rstest]
@@ -309,13 +423,11 @@ mod tests {
         #[case] expected_result: Option<(Host, u16)>,
     ) {
         let (mut client, stream) = connected_client;
-        let auth_header = auth.map(|x| x.to_string());
-        let proxy_cfg = Arc::new((auth_header, http1::Builder::new()));
 
         client.write_all(input.as_ref()).await.unwrap();
 
-        let ret = handle_new_connection(proxy_cfg.clone(), stream).await;
-        assert_eq!(ret.map(|(_, x)| x), expected_result);
+        let ret = handle_new_connection(proxy_cfg(auth, vec![]), stream).await;
+        assert_eq!(ret.map(|(_, _, x)| x), expected_result);
 
         let mut buf = Vec::with_capacity(1024);
         client.read_to_end(&mut buf).await.unwrap();
@@ -325,4 +437,87 @@ mod tests {
             assert_eq!(String::from_utf8_lossy(&buf)[..27], *"HTTP/1.0 401 Unauthorized\r\n");
         }
     }
+
+    #[rstest]
+    #[timeout(Duration::from_secs(10))]
+    #[tokio::test]
+    #[awt]
+    async fn test_connect_path_headers(#[future] connected_client: (TcpStream, TcpStream)) {
+        let (mut client, stream) = connected_client;
+        client
+            .write_all(b"CONNECT google.com:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut cfg = Arc::into_inner(proxy_cfg(None, vec![])).unwrap();
+        cfg.path_headers = true;
+        assert!(handle_new_connection(Arc::new(cfg), stream).await.is_some());
+
+        let mut buf = Vec::with_capacity(1024);
+        client.read_to_end(&mut buf).await.unwrap();
+        let response = String::from_utf8_lossy(&buf).to_lowercase();
+        assert!(response.starts_with("http/1.1 200 ok\r\n"), "{response}");
+        assert!(response.contains("\r\nvia: 1.1 wstunnel/"), "{response}");
+    }
+
+    #[rstest]
+    #[timeout(Duration::from_secs(10))]
+    #[tokio::test]
+    #[awt]
+    async fn test_rewrite_regular_request(#[future] connected_client: (TcpStream, TcpStream)) {
+        let (mut client, stream) = connected_client;
+        client
+            .write_all(b"POST http://google.com:8080/search?q=a HTTP/1.1\r\nHost: google.com:8080\r\nProxy-Authorization: Basic toto\r\nProxy-Connection: keep-alive\r\nContent-Length: 4\r\n\r\nbody")
+            .await
+            .unwrap();
+
+        let (_, head, forward_to) = handle_new_connection(proxy_cfg(Some("toto"), vec![]), stream)
+            .await
+            .unwrap();
+        assert_eq!(forward_to, (Host::Domain("google.com".to_string()), 8080));
+        assert_eq!(
+            String::from_utf8_lossy(&head),
+            "POST /search?q=a HTTP/1.1\r\nHost: google.com:8080\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody"
+        );
+    }
+
+    #[rstest]
+    #[timeout(Duration::from_secs(10))]
+    #[tokio::test]
+    #[awt]
+    async fn test_rewrite_request_without_host(#[future] connected_client: (TcpStream, TcpStream)) {
+        let (mut client, stream) = connected_client;
+        client
+            .write_all(b"GET http://google.com HTTP/1.0\r\n\r\n")
+            .await
+            .unwrap();
+
+        let (_, head, _) = handle_new_connection(proxy_cfg(None, vec![]), stream).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&head),
+            "GET / HTTP/1.0\r\nHost: google.com\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[rstest]
+    #[case("GET ftp://ftp.lan/file HTTP/1.1\r\n\r\n", "HTTP/1.1 501 Not Implemented\r\n")]
+    #[case("CONNECT google.com:22 HTTP/1.1\r\n\r\n", "HTTP/1.1 403 Forbidden\r\n")]
+    #[timeout(Duration::from_secs(10))]
+    #[tokio::test]
+    #[awt]
+    async fn test_reject_request(
+        #[future] connected_client: (TcpStream, TcpStream),
+        #[case] input: &str,
+        #[case] expected_response: &str,
+    ) {
+        let (mut client, stream) = connected_client;
+        client.write_all(input.as_bytes()).await.unwrap();
+
+        let ret = handle_new_connection(proxy_cfg(None, vec![443..=443, 8000..=8999]), stream).await;
+        assert!(ret.is_none());
+
+        let mut buf = Vec::with_capacity(1024);
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf).starts_with(expected_response));
+    }
 }
@@ -1,5 +1,8 @@
 pub mod dns;
+pub mod http_client;
 pub mod http_proxy;
+#[cfg(target_os = "linux")]
+pub mod sctp;
 pub mod socks5;
 pub mod stdio;
 pub mod tcp;
@@ -7,3 +10,5 @@ pub mod tls;
 pub mod udp;
 #[cfg(unix)]
 pub mod unix_sock;
+#[cfg(all(target_os = "linux", feature = "vsock"))]
+pub mod vsock;
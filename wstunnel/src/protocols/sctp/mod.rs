@@ -0,0 +1,4 @@
+mod server;
+
+pub use server::connect;
+pub use server::run_server;
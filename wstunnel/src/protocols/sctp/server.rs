@@ -0,0 +1,85 @@
+//! SCTP associations using the one-to-one style sockets of the kernel (SOCK_STREAM + IPPROTO_SCTP).
+//! Those sockets behave like TCP ones for accept/connect/read/write, so they are driven by tokio TCP types
+//! and flow through the tunnels as a byte stream. Protocols carried over SCTP (Diameter, M3UA, ...) frame their messages
+use crate::protocols::dns::DnsResolver;
+use crate::somark::SoMark;
+use anyhow::{Context, anyhow};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::timeout;
+use tokio_stream::wrappers::TcpListenerStream;
+use tracing::debug;
+use tracing::log::info;
+use url::Host;
+
+const IPPROTO_SCTP: i32 = 132;
+
+fn new_socket(addr: &SocketAddr) -> std::io::Result<Socket> {
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM.nonblocking().cloexec(),
+        Some(Protocol::from(IPPROTO_SCTP)),
+    )?;
+    Ok(socket)
+}
+
+pub async fn connect(
+    host: &Host<String>,
+    port: u16,
+    so_mark: SoMark,
+    connect_timeout: Duration,
+    dns_resolver: &DnsResolver,
+) -> Result<TcpStream, anyhow::Error> {
+    info!("Opening SCTP association to {host}:{port}");
+
+    let socket_addrs: Vec<SocketAddr> = match host {
+        Host::Domain(domain) => dns_resolver
+            .lookup_host(domain.as_str(), port)
+            .await
+            .with_context(|| format!("cannot resolve domain: {domain}"))?,
+        Host::Ipv4(ip) => vec![SocketAddr::V4(SocketAddrV4::new(*ip, port))],
+        Host::Ipv6(ip) => vec![SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, 0))],
+    };
+
+    let mut last_err = None;
+    for addr in socket_addrs {
+        let socket = new_socket(&addr).context("Cannot create SCTP socket, is the sctp kernel module loaded ?")?;
+        so_mark
+            .set_mark(SockRef::from(&socket))
+            .context("cannot set SO_MARK on socket")?;
+        let socket = TcpSocket::from_std_stream(std::net::TcpStream::from(socket));
+
+        debug!("Connecting to {}", addr);
+        match timeout(connect_timeout, socket.connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => {
+                debug!("Cannot connect to sctp endpoint {addr} reason {err}");
+                last_err = Some(err);
+            }
+            Err(_) => {
+                debug!(
+                    "Cannot connect to sctp endpoint {addr} due to timeout of {}s elapsed",
+                    connect_timeout.as_secs()
+                );
+            }
+        }
+    }
+
+    Err(anyhow!("Cannot connect to sctp endpoint {host}:{port} reason {last_err:?}"))
+}
+
+pub async fn run_server(bind: SocketAddr) -> Result<TcpListenerStream, anyhow::Error> {
+    info!("Starting SCTP server listening cnx on {bind}");
+
+    let socket = new_socket(&bind).context("Cannot create SCTP socket, is the sctp kernel module loaded ?")?;
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&SockAddr::from(bind))
+        .with_context(|| format!("Cannot create SCTP server {bind:?}"))?;
+    socket.listen(1024)?;
+    let listener = TcpListener::from_std(std::net::TcpListener::from(socket))?;
+
+    Ok(TcpListenerStream::new(listener))
+}
@@ -1,6 +1,8 @@
+mod resolve;
 mod tcp_server;
 mod udp_server;
 
+pub use resolve::{FakeIps, Socks5Resolver, run_fake_dns_server};
 pub use tcp_server::Socks5Listener;
 pub use tcp_server::Socks5ReadHalf;
 pub use tcp_server::Socks5WriteHalf;
@@ -0,0 +1,202 @@
+use crate::protocols::dns::DnsResolver;
+use crate::tunnel::to_host_port;
+use anyhow::{Context, anyhow};
+use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::rdata::A;
+use hickory_resolver::proto::rr::{RData, Record, RecordType};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+use url::Host;
+
+/// 198.18.0.0/15, reserved for benchmarks, so never the ip of a real destination
+const FAKE_IP_NETWORK: (Ipv4Addr, u32) = (Ipv4Addr::new(198, 18, 0, 0), 1 << 17);
+/// Apps must ask again once a fake ip is recycled for another hostname
+const FAKE_IP_TTL: u32 = 1;
+
+/// How the destinations of the socks5 requests are resolved before being sent to the server
+#[derive(Clone)]
+pub enum Socks5Resolver {
+    /// Hostnames are sent as is, the server resolves them
+    Remote,
+    /// Hostnames are resolved by the client
+    Local(DnsResolver),
+    /// Fake ips handed out by the dns server of the client are turned back into their hostname
+    FakeIp(Arc<FakeIps>),
+}
+
+impl Socks5Resolver {
+    pub async fn resolve(&self, (host, port): (Host, u16)) -> anyhow::Result<(Host, u16)> {
+        match (self, host) {
+            (Self::Local(dns_resolver), Host::Domain(domain)) => {
+                let addr = dns_resolver
+                    .lookup_host(&domain, port)
+                    .await
+                    .with_context(|| format!("cannot resolve {domain}"))?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("no address found for {domain}"))?;
+                Ok(to_host_port(addr))
+            }
+            (Self::FakeIp(fake_ips), Host::Ipv4(ip)) if FakeIps::is_fake(ip) => match fake_ips.domain(ip) {
+                Some(domain) => Ok((Host::Domain(domain), port)),
+                None => Err(anyhow!(
+                    "unknown fake ip {ip}, it has been recycled or was not handed out by wstunnel"
+                )),
+            },
+            (_, host) => Ok((host, port)),
+        }
+    }
+}
+
+/// Fake ips handed out for the hostnames, the oldest ones are recycled once all the ips of the range are used
+#[derive(Default)]
+pub struct FakeIps {
+    inner: Mutex<FakeIpsInner>,
+}
+
+#[derive(Default)]
+struct FakeIpsInner {
+    ips: HashMap<String, Ipv4Addr>,
+    domains: HashMap<Ipv4Addr, String>,
+    next: u32,
+}
+
+impl FakeIps {
+    fn is_fake(ip: Ipv4Addr) -> bool {
+        let (network, size) = FAKE_IP_NETWORK;
+        ip.to_bits().wrapping_sub(network.to_bits()) < size
+    }
+
+    pub fn ip(&self, domain: &str) -> Ipv4Addr {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut inner = self.inner.lock();
+        if let Some(ip) = inner.ips.get(&domain) {
+            return *ip;
+        }
+
+        // The first ip of the range is skipped, some apps consider it as the network and not a host
+        let (network, size) = FAKE_IP_NETWORK;
+        inner.next = inner.next % (size - 1) + 1;
+        let ip = Ipv4Addr::from_bits(network.to_bits() + inner.next);
+        if let Some(recycled) = inner.domains.insert(ip, domain.clone()) {
+            inner.ips.remove(&recycled);
+        }
+        inner.ips.insert(domain, ip);
+        ip
+    }
+
+    pub fn domain(&self, ip: Ipv4Addr) -> Option<String> {
+        self.inner.lock().domains.get(&ip).cloned()
+    }
+}
+
+/// Answer the A queries received on `bind` with fake ips, and every other query without any record, so apps fall
+/// back to ipv4
+pub async fn run_fake_dns_server(bind: SocketAddr, fake_ips: Arc<FakeIps>) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(bind)
+        .await
+        .with_context(|| format!("Cannot start fake dns server on {bind}"))?;
+    info!("Starting fake dns server listening on {bind}");
+
+    let mut buf = vec![0u8; u16::MAX as usize];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(ret) => ret,
+            Err(err) => {
+                warn!("Error while receiving dns query {err:?}");
+                continue;
+            }
+        };
+        let Ok(query) = Message::from_vec(&buf[..len]) else {
+            debug!("Ignoring invalid dns query from {peer}");
+            continue;
+        };
+
+        match fake_response(&fake_ips, &query).to_vec() {
+            Ok(response) => {
+                if let Err(err) = socket.send_to(&response, peer).await {
+                    warn!("Cannot send dns response to {peer}: {err}");
+                }
+            }
+            Err(err) => warn!("Cannot encode dns response: {err}"),
+        }
+    }
+}
+
+fn fake_response(fake_ips: &FakeIps, query: &Message) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_recursion_available(true)
+        .add_queries(query.queries().iter().cloned());
+
+    let Some(question) = query.queries().first() else {
+        response.set_response_code(ResponseCode::FormErr);
+        return response;
+    };
+    if question.query_type() == RecordType::A {
+        let ip = fake_ips.ip(&question.name().to_utf8());
+        response.add_answer(Record::from_rdata(question.name().clone(), FAKE_IP_TTL, RData::A(A(ip))));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::Query;
+    use hickory_resolver::proto::rr::Name;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_fake_ip_resolves_back_to_the_domain() {
+        let fake_ips = Arc::new(FakeIps::default());
+        let mut query = Message::new();
+        query.add_query(Query::query(Name::from_str("Example.com.").unwrap(), RecordType::A));
+
+        let response = fake_response(&fake_ips, &query);
+        let Some(RData::A(A(ip))) = response.answers().first().map(Record::data) else {
+            panic!("expected an A record in {response:?}");
+        };
+        assert_eq!(*ip, Ipv4Addr::new(198, 18, 0, 1));
+        assert_eq!(fake_ips.ip("example.com"), *ip);
+
+        let resolver = Socks5Resolver::FakeIp(fake_ips);
+        let (host, port) = resolver.resolve((Host::Ipv4(*ip), 443)).await.unwrap();
+        assert_eq!(host, Host::Domain("example.com".to_string()));
+        assert_eq!(port, 443);
+
+        // Real ips are left as is, unknown fake ones cannot be sent to the server
+        let (host, _) = resolver
+            .resolve((Host::Ipv4(Ipv4Addr::new(1, 1, 1, 1)), 53))
+            .await
+            .unwrap();
+        assert_eq!(host, Host::<String>::Ipv4(Ipv4Addr::new(1, 1, 1, 1)));
+        assert!(
+            resolver
+                .resolve((Host::Ipv4(Ipv4Addr::new(198, 19, 0, 1)), 53))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_fake_ips_are_recycled() {
+        let fake_ips = FakeIps::default();
+        let first = fake_ips.ip("first.lan");
+        for i in 0..FAKE_IP_NETWORK.1 - 2 {
+            fake_ips.ip(&format!("host-{i}.lan"));
+        }
+
+        let recycled = fake_ips.ip("last.lan");
+        assert_eq!(recycled, first);
+        assert_eq!(fake_ips.domain(first).as_deref(), Some("last.lan"));
+    }
+}
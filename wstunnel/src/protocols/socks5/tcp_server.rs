@@ -1,18 +1,20 @@
+use super::Socks5Resolver;
 use super::udp_server::{Socks5UdpStream, Socks5UdpStreamWriter};
-use crate::tunnel::LocalProtocol;
+use crate::tunnel::transport::io::{LocalReset, Resettable};
+use crate::tunnel::{AccessList, LocalProtocol};
 use anyhow::Context;
-use fast_socks5::server::{Config, DenyAuthentication, SimpleUserPassword, Socks5Server};
+use fast_socks5::server::Socks5ServerProtocol;
 use fast_socks5::util::target_addr::TargetAddr;
-use fast_socks5::{ReplyError, consts};
+use fast_socks5::{ReplyError, Socks5Command};
 use futures_util::{Stream, StreamExt, stream};
 use std::io::{Error, IoSlice};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
 use std::task::Poll;
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
 use tokio::task::JoinSet;
 use tracing::{info, warn};
@@ -41,7 +43,14 @@ pub enum Socks5Stream {
 impl Socks5Stream {
     pub fn local_protocol(&self) -> LocalProtocol {
         match self {
-            Self::Tcp(_) => LocalProtocol::Tcp { proxy_protocol: false }, // TODO: Implement proxy protocol
+            Self::Tcp(_) => LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            }, // TODO: Implement proxy protocol
             Self::Udp(s) => LocalProtocol::Udp {
                 timeout: s.0.watchdog_deadline.as_ref().map(|x| x.period()),
             },
@@ -71,123 +80,130 @@ pub async fn run_server(
     bind: SocketAddr,
     timeout: Option<Duration>,
     credentials: Option<(String, String)>,
+    resolver: Socks5Resolver,
+    access: AccessList,
 ) -> Result<Socks5Listener, anyhow::Error> {
     info!(
         "Starting SOCKS5 server listening cnx on {} with credentials {:?}",
         bind, credentials
     );
 
-    let server = Socks5Server::<DenyAuthentication>::bind(bind)
+    let listener = TcpListener::bind(bind)
         .await
         .with_context(|| format!("Cannot create socks5 server {bind:?}"))?;
-
-    let mut cfg = Config::default();
-    cfg = if let Some((username, password)) = credentials {
-        cfg.set_allow_no_auth(false);
-        cfg.with_authentication(SimpleUserPassword { username, password })
-    } else {
-        cfg.set_allow_no_auth(true);
-        cfg
-    };
-
-    cfg.set_dns_resolve(false);
-    cfg.set_execute_command(false);
-    cfg.set_udp_support(true);
-
     let udp_server = super::udp_server::run_server(bind, timeout).await?;
-    let server = server.with_config(cfg);
     let stream = stream::unfold(
-        (server, Box::pin(udp_server), JoinSet::new()),
-        move |(server, mut udp_server, mut tasks)| async move {
-            let mut acceptor = server.incoming();
-            loop {
-                let cnx = select! {
-                    biased;
-
-                    cnx = acceptor.next() => match cnx {
-                        None => return None,
-                        Some(Err(err)) => {
-                            drop(acceptor);
-                            return Some((Err(anyhow::Error::new(err)), (server, udp_server, tasks)));
+        (listener, Box::pin(udp_server), JoinSet::new()),
+        move |(listener, mut udp_server, mut tasks)| {
+            let resolver = resolver.clone();
+            let (credentials, access) = (credentials.clone(), access.clone());
+            async move {
+                loop {
+                    let (cnx, peer) = select! {
+                        biased;
+
+                        cnx = listener.accept() => match cnx {
+                            Ok(cnx) => cnx,
+                            Err(err) => return Some((Err(anyhow::Error::new(err)), (listener, udp_server, tasks))),
+                        },
+
+                        // new incoming udp stream
+                        udp_conn = udp_server.next() => {
+                            return match udp_conn {
+                                Some(Ok(stream)) => {
+                                    let dest = match resolver.resolve(stream.destination()).await {
+                                        Ok(dest) => dest,
+                                        Err(err) => return Some((Err(err), (listener, udp_server, tasks))),
+                                    };
+                                    let writer = stream.writer();
+                                    Some((Ok((Socks5Stream::Udp((stream, writer)), dest)), (listener, udp_server, tasks)))
+                                }
+                                Some(Err(err)) => {
+                                    Some((Err(anyhow::Error::new(err)), (listener, udp_server, tasks)))
+                                }
+                                None => {
+                                    None
+                                }
+                            };
                         }
-                        Some(Ok(cnx)) => cnx,
-                    },
-
-                    // new incoming udp stream
-                    udp_conn = udp_server.next() => {
-                        drop(acceptor);
-                        return match udp_conn {
-                            Some(Ok(stream)) => {
-                                let dest = stream.destination();
-                                let writer = stream.writer();
-                                Some((Ok((Socks5Stream::Udp((stream, writer)), dest)), (server, udp_server, tasks)))
-                            }
-                            Some(Err(err)) => {
-                                Some((Err(anyhow::Error::new(err)), (server, udp_server, tasks)))
-                            }
-                            None => {
-                                None
-                            }
-                        };
-                    }
-                };
+                    };
 
-                let cnx = match cnx.upgrade_to_socks5().await {
-                    Ok(cnx) => cnx,
-                    Err(err) => {
-                        warn!("Rejecting socks5 cnx: {}", err);
+                    // The peer is checked before the socks5 handshake, so a refused one learns nothing about the listener
+                    if !access.is_empty() && !access.accepts(peer.ip()) {
+                        warn!("Rejecting socks5 cnx of {peer}, it is not allowed by the access list of the listener");
                         continue;
                     }
-                };
-
-                let Some(target) = cnx.target_addr() else {
-                    warn!("Rejecting socks5 cnx: no target addr");
-                    continue;
-                };
-
-                let (host, port) = match target {
-                    TargetAddr::Ip(SocketAddr::V4(ip)) => (Host::Ipv4(*ip.ip()), ip.port()),
-                    TargetAddr::Ip(SocketAddr::V6(ip)) => (Host::Ipv6(*ip.ip()), ip.port()),
-                    TargetAddr::Domain(host, port) => (Host::Domain(host.clone()), *port),
-                };
 
-                // Special case for UDP Associate where we return the bind addr of the udp server
-                if matches!(cnx.cmd(), Some(fast_socks5::Socks5Command::UDPAssociate)) {
-                    let mut cnx = cnx.into_inner();
-                    let ret = cnx.write_all(&new_reply(&ReplyError::Succeeded, bind)).await;
-
-                    if let Err(err) = ret {
-                        warn!("Cannot reply to socks5 udp client: {}", err);
-                        continue;
-                    }
-                    tasks.spawn(async move {
-                        let mut buf = [0u8; 8];
-                        loop {
-                            match cnx.read(&mut buf).await {
-                                Ok(0) => return,
-                                Err(_) => return,
-                                _ => {}
+                    let handshake = async {
+                        let proto = match &credentials {
+                            Some((username, password)) => {
+                                Socks5ServerProtocol::accept_password_auth(cnx, |user, pass| {
+                                    user == *username && pass == *password
+                                })
+                                .await?
+                                .0
                             }
+                            None => Socks5ServerProtocol::accept_no_auth(cnx).await?,
+                        };
+                        proto.read_command().await
+                    };
+                    let (proto, cmd, target) = match handshake.await {
+                        Ok(cnx) => cnx,
+                        Err(err) => {
+                            warn!("Rejecting socks5 cnx: {}", err);
+                            continue;
                         }
-                    });
-                    continue;
-                };
-
-                let mut cnx = cnx.into_inner();
-                let ret = cnx
-                    .write_all(&new_reply(
-                        &ReplyError::Succeeded,
-                        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
-                    ))
-                    .await;
+                    };
+
+                    let (host, port) = match target {
+                        TargetAddr::Ip(SocketAddr::V4(ip)) => (Host::Ipv4(*ip.ip()), ip.port()),
+                        TargetAddr::Ip(SocketAddr::V6(ip)) => (Host::Ipv6(*ip.ip()), ip.port()),
+                        TargetAddr::Domain(host, port) => (Host::Domain(host), port),
+                    };
+
+                    // Special case for UDP Associate where we return the bind addr of the udp server
+                    if matches!(cmd, Socks5Command::UDPAssociate) {
+                        let mut cnx = match proto.reply_success(bind).await {
+                            Ok(cnx) => cnx,
+                            Err(err) => {
+                                warn!("Cannot reply to socks5 udp client: {}", err);
+                                continue;
+                            }
+                        };
+                        tasks.spawn(async move {
+                            let mut buf = [0u8; 8];
+                            loop {
+                                match cnx.read(&mut buf).await {
+                                    Ok(0) => return,
+                                    Err(_) => return,
+                                    _ => {}
+                                }
+                            }
+                        });
+                        continue;
+                    };
+
+                    let (host, port) = match resolver.resolve((host, port)).await {
+                        Ok(dest) => dest,
+                        Err(err) => {
+                            warn!("Rejecting socks5 cnx: {:?}", err);
+                            let _ = proto.reply_error(&ReplyError::HostUnreachable).await;
+                            continue;
+                        }
+                    };
+                    let cnx = match proto
+                        .reply_success(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0))
+                        .await
+                    {
+                        Ok(cnx) => cnx,
+                        Err(err) => {
+                            warn!("Cannot reply to socks5 client: {}", err);
+                            continue;
+                        }
+                    };
 
-                if let Err(err) = ret {
-                    warn!("Cannot reply to socks5 client: {}", err);
-                    continue;
+                    return Some((Ok((Socks5Stream::Tcp(cnx), (host, port))), (listener, udp_server, tasks)));
                 }
-
-                drop(acceptor);
-                return Some((Ok((Socks5Stream::Tcp(cnx), (host, port))), (server, udp_server, tasks)));
             }
         },
     );
@@ -199,32 +215,6 @@ pub async fn run_server(
     Ok(listener)
 }
 
-fn new_reply(error: &ReplyError, sock_addr: SocketAddr) -> Vec<u8> {
-    let (addr_type, mut ip_oct, mut port) = match sock_addr {
-        SocketAddr::V4(sock) => (
-            consts::SOCKS5_ADDR_TYPE_IPV4,
-            sock.ip().octets().to_vec(),
-            sock.port().to_be_bytes().to_vec(),
-        ),
-        SocketAddr::V6(sock) => (
-            consts::SOCKS5_ADDR_TYPE_IPV6,
-            sock.ip().octets().to_vec(),
-            sock.port().to_be_bytes().to_vec(),
-        ),
-    };
-
-    let mut reply = vec![
-        consts::SOCKS5_VERSION,
-        error.as_u8(), // transform the error into byte code
-        0x00,          // reserved
-        addr_type,     // address type (ipv4, v6, domain)
-    ];
-    reply.append(&mut ip_oct);
-    reply.append(&mut port);
-
-    reply
-}
-
 impl Unpin for Socks5Stream {}
 impl AsyncRead for Socks5ReadHalf {
     fn poll_read(
@@ -239,6 +229,15 @@ impl AsyncRead for Socks5ReadHalf {
     }
 }
 
+impl Resettable for Socks5WriteHalf {
+    fn local_reset(&self) -> LocalReset {
+        match self {
+            Self::Tcp(s) => s.local_reset(),
+            Self::Udp(_) => LocalReset::default(),
+        }
+    }
+}
+
 impl AsyncWrite for Socks5WriteHalf {
     fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
         match self.get_mut() {
@@ -279,3 +278,45 @@ impl AsyncWrite for Socks5WriteHalf {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::socks5::Socks5Resolver;
+    use fast_socks5::client;
+
+    #[tokio::test]
+    async fn test_socks5_connect_with_password() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let bind = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        let mut listener = run_server(
+            bind,
+            None,
+            Some(("alice".to_string(), "s3cret".to_string())),
+            Socks5Resolver::Remote,
+            AccessList::default(),
+        )
+        .await
+        .unwrap();
+        let accepted = tokio::spawn(async move { listener.next().await.unwrap().unwrap().1 });
+
+        let connect = |password: &str| {
+            client::Socks5Stream::connect_with_password(
+                bind,
+                "example.com".to_string(),
+                443,
+                "alice".to_string(),
+                password.to_string(),
+                client::Config::default(),
+            )
+        };
+        assert!(connect("wrong").await.is_err());
+        connect("s3cret").await.unwrap();
+        assert_eq!(accepted.await.unwrap(), (Host::Domain("example.com".to_string()), 443));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::tunnel::transport::io::Resettable;
+use bytes::{Buf, BufMut, BytesMut};
+use pin_project::pin_project;
+use std::io;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const LENGTH_PREFIX: usize = 2;
+
+/// Read datagrams from a byte stream where each one is prefixed by its length (u16 big endian).
+/// Each read returns exactly one datagram, the same way as reading from an udp socket
+#[pin_project]
+pub struct LengthPrefixedReader<R> {
+    #[pin]
+    inner: R,
+    buf: BytesMut,
+}
+
+impl<R> LengthPrefixedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::with_capacity(u16::MAX as usize + LENGTH_PREFIX),
+        }
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for LengthPrefixedReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            if this.buf.len() >= LENGTH_PREFIX {
+                let len = u16::from_be_bytes([this.buf[0], this.buf[1]]) as usize;
+                if this.buf.len() >= LENGTH_PREFIX + len {
+                    this.buf.advance(LENGTH_PREFIX);
+                    let datagram = this.buf.split_to(len);
+                    // An empty read means EOF, so empty datagrams cannot be forwarded
+                    if datagram.is_empty() {
+                        continue;
+                    }
+                    // Like udp, a datagram bigger than the read buffer is truncated
+                    let len = datagram.len().min(out.remaining());
+                    out.put_slice(&datagram[..len]);
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            this.buf.reserve(u16::MAX as usize + LENGTH_PREFIX);
+            let read_len = {
+                let mut read_buf = ReadBuf::uninit(this.buf.spare_capacity_mut());
+                ready!(this.inner.as_mut().poll_read(cx, &mut read_buf))?;
+                read_buf.filled().len()
+            };
+            if read_len == 0 {
+                // EOF, a partially received datagram is dropped
+                return Poll::Ready(Ok(()));
+            }
+            // Safety: the read initialized those bytes of the spare capacity
+            unsafe { this.buf.set_len(this.buf.len() + read_len) };
+        }
+    }
+}
+
+/// Write each buffer it is given as one datagram prefixed by its length (u16 big endian)
+#[pin_project]
+pub struct LengthPrefixedWriter<W> {
+    #[pin]
+    inner: W,
+    buf: BytesMut,
+}
+
+impl<W> LengthPrefixedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::with_capacity(u16::MAX as usize + LENGTH_PREFIX),
+        }
+    }
+}
+
+impl<W> Resettable for LengthPrefixedWriter<W> {}
+
+impl<W: AsyncWrite> AsyncWrite for LengthPrefixedWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, datagram: &[u8]) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+
+        // The datagram is only accepted once written as a whole, so when we return pending
+        // the caller polls us again with the same datagram, that is already encoded in the buffer
+        if this.buf.is_empty() {
+            let Ok(len) = u16::try_from(datagram.len()) else {
+                return Poll::Ready(Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("datagram of {} bytes is too big to be length prefixed", datagram.len()),
+                )));
+            };
+            this.buf.put_u16(len);
+            this.buf.put_slice(datagram);
+        }
+
+        while !this.buf.is_empty() {
+            let written = ready!(this.inner.as_mut().poll_write(cx, this.buf))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::Error::new(ErrorKind::WriteZero, "cannot write datagram")));
+            }
+            this.buf.advance(written);
+        }
+
+        Poll::Ready(Ok(datagram.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_length_prefixed_datagrams() {
+        let (client, server) = tokio::io::duplex(8);
+        let mut writer = LengthPrefixedWriter::new(client);
+        let mut reader = LengthPrefixedReader::new(server);
+
+        // The pipe is smaller than the datagrams, so they must be reassembled
+        let write = async {
+            writer.write_all(b"hello world").await.unwrap();
+            writer.write_all(b"bye").await.unwrap();
+            drop(writer);
+        };
+        let read = async {
+            let mut datagrams = vec![];
+            let mut buf = [0u8; 64];
+            loop {
+                let len = reader.read(&mut buf).await.unwrap();
+                datagrams.push(buf[..len].to_vec());
+                if len == 3 {
+                    return datagrams;
+                }
+            }
+        };
+        let ((), datagrams) = tokio::join!(write, read);
+        assert_eq!(datagrams, vec![b"hello world".to_vec(), b"bye".to_vec()]);
+    }
+}
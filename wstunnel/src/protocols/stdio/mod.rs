@@ -1,8 +1,10 @@
+mod length_prefixed;
 #[cfg(unix)]
 mod server_unix;
 #[cfg(not(unix))]
 mod server_windows;
 
+pub use length_prefixed::{LengthPrefixedReader, LengthPrefixedWriter};
 #[cfg(unix)]
 pub use server_unix::run_server;
 #[cfg(not(unix))]
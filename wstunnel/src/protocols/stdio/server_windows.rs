@@ -12,7 +12,10 @@ use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_util::io::StreamReader;
 use tracing::info;
 
-pub async fn run_server() -> Result<((impl AsyncRead, impl AsyncWrite), oneshot::Sender<()>), anyhow::Error> {
+use crate::tunnel::transport::io::Resettable;
+
+pub async fn run_server() -> Result<((impl AsyncRead, impl AsyncWrite + Resettable), oneshot::Sender<()>), anyhow::Error>
+{
     info!("Starting STDIO server. Press ctrl+c twice to exit");
 
     crossterm::terminal::enable_raw_mode()?;
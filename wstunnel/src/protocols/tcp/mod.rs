@@ -1,6 +1,15 @@
 mod server;
 
+pub use server::bind_listener;
+#[cfg(unix)]
+pub use server::bind_reuse_port_listener;
 pub use server::configure_socket;
 pub use server::connect;
 pub use server::connect_with_http_proxy;
+pub use server::reset;
 pub use server::run_server;
+pub use server::set_tcp_defer_accept;
+pub use server::set_tcp_fastopen_listener;
+pub use server::set_tcp_keepalive;
+pub use server::{DEFAULT_KEEPALIVE_COUNT, DEFAULT_KEEPALIVE_IDLE, DEFAULT_KEEPALIVE_INTERVAL};
+pub use server::{TCP_DEFER_ACCEPT_SUPPORTED, TCP_FASTOPEN_LISTENER_SUPPORTED};
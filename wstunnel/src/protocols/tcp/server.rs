@@ -10,6 +10,7 @@ use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use crate::protocols::dns::DnsResolver;
 use crate::somark::SoMark;
+use crate::source_bind::SourceBind;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
@@ -24,25 +25,145 @@ pub fn configure_socket(socket: SockRef, so_mark: SoMark) -> Result<(), anyhow::
         .set_tcp_nodelay(true)
         .with_context(|| format!("cannot set no_delay on socket: {:?}", io::Error::last_os_error()))?;
 
+    set_tcp_keepalive(
+        SockRef::from(&*socket),
+        DEFAULT_KEEPALIVE_IDLE,
+        DEFAULT_KEEPALIVE_INTERVAL,
+        DEFAULT_KEEPALIVE_COUNT,
+    )
+    .with_context(|| format!("cannot set tcp_keepalive on socket: {:?}", io::Error::last_os_error()))?;
+
+    // The urgent byte stays in the stream instead of being dropped, the tunnel carries it as a regular byte
+    socket
+        .set_out_of_band_inline(true)
+        .with_context(|| format!("cannot set oob_inline on socket: {:?}", io::Error::last_os_error()))?;
+
+    so_mark.set_mark(socket).context("cannot set SO_MARK on socket")?;
+
+    Ok(())
+}
+
+pub const DEFAULT_KEEPALIVE_IDLE: Duration = Duration::from_secs(60);
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_KEEPALIVE_COUNT: u32 = 3;
+
+/// Probe the peer after `idle` without traffic, every `interval`, and give up on it after `count` unanswered probes.
+/// Windows has no count, openbsd only has the system wide settings
+pub fn set_tcp_keepalive(socket: SockRef, idle: Duration, interval: Duration, count: u32) -> io::Result<()> {
     #[cfg(not(any(target_os = "windows", target_os = "openbsd")))]
     let tcp_keepalive = TcpKeepalive::new()
-        .with_time(Duration::from_secs(60))
-        .with_interval(Duration::from_secs(10))
-        .with_retries(3);
+        .with_time(idle)
+        .with_interval(interval)
+        .with_retries(count);
 
     #[cfg(target_os = "windows")]
-    let tcp_keepalive = TcpKeepalive::new()
-        .with_time(Duration::from_secs(60))
-        .with_interval(Duration::from_secs(10));
+    let tcp_keepalive = {
+        let _ = count;
+        TcpKeepalive::new().with_time(idle).with_interval(interval)
+    };
 
     #[cfg(target_os = "openbsd")]
-    let tcp_keepalive = TcpKeepalive::new().with_time(Duration::from_secs(60));
+    let tcp_keepalive = {
+        let _ = (interval, count);
+        TcpKeepalive::new().with_time(idle)
+    };
 
-    socket
-        .set_tcp_keepalive(&tcp_keepalive)
-        .with_context(|| format!("cannot set tcp_keepalive on socket: {:?}", io::Error::last_os_error()))?;
+    socket.set_tcp_keepalive(&tcp_keepalive)
+}
 
-    so_mark.set_mark(socket).context("cannot set SO_MARK on socket")?;
+/// Allow to send data in the SYN packet when connecting, if the server already gave us a fast open cookie
+#[cfg(target_os = "linux")]
+pub fn set_tcp_fastopen_connect(socket: SockRef) -> io::Result<()> {
+    nix::sys::socket::setsockopt(&*socket, nix::sys::socket::sockopt::TcpFastOpenConnect, &true)
+        .map_err(io::Error::from)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_fastopen_connect(_socket: SockRef) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP fast open is only supported on linux",
+    ))
+}
+
+// TCP_FASTOPEN and TCP_DEFER_ACCEPT are not wrapped by nix, nor socket2
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+mod sockopt {
+    use nix::libc;
+    use nix::{getsockopt_impl, setsockopt_impl, sockopt_impl};
+
+    sockopt_impl!(TcpFastOpen, Both, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, usize);
+    #[cfg(target_os = "linux")]
+    sockopt_impl!(TcpDeferAccept, Both, libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT, usize);
+}
+
+/// Platforms where the listener can accept data in the SYN packet and wait for data before accepting
+pub const TCP_FASTOPEN_LISTENER_SUPPORTED: bool =
+    cfg!(any(target_os = "linux", target_os = "macos", target_os = "freebsd"));
+pub const TCP_DEFER_ACCEPT_SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Accept data in the SYN packet of clients that have a fast open cookie. On linux the queue of pending fast open
+/// requests holds `queue_len` of them, macos and freebsd only take the option as a switch and size it by themselves
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+pub fn set_tcp_fastopen_listener(socket: SockRef, queue_len: u32) -> io::Result<()> {
+    let value = if cfg!(target_os = "linux") {
+        queue_len.min(i32::MAX as u32) as usize
+    } else {
+        1
+    };
+    nix::sys::socket::setsockopt(&*socket, sockopt::TcpFastOpen, &value).map_err(io::Error::from)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+pub fn set_tcp_fastopen_listener(_socket: SockRef, _queue_len: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP fast open is only supported on linux, macos and freebsd",
+    ))
+}
+
+/// Only wake up the listener when the connection has data to read, or after `timeout` elapsed
+#[cfg(target_os = "linux")]
+pub fn set_tcp_defer_accept(socket: SockRef, timeout: Duration) -> io::Result<()> {
+    let value = timeout.as_secs().min(i32::MAX as u64) as usize;
+    nix::sys::socket::setsockopt(&*socket, sockopt::TcpDeferAccept, &value).map_err(io::Error::from)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_defer_accept(_socket: SockRef, _timeout: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP defer accept is only supported on linux",
+    ))
+}
+
+/// Abort the connection with a RST, instead of the FIN of a close, so the peer sees it failed. On linux the RST is sent
+/// right away, by disconnecting the socket. Elsewhere it is sent once the socket is closed, after the FIN of the
+/// shutdown of its write half
+pub fn reset(socket: SockRef) -> io::Result<()> {
+    socket.set_linger(Some(Duration::ZERO))?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        // Connecting a TCP socket to an AF_UNSPEC address disconnects it, with a RST
+        let addr = nix::libc::sockaddr {
+            sa_family: nix::libc::AF_UNSPEC as nix::libc::sa_family_t,
+            sa_data: [0; 14],
+        };
+        // safety: the address is a plain sockaddr that outlives the call
+        let ret = unsafe {
+            nix::libc::connect(
+                socket.as_raw_fd(),
+                &addr,
+                size_of::<nix::libc::sockaddr>() as nix::libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
 
     Ok(())
 }
@@ -51,6 +172,8 @@ pub async fn connect(
     host: &Host<String>,
     port: u16,
     so_mark: SoMark,
+    source_bind: &SourceBind,
+    tcp_fastopen: bool,
     connect_timeout: Duration,
     dns_resolver: &DnsResolver,
 ) -> Result<TcpStream, anyhow::Error> {
@@ -82,6 +205,14 @@ pub async fn connect(
             }
         };
         configure_socket(socket2::SockRef::from(&socket), so_mark)?;
+        if let Err(err) = source_bind.bind(socket2::SockRef::from(&socket), &addr) {
+            debug!("Cannot bind the socket to connect to {addr}: {err}");
+            last_err = Some(err);
+            continue;
+        }
+        if tcp_fastopen && let Err(err) = set_tcp_fastopen_connect(socket2::SockRef::from(&socket)) {
+            warn!("Cannot enable TCP fast open on socket: {err}");
+        }
 
         // Spawn the connection attempt in the join set.
         // We include a delay of ix * 250 milliseconds, as per RFC8305.
@@ -139,6 +270,7 @@ pub async fn connect_with_http_proxy(
     host: &Host<String>,
     port: u16,
     so_mark: SoMark,
+    source_bind: &SourceBind,
     connect_timeout: Duration,
     dns_resolver: &DnsResolver,
 ) -> Result<TcpStream, anyhow::Error> {
@@ -146,13 +278,21 @@ pub async fn connect_with_http_proxy(
     let proxy_port = proxy.port_or_known_default().unwrap_or(80);
 
     info!("Connecting to http proxy {}:{}", proxy_host, proxy_port);
-    let mut socket = connect(&proxy_host, proxy_port, so_mark, connect_timeout, dns_resolver).await?;
+    let mut socket = connect(
+        &proxy_host,
+        proxy_port,
+        so_mark,
+        source_bind,
+        false,
+        connect_timeout,
+        dns_resolver,
+    )
+    .await?;
     debug!("Connected to http proxy {}", socket.peer_addr()?);
 
     let authorization = if let Some((user, password)) = proxy.password().map(|p| (proxy.username(), p)) {
         let user = urlencoding::decode(user).with_context(|| format!("Cannot urldecode proxy user: {user}"))?;
-        let password =
-            urlencoding::decode(password).with_context(|| format!("Cannot urldecode proxy password: {password}"))?;
+        let password = urlencoding::decode(password).context("Cannot urldecode proxy password")?;
         let creds = base64::engine::general_purpose::STANDARD.encode(format!("{user}:{password}"));
         format!("Proxy-Authorization: Basic {creds}\r\n")
     } else {
@@ -208,13 +348,47 @@ pub async fn connect_with_http_proxy(
     Ok(socket)
 }
 
+/// Bind a listener like [`TcpListener::bind`], with IPV6_V6ONLY set to `v6only` instead of the default of the OS,
+/// when `bind` is an ipv6 address
+pub fn bind_listener(bind: SocketAddr, v6only: Option<bool>) -> io::Result<TcpListener> {
+    let socket = match bind {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    if let Some(v6only) = v6only
+        && bind.is_ipv6()
+    {
+        socket2::SockRef::from(&socket).set_only_v6(v6only)?;
+    }
+    socket.bind(bind)?;
+    socket.listen(1024)
+}
+
+/// Bind one of several listeners on the same address with SO_REUSEPORT, the kernel spreading the new connections
+/// between them
+#[cfg(unix)]
+pub fn bind_reuse_port_listener(bind: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match bind {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(bind)?;
+    socket.listen(1024)
+}
+
 #[cfg_attr(not(target_os = "linux"), expect(unused_variables))]
-pub async fn run_server(bind: SocketAddr, ip_transparent: bool) -> Result<TcpListenerStream, anyhow::Error> {
+pub async fn run_server(
+    bind: SocketAddr,
+    ip_transparent: bool,
+    v6only: Option<bool>,
+) -> Result<TcpListenerStream, anyhow::Error> {
     info!("Starting TCP server listening cnx on {bind}");
 
-    let listener = TcpListener::bind(bind)
-        .await
-        .with_context(|| format!("Cannot create TCP server {bind:?}"))?;
+    let listener = bind_listener(bind, v6only).with_context(|| format!("Cannot create TCP server {bind:?}"))?;
 
     #[cfg(target_os = "linux")]
     if ip_transparent {
@@ -229,6 +403,7 @@ pub async fn run_server(bind: SocketAddr, ip_transparent: bool) -> Result<TcpLis
 #[cfg(all(test, not(target_os = "openbsd")))]
 mod tests {
     use super::*;
+    use crate::source_bind::UNBOUND;
     use futures_util::pin_mut;
     use std::borrow::Cow;
     use std::net::IpAddr;
@@ -259,6 +434,82 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_bind_listener_v6only() {
+        // Linux forces v6only on the sockets bound to a specific ipv6 address, so use the unspecified one
+        let bind: SocketAddr = "[::]:0".parse().unwrap();
+        for v6only in [true, false] {
+            let listener = bind_listener(bind, Some(v6only)).unwrap();
+            assert_eq!(socket2::SockRef::from(&listener).only_v6().unwrap(), v6only);
+        }
+
+        // Not applicable to an ipv4 listener
+        bind_listener("127.0.0.1:0".parse().unwrap(), Some(true)).unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+    #[tokio::test]
+    async fn test_tcp_fastopen_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = SockRef::from(&listener);
+        assert_eq!(nix::sys::socket::getsockopt(&*socket, sockopt::TcpFastOpen).unwrap(), 0);
+
+        set_tcp_fastopen_listener(SockRef::from(&listener), 256).unwrap();
+        let expected = if cfg!(target_os = "linux") { 256 } else { 1 };
+        assert_eq!(nix::sys::socket::getsockopt(&*socket, sockopt::TcpFastOpen).unwrap(), expected);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_tcp_defer_accept() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = SockRef::from(&listener);
+        assert_eq!(nix::sys::socket::getsockopt(&*socket, sockopt::TcpDeferAccept).unwrap(), 0);
+
+        // The kernel rounds the timeout to its SYN-ACK retransmissions
+        set_tcp_defer_accept(SockRef::from(&listener), Duration::from_secs(5)).unwrap();
+        assert!(nix::sys::socket::getsockopt(&*socket, sockopt::TcpDeferAccept).unwrap() >= 5);
+
+        // The client sends nothing, so its connection is not accepted yet
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        assert!(timeout(Duration::from_millis(500), listener.accept()).await.is_err());
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(b"Hello").await.unwrap();
+        let (mut stream, _) = timeout(Duration::from_secs(1), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello");
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[tokio::test]
+    async fn test_tcp_defer_accept_unsupported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let err = set_tcp_defer_accept(SockRef::from(&listener), Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_reuse_port_listener() {
+        let first = bind_reuse_port_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let bind = first.local_addr().unwrap();
+        let second = bind_reuse_port_listener(bind).unwrap();
+        // Not a listener sharing the port, it must still be refused
+        assert!(TcpListener::bind(bind).await.is_err());
+
+        let _client = TcpStream::connect(bind).await.unwrap();
+        let accepted = tokio::select! {
+            ret = first.accept() => ret,
+            ret = second.accept() => ret,
+        };
+        accepted.unwrap();
+    }
+
     #[tokio::test]
     async fn test_proxy_connection() {
         let (network_name, host) = if cfg!(not(target_os = "macos")) {
@@ -289,6 +540,7 @@ mod tests {
             &Host::Domain(host.to_string()),
             server_port,
             SoMark::new(None),
+            &UNBOUND,
             Duration::from_secs(1),
             &DnsResolver::System,
         )
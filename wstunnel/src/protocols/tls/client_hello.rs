@@ -0,0 +1,155 @@
+//! client_hello - read the server name (SNI) of a tls client hello without consuming it, so the connection can still be
+//! handed as is to another server
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME_EXTENSION: u16 = 0x0000;
+const HOST_NAME: u8 = 0x00;
+const RECORD_HEADER_LEN: usize = 5;
+/// Largest record a client can send, the client hello is expected to fit in the first one
+pub const MAX_RECORD_LEN: usize = RECORD_HEADER_LEN + 16 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientHelloServerName {
+    /// The first record is not fully received yet
+    Incomplete,
+    /// The client did not send a server name, or did not send a client hello
+    Missing,
+    Found(String),
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| (usize::from(b[0]) << 16) | (usize::from(b[1]) << 8) | usize::from(b[2]))
+    }
+
+    fn vec_u8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?;
+        self.take(len.into())
+    }
+
+    fn vec_u16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len.into())
+    }
+}
+
+/// Server name of the client hello at the start of `first_bytes`, the bytes received so far on the connection
+pub fn client_hello_server_name(first_bytes: &[u8]) -> ClientHelloServerName {
+    let mut record = Reader(first_bytes);
+    let (Some(record_type), Some(_version), Some(record_len)) = (record.u8(), record.u16(), record.u16()) else {
+        return ClientHelloServerName::Incomplete;
+    };
+    if record_type != HANDSHAKE_RECORD {
+        return ClientHelloServerName::Missing;
+    }
+    let Some(handshake) = record.take(record_len.into()) else {
+        return ClientHelloServerName::Incomplete;
+    };
+
+    match find_server_name(Reader(handshake)) {
+        Some(server_name) => ClientHelloServerName::Found(server_name),
+        None => ClientHelloServerName::Missing,
+    }
+}
+
+fn find_server_name(mut handshake: Reader) -> Option<String> {
+    if handshake.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    // A client hello split over several records is not looked into
+    let hello_len = handshake.u24()?;
+    let mut hello = Reader(handshake.take(hello_len)?);
+    let _version = hello.u16()?;
+    let _random = hello.take(32)?;
+    let _session_id = hello.vec_u8()?;
+    let _cipher_suites = hello.vec_u16()?;
+    let _compression_methods = hello.vec_u8()?;
+
+    let mut extensions = Reader(hello.vec_u16()?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let mut extension = Reader(extensions.vec_u16()?);
+        if extension_type != SERVER_NAME_EXTENSION {
+            continue;
+        }
+
+        let mut names = Reader(extension.vec_u16()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec_u16()?;
+            if name_type == HOST_NAME {
+                return std::str::from_utf8(name).ok().map(|name| name.to_ascii_lowercase());
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        #[cfg(feature = "aws-lc-rs")]
+        let provider = tokio_rustls::rustls::crypto::aws_lc_rs::default_provider();
+        #[cfg(not(feature = "aws-lc-rs"))]
+        let provider = tokio_rustls::rustls::crypto::ring::default_provider();
+
+        let config = ClientConfig::builder_with_provider(Arc::new(provider))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        let mut conn = ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let mut client_hello = vec![];
+        conn.write_tls(&mut client_hello).unwrap();
+        client_hello
+    }
+
+    #[test]
+    fn test_client_hello_server_name() {
+        let hello = client_hello("WWW.example.com");
+        assert_eq!(
+            client_hello_server_name(&hello),
+            ClientHelloServerName::Found("www.example.com".to_string())
+        );
+        assert_eq!(
+            client_hello_server_name(&hello[..hello.len() - 1]),
+            ClientHelloServerName::Incomplete
+        );
+        assert_eq!(client_hello_server_name(&hello[..3]), ClientHelloServerName::Incomplete);
+
+        // rustls does not send the ip addresses as server name
+        let hello = client_hello("127.0.0.1");
+        assert_eq!(client_hello_server_name(&hello), ClientHelloServerName::Missing);
+        assert_eq!(
+            client_hello_server_name(b"GET / HTTP/1.1\r\n\r\n"),
+            ClientHelloServerName::Missing
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! fingerprint - offer the cipher suites and key exchange groups of a browser in the tls client hello, in the order the
+//! browser does. Only what rustls lets us configure can match: the extensions, their order, GREASE and the certificate
+//! compression stay the ones of rustls, so the JA3/JA4 fingerprints get closer to the ones of the browser, not identical
+use std::str::FromStr;
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::{CipherSuite, NamedGroup};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TlsFingerprint {
+    /// The defaults of rustls
+    #[default]
+    Rustls,
+    Chrome,
+    Firefox,
+}
+
+impl FromStr for TlsFingerprint {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rustls" => Ok(Self::Rustls),
+            "chrome" => Ok(Self::Chrome),
+            "firefox" => Ok(Self::Firefox),
+            _ => Err(()),
+        }
+    }
+}
+
+// Only the suites rustls implements, the browsers also offer some cbc ones
+const CHROME_CIPHER_SUITES: &[CipherSuite] = &[
+    CipherSuite::TLS13_AES_128_GCM_SHA256,
+    CipherSuite::TLS13_AES_256_GCM_SHA384,
+    CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+];
+const FIREFOX_CIPHER_SUITES: &[CipherSuite] = &[
+    CipherSuite::TLS13_AES_128_GCM_SHA256,
+    CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+    CipherSuite::TLS13_AES_256_GCM_SHA384,
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+];
+const CHROME_KX_GROUPS: &[NamedGroup] = &[
+    NamedGroup::X25519MLKEM768,
+    NamedGroup::X25519,
+    NamedGroup::secp256r1,
+    NamedGroup::secp384r1,
+];
+const FIREFOX_KX_GROUPS: &[NamedGroup] = &[
+    NamedGroup::X25519MLKEM768,
+    NamedGroup::X25519,
+    NamedGroup::secp256r1,
+    NamedGroup::secp384r1,
+    NamedGroup::secp521r1,
+];
+
+impl TlsFingerprint {
+    /// Keep the cipher suites and key exchange groups of the provider the browser offers, in the order of the browser
+    pub fn apply(self, mut provider: CryptoProvider) -> CryptoProvider {
+        let (cipher_suites, kx_groups) = match self {
+            Self::Rustls => return provider,
+            Self::Chrome => (CHROME_CIPHER_SUITES, CHROME_KX_GROUPS),
+            Self::Firefox => (FIREFOX_CIPHER_SUITES, FIREFOX_KX_GROUPS),
+        };
+
+        provider
+            .cipher_suites
+            .retain(|suite| cipher_suites.contains(&suite.suite()));
+        provider
+            .cipher_suites
+            .sort_by_key(|suite| cipher_suites.iter().position(|s| *s == suite.suite()));
+        provider.kx_groups.retain(|group| kx_groups.contains(&group.name()));
+        provider
+            .kx_groups
+            .sort_by_key(|group| kx_groups.iter().position(|g| *g == group.name()));
+        provider
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_order() {
+        #[cfg(feature = "aws-lc-rs")]
+        let provider = tokio_rustls::rustls::crypto::aws_lc_rs::default_provider();
+        #[cfg(not(feature = "aws-lc-rs"))]
+        let provider = tokio_rustls::rustls::crypto::ring::default_provider();
+
+        let rustls = TlsFingerprint::Rustls.apply(provider.clone());
+        assert_eq!(rustls.cipher_suites.len(), provider.cipher_suites.len());
+
+        let firefox = TlsFingerprint::Firefox.apply(provider.clone());
+        let suites: Vec<_> = firefox.cipher_suites.iter().map(|suite| suite.suite()).collect();
+        assert_eq!(
+            suites[..3],
+            [
+                CipherSuite::TLS13_AES_128_GCM_SHA256,
+                CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                CipherSuite::TLS13_AES_256_GCM_SHA384
+            ]
+        );
+
+        let chrome = TlsFingerprint::Chrome.apply(provider);
+        let suites: Vec<_> = chrome.cipher_suites.iter().map(|suite| suite.suite()).collect();
+        assert_eq!(suites[1], CipherSuite::TLS13_AES_256_GCM_SHA384);
+        let groups: Vec<_> = chrome.kx_groups.iter().map(|group| group.name()).collect();
+        assert!(groups.contains(&NamedGroup::X25519));
+        assert!(groups.is_sorted_by_key(|group| CHROME_KX_GROUPS.iter().position(|g| g == group)));
+    }
+}
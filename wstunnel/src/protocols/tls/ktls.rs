@@ -0,0 +1,897 @@
+//! kTLS, the kernel encrypting and decrypting the data of the TLS connections of the clients once rustls did the
+//! handshake, saving a copy of every byte of the tunnels. The acceptor only offers the ciphers the kernel supports, so
+//! almost every handshake done for kTLS can be handed over to it. The others stay with rustls.
+//!
+//! The kernel only knows the current keys of a connection. To follow the key updates of TLS 1.3, the traffic secrets
+//! are logged during the handshake and the next keys derived from them, which needs linux 6.14 or later. On older
+//! kernels, the TLS 1.3 connections stay with rustls
+use super::ServerTlsStream;
+use bytes::Bytes;
+use nix::libc;
+use nix::sys::socket::sockopt::{TcpTlsRx, TcpTlsTx, TcpUlp, TlsCryptoInfo};
+use nix::sys::socket::{ControlMessageOwned, MsgFlags, TlsGetRecordType, recvmsg, setsockopt};
+use std::io::{IoSlice, IoSliceMut, Read};
+use std::net::Ipv4Addr;
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll, ready};
+use std::{cmp, io, mem};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, Interest, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::crypto::tls13::{Hkdf, OkmBlock};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::ServerConnection;
+use tokio_rustls::rustls::{
+    CipherSuite, ConnectionTrafficSecrets, KeyLog, ProtocolVersion, ServerConfig, SupportedCipherSuite,
+};
+use tracing::debug;
+
+const TLS_HEADER_LEN: usize = 5;
+const ALERT_RECORD_TYPE: u8 = 21;
+const ALERT_LEVEL_WARNING: u8 = 1;
+const ALERT_CLOSE_NOTIFY: u8 = 0;
+const HANDSHAKE_RECORD_TYPE: u8 = 22;
+const HANDSHAKE_HEADER_LEN: usize = 4;
+const KEY_UPDATE: u8 = 24;
+const UPDATE_REQUESTED: u8 = 1;
+const CLIENT_TRAFFIC_SECRET: &str = "CLIENT_TRAFFIC_SECRET_0";
+const SERVER_TRAFFIC_SECRET: &str = "SERVER_TRAFFIC_SECRET_0";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Cipher {
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20Poly1305,
+}
+
+impl Cipher {
+    const fn key_len(self) -> usize {
+        match self {
+            Self::Aes128Gcm => 16,
+            Self::Aes256Gcm | Self::Chacha20Poly1305 => 32,
+        }
+    }
+
+    fn of(suite: CipherSuite) -> Option<Self> {
+        match suite {
+            CipherSuite::TLS13_AES_128_GCM_SHA256
+            | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+            | CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 => Some(Self::Aes128Gcm),
+            CipherSuite::TLS13_AES_256_GCM_SHA384
+            | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+            | CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384 => Some(Self::Aes256Gcm),
+            CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
+            | CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+            | CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256 => Some(Self::Chacha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+fn kernel_version(version: ProtocolVersion) -> Option<u16> {
+    match version {
+        ProtocolVersion::TLSv1_2 => Some(libc::TLS_1_2_VERSION),
+        ProtocolVersion::TLSv1_3 => Some(libc::TLS_1_3_VERSION),
+        _ => None,
+    }
+}
+
+/// TLS versions and ciphers the kernel can take over, found once by setting them on a loopback connection
+static SUPPORTED: LazyLock<Vec<(u16, Cipher)>> = LazyLock::new(|| {
+    let mut supported = vec![];
+    for version in [libc::TLS_1_2_VERSION, libc::TLS_1_3_VERSION] {
+        for cipher in [Cipher::Aes128Gcm, Cipher::Aes256Gcm, Cipher::Chacha20Poly1305] {
+            match probe(version, cipher, false) {
+                Ok(()) => supported.push((version, cipher)),
+                Err(err) => debug!("kTLS does not support {cipher:?} with TLS version {version:#x}: {err}"),
+            }
+        }
+    }
+    supported
+});
+
+/// Whether the kernel can be given the next keys of a TLS 1.3 connection, since linux 6.14
+static REKEY_SUPPORTED: LazyLock<bool> =
+    LazyLock::new(|| match probe(libc::TLS_1_3_VERSION, Cipher::Aes128Gcm, true) {
+        Ok(()) => true,
+        Err(err) => {
+            debug!("kTLS cannot update the keys of TLS 1.3 connections: {err}");
+            false
+        }
+    });
+
+fn probe(version: u16, cipher: Cipher, rekey: bool) -> io::Result<()> {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let stream = std::net::TcpStream::connect(listener.local_addr()?)?;
+    let _peer = listener.accept()?;
+    setsockopt(&stream, TcpUlp::default(), &b"tls")?;
+    let crypto_info = crypto_info(version, cipher, &[0; 32][..cipher.key_len()], &[0; 12], 0)?;
+    setsockopt(&stream, TcpTlsTx, &crypto_info)?;
+    setsockopt(&stream, TcpTlsRx, &crypto_info)?;
+    if rekey {
+        setsockopt(&stream, TcpTlsTx, &crypto_info)?;
+    }
+    Ok(())
+}
+
+/// `provider` restricted to the ciphers the kernel can take over, None if it supports none of them
+pub fn kernel_crypto_provider(provider: &CryptoProvider) -> Option<CryptoProvider> {
+    let is_supported = |suite: &SupportedCipherSuite| {
+        let version = kernel_version(suite.version().version);
+        let cipher = Cipher::of(suite.suite());
+        version
+            .zip(cipher)
+            .is_some_and(|supported| SUPPORTED.contains(&supported))
+    };
+
+    let mut provider = provider.clone();
+    provider.cipher_suites.retain(is_supported);
+    if provider.cipher_suites.is_empty() {
+        return None;
+    }
+    Some(provider)
+}
+
+/// Attach the TLS upper layer protocol to the socket of a client before its handshake. The socket behaves as usual until
+/// the keys are given to the kernel, so the connection can stay with rustls if it fails
+pub fn enable_ulp(stream: &TcpStream) -> io::Result<()> {
+    setsockopt(stream, TcpUlp::default(), &b"tls")?;
+    Ok(())
+}
+
+fn crypto_info(version: u16, cipher: Cipher, key: &[u8], iv: &[u8], seq: u64) -> io::Result<TlsCryptoInfo> {
+    fn array<const N: usize>(bytes: &[u8]) -> io::Result<[u8; N]> {
+        bytes
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid length of the TLS secrets"))
+    }
+
+    let rec_seq = seq.to_be_bytes();
+    // The kernel wants the implicit part of the nonce of AES-GCM apart, as the salt
+    let crypto_info = match cipher {
+        Cipher::Aes128Gcm => TlsCryptoInfo::Aes128Gcm(libc::tls12_crypto_info_aes_gcm_128 {
+            info: libc::tls_crypto_info {
+                version,
+                cipher_type: libc::TLS_CIPHER_AES_GCM_128,
+            },
+            iv: array(iv.get(4..).unwrap_or_default())?,
+            key: array(key)?,
+            salt: array(iv.get(..4).unwrap_or_default())?,
+            rec_seq,
+        }),
+        Cipher::Aes256Gcm => TlsCryptoInfo::Aes256Gcm(libc::tls12_crypto_info_aes_gcm_256 {
+            info: libc::tls_crypto_info {
+                version,
+                cipher_type: libc::TLS_CIPHER_AES_GCM_256,
+            },
+            iv: array(iv.get(4..).unwrap_or_default())?,
+            key: array(key)?,
+            salt: array(iv.get(..4).unwrap_or_default())?,
+            rec_seq,
+        }),
+        Cipher::Chacha20Poly1305 => TlsCryptoInfo::Chacha20Poly1305(libc::tls12_crypto_info_chacha20_poly1305 {
+            info: libc::tls_crypto_info {
+                version,
+                cipher_type: libc::TLS_CIPHER_CHACHA20_POLY1305,
+            },
+            iv: array(iv)?,
+            key: array(key)?,
+            salt: [],
+            rec_seq,
+        }),
+    };
+    Ok(crypto_info)
+}
+
+fn traffic_crypto_info(version: u16, seq: u64, secrets: &ConnectionTrafficSecrets) -> io::Result<TlsCryptoInfo> {
+    match secrets {
+        ConnectionTrafficSecrets::Aes128Gcm { key, iv } => {
+            crypto_info(version, Cipher::Aes128Gcm, key.as_ref(), iv.as_ref(), seq)
+        }
+        ConnectionTrafficSecrets::Aes256Gcm { key, iv } => {
+            crypto_info(version, Cipher::Aes256Gcm, key.as_ref(), iv.as_ref(), seq)
+        }
+        ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => {
+            crypto_info(version, Cipher::Chacha20Poly1305, key.as_ref(), iv.as_ref(), seq)
+        }
+        _ => Err(io::Error::new(io::ErrorKind::Unsupported, "cipher not supported by kTLS")),
+    }
+}
+
+/// HKDF-Expand-Label of TLS 1.3 without context, RFC 8446 section 7.1
+fn expand_label(hkdf: &dyn Hkdf, secret: &[u8], label: &[u8], out: &mut [u8]) -> io::Result<()> {
+    const PREFIX: &[u8] = b"tls13 ";
+    let invalid_length = || io::Error::new(io::ErrorKind::InvalidInput, "invalid length of the TLS secrets");
+    let len = u16::try_from(out.len()).map_err(|_| invalid_length())?;
+    let label_len = [(PREFIX.len() + label.len()) as u8];
+    hkdf.expander_for_okm(&OkmBlock::new(secret))
+        .expand_slice(&[&len.to_be_bytes(), &label_len, PREFIX, label, &[0]], out)
+        .map_err(|_| invalid_length())
+}
+
+/// Traffic secrets of TLS 1.3 of one connection, logged by rustls during its handshake along with the key log of the
+/// acceptor. The kernel derives nothing by itself, the next keys of a key update come from these secrets
+#[derive(Debug)]
+pub struct TrafficSecrets {
+    key_log: Arc<dyn KeyLog>,
+    client: parking_lot::Mutex<Option<Vec<u8>>>,
+    server: parking_lot::Mutex<Option<Vec<u8>>>,
+}
+
+impl KeyLog for TrafficSecrets {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        match label {
+            CLIENT_TRAFFIC_SECRET => *self.client.lock() = Some(secret.to_vec()),
+            SERVER_TRAFFIC_SECRET => *self.server.lock() = Some(secret.to_vec()),
+            _ => {}
+        }
+        if self.key_log.will_log(label) {
+            self.key_log.log(label, client_random, secret);
+        }
+    }
+
+    fn will_log(&self, label: &str) -> bool {
+        matches!(label, CLIENT_TRAFFIC_SECRET | SERVER_TRAFFIC_SECRET) || self.key_log.will_log(label)
+    }
+}
+
+/// Acceptor for one connection, logging its traffic secrets
+pub fn logging_traffic_secrets(acceptor: &TlsAcceptor) -> (TlsAcceptor, Arc<TrafficSecrets>) {
+    let secrets = Arc::new(TrafficSecrets {
+        key_log: acceptor.config().key_log.clone(),
+        client: parking_lot::Mutex::new(None),
+        server: parking_lot::Mutex::new(None),
+    });
+    let mut config = ServerConfig::clone(acceptor.config());
+    config.key_log = secrets.clone();
+    (TlsAcceptor::from(Arc::new(config)), secrets)
+}
+
+/// Keys of a TLS 1.3 connection to update, the kernel only knowing the current ones
+struct KeyUpdates {
+    hkdf: &'static dyn Hkdf,
+    cipher: Cipher,
+    rx_secret: Vec<u8>,
+    tx_secret: Vec<u8>,
+    /// Handshake message of the client not received entirely yet
+    handshake: Vec<u8>,
+    /// The client asked for our keys to be updated too, which must be done before sending more data
+    reply_pending: bool,
+}
+
+impl KeyUpdates {
+    fn traffic_key(&self, secret: &[u8]) -> io::Result<(Vec<u8>, [u8; 12])> {
+        let mut key = vec![0; self.cipher.key_len()];
+        let mut iv = [0; 12];
+        expand_label(self.hkdf, secret, b"key", &mut key)?;
+        expand_label(self.hkdf, secret, b"iv", &mut iv)?;
+        Ok((key, iv))
+    }
+
+    fn crypto_info(&self, secret: &[u8]) -> io::Result<TlsCryptoInfo> {
+        let (key, iv) = self.traffic_key(secret)?;
+        crypto_info(libc::TLS_1_3_VERSION, self.cipher, &key, &iv, 0)
+    }
+
+    fn next_secret(&self, secret: &[u8]) -> io::Result<Vec<u8>> {
+        let mut next = vec![0; secret.len()];
+        expand_label(self.hkdf, secret, b"traffic upd", &mut next)?;
+        Ok(next)
+    }
+
+    /// Handle the handshake records of the client, only a key update can come once the handshake is done
+    fn on_handshake(&mut self, stream: &TcpStream, data: &[u8]) -> io::Result<()> {
+        self.handshake.extend_from_slice(data);
+        while self.handshake.len() >= HANDSHAKE_HEADER_LEN {
+            let msg_type = self.handshake[0];
+            let len = u32::from_be_bytes([0, self.handshake[1], self.handshake[2], self.handshake[3]]);
+            if msg_type != KEY_UPDATE || len != 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected TLS handshake message {msg_type} after the handshake"),
+                ));
+            }
+            let Some(&request_update) = self.handshake.get(HANDSHAKE_HEADER_LEN) else {
+                break;
+            };
+            self.handshake.drain(..=HANDSHAKE_HEADER_LEN);
+
+            self.rx_secret = self.next_secret(&self.rx_secret)?;
+            setsockopt(stream, TcpTlsRx, &self.crypto_info(&self.rx_secret)?)?;
+            self.reply_pending |= request_update == UPDATE_REQUESTED;
+            debug!("TLS keys of the client updated");
+        }
+        Ok(())
+    }
+}
+
+/// Socket of a client during its TLS handshake. It only ever gives whole TLS records to rustls, so that rustls keeps no
+/// byte of the records following the handshake, they are for the kernel to decrypt
+pub struct RecordReader<S> {
+    stream: S,
+    header: [u8; TLS_HEADER_LEN],
+    header_len: usize,
+    body_left: usize,
+    passthrough: bool,
+}
+
+impl<S> RecordReader<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            header: [0; TLS_HEADER_LEN],
+            header_len: 0,
+            body_left: 0,
+            passthrough: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordReader<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // The connection stays with rustls, no need to read it record by record anymore
+        if this.passthrough {
+            return Pin::new(&mut this.stream).poll_read(cx, buf);
+        }
+        let left = if this.header_len < TLS_HEADER_LEN {
+            TLS_HEADER_LEN - this.header_len
+        } else {
+            this.body_left
+        };
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(cmp::min(left, buf.remaining())));
+        ready!(Pin::new(&mut this.stream).poll_read(cx, &mut limited))?;
+        let read = limited.filled();
+        let len = read.len();
+
+        if this.header_len < TLS_HEADER_LEN {
+            this.header[this.header_len..][..len].copy_from_slice(read);
+            this.header_len += len;
+            if this.header_len == TLS_HEADER_LEN {
+                this.body_left = u16::from_be_bytes([this.header[3], this.header[4]]) as usize;
+            }
+        } else {
+            this.body_left -= len;
+        }
+        if this.header_len == TLS_HEADER_LEN && this.body_left == 0 {
+            this.header_len = 0;
+        }
+        buf.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordReader<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// TLS version and cipher of the connection for the kernel, or why it cannot take the connection over. It is checked
+/// before extracting the secrets, past that rustls cannot keep the connection anymore
+fn kernel_cipher(conn: &ServerConnection, secrets: &TrafficSecrets) -> Result<(u16, Cipher), &'static str> {
+    let version = conn
+        .protocol_version()
+        .and_then(kernel_version)
+        .ok_or("TLS version not supported by kTLS")?;
+    let cipher = conn
+        .negotiated_cipher_suite()
+        .and_then(|suite| Cipher::of(suite.suite()))
+        .ok_or("cipher not supported by kTLS")?;
+    if !SUPPORTED.contains(&(version, cipher)) {
+        return Err("cipher not supported by the kernel");
+    }
+    if version == libc::TLS_1_3_VERSION {
+        if !*REKEY_SUPPORTED {
+            return Err("the kernel cannot update the keys of TLS 1.3");
+        }
+        if secrets.client.lock().is_none() || secrets.server.lock().is_none() {
+            return Err("traffic secrets of TLS 1.3 not logged");
+        }
+    }
+    Ok((version, cipher))
+}
+
+/// Hand the connection of a client over to the kernel, once rustls did the handshake. The connections the kernel cannot
+/// take over stay with rustls. Only a failure of the kernel once given the keys fails the connection
+pub async fn into_kernel(
+    mut tls: tokio_rustls::server::TlsStream<RecordReader<TcpStream>>,
+    secrets: &TrafficSecrets,
+) -> io::Result<ServerTlsStream> {
+    // The session tickets of TLS 1.3 are sent after the handshake, by rustls
+    tls.flush().await?;
+    let (version, cipher) = match kernel_cipher(tls.get_ref().1, secrets) {
+        Ok(kernel_cipher) => kernel_cipher,
+        Err(reason) => {
+            debug!("TLS connection kept with rustls: {reason}");
+            tls.get_mut().0.passthrough = true;
+            return Ok(ServerTlsStream::KtlsFallback(tls));
+        }
+    };
+    let (reader, mut conn) = tls.into_inner();
+
+    // The data the client sent along with the end of its handshake is already decrypted
+    let mut read_buf = Vec::new();
+    match conn.reader().read_to_end(&mut read_buf) {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+        Err(err) => return Err(err),
+    }
+
+    let alpn_protocol = conn.alpn_protocol().map(<[u8]>::to_vec);
+    let peer_certificates = conn.peer_certificates().map(<[_]>::to_vec);
+    let key_updates = match conn.negotiated_cipher_suite() {
+        Some(SupportedCipherSuite::Tls13(suite)) => Some(KeyUpdates {
+            hkdf: suite.hkdf_provider,
+            cipher,
+            rx_secret: secrets.client.lock().take().unwrap_or_default(),
+            tx_secret: secrets.server.lock().take().unwrap_or_default(),
+            handshake: Vec::new(),
+            reply_pending: false,
+        }),
+        _ => None,
+    };
+    let secrets = conn.dangerous_extract_secrets().map_err(io::Error::other)?;
+
+    // The logged secrets must be the ones of the keys, for the next keys to be right
+    if let Some(key_updates) = &key_updates {
+        let (rx_key, _) = key_updates.traffic_key(&key_updates.rx_secret)?;
+        let (tx_key, _) = key_updates.traffic_key(&key_updates.tx_secret)?;
+        if traffic_key(&secrets.rx.1) != Some(&rx_key[..]) || traffic_key(&secrets.tx.1) != Some(&tx_key[..]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "logged TLS traffic secrets do not match the keys of the connection",
+            ));
+        }
+    }
+
+    let stream = reader.stream;
+    setsockopt(&stream, TcpTlsTx, &traffic_crypto_info(version, secrets.tx.0, &secrets.tx.1)?)?;
+    setsockopt(&stream, TcpTlsRx, &traffic_crypto_info(version, secrets.rx.0, &secrets.rx.1)?)?;
+    debug!("TLS connection handed over to the kernel");
+
+    Ok(ServerTlsStream::Kernel(KtlsStream {
+        stream,
+        read_buf: Bytes::from(read_buf),
+        read_closed: false,
+        close_notify_sent: false,
+        key_updates,
+        alpn_protocol,
+        peer_certificates,
+    }))
+}
+
+fn traffic_key(secrets: &ConnectionTrafficSecrets) -> Option<&[u8]> {
+    match secrets {
+        ConnectionTrafficSecrets::Aes128Gcm { key, .. }
+        | ConnectionTrafficSecrets::Aes256Gcm { key, .. }
+        | ConnectionTrafficSecrets::Chacha20Poly1305 { key, .. } => Some(key.as_ref()),
+        _ => None,
+    }
+}
+
+/// TLS connection of a client, encrypted and decrypted by the kernel
+pub struct KtlsStream {
+    stream: TcpStream,
+    /// Data of the client decrypted by rustls, before the kernel took over
+    read_buf: Bytes,
+    read_closed: bool,
+    close_notify_sent: bool,
+    /// None with TLS 1.2, which has no key update
+    key_updates: Option<KeyUpdates>,
+    alpn_protocol: Option<Vec<u8>>,
+    peer_certificates: Option<Vec<CertificateDer<'static>>>,
+}
+
+impl KtlsStream {
+    pub fn tcp_stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.peer_certificates.as_deref()
+    }
+
+    /// Update our keys too when the client asked for it, before anything else is sent
+    fn poll_key_update_reply(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(key_updates) = &mut self.key_updates else {
+            return Poll::Ready(Ok(()));
+        };
+        while key_updates.reply_pending {
+            ready!(self.stream.poll_write_ready(cx))?;
+            let key_update = [KEY_UPDATE, 0, 0, 1, 0];
+            match self.stream.try_io(Interest::WRITABLE, || {
+                send_record(&self.stream, HANDSHAKE_RECORD_TYPE, &key_update)
+            }) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+            key_updates.reply_pending = false;
+            key_updates.tx_secret = key_updates.next_secret(&key_updates.tx_secret)?;
+            setsockopt(&self.stream, TcpTlsTx, &key_updates.crypto_info(&key_updates.tx_secret)?)?;
+            debug!("TLS keys of the server updated");
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Receive the data of one TLS record at most, with its type. Without asking for it, the kernel fails the reads of the
+/// records other than application data
+fn recv_record(stream: &TcpStream, buf: &mut [u8]) -> io::Result<(usize, TlsGetRecordType)> {
+    let mut cmsg = nix::cmsg_space!(u8);
+    let mut iov = [IoSliceMut::new(buf)];
+    let msg = recvmsg::<()>(stream.as_raw_fd(), &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+    let mut record_type = TlsGetRecordType::ApplicationData;
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::TlsGetRecordType(cmsg_record_type) = cmsg {
+            record_type = cmsg_record_type;
+        }
+    }
+    Ok((msg.bytes, record_type))
+}
+
+/// Send a record other than application data, the only one the kernel sends by itself, as for the close notify or the
+/// key updates
+fn send_record(stream: &TcpStream, record_type: u8, data: &[u8]) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr().cast_mut().cast(),
+        iov_len: data.len(),
+    };
+    // u64 to align the header of the control message
+    let mut cmsg_buf = [0u64; 4];
+    // Safety: the message only points to the buffers above, big enough for a control message of one byte. The data is
+    // only read by sendmsg
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+        msg.msg_controllen = libc::CMSG_SPACE(1) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_TLS;
+        (*cmsg).cmsg_type = libc::TLS_SET_RECORD_TYPE;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(1) as _;
+        *libc::CMSG_DATA(cmsg) = record_type;
+        if libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl AsyncRead for KtlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_buf.is_empty() {
+            let len = cmp::min(this.read_buf.len(), buf.remaining());
+            buf.put_slice(&this.read_buf.split_to(len));
+            return Poll::Ready(Ok(()));
+        }
+        if this.read_closed || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            ready!(this.stream.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            let (len, record_type) = match this
+                .stream
+                .try_io(Interest::READABLE, || recv_record(&this.stream, &mut *unfilled))
+            {
+                Ok(ret) => ret,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+
+            return match record_type {
+                TlsGetRecordType::ApplicationData => {
+                    buf.advance(len);
+                    Poll::Ready(Ok(()))
+                }
+                TlsGetRecordType::Alert => {
+                    this.read_closed = true;
+                    if unfilled[..len] == [ALERT_LEVEL_WARNING, ALERT_CLOSE_NOTIFY] {
+                        Poll::Ready(Ok(()))
+                    } else {
+                        Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            format!("TLS alert {:?} received from the client", &unfilled[..len]),
+                        )))
+                    }
+                }
+                TlsGetRecordType::Handshake => match &mut this.key_updates {
+                    Some(key_updates) => {
+                        key_updates.on_handshake(&this.stream, &unfilled[..len])?;
+                        continue;
+                    }
+                    None => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected TLS handshake message after the handshake",
+                    ))),
+                },
+                record_type => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected TLS record {record_type:?} after the handshake"),
+                ))),
+            };
+        }
+    }
+}
+
+impl AsyncWrite for KtlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_key_update_reply(cx))?;
+        Pin::new(&mut this.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_key_update_reply(cx))?;
+        while !this.close_notify_sent {
+            ready!(this.stream.poll_write_ready(cx))?;
+            let alert = [ALERT_LEVEL_WARNING, ALERT_CLOSE_NOTIFY];
+            match this
+                .stream
+                .try_io(Interest::WRITABLE, || send_record(&this.stream, ALERT_RECORD_TYPE, &alert))
+            {
+                Ok(()) => this.close_notify_sent = true,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_key_update_reply(cx))?;
+        Pin::new(&mut this.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedded_certificate;
+    use crate::protocols::tls::server::NullVerifier;
+    use tokio::io::AsyncReadExt;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::version::TLS13;
+
+    fn crypto_provider() -> CryptoProvider {
+        #[cfg(feature = "aws-lc-rs")]
+        let provider = tokio_rustls::rustls::crypto::aws_lc_rs::default_provider();
+        #[cfg(not(feature = "aws-lc-rs"))]
+        let provider = tokio_rustls::rustls::crypto::ring::default_provider();
+        provider
+    }
+
+    fn tls13(server_provider: CryptoProvider) -> (TlsAcceptor, TlsConnector) {
+        let mut server = ServerConfig::builder_with_provider(Arc::new(server_provider))
+            .with_protocol_versions(&[&TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                embedded_certificate::TLS_CERTIFICATE.0.clone(),
+                embedded_certificate::TLS_CERTIFICATE.1.clone_key(),
+            )
+            .unwrap();
+        server.enable_secret_extraction = true;
+
+        let mut client = ClientConfig::builder_with_provider(Arc::new(crypto_provider()))
+            .with_protocol_versions(&[&TLS13])
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NullVerifier))
+            .with_no_client_auth();
+        client.enable_secret_extraction = true;
+
+        (TlsAcceptor::from(Arc::new(server)), TlsConnector::from(Arc::new(client)))
+    }
+
+    #[tokio::test]
+    async fn test_record_reader_stops_at_record_boundaries() {
+        let handshake = [22, 3, 3, 0, 3, 1, 2, 3];
+        let application_data = [23, 3, 3, 0, 2, 9, 9];
+        let mut data = handshake.to_vec();
+        data.extend_from_slice(&application_data);
+        data.extend_from_slice(&[23, 3, 3]);
+
+        let mut reader = RecordReader::new(&data[..]);
+        let mut buf = [0; 64];
+        let mut read = vec![];
+        while read.len() < handshake.len() {
+            let len = reader.read(&mut buf).await.unwrap();
+            read.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(read, handshake);
+
+        // The next record is only given once asked for, then the partial one stays unread
+        let mut read = vec![];
+        loop {
+            let len = reader.read(&mut buf).await.unwrap();
+            if len == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(read, [&application_data[..], &[23, 3, 3]].concat());
+        assert_eq!(reader.header_len, 3);
+    }
+
+    #[test]
+    fn test_crypto_info() {
+        let iv: Vec<u8> = (0..12).collect();
+        let TlsCryptoInfo::Aes128Gcm(info) =
+            crypto_info(libc::TLS_1_3_VERSION, Cipher::Aes128Gcm, &[7; 16], &iv, 1).unwrap()
+        else {
+            panic!("not aes-128-gcm");
+        };
+        assert_eq!(info.salt, [0, 1, 2, 3]);
+        assert_eq!(info.iv, [4, 5, 6, 7, 8, 9, 10, 11]);
+        assert_eq!(info.rec_seq, [0, 0, 0, 0, 0, 0, 0, 1]);
+
+        let TlsCryptoInfo::Chacha20Poly1305(info) =
+            crypto_info(libc::TLS_1_2_VERSION, Cipher::Chacha20Poly1305, &[7; 32], &iv, 0).unwrap()
+        else {
+            panic!("not chacha20-poly1305");
+        };
+        assert_eq!(info.iv.as_slice(), iv.as_slice());
+
+        assert!(crypto_info(libc::TLS_1_3_VERSION, Cipher::Aes256Gcm, &[7; 16], &iv, 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_update_keys() {
+        let (acceptor, connector) = tls13(crypto_provider());
+        let (acceptor, secrets) = logging_traffic_secrets(&acceptor);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (client, server) = tokio::join!(
+            connector.connect(ServerName::try_from("localhost").unwrap(), client),
+            acceptor.accept(server)
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        let Some(SupportedCipherSuite::Tls13(suite)) = server.get_ref().1.negotiated_cipher_suite() else {
+            panic!("not TLS 1.3");
+        };
+        let key_updates = KeyUpdates {
+            hkdf: suite.hkdf_provider,
+            cipher: Cipher::of(suite.common.suite).unwrap(),
+            rx_secret: secrets.client.lock().clone().unwrap(),
+            tx_secret: secrets.server.lock().clone().unwrap(),
+            handshake: vec![],
+            reply_pending: false,
+        };
+
+        // The client updates its keys and asks for the ones of the server to be updated too
+        client.get_mut().1.refresh_traffic_keys().unwrap();
+        client.write_all(b"ping").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"pong").await.unwrap();
+        server.flush().await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+
+        let client_secrets = client.into_inner().1.dangerous_extract_secrets().unwrap();
+        let server_secrets = server.into_inner().1.dangerous_extract_secrets().unwrap();
+        let (rx_key, _) = key_updates
+            .traffic_key(&key_updates.next_secret(&key_updates.rx_secret).unwrap())
+            .unwrap();
+        let (tx_key, _) = key_updates
+            .traffic_key(&key_updates.next_secret(&key_updates.tx_secret).unwrap())
+            .unwrap();
+        assert_eq!(traffic_key(&client_secrets.tx.1), Some(&rx_key[..]));
+        assert_eq!(traffic_key(&server_secrets.rx.1), Some(&rx_key[..]));
+        assert_eq!(traffic_key(&server_secrets.tx.1), Some(&tx_key[..]));
+        assert_eq!(traffic_key(&client_secrets.rx.1), Some(&tx_key[..]));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_to_rustls() {
+        let (acceptor, connector) = tls13(crypto_provider());
+        // Nothing is logged by the acceptor, without kTLS nothing could be handed over to the kernel anyway
+        let (_, secrets) = logging_traffic_secrets(&acceptor);
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (client, server) = tokio::join!(
+            connector.connect(ServerName::try_from("localhost").unwrap(), client),
+            acceptor.accept(RecordReader::new(server))
+        );
+        let mut client = client.unwrap();
+        let mut server = into_kernel(server.unwrap(), &secrets).await.unwrap();
+        assert!(matches!(server, ServerTlsStream::KtlsFallback(_)));
+
+        let mut buf = [0; 5];
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        server.write_all(b"world").await.unwrap();
+        server.flush().await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn test_handover_to_kernel() {
+        let Some(provider) = kernel_crypto_provider(&crypto_provider()) else {
+            // The tls module of the kernel is missing
+            return;
+        };
+        let (acceptor, connector) = tls13(provider);
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (client, server) = tokio::join!(
+            connector.connect(ServerName::try_from("localhost").unwrap(), client),
+            crate::protocols::tls::accept(&acceptor, server)
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        if *REKEY_SUPPORTED {
+            assert!(matches!(server, ServerTlsStream::Kernel(_)));
+        } else {
+            assert!(matches!(server, ServerTlsStream::KtlsFallback(_)));
+        }
+
+        let mut buf = [0; 5];
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        server.write_all(b"world").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        // The keys are updated in both directions, the tunnel going on with them
+        client.get_mut().1.refresh_traffic_keys().unwrap();
+        client.write_all(b"again").await.unwrap();
+        client.flush().await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"again");
+        server.write_all(b"reply").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"reply");
+
+        server.shutdown().await.unwrap();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
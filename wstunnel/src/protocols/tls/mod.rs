@@ -1,10 +1,21 @@
+mod client_hello;
+mod fingerprint;
+#[cfg(target_os = "linux")]
+mod ktls;
+mod provider;
 mod server;
+mod stream;
 mod utils;
 
+pub use client_hello::{ClientHelloServerName, MAX_RECORD_LEN, client_hello_server_name};
+pub use fingerprint::TlsFingerprint;
+pub use provider::CryptoProviderKind;
+pub(crate) use provider::handshake_done;
 pub use server::connect;
 pub use server::load_certificates_from_pem;
 pub use server::load_private_key_from_file;
 pub use server::tls_acceptor;
 pub use server::tls_connector;
+pub use stream::{ServerTlsStream, accept};
 pub use utils::cn_from_certificate;
 pub use utils::find_leaf_certificate;
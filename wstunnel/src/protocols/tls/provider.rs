@@ -0,0 +1,112 @@
+use crate::metrics::METRICS;
+use anyhow::anyhow;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Instant;
+use tokio_rustls::rustls::CommonState;
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tracing::{debug, info};
+
+/// Crypto library doing the TLS of the tunnels. aws-lc-rs has assembly tuned for the AES and SHA extensions of the
+/// recent cpus (i.e: ARMv8 gateways), ring is smaller and simpler to build
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CryptoProviderKind {
+    AwsLcRs,
+    Ring,
+}
+
+impl CryptoProviderKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::AwsLcRs => "aws-lc-rs",
+            Self::Ring => "ring",
+        }
+    }
+
+    /// The provider, if its feature is compiled in
+    pub fn provider(self) -> anyhow::Result<CryptoProvider> {
+        match self {
+            #[cfg(feature = "aws-lc-rs")]
+            Self::AwsLcRs => Ok(tokio_rustls::rustls::crypto::aws_lc_rs::default_provider()),
+            #[cfg(feature = "ring")]
+            Self::Ring => Ok(tokio_rustls::rustls::crypto::ring::default_provider()),
+            #[allow(unreachable_patterns)]
+            kind => Err(anyhow!("wstunnel is built without the {kind} crypto provider")),
+        }
+    }
+
+    /// Use the provider for all the TLS connections of the process. To be called once, before any of them is made
+    pub fn install(self) -> anyhow::Result<()> {
+        let provider = self.provider()?;
+        let cipher_suites = provider
+            .cipher_suites
+            .iter()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .collect::<Vec<_>>();
+        provider
+            .install_default()
+            .map_err(|_| anyhow!("Cannot use the {self} crypto provider, another one is already installed"))?;
+        info!(
+            "Using the {self} crypto provider, with cipher suites {}",
+            cipher_suites.join(", ")
+        );
+        Ok(())
+    }
+}
+
+impl Default for CryptoProviderKind {
+    /// aws-lc-rs when it is compiled in, as it is the faster one
+    fn default() -> Self {
+        if cfg!(feature = "aws-lc-rs") {
+            Self::AwsLcRs
+        } else {
+            Self::Ring
+        }
+    }
+}
+
+impl Display for CryptoProviderKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CryptoProviderKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aws-lc-rs" => Ok(Self::AwsLcRs),
+            "ring" => Ok(Self::Ring),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Log the cipher suite negotiated by a TLS handshake started at `started`, and account its duration in the metrics,
+/// to compare the cost of the suites and of the crypto providers
+pub(crate) fn handshake_done(conn: &CommonState, started: Instant) {
+    let Some(suite) = conn.negotiated_cipher_suite() else {
+        return;
+    };
+    let elapsed = started.elapsed();
+    let suite = format!("{:?}", suite.suite());
+    debug!("TLS handshake done in {elapsed:?} with {suite}");
+    METRICS.tls_handshake_done(&suite, elapsed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crypto_provider_kind() {
+        assert_eq!("ring".parse(), Ok(CryptoProviderKind::Ring));
+        assert_eq!("aws-lc-rs".parse(), Ok(CryptoProviderKind::AwsLcRs));
+        assert_eq!("openssl".parse::<CryptoProviderKind>(), Err(()));
+        assert_eq!(CryptoProviderKind::AwsLcRs.to_string(), "aws-lc-rs");
+        assert_eq!(CryptoProviderKind::AwsLcRs.provider().is_ok(), cfg!(feature = "aws-lc-rs"));
+        assert_eq!(CryptoProviderKind::Ring.provider().is_ok(), cfg!(feature = "ring"));
+        assert!(CryptoProviderKind::default().provider().is_ok());
+    }
+}
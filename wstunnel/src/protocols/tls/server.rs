@@ -6,9 +6,11 @@ use log::warn;
 use std::io::BufReader;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::net::TcpStream;
 use tokio_rustls::client::TlsStream;
 
+use crate::protocols::tls::{TlsFingerprint, handshake_done};
 use crate::tunnel::client::WsClientConfig;
 use crate::tunnel::server::TlsServerConfig;
 use crate::tunnel::transport::TransportAddr;
@@ -20,7 +22,7 @@ use tokio_rustls::{TlsAcceptor, TlsConnector, rustls};
 use tracing::info;
 
 #[derive(Debug)]
-struct NullVerifier;
+pub(super) struct NullVerifier;
 
 impl ServerCertVerifier for NullVerifier {
     fn verify_server_cert(
@@ -107,6 +109,7 @@ pub fn tls_connector(
     tls_verify_certificate: bool,
     alpn_protocols: Vec<Vec<u8>>,
     enable_sni: bool,
+    tls_fingerprint: TlsFingerprint,
     ech_config: Option<EchConfig>,
     tls_client_certificate: Option<Vec<CertificateDer<'static>>>,
     tls_client_key: Option<PrivateKeyDer<'static>>,
@@ -125,7 +128,8 @@ pub fn tls_connector(
         }
     }
 
-    let crypto_provider = ClientConfig::builder().crypto_provider().clone();
+    let crypto_provider = ClientConfig::builder().crypto_provider().as_ref().clone();
+    let crypto_provider = Arc::new(tls_fingerprint.apply(crypto_provider));
     let config_builder = ClientConfig::builder_with_provider(crypto_provider);
     let config_builder = if let Some(ech_config) = ech_config {
         info!("Using TLS ECH (encrypted sni) with config: {:?}", ech_config);
@@ -171,26 +175,64 @@ pub fn tls_acceptor(tls_cfg: &TlsServerConfig, alpn_protocols: Option<Vec<Vec<u8
         WebPkiClientVerifier::no_client_auth()
     };
 
-    let mut config = rustls::ServerConfig::builder()
+    let builder = rustls::ServerConfig::builder();
+    #[cfg(target_os = "linux")]
+    let kernel_provider = if tls_cfg.ktls {
+        let provider = super::ktls::kernel_crypto_provider(builder.crypto_provider());
+        if provider.is_none() {
+            warn!("kTLS is not supported by the kernel, the TLS connections stay encrypted by wstunnel");
+        }
+        provider
+    } else {
+        None
+    };
+    #[cfg(not(target_os = "linux"))]
+    let kernel_provider: Option<rustls::crypto::CryptoProvider> = None;
+    #[cfg(not(target_os = "linux"))]
+    if tls_cfg.ktls {
+        warn!("kTLS is only supported on linux, the TLS connections stay encrypted by wstunnel");
+    }
+
+    let enable_secret_extraction = kernel_provider.is_some();
+    let builder = match kernel_provider {
+        Some(provider) => rustls::ServerConfig::builder_with_provider(Arc::new(provider))
+            .with_safe_default_protocol_versions()
+            .with_context(|| "no TLS version supported by kTLS")?,
+        None => builder,
+    };
+    let mut config = builder
         .with_client_cert_verifier(client_cert_verifier)
         .with_single_cert(tls_cfg.tls_certificate.lock().clone(), tls_cfg.tls_key.lock().clone_key())
         .with_context(|| "invalid tls certificate or private key")?;
 
     config.key_log = Arc::new(KeyLogFile::new());
+    // The keys are handed over to the kernel after the handshake, see [`super::accept`]
+    config.enable_secret_extraction = enable_secret_extraction;
     if let Some(alpn_protocols) = alpn_protocols {
         config.alpn_protocols = alpn_protocols;
     }
     Ok(TlsAcceptor::from(Arc::new(config)))
 }
 
-pub async fn connect(client_cfg: &WsClientConfig, tcp_stream: TcpStream) -> anyhow::Result<TlsStream<TcpStream>> {
-    let sni = client_cfg.tls_server_name();
+pub async fn connect(
+    client_cfg: &WsClientConfig,
+    tcp_stream: TcpStream,
+    sni: ServerName<'static>,
+) -> anyhow::Result<TlsStream<TcpStream>> {
     let tls_config = match &client_cfg.remote_addr {
         TransportAddr::Wss { tls, .. } => tls,
         TransportAddr::Https { tls, .. } => tls,
         TransportAddr::Http { .. } | TransportAddr::Ws { .. } => {
             return Err(anyhow!("Transport does not support TLS: {}", client_cfg.remote_addr.scheme()));
         }
+        #[cfg(feature = "dns-transport")]
+        TransportAddr::Dns { .. } => {
+            return Err(anyhow!("Transport does not support TLS: {}", client_cfg.remote_addr.scheme()));
+        }
+        #[cfg(feature = "icmp-transport")]
+        TransportAddr::Icmp { .. } => {
+            return Err(anyhow!("Transport does not support TLS: {}", client_cfg.remote_addr.scheme()));
+        }
     };
 
     if tls_config.tls_sni_disabled {
@@ -208,7 +250,9 @@ pub async fn connect(client_cfg: &WsClientConfig, tcp_stream: TcpStream) -> anyh
     }
 
     let tls_connector = tls_config.tls_connector();
+    let started = Instant::now();
     let tls_stream = tls_connector.connect(sni, tcp_stream).await?;
+    handshake_done(tls_stream.get_ref().1, started);
 
     Ok(tls_stream)
 }
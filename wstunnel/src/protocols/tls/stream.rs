@@ -0,0 +1,154 @@
+use super::handshake_done;
+#[cfg(target_os = "linux")]
+use super::ktls;
+use std::io;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+#[cfg(target_os = "linux")]
+use tracing::debug;
+
+/// TLS connection of a client of the server, encrypted by rustls, or by the kernel once the handshake is done (kTLS)
+#[allow(clippy::large_enum_variant)] // Boxing the rustls variant would cost the common case an indirection
+pub enum ServerTlsStream {
+    Rustls(tokio_rustls::server::TlsStream<TcpStream>),
+    #[cfg(target_os = "linux")]
+    Kernel(ktls::KtlsStream),
+    /// Handshake done for kTLS, but the kernel cannot take the connection over so it stays with rustls
+    #[cfg(target_os = "linux")]
+    KtlsFallback(tokio_rustls::server::TlsStream<ktls::RecordReader<TcpStream>>),
+}
+
+/// Do the TLS handshake with a client. When the acceptor is set up for kTLS, the connection is handed over to the kernel
+/// afterwards, unless the kernel cannot take it
+pub async fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> io::Result<ServerTlsStream> {
+    let started = Instant::now();
+    // Secret extraction is only enabled on the acceptors for kTLS, see [`super::tls_acceptor`]
+    #[cfg(target_os = "linux")]
+    if acceptor.config().enable_secret_extraction {
+        match ktls::enable_ulp(&stream) {
+            Ok(()) => {
+                let (acceptor, secrets) = ktls::logging_traffic_secrets(acceptor);
+                let tls = acceptor.accept(ktls::RecordReader::new(stream)).await?;
+                handshake_done(tls.get_ref().1, started);
+                return ktls::into_kernel(tls, &secrets).await;
+            }
+            Err(err) => debug!("Cannot use kTLS for the connection, keeping it with rustls: {err}"),
+        }
+    }
+
+    let tls = acceptor.accept(stream).await?;
+    handshake_done(tls.get_ref().1, started);
+    Ok(ServerTlsStream::Rustls(tls))
+}
+
+impl ServerTlsStream {
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            Self::Rustls(tls) => tls.get_ref().0,
+            #[cfg(target_os = "linux")]
+            Self::Kernel(tls) => tls.tcp_stream(),
+            #[cfg(target_os = "linux")]
+            Self::KtlsFallback(tls) => tls.get_ref().0.get_ref(),
+        }
+    }
+
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            Self::Rustls(tls) => tls.get_ref().1.alpn_protocol(),
+            #[cfg(target_os = "linux")]
+            Self::Kernel(tls) => tls.alpn_protocol(),
+            #[cfg(target_os = "linux")]
+            Self::KtlsFallback(tls) => tls.get_ref().1.alpn_protocol(),
+        }
+    }
+
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        match self {
+            Self::Rustls(tls) => tls.get_ref().1.peer_certificates(),
+            #[cfg(target_os = "linux")]
+            Self::Kernel(tls) => tls.peer_certificates(),
+            #[cfg(target_os = "linux")]
+            Self::KtlsFallback(tls) => tls.get_ref().1.peer_certificates(),
+        }
+    }
+}
+
+impl AsyncRead for ServerTlsStream {
+    #[inline]
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Rustls(tls) => Pin::new(tls).poll_read(cx, buf),
+            #[cfg(target_os = "linux")]
+            Self::Kernel(tls) => Pin::new(tls).poll_read(cx, buf),
+            #[cfg(target_os = "linux")]
+            Self::KtlsFallback(tls) => Pin::new(tls).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerTlsStream {
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Rustls(tls) => Pin::new(tls).poll_write(cx, buf),
+            #[cfg(target_os = "linux")]
+            Self::Kernel(tls) => Pin::new(tls).poll_write(cx, buf),
+            #[cfg(target_os = "linux")]
+            Self::KtlsFallback(tls) => Pin::new(tls).poll_write(cx, buf),
+        }
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Rustls(tls) => Pin::new(tls).poll_flush(cx),
+            #[cfg(target_os = "linux")]
+            Self::Kernel(tls) => Pin::new(tls).poll_flush(cx),
+            #[cfg(target_os = "linux")]
+            Self::KtlsFallback(tls) => Pin::new(tls).poll_flush(cx),
+        }
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Rustls(tls) => Pin::new(tls).poll_shutdown(cx),
+            #[cfg(target_os = "linux")]
+            Self::Kernel(tls) => Pin::new(tls).poll_shutdown(cx),
+            #[cfg(target_os = "linux")]
+            Self::KtlsFallback(tls) => Pin::new(tls).poll_shutdown(cx),
+        }
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Rustls(tls) => Pin::new(tls).poll_write_vectored(cx, bufs),
+            #[cfg(target_os = "linux")]
+            Self::Kernel(tls) => Pin::new(tls).poll_write_vectored(cx, bufs),
+            #[cfg(target_os = "linux")]
+            Self::KtlsFallback(tls) => Pin::new(tls).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Rustls(tls) => tls.is_write_vectored(),
+            #[cfg(target_os = "linux")]
+            Self::Kernel(tls) => tls.is_write_vectored(),
+            #[cfg(target_os = "linux")]
+            Self::KtlsFallback(tls) => tls.is_write_vectored(),
+        }
+    }
+}
@@ -13,6 +13,7 @@ use tokio::task::JoinSet;
 use log::warn;
 use socket2::SockRef;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Weak};
 use std::task::{Poll, ready};
 use std::time::Duration;
@@ -20,26 +21,46 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::UdpSocket;
 use tokio::sync::futures::Notified;
 
+use crate::metrics::{METRICS, Metrics};
 use crate::protocols::dns::DnsResolver;
 use crate::somark::SoMark;
+use crate::source_bind::SourceBind;
+use crate::tunnel::UdpFlowEviction;
+use crate::tunnel::transport::io::Resettable;
 use tokio::sync::Notify;
-use tokio::time::{Interval, sleep, timeout};
+use tokio::time::{Instant, Interval, sleep, timeout};
 use tracing::{debug, error, info};
 use url::Host;
 
 struct IoInner {
     has_data_to_read: Notify,
     has_read_data: Notify,
+    evicted: AtomicBool,
 }
+
+struct UdpPeer {
+    io: Pin<Arc<IoInner>>,
+    last_seen: Instant,
+}
+
 struct UdpServer {
     listener: Arc<UdpSocket>,
-    peers: HashMap<SocketAddr, Pin<Arc<IoInner>>, ahash::RandomState>,
+    peers: HashMap<SocketAddr, UdpPeer, ahash::RandomState>,
     keys_to_delete: Arc<RwLock<Vec<SocketAddr>>>,
     cnx_timeout: Option<Duration>,
+    max_flows: Option<usize>,
+    flow_eviction: UdpFlowEviction,
+    /// Packets dropped since max_flows was reached, logged once the server accepts new flows again
+    dropped_packets: u64,
 }
 
 impl UdpServer {
-    pub fn new(listener: UdpSocket, timeout: Option<Duration>) -> Self {
+    pub fn new(
+        listener: UdpSocket,
+        timeout: Option<Duration>,
+        max_flows: Option<usize>,
+        flow_eviction: UdpFlowEviction,
+    ) -> Self {
         let socket = SockRef::from(&listener);
 
         // Increase receive buffer
@@ -80,6 +101,9 @@ impl UdpServer {
             peers: HashMap::with_hasher(ahash::RandomState::new()),
             keys_to_delete: Default::default(),
             cnx_timeout: timeout,
+            max_flows,
+            flow_eviction,
+            dropped_packets: 0,
         }
     }
 
@@ -100,6 +124,46 @@ impl UdpServer {
     pub fn clone_socket(&self) -> Arc<UdpSocket> {
         self.listener.clone()
     }
+
+    /// Drop the packet of a new peer, warning only for the first one until a new flow gets accepted
+    async fn drop_new_flow_packet(&mut self, peer: SocketAddr) {
+        if self.dropped_packets == 0 {
+            warn!(
+                "Max number of {} UDP flows reached, dropping the packets of new peers like {peer} until a flow closes",
+                self.max_flows.unwrap_or_default()
+            );
+        }
+        self.dropped_packets += 1;
+        Metrics::inc(&METRICS.udp_packets_dropped);
+
+        // Consume the datagram, else we are going to peek it forever
+        let _ = self.listener.recv_from(&mut [0u8; 0]).await;
+    }
+
+    /// Returns true if there is room for a new flow, evicting the idlest one if the policy allows it
+    fn make_room_for_new_flow(&mut self) -> bool {
+        let Some(max_flows) = self.max_flows else {
+            return true;
+        };
+
+        let active_flows = || self.peers.values().filter(|p| !p.io.evicted.load(Ordering::Relaxed));
+        if active_flows().count() < max_flows {
+            return true;
+        }
+
+        match self.flow_eviction {
+            UdpFlowEviction::DropNew => false,
+            UdpFlowEviction::EvictIdlest => {
+                let Some(idlest) = active_flows().min_by_key(|p| p.last_seen) else {
+                    return false;
+                };
+                // The stream is going to close itself on its next read, and its key cleaned on drop
+                idlest.io.evicted.store(true, Ordering::Relaxed);
+                idlest.io.has_data_to_read.notify_one();
+                true
+            }
+        }
+    }
 }
 
 #[pin_project(PinnedDrop)]
@@ -145,6 +209,7 @@ impl UdpStream {
         let io = Arc::pin(IoInner {
             has_data_to_read,
             has_read_data,
+            evicted: AtomicBool::new(false),
         });
         let mut s = Self {
             recv_socket,
@@ -182,6 +247,13 @@ impl UdpStream {
 impl AsyncRead for UdpStream {
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, obuf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
         let mut project = self.project();
+        if project.io.evicted.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("UDP stream with {} evicted to make room for a new flow", project.peer),
+            )));
+        }
+
         // Look that the timeout for client has not elapsed
         if let Some(mut deadline) = project.watchdog_deadline.as_pin_mut()
             && deadline.poll_tick(cx).is_ready()
@@ -223,6 +295,8 @@ pub struct UdpStreamWriter {
     peer: SocketAddr,
 }
 
+impl Resettable for UdpStreamWriter {}
+
 impl AsyncWrite for UdpStreamWriter {
     fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
         self.send_socket.poll_send_to(cx, buf, self.peer)
@@ -237,9 +311,26 @@ impl AsyncWrite for UdpStreamWriter {
     }
 }
 
+/// Bind a socket like [`UdpSocket::bind`], with IPV6_V6ONLY set to `v6only` instead of the default of the OS,
+/// when `bind` is an ipv6 address
+fn bind_socket(bind: SocketAddr, v6only: Option<bool>) -> io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(bind), socket2::Type::DGRAM, None)?;
+    if let Some(v6only) = v6only
+        && bind.is_ipv6()
+    {
+        socket.set_only_v6(v6only)?;
+    }
+    socket.bind(&bind.into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
 pub async fn run_server(
     bind: SocketAddr,
+    v6only: Option<bool>,
     timeout: Option<Duration>,
+    max_flows: Option<usize>,
+    flow_eviction: UdpFlowEviction,
     configure_listener: impl Fn(&UdpSocket) -> anyhow::Result<()>,
     mk_send_socket: impl Fn(&Arc<UdpSocket>) -> anyhow::Result<Arc<UdpSocket>>,
 ) -> Result<impl Stream<Item = io::Result<UdpStream>>, anyhow::Error> {
@@ -249,12 +340,14 @@ pub async fn run_server(
         timeout.unwrap_or(Duration::from_secs(0)).as_secs()
     );
 
-    let listener = UdpSocket::bind(bind)
-        .await
-        .with_context(|| format!("Cannot create UDP server {bind:?}"))?;
+    let listener = bind_socket(bind, v6only).with_context(|| format!("Cannot create UDP server {bind:?}"))?;
     configure_listener(&listener)?;
 
-    let udp_server = UdpServer::new(listener, timeout);
+    if let Some(max_flows) = max_flows {
+        info!("UDP server on {bind} limited to {max_flows} concurrent flows with {flow_eviction:?} policy");
+    }
+
+    let udp_server = UdpServer::new(listener, timeout, max_flows, flow_eviction);
     let stream = stream::unfold(
         (udp_server, None, mk_send_socket),
         |(mut server, peer_with_data, mk_send_socket)| async move {
@@ -262,7 +355,7 @@ pub async fn run_server(
             if let Some(await_peer) = peer_with_data
                 && let Some(peer) = server.peers.get(&await_peer)
             {
-                peer.has_read_data.notified().await;
+                peer.io.has_read_data.notified().await;
             };
 
             loop {
@@ -275,12 +368,25 @@ pub async fn run_server(
                     }
                 };
 
-                match server.peers.get(&peer_addr) {
-                    Some(io) => {
-                        io.has_data_to_read.notify_one();
-                        io.has_read_data.notified().await;
+                match server.peers.get_mut(&peer_addr) {
+                    Some(peer) => {
+                        peer.last_seen = Instant::now();
+                        peer.io.has_data_to_read.notify_one();
+                        peer.io.has_read_data.notified().await;
                     }
                     None => {
+                        if !server.make_room_for_new_flow() {
+                            server.drop_new_flow_packet(peer_addr).await;
+                            continue;
+                        }
+                        if server.dropped_packets > 0 {
+                            info!(
+                                "Accepting new UDP flows again, {} packets dropped while max number of flows was reached",
+                                server.dropped_packets
+                            );
+                            server.dropped_packets = 0;
+                        }
+
                         info!("New UDP connection from {}", peer_addr);
                         let (udp_client, io) = UdpStream::new(
                             server.clone_socket(),
@@ -290,7 +396,13 @@ pub async fn run_server(
                             Arc::downgrade(&server.keys_to_delete),
                         );
                         io.has_data_to_read.notify_waiters();
-                        server.peers.insert(peer_addr, io);
+                        server.peers.insert(
+                            peer_addr,
+                            UdpPeer {
+                                io,
+                                last_seen: Instant::now(),
+                            },
+                        );
                         return Some((Ok(udp_client), (server, Some(peer_addr), mk_send_socket)));
                     }
                 }
@@ -320,6 +432,8 @@ impl AsyncRead for WsUdpSocket {
     }
 }
 
+impl Resettable for WsUdpSocket {}
+
 impl AsyncWrite for WsUdpSocket {
     fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
         unsafe { self.map_unchecked_mut(|x| &mut x.socket) }.poll_send(cx, buf)
@@ -339,6 +453,7 @@ pub async fn connect(
     port: u16,
     connect_timeout: Duration,
     so_mark: SoMark,
+    source_bind: &SourceBind,
     dns_resolver: &DnsResolver,
 ) -> anyhow::Result<WsUdpSocket> {
     info!("Opening UDP connection to {}:{}", host, port);
@@ -357,9 +472,11 @@ pub async fn connect(
     let mut join_set = JoinSet::new();
 
     for (ix, addr) in socket_addrs.into_iter().enumerate() {
-        let socket = match &addr {
-            SocketAddr::V4(_) => UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await,
-            SocketAddr::V6(_) => UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).await,
+        let socket = match (source_bind.local_addr(&addr), &addr) {
+            (Ok(Some(local_addr)), _) => UdpSocket::bind(local_addr).await,
+            (Ok(None), SocketAddr::V4(_)) => UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await,
+            (Ok(None), SocketAddr::V6(_)) => UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).await,
+            (Err(err), _) => Err(err),
         };
 
         let socket = match socket {
@@ -369,6 +486,9 @@ pub async fn connect(
                 continue;
             }
         };
+        source_bind
+            .bind_interface(&SockRef::from(&socket))
+            .context("cannot bind udp socket to interface")?;
 
         so_mark
             .set_mark(SockRef::from(&socket))
@@ -509,9 +629,17 @@ mod tests {
     #[tokio::test]
     async fn test_udp_server() {
         let server_addr: SocketAddr = "[::1]:1234".parse().unwrap();
-        let server = run_server(server_addr, None, |_| Ok(()), |l| Ok(l.clone()))
-            .await
-            .unwrap();
+        let server = run_server(
+            server_addr,
+            None,
+            None,
+            None,
+            UdpFlowEviction::DropNew,
+            |_| Ok(()),
+            |l| Ok(l.clone()),
+        )
+        .await
+        .unwrap();
         pin_mut!(server);
 
         // Should timeout
@@ -558,9 +686,17 @@ mod tests {
     async fn test_multiple_client() {
         let server_addr: SocketAddr = "[::1]:1235".parse().unwrap();
         let mut server = Box::pin(
-            run_server(server_addr, None, |_| Ok(()), |l| Ok(l.clone()))
-                .await
-                .unwrap(),
+            run_server(
+                server_addr,
+                None,
+                None,
+                None,
+                UdpFlowEviction::DropNew,
+                |_| Ok(()),
+                |l| Ok(l.clone()),
+            )
+            .await
+            .unwrap(),
         );
 
         // Send some data to the server
@@ -627,9 +763,17 @@ mod tests {
     async fn test_udp_should_timeout() {
         let server_addr: SocketAddr = "[::1]:1237".parse().unwrap();
         let socket_timeout = Duration::from_secs(1);
-        let server = run_server(server_addr, Some(socket_timeout), |_| Ok(()), |l| Ok(l.clone()))
-            .await
-            .unwrap();
+        let server = run_server(
+            server_addr,
+            None,
+            Some(socket_timeout),
+            None,
+            UdpFlowEviction::DropNew,
+            |_| Ok(()),
+            |l| Ok(l.clone()),
+        )
+        .await
+        .unwrap();
         pin_mut!(server);
 
         // Send some data to the server
@@ -659,4 +803,88 @@ mod tests {
         let ret = stream.read(&mut buf[5..]).await;
         assert!(ret.is_err());
     }
+
+    #[tokio::test]
+    async fn test_udp_max_flows_drop_new() {
+        let server_addr: SocketAddr = "[::1]:1238".parse().unwrap();
+        let server = run_server(
+            server_addr,
+            None,
+            None,
+            Some(1),
+            UdpFlowEviction::DropNew,
+            |_| Ok(()),
+            |l| Ok(l.clone()),
+        )
+        .await
+        .unwrap();
+        pin_mut!(server);
+
+        let client = UdpSocket::bind("[::1]:0").await.unwrap();
+        assert!(client.send_to(b"aaaaa".as_ref(), server_addr).await.is_ok());
+        let stream = timeout(Duration::from_millis(100), server.next()).await;
+        let stream = stream.unwrap().unwrap().unwrap();
+        pin_mut!(stream);
+
+        let mut buf = [0u8; 25];
+        let ret = stream.read(&mut buf).await;
+        assert!(matches!(ret, Ok(5)));
+
+        // Max flows reached, the new peer should be dropped
+        let dropped = METRICS.udp_packets_dropped.load(Ordering::Relaxed);
+        let client2 = UdpSocket::bind("[::1]:0").await.unwrap();
+        assert!(client2.send_to(b"bbbbb".as_ref(), server_addr).await.is_ok());
+        assert!(client2.send_to(b"bbbbb".as_ref(), server_addr).await.is_ok());
+        let fut = timeout(Duration::from_millis(100), server.next()).await;
+        assert!(matches!(fut, Err(Elapsed { .. })));
+        assert!(METRICS.udp_packets_dropped.load(Ordering::Relaxed) >= dropped + 2);
+
+        // Existing flow should still be working
+        assert!(client.send_to(b"ccccc".as_ref(), server_addr).await.is_ok());
+        let _ = timeout(Duration::from_millis(100), server.next()).await;
+        let ret = timeout(Duration::from_millis(100), stream.read(&mut buf)).await;
+        assert!(matches!(ret, Ok(Ok(5))));
+        assert_eq!(&buf[..6], b"ccccc\0");
+    }
+
+    #[tokio::test]
+    async fn test_udp_max_flows_evict_idlest() {
+        let server_addr: SocketAddr = "[::1]:1239".parse().unwrap();
+        let server = run_server(
+            server_addr,
+            None,
+            None,
+            Some(1),
+            UdpFlowEviction::EvictIdlest,
+            |_| Ok(()),
+            |l| Ok(l.clone()),
+        )
+        .await
+        .unwrap();
+        pin_mut!(server);
+
+        let client = UdpSocket::bind("[::1]:0").await.unwrap();
+        assert!(client.send_to(b"aaaaa".as_ref(), server_addr).await.is_ok());
+        let stream = timeout(Duration::from_millis(100), server.next()).await;
+        let stream = stream.unwrap().unwrap().unwrap();
+        pin_mut!(stream);
+
+        let mut buf = [0u8; 25];
+        let ret = stream.read(&mut buf).await;
+        assert!(matches!(ret, Ok(5)));
+
+        // Max flows reached, the idlest flow should be evicted to make room for the new peer
+        let client2 = UdpSocket::bind("[::1]:0").await.unwrap();
+        assert!(client2.send_to(b"bbbbb".as_ref(), server_addr).await.is_ok());
+        let stream2 = timeout(Duration::from_millis(100), server.next()).await;
+        let stream2 = stream2.unwrap().unwrap().unwrap();
+        pin_mut!(stream2);
+
+        let ret = stream2.read(&mut buf).await;
+        assert!(matches!(ret, Ok(5)));
+        assert_eq!(&buf[..6], b"bbbbb\0");
+
+        let ret = stream.read(&mut buf).await;
+        assert!(matches!(ret, Err(err) if err.kind() == ErrorKind::ConnectionAborted));
+    }
 }
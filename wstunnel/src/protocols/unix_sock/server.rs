@@ -1,15 +1,28 @@
+use crate::tunnel::{UnixSocketLifecycle, UnixSocketPermissions};
 use anyhow::Context;
 use futures_util::Stream;
+use nix::unistd::{Group, User};
+use notify::{RecommendedWatcher, Watcher};
 use std::io;
-use std::path::Path;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::Poll;
+use std::time::Duration;
 use tokio::net::{UnixListener, UnixStream};
-use tracing::log::info;
+use tokio::sync::mpsc;
+use tracing::log::{info, warn};
+
+/// How often the socket is checked when no event of its directory tells it was removed, and its directory while it does
+/// not exist
+const RECREATE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const RECREATE_DIR_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct UnixListenerStream {
     inner: UnixListener,
     path_to_delete: bool,
+    /// Listeners of the socket created again after its file was removed, see [`watch_socket`]
+    recreated: Option<mpsc::Receiver<UnixListener>>,
 }
 
 impl UnixListenerStream {
@@ -17,6 +30,7 @@ impl UnixListenerStream {
         Self {
             inner: listener,
             path_to_delete,
+            recreated: None,
         }
     }
 }
@@ -39,7 +53,25 @@ impl Stream for UnixListenerStream {
     type Item = io::Result<UnixStream>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<io::Result<UnixStream>>> {
-        match self.inner.poll_accept(cx) {
+        let this = self.get_mut();
+        if let Some(recreated) = &mut this.recreated {
+            loop {
+                match recreated.poll_recv(cx) {
+                    // The listener of the removed file accepts nothing anymore
+                    Poll::Ready(Some(listener)) => {
+                        this.inner = listener;
+                        this.path_to_delete = true;
+                    }
+                    Poll::Ready(None) => {
+                        this.recreated = None;
+                        break;
+                    }
+                    Poll::Pending => break,
+                }
+            }
+        }
+
+        match this.inner.poll_accept(cx) {
             Poll::Ready(Ok((stream, _))) => Poll::Ready(Some(Ok(stream))),
             Poll::Ready(Err(err)) => Poll::Ready(Some(Err(err))),
             Poll::Pending => Poll::Pending,
@@ -47,12 +79,268 @@ impl Stream for UnixListenerStream {
     }
 }
 
-pub async fn run_server(socket_path: &Path) -> Result<UnixListenerStream, anyhow::Error> {
+pub async fn run_server(
+    socket_path: &Path,
+    permissions: &UnixSocketPermissions,
+    lifecycle: UnixSocketLifecycle,
+) -> Result<UnixListenerStream, anyhow::Error> {
     info!("Starting Unix socket server listening cnx on {socket_path:?}");
 
+    if let Some(name) = socket_path.to_str().and_then(|path| path.strip_prefix('@')) {
+        if !permissions.is_empty() || lifecycle != UnixSocketLifecycle::default() {
+            anyhow::bail!(
+                "Cannot set mode, owner, group, remove_stale or recreate of abstract Unix socket {socket_path:?}, it has no file"
+            );
+        }
+        let listener = bind_abstract(name)
+            .with_context(|| format!("Cannot create abstract Unix socket server {socket_path:?}"))?;
+        return Ok(UnixListenerStream::new(listener, false));
+    }
+
+    if lifecycle.remove_stale && remove_stale_socket(socket_path) {
+        info!("Removed stale Unix socket {socket_path:?}, nothing was listening on it");
+    }
     let path_to_delete = !socket_path.exists();
     let listener =
         UnixListener::bind(socket_path).with_context(|| format!("Cannot create Unix socket server {socket_path:?}"))?;
+    let mut listener = UnixListenerStream::new(listener, path_to_delete);
+    apply_permissions(socket_path, permissions)
+        .with_context(|| format!("Cannot set permissions of Unix socket {socket_path:?}"))?;
+    if lifecycle.recreate {
+        let (tx, rx) = mpsc::channel(1);
+        watch_socket(socket_path.to_path_buf(), permissions.clone(), tx)
+            .with_context(|| format!("Cannot watch the directory of Unix socket {socket_path:?}"))?;
+        listener.recreated = Some(rx);
+    }
+
+    Ok(listener)
+}
+
+/// Remove the socket at the path if nothing listens on it anymore, i.e: left behind by a process that was killed.
+/// Tell whether it was removed
+fn remove_stale_socket(socket_path: &Path) -> bool {
+    let is_stale = std::fs::symlink_metadata(socket_path).is_ok_and(|meta| meta.file_type().is_socket())
+        && std::os::unix::net::UnixStream::connect(socket_path)
+            .is_err_and(|err| err.kind() == io::ErrorKind::ConnectionRefused);
+
+    is_stale && std::fs::remove_file(socket_path).is_ok()
+}
+
+/// Create the socket again when its file is removed, and send its new listener to the stream, until the stream is
+/// dropped. The directory of the socket is watched for its removal, and polled while it does not exist once removed
+fn watch_socket(
+    socket_path: PathBuf,
+    permissions: UnixSocketPermissions,
+    listeners: mpsc::Sender<UnixListener>,
+) -> notify::Result<()> {
+    let dir = match socket_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|event| event.kind.is_remove()) {
+            let _ = events_tx.send(());
+        }
+    })?;
+    watcher.watch(&dir, notify::RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = listeners.closed() => return,
+                _ = events.recv() => {},
+                _ = tokio::time::sleep(RECREATE_CHECK_INTERVAL) => {},
+            }
+            if std::fs::symlink_metadata(&socket_path).is_ok() {
+                continue;
+            }
+
+            warn!("Unix socket {socket_path:?} has been removed, waiting for its directory to create it again");
+            while !dir.is_dir() {
+                tokio::select! {
+                    _ = listeners.closed() => return,
+                    _ = tokio::time::sleep(RECREATE_DIR_POLL_INTERVAL) => {},
+                }
+            }
+            match recreate_socket(&socket_path, &permissions, &mut watcher, &dir) {
+                Ok(listener) => {
+                    info!("Created Unix socket {socket_path:?} again");
+                    if listeners.send(listener).await.is_err() {
+                        let _ = std::fs::remove_file(&socket_path);
+                        return;
+                    }
+                }
+                Err(err) => warn!("Cannot create Unix socket {socket_path:?} again: {err:?}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn recreate_socket(
+    socket_path: &Path,
+    permissions: &UnixSocketPermissions,
+    watcher: &mut RecommendedWatcher,
+    dir: &Path,
+) -> anyhow::Result<UnixListener> {
+    // The watch of a removed directory is gone, the new one must be watched
+    let _ = watcher.unwatch(dir);
+    watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+    let listener = UnixListener::bind(socket_path)?;
+    if let Err(err) = apply_permissions(socket_path, permissions) {
+        let _ = std::fs::remove_file(socket_path);
+        return Err(err.into());
+    }
+
+    Ok(listener)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> io::Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(_name: &str) -> io::Result<UnixListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract Unix sockets are only supported on Linux",
+    ))
+}
 
-    Ok(UnixListenerStream::new(listener, path_to_delete))
+fn apply_permissions(socket_path: &Path, permissions: &UnixSocketPermissions) -> io::Result<()> {
+    if let Some(mode) = permissions.mode {
+        std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(mode))?;
+    }
+
+    let uid = permissions.owner.as_deref().map(resolve_uid).transpose()?;
+    let gid = permissions.group.as_deref().map(resolve_gid).transpose()?;
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(socket_path, uid, gid)?;
+    }
+
+    Ok(())
+}
+
+fn resolve_uid(owner: &str) -> io::Result<u32> {
+    if let Ok(uid) = owner.parse::<u32>() {
+        return Ok(uid);
+    }
+    match User::from_name(owner)? {
+        Some(user) => Ok(user.uid.as_raw()),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown user {owner}"))),
+    }
+}
+
+fn resolve_gid(group: &str) -> io::Result<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+    match Group::from_name(group)? {
+        Some(group) => Ok(group.gid.as_raw()),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown group {group}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[tokio::test]
+    async fn test_run_server_permissions() {
+        let dir = std::env::temp_dir().join(format!("wstunnel-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("perm.sock");
+        let permissions = UnixSocketPermissions {
+            mode: Some(0o660),
+            owner: Some(nix::unistd::getuid().to_string()),
+            group: None,
+        };
+
+        let listener = run_server(&path, &permissions, UnixSocketLifecycle::default())
+            .await
+            .unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o660);
+        assert_eq!(metadata.uid(), nix::unistd::getuid().as_raw());
+
+        drop(listener);
+        assert!(!path.exists());
+        let _ = std::fs::remove_dir(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_run_server_lifecycle() {
+        use tokio_stream::StreamExt;
+
+        let dir = std::env::temp_dir().join(format!("wstunnel-unix-lifecycle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.sock");
+        let permissions = UnixSocketPermissions {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+
+        // A socket left behind by a process that is gone
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(
+            run_server(&path, &permissions, UnixSocketLifecycle::default())
+                .await
+                .is_err()
+        );
+        let lifecycle = UnixSocketLifecycle {
+            remove_stale: true,
+            recreate: true,
+        };
+        let mut listener = run_server(&path, &permissions, lifecycle).await.unwrap();
+        tokio::spawn(async move { while listener.next().await.is_some() {} });
+
+        // The directory is cleaned up and created again
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        let connected = async {
+            while UnixStream::connect(&path).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), connected).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o7777, 0o600);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_run_server_abstract() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("@wstunnel-test-{}", std::process::id());
+        let _listener = run_server(
+            Path::new(&name),
+            &UnixSocketPermissions::default(),
+            UnixSocketLifecycle::default(),
+        )
+        .await
+        .unwrap();
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name[1..]).unwrap();
+        std::os::unix::net::UnixStream::connect_addr(&addr).unwrap();
+
+        let permissions = UnixSocketPermissions {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        assert!(
+            run_server(Path::new(&name), &permissions, UnixSocketLifecycle::default())
+                .await
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,5 @@
+mod server;
+
+pub use server::VsockListenerStream;
+pub use server::VsockStream;
+pub use server::run_server;
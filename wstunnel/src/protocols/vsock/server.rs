@@ -0,0 +1,96 @@
+use anyhow::Context;
+use futures_util::Stream;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::io;
+use std::io::Read;
+use std::net::Shutdown;
+use std::pin::Pin;
+use std::task::{Poll, ready};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::log::info;
+
+/// Stream socket of the AF_VSOCK family, used to talk between a VM and its host without any network configured
+pub struct VsockStream {
+    inner: AsyncFd<Socket>,
+}
+
+impl VsockStream {
+    fn new(socket: Socket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            inner: AsyncFd::new(socket)?,
+        })
+    }
+}
+
+impl AsyncRead for VsockStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|inner| inner.get_ref().read(unfilled)) {
+                Ok(Ok(len)) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_write_ready(cx))?;
+            match guard.try_io(|inner| inner.get_ref().send(buf)) {
+                Ok(ret) => return Poll::Ready(ret),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.get_ref().shutdown(Shutdown::Write))
+    }
+}
+
+pub struct VsockListenerStream {
+    inner: AsyncFd<Socket>,
+}
+
+impl Stream for VsockListenerStream {
+    type Item = io::Result<VsockStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<io::Result<VsockStream>>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_read_ready(cx))?;
+            match guard.try_io(|inner| inner.get_ref().accept()) {
+                Ok(Ok((socket, _))) => return Poll::Ready(Some(VsockStream::new(socket))),
+                Ok(Err(err)) => return Poll::Ready(Some(Err(err))),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+pub fn run_server(cid: u32, port: u32) -> Result<VsockListenerStream, anyhow::Error> {
+    info!("Starting vsock server listening cnx on {cid}:{port}");
+
+    let socket = Socket::new(Domain::VSOCK, Type::STREAM.nonblocking().cloexec(), None)
+        .context("Cannot create vsock socket, is the vsock kernel module loaded ?")?;
+    socket
+        .bind(&SockAddr::vsock(cid, port))
+        .with_context(|| format!("Cannot bind vsock server on {cid}:{port}"))?;
+    socket.listen(1024)?;
+
+    Ok(VsockListenerStream {
+        inner: AsyncFd::new(socket)?,
+    })
+}
@@ -1,4 +1,6 @@
+use super::loader;
 use super::types::RestrictionsRules;
+use crate::health::HEALTH;
 use crate::restrictions::config_reloader::RestrictionsRulesReloaderState::{Config, Static};
 use anyhow::Context;
 use arc_swap::ArcSwap;
@@ -14,6 +16,8 @@ use tracing::{error, info, warn};
 struct ConfigReloaderState {
     fs_watcher: Mutex<RecommendedWatcher>,
     config_path: PathBuf,
+    /// Files and directories included by the config file, that are watched along with it
+    includes: Mutex<Vec<PathBuf>>,
 }
 
 #[derive(Clone)]
@@ -52,6 +56,7 @@ impl RestrictionsRulesReloader {
             state: Config(Arc::new(ConfigReloaderState {
                 fs_watcher: Mutex::new(notify::recommended_watcher(|_| {})?),
                 config_path,
+                includes: Mutex::new(vec![]),
             })),
             restrictions: Arc::new(ArcSwap::from_pointee(restrictions_rules)),
         };
@@ -68,23 +73,50 @@ impl RestrictionsRulesReloader {
             Static => {}
             Config(cfg) => {
                 watcher.watch(&cfg.config_path, notify::RecursiveMode::NonRecursive)?;
-                *cfg.fs_watcher.lock() = watcher
+                *cfg.fs_watcher.lock() = watcher;
+                if let Ok((_, sources)) = loader::load(&cfg.config_path) {
+                    Self::watch_includes(cfg, includes_of(sources));
+                }
             }
         }
 
         Ok(reloader)
     }
 
+    /// Replace the watched includes by the new ones, as files can start or stop being included on each reload
+    fn watch_includes(state: &ConfigReloaderState, includes: Vec<PathBuf>) {
+        let mut watched = state.includes.lock();
+        if *watched == includes {
+            return;
+        }
+
+        let mut watcher = state.fs_watcher.lock();
+        for path in watched.iter() {
+            let _ = watcher.unwatch(path);
+        }
+        for path in &includes {
+            if let Err(err) = watcher.watch(path, notify::RecursiveMode::NonRecursive) {
+                error!("Cannot watch included restriction file {:?}: {:?}", path, err);
+            }
+        }
+        *watched = includes;
+    }
+
     pub fn reload_restrictions_config(&self) {
         let restrictions = match &self.state {
             Static => return,
-            Config(st) => match RestrictionsRules::from_config_file(&st.config_path) {
-                Ok(restrictions) => {
+            Config(st) => match loader::load(&st.config_path) {
+                Ok((restrictions, sources)) => {
                     info!("Restrictions config file has been reloaded");
+                    HEALTH.set_restrictions_reload(Ok(()));
+                    // The watcher cannot be updated from its own event handler thread
+                    let st = st.clone();
+                    thread::spawn(move || Self::watch_includes(&st, includes_of(sources)));
                     restrictions
                 }
                 Err(err) => {
                     error!("Cannot reload restrictions config file, keeping the old one. Error: {:?}", err);
+                    HEALTH.set_restrictions_reload(Err(format!("{err:#}")));
                     return;
                 }
             },
@@ -162,6 +194,28 @@ impl RestrictionsRulesReloader {
                     trace!("Ignoring event {event:?}");
                 }
             }
+            return;
+        }
+
+        // Any change of an included file, or of the content of an included directory, is reloaded
+        let includes = this.includes.lock().clone();
+        let is_included = |path: &PathBuf| {
+            includes
+                .iter()
+                .any(|include| path == include || path.parent() == Some(include.as_path()))
+        };
+        if event.paths.iter().any(is_included)
+            && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_))
+        {
+            reloader.reload_restrictions_config();
         }
     }
 }
+
+/// The first source is the config file itself, that is watched on its own
+fn includes_of(mut sources: Vec<PathBuf>) -> Vec<PathBuf> {
+    if !sources.is_empty() {
+        sources.remove(0);
+    }
+    sources
+}
@@ -0,0 +1,338 @@
+//! Generate, from the tunnels of a client, the tightest restrictions file the server needs to accept them
+use crate::config::LocalToRemote;
+use crate::tunnel::LocalProtocol;
+use ipnet::IpNet;
+use serde_yaml::value::{Tag, TaggedValue};
+use serde_yaml::{Mapping, Value};
+use std::net::IpAddr;
+use std::path::Path;
+use url::Host;
+
+pub fn client_restrictions(
+    path_prefix: &str,
+    local_to_remote: &[LocalToRemote],
+    remote_to_local: &[LocalToRemote],
+) -> anyhow::Result<String> {
+    let allow = local_to_remote
+        .iter()
+        .map(allow_tunnel)
+        .chain(local_to_remote.iter().filter_map(allow_mirror))
+        .chain(local_to_remote.iter().flat_map(allow_fallbacks))
+        .chain(remote_to_local.iter().map(allow_reverse_tunnel))
+        .collect::<Vec<_>>();
+
+    let restriction = mapping([
+        ("name", Value::from(format!("generated for path prefix {path_prefix}"))),
+        (
+            "match",
+            Value::Sequence(vec![tagged("PathPrefix", Value::from(exact_regex(path_prefix)))]),
+        ),
+        ("allow", Value::Sequence(allow)),
+    ]);
+    let rules = mapping([("restrictions", Value::Sequence(vec![Value::Mapping(restriction)]))]);
+
+    Ok(serde_yaml::to_string(&rules)?)
+}
+
+pub fn write_client_restrictions(
+    path: &Path,
+    path_prefix: &str,
+    local_to_remote: &[LocalToRemote],
+    remote_to_local: &[LocalToRemote],
+) -> anyhow::Result<()> {
+    let restrictions = client_restrictions(path_prefix, local_to_remote, remote_to_local)?;
+    std::fs::write(path, restrictions)?;
+    Ok(())
+}
+
+fn allow_tunnel(tunnel: &LocalToRemote) -> Value {
+    let protocols: &[&str] = match tunnel.local_protocol {
+        LocalProtocol::Udp { .. } | LocalProtocol::StdioUdp { .. } | LocalProtocol::TProxyUdp { .. } => &["Udp"],
+        LocalProtocol::Sctp => &["Sctp"],
+        LocalProtocol::Socks5 { .. } => &["Tcp", "Udp"],
+        _ => &["Tcp"],
+    };
+    let mut config = mapping([("protocol", strings(protocols))]);
+
+    // Dynamic tunnels pick their destination at runtime, so only the protocol can be restricted
+    let is_dynamic = matches!(
+        tunnel.local_protocol,
+        LocalProtocol::Socks5 { .. }
+            | LocalProtocol::HttpProxy { .. }
+            | LocalProtocol::TProxyTcp
+            | LocalProtocol::TProxyUdp { .. }
+    );
+    if !is_dynamic {
+        insert_destination(&mut config, &tunnel.remote, tunnel.port_count);
+    }
+
+    tagged("Tunnel", Value::Mapping(config))
+}
+
+/// The server checks the mirror of a tunnel as a destination of its own
+fn allow_mirror(tunnel: &LocalToRemote) -> Option<Value> {
+    let LocalProtocol::Tcp {
+        mirror: Some(mirror), ..
+    } = &tunnel.local_protocol
+    else {
+        return None;
+    };
+
+    let mut config = mapping([("protocol", strings(&["Tcp"]))]);
+    insert_destination(&mut config, mirror, 1);
+    Some(tagged("Tunnel", Value::Mapping(config)))
+}
+
+/// And so are the other destinations of a load balanced tunnel
+fn allow_fallbacks(tunnel: &LocalToRemote) -> Vec<Value> {
+    let LocalProtocol::Tcp {
+        balancing: Some(balancing),
+        ..
+    } = &tunnel.local_protocol
+    else {
+        return vec![];
+    };
+
+    balancing
+        .fallbacks
+        .iter()
+        .map(|fallback| {
+            let mut config = mapping([("protocol", strings(&["Tcp"]))]);
+            insert_destination(&mut config, fallback, 1);
+            tagged("Tunnel", Value::Mapping(config))
+        })
+        .collect()
+}
+
+fn insert_destination(config: &mut Mapping, (host, port): &(Host, u16), port_count: u16) {
+    config.insert("port".into(), Value::Sequence(vec![port_range(*port, port_count)]));
+    match host {
+        Host::Domain(domain) => {
+            config.insert("host".into(), Value::from(exact_regex(domain)));
+            config.insert("cidr".into(), Value::Sequence(vec![]));
+        }
+        Host::Ipv4(ip) => {
+            config.insert("host".into(), Value::from("^$"));
+            config.insert("cidr".into(), Value::Sequence(vec![ip_cidr(IpAddr::V4(*ip))]));
+        }
+        Host::Ipv6(ip) => {
+            config.insert("host".into(), Value::from("^$"));
+            config.insert("cidr".into(), Value::Sequence(vec![ip_cidr(IpAddr::V6(*ip))]));
+        }
+    }
+}
+
+fn allow_reverse_tunnel(tunnel: &LocalToRemote) -> Value {
+    let protocol = match &tunnel.local_protocol {
+        LocalProtocol::ReverseUnix { path, .. } => {
+            return tagged(
+                "ReverseTunnel",
+                Value::Mapping(mapping([
+                    ("protocol", strings(&["Unix"])),
+                    ("unix_path", Value::from(exact_regex(&path.to_string_lossy()))),
+                ])),
+            );
+        }
+        LocalProtocol::ReverseHttpIngress { vhost, .. } => {
+            // the subdomain allocated to the client is only known once it runs
+            let vhost = match vhost.strip_prefix("*.") {
+                Some(domain) => format!("^[0-9a-f]+\\.{}$", regex::escape(domain)),
+                None => exact_regex(vhost),
+            };
+            return tagged(
+                "ReverseTunnel",
+                Value::Mapping(mapping([
+                    ("protocol", strings(&["HttpIngress"])),
+                    ("vhost", Value::from(vhost)),
+                ])),
+            );
+        }
+        LocalProtocol::ReverseUdp { .. } => "Udp",
+        LocalProtocol::ReverseSocks5 { .. } => "Socks5",
+        LocalProtocol::ReverseHttpProxy { .. } => "HttpProxy",
+        _ => "Tcp",
+    };
+
+    // The server listens on the bind address of the tunnel, that is what it checks
+    tagged(
+        "ReverseTunnel",
+        Value::Mapping(mapping([
+            ("protocol", strings(&[protocol])),
+            (
+                "port",
+                Value::Sequence(vec![port_range(tunnel.local.port(), tunnel.port_count)]),
+            ),
+            ("cidr", Value::Sequence(vec![ip_cidr(tunnel.local.ip())])),
+        ])),
+    )
+}
+
+fn port_range(port: u16, port_count: u16) -> Value {
+    if port_count > 1 {
+        Value::from(format!("{port}..{}", port + port_count - 1))
+    } else {
+        Value::from(port)
+    }
+}
+
+fn exact_regex(value: &str) -> String {
+    format!("^{}$", regex::escape(value))
+}
+
+fn ip_cidr(ip: IpAddr) -> Value {
+    Value::from(IpNet::from(ip).to_string())
+}
+
+fn strings(values: &[&str]) -> Value {
+    Value::Sequence(values.iter().map(|v| Value::from(*v)).collect())
+}
+
+fn tagged(tag: &str, value: Value) -> Value {
+    Value::Tagged(Box::new(TaggedValue {
+        tag: Tag::new(tag),
+        value,
+    }))
+}
+
+fn mapping<const N: usize>(entries: [(&str, Value); N]) -> Mapping {
+    entries.into_iter().map(|(k, v)| (Value::from(k), v)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restrictions::types::{
+        AllowConfig, MatchConfig, RestrictionsRules, ReverseTunnelConfigProtocol, TunnelConfigProtocol,
+    };
+    use crate::tunnel::client::AcceptLimits;
+    use crate::tunnel::{AccessList, LoadBalancing, LoadBalancingStrategy};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::path::PathBuf;
+
+    fn tunnel(local_protocol: LocalProtocol, local: &str, remote: (Host, u16)) -> LocalToRemote {
+        LocalToRemote {
+            local_protocol,
+            local: local.parse::<SocketAddr>().unwrap(),
+            remote,
+            label: None,
+            dscp: None,
+            v6only: None,
+            access: AccessList::default(),
+            accept_limits: AcceptLimits::default(),
+            port_count: 1,
+        }
+    }
+
+    #[test]
+    fn test_client_restrictions() {
+        let local_to_remote = vec![
+            tunnel(
+                LocalProtocol::Tcp {
+                    proxy_protocol: false,
+                    resume: None,
+                    idle_timeout: None,
+                    mirror: Some((Host::Domain("db-next.lan".to_string()), 5433)),
+                    balancing: Some(LoadBalancing {
+                        fallbacks: vec![(Host::Domain("db-replica.lan".to_string()), 5432)],
+                        strategy: LoadBalancingStrategy::RoundRobin,
+                        health_check: None,
+                    }),
+                    keepalive: None,
+                },
+                "127.0.0.1:1212",
+                (Host::Domain("db.lan".to_string()), 5432),
+            ),
+            tunnel(
+                LocalProtocol::Udp { timeout: None },
+                "127.0.0.1:1053",
+                (Host::Ipv4(Ipv4Addr::new(1, 1, 1, 1)), 53),
+            ),
+            tunnel(
+                LocalProtocol::Socks5 {
+                    timeout: None,
+                    credentials: None,
+                    resume: None,
+                    resolve: Default::default(),
+                },
+                "127.0.0.1:1080",
+                (Host::Ipv4(Ipv4Addr::UNSPECIFIED), 0),
+            ),
+        ];
+        let remote_to_local = vec![
+            tunnel(
+                LocalProtocol::ReverseTcp {
+                    resume: None,
+                    idle_timeout: None,
+                    v6only: None,
+                    access: AccessList::default(),
+                    keepalive: None,
+                },
+                "[::1]:8080",
+                (Host::Domain("localhost".to_string()), 80),
+            ),
+            tunnel(
+                LocalProtocol::ReverseUnix {
+                    path: PathBuf::from("/tmp/app.sock"),
+                    allowed_uids: vec![],
+                    permissions: Default::default(),
+                    lifecycle: Default::default(),
+                },
+                "[::]:0",
+                (Host::Ipv6(Ipv6Addr::LOCALHOST), 80),
+            ),
+        ];
+        let yaml = client_restrictions("my.secret", &local_to_remote, &remote_to_local).unwrap();
+
+        // The generated file must be loadable by the server
+        let rules: RestrictionsRules = serde_yaml::from_str(&yaml).unwrap();
+        let restriction = &rules.restrictions[0];
+        let MatchConfig::PathPrefix(prefix) = &restriction.r#match[0] else {
+            panic!("expected a path prefix match");
+        };
+        assert!(prefix.is_match("my.secret"));
+        assert!(!prefix.is_match("myXsecret"));
+
+        let [
+            AllowConfig::Tunnel(tcp),
+            AllowConfig::Tunnel(udp),
+            AllowConfig::Tunnel(socks5),
+            AllowConfig::Tunnel(mirror),
+            AllowConfig::Tunnel(fallback),
+            AllowConfig::ReverseTunnel(reverse_tcp),
+            AllowConfig::ReverseTunnel(reverse_unix),
+        ] = restriction.allow.as_slice()
+        else {
+            panic!("unexpected allow list {:?}", restriction.allow);
+        };
+
+        assert_eq!(tcp.protocol, vec![TunnelConfigProtocol::Tcp]);
+        assert_eq!(tcp.port, vec![5432..=5432]);
+        assert!(tcp.host.is_match("db.lan") && !tcp.host.is_match("dbxlan"));
+        assert!(tcp.cidr.is_empty());
+
+        assert_eq!(udp.protocol, vec![TunnelConfigProtocol::Udp]);
+        assert_eq!(udp.port, vec![53..=53]);
+        assert!(!udp.host.is_match("one.one.one.one"));
+        assert_eq!(udp.cidr, vec!["1.1.1.1/32".parse().unwrap()]);
+
+        assert_eq!(socks5.protocol, vec![TunnelConfigProtocol::Tcp, TunnelConfigProtocol::Udp]);
+        assert!(socks5.port.is_empty());
+
+        assert_eq!(mirror.protocol, vec![TunnelConfigProtocol::Tcp]);
+        assert_eq!(mirror.port, vec![5433..=5433]);
+        assert!(mirror.host.is_match("db-next.lan"));
+
+        assert_eq!(fallback.protocol, vec![TunnelConfigProtocol::Tcp]);
+        assert_eq!(fallback.port, vec![5432..=5432]);
+        assert!(fallback.host.is_match("db-replica.lan"));
+
+        assert_eq!(reverse_tcp.protocol, vec![ReverseTunnelConfigProtocol::Tcp]);
+        assert_eq!(reverse_tcp.port, vec![8080..=8080]);
+        assert!(reverse_tcp.cidr[0].contains(&"::1".parse::<IpAddr>().unwrap()));
+        assert_eq!(reverse_tcp.cidr.len(), 1);
+
+        assert_eq!(reverse_unix.protocol, vec![ReverseTunnelConfigProtocol::Unix]);
+        assert!(reverse_unix.unix_path.is_match("/tmp/app.sock"));
+        assert!(!reverse_unix.unix_path.is_match("/tmp/app.sock2"));
+    }
+}
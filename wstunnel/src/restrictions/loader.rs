@@ -0,0 +1,275 @@
+use crate::restrictions::types::{AllowConfig, RestrictionConfig, RestrictionsFile, RestrictionsRules};
+use anyhow::{Context, anyhow, bail};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// Load a restriction file along with the files it includes, and resolve the groups referenced by its restrictions.
+/// Returns the rules and every file and directory that has been read, to be watched for changes
+pub fn load(config_path: &Path) -> anyhow::Result<(RestrictionsRules, Vec<PathBuf>)> {
+    let mut loader = Loader::default();
+    loader.load_file(config_path)?;
+    let sources = loader.sources.clone();
+
+    Ok((loader.resolve()?, sources))
+}
+
+#[derive(Default)]
+struct Loader {
+    /// Canonical paths of the files and include directories read
+    sources: Vec<PathBuf>,
+    /// Files being loaded, to detect include cycles
+    stack: Vec<PathBuf>,
+    groups: HashMap<String, Vec<AllowConfig>>,
+    restrictions: Vec<RestrictionConfig>,
+}
+
+impl Loader {
+    fn load_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Cannot open restriction file {}", path.display()))?;
+        if self.stack.contains(&canonical) {
+            bail!("Restriction file {} includes itself", path.display());
+        }
+        // Included twice without a cycle (i.e: a shared file), its content is already there
+        if self.sources.contains(&canonical) {
+            return Ok(());
+        }
+        self.sources.push(canonical.clone());
+
+        let file: RestrictionsFile = serde_yaml::from_reader(BufReader::new(File::open(&canonical)?))
+            .with_context(|| format!("Cannot parse restriction file {}", path.display()))?;
+
+        self.stack.push(canonical.clone());
+        let base_dir = canonical.parent().unwrap_or(Path::new("/"));
+        for include in &file.include {
+            let include = base_dir.join(include);
+            if include.is_dir() {
+                self.load_dir(&include)?;
+            } else {
+                self.load_file(&include)?;
+            }
+        }
+        self.stack.pop();
+
+        for (name, allow) in file.groups {
+            if self.groups.insert(name.clone(), allow).is_some() {
+                bail!("Group {name} is defined more than once, last time in {}", path.display());
+            }
+        }
+        self.restrictions.extend(file.restrictions);
+
+        Ok(())
+    }
+
+    fn load_dir(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let canonical = dir
+            .canonicalize()
+            .with_context(|| format!("Cannot open restriction directory {}", dir.display()))?;
+        let mut files = std::fs::read_dir(&canonical)
+            .with_context(|| format!("Cannot list restriction directory {}", dir.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_file() && matches!(path.extension().and_then(|ext| ext.to_str()), Some("yaml" | "yml"))
+            })
+            .collect::<Vec<_>>();
+        // Files are loaded in name order, so the order of their restrictions is stable
+        files.sort();
+
+        if !self.sources.contains(&canonical) {
+            self.sources.push(canonical);
+        }
+        for file in files {
+            self.load_file(&file)?;
+        }
+
+        Ok(())
+    }
+
+    fn resolve(self) -> anyhow::Result<RestrictionsRules> {
+        for (name, allow) in &self.groups {
+            if allow.iter().any(|allow| matches!(allow, AllowConfig::Group(_))) {
+                bail!("Group {name} references another group, groups cannot be nested");
+            }
+        }
+
+        let restrictions = self
+            .restrictions
+            .into_iter()
+            .map(|mut restriction| {
+                let mut allow = Vec::with_capacity(restriction.allow.len());
+                for rule in restriction.allow {
+                    match rule {
+                        AllowConfig::Group(group) => {
+                            let rules = self.groups.get(&group).ok_or_else(|| {
+                                anyhow!("Restriction {} references unknown group {group}", restriction.name)
+                            })?;
+                            allow.extend(rules.iter().cloned());
+                        }
+                        rule => allow.push(rule),
+                    }
+                }
+                restriction.allow = allow;
+                Ok(restriction)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(RestrictionsRules { restrictions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restrictions::types::{AllowReverseTunnelConfig, MatchConfig, ReverseTunnelBind};
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wstunnel-restrictions-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_load_with_includes_and_groups() {
+        let dir = test_dir("includes");
+        write(
+            &dir,
+            "groups.yaml",
+            r#"
+groups:
+  databases:
+    - !Tunnel
+      port: [5432]
+      host: ^db$
+    - !Tunnel
+      port: [6379]
+      host: ^cache$
+"#,
+        );
+        write(
+            &dir,
+            "teams/b.yaml",
+            r#"
+restrictions:
+  - name: "team b"
+    match:
+      - !PathPrefix "^team-b$"
+    allow:
+      - !Group databases
+"#,
+        );
+        write(
+            &dir,
+            "teams/a.yml",
+            r#"
+restrictions:
+  - name: "team a"
+    description: "Team a only reaches the databases"
+    match:
+      - !PathPrefix "^team-a$"
+    allow:
+      - !Group databases
+      - !ReverseTunnel
+        port: [8080]
+        bind: Loopback
+        owned: true
+"#,
+        );
+        write(&dir, "teams/README.md", "not a restriction file");
+        let main = write(
+            &dir,
+            "main.yaml",
+            r#"
+include:
+  - groups.yaml
+  - teams
+restrictions:
+  - name: "admin"
+    match:
+      - !Any
+    allow:
+      - !Tunnel
+"#,
+        );
+
+        let (rules, sources) = load(&main).unwrap();
+        let names = rules.restrictions.iter().map(|r| r.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["team a", "team b", "admin"]);
+
+        let team_a = &rules.restrictions[0];
+        assert_eq!(team_a.description.as_deref(), Some("Team a only reaches the databases"));
+        assert!(matches!(team_a.r#match[0], MatchConfig::PathPrefix(_)));
+        assert!(matches!(
+            team_a.allow.as_slice(),
+            [
+                AllowConfig::Tunnel(_),
+                AllowConfig::Tunnel(_),
+                AllowConfig::ReverseTunnel(AllowReverseTunnelConfig {
+                    bind: ReverseTunnelBind::Loopback,
+                    owned: true,
+                    ..
+                })
+            ]
+        ));
+        assert_eq!(rules.restrictions[1].allow.len(), 2);
+
+        let dir = dir.canonicalize().unwrap();
+        assert_eq!(
+            sources,
+            vec![
+                dir.join("main.yaml"),
+                dir.join("groups.yaml"),
+                dir.join("teams"),
+                dir.join("teams/a.yml"),
+                dir.join("teams/b.yaml"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let dir = test_dir("errors");
+        let unknown_group = write(
+            &dir,
+            "unknown.yaml",
+            "restrictions:\n  - name: r\n    match:\n      - !Any\n    allow:\n      - !Group nope\n",
+        );
+        assert!(
+            load(&unknown_group)
+                .unwrap_err()
+                .to_string()
+                .contains("unknown group nope")
+        );
+
+        let cycle = write(&dir, "cycle.yaml", "include: [cycle2.yaml]\n");
+        write(&dir, "cycle2.yaml", "include: [cycle.yaml]\n");
+        assert!(load(&cycle).unwrap_err().to_string().contains("includes itself"));
+
+        let duplicate = write(&dir, "duplicate.yaml", "include: [g.yaml]\ngroups:\n  g: []\n");
+        write(&dir, "g.yaml", "groups:\n  g: []\n");
+        assert!(
+            load(&duplicate)
+                .unwrap_err()
+                .to_string()
+                .contains("defined more than once")
+        );
+
+        // Files without includes nor groups keep loading as before
+        let v1 = write(
+            &dir,
+            "v1.yaml",
+            "restrictions:\n  - name: r\n    match:\n      - !Any\n    allow: []\n",
+        );
+        assert_eq!(load(&v1).unwrap().0.restrictions.len(), 1);
+    }
+}
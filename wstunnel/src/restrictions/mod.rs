@@ -1,7 +1,5 @@
 use ipnet::IpNet;
 use regex::Regex;
-use std::fs::File;
-use std::io::BufReader;
 use std::net::IpAddr;
 use std::ops::RangeInclusive;
 use std::path::Path;
@@ -12,11 +10,13 @@ use types::RestrictionsRules;
 use crate::restrictions::types::{default_cidr, default_host};
 
 pub mod config_reloader;
+pub mod emit;
+pub mod loader;
 pub mod types;
 
 impl RestrictionsRules {
     pub fn from_config_file(config_path: &Path) -> anyhow::Result<Self> {
-        let restrictions: Self = serde_yaml::from_reader(BufReader::new(File::open(config_path)?))?;
+        let (restrictions, _) = loader::load(config_path)?;
         Ok(restrictions)
     }
 
@@ -27,6 +27,7 @@ impl RestrictionsRules {
                 port: vec![],
                 host: default_host(),
                 cidr: default_cidr(),
+                alias: vec![],
             });
             let reverse_tunnel = types::AllowConfig::ReverseTunnel(types::AllowReverseTunnelConfig {
                 protocol: vec![],
@@ -34,6 +35,9 @@ impl RestrictionsRules {
                 port_mapping: Default::default(),
                 cidr: default_cidr(),
                 unix_path: default_host(),
+                vhost: default_host(),
+                bind: Default::default(),
+                owned: false,
             });
 
             vec![r, reverse_tunnel]
@@ -47,6 +51,7 @@ impl RestrictionsRules {
                             port: vec![RangeInclusive::new(*port, *port)],
                             host: Regex::new("^$")?,
                             cidr: vec![IpNet::new(ip, if ip.is_ipv4() { 32 } else { 128 })?],
+                            alias: vec![],
                         })]
                     } else {
                         vec![types::AllowConfig::Tunnel(types::AllowTunnelConfig {
@@ -54,6 +59,7 @@ impl RestrictionsRules {
                             port: vec![RangeInclusive::new(*port, *port)],
                             host: Regex::new(&format!("^{}$", regex::escape(host)))?,
                             cidr: vec![],
+                            alias: vec![],
                         })]
                     };
 
@@ -69,6 +75,7 @@ impl RestrictionsRules {
             // if no path prefixes are provided, we allow all
             let r = types::RestrictionConfig {
                 name: "Allow All".to_string(),
+                description: None,
                 r#match: vec![types::MatchConfig::Any],
                 allow: tunnels_restrictions,
             };
@@ -80,6 +87,7 @@ impl RestrictionsRules {
                     let reg = Regex::new(&format!("^{}$", regex::escape(path_prefix)))?;
                     Ok(types::RestrictionConfig {
                         name: format!("Allow path prefix {path_prefix}"),
+                        description: None,
                         r#match: vec![types::MatchConfig::PathPrefix(reg)],
                         allow: tunnels_restrictions.clone(),
                     })
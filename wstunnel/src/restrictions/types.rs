@@ -4,15 +4,33 @@ use regex::Regex;
 use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
 use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use url::Host;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RestrictionsRules {
     pub restrictions: Vec<RestrictionConfig>,
 }
 
+/// Content of a restriction file, before its includes and groups are resolved into RestrictionsRules
+#[derive(Debug, Clone, Deserialize)]
+pub struct RestrictionsFile {
+    /// Other files, or directories of *.yaml files, to load. Relative paths are relative to this file.
+    /// Their restrictions come before the ones of this file
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
+    /// Named lists of allow rules, that restrictions of any loaded file can reference with !Group
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<AllowConfig>>,
+    #[serde(default)]
+    pub restrictions: Vec<RestrictionConfig>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct RestrictionConfig {
     pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
     #[serde(deserialize_with = "deserialize_non_empty_vec")]
     pub r#match: Vec<MatchConfig>,
     pub allow: Vec<AllowConfig>,
@@ -32,6 +50,8 @@ pub enum MatchConfig {
 pub enum AllowConfig {
     ReverseTunnel(AllowReverseTunnelConfig),
     Tunnel(AllowTunnelConfig),
+    /// Reference to the allow rules of a group, replaced by them when the file is loaded
+    Group(String),
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -49,6 +69,21 @@ pub struct AllowTunnelConfig {
 
     #[serde(default = "default_cidr")]
     pub cidr: Vec<IpNet>,
+
+    #[serde(deserialize_with = "deserialize_destination_alias")]
+    #[serde(default)]
+    pub alias: Vec<DestinationAlias>,
+}
+
+/// Rewrite a destination requested by the client (i.e: db:5432) to the real one (i.e: 10.0.3.7:5432)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DestinationAlias {
+    pub host: Host,
+    /// None means any port
+    pub port: Option<u16>,
+    pub target_host: Host,
+    /// None means keep the requested port
+    pub target_port: Option<u16>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -70,12 +105,41 @@ pub struct AllowReverseTunnelConfig {
     #[serde(with = "serde_regex")]
     #[serde(default = "default_host")]
     pub unix_path: Regex,
+
+    /// Virtual hosts that reverse http ingress tunnels can expose
+    #[serde(with = "serde_regex")]
+    #[serde(default = "default_host")]
+    pub vhost: Regex,
+
+    /// Address the server listens on for the reverse tunnels, instead of the one requested by the client
+    #[serde(default)]
+    pub bind: ReverseTunnelBind,
+
+    /// A port bound by a client is reserved for its identity, its upgrade path prefix or mTLS certificate common name
+    /// followed by its device id if it has one (i.e: team-a/sensor-42), as long as its reverse tunnel server runs. The
+    /// clients of other identities cannot share it meanwhile
+    #[serde(default)]
+    pub owned: bool,
+}
+
+/// Like the GatewayPorts of sshd, which address a reverse tunnel listens on. The client picks the address family with
+/// the address it requests, i.e: 127.0.0.1 or [::1] with -R tcp://127.0.0.1:2222:localhost:22
+#[derive(Debug, Clone, Copy, Default, Deserialize, Eq, PartialEq)]
+pub enum ReverseTunnelBind {
+    /// The address requested by the client
+    #[default]
+    ClientSpecified,
+    /// The loopback address, only the server itself can reach the reverse tunnels
+    Loopback,
+    /// All the addresses of the server, the whole network can reach the reverse tunnels
+    Any,
 }
 
 #[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
 pub enum TunnelConfigProtocol {
     Tcp,
     Udp,
+    Sctp,
     Unknown,
 }
 
@@ -86,6 +150,7 @@ pub enum ReverseTunnelConfigProtocol {
     Socks5,
     Unix,
     HttpProxy,
+    HttpIngress,
     Unknown,
 }
 
@@ -143,6 +208,47 @@ where
         .collect()
 }
 
+fn deserialize_destination_alias<'de, D>(deserializer: D) -> Result<Vec<DestinationAlias>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let aliases: Vec<String> = Deserialize::deserialize(deserializer)?;
+    aliases
+        .into_iter()
+        .map(|alias| {
+            let Some((from, to)) = alias.split_once('=') else {
+                return Err(serde::de::Error::custom(format!(
+                    "Invalid alias entry, expected ALIAS=TARGET: {alias}"
+                )));
+            };
+            let (host, port) = parse_host_port(from.trim()).map_err(serde::de::Error::custom)?;
+            let (target_host, target_port) = parse_host_port(to.trim()).map_err(serde::de::Error::custom)?;
+            Ok(DestinationAlias {
+                host,
+                port,
+                target_host,
+                target_port,
+            })
+        })
+        .collect()
+}
+
+fn parse_host_port(arg: &str) -> Result<(Host, Option<u16>), String> {
+    let (host, port) = match arg.rsplit_once(':') {
+        // a bare ipv6 has colons but no port
+        Some((host, port)) if host.ends_with(']') || !host.contains(':') => (
+            host,
+            Some(
+                port.parse::<u16>()
+                    .map_err(|err| format!("Invalid port in {arg}: {err}"))?,
+            ),
+        ),
+        _ => (arg, None),
+    };
+    let host = Host::parse(host).map_err(|err| format!("Invalid host in {arg}: {err}"))?;
+    Ok((host, port))
+}
+
 fn deserialize_non_empty_vec<'de, D, T>(d: D) -> Result<Vec<T>, D::Error>
 where
     D: Deserializer<'de>,
@@ -162,35 +268,47 @@ impl From<&LocalProtocol> for ReverseTunnelConfigProtocol {
             LocalProtocol::Tcp { .. }
             | LocalProtocol::Udp { .. }
             | LocalProtocol::Stdio { .. }
+            | LocalProtocol::StdioUdp { .. }
             | LocalProtocol::Socks5 { .. }
             | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
-            | LocalProtocol::Unix { .. } => Self::Unknown,
-            LocalProtocol::ReverseTcp => Self::Tcp,
+            | LocalProtocol::Unix { .. }
+            | LocalProtocol::Vsock { .. }
+            | LocalProtocol::Sctp
+            | LocalProtocol::Mux => Self::Unknown,
+            // the client requests it as a reverse tcp tunnel
+            LocalProtocol::ReverseTcp { .. } | LocalProtocol::ReverseEncryptedDns { .. } => Self::Tcp,
             LocalProtocol::ReverseUdp { .. } => Self::Udp,
             LocalProtocol::ReverseSocks5 { .. } => Self::Socks5,
             LocalProtocol::ReverseUnix { .. } => Self::Unix,
             LocalProtocol::ReverseHttpProxy { .. } => Self::HttpProxy,
+            LocalProtocol::ReverseHttpIngress { .. } => Self::HttpIngress,
         }
     }
 }
 impl From<&LocalProtocol> for TunnelConfigProtocol {
     fn from(value: &LocalProtocol) -> Self {
         match value {
-            LocalProtocol::ReverseTcp
+            LocalProtocol::ReverseTcp { .. }
             | LocalProtocol::ReverseUdp { .. }
             | LocalProtocol::ReverseSocks5 { .. }
             | LocalProtocol::ReverseUnix { .. }
             | LocalProtocol::Stdio { .. }
+            | LocalProtocol::StdioUdp { .. }
             | LocalProtocol::Socks5 { .. }
             | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
             | LocalProtocol::ReverseHttpProxy { .. }
-            | LocalProtocol::Unix { .. } => Self::Unknown,
+            | LocalProtocol::ReverseHttpIngress { .. }
+            | LocalProtocol::ReverseEncryptedDns { .. }
+            | LocalProtocol::Unix { .. }
+            | LocalProtocol::Vsock { .. }
+            | LocalProtocol::Mux => Self::Unknown,
             LocalProtocol::Tcp { .. } => Self::Tcp,
             LocalProtocol::Udp { .. } => Self::Udp,
+            LocalProtocol::Sctp => Self::Sctp,
         }
     }
 }
@@ -0,0 +1,96 @@
+//! source_bind - choose the interface or the source ip of the outgoing connections
+//!
+//! binding to an interface uses SO_BINDTODEVICE, so it is only supported on linux and android
+
+use socket2::SockRef;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+/// To not bind the outgoing connections, for the callers without a configuration
+pub static UNBOUND: SourceBind = SourceBind {
+    interface: None,
+    address: None,
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceBind {
+    /// i.e: eth1, to leave by this uplink without policy routing
+    pub interface: Option<String>,
+    /// i.e: 10.0.0.5, to use this source ip. The destinations of the other ip family are skipped
+    pub address: Option<IpAddr>,
+}
+
+impl SourceBind {
+    pub fn new(interface: Option<String>, address: Option<IpAddr>) -> Self {
+        Self { interface, address }
+    }
+
+    /// Must be called before connecting the socket to `remote`
+    pub fn bind(&self, socket: SockRef, remote: &SocketAddr) -> io::Result<()> {
+        self.bind_interface(&socket)?;
+        if let Some(local_addr) = self.local_addr(remote)? {
+            socket.bind(&local_addr.into())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn bind_interface(&self, socket: &SockRef) -> io::Result<()> {
+        match &self.interface {
+            Some(interface) => bind_device(socket, interface),
+            None => Ok(()),
+        }
+    }
+
+    /// The address to bind the socket to, to reach `remote` from the source ip
+    pub fn local_addr(&self, remote: &SocketAddr) -> io::Result<Option<SocketAddr>> {
+        let Some(address) = self.address else { return Ok(None) };
+        if address.is_ipv4() != remote.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("cannot reach {remote} from the bind address {address}"),
+            ));
+        }
+
+        Ok(Some(SocketAddr::new(address, 0)))
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_device(socket: &SockRef, interface: &str) -> io::Result<()> {
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|err| io::Error::new(err.kind(), format!("cannot bind to interface {interface}: {err}")))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_device(_socket: &SockRef, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on linux and android",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    #[test]
+    fn test_bind_address() {
+        let source = SourceBind::new(None, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None).unwrap();
+        source
+            .bind(SockRef::from(&socket), &"127.0.0.1:53".parse().unwrap())
+            .unwrap();
+        let local = socket.local_addr().unwrap().as_socket().unwrap();
+        assert_eq!(local.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let socket = UdpSocket::bind("[::1]:0").unwrap();
+        assert!(
+            source
+                .bind(SockRef::from(&socket), &"[::1]:53".parse().unwrap())
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,675 @@
+//! Live statistics of the tunnels, served as json on `--admin-listen` for `wstunnel top` to display them, and the
+//! status of the process dumped by `wstunnel status` or on SIGUSR1
+use crate::health::SERVER_REACHABILITY;
+use crate::hooks;
+use crate::hooks::{TunnelEvent, TunnelEventKind};
+use crate::metrics;
+use crate::tunnel::client::ReconnectEventKind;
+use crate::tunnel::protocol::close_reason::CloseReason;
+use crate::tunnel::{LocalProtocol, RemoteAddr};
+use ahash::AHashMap;
+use anyhow::Context;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::Response;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::task::{Context as TaskContext, Poll, ready};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+/// Which end of the tunnels a wstunnel process is. A client and a server can run in the same process, and share the
+/// ids of their tunnels
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Client,
+    Server,
+}
+
+pub struct TunnelStats {
+    id: String,
+    side: Side,
+    protocol: &'static str,
+    remote: String,
+    peer: Option<SocketAddr>,
+    label: Option<String>,
+    /// Device id of the client of the tunnel, only known by the server
+    device: Option<String>,
+    opened_at: Instant,
+    /// Bytes read from the local side of the tunnel, sent to the other end
+    tx_bytes: AtomicU64,
+    /// Bytes received from the other end, written to the local side of the tunnel
+    rx_bytes: AtomicU64,
+    /// Round trip time of the last websocket ping, in microseconds. 0 until one is answered
+    rtt_us: AtomicU64,
+    /// Round trip time of the websocket pings smoothed like the srtt of TCP, in microseconds. 0 until one is answered
+    srtt_us: AtomicU64,
+    /// Times the tunnel was resumed after losing the connection between the client and the server
+    reconnects: AtomicU64,
+    /// Failure of the local side of the tunnel, the first one seen. Unset if it ended cleanly
+    failure: OnceLock<CloseReason>,
+}
+
+/// Smoothed round trip time of the client with the server, over the pings of all its tunnels. 0 until one is answered
+static SERVER_SRTT_US: AtomicU64 = AtomicU64::new(0);
+
+/// Smoothed round trip time of the client with the server, i.e: for the X-Wstunnel-RTT header of the http proxies
+pub fn server_rtt() -> Option<Duration> {
+    Some(SERVER_SRTT_US.load(Ordering::Relaxed))
+        .filter(|srtt| *srtt > 0)
+        .map(Duration::from_micros)
+}
+
+/// Weigh a new sample of the round trip time like TCP does, by 1/8
+fn smooth_rtt(srtt_us: &AtomicU64, rtt_us: u64) {
+    let _ = srtt_us.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |srtt| {
+        Some(if srtt == 0 { rtt_us } else { (srtt * 7 + rtt_us) / 8 })
+    });
+}
+
+impl TunnelStats {
+    pub fn set_rtt(&self, rtt: Duration) {
+        let rtt = u64::try_from(rtt.as_micros())
+            .unwrap_or(u64::MAX / 8)
+            .clamp(1, u64::MAX / 8);
+        self.rtt_us.store(rtt, Ordering::Relaxed);
+        smooth_rtt(&self.srtt_us, rtt);
+        if self.side == Side::Client {
+            smooth_rtt(&SERVER_SRTT_US, rtt);
+        }
+    }
+
+    /// Remember the failure of the local side, told to the close hook
+    fn failed(&self, err: &io::Error) {
+        let _ = self.failure.set(CloseReason::of_io_error(err));
+    }
+
+    fn run_hook(&self, kind: TunnelEventKind) {
+        if !hooks::has_hook(self.side, kind) {
+            return;
+        }
+        let closed = kind == TunnelEventKind::Close;
+        hooks::run_hook(TunnelEvent {
+            event: kind,
+            side: self.side,
+            id: self.id.clone(),
+            protocol: self.protocol,
+            remote: self.remote.clone(),
+            peer: self.peer,
+            label: self.label.clone(),
+            duration_secs: Some(self.opened_at.elapsed().as_secs()).filter(|_| closed),
+            tx_bytes: Some(self.tx_bytes.load(Ordering::Relaxed)).filter(|_| closed),
+            rx_bytes: Some(self.rx_bytes.load(Ordering::Relaxed)).filter(|_| closed),
+            close_reason: Some(self.failure.get().copied().unwrap_or(CloseReason::Normal)).filter(|_| closed),
+        });
+    }
+}
+
+pub struct Stats {
+    started_at: Instant,
+    tunnels: Mutex<AHashMap<(Side, String), Arc<TunnelStats>>>,
+    tunnels_opened: AtomicU64,
+    /// Bytes of the tunnels already closed, the ones of the live tunnels are added when taking a snapshot
+    closed_tx_bytes: AtomicU64,
+    closed_rx_bytes: AtomicU64,
+    reconnects: AtomicU64,
+    reverse_tunnels: ReverseTunnelCounters,
+    /// State of the pool of connections to the server, only set on a client
+    pool: Mutex<Option<Box<dyn Fn() -> PoolStatus + Send + Sync>>>,
+}
+
+/// Times the reverse tunnels lost their connection to the server, got it back, or gave up getting it back
+#[derive(Debug, Default)]
+struct ReverseTunnelCounters {
+    disconnects: AtomicU64,
+    reconnects: AtomicU64,
+    gave_up: AtomicU64,
+}
+
+pub static STATS: LazyLock<Stats> = LazyLock::new(Stats::new);
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            tunnels: Mutex::new(AHashMap::new()),
+            tunnels_opened: AtomicU64::new(0),
+            closed_tx_bytes: AtomicU64::new(0),
+            closed_rx_bytes: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            reverse_tunnels: ReverseTunnelCounters::default(),
+            pool: Mutex::new(None),
+        }
+    }
+
+    /// Start tracking a tunnel, until the returned registration is dropped
+    pub fn register(
+        &'static self,
+        side: Side,
+        id: &str,
+        remote: &RemoteAddr,
+        peer: Option<SocketAddr>,
+        label: Option<&str>,
+        device: Option<&str>,
+    ) -> Arc<Registration> {
+        let tunnel = Arc::new(TunnelStats {
+            id: id.to_string(),
+            side,
+            protocol: protocol_name(&remote.protocol),
+            remote: format!("{}:{}", remote.host, remote.port),
+            peer,
+            label: label.map(str::to_string),
+            device: device.map(str::to_string),
+            opened_at: Instant::now(),
+            tx_bytes: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            rtt_us: AtomicU64::new(0),
+            srtt_us: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            failure: OnceLock::new(),
+        });
+        metrics::Metrics::inc(&self.tunnels_opened);
+        self.tunnels.lock().insert((side, id.to_string()), tunnel.clone());
+        tunnel.run_hook(TunnelEventKind::Connect);
+        Arc::new(Registration { stats: self, tunnel })
+    }
+
+    /// Statistics of a live tunnel
+    pub fn get(&self, side: Side, id: &str) -> Option<Arc<TunnelStats>> {
+        self.tunnels.lock().get(&(side, id.to_string())).cloned()
+    }
+
+    pub fn reconnected(&self, side: Side, id: &str) {
+        metrics::Metrics::inc(&self.reconnects);
+        if let Some(tunnel) = self.get(side, id) {
+            metrics::Metrics::inc(&tunnel.reconnects);
+        }
+    }
+
+    pub fn reverse_tunnel_event(&self, kind: ReconnectEventKind) {
+        let counter = match kind {
+            ReconnectEventKind::Disconnected => &self.reverse_tunnels.disconnects,
+            ReconnectEventKind::Reconnected => &self.reverse_tunnels.reconnects,
+            ReconnectEventKind::GaveUp => &self.reverse_tunnels.gave_up,
+        };
+        metrics::Metrics::inc(counter);
+    }
+
+    /// Report the state of the pool of connections of the client, the last one set when several clients share the process
+    pub fn watch_pool(&self, state: impl Fn() -> PoolStatus + Send + Sync + 'static) {
+        *self.pool.lock() = Some(Box::new(state));
+    }
+
+    pub fn status(&self) -> Status {
+        let pool = self.pool.lock().as_ref().map(|state| state());
+        Status {
+            snapshot: self.snapshot(),
+            server_unreachable_secs: pool
+                .is_some()
+                .then(|| SERVER_REACHABILITY.unreachable_for().map(|since| since.as_secs()))
+                .flatten(),
+            pool,
+            reverse_tunnels: ReverseTunnelStatus {
+                disconnects: self.reverse_tunnels.disconnects.load(Ordering::Relaxed),
+                reconnects: self.reverse_tunnels.reconnects.load(Ordering::Relaxed),
+                gave_up: self.reverse_tunnels.gave_up.load(Ordering::Relaxed),
+            },
+            memory_rss_bytes: memory_rss_bytes(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut tunnels: Vec<TunnelSnapshot> = self
+            .tunnels
+            .lock()
+            .values()
+            .map(|tunnel| TunnelSnapshot {
+                id: tunnel.id.clone(),
+                side: tunnel.side,
+                protocol: tunnel.protocol.to_string(),
+                remote: tunnel.remote.clone(),
+                peer: tunnel.peer,
+                label: tunnel.label.clone(),
+                device: tunnel.device.clone(),
+                age_secs: tunnel.opened_at.elapsed().as_secs(),
+                tx_bytes: tunnel.tx_bytes.load(Ordering::Relaxed),
+                rx_bytes: tunnel.rx_bytes.load(Ordering::Relaxed),
+                rtt_us: Some(tunnel.rtt_us.load(Ordering::Relaxed)).filter(|rtt| *rtt > 0),
+                srtt_us: Some(tunnel.srtt_us.load(Ordering::Relaxed)).filter(|srtt| *srtt > 0),
+                reconnects: tunnel.reconnects.load(Ordering::Relaxed),
+            })
+            .collect();
+        tunnels.sort_by(|a, b| a.id.cmp(&b.id).then(a.side.cmp(&b.side)));
+
+        Snapshot {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            tunnels_opened: self.tunnels_opened.load(Ordering::Relaxed),
+            tx_bytes: self.closed_tx_bytes.load(Ordering::Relaxed) + tunnels.iter().map(|t| t.tx_bytes).sum::<u64>(),
+            rx_bytes: self.closed_rx_bytes.load(Ordering::Relaxed) + tunnels.iter().map(|t| t.rx_bytes).sum::<u64>(),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            tunnels,
+        }
+    }
+}
+
+/// A tunnel tracked by the statistics, removed from them once dropped
+pub struct Registration {
+    stats: &'static Stats,
+    tunnel: Arc<TunnelStats>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let key = (self.tunnel.side, self.tunnel.id.clone());
+        let mut tunnels = self.stats.tunnels.lock();
+        // The id of a tunnel is chosen by the client, another tunnel may have taken it since
+        if tunnels
+            .get(&key)
+            .is_some_and(|tunnel| Arc::ptr_eq(tunnel, &self.tunnel))
+        {
+            tunnels.remove(&key);
+        }
+        drop(tunnels);
+        metrics::Metrics::add(&self.stats.closed_tx_bytes, self.tunnel.tx_bytes.load(Ordering::Relaxed));
+        metrics::Metrics::add(&self.stats.closed_rx_bytes, self.tunnel.rx_bytes.load(Ordering::Relaxed));
+        self.tunnel.run_hook(TunnelEventKind::Close);
+    }
+}
+
+/// Statistics of a wstunnel process, as served by its admin api
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub uptime_secs: u64,
+    pub tunnels_opened: u64,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    pub reconnects: u64,
+    pub tunnels: Vec<TunnelSnapshot>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TunnelSnapshot {
+    pub id: String,
+    pub side: Side,
+    pub protocol: String,
+    pub remote: String,
+    pub peer: Option<SocketAddr>,
+    pub label: Option<String>,
+    /// Not served by the servers older than the device ids
+    #[serde(default)]
+    pub device: Option<String>,
+    pub age_secs: u64,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    pub rtt_us: Option<u64>,
+    pub srtt_us: Option<u64>,
+    pub reconnects: u64,
+}
+
+/// Everything worth looking at when debugging a wstunnel process in the field, as served on `/status` of the admin api
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Status {
+    #[serde(flatten)]
+    pub snapshot: Snapshot,
+    /// None on a server
+    pub pool: Option<PoolStatus>,
+    /// For how long every connection to the server failed, None if the last one succeeded or on a server
+    pub server_unreachable_secs: Option<u64>,
+    pub reverse_tunnels: ReverseTunnelStatus,
+    /// Resident memory of the process, None where it cannot be read
+    pub memory_rss_bytes: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PoolStatus {
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub connections_created: u64,
+    pub connections_closed_broken: u64,
+    /// Tunnels that could not get a connection to the server in time
+    pub get_timed_out: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReverseTunnelStatus {
+    pub disconnects: u64,
+    pub reconnects: u64,
+    pub gave_up: u64,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let snapshot = &self.snapshot;
+        writeln!(
+            f,
+            "uptime: {}s   tunnels: {} live, {} opened   tx: {}   rx: {}",
+            snapshot.uptime_secs,
+            snapshot.tunnels.len(),
+            snapshot.tunnels_opened,
+            human_bytes(snapshot.tx_bytes),
+            human_bytes(snapshot.rx_bytes),
+        )?;
+        if let Some(pool) = &self.pool {
+            match self.server_unreachable_secs {
+                None => writeln!(f, "server: reachable")?,
+                Some(secs) => writeln!(f, "server: unreachable for {secs}s")?,
+            }
+            writeln!(
+                f,
+                "connection pool: {} connections, {} idle, {} created, {} broken, {} timed out",
+                pool.connections,
+                pool.idle_connections,
+                pool.connections_created,
+                pool.connections_closed_broken,
+                pool.get_timed_out,
+            )?;
+        }
+        writeln!(
+            f,
+            "reconnects: {} tunnels resumed   reverse tunnels: {} disconnects, {} reconnects, {} gave up",
+            snapshot.reconnects,
+            self.reverse_tunnels.disconnects,
+            self.reverse_tunnels.reconnects,
+            self.reverse_tunnels.gave_up,
+        )?;
+        match self.memory_rss_bytes {
+            Some(rss) => writeln!(f, "memory: {} resident", human_bytes(rss))?,
+            None => writeln!(f, "memory: unknown")?,
+        }
+        for tunnel in &snapshot.tunnels {
+            write!(
+                f,
+                "tunnel {} {} {} {}",
+                tunnel.id,
+                if tunnel.side == Side::Client {
+                    "client"
+                } else {
+                    "server"
+                },
+                tunnel.protocol,
+                tunnel.remote
+            )?;
+            if let Some(peer) = tunnel.peer {
+                write!(f, " peer={peer}")?;
+            }
+            if let Some(label) = &tunnel.label {
+                write!(f, " label={label}")?;
+            }
+            if let Some(device) = &tunnel.device {
+                write!(f, " device={device}")?;
+            }
+            write!(
+                f,
+                " age={}s tx={} rx={}",
+                tunnel.age_secs,
+                human_bytes(tunnel.tx_bytes),
+                human_bytes(tunnel.rx_bytes)
+            )?;
+            if let Some(rtt) = tunnel.srtt_us.or(tunnel.rtt_us) {
+                write!(f, " rtt={:.1}ms", rtt as f64 / 1000.0)?;
+            }
+            writeln!(f, " reconnects={}", tunnel.reconnects)?;
+        }
+        Ok(())
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{value:.1}{}", UNITS[unit])
+    }
+}
+
+/// Resident memory of the process, as accounted by the kernel
+#[cfg(target_os = "linux")]
+fn memory_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn memory_rss_bytes() -> Option<u64> {
+    None
+}
+
+pub(crate) fn protocol_name(protocol: &LocalProtocol) -> &'static str {
+    match protocol {
+        LocalProtocol::Tcp { .. } => "tcp",
+        LocalProtocol::Udp { .. } => "udp",
+        LocalProtocol::Stdio { .. } => "stdio",
+        LocalProtocol::StdioUdp { .. } => "stdio-udp",
+        LocalProtocol::Socks5 { .. } => "socks5",
+        LocalProtocol::TProxyTcp => "tproxy-tcp",
+        LocalProtocol::TProxyUdp { .. } => "tproxy-udp",
+        LocalProtocol::HttpProxy { .. } => "http-proxy",
+        LocalProtocol::ReverseTcp { .. } => "reverse-tcp",
+        LocalProtocol::ReverseUdp { .. } => "reverse-udp",
+        LocalProtocol::ReverseSocks5 { .. } => "reverse-socks5",
+        LocalProtocol::ReverseHttpProxy { .. } => "reverse-http-proxy",
+        LocalProtocol::ReverseUnix { .. } => "reverse-unix",
+        LocalProtocol::ReverseHttpIngress { .. } => "reverse-http-ingress",
+        LocalProtocol::ReverseEncryptedDns { .. } => "reverse-encrypted-dns",
+        LocalProtocol::Unix { .. } => "unix",
+        LocalProtocol::Vsock { .. } => "vsock",
+        LocalProtocol::Sctp => "sctp",
+        LocalProtocol::Mux => "mux",
+    }
+}
+
+/// Count the bytes going through both halves of the local side of a tunnel
+pub fn track<R, W>(registration: Arc<Registration>, rx: R, tx: W) -> (StatsReader<R>, StatsWriter<W>) {
+    let reader = StatsReader {
+        inner: rx,
+        registration: registration.clone(),
+    };
+    let writer = StatsWriter {
+        inner: tx,
+        registration,
+    };
+    (reader, writer)
+}
+
+/// Count the bytes read from the inner stream
+#[pin_project]
+pub struct StatsReader<R> {
+    #[pin]
+    inner: R,
+    registration: Arc<Registration>,
+}
+
+impl<R: AsyncRead> AsyncRead for StatsReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        if let Err(err) = ready!(this.inner.poll_read(cx, buf)) {
+            this.registration.tunnel.failed(&err);
+            return Poll::Ready(Err(err));
+        }
+        metrics::Metrics::add(&this.registration.tunnel.tx_bytes, (buf.filled().len() - filled) as u64);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Count the bytes written to the inner stream
+#[pin_project]
+pub struct StatsWriter<W> {
+    #[pin]
+    inner: W,
+    registration: Arc<Registration>,
+}
+
+impl<W: AsyncWrite> AsyncWrite for StatsWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let written = match ready!(this.inner.poll_write(cx, buf)) {
+            Ok(written) => written,
+            Err(err) => {
+                this.registration.tunnel.failed(&err);
+                return Poll::Ready(Err(err));
+            }
+        };
+        metrics::Metrics::add(&this.registration.tunnel.rx_bytes, written as u64);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+pub async fn run_admin_server(bind: SocketAddr) -> anyhow::Result<()> {
+    // The uptime is counted from the start of the process, not from the first request
+    LazyLock::force(&STATS);
+    let listener = TcpListener::bind(bind)
+        .await
+        .with_context(|| format!("Cannot bind admin server on {bind}"))?;
+    info!("Serving tunnels statistics on http://{bind}/tunnels and http://{bind}/status");
+
+    metrics::serve_http(listener, |path| {
+        let body = match path {
+            "/tunnels" => serde_json::to_vec(&STATS.snapshot()),
+            "/status" => serde_json::to_vec(&STATS.status()),
+            _ => return metrics::not_found(),
+        };
+        Response::builder()
+            .header("content-type", "application/json")
+            .body(Full::new(Bytes::from(body.unwrap_or_default())))
+    })
+    .await
+}
+
+/// Log the status of the process each time it receives a SIGUSR1, i.e: `kill -USR1 $(pidof wstunnel)`, to debug it
+/// where its admin api is not enabled
+#[cfg(unix)]
+pub async fn log_status_on_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    // A client and a server in the same process share their statistics, a single dump is enough
+    static LISTENING: AtomicBool = AtomicBool::new(false);
+    if LISTENING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            warn!("Cannot listen for SIGUSR1 to dump the status: {err}");
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        info!("Status on SIGUSR1\n{}", STATS.status().to_string().trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use url::Host;
+
+    #[tokio::test]
+    async fn test_track_tunnel() {
+        let stats: &'static Stats = Box::leak(Box::new(Stats::new()));
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::TProxyTcp,
+            host: Host::Domain("example.com".to_string()),
+            port: 443,
+        };
+        let registration = stats.register(Side::Server, "tunnel-1", &remote, None, Some("ci"), Some("sensor-42"));
+        let (local, mut peer) = tokio::io::duplex(64);
+        let (rx, tx) = tokio::io::split(local);
+        let (mut rx, mut tx) = track(registration, rx, tx);
+
+        peer.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        rx.read_exact(&mut buf).await.unwrap();
+        tx.write_all(b"hi").await.unwrap();
+        stats
+            .get(Side::Server, "tunnel-1")
+            .unwrap()
+            .set_rtt(Duration::from_millis(20));
+        stats
+            .get(Side::Server, "tunnel-1")
+            .unwrap()
+            .set_rtt(Duration::from_millis(28));
+        stats.reconnected(Side::Server, "tunnel-1");
+        assert!(stats.get(Side::Client, "tunnel-1").is_none());
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.tunnels_opened, 1);
+        assert_eq!(snapshot.reconnects, 1);
+        assert_eq!((snapshot.tx_bytes, snapshot.rx_bytes), (5, 2));
+        let tunnel = &snapshot.tunnels[0];
+        assert_eq!(tunnel.protocol, "tproxy-tcp");
+        assert_eq!(tunnel.remote, "example.com:443");
+        assert_eq!(tunnel.label.as_deref(), Some("ci"));
+        assert_eq!(tunnel.device.as_deref(), Some("sensor-42"));
+        assert_eq!(tunnel.rtt_us, Some(28_000));
+        assert_eq!(tunnel.srtt_us, Some(21_000));
+        assert_eq!(tunnel.reconnects, 1);
+
+        // The bytes of the closed tunnels stay in the totals
+        drop((rx, tx));
+        let snapshot = stats.snapshot();
+        assert!(snapshot.tunnels.is_empty());
+        assert_eq!((snapshot.tx_bytes, snapshot.rx_bytes), (5, 2));
+    }
+
+    #[test]
+    fn test_status() {
+        let stats: &'static Stats = Box::leak(Box::new(Stats::new()));
+        stats.reverse_tunnel_event(ReconnectEventKind::Disconnected);
+        stats.reverse_tunnel_event(ReconnectEventKind::Reconnected);
+        let status = stats.status();
+        assert!(status.pool.is_none());
+        assert_eq!((status.reverse_tunnels.disconnects, status.reverse_tunnels.reconnects), (1, 1));
+        assert!(!status.to_string().contains("connection pool"));
+        #[cfg(target_os = "linux")]
+        assert!(status.memory_rss_bytes.is_some_and(|rss| rss > 0));
+
+        stats.watch_pool(|| PoolStatus {
+            connections: 2,
+            idle_connections: 1,
+            ..PoolStatus::default()
+        });
+        let status = stats.status();
+        assert_eq!(status.pool.as_ref().map(|pool| pool.connections), Some(2));
+        assert!(status.to_string().contains("connection pool: 2 connections, 1 idle"));
+
+        // The snapshot is flattened, so the status can be read as one by the clients of /tunnels
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["tunnels_opened"], 0);
+        assert_eq!(json["pool"]["idle_connections"], 1);
+    }
+}
@@ -1,67 +1,144 @@
+use crate::config::LocalToRemote;
+use crate::config::parsers::parse_tunnel_arg;
+use crate::embedded_certificate;
 use crate::executor::DefaultTokioExecutor;
 use crate::protocols;
 use crate::protocols::dns::DnsResolver;
 use crate::restrictions::types;
 use crate::restrictions::types::{AllowConfig, MatchConfig, RestrictionConfig, RestrictionsRules};
 use crate::somark::SoMark;
-use crate::tunnel::client::{WsClient, WsClientConfig};
+use crate::source_bind::{SourceBind, UNBOUND};
+use crate::tunnel::client::{
+    Browser, Camouflage, OnListenerError, RedirectPolicy, SplitRequests, WsClient, WsClientConfig,
+};
 use crate::tunnel::listeners::{TcpTunnelListener, UdpTunnelListener};
-use crate::tunnel::server::{WsServer, WsServerConfig};
+use crate::tunnel::protocol::close_reason::WsCloseCodes;
+use crate::tunnel::server::{
+    ProtocolHandler, SniPassthrough, SniffedProtocol, TlsServerConfig, WsServer, WsServerConfig,
+};
+use crate::tunnel::transport::http1::is_session_request;
+use crate::tunnel::transport::io::LocalReset;
+use crate::tunnel::transport::obfuscation::TrafficObfuscation;
+use crate::tunnel::transport::websocket::DEFAULT_MAX_FRAME_SIZE;
 use crate::tunnel::transport::{TransportAddr, TransportScheme};
-use bytes::BytesMut;
+use crate::tunnel::{AccessList, LocalProtocol, RemoteAddr, UdpFlowEviction};
+use crate::{FailureKind, start_tunnels};
+use bytes::{Bytes, BytesMut};
 use futures_util::StreamExt;
+use futures_util::future::join_all;
+use http_body_util::Empty;
+use hyper::body::Incoming;
+use hyper::header::{LOCATION, RETRY_AFTER};
 use hyper::http::HeaderValue;
+use hyper::service::{Service, service_fn};
+use hyper::{Method, Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use regex::Regex;
 use rstest::{fixture, rstest};
 use scopeguard::defer;
 use serial_test::serial;
+use socket2::SockRef;
 use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio::pin;
+use tokio_rustls::rustls::pki_types::DnsName;
 use url::Host;
+use uuid::Uuid;
 
 #[fixture]
-fn dns_resolver() -> DnsResolver {
+pub(crate) fn dns_resolver() -> DnsResolver {
     DnsResolver::new_from_urls(&[], None, SoMark::new(None), true).expect("Cannot create DNS resolver")
 }
 
 #[fixture]
 fn server_no_tls(dns_resolver: DnsResolver) -> WsServer {
+    server(dns_resolver, None)
+}
+
+pub(crate) fn server(dns_resolver: DnsResolver, traffic_obfuscation: Option<TrafficObfuscation>) -> WsServer {
     let server_config = WsServerConfig {
         socket_so_mark: SoMark::new(None),
         bind: "127.0.0.1:8080".parse().unwrap(),
         websocket_ping_frequency: Some(Duration::from_secs(10)),
         timeout_connect: Duration::from_secs(10),
         websocket_mask_frame: false,
+        tcp_fastopen: false,
+        dscp: None,
+        source_bind: SourceBind::default(),
+        tcp_defer_accept: None,
+        accept_shards: 1,
+        auth_hook: None,
+        auth_hook_timeout: Duration::from_secs(5),
+        oidc: None,
+        psk: None,
+        noise: None,
+        #[cfg(feature = "dns-transport")]
+        dns_transport: None,
+        #[cfg(feature = "icmp-transport")]
+        icmp_transport: None,
+        #[cfg(feature = "ssh-transport")]
+        ssh_transport: Some(crate::tunnel::server::SshTransportConfig::new(None).unwrap()),
+        websocket_max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        websocket_close_timeout: Duration::from_secs(1),
+        websocket_close_codes: WsCloseCodes::default(),
+        max_inflight_per_tunnel: 4 * 1024 * 1024,
+        pcap_dir: None,
         tls: None,
+        protocol_handlers: vec![],
+        tls_sni_passthrough: None,
         dns_resolver,
         restriction_config: None,
         http_proxy: None,
         remote_server_idle_timeout: Duration::from_secs(30),
+        tunnel_resume_max_timeout: Duration::from_secs(30),
+        tunnel_idle_timeout: None,
+        metrics_listen: None,
+        max_clients: None,
+        max_tunnels_per_client: None,
+        require_min_client_version: None,
+        http_ingress: None,
+        traffic_obfuscation,
+        reject_response: None,
+        standby_file: None,
+        cluster: None,
+        sticky_session: None,
     };
     WsServer::new(server_config, DefaultTokioExecutor::default())
 }
 
 #[fixture]
 async fn client_ws(dns_resolver: DnsResolver) -> WsClient {
-    let client_config = WsClientConfig {
-        remote_addr: TransportAddr::new(TransportScheme::Ws, Host::Ipv4("127.0.0.1".parse().unwrap()), 8080, None)
-            .unwrap(),
-        socket_so_mark: SoMark::new(None),
-        http_upgrade_path_prefix: "wstunnel".to_string(),
-        http_upgrade_credentials: None,
-        http_headers: HashMap::new(),
-        http_headers_file: None,
-        http_header_host: HeaderValue::from_static("127.0.0.1:8080"),
-        timeout_connect: Duration::from_secs(10),
-        websocket_ping_frequency: Some(Duration::from_secs(10)),
-        websocket_mask_frame: false,
+    client(
         dns_resolver,
-        http_proxy: None,
-    };
+        TransportScheme::Ws,
+        SplitRequests::Auto,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await
+}
+
+async fn client(
+    dns_resolver: DnsResolver,
+    transport: TransportScheme,
+    split_requests: SplitRequests,
+    mux: bool,
+    early_data: bool,
+    camouflage: Camouflage,
+) -> WsClient {
+    let mut client_config = client_config(dns_resolver, transport, 8080);
+    client_config.http_split_requests = split_requests;
+    client_config.mux = mux;
+    client_config.early_data = early_data;
+    client_config.camouflage = camouflage;
 
     WsClient::new(
         client_config,
@@ -74,8 +151,56 @@ async fn client_ws(dns_resolver: DnsResolver) -> WsClient {
     .unwrap()
 }
 
+pub(crate) fn client_config(dns_resolver: DnsResolver, transport: TransportScheme, port: u16) -> WsClientConfig {
+    WsClientConfig {
+        remote_addr: TransportAddr::new(transport, Host::Ipv4("127.0.0.1".parse().unwrap()), port, None).unwrap(),
+        socket_so_mark: SoMark::new(None),
+        http_upgrade_path_prefix: "wstunnel".to_string(),
+        device_id: None,
+        http_upgrade_credentials: None,
+        oidc_token_cache: None,
+        psk: None,
+        noise: None,
+        http_headers: HashMap::new(),
+        http_headers_file: None,
+        http_header_host: HeaderValue::from_str(&format!("127.0.0.1:{port}")).unwrap(),
+        timeout_connect: Duration::from_secs(10),
+        connection_health_check_interval: None,
+        connection_max_idle_age: None,
+        connection_warmup_timeout: Duration::from_secs(1),
+        websocket_ping_frequency: Some(Duration::from_secs(10)),
+        websocket_mask_frame: false,
+        tcp_fastopen: false,
+        dscp: None,
+        source_bind: SourceBind::default(),
+        websocket_max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        websocket_close_timeout: Duration::from_secs(1),
+        websocket_close_codes: WsCloseCodes::default(),
+        max_inflight_per_tunnel: 4 * 1024 * 1024,
+        http_split_requests: SplitRequests::Auto,
+        mux: false,
+        transport_max_lifetime: None,
+        transport_max_bytes: None,
+        early_data: false,
+        camouflage: Camouflage::default(),
+        upgrade_max_redirects: 5,
+        upgrade_redirect_policy: RedirectPolicy::default(),
+        standby_servers: vec![],
+        pcap_dir: None,
+        integrity_check_interval: None,
+        network_sim: None,
+        dns_resolver,
+        http_proxy: None,
+        reverse_tunnel_max_retries: None,
+        reverse_tunnel_hook: None,
+        reverse_tunnel_probe_interval: None,
+        #[cfg(feature = "dns-transport")]
+        dns_transport_resolver: None,
+    }
+}
+
 #[fixture]
-fn no_restrictions() -> RestrictionsRules {
+pub(crate) fn no_restrictions() -> RestrictionsRules {
     pub fn default_host() -> Regex {
         Regex::new("^.*$").unwrap()
     }
@@ -89,6 +214,7 @@ fn no_restrictions() -> RestrictionsRules {
         port: vec![],
         host: default_host(),
         cidr: default_cidr(),
+        alias: vec![],
     });
     let reverse_tunnel = AllowConfig::ReverseTunnel(types::AllowReverseTunnelConfig {
         protocol: vec![],
@@ -96,11 +222,15 @@ fn no_restrictions() -> RestrictionsRules {
         port_mapping: Default::default(),
         cidr: default_cidr(),
         unix_path: default_host(),
+        vhost: default_host(),
+        bind: Default::default(),
+        owned: false,
     });
 
     RestrictionsRules {
         restrictions: vec![RestrictionConfig {
             name: "".to_string(),
+            description: None,
             r#match: vec![MatchConfig::Any],
             allow: vec![tunnels, reverse_tunnel],
         }],
@@ -121,7 +251,13 @@ const ENDPOINT_LISTEN: (SocketAddr, Host) = (
 #[tokio::test]
 #[serial]
 async fn test_tcp_tunnel(
-    #[future] client_ws: WsClient,
+    #[values(
+        (TransportScheme::Ws, SplitRequests::Auto),
+        (TransportScheme::Http1, SplitRequests::Never),
+        (TransportScheme::Http1, SplitRequests::Always),
+        (TransportScheme::Grpc, SplitRequests::Never)
+    )]
+    transport: (TransportScheme, SplitRequests),
     server_no_tls: WsServer,
     no_restrictions: RestrictionsRules,
     dns_resolver: DnsResolver,
@@ -129,20 +265,43 @@ async fn test_tcp_tunnel(
     let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
     defer! { server_h.abort(); };
 
-    let client_ws = client_ws.await;
+    let client_ws = client(
+        dns_resolver.clone(),
+        transport.0,
+        transport.1,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await;
 
-    let server = TcpTunnelListener::new(TUNNEL_LISTEN.0, (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()), false)
-        .await
-        .unwrap();
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
     tokio::spawn(async move {
         client_ws.run_tunnel(server).await.unwrap();
     });
 
-    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
+        .await
+        .unwrap();
     let mut client = protocols::tcp::connect(
         &TUNNEL_LISTEN.1,
         TUNNEL_LISTEN.0.port(),
         SoMark::new(None),
+        &UNBOUND,
+        false,
         Duration::from_secs(10),
         &dns_resolver,
     )
@@ -165,8 +324,8 @@ async fn test_tcp_tunnel(
 #[timeout(Duration::from_secs(10))]
 #[tokio::test]
 #[serial]
-async fn test_udp_tunnel(
-    #[future] client_ws: WsClient,
+async fn test_tcp_tunnel_reset(
+    #[values(TransportScheme::Ws, TransportScheme::Http)] transport: TransportScheme,
     server_no_tls: WsServer,
     no_restrictions: RestrictionsRules,
     dns_resolver: DnsResolver,
@@ -174,72 +333,1291 @@ async fn test_udp_tunnel(
     let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
     defer! { server_h.abort(); };
 
-    let client_ws = client_ws.await;
+    let client_ws = client(
+        dns_resolver.clone(),
+        transport,
+        SplitRequests::Auto,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await;
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
 
-    let server = UdpTunnelListener::new(TUNNEL_LISTEN.0, (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()), None)
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
         .await
         .unwrap();
+    let mut client = TcpStream::connect(TUNNEL_LISTEN.0).await.unwrap();
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = [0u8; 5];
+    dd.read_exact(&mut buf).await.unwrap();
+
+    // The destination aborting its connection aborts the local one too, instead of closing it cleanly
+    protocols::tcp::reset(SockRef::from(&dd)).unwrap();
+    drop(dd);
+    let mut buf = BytesMut::new();
+    let err = loop {
+        match client.read_buf(&mut buf).await {
+            Ok(0) => panic!("the local connection must be reset, not closed"),
+            Ok(_) => continue,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(20))]
+#[tokio::test]
+#[serial]
+async fn test_frame_size_negotiation(
+    #[values(TransportScheme::Ws, TransportScheme::Http)] transport: TransportScheme,
+    #[values((64 * 1024, DEFAULT_MAX_FRAME_SIZE), (DEFAULT_MAX_FRAME_SIZE, 64 * 1024))] max_frame_size: (usize, usize),
+    mut server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    // Each side only accepts small frames in turn, the other one must send it smaller ones
+    let (client_max_frame_size, server_max_frame_size) = max_frame_size;
+    let config = Arc::get_mut(&mut server_no_tls.config).unwrap();
+    config.websocket_max_frame_size = server_max_frame_size;
+    config.max_inflight_per_tunnel = 64 * 1024;
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let mut client_config = client_config(dns_resolver.clone(), transport, 8080);
+    client_config.websocket_max_frame_size = client_max_frame_size;
+    client_config.max_inflight_per_tunnel = 64 * 1024;
+    let client_ws = WsClient::new(
+        client_config,
+        1,
+        Duration::from_secs(1),
+        Duration::from_secs(1),
+        DefaultTokioExecutor::default(),
+    )
+    .await
+    .unwrap();
+
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
     tokio::spawn(async move {
         client_ws.run_tunnel(server).await.unwrap();
     });
 
-    let udp_listener = protocols::udp::run_server(ENDPOINT_LISTEN.0, None, |_| Ok(()), |s| Ok(s.clone()))
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
         .await
         .unwrap();
-    let mut client = protocols::udp::connect(
+    let mut client = protocols::tcp::connect(
         &TUNNEL_LISTEN.1,
         TUNNEL_LISTEN.0.port(),
+        SoMark::new(None),
+        &UNBOUND,
+        false,
         Duration::from_secs(10),
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = [0u8; 5];
+    dd.read_exact(&mut buf).await.unwrap();
+
+    // Far bigger than the frames and the flow control windows, in both directions at once
+    let payload: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+    let (mut client_rx, mut client_tx) = client.split();
+    let (mut dd_rx, mut dd_tx) = dd.split();
+    let (client_written, dd_written, to_server, to_client) = tokio::join!(
+        client_tx.write_all(&payload),
+        dd_tx.write_all(&payload),
+        async {
+            let mut buf = vec![0u8; payload.len()];
+            dd_rx.read_exact(&mut buf).await.unwrap();
+            buf
+        },
+        async {
+            let mut buf = vec![0u8; payload.len()];
+            client_rx.read_exact(&mut buf).await.unwrap();
+            buf
+        },
+    );
+    client_written.unwrap();
+    dd_written.unwrap();
+    assert!(to_server == payload);
+    assert!(to_client == payload);
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_half_close(
+    #[values(TransportScheme::Ws, TransportScheme::Http1, TransportScheme::Grpc)] transport: TransportScheme,
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let client_ws = client(
+        dns_resolver.clone(),
+        transport,
+        SplitRequests::Never,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await;
+
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
+        .await
+        .unwrap();
+    let mut client = protocols::tcp::connect(
+        &TUNNEL_LISTEN.1,
+        TUNNEL_LISTEN.0.port(),
         SoMark::new(None),
+        &UNBOUND,
+        false,
+        Duration::from_secs(10),
         &dns_resolver,
     )
     .await
     .unwrap();
 
+    // The FIN of the client reaches the destination as the end of its stream
     client.write_all(b"Hello").await.unwrap();
-    pin!(udp_listener);
-    let dd = udp_listener.next().await.unwrap().unwrap();
-    pin!(dd);
+    client.shutdown().await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = vec![];
+    dd.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"Hello");
+
+    // While the destination keeps sending its response
+    for _ in 0..3 {
+        dd.write_all(b"world!").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    dd.shutdown().await.unwrap();
+    let mut buf = vec![];
+    client.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"world!world!world!");
+}
+
+#[cfg(feature = "ssh-transport")]
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_ssh(server_no_tls: WsServer, no_restrictions: RestrictionsRules, dns_resolver: DnsResolver) {
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let client_ws = client(
+        dns_resolver.clone(),
+        TransportScheme::Ssh,
+        SplitRequests::Never,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await;
+
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
+        .await
+        .unwrap();
+    let mut client = protocols::tcp::connect(
+        &TUNNEL_LISTEN.1,
+        TUNNEL_LISTEN.0.port(),
+        SoMark::new(None),
+        &UNBOUND,
+        false,
+        Duration::from_secs(10),
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
     let mut buf = BytesMut::new();
     dd.read_buf(&mut buf).await.unwrap();
     assert_eq!(&buf[..5], b"Hello");
     buf.clear();
 
-    dd.writer().write_all(b"world!").await.unwrap();
+    dd.write_all(b"world!").await.unwrap();
     client.read_buf(&mut buf).await.unwrap();
     assert_eq!(&buf[..6], b"world!");
 }
 
-//#[rstest]
-//#[timeout(Duration::from_secs(10))]
-//#[tokio::test]
-//async fn test_socks5_tunnel(
-//    #[future] client_ws: WsClient,
-//    server_no_tls: WsServer,
-//    no_restrictions: RestrictionsRules,
-//    dns_resolver: DnsResolver,
-//) {
-//    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
-//    defer! { server_h.abort(); };
-//
-//    let client_ws = client_ws.await;
-//
-//    let server = Socks5TunnelListener::new(TUNNEL_LISTEN.0, None, None).await.unwrap();
-//    tokio::spawn(async move { client_ws.run_tunnel(server).await.unwrap(); });
-//
-//    let socks5_listener = protocols::socks5::run_server(ENDPOINT_LISTEN.0, None, None).await.unwrap();
-//    let mut client = protocols::tcp::connect(&TUNNEL_LISTEN.1, TUNNEL_LISTEN.0.port(), None, Duration::from_secs(10), &dns_resolver).await.unwrap();
-//
-//    client.write_all(b"Hello").await.unwrap();
-//    pin!(socks5_listener);
-//    let (dd, _) = socks5_listener.next().await.unwrap().unwrap();
-//    let (mut read, mut write) = dd.into_split();
-//    let mut buf = BytesMut::new();
-//    read.read_buf(&mut buf).await.unwrap();
-//    assert_eq!(&buf[..5], b"Hello");
-//    buf.clear();
-//
-//    write.write_all(b"world!").await.unwrap();
-//    client.read_buf(&mut buf).await.unwrap();
-//    assert_eq!(&buf[..6], b"world!");
-//}
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_early_data(
+    #[values(TransportScheme::Ws, TransportScheme::Http1)] transport: TransportScheme,
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let client_ws = client(
+        dns_resolver.clone(),
+        transport,
+        SplitRequests::Never,
+        false,
+        true,
+        Camouflage::default(),
+    )
+    .await;
+
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
+        .await
+        .unwrap();
+    let mut client = protocols::tcp::connect(
+        &TUNNEL_LISTEN.1,
+        TUNNEL_LISTEN.0.port(),
+        SoMark::new(None),
+        &UNBOUND,
+        false,
+        Duration::from_secs(10),
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+
+    // Sent right away, so it goes along with the request opening the tunnel
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+    buf.clear();
+
+    dd.write_all(b"world!").await.unwrap();
+    client.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..6], b"world!");
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_obfuscated(no_restrictions: RestrictionsRules, dns_resolver: DnsResolver) {
+    let obfuscation = TrafficObfuscation {
+        padding_percent: 50,
+        max_jitter: Duration::from_millis(5),
+    };
+    let server_h = tokio::spawn(server(dns_resolver.clone(), Some(obfuscation)).serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let client_ws = client(
+        dns_resolver.clone(),
+        TransportScheme::Ws,
+        SplitRequests::Auto,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await;
+
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
+        .await
+        .unwrap();
+    let mut client = protocols::tcp::connect(
+        &TUNNEL_LISTEN.1,
+        TUNNEL_LISTEN.0.port(),
+        SoMark::new(None),
+        &UNBOUND,
+        false,
+        Duration::from_secs(10),
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+
+    // The padding frames must never end up in the data of the tunnel
+    let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+    for chunk in data.chunks(1000) {
+        dd.write_all(chunk).await.unwrap();
+    }
+    let mut received = vec![0; data.len()];
+    client.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, data);
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_mux(
+    #[values(TransportScheme::Ws, TransportScheme::Http1)] transport: TransportScheme,
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let client_ws = client(
+        dns_resolver.clone(),
+        transport,
+        SplitRequests::Never,
+        true,
+        false,
+        Camouflage::default(),
+    )
+    .await;
+
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
+        .await
+        .unwrap();
+    let mut tunnels = vec![];
+    for _ in 0..2 {
+        let client = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            &UNBOUND,
+            false,
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await
+        .unwrap();
+        tunnels.push(client);
+    }
+
+    // Both tunnels share the same connection with the server, but each one gets its own stream
+    for (ix, client) in tunnels.iter_mut().enumerate() {
+        client.write_all(format!("Hello {ix}").as_bytes()).await.unwrap();
+        let mut dd = tcp_listener.next().await.unwrap().unwrap();
+        let mut buf = BytesMut::new();
+        dd.read_buf(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], format!("Hello {ix}").as_bytes());
+        buf.clear();
+
+        dd.write_all(b"world!").await.unwrap();
+        client.read_buf(&mut buf).await.unwrap();
+        assert_eq!(&buf[..6], b"world!");
+    }
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_camouflage(
+    #[values((TransportScheme::Ws, false), (TransportScheme::Http1, false), (TransportScheme::Ws, true))] transport: (
+        TransportScheme,
+        bool,
+    ),
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let camouflage = Camouflage {
+        random_path: true,
+        hosts: vec![DnsName::try_from("localhost").unwrap().to_owned()],
+        browser: Some(Browser::Chrome),
+    };
+    let client_ws = client(
+        dns_resolver.clone(),
+        transport.0,
+        SplitRequests::Never,
+        transport.1,
+        false,
+        camouflage,
+    )
+    .await;
+
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
+        .await
+        .unwrap();
+    let mut client = protocols::tcp::connect(
+        &TUNNEL_LISTEN.1,
+        TUNNEL_LISTEN.0.port(),
+        SoMark::new(None),
+        &UNBOUND,
+        false,
+        Duration::from_secs(10),
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+    buf.clear();
+
+    dd.write_all(b"world!").await.unwrap();
+    client.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..6], b"world!");
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_udp_tunnel(
+    #[future] client_ws: WsClient,
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let client_ws = client_ws.await;
+
+    let server = UdpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        None,
+        None,
+        UdpFlowEviction::DropNew,
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    let udp_listener = protocols::udp::run_server(
+        ENDPOINT_LISTEN.0,
+        None,
+        None,
+        None,
+        UdpFlowEviction::DropNew,
+        |_| Ok(()),
+        |s| Ok(s.clone()),
+    )
+    .await
+    .unwrap();
+    let mut client = protocols::udp::connect(
+        &TUNNEL_LISTEN.1,
+        TUNNEL_LISTEN.0.port(),
+        Duration::from_secs(10),
+        SoMark::new(None),
+        &UNBOUND,
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+
+    client.write_all(b"Hello").await.unwrap();
+    pin!(udp_listener);
+    let dd = udp_listener.next().await.unwrap().unwrap();
+    pin!(dd);
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+    buf.clear();
+
+    dd.writer().write_all(b"world!").await.unwrap();
+    client.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..6], b"world!");
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_redirected(
+    #[values(TransportScheme::Ws, TransportScheme::Http1)] transport: TransportScheme,
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    // A load balancer busy at first, then moving the server to another port
+    let nb_requests = Arc::new(AtomicUsize::new(0));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:8081").await.unwrap();
+    let redirect_h = tokio::spawn({
+        let nb_requests = nb_requests.clone();
+        async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let nb_requests = nb_requests.clone();
+                let service = service_fn(move |_: Request<Incoming>| {
+                    let response = match nb_requests.fetch_add(1, Ordering::Relaxed) {
+                        0 => Response::builder().status(503).header(RETRY_AFTER, "0"),
+                        _ => Response::builder()
+                            .status(308)
+                            .header(LOCATION, "http://127.0.0.1:8080/wstunnel/events"),
+                    };
+                    async move { response.body(Empty::<Bytes>::new()) }
+                });
+                tokio::spawn(async move {
+                    let _ = auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        }
+    });
+    defer! { redirect_h.abort(); };
+
+    let client_ws = client(
+        dns_resolver.clone(),
+        transport,
+        SplitRequests::Never,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await;
+    let mut client_config = client_ws.config.as_ref().clone();
+    client_config.remote_addr =
+        TransportAddr::new(transport, Host::Ipv4("127.0.0.1".parse().unwrap()), 8081, None).unwrap();
+    client_config.http_header_host = HeaderValue::from_static("127.0.0.1:8081");
+    let client_ws = WsClient::new(
+        client_config,
+        0,
+        Duration::from_secs(1),
+        Duration::from_secs(1),
+        DefaultTokioExecutor::default(),
+    )
+    .await
+    .unwrap();
+
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        let mut client = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            &UNBOUND,
+            false,
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await
+        .unwrap();
+
+        client.write_all(b"Hello").await.unwrap();
+        let mut dd = tcp_listener.next().await.unwrap().unwrap();
+        let mut buf = BytesMut::new();
+        dd.read_buf(&mut buf).await.unwrap();
+        assert_eq!(&buf[..5], b"Hello");
+        buf.clear();
+
+        dd.write_all(b"world!").await.unwrap();
+        client.read_buf(&mut buf).await.unwrap();
+        assert_eq!(&buf[..6], b"world!");
+    }
+
+    // The redirect is permanent, so the second tunnel goes straight to the server
+    assert_eq!(nb_requests.load(Ordering::Relaxed), 2);
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(20))]
+#[tokio::test]
+#[serial]
+async fn test_http2_split_requests_detection(
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    // In front of the server, a proxy buffering the body of the requests never forwards the bidirectional ones
+    let service = server_no_tls.service(no_restrictions).unwrap();
+    let nb_stalled = Arc::new(AtomicUsize::new(0));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:8080").await.unwrap();
+    let server_h = tokio::spawn({
+        let nb_stalled = nb_stalled.clone();
+        async move {
+            loop {
+                let (stream, peer_addr) = listener.accept().await.unwrap();
+                let service = service.clone();
+                let nb_stalled = nb_stalled.clone();
+                let service = service_fn(move |mut req: Request<Incoming>| {
+                    req.extensions_mut().insert(peer_addr);
+                    let stall = req.method() == Method::POST && !is_session_request(&req);
+                    let response = Service::call(&service, req);
+                    let nb_stalled = nb_stalled.clone();
+                    async move {
+                        if stall {
+                            nb_stalled.fetch_add(1, Ordering::Relaxed);
+                            std::future::pending::<()>().await;
+                        }
+                        response.await
+                    }
+                });
+                tokio::spawn(async move {
+                    let _ = auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        }
+    });
+    defer! { server_h.abort(); };
+
+    let mut client_ws = client(
+        dns_resolver.clone(),
+        TransportScheme::Http,
+        SplitRequests::Auto,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await;
+    client_ws.http_stall_timeout = Duration::from_secs(1);
+    let split_detected = client_ws.http_split_detected.clone();
+
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        let mut client = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            &UNBOUND,
+            false,
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await
+        .unwrap();
+
+        client.write_all(b"Hello").await.unwrap();
+        let mut dd = tcp_listener.next().await.unwrap().unwrap();
+        let mut buf = BytesMut::new();
+        dd.read_buf(&mut buf).await.unwrap();
+        assert_eq!(&buf[..5], b"Hello");
+        buf.clear();
+
+        dd.write_all(b"world!").await.unwrap();
+        client.read_buf(&mut buf).await.unwrap();
+        assert_eq!(&buf[..6], b"world!");
+        assert!(split_detected.load(Ordering::Relaxed));
+    }
+
+    // Once detected, the next tunnels split their requests right away
+    assert_eq!(nb_stalled.load(Ordering::Relaxed), 1);
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_mounted_service(
+    #[values(
+        (TransportScheme::Ws, SplitRequests::Auto),
+        (TransportScheme::Http1, SplitRequests::Always)
+    )]
+    transport: (TransportScheme, SplitRequests),
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let service = server_no_tls.service(no_restrictions).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:8080").await.unwrap();
+    let server_h = tokio::spawn(async move {
+        loop {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            let service = service.clone();
+            let service = service_fn(move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(peer_addr);
+                Service::call(&service, req)
+            });
+            tokio::spawn(async move {
+                let _ = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    defer! { server_h.abort(); };
+
+    let client_ws = client(
+        dns_resolver.clone(),
+        transport.0,
+        transport.1,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await;
+
+    let server = TcpTunnelListener::new(
+        TUNNEL_LISTEN.0,
+        None,
+        (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        AccessList::default(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false, None)
+        .await
+        .unwrap();
+    let mut client = protocols::tcp::connect(
+        &TUNNEL_LISTEN.1,
+        TUNNEL_LISTEN.0.port(),
+        SoMark::new(None),
+        &UNBOUND,
+        false,
+        Duration::from_secs(10),
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+    buf.clear();
+
+    dd.write_all(b"world!").await.unwrap();
+    client.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..6], b"world!");
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_protocol_passthrough(
+    mut server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    Arc::get_mut(&mut server_no_tls.config).unwrap().protocol_handlers = vec![(
+        SniffedProtocol::Unknown,
+        ProtocolHandler::Passthrough(ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+    )];
+    let bind = server_no_tls.config.bind;
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    // Neither tls, http nor ssh, so forwarded as is to the endpoint
+    let backend = tokio::net::TcpListener::bind(ENDPOINT_LISTEN.0).await.unwrap();
+    let mut client = protocols::tcp::connect(
+        &Host::Ipv4(Ipv4Addr::LOCALHOST),
+        bind.port(),
+        SoMark::new(None),
+        &SourceBind::default(),
+        false,
+        Duration::from_secs(10),
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+    client.write_all(b"\x00Hello").await.unwrap();
+
+    let (mut dd, _) = backend.accept().await.unwrap();
+    let mut buf = BytesMut::new();
+    while buf.len() < 6 {
+        dd.read_buf(&mut buf).await.unwrap();
+    }
+    assert_eq!(&buf[..6], b"\x00Hello");
+    buf.clear();
+
+    dd.write_all(b"world!").await.unwrap();
+    client.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..6], b"world!");
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tls_sni_passthrough(
+    mut server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    // The tests are built with both crypto providers, so none is picked by default
+    #[cfg(feature = "aws-lc-rs")]
+    let provider = tokio_rustls::rustls::crypto::aws_lc_rs::default_provider();
+    #[cfg(not(feature = "aws-lc-rs"))]
+    let provider = tokio_rustls::rustls::crypto::ring::default_provider();
+    let _ = provider.clone().install_default();
+
+    let config = Arc::get_mut(&mut server_no_tls.config).unwrap();
+    config.tls = Some(TlsServerConfig {
+        tls_certificate: parking_lot::Mutex::new(embedded_certificate::TLS_CERTIFICATE.0.clone()),
+        tls_key: parking_lot::Mutex::new(embedded_certificate::TLS_CERTIFICATE.1.clone_key()),
+        tls_client_ca_certificates: None,
+        tls_certificate_path: None,
+        tls_key_path: None,
+        tls_client_ca_certs_path: None,
+        ktls: false,
+    });
+    config.tls_sni_passthrough = Some(SniPassthrough {
+        server_names: vec!["tunnel.example.com".to_string()],
+        backend: (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()),
+    });
+    let bind = config.bind;
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    // A client hello for another name than the one of wstunnel reaches the endpoint untouched
+    let client_config = tokio_rustls::rustls::ClientConfig::builder_with_provider(Arc::new(provider))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(tokio_rustls::rustls::RootCertStore::empty())
+        .with_no_client_auth();
+    let server_name = "www.example.com".try_into().unwrap();
+    let mut tls = tokio_rustls::rustls::ClientConnection::new(Arc::new(client_config), server_name).unwrap();
+    let mut client_hello = vec![];
+    tls.write_tls(&mut client_hello).unwrap();
+
+    let backend = tokio::net::TcpListener::bind(ENDPOINT_LISTEN.0).await.unwrap();
+    let mut client = protocols::tcp::connect(
+        &Host::Ipv4(Ipv4Addr::LOCALHOST),
+        bind.port(),
+        SoMark::new(None),
+        &SourceBind::default(),
+        false,
+        Duration::from_secs(10),
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+    client.write_all(&client_hello).await.unwrap();
+
+    let (mut dd, _) = backend.accept().await.unwrap();
+    let mut buf = BytesMut::new();
+    while buf.len() < client_hello.len() {
+        dd.read_buf(&mut buf).await.unwrap();
+    }
+    assert_eq!(&buf[..], &client_hello[..]);
+}
+
+fn listener_tunnel(port: u16) -> LocalToRemote {
+    parse_tunnel_arg(&format!(
+        "tcp://127.0.0.1:{port}:{}:{}",
+        ENDPOINT_LISTEN.1,
+        ENDPOINT_LISTEN.0.port()
+    ))
+    .unwrap()
+}
+
+async fn free_port() -> u16 {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_skip_listener_error(dns_resolver: DnsResolver) {
+    let client_ws = client(
+        dns_resolver,
+        TransportScheme::Ws,
+        SplitRequests::Auto,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await;
+    let held = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let held_port = held.local_addr().unwrap().port();
+    let free_port = free_port().await;
+
+    // The other tunnels are started without the one whose port is taken
+    let tunnels = start_tunnels(
+        client_ws.clone(),
+        vec![],
+        vec![listener_tunnel(held_port), listener_tunnel(free_port)],
+        OnListenerError::Skip,
+        Duration::from_millis(10),
+    )
+    .await
+    .unwrap();
+    assert_eq!(tunnels.len(), 1);
+    let tunnels_h = tokio::spawn(join_all(tunnels));
+    defer! { tunnels_h.abort(); };
+    TcpStream::connect(("127.0.0.1", free_port)).await.unwrap();
+
+    // Without any tunnel left, the client exits as the listener cannot be bound
+    let Err(err) = start_tunnels(
+        client_ws,
+        vec![],
+        vec![listener_tunnel(held_port)],
+        OnListenerError::Skip,
+        Duration::from_millis(10),
+    )
+    .await
+    else {
+        panic!("the client must not start without any tunnel");
+    };
+    assert_eq!(FailureKind::of(&err), Some(FailureKind::Bind));
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_retry_listener_error(dns_resolver: DnsResolver) {
+    let client_ws = client(
+        dns_resolver,
+        TransportScheme::Ws,
+        SplitRequests::Auto,
+        false,
+        false,
+        Camouflage::default(),
+    )
+    .await;
+    let held = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let held_port = held.local_addr().unwrap().port();
+    let free_port = free_port().await;
+
+    // The tunnel whose port is taken is bound once the port is freed
+    let tunnels = start_tunnels(
+        client_ws.clone(),
+        vec![],
+        vec![listener_tunnel(held_port), listener_tunnel(free_port)],
+        OnListenerError::Retry,
+        Duration::from_millis(10),
+    )
+    .await
+    .unwrap();
+    assert_eq!(tunnels.len(), 2);
+    let tunnels_h = tokio::spawn(join_all(tunnels));
+    defer! { tunnels_h.abort(); };
+    TcpStream::connect(("127.0.0.1", free_port)).await.unwrap();
+    drop(held);
+    while TcpStream::connect(("127.0.0.1", held_port)).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    tunnels_h.abort();
+
+    // Without any tunnel started, the client waits for a listener to be bound, but not forever
+    let held = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let held_port = held.local_addr().unwrap().port();
+    let Err(err) = start_tunnels(
+        client_ws.clone(),
+        vec![],
+        vec![listener_tunnel(held_port)],
+        OnListenerError::Retry,
+        Duration::from_millis(10),
+    )
+    .await
+    else {
+        panic!("the client must not wait forever for its listeners");
+    };
+    assert_eq!(FailureKind::of(&err), Some(FailureKind::Bind));
+
+    let starting = tokio::spawn(start_tunnels(
+        client_ws,
+        vec![],
+        vec![listener_tunnel(held_port)],
+        OnListenerError::Retry,
+        Duration::from_millis(50),
+    ));
+    tokio::time::sleep(Duration::from_millis(75)).await;
+    drop(held);
+    let tunnels = starting.await.unwrap().unwrap();
+    assert_eq!(tunnels.len(), 1);
+}
+
+//#[rstest]
+//#[timeout(Duration::from_secs(10))]
+//#[tokio::test]
+//async fn test_socks5_tunnel(
+//    #[future] client_ws: WsClient,
+//    server_no_tls: WsServer,
+//    no_restrictions: RestrictionsRules,
+//    dns_resolver: DnsResolver,
+//) {
+//    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+//    defer! { server_h.abort(); };
+//
+//    let client_ws = client_ws.await;
+//
+//    let server = Socks5TunnelListener::new(TUNNEL_LISTEN.0, None, None).await.unwrap();
+//    tokio::spawn(async move { client_ws.run_tunnel(server).await.unwrap(); });
+//
+//    let socks5_listener = protocols::socks5::run_server(ENDPOINT_LISTEN.0, None, None).await.unwrap();
+//    let mut client = protocols::tcp::connect(&TUNNEL_LISTEN.1, TUNNEL_LISTEN.0.port(), None, Duration::from_secs(10), &dns_resolver).await.unwrap();
+//
+//    client.write_all(b"Hello").await.unwrap();
+//    pin!(socks5_listener);
+//    let (dd, _) = socks5_listener.next().await.unwrap().unwrap();
+//    let (mut read, mut write) = dd.into_split();
+//    let mut buf = BytesMut::new();
+//    read.read_buf(&mut buf).await.unwrap();
+//    assert_eq!(&buf[..5], b"Hello");
+//    buf.clear();
+//
+//    write.write_all(b"world!").await.unwrap();
+//    client.read_buf(&mut buf).await.unwrap();
+//    assert_eq!(&buf[..6], b"world!");
+//}
+
+async fn device_client(dns_resolver: DnsResolver, path_prefix: &str, device_id: &str) -> WsClient {
+    let mut client_config = client_config(dns_resolver, TransportScheme::Ws, 8080);
+    client_config.http_upgrade_path_prefix = path_prefix.to_string();
+    client_config.device_id = Some(device_id.to_string());
+    WsClient::new(
+        client_config,
+        1,
+        Duration::from_secs(1),
+        Duration::from_secs(1),
+        DefaultTokioExecutor::default(),
+    )
+    .await
+    .unwrap()
+}
+
+/// Open the reverse tunnel, which waits on the server for a connection to its port
+async fn open_reverse_tunnel(client: WsClient, remote: RemoteAddr) -> anyhow::Result<()> {
+    let (local, _peer) = tokio::io::duplex(1024);
+    client
+        .connect_to_server(Uuid::now_v7(), &remote, tokio::io::split(local), LocalReset::default())
+        .await
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_owned_port_of_device(
+    server_no_tls: WsServer,
+    mut no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    for allow in &mut no_restrictions.restrictions[0].allow {
+        if let AllowConfig::ReverseTunnel(allow) = allow {
+            allow.owned = true;
+        }
+    }
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let remote = RemoteAddr {
+        protocol: LocalProtocol::ReverseTcp {
+            resume: None,
+            idle_timeout: None,
+            v6only: None,
+            access: AccessList::default(),
+            keepalive: None,
+        },
+        host: Host::Ipv4(Ipv4Addr::LOCALHOST),
+        port: free_port().await,
+    };
+    let team_a = device_client(dns_resolver.clone(), "team-a", "sensor-42").await;
+    let tunnel_h = tokio::spawn(open_reverse_tunnel(team_a.clone(), remote.clone()));
+    defer! { tunnel_h.abort(); };
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // The device id is chosen by the client, it does not let another path prefix take the port
+    let team_b = device_client(dns_resolver.clone(), "team-b", "sensor-42").await;
+    let tunnel = tokio::time::timeout(Duration::from_secs(2), open_reverse_tunnel(team_b, remote.clone())).await;
+    assert!(matches!(tunnel, Ok(Err(_))));
+
+    // While the same device of the same path prefix waits on the port with the first tunnel
+    let tunnel = tokio::time::timeout(Duration::from_millis(500), open_reverse_tunnel(team_a, remote)).await;
+    assert!(tunnel.is_err());
+    assert!(!tunnel_h.is_finished());
+}
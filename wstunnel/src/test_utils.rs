@@ -0,0 +1,239 @@
+//! Run a server and a client in process, on ephemeral ports of localhost, to test real tunnels without the binary.
+//! Enabled with the `test-utils` feature. Everything started is stopped once its handle is dropped.
+//!
+//! ```no_run
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//! use wstunnel::LocalProtocol;
+//! use wstunnel::test_utils::{EchoServer, TestClient, TestServer, local_addr};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let tcp = LocalProtocol::Tcp {
+//!     proxy_protocol: false,
+//!     resume: None,
+//!     idle_timeout: None,
+//!     mirror: None,
+//!     balancing: None,
+//!     keepalive: None,
+//! };
+//! let server = TestServer::start(false, |server| server.psk("secret")).await?;
+//! let echo = EchoServer::start().await?;
+//! let bind = local_addr()?;
+//! let client = TestClient::start(
+//!     server
+//!         .client()
+//!         .psk("secret")
+//!         .add_local_tunnel(tcp, bind, echo.dest()),
+//! )?;
+//!
+//! let mut tunnel = client.connect(bind).await?;
+//! tunnel.write_all(b"hello").await?;
+//! let mut buf = [0u8; 5];
+//! tunnel.read_exact(&mut buf).await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::builder::{ClientBuilder, ServerBuilder};
+use crate::executor::JoinSetTokioExecutor;
+use anyhow::{Context, anyhow};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use url::{Host, Url};
+
+/// How long the server and the tunnels of the client are waited for before giving up
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+const READY_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+/// Attempts to start the server, in case another process took its port meanwhile
+const START_MAX_ATTEMPTS: usize = 3;
+
+/// Address of localhost with a port nobody listens on, to bind a server or a tunnel on
+pub fn local_addr() -> io::Result<SocketAddr> {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.local_addr()
+}
+
+/// Tests are often built with both crypto providers, so none is picked by default
+fn install_crypto_provider() {
+    #[cfg(feature = "aws-lc-rs")]
+    let _ = tokio_rustls::rustls::crypto::aws_lc_rs::default_provider().install_default();
+    #[cfg(not(feature = "aws-lc-rs"))]
+    let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
+}
+
+/// Connect to `addr`, retrying while nobody listens on it yet
+async fn connect_when_ready(addr: SocketAddr, task: &JoinHandle<anyhow::Result<()>>) -> anyhow::Result<TcpStream> {
+    let deadline = tokio::time::Instant::now() + READY_TIMEOUT;
+    loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) if err.kind() != io::ErrorKind::ConnectionRefused => return Err(err.into()),
+            Err(_) if task.is_finished() => return Err(anyhow!("stopped before listening on {addr}")),
+            Err(_) if tokio::time::Instant::now() >= deadline => {
+                return Err(anyhow!("nothing listening on {addr} after {READY_TIMEOUT:?}"));
+            }
+            Err(_) => tokio::time::sleep(READY_RETRY_INTERVAL).await,
+        }
+    }
+}
+
+/// Server listening on an ephemeral port of localhost
+pub struct TestServer {
+    url: Url,
+    task: JoinHandle<anyhow::Result<()>>,
+    _executor: JoinSetTokioExecutor,
+}
+
+impl TestServer {
+    /// Start a server, with tls and its embedded certificate if `tls`. `configure` sets its other options, and is
+    /// called again if the server has to be started on another port
+    pub async fn start(tls: bool, configure: impl Fn(ServerBuilder) -> ServerBuilder) -> anyhow::Result<Self> {
+        install_crypto_provider();
+        let scheme = if tls { "wss" } else { "ws" };
+        let mut last_err = anyhow!("server not started");
+        for _ in 0..START_MAX_ATTEMPTS {
+            let url = Url::parse(&format!("{scheme}://{}", local_addr()?))?;
+            let server = configure(ServerBuilder::new(url.clone())).build()?;
+            let executor = JoinSetTokioExecutor::default();
+            let task = tokio::spawn(crate::run_server(server, executor.clone()));
+
+            let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), url.port().unwrap_or_default());
+            match connect_when_ready(addr, &task).await {
+                Ok(_) => {
+                    return Ok(Self {
+                        url,
+                        task,
+                        _executor: executor,
+                    });
+                }
+                Err(err) => {
+                    task.abort();
+                    last_err = err;
+                }
+            }
+        }
+
+        Err(last_err.context("cannot start the server"))
+    }
+
+    /// Url of the server, to connect the clients to
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Builder of a client of this server, that accepts its embedded certificate
+    pub fn client(&self) -> ClientBuilder {
+        ClientBuilder::new(self.url.clone())
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Client running its tunnels until dropped
+pub struct TestClient {
+    task: JoinHandle<anyhow::Result<()>>,
+    _executor: JoinSetTokioExecutor,
+}
+
+impl TestClient {
+    pub fn start(client: ClientBuilder) -> anyhow::Result<Self> {
+        install_crypto_provider();
+        let client = client.build()?;
+        let executor = JoinSetTokioExecutor::default();
+        let task = tokio::spawn(crate::run_client(client, executor.clone()));
+        Ok(Self {
+            task,
+            _executor: executor,
+        })
+    }
+
+    /// Connect to the tunnel listening on `bind`, once the client listens on it
+    pub async fn connect(&self, bind: SocketAddr) -> anyhow::Result<TcpStream> {
+        connect_when_ready(bind, &self.task)
+            .await
+            .with_context(|| format!("cannot connect to the tunnel on {bind}"))
+    }
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Tcp server sending back everything it receives, as the destination of the tunnels
+pub struct EchoServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl EchoServer {
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = stream.into_split();
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Destination of a tunnel to this server
+    pub fn dest(&self) -> (Host, u16) {
+        (Host::Ipv4(Ipv4Addr::LOCALHOST), self.addr.port())
+    }
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LocalProtocol;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_tunnel_through_test_server() {
+        let server = TestServer::start(true, |server| server.psk("test-utils-secret"))
+            .await
+            .unwrap();
+        let echo = EchoServer::start().await.unwrap();
+        let bind = local_addr().unwrap();
+        let client = TestClient::start(server.client().psk("test-utils-secret").add_local_tunnel(
+            LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
+            bind,
+            echo.dest(),
+        ))
+        .unwrap();
+
+        let mut tunnel = client.connect(bind).await.unwrap();
+        tunnel.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tunnel.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
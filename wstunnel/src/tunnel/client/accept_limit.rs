@@ -0,0 +1,139 @@
+//! accept_limit - bound the connections accepted by a -L listener with max_conns and accept_rate, so a misbehaving
+//! local app cannot open tens of thousands of tunnels through the connection with the server
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// What to do with a connection accepted over the limits of its listener
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AcceptOverflow {
+    /// Hold it until it fits in the limits. Meanwhile, the next connections wait in the backlog of the listener
+    #[default]
+    Queue,
+    /// Close it right away
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AcceptLimits {
+    /// Tunnels of the listener open at the same time
+    pub max_conns: Option<usize>,
+    /// Connections accepted per second, in bursts of at most as many
+    pub accept_rate: Option<u32>,
+    pub overflow: AcceptOverflow,
+}
+
+impl AcceptLimits {
+    pub const fn is_empty(&self) -> bool {
+        self.max_conns.is_none() && self.accept_rate.is_none()
+    }
+}
+
+pub(super) struct AcceptLimiter {
+    limits: AcceptLimits,
+    conns: Arc<Semaphore>,
+    /// Connections that can be accepted right away under the accept rate, refilled over time
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl AcceptLimiter {
+    pub fn new(limits: AcceptLimits) -> Self {
+        Self {
+            limits,
+            conns: Arc::new(Semaphore::new(limits.max_conns.unwrap_or(Semaphore::MAX_PERMITS))),
+            tokens: limits.accept_rate.unwrap_or(0) as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Permit of the tunnel of a connection just accepted, to hold until the tunnel is closed.
+    /// None if the connection is over the limits and must be rejected
+    pub async fn admit(&mut self) -> Option<OwnedSemaphorePermit> {
+        let permit = match self.limits.overflow {
+            AcceptOverflow::Queue => self.conns.clone().acquire_owned().await.ok()?,
+            AcceptOverflow::Reject => self.conns.clone().try_acquire_owned().ok()?,
+        };
+
+        let Some(rate) = self.limits.accept_rate else {
+            return Some(permit);
+        };
+        let rate = rate as f64;
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * rate).min(rate);
+        self.refilled_at = now;
+        if self.tokens < 1.0 {
+            match self.limits.overflow {
+                AcceptOverflow::Reject => return None,
+                AcceptOverflow::Queue => {
+                    tokio::time::sleep(Duration::from_secs_f64((1.0 - self.tokens) / rate)).await;
+                    self.tokens = 1.0;
+                    self.refilled_at = Instant::now();
+                }
+            }
+        }
+        self.tokens -= 1.0;
+
+        Some(permit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_max_conns() {
+        let mut limiter = AcceptLimiter::new(AcceptLimits {
+            max_conns: Some(2),
+            accept_rate: None,
+            overflow: AcceptOverflow::Reject,
+        });
+        let first = limiter.admit().await.unwrap();
+        let _second = limiter.admit().await.unwrap();
+        assert!(limiter.admit().await.is_none());
+        drop(first);
+        assert!(limiter.admit().await.is_some());
+
+        let mut limiter = AcceptLimiter::new(AcceptLimits {
+            max_conns: Some(1),
+            accept_rate: None,
+            overflow: AcceptOverflow::Queue,
+        });
+        let first = limiter.admit().await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), limiter.admit())
+                .await
+                .is_err()
+        );
+        drop(first);
+        assert!(limiter.admit().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_accept_rate() {
+        let mut limiter = AcceptLimiter::new(AcceptLimits {
+            max_conns: None,
+            accept_rate: Some(20),
+            overflow: AcceptOverflow::Reject,
+        });
+        for _ in 0..20 {
+            assert!(limiter.admit().await.is_some());
+        }
+        assert!(limiter.admit().await.is_none());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(limiter.admit().await.is_some());
+
+        let mut limiter = AcceptLimiter::new(AcceptLimits {
+            max_conns: None,
+            accept_rate: Some(20),
+            overflow: AcceptOverflow::Queue,
+        });
+        let start = Instant::now();
+        for _ in 0..22 {
+            assert!(limiter.admit().await.is_some());
+        }
+        assert!(start.elapsed() >= Duration::from_millis(90), "{:?}", start.elapsed());
+    }
+}
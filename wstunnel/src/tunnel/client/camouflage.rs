@@ -0,0 +1,163 @@
+//! camouflage - vary what the connections of the client look like to the middleboxes, so they do not share a static
+//! fingerprint across reconnects: a random upgrade path, a host/sni picked among several ones, and the headers of a
+//! browser. Only the http layer is camouflaged, the tls handshake stays the one of rustls
+use hyper::HeaderMap;
+use hyper::header::{
+    ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, CACHE_CONTROL, HeaderName, HeaderValue, ORIGIN, PRAGMA,
+    SEC_WEBSOCKET_EXTENSIONS, USER_AGENT,
+};
+use rand::Rng;
+use rand::distr::Alphanumeric;
+use rand::seq::IndexedRandom;
+use std::str::FromStr;
+use tokio_rustls::rustls::pki_types::DnsName;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Browser {
+    Chrome,
+    Firefox,
+}
+
+impl FromStr for Browser {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "browser-chrome" => Ok(Self::Chrome),
+            "browser-firefox" => Ok(Self::Firefox),
+            _ => Err(()),
+        }
+    }
+}
+
+// To bump along the releases of the browsers, an outdated version stands out too
+const CHROME_USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/141.0.0.0 Safari/537.36";
+const CHROME_CLIENT_HINTS: &str = r#""Google Chrome";v="141", "Not?A_Brand";v="8", "Chromium";v="141""#;
+const FIREFOX_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:144.0) Gecko/20100101 Firefox/144.0";
+
+#[derive(Clone, Debug, Default)]
+pub struct Camouflage {
+    /// Add a random segment to the upgrade path of each request
+    pub random_path: bool,
+    /// Host and tls sni of the connections to the server, one is picked at random for each of them
+    pub hosts: Vec<DnsName<'static>>,
+    /// Send the headers of this browser with the requests
+    pub browser: Option<Browser>,
+}
+
+impl Camouflage {
+    /// Path of a request to the server. The server only looks at the prefix and at the `events` suffix, so the
+    /// segment in between can be anything
+    pub fn upgrade_path(&self, path_prefix: &str) -> String {
+        if !self.random_path {
+            return format!("/{path_prefix}/events");
+        }
+
+        let mut rng = rand::rng();
+        let len = rng.random_range(6..=16);
+        let segment: String = (&mut rng)
+            .sample_iter(Alphanumeric)
+            .take(len)
+            .map(|c| char::from(c).to_ascii_lowercase())
+            .collect();
+        format!("/{path_prefix}/{segment}/events")
+    }
+
+    pub fn pick_host(&self) -> Option<&DnsName<'static>> {
+        self.hosts.choose(&mut rand::rng())
+    }
+
+    /// Add the headers the browser sends with a websocket upgrade, or with a fetch for the http transports. The ones
+    /// already set are kept. The http transports never ask for a compressed response, as a CDN could then compress the
+    /// stream of the tunnel
+    pub fn add_browser_headers(&self, headers: &mut HeaderMap, origin: Option<&str>, websocket: bool) {
+        let Some(browser) = self.browser else {
+            return;
+        };
+
+        let mut browser_headers: Vec<(HeaderName, &'static str)> =
+            vec![(PRAGMA, "no-cache"), (CACHE_CONTROL, "no-cache")];
+        match browser {
+            Browser::Chrome => {
+                browser_headers.push((USER_AGENT, CHROME_USER_AGENT));
+                browser_headers.push((ACCEPT_LANGUAGE, "en-US,en;q=0.9"));
+                browser_headers.push((HeaderName::from_static("sec-ch-ua"), CHROME_CLIENT_HINTS));
+                browser_headers.push((HeaderName::from_static("sec-ch-ua-mobile"), "?0"));
+                browser_headers.push((HeaderName::from_static("sec-ch-ua-platform"), r#""Windows""#));
+                if websocket {
+                    browser_headers.push((ACCEPT_ENCODING, "gzip, deflate, br, zstd"));
+                    browser_headers.push((SEC_WEBSOCKET_EXTENSIONS, "permessage-deflate; client_max_window_bits"));
+                } else {
+                    browser_headers.push((ACCEPT, "*/*"));
+                }
+            }
+            Browser::Firefox => {
+                browser_headers.push((USER_AGENT, FIREFOX_USER_AGENT));
+                browser_headers.push((ACCEPT, "*/*"));
+                browser_headers.push((ACCEPT_LANGUAGE, "en-US,en;q=0.5"));
+                browser_headers.push((HeaderName::from_static("sec-fetch-dest"), "empty"));
+                browser_headers.push((HeaderName::from_static("sec-fetch-site"), "same-origin"));
+                if websocket {
+                    browser_headers.push((ACCEPT_ENCODING, "gzip, deflate, br, zstd"));
+                    browser_headers.push((SEC_WEBSOCKET_EXTENSIONS, "permessage-deflate"));
+                    browser_headers.push((HeaderName::from_static("sec-fetch-mode"), "websocket"));
+                } else {
+                    browser_headers.push((HeaderName::from_static("sec-fetch-mode"), "cors"));
+                }
+            }
+        }
+
+        for (name, value) in browser_headers {
+            headers.entry(name).or_insert(HeaderValue::from_static(value));
+        }
+        if let Some(origin) = origin.and_then(|origin| HeaderValue::from_str(origin).ok()) {
+            headers.entry(ORIGIN).or_insert(origin);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_path() {
+        let camouflage = Camouflage::default();
+        assert_eq!(camouflage.upgrade_path("v1"), "/v1/events");
+
+        let camouflage = Camouflage {
+            random_path: true,
+            ..Default::default()
+        };
+        let path = camouflage.upgrade_path("v1");
+        let segment = path.strip_prefix("/v1/").unwrap().strip_suffix("/events").unwrap();
+        assert!((6..=16).contains(&segment.len()));
+        assert!(segment.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_browser_headers() {
+        let camouflage = Camouflage {
+            browser: Some(Browser::Firefox),
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static("custom"));
+        camouflage.add_browser_headers(&mut headers, Some("https://example.com"), false);
+        assert_eq!(headers[USER_AGENT], "custom");
+        assert_eq!(headers[ORIGIN], "https://example.com");
+        assert_eq!(headers["sec-fetch-mode"], "cors");
+        assert!(!headers.contains_key(ACCEPT_ENCODING));
+
+        let camouflage = Camouflage {
+            browser: Some(Browser::Chrome),
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        camouflage.add_browser_headers(&mut headers, None, true);
+        assert!(headers[USER_AGENT].to_str().unwrap().contains("Chrome/"));
+        assert!(headers.contains_key(ACCEPT_ENCODING));
+        assert!(!headers.contains_key(ORIGIN));
+    }
+}
@@ -1,34 +1,98 @@
 use crate::executor::{DefaultTokioExecutor, TokioExecutorRef};
+use crate::stats;
+use crate::stats::{PoolStatus, STATS, Side, StatsReader, StatsWriter};
 use crate::tunnel;
-use crate::tunnel::RemoteAddr;
-use crate::tunnel::client::WsClientConfig;
-use crate::tunnel::client::cnx_pool::WsConnection;
+use crate::tunnel::client::accept_limit::AcceptLimiter;
+use crate::tunnel::client::cnx_pool;
+use crate::tunnel::client::cnx_pool::{HealthChecker, WsConnection};
+use crate::tunnel::client::l4_transport_stream::TransportStream;
+use crate::tunnel::client::probe;
+use crate::tunnel::client::reconnect::{
+    RECONNECT_GAVE_UP, RECONNECT_GAVE_UP_UNAUTHORIZED, ReconnectEvent, ReconnectEventKind, new_reconnect_delay,
+};
+use crate::tunnel::client::redirect;
+use crate::tunnel::client::rotation;
+use crate::tunnel::client::{AcceptLimits, WsClientConfig};
 use crate::tunnel::connectors::TunnelConnector;
+use crate::tunnel::integrity;
 use crate::tunnel::listeners::TunnelListener;
+use crate::tunnel::mux::MuxSession;
+use crate::tunnel::noise;
+use crate::tunnel::pcap;
+use crate::tunnel::pcap::{Direction, PcapReader, PcapWriter};
+use crate::tunnel::protocol::integrity_record::INTEGRITY_HEADER;
+use crate::tunnel::protocol::mux_frame::open_payload;
+use crate::tunnel::protocol::probe_record::PROBE_HEADER;
+use crate::tunnel::protocol::{
+    Capabilities, carries_stream, close_capabilities, missing_capabilities, server_capabilities,
+};
+use crate::tunnel::resume::{Outcome, ResumableStream, TRANSPORT_PIPE_SIZE};
 use crate::tunnel::tls_reloader::TlsReloader;
-use crate::tunnel::transport::io::{TunnelReader, TunnelWriter};
-use crate::tunnel::transport::{TransportScheme, jwt_token_to_tunnel};
+use crate::tunnel::transport::grpc::GrpcChannel;
+use crate::tunnel::transport::io::{LocalReset, Resettable, TunnelReader, TunnelWriter, close_channel};
+#[cfg(feature = "ssh-transport")]
+use crate::tunnel::transport::ssh::SshSession;
+use crate::tunnel::transport::{
+    STICKY_SESSION_HEADER, TransportScheme, UpgradeRejected, early_data, jwt_token_to_tunnel, tunnel_to_jwt_token,
+};
+use crate::tunnel::{LocalProtocol, RemoteAddr, TunnelResume};
 use anyhow::Context;
 use futures_util::pin_mut;
-use hyper::header::COOKIE;
+use hyper::header::{COOKIE, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::{HeaderMap, StatusCode};
 use log::debug;
-use std::cmp::min;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::oneshot;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
-use tracing::{Instrument, Level, Span, error, event, span};
+use tracing::{Instrument, Level, Span, error, event, info, span, warn};
 use url::Host;
 use uuid::Uuid;
 
+/// Times a busy server is tried again for the same tunnel
+const MAX_BUSY_RETRIES: usize = 3;
+/// Longest wait for a busy server, whatever it asks for
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+/// Age of the idle connections of the pool when they are renewed, without --connection-max-idle-age
+const DEFAULT_MAX_IDLE_AGE: Duration = Duration::from_secs(30);
+
 #[derive(Clone)]
 pub struct WsClient<E: TokioExecutorRef = DefaultTokioExecutor> {
     pub config: Arc<WsClientConfig>,
     pub cnx_pool: bb8::Pool<WsConnection>,
+    connection_retry_max_backoff: Duration,
     reverse_tunnel_connection_retry_max_backoff: Duration,
     _tls_reloader: Arc<TlsReloader>,
+    _health_checker: Option<Arc<HealthChecker>>,
     pub(crate) executor: E,
+    /// Label of the tunnels opened by this client, sent to the server to tag them in its logs and metrics
+    pub(crate) label: Option<Arc<str>>,
+    /// DSCP codepoint of the connections to the server of the tunnels opened by this client, instead of the global one
+    pub(crate) dscp: Option<u8>,
+    /// Limits of the connections accepted by the listener of the tunnels opened by this client
+    pub(crate) accept_limits: AcceptLimits,
+    /// Set once a http2 tunnel stalled, to split the requests of the next ones
+    pub(crate) http_split_detected: Arc<AtomicBool>,
+    /// Time to wait for the answer of the server to a http2 tunnel request, before splitting the requests with `auto`
+    pub(crate) http_stall_timeout: Duration,
+    /// Connection with the server the tunnels are multiplexed on with `--mux`, opened with the first of them
+    mux: Arc<tokio::sync::Mutex<Option<MuxSession<E>>>>,
+    /// Connection with the server the grpc tunnels are multiplexed on as streams
+    pub(crate) grpc_channel: GrpcChannel,
+    /// Ssh connection with the server the tunnels are multiplexed on as channels
+    #[cfg(feature = "ssh-transport")]
+    pub(crate) ssh_session: SshSession,
+    /// Client of the server the upgrade requests are permanently redirected to
+    redirect: Arc<parking_lot::Mutex<Option<WsClient<E>>>>,
+    /// Server the tunnels are opened with, 0 for the main one and then the index of the standby server plus one
+    active_server: Arc<parking_lot::Mutex<usize>>,
+    /// Token the server signed in its last response, sent back so the load balancer routes the requests to it
+    sticky_session: Arc<parking_lot::Mutex<Option<HeaderValue>>>,
 }
 
 impl<E: TokioExecutorRef> WsClient<E> {
@@ -41,52 +105,304 @@ impl<E: TokioExecutorRef> WsClient<E> {
     ) -> anyhow::Result<Self> {
         let config = Arc::new(config);
         let cnx = WsConnection::new(config.clone());
+        let connected = cnx.connected();
         let tls_reloader = TlsReloader::new_for_client(config.clone()).with_context(|| "Cannot create tls reloader")?;
+        // Connections are only ever idle in the pool, a used one is not put back, so their lifetime is their idle age
+        let max_idle_age = config.connection_max_idle_age.unwrap_or(DEFAULT_MAX_IDLE_AGE);
         let cnx_pool = bb8::Pool::builder()
             .max_size(1000)
             .min_idle(Some(connection_min_idle))
-            .max_lifetime(Some(Duration::from_secs(30)))
+            .max_lifetime(Some(max_idle_age))
+            .reaper_rate(max_idle_age)
             .connection_timeout(connection_retry_max_backoff)
             .retry_connection(true)
-            .build(cnx)
-            .await?;
+            .build_unchecked(cnx);
+
+        STATS.watch_pool({
+            let cnx_pool = cnx_pool.clone();
+            move || {
+                let state = cnx_pool.state();
+                PoolStatus {
+                    connections: state.connections,
+                    idle_connections: state.idle_connections,
+                    connections_created: state.statistics.connections_created,
+                    connections_closed_broken: state.statistics.connections_closed_broken,
+                    get_timed_out: state.statistics.get_timed_out,
+                }
+            }
+        });
+        cnx_pool::warm_up(&cnx_pool, connected, connection_min_idle, config.connection_warmup_timeout).await;
+        let health_checker = config.connection_health_check_interval.map(|interval| {
+            let task = executor.spawn(cnx_pool::check_idle_connections(cnx_pool.clone(), interval));
+            Arc::new(HealthChecker(task))
+        });
 
         Ok(Self {
             config,
             cnx_pool,
+            connection_retry_max_backoff,
             reverse_tunnel_connection_retry_max_backoff,
             _tls_reloader: Arc::new(tls_reloader),
+            _health_checker: health_checker,
             executor,
+            label: None,
+            dscp: None,
+            accept_limits: AcceptLimits::default(),
+            http_split_detected: Arc::new(AtomicBool::new(false)),
+            http_stall_timeout: tunnel::transport::http2::STALL_TIMEOUT,
+            mux: Arc::new(tokio::sync::Mutex::new(None)),
+            grpc_channel: Arc::new(tokio::sync::Mutex::new(None)),
+            #[cfg(feature = "ssh-transport")]
+            ssh_session: Arc::new(tokio::sync::Mutex::new(None)),
+            redirect: Arc::new(parking_lot::Mutex::new(None)),
+            active_server: Arc::new(parking_lot::Mutex::new(0)),
+            sticky_session: Arc::new(parking_lot::Mutex::new(None)),
         })
     }
 
-    pub async fn connect_to_server<R, W>(
+    /// Tag the tunnels opened by this client with the given label
+    pub fn with_label(mut self, label: Option<&str>) -> Self {
+        self.label = label.map(Arc::from);
+        self
+    }
+
+    /// Mark the connections to the server of the tunnels opened by this client with the given DSCP codepoint
+    pub fn with_dscp(mut self, dscp: Option<u8>) -> Self {
+        self.dscp = dscp;
+        self
+    }
+
+    /// Bound the connections accepted by the listener of the tunnels opened by this client
+    pub fn with_accept_limits(mut self, accept_limits: AcceptLimits) -> Self {
+        self.accept_limits = accept_limits;
+        self
+    }
+
+    /// Stick the request to the server that answered the previous ones, if it asked for it
+    pub(crate) fn add_sticky_session(&self, headers: &mut HeaderMap) {
+        if let Some(sticky_session) = self.sticky_session.lock().clone() {
+            headers.insert(STICKY_SESSION_HEADER, sticky_session);
+        }
+    }
+
+    /// Ask the server to probe the reverse tunnel while it waits for a connection, with --reverse-tunnel-probe-interval.
+    /// The resumable tunnels already survive the loss of the client, they are not probed
+    pub(crate) fn add_probe_interval(&self, headers: &mut HeaderMap, dest_addr: &RemoteAddr) {
+        if let Some(interval) = self.config.reverse_tunnel_probe_interval
+            && dest_addr.protocol.is_reverse_tunnel()
+            && !matches!(dest_addr.protocol, LocalProtocol::ReverseTcp { resume: Some(_), .. })
+        {
+            headers.insert(PROBE_HEADER, HeaderValue::from(interval.as_secs()));
+        }
+    }
+
+    /// Ask the server to check the integrity of the tunnel, with --integrity-check-interval. Only the tunnels carrying a
+    /// byte stream are checked, the tunnels multiplexed with --mux are carried by one that is not
+    pub(crate) fn add_integrity_interval(&self, headers: &mut HeaderMap, dest_addr: &RemoteAddr) {
+        if let Some(interval) = self.config.integrity_check_interval
+            && carries_stream(&dest_addr.protocol)
+        {
+            headers.insert(INTEGRITY_HEADER, HeaderValue::from(interval));
+        }
+    }
+
+    /// Apply the DSCP codepoint of the tunnels to the connection taken from the pool for one of them
+    pub(crate) fn mark_transport(&self, transport: &TransportStream) {
+        if let Some(dscp) = self.dscp
+            && let Err(err) = transport.set_dscp(dscp)
+        {
+            warn!("Cannot set dscp {dscp} of the connection to the server: {err:?}");
+        }
+    }
+
+    /// Open a connection with the server for the given tunnel. The redirects of the server are followed, when it is
+    /// busy, it is tried again after the delay it asked for, and when it is unreachable or a standby, the next server is used
+    async fn open_transport(
         &self,
         request_id: Uuid,
         remote_cfg: &RemoteAddr,
-        duplex_stream: (R, W),
-    ) -> anyhow::Result<()>
-    where
-        R: AsyncRead + Send + 'static,
-        W: AsyncWrite + Send + 'static,
-    {
-        // Connect to server with the correct protocol
-        let (ws_rx, ws_tx, response) = match self.config.remote_addr.scheme() {
+        early_data: &[u8],
+    ) -> anyhow::Result<(TunnelReader, TunnelWriter, Parts)> {
+        let (mut client, mut server) = {
+            let active_server = self.active_server.lock();
+            (self.redirect.lock().clone().unwrap_or_else(|| self.clone()), *active_server)
+        };
+        let mut redirects = 0;
+        let mut retries = 0;
+        let mut switches = 0;
+        loop {
+            let err = match client.connect_transport(request_id, remote_cfg, early_data).await {
+                Ok(transport) => {
+                    if let Some(sticky_session) = transport.2.headers.get(STICKY_SESSION_HEADER) {
+                        *client.sticky_session.lock() = Some(sticky_session.clone());
+                    }
+                    let missing = missing_capabilities(&transport.2.headers, &remote_cfg.protocol);
+                    if !missing.is_empty() {
+                        warn!(
+                            "Server does not announce the {missing} capabilities the tunnel relies on, it may be older than the client and fail to serve it"
+                        );
+                    }
+                    return Ok(transport);
+                }
+                Err(err) => err,
+            };
+            let rejected = err.downcast_ref::<UpgradeRejected>();
+            if rejected.is_none_or(UpgradeRejected::is_standby) && switches < self.config.standby_servers.len() {
+                switches += 1;
+                warn!("Cannot open tunnel with the server, switching to the next one: {err:?}");
+                (client, server) = self.switch_server(server).await?;
+                continue;
+            }
+            let Some(rejected) = rejected else {
+                return Err(err);
+            };
+
+            if let Some(location) = rejected.redirect_location().cloned() {
+                if redirects >= self.config.upgrade_max_redirects {
+                    return Err(err.context(format!(
+                        "not following the redirect to {location:?}, after {redirects} redirects"
+                    )));
+                }
+                redirects += 1;
+                let permanent = rejected.is_permanent_redirect();
+                let config = redirect::redirect_config(&client.config, &location)?;
+                info!(
+                    "Server redirected the upgrade request to {:?}/{}",
+                    config.remote_addr, config.http_upgrade_path_prefix
+                );
+                client = client.redirected(config).await?;
+                if permanent {
+                    *self.redirect.lock() = Some(client.clone());
+                }
+                continue;
+            }
+
+            if let Some(delay) = rejected.retry_after()
+                && retries < MAX_BUSY_RETRIES
+            {
+                retries += 1;
+                let delay = delay.min(MAX_RETRY_AFTER);
+                warn!("Server is busy ({}), trying again in {}s", rejected.status, delay.as_secs());
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+
+            // An older server rejects what it does not understand as a bad request, without telling why
+            let required = Capabilities::required_by(&remote_cfg.protocol);
+            if rejected.status == StatusCode::BAD_REQUEST && !required.is_empty() {
+                return Err(err.context(format!(
+                    "the server may be too old to serve a tunnel relying on the {required} capabilities"
+                )));
+            }
+            return Err(err);
+        }
+    }
+
+    /// Make the server after `from` the one the tunnels are opened with, unless another tunnel already switched from it
+    async fn switch_server(&self, from: usize) -> anyhow::Result<(Self, usize)> {
+        let next = (from + 1) % (self.config.standby_servers.len() + 1);
+        let client = match next {
+            0 => self.clone(),
+            _ => {
+                let config = redirect::standby_config(&self.config, &self.config.standby_servers[next - 1])?;
+                self.redirected(config).await?
+            }
+        };
+
+        let mut active_server = self.active_server.lock();
+        if *active_server != from {
+            let active = self.redirect.lock().clone().unwrap_or_else(|| self.clone());
+            return Ok((active, *active_server));
+        }
+        *active_server = next;
+        *self.redirect.lock() = (next != 0).then(|| client.clone());
+        info!(
+            "Switched to server {:?}/{}",
+            client.config.remote_addr, client.config.http_upgrade_path_prefix
+        );
+        Ok((client, next))
+    }
+
+    /// Client of the server the upgrade requests were redirected to, opening the tunnels like this one
+    async fn redirected(&self, config: WsClientConfig) -> anyhow::Result<Self> {
+        let client = Self::new(
+            config,
+            0,
+            self.connection_retry_max_backoff,
+            self.reverse_tunnel_connection_retry_max_backoff,
+            self.executor.clone(),
+        )
+        .await?;
+        Ok(Self {
+            label: self.label.clone(),
+            dscp: self.dscp,
+            accept_limits: self.accept_limits,
+            ..client
+        })
+    }
+
+    /// Open a connection with the server for the given tunnel, using the transport of the configured scheme.
+    /// The early data is only sent by the http based transports, the other ones never acknowledge it
+    async fn connect_transport(
+        &self,
+        request_id: Uuid,
+        remote_cfg: &RemoteAddr,
+        early_data: &[u8],
+    ) -> anyhow::Result<(TunnelReader, TunnelWriter, Parts)> {
+        match self.config.remote_addr.scheme() {
             TransportScheme::Ws | TransportScheme::Wss => {
-                tunnel::transport::websocket::connect(request_id, self, remote_cfg)
+                tunnel::transport::websocket::connect(request_id, self, remote_cfg, early_data)
                     .await
-                    .map(|(r, w, response)| (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response))?
+                    .map(|(r, w, response)| (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response))
             }
             TransportScheme::Http | TransportScheme::Https => {
-                tunnel::transport::http2::connect(request_id, self, remote_cfg)
+                tunnel::transport::http2::connect(request_id, self, remote_cfg, early_data)
                     .await
-                    .map(|(r, w, response)| (TunnelReader::Http2(r), TunnelWriter::Http2(w), response))?
+                    .map(|(r, w, response)| (TunnelReader::Http2(r), TunnelWriter::Http2(w), response))
             }
-        };
+            TransportScheme::Grpc | TransportScheme::Grpcs => {
+                tunnel::transport::grpc::connect(request_id, self, remote_cfg, early_data)
+                    .await
+                    .map(|(r, w, response)| (TunnelReader::Grpc(r), TunnelWriter::Grpc(w), response))
+            }
+            TransportScheme::Http1 | TransportScheme::Https1 => {
+                tunnel::transport::http1::connect(request_id, self, remote_cfg, early_data)
+                    .await
+                    .map(|(r, w, response)| (TunnelReader::Http2(r), TunnelWriter::Http2(w), response))
+            }
+            #[cfg(feature = "ssh-transport")]
+            TransportScheme::Ssh => tunnel::transport::ssh::connect(request_id, self, remote_cfg, early_data)
+                .await
+                .map(|(r, w, response)| (TunnelReader::Ssh(r), TunnelWriter::Ssh(w), response)),
+            #[cfg(feature = "dns-transport")]
+            TransportScheme::Dns => tunnel::transport::dns::connect(request_id, self, remote_cfg)
+                .await
+                .map(|(r, w, response)| (TunnelReader::Datagram(r), TunnelWriter::Datagram(w), response)),
+            #[cfg(feature = "icmp-transport")]
+            TransportScheme::Icmp => tunnel::transport::icmp::connect(request_id, self, remote_cfg)
+                .await
+                .map(|(r, w, response)| (TunnelReader::Datagram(r), TunnelWriter::Datagram(w), response)),
+        }
+    }
 
-        debug!("Server response: {response:?}");
-        let (local_rx, local_tx) = duplex_stream;
-        let (close_tx, close_rx) = oneshot::channel::<()>();
+    /// Forward the traffic between the connection with the server and the local stream, until one of them is closed
+    /// With the `capabilities` of [`close_capabilities`], the end of each direction is told to the other side instead
+    /// of closing the tunnel, and `local_reset` is reset when the other side tells that its local stream failed
+    async fn forward_transport<R, W>(
+        &self,
+        (ws_rx, ws_tx): (TunnelReader, TunnelWriter),
+        (local_rx, local_tx): (R, W),
+        mut capabilities: Capabilities,
+        local_reset: LocalReset,
+    ) where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        if !ws_tx.can_half_close() {
+            capabilities = capabilities.difference(Capabilities::HALF_CLOSE);
+        }
+        let (close_tx, close_rx) = close_channel(capabilities);
+        let close_rx = close_rx.with_local_reset(local_reset);
 
         // Forward local tx to websocket tx
         let ping_frequency = self.config.websocket_ping_frequency;
@@ -97,12 +413,362 @@ impl<E: TokioExecutorRef> WsClient<E> {
 
         // Forward websocket rx to local rx
         let _ = super::super::transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).await;
+    }
+
+    /// Connection with the server to multiplex the tunnels on, opened again if it was lost
+    async fn mux_session(&self) -> anyhow::Result<MuxSession<E>> {
+        let mut mux = self.mux.lock().await;
+        if let Some(session) = mux.as_ref().filter(|session| !session.is_closed()) {
+            return Ok(session.clone());
+        }
+
+        let (session, bytes) = self.open_mux_session().await?;
+        *mux = Some(session.clone());
+        if rotation::is_enabled(&self.config) {
+            let client = self.clone();
+            let session = session.clone();
+            self.executor
+                .spawn(async move { client.rotate_mux_session(session, bytes).await });
+        }
+        Ok(session)
+    }
+
+    async fn open_mux_session(&self) -> anyhow::Result<(MuxSession<E>, Arc<AtomicU64>)> {
+        let remote_cfg = RemoteAddr {
+            protocol: LocalProtocol::Mux,
+            host: Host::Ipv4(Ipv4Addr::UNSPECIFIED),
+            port: 0,
+        };
+        let (ws_rx, ws_tx, response) = self.open_transport(Uuid::now_v7(), &remote_cfg, &[]).await?;
+        debug!("Server response: {response:?}");
+        info!("Multiplexing the tunnels over a new connection with the server");
+        let (local_side, bytes) = self.carry_transport((ws_rx, ws_tx));
+
+        // The server never opens streams towards the client
+        let (session, _) = MuxSession::new(local_side, self.executor.clone());
+        Ok((session, bytes))
+    }
+
+    /// Each time the mux connection reaches its limits, replace it. The new tunnels go to the new connection, and the
+    /// old one is closed once its tunnels are done
+    async fn rotate_mux_session(self, mut session: MuxSession<E>, mut bytes: Arc<AtomicU64>) {
+        loop {
+            tokio::select! {
+                _ = rotation::limit_reached(&self.config, &bytes) => {}
+                _ = session.closed() => return,
+            }
+
+            let mut reconnect_delay = new_reconnect_delay(self.connection_retry_max_backoff);
+            let (next, next_bytes) = loop {
+                match self.open_mux_session().await {
+                    Ok(next) => break next,
+                    Err(_) if session.is_closed() => return,
+                    Err(err) => {
+                        let reconnect_delay = reconnect_delay();
+                        warn!(
+                            "Retrying in {reconnect_delay:?}, cannot replace the mux connection with the server: {err:?}"
+                        );
+                        tokio::time::sleep(reconnect_delay).await;
+                    }
+                }
+            };
+
+            {
+                let mut mux = self.mux.lock().await;
+                if !mux.as_ref().is_some_and(|current| current.is_same(&session)) {
+                    // The session was lost meanwhile, and a tunnel already opened another one, which has its own rotation
+                    next.close();
+                    return;
+                }
+                *mux = Some(next.clone());
+            }
+            info!("Replaced the mux connection with the server, the old one closes once its tunnels are done");
+            let old = std::mem::replace(&mut session, next);
+            self.executor.spawn(async move { old.close_when_idle().await });
+            bytes = next_bytes;
+        }
+    }
+
+    /// Forward the traffic of the connection with the server from an in-memory pipe, and return the other end of the
+    /// pipe along with the count of the bytes going through it
+    fn carry_transport(
+        &self,
+        transport: (TunnelReader, TunnelWriter),
+    ) -> (rotation::CountedStream<DuplexStream>, Arc<AtomicU64>) {
+        let (local_side, server_side) = tokio::io::duplex(TRANSPORT_PIPE_SIZE);
+        let client = self.clone();
+        self.executor.spawn(
+            async move {
+                client
+                    .forward_transport(
+                        transport,
+                        tokio::io::split(server_side),
+                        Capabilities::NONE,
+                        LocalReset::default(),
+                    )
+                    .await
+            }
+            .instrument(Span::current()),
+        );
+        rotation::CountedStream::new(local_side)
+    }
+
+    /// Open the tunnel as a stream of the connection shared with the other tunnels of the client
+    async fn connect_to_server_mux<R, W>(
+        &self,
+        request_id: Uuid,
+        remote_cfg: &RemoteAddr,
+        (local_rx, local_tx): (R, W),
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        let session = self.mux_session().await?;
+        // The server checks the proof over the default path, whatever the path of the mux connection
+        let path = format!("/{}/events", self.config.http_upgrade_path_prefix);
+        let tunnel_token =
+            tunnel_to_jwt_token(request_id, remote_cfg, self.label.as_deref(), self.config.device_id.as_deref());
+        let psk_proof = self
+            .config
+            .psk
+            .as_ref()
+            .map(|psk| psk.client_proof(&path, &tunnel_token));
+        let id = tokio::time::timeout(
+            self.config.timeout_connect,
+            session.open(open_payload(&tunnel_token, psk_proof.as_deref())),
+        )
+        .await
+        .context("timeout while opening the tunnel on the mux connection")??;
+        session.forward(id, local_rx, local_tx).await
+    }
+
+    pub async fn connect_to_server<R, W>(
+        &self,
+        request_id: Uuid,
+        remote_cfg: &RemoteAddr,
+        duplex_stream: (R, W),
+        local_reset: LocalReset,
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        if self.config.mux {
+            return self.connect_to_server_mux(request_id, remote_cfg, duplex_stream).await;
+        }
+
+        let (local_rx, local_tx) = duplex_stream;
+        let mut local_rx = Box::pin(local_rx);
+        let early_data = if self.config.early_data {
+            early_data::read(&mut local_rx).await?
+        } else {
+            Vec::new()
+        };
+
+        // Connect to server with the correct protocol
+        let (ws_rx, ws_tx, response) = self.open_transport(request_id, remote_cfg, &early_data).await?;
+        debug!("Server response: {response:?}");
+        let unsent = if early_data::is_acknowledged(&response, &early_data) {
+            Vec::new()
+        } else {
+            early_data
+        };
+        let local_rx = std::io::Cursor::new(unsent).chain(local_rx);
+        let (local_rx, local_tx) =
+            integrity::channel(integrity_interval(&response), &self.executor, local_rx, local_tx);
+        let capabilities = close_capabilities(server_capabilities(&response.headers), &remote_cfg.protocol);
+        self.forward_transport((ws_rx, ws_tx), (local_rx, local_tx), capabilities, local_reset)
+            .await;
 
         Ok(())
     }
 
+    /// Same as connect_to_server, but reconnect to the server when the connection is lost, and resume the tunnel
+    /// where it stopped
+    async fn connect_to_server_resumable<R, W>(
+        &self,
+        request_id: Uuid,
+        remote_cfg: &RemoteAddr,
+        resume: TunnelResume,
+        (local_rx, local_tx): (R, W),
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        let (ws_rx, ws_tx, response) = self.open_transport(request_id, remote_cfg, &[]).await?;
+        debug!("Server response: {response:?}");
+        let stream = ResumableStream::new(local_rx, local_tx, resume.buffer_size);
+        self.run_resumable(request_id, remote_cfg.clone(), &response, stream, (ws_rx, ws_tx))
+            .await
+    }
+
+    /// Drive a resumable tunnel, and each time the connection with the server is lost, reconnect with the session token
+    /// the server returned when the tunnel was opened. The client can come back from another network (i.e: wifi to LTE),
+    /// but fails if it was disconnected for longer than the resume timeout
+    async fn run_resumable(
+        &self,
+        request_id: Uuid,
+        mut remote_cfg: RemoteAddr,
+        response: &Parts,
+        mut stream: ResumableStream,
+        mut transport: (TunnelReader, TunnelWriter),
+    ) -> anyhow::Result<()> {
+        let session = resume_session(response).context("server did not return a session for the resumable tunnel")?;
+        let resume = remote_cfg.protocol.resume_mut().context("tunnel is not resumable")?;
+        resume.session = Some(session);
+        let timeout = resume.timeout;
+
+        let (next_tx, mut next_rx) = mpsc::channel(1);
+        loop {
+            let (local_side, server_side) = tokio::io::duplex(TRANSPORT_PIPE_SIZE);
+            let (server_side, bytes) = rotation::CountedStream::new(server_side);
+            let client = self.clone();
+            self.executor.spawn(
+                async move {
+                    client
+                        .forward_transport(
+                            transport,
+                            tokio::io::split(server_side),
+                            Capabilities::NONE,
+                            LocalReset::default(),
+                        )
+                        .await
+                }
+                .instrument(Span::current()),
+            );
+            let rotation = rotation::is_enabled(&self.config).then(|| {
+                let rotate =
+                    self.clone()
+                        .rotate_resumable_transport(request_id, remote_cfg.clone(), bytes, next_tx.clone());
+                self.executor.spawn(rotate.instrument(Span::current()))
+            });
+            scopeguard::defer! {
+                if let Some(rotation) = &rotation {
+                    rotation.abort();
+                }
+            };
+
+            let mut local_side = local_side;
+            loop {
+                match stream.run(local_side, Some(&mut next_rx)).await? {
+                    Outcome::Finished => return Ok(()),
+                    Outcome::Replaced(next) => local_side = next,
+                    Outcome::Disconnected => break,
+                }
+            }
+
+            let disconnected_at = Instant::now();
+            let mut reconnect_delay = new_reconnect_delay(self.reverse_tunnel_connection_retry_max_backoff);
+            transport = loop {
+                match self.open_transport(request_id, &remote_cfg, &[]).await {
+                    Ok((ws_rx, ws_tx, _)) => break (ws_rx, ws_tx),
+                    Err(err) if disconnected_at.elapsed() > timeout => {
+                        return Err(
+                            err.context(format!("cannot resume tunnel after being disconnected for {timeout:?}"))
+                        );
+                    }
+                    Err(err) => {
+                        let reconnect_delay = reconnect_delay();
+                        warn!("Retrying in {:?}, cannot resume tunnel: {:?}", reconnect_delay, err);
+                        tokio::time::sleep(reconnect_delay).await;
+                    }
+                }
+            };
+            info!("Tunnel resumed after being disconnected for {:?}", disconnected_at.elapsed());
+            STATS.reconnected(Side::Client, &request_id.to_string());
+        }
+    }
+
+    /// Each time the connection of a resumable tunnel reaches its limits, open another one and hand it to the tunnel,
+    /// which resumes over it where the previous one stopped
+    async fn rotate_resumable_transport(
+        self,
+        request_id: Uuid,
+        remote_cfg: RemoteAddr,
+        mut bytes: Arc<AtomicU64>,
+        next_tx: mpsc::Sender<DuplexStream>,
+    ) {
+        loop {
+            rotation::limit_reached(&self.config, &bytes).await;
+            let mut reconnect_delay = new_reconnect_delay(self.reverse_tunnel_connection_retry_max_backoff);
+            let transport = loop {
+                match self.open_transport(request_id, &remote_cfg, &[]).await {
+                    Ok((ws_rx, ws_tx, _)) => break (ws_rx, ws_tx),
+                    Err(err) => {
+                        let reconnect_delay = reconnect_delay();
+                        warn!("Retrying in {reconnect_delay:?}, cannot replace the connection of the tunnel: {err:?}");
+                        tokio::time::sleep(reconnect_delay).await;
+                    }
+                }
+            };
+
+            let (local_side, server_side) = tokio::io::duplex(TRANSPORT_PIPE_SIZE);
+            let (server_side, next_bytes) = rotation::CountedStream::new(server_side);
+            let client = self.clone();
+            self.executor.spawn(
+                async move {
+                    client
+                        .forward_transport(
+                            transport,
+                            tokio::io::split(server_side),
+                            Capabilities::NONE,
+                            LocalReset::default(),
+                        )
+                        .await
+                }
+                .instrument(Span::current()),
+            );
+            if next_tx.send(local_side).await.is_err() {
+                return;
+            }
+            info!("Replaced the connection of the tunnel with the server");
+            bytes = next_bytes;
+        }
+    }
+
+    /// The client does not know who is at the origin of the tunnel, so it is recorded as coming from 0.0.0.0:0
+    fn record_pcap<R, W>(
+        &self,
+        request_id: Uuid,
+        remote_addr: &RemoteAddr,
+        local_rx: R,
+        local_tx: W,
+        read_direction: Direction,
+    ) -> (PcapReader<R>, PcapWriter<W>) {
+        let recorder = pcap::recorder(
+            self.config.pcap_dir.as_deref(),
+            &request_id.to_string(),
+            &remote_addr.protocol,
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            (&remote_addr.host, remote_addr.port),
+        );
+        pcap::record(recorder, local_rx, local_tx, read_direction)
+    }
+
+    /// Count the traffic of the tunnel in the statistics served on `--admin-listen`
+    fn track_stats<R, W>(
+        &self,
+        request_id: Uuid,
+        remote_addr: &RemoteAddr,
+        local_rx: R,
+        local_tx: W,
+    ) -> (StatsReader<R>, StatsWriter<W>) {
+        let registration = STATS.register(
+            Side::Client,
+            &request_id.to_string(),
+            remote_addr,
+            None,
+            self.label.as_deref(),
+            None,
+        );
+        stats::track(registration, local_rx, local_tx)
+    }
+
     pub async fn run_tunnel(self, tunnel_listener: impl TunnelListener) -> anyhow::Result<()> {
         pin_mut!(tunnel_listener);
+        let mut limiter = AcceptLimiter::new(self.accept_limits);
         // everybody who connects to the local socket gets their own tunnel
         while let Some(cnx) = tunnel_listener.next().await {
             let (cnx_stream, remote_addr) = match cnx {
@@ -112,20 +778,50 @@ impl<E: TokioExecutorRef> WsClient<E> {
                     continue;
                 }
             };
+            let Some(permit) = limiter.admit().await else {
+                warn!(
+                    "Rejecting cnx to {}:{}, the listener is over its max_conns or accept_rate",
+                    remote_addr.host, remote_addr.port
+                );
+                continue;
+            };
 
             let request_id = Uuid::now_v7();
             let span = span!(
                 Level::INFO,
                 "tunnel",
                 id = request_id.to_string(),
-                remote = format!("{}:{}", remote_addr.host, remote_addr.port)
+                remote = format!("{}:{}", remote_addr.host, remote_addr.port),
+                label = self.label.as_deref()
             );
             let client = self.clone();
             let tunnel = async move {
-                let _ = client
-                    .connect_to_server(request_id, &remote_addr, cnx_stream)
-                    .await
-                    .map_err(|err| error!("{:?}", err));
+                let ret = async {
+                    let (local_rx, local_tx) = cnx_stream;
+                    let local_reset = local_tx.local_reset();
+                    let (local_rx, local_tx) =
+                        client.record_pcap(request_id, &remote_addr, local_rx, local_tx, Direction::ToDestination);
+                    let (local_rx, local_tx) = client.track_stats(request_id, &remote_addr, local_rx, local_tx);
+                    let cnx_stream =
+                        noise::client_channel(client.config.noise.as_ref(), &client.executor, local_rx, local_tx)?;
+                    match remote_addr.protocol {
+                        LocalProtocol::Tcp {
+                            resume: Some(resume), ..
+                        } => {
+                            client
+                                .connect_to_server_resumable(request_id, &remote_addr, resume, cnx_stream)
+                                .await
+                        }
+                        _ => {
+                            client
+                                .connect_to_server(request_id, &remote_addr, cnx_stream, local_reset)
+                                .await
+                        }
+                    }
+                }
+                .await;
+                let _ = ret.map_err(|err| error!("{:?}", err));
+                drop(permit);
             }
             .instrument(span);
 
@@ -140,17 +836,9 @@ impl<E: TokioExecutorRef> WsClient<E> {
         remote_addr: RemoteAddr,
         connector: impl TunnelConnector,
     ) -> anyhow::Result<()> {
-        fn new_reconnect_delay(max_delay: Duration) -> impl FnMut() -> Duration {
-            let mut reconnect_delay = Duration::from_secs(1);
-
-            move || -> Duration {
-                let delay = reconnect_delay;
-                reconnect_delay = min(reconnect_delay * 2, max_delay);
-                delay
-            }
-        }
-
         let mut reconnect_delay = new_reconnect_delay(self.reverse_tunnel_connection_retry_max_backoff);
+        // Consecutive failed attempts to connect to the server
+        let mut retries = 0;
         loop {
             let client = self.clone();
             let request_id = Uuid::now_v7();
@@ -158,46 +846,87 @@ impl<E: TokioExecutorRef> WsClient<E> {
                 Level::INFO,
                 "tunnel",
                 id = request_id.to_string(),
-                remote = format!("{}:{}", remote_addr.host, remote_addr.port)
+                remote = format!("{}:{}", remote_addr.host, remote_addr.port),
+                label = self.label.as_deref()
             );
             // Correctly configure tunnel cfg
-            let (ws_rx, ws_tx, response) = match client.config.remote_addr.scheme() {
-                TransportScheme::Ws | TransportScheme::Wss => {
-                    match tunnel::transport::websocket::connect(request_id, &client, &remote_addr)
-                        .instrument(span.clone())
-                        .await
-                    {
-                        Ok((r, w, response)) => (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response),
-                        Err(err) => {
-                            let reconnect_delay = reconnect_delay();
-                            event!(parent: &span, Level::ERROR, "Retrying in {:?}, cannot connect to remote server: {:?}", reconnect_delay, err);
-                            tokio::time::sleep(reconnect_delay).await;
-                            continue;
-                        }
+            let (ws_rx, ws_tx, response) = match client
+                .open_transport(request_id, &remote_addr, &[])
+                .instrument(span.clone())
+                .await
+            {
+                Ok(ret) => ret,
+                Err(err) => {
+                    retries += 1;
+                    if retries == 1 {
+                        self.reconnect_event(ReconnectEventKind::Disconnected, &remote_addr, retries, Some(&err))
+                            .instrument(span.clone())
+                            .await;
                     }
-                }
-                TransportScheme::Http | TransportScheme::Https => {
-                    match tunnel::transport::http2::connect(request_id, &client, &remote_addr)
-                        .instrument(span.clone())
-                        .await
+                    if let Some(max_retries) = self.config.reverse_tunnel_max_retries
+                        && retries > max_retries
                     {
-                        Ok((r, w, response)) => (TunnelReader::Http2(r), TunnelWriter::Http2(w), response),
-                        Err(err) => {
-                            let reconnect_delay = reconnect_delay();
-                            event!(parent: &span, Level::ERROR, "Retrying in {:?}, cannot connect to remote server: {:?}", reconnect_delay, err);
-                            tokio::time::sleep(reconnect_delay).await;
-                            continue;
+                        self.reconnect_event(ReconnectEventKind::GaveUp, &remote_addr, retries, Some(&err))
+                            .instrument(span.clone())
+                            .await;
+                        if err
+                            .downcast_ref::<UpgradeRejected>()
+                            .is_some_and(UpgradeRejected::is_unauthorized)
+                        {
+                            RECONNECT_GAVE_UP_UNAUTHORIZED.store(true, Ordering::Relaxed);
                         }
+                        RECONNECT_GAVE_UP.notify_one();
+                        return Err(
+                            err.context(format!("Giving up after {max_retries} retries to connect to the server"))
+                        );
                     }
+
+                    let reconnect_delay = reconnect_delay();
+                    event!(parent: &span, Level::ERROR, "Retrying in {:?}, cannot connect to remote server: {:?}", reconnect_delay, err);
+                    tokio::time::sleep(reconnect_delay).await;
+                    continue;
                 }
             };
+            if retries > 0 {
+                self.reconnect_event(ReconnectEventKind::Reconnected, &remote_addr, retries, None)
+                    .instrument(span.clone())
+                    .await;
+                retries = 0;
+            }
             reconnect_delay = new_reconnect_delay(self.reverse_tunnel_connection_retry_max_backoff);
 
+            // Probed by the server, the tunnel is answered right away and only opened once a connection arrives
+            let mut response = response;
+            let mut transport = Some((ws_rx, ws_tx));
+            let mut probed = None;
+            if let Some(interval) = probe::probe_interval(&response)
+                && let Some(ws) = transport.take()
+            {
+                let (mut stream, _) = client.carry_transport(ws);
+                let (cookie, received) = match probe::wait_connection(&mut stream, interval)
+                    .instrument(span.clone())
+                    .await
+                {
+                    Ok(opened) => opened,
+                    Err(err) => {
+                        event!(parent: &span, Level::WARN, "Reverse tunnel closed while waiting for a connection: {err:?}");
+                        continue;
+                    }
+                };
+                if let Ok(cookie) = HeaderValue::from_maybe_shared(cookie)
+                    && !cookie.is_empty()
+                {
+                    response.headers.insert(COOKIE, cookie);
+                }
+                probed = Some((stream, received));
+            }
+
             // Connect to endpoint
             event!(parent: &span, Level::DEBUG, "Server response: {:?}", response);
             let remote = response
                 .headers
                 .get(COOKIE)
+                .filter(|_| remote_addr.protocol.is_dynamic_reverse_tunnel())
                 .and_then(|h| h.to_str().ok())
                 .and_then(|h| jwt_token_to_tunnel(h).ok())
                 .map(|jwt| RemoteAddr {
@@ -213,8 +942,63 @@ impl<E: TokioExecutorRef> WsClient<E> {
                     continue;
                 }
             };
+            let local_reset = local_tx.local_reset();
+            let local = span.in_scope(|| {
+                let destination = remote.as_ref().unwrap_or(&remote_addr);
+                let (local_rx, local_tx) =
+                    client.record_pcap(request_id, destination, local_rx, local_tx, Direction::ToOrigin);
+                let (local_rx, local_tx) = client.track_stats(request_id, destination, local_rx, local_tx);
+                let (local_rx, local_tx) =
+                    noise::client_channel(client.config.noise.as_ref(), &client.executor, local_rx, local_tx)?;
+                anyhow::Ok(integrity::channel(
+                    integrity_interval(&response),
+                    &client.executor,
+                    local_rx,
+                    local_tx,
+                ))
+            });
+            let (local_rx, local_tx) = match local {
+                Ok(s) => s,
+                Err(err) => {
+                    event!(parent: &span, Level::ERROR, "Cannot setup noise encryption: {err:?}");
+                    continue;
+                }
+            };
+
+            let Some((ws_rx, ws_tx)) = transport else {
+                if let Some((stream, received)) = probed {
+                    self.executor
+                        .spawn(probe::forward(stream, received, local_rx, local_tx).instrument(span.clone()));
+                }
+                continue;
+            };
 
-            let (close_tx, close_rx) = oneshot::channel::<()>();
+            if let LocalProtocol::ReverseTcp {
+                resume: Some(resume), ..
+            } = remote_addr.protocol
+            {
+                let stream = ResumableStream::new(local_rx, local_tx, resume.buffer_size);
+                let remote_addr = remote_addr.clone();
+                self.executor.spawn(
+                    async move {
+                        if let Err(err) = client
+                            .run_resumable(request_id, remote_addr, &response, stream, (ws_rx, ws_tx))
+                            .await
+                        {
+                            error!("{:?}", err);
+                        }
+                    }
+                    .instrument(span.clone()),
+                );
+                continue;
+            }
+
+            let mut capabilities = close_capabilities(server_capabilities(&response.headers), &remote_addr.protocol);
+            if !ws_tx.can_half_close() {
+                capabilities = capabilities.difference(Capabilities::HALF_CLOSE);
+            }
+            let (close_tx, close_rx) = close_channel(capabilities);
+            let close_rx = close_rx.with_local_reset(local_reset);
             self.executor.spawn({
                 let ping_frequency = client.config.websocket_ping_frequency;
                 super::super::transport::io::propagate_local_to_remote(local_rx, ws_tx, close_tx, ping_frequency)
@@ -228,4 +1012,66 @@ impl<E: TokioExecutorRef> WsClient<E> {
             );
         }
     }
+
+    /// Log the change of the connection of a reverse tunnel with the server, and tell the reconnect hook about it.
+    /// The hook runs in the background, except when giving up as the client exits right after
+    async fn reconnect_event(
+        &self,
+        kind: ReconnectEventKind,
+        remote_addr: &RemoteAddr,
+        retries: u32,
+        err: Option<&anyhow::Error>,
+    ) {
+        STATS.reverse_tunnel_event(kind);
+        match kind {
+            ReconnectEventKind::Disconnected => warn!("Reverse tunnel disconnected from the server"),
+            ReconnectEventKind::Reconnected => {
+                info!("Reverse tunnel reconnected to the server after {retries} retries")
+            }
+            ReconnectEventKind::GaveUp => {
+                error!("Reverse tunnel gave up reconnecting to the server after {retries} retries")
+            }
+        }
+
+        let Some(hook) = self.config.reverse_tunnel_hook.clone() else {
+            return;
+        };
+        let event = ReconnectEvent {
+            event: kind,
+            tunnel: format!("{}:{}", remote_addr.host, remote_addr.port),
+            label: self.label.as_deref().map(str::to_string),
+            retries,
+            error: err.map(|err| format!("{err:#}")),
+        };
+        let http_cfg = self.config.http_client_config();
+        let notify = async move {
+            if let Err(err) = hook.notify(&event, &http_cfg).await {
+                warn!("Cannot run reconnect hook: {err:?}");
+            }
+        }
+        .instrument(Span::current());
+
+        match kind {
+            ReconnectEventKind::GaveUp => notify.await,
+            _ => {
+                self.executor.spawn(notify);
+            }
+        }
+    }
+}
+
+/// Interval the server checks the integrity of the tunnel at, None if it does not check it
+fn integrity_interval(response: &Parts) -> Option<u64> {
+    response.headers.get(INTEGRITY_HEADER)?.to_str().ok()?.parse().ok()
+}
+
+/// Session token of a resumable tunnel, sent back by the server in the cookie of its response
+fn resume_session(response: &Parts) -> Option<Uuid> {
+    let jwt = response
+        .headers
+        .get(COOKIE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| jwt_token_to_tunnel(h).ok())?;
+    let mut protocol = jwt.claims.p;
+    protocol.resume_mut()?.session
 }
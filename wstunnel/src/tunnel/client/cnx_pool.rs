@@ -1,44 +1,56 @@
+use crate::dscp;
+use crate::executor::AbortHandle;
+use crate::health::SERVER_REACHABILITY;
 use crate::protocols;
 use crate::protocols::tls;
 use crate::tunnel::client::WsClientConfig;
 use crate::tunnel::client::l4_transport_stream::TransportStream;
+use crate::tunnel::client::netsim;
+use anyhow::anyhow;
 use bb8::ManageConnection;
 use bytes::Bytes;
+use futures_util::future::join_all;
+use socket2::SockRef;
 use std::ops::Deref;
+use std::pin::Pin;
 use std::sync::Arc;
-use tracing::instrument;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tracing::{debug, info, instrument, warn};
 
 #[derive(Clone)]
-pub struct WsConnection(Arc<WsClientConfig>);
+pub struct WsConnection {
+    config: Arc<WsClientConfig>,
+    /// Number of connections opened to the server
+    connected: watch::Sender<u32>,
+}
 
 impl WsConnection {
     pub fn new(config: Arc<WsClientConfig>) -> Self {
-        Self(config)
+        Self {
+            config,
+            connected: watch::Sender::new(0),
+        }
     }
-}
 
-impl Deref for WsConnection {
-    type Target = WsClientConfig;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    pub fn connected(&self) -> watch::Receiver<u32> {
+        self.connected.subscribe()
     }
-}
 
-impl ManageConnection for WsConnection {
-    type Connection = Option<TransportStream>;
-    type Error = anyhow::Error;
-
-    #[instrument(level = "trace", name = "cnx_server", skip_all)]
-    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+    async fn connect_to_server(&self) -> anyhow::Result<TransportStream> {
         let timeout = self.timeout_connect;
 
         let tcp_stream = if let Some(http_proxy) = &self.http_proxy {
             protocols::tcp::connect_with_http_proxy(
-                http_proxy,
+                http_proxy.expose(),
                 self.remote_addr.host(),
                 self.remote_addr.port(),
                 self.socket_so_mark,
+                &self.source_bind,
                 timeout,
                 &self.dns_resolver,
             )
@@ -48,25 +60,219 @@ impl ManageConnection for WsConnection {
                 self.remote_addr.host(),
                 self.remote_addr.port(),
                 self.socket_so_mark,
+                &self.source_bind,
+                self.tcp_fastopen,
                 timeout,
                 &self.dns_resolver,
             )
             .await?
         };
+        if let Some(dscp) = self.dscp
+            && let Err(err) = dscp::set_dscp(SockRef::from(&tcp_stream), dscp)
+        {
+            warn!("Cannot set dscp {dscp} of the connection to the server: {err:?}");
+        }
+        let tcp_stream = match self.network_sim {
+            Some(sim) => netsim::degrade(tcp_stream, sim).await?,
+            None => tcp_stream,
+        };
 
-        if self.remote_addr.tls().is_some() {
-            let tls_stream = tls::connect(self, tcp_stream).await?;
-            Ok(Some(TransportStream::from_client_tls(tls_stream, Bytes::default())))
+        // With several hosts to rotate among, the host of the requests sent on this connection must match its sni
+        let host = self.camouflage.pick_host();
+        let stream = if self.remote_addr.tls().is_some() {
+            let sni = host.map_or_else(|| self.tls_server_name(), |host| ServerName::DnsName(host.clone()));
+            let tls_stream = tls::connect(self, tcp_stream, sni).await?;
+            TransportStream::from_client_tls(tls_stream, Bytes::default())
         } else {
-            Ok(Some(TransportStream::from_tcp(tcp_stream, Bytes::default())))
+            TransportStream::from_tcp(tcp_stream, Bytes::default())
+        };
+
+        match host {
+            Some(host) => Ok(stream.with_host(self.host_header(host.as_ref())?)),
+            None => Ok(stream),
+        }
+    }
+}
+
+impl Deref for WsConnection {
+    type Target = WsClientConfig;
+
+    fn deref(&self) -> &Self::Target {
+        &self.config
+    }
+}
+
+impl ManageConnection for WsConnection {
+    type Connection = Option<TransportStream>;
+    type Error = anyhow::Error;
+
+    #[instrument(level = "trace", name = "cnx_server", skip_all)]
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        match self.connect_to_server().await {
+            Ok(stream) => {
+                SERVER_REACHABILITY.connected();
+                self.connected.send_modify(|connected| *connected += 1);
+                Ok(Some(stream))
+            }
+            Err(err) => {
+                SERVER_REACHABILITY.failed(&err);
+                Err(err)
+            }
         }
     }
 
-    async fn is_valid(&self, _conn: &mut Self::Connection) -> Result<(), Self::Error> {
-        Ok(())
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        let Some(stream) = conn else {
+            return Err(anyhow!("connection has already been used"));
+        };
+
+        // The server never sends anything before receiving our upgrade request.
+        // So if the stream is ready to be read, it means the connection has been closed or is broken
+        let mut buf = [0u8; 1];
+        let mut read_buf = ReadBuf::new(&mut buf);
+        match Pin::new(stream).poll_read(&mut Context::from_waker(Waker::noop()), &mut read_buf) {
+            Poll::Pending => Ok(()),
+            Poll::Ready(Ok(())) if read_buf.filled().is_empty() => Err(anyhow!("idle connection closed by the server")),
+            Poll::Ready(Ok(())) => Err(anyhow!("unexpected data received on idle connection")),
+            Poll::Ready(Err(err)) => Err(anyhow!("idle connection is broken: {err}")),
+        }
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
         conn.is_none()
     }
 }
+
+/// Wait for the pool to open its `min_idle` connections, or until the timeout is reached.
+/// `connected` must come from the connection manager of the pool, see [`WsConnection::connected`]
+pub async fn warm_up(
+    pool: &bb8::Pool<WsConnection>,
+    mut connected: watch::Receiver<u32>,
+    min_idle: u32,
+    timeout: Duration,
+) {
+    if min_idle == 0 || timeout.is_zero() {
+        return;
+    }
+
+    info!("Warming up connection pool with {min_idle} connections");
+    // Getting the connections from the pool to wait for them would make it open more to keep `min_idle` ones idle
+    let warm = tokio::time::timeout(timeout, connected.wait_for(|connected| *connected >= min_idle)).await;
+    if !warm.is_ok_and(|warm| warm.is_ok()) {
+        warn!(
+            "Connection pool only has {}/{} connections after {}s. Continuing while it fills up in the background",
+            pool.state().idle_connections,
+            min_idle,
+            timeout.as_secs()
+        );
+    }
+}
+
+/// Periodically check out every idle connection of the pool, in order to validate them.
+/// Broken connections are discarded by the pool and replaced to maintain `min_idle`
+pub async fn check_idle_connections(pool: bb8::Pool<WsConnection>, interval: Duration) {
+    let mut timer = tokio::time::interval_at(Instant::now() + interval, interval);
+    loop {
+        timer.tick().await;
+
+        // All connections must be held at the same time, else we would check the same connection over and over
+        let nb_idle = pool.state().idle_connections;
+        let cnxs = join_all((0..nb_idle).map(|_| pool.get())).await;
+        if cnxs.iter().any(|cnx| cnx.is_ok()) {
+            SERVER_REACHABILITY.connected();
+        }
+        debug!(
+            "Health checked {} idle connections, {} failed to be replaced",
+            nb_idle,
+            cnxs.iter().filter(|cnx| cnx.is_err()).count()
+        );
+    }
+}
+
+/// Stop the health check of the pool when dropped
+pub struct HealthChecker(pub AbortHandle);
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_integrations::{client_config, dns_resolver};
+    use crate::tunnel::transport::TransportScheme;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    async fn server() -> (TcpListener, WsConnection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = client_config(dns_resolver(), TransportScheme::Ws, listener.local_addr().unwrap().port());
+        (listener, WsConnection::new(Arc::new(config)))
+    }
+
+    async fn invalid_reason(cnx: &WsConnection, conn: &mut Option<TransportStream>) -> String {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match cnx.is_valid(conn).await {
+                    Ok(()) => tokio::time::sleep(Duration::from_millis(10)).await,
+                    Err(err) => return err.to_string(),
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_is_valid() {
+        let (listener, cnx) = server().await;
+        let mut conn = cnx.connect().await.unwrap();
+        let (mut server_side, _) = listener.accept().await.unwrap();
+        cnx.is_valid(&mut conn).await.unwrap();
+
+        // The server sends nothing before the upgrade request
+        server_side.write_all(b"x").await.unwrap();
+        assert!(invalid_reason(&cnx, &mut conn).await.contains("unexpected data"));
+
+        let mut conn = cnx.connect().await.unwrap();
+        let (server_side, _) = listener.accept().await.unwrap();
+        drop(server_side);
+        assert!(invalid_reason(&cnx, &mut conn).await.contains("closed by the server"));
+
+        assert!(cnx.is_valid(&mut None).await.is_err());
+        assert!(cnx.has_broken(&mut None));
+    }
+
+    #[tokio::test]
+    async fn test_check_idle_connections() {
+        let (listener, cnx) = server().await;
+        let connected = cnx.connected();
+        let pool = bb8::Pool::builder().min_idle(Some(2)).build_unchecked(cnx);
+        warm_up(&pool, connected.clone(), 2, Duration::from_secs(2)).await;
+        assert_eq!(*connected.borrow(), 2);
+        let (closed, _) = listener.accept().await.unwrap();
+        let (open, _) = listener.accept().await.unwrap();
+        let acceptor = tokio::spawn(async move {
+            let mut cnxs = vec![open];
+            while let Ok((cnx, _)) = listener.accept().await {
+                cnxs.push(cnx);
+            }
+        });
+
+        // The connection closed by the server is found and replaced
+        drop(closed);
+        let checker = tokio::spawn(check_idle_connections(pool.clone(), Duration::from_millis(50)));
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while pool.state().statistics.connections_closed_invalid == 0 || pool.state().idle_connections < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        checker.abort();
+        acceptor.abort();
+        assert_eq!(pool.state().statistics.connections_closed_invalid, 1);
+    }
+}
@@ -1,11 +1,19 @@
+use crate::config::Secret;
 use crate::protocols::dns::DnsResolver;
+use crate::protocols::http_client::HttpClientConfig;
+use crate::protocols::tls::TlsFingerprint;
 use crate::somark::SoMark;
-use crate::tunnel::transport::TransportAddr;
+use crate::source_bind::SourceBind;
+use crate::tunnel::client::{Camouflage, NetworkSim, ReconnectHook, RedirectPolicy};
+use crate::tunnel::noise::NoiseClientConfig;
+use crate::tunnel::protocol::close_reason::WsCloseCodes;
+use crate::tunnel::transport::{PreSharedKey, TransportAddr};
 use hyper::header::{HeaderName, HeaderValue};
 use parking_lot::RwLock;
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::{Arc, LazyLock};
 use std::time::Duration;
 use tokio_rustls::TlsConnector;
@@ -17,18 +25,135 @@ pub struct WsClientConfig {
     pub remote_addr: TransportAddr,
     pub socket_so_mark: SoMark,
     pub http_upgrade_path_prefix: String,
+    /// Stable name of the client, the server tells its tunnels apart with it in its logs, metrics and admin api
+    pub device_id: Option<String>,
     pub http_upgrade_credentials: Option<HeaderValue>,
+    pub oidc_token_cache: Option<PathBuf>,
+    /// Key to sign the upgrade requests with, and to authenticate the server
+    pub psk: Option<PreSharedKey>,
+    /// Encrypt the data of the tunnels end to end with the server
+    pub noise: Option<NoiseClientConfig>,
     pub http_headers: HashMap<HeaderName, HeaderValue>,
     pub http_headers_file: Option<PathBuf>,
     pub http_header_host: HeaderValue,
     pub timeout_connect: Duration,
+    pub connection_health_check_interval: Option<Duration>,
+    pub connection_max_idle_age: Option<Duration>,
+    pub connection_warmup_timeout: Duration,
     pub websocket_ping_frequency: Option<Duration>,
     pub websocket_mask_frame: bool,
-    pub http_proxy: Option<Url>,
+    pub websocket_max_frame_size: usize,
+    /// How long to wait for the server to answer the close frame of a websocket tunnel
+    pub websocket_close_timeout: Duration,
+    pub websocket_close_codes: WsCloseCodes,
+    pub max_inflight_per_tunnel: usize,
+    /// When the http transports send the data of a tunnel to the server in separate requests from the ones receiving it
+    pub http_split_requests: SplitRequests,
+    /// Carry the tunnels as streams of a single connection with the server
+    pub mux: bool,
+    /// Age after which the connection of the mux and the ones of the resumable tunnels are replaced
+    pub transport_max_lifetime: Option<Duration>,
+    /// Bytes after which the connection of the mux and the ones of the resumable tunnels are replaced
+    pub transport_max_bytes: Option<u64>,
+    /// Send the first bytes of a tunnel along with the request opening it
+    pub early_data: bool,
+    /// Vary the path, host and headers of the requests to the server
+    pub camouflage: Camouflage,
+    /// Number of redirects followed when the server answers one to an upgrade request
+    pub upgrade_max_redirects: u8,
+    /// Where the upgrade requests can be redirected to
+    pub upgrade_redirect_policy: RedirectPolicy,
+    /// Servers switched to, in order, when the current one cannot be reached or is a standby
+    pub standby_servers: Vec<Url>,
+    /// Directory where the traffic of each tunnel is recorded as a pcap file
+    pub pcap_dir: Option<PathBuf>,
+    /// Bytes after which both sides of a tunnel compare the checksums of its data, to find out corruption in between
+    pub integrity_check_interval: Option<u64>,
+    /// Latency, losses and rate limit added to the connections to the server, to test them on a bad network
+    pub network_sim: Option<NetworkSim>,
+    pub tcp_fastopen: bool,
+    /// DSCP codepoint of the packets of the connections to the server
+    pub dscp: Option<u8>,
+    /// Interface or source ip of the connections to the server
+    pub source_bind: SourceBind,
+    pub http_proxy: Option<Secret<Url>>,
     pub dns_resolver: DnsResolver,
+    /// Consecutive failed attempts of a reverse tunnel to connect to the server before the client exits
+    pub reverse_tunnel_max_retries: Option<u32>,
+    /// Told when a reverse tunnel loses or recovers its connection with the server
+    pub reverse_tunnel_hook: Option<ReconnectHook>,
+    /// Interval the server probes the reverse tunnels waiting for a connection at
+    pub reverse_tunnel_probe_interval: Option<Duration>,
+    /// Resolver the dns transport sends its queries to
+    #[cfg(feature = "dns-transport")]
+    pub dns_transport_resolver: Option<std::net::SocketAddr>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SplitRequests {
+    Never,
+    Always,
+    /// Split the requests once a tunnel stalled, because a proxy/CDN in the middle buffered its request body
+    #[default]
+    Auto,
+}
+
+impl FromStr for SplitRequests {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "always" => Ok(Self::Always),
+            "auto" => Ok(Self::Auto),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the client does with the -L tunnels whose listener cannot be bound, i.e: because its port is already in use
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnListenerError {
+    /// Exit
+    #[default]
+    Fail,
+    /// Start the other tunnels without it, and exit if none could be started
+    Skip,
+    /// Start the other tunnels, and try again to bind it periodically. Exit if none could be started after a few attempts
+    Retry,
+}
+
+impl FromStr for OnListenerError {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            "retry" => Ok(Self::Retry),
+            _ => Err(()),
+        }
+    }
 }
 
 impl WsClientConfig {
+    pub fn http_client_config(&self) -> HttpClientConfig {
+        HttpClientConfig {
+            so_mark: self.socket_so_mark,
+            timeout: self.timeout_connect,
+            dns_resolver: self.dns_resolver.clone(),
+        }
+    }
+
+    /// Host header of the requests sent to `host`, with the port of the server when it is not a default one
+    pub fn host_header(&self, host: &str) -> anyhow::Result<HeaderValue> {
+        let host = match self.remote_addr.port() {
+            80 | 443 => host.to_string(),
+            port => format!("{host}:{port}"),
+        };
+        Ok(HeaderValue::from_str(&host)?)
+    }
+
     pub fn tls_server_name(&self) -> ServerName<'static> {
         static INVALID_DNS_NAME: LazyLock<DnsName> =
             LazyLock::new(|| DnsName::try_from("dns-name-invalid.com").unwrap());
@@ -53,6 +178,8 @@ impl WsClientConfig {
 pub struct TlsClientConfig {
     pub tls_sni_disabled: bool,
     pub tls_sni_override: Option<DnsName<'static>>,
+    /// Browser to look like in the tls client hello
+    pub tls_fingerprint: TlsFingerprint,
     pub tls_verify_certificate: bool,
     pub tls_connector: Arc<RwLock<TlsConnector>>,
     pub tls_certificate_path: Option<PathBuf>,
@@ -0,0 +1,57 @@
+use crate::tunnel::{MAX_LABEL_LEN, is_valid_label};
+use anyhow::{Context, anyhow};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use tracing::info;
+use uuid::Uuid;
+
+/// Device id stored in the file, generated on the first run so the client keeps it across its restarts
+pub fn load_or_generate_device_id(path: &Path) -> anyhow::Result<String> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            let id = content.trim();
+            if !is_valid_label(id) {
+                return Err(anyhow!(
+                    "invalid device id {id:?} in {}, expected at most {MAX_LABEL_LEN} letters, digits, '.', '_' or '-'",
+                    path.display()
+                ));
+            }
+            Ok(id.to_string())
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let id = Uuid::new_v4().to_string();
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::create_dir_all(dir).with_context(|| format!("cannot create directory {}", dir.display()))?;
+            }
+            fs::write(path, format!("{id}\n"))
+                .with_context(|| format!("cannot write device id to {}", path.display()))?;
+            info!("Generated device id {id} in {}", path.display());
+            Ok(id)
+        }
+        Err(err) => Err(err).with_context(|| format!("cannot read device id from {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_id_is_generated_once() {
+        let dir = std::env::temp_dir().join(format!("wstunnel-device-{}", std::process::id()));
+        let path = dir.join("device").join("id");
+
+        let id = load_or_generate_device_id(&path).unwrap();
+        assert!(is_valid_label(&id));
+        assert_eq!(load_or_generate_device_id(&path).unwrap(), id);
+
+        fs::write(&path, "sensor-42\n").unwrap();
+        assert_eq!(load_or_generate_device_id(&path).unwrap(), "sensor-42");
+
+        fs::write(&path, "not a device id").unwrap();
+        assert!(load_or_generate_device_id(&path).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
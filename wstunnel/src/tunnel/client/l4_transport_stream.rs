@@ -1,4 +1,6 @@
+use crate::protocols::tls::ServerTlsStream;
 use bytes::{Buf, Bytes};
+use hyper::header::HeaderValue;
 use std::cmp;
 use std::io::{Error, IoSlice};
 use std::pin::Pin;
@@ -10,30 +12,46 @@ use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 pub struct TransportStream {
     read: TransportReadHalf,
     write: TransportWriteHalf,
+    /// The tcp socket under the halves, to change its options once split
+    #[cfg(unix)]
+    socket: std::os::fd::RawFd,
+    #[cfg(windows)]
+    socket: std::os::windows::io::RawSocket,
+    /// Host header to use with this connection, when it differs from the configured one
+    host: Option<HeaderValue>,
 }
 
 impl TransportStream {
     pub fn from_tcp(tcp: TcpStream, read_buf: Bytes) -> Self {
+        let socket = raw_socket(&tcp);
         let (read, write) = tcp.into_split();
         Self {
             read: TransportReadHalf::Plain(read, read_buf),
             write: TransportWriteHalf::Plain(write),
+            socket,
+            host: None,
         }
     }
 
     pub fn from_client_tls(tls: tokio_rustls::client::TlsStream<TcpStream>, read_buf: Bytes) -> Self {
+        let socket = raw_socket(tls.get_ref().0);
         let (read, write) = tokio::io::split(tls);
         Self {
             read: TransportReadHalf::Tls(read, read_buf),
             write: TransportWriteHalf::Tls(write),
+            socket,
+            host: None,
         }
     }
 
-    pub fn from_server_tls(tls: tokio_rustls::server::TlsStream<TcpStream>, read_buf: Bytes) -> Self {
+    pub fn from_server_tls(tls: ServerTlsStream, read_buf: Bytes) -> Self {
+        let socket = raw_socket(tls.tcp_stream());
         let (read, write) = tokio::io::split(tls);
         Self {
             read: TransportReadHalf::TlsSrv(read, read_buf),
             write: TransportWriteHalf::TlsSrv(write),
+            socket,
+            host: None,
         }
     }
 
@@ -43,18 +61,49 @@ impl TransportStream {
         Self {
             read,
             write: self.write,
+            socket: self.socket,
+            host: self.host,
         }
     }
 
+    pub fn with_host(mut self, host: HeaderValue) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    pub fn host(&self) -> Option<&HeaderValue> {
+        self.host.as_ref()
+    }
+
+    /// Mark the packets sent on the connection with this DSCP codepoint
+    pub fn set_dscp(&self, dscp: u8) -> std::io::Result<()> {
+        // Safety: the socket is owned by the halves of self, so it stays open while borrowed
+        #[cfg(unix)]
+        let socket = unsafe { std::os::fd::BorrowedFd::borrow_raw(self.socket) };
+        #[cfg(windows)]
+        let socket = unsafe { std::os::windows::io::BorrowedSocket::borrow_raw(self.socket) };
+        crate::dscp::set_dscp(socket2::SockRef::from(&socket), dscp)
+    }
+
     pub fn into_split(self) -> (TransportReadHalf, TransportWriteHalf) {
         (self.read, self.write)
     }
 }
 
+#[cfg(unix)]
+fn raw_socket(tcp: &TcpStream) -> std::os::fd::RawFd {
+    std::os::fd::AsRawFd::as_raw_fd(tcp)
+}
+
+#[cfg(windows)]
+fn raw_socket(tcp: &TcpStream) -> std::os::windows::io::RawSocket {
+    std::os::windows::io::AsRawSocket::as_raw_socket(tcp)
+}
+
 pub enum TransportReadHalf {
     Plain(OwnedReadHalf, Bytes),
     Tls(ReadHalf<tokio_rustls::client::TlsStream<TcpStream>>, Bytes),
-    TlsSrv(ReadHalf<tokio_rustls::server::TlsStream<TcpStream>>, Bytes),
+    TlsSrv(ReadHalf<ServerTlsStream>, Bytes),
 }
 
 impl TransportReadHalf {
@@ -70,7 +119,7 @@ impl TransportReadHalf {
 pub enum TransportWriteHalf {
     Plain(OwnedWriteHalf),
     Tls(WriteHalf<tokio_rustls::client::TlsStream<TcpStream>>),
-    TlsSrv(WriteHalf<tokio_rustls::server::TlsStream<TcpStream>>),
+    TlsSrv(WriteHalf<ServerTlsStream>),
 }
 
 impl AsyncRead for TransportStream {
@@ -1,9 +1,26 @@
 #![allow(clippy::module_inception)]
+mod accept_limit;
+mod camouflage;
 mod client;
 mod cnx_pool;
 mod config;
+mod device;
 pub mod l4_transport_stream;
+mod netsim;
+mod probe;
+mod reconnect;
+mod redirect;
+mod rotation;
 
+pub use accept_limit::{AcceptLimits, AcceptOverflow};
+pub use camouflage::{Browser, Camouflage};
 pub use client::WsClient;
+pub use config::OnListenerError;
+pub use config::SplitRequests;
 pub use config::TlsClientConfig;
 pub use config::WsClientConfig;
+pub use device::load_or_generate_device_id;
+pub use netsim::NetworkSim;
+pub(crate) use reconnect::ReconnectEventKind;
+pub use reconnect::{RECONNECT_GAVE_UP, RECONNECT_GAVE_UP_UNAUTHORIZED, ReconnectHook};
+pub use redirect::{AFFINITY_HEADER, RedirectPolicy};
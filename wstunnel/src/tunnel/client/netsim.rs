@@ -0,0 +1,214 @@
+//! netsim - degrade the connections to the server like a bad network would, with latency, losses and a limited rate,
+//! to test how the transports behave without tc/netem.
+//! The tcp connection to the server is relayed through a loopback connection, so everything above it (tls, http, ...)
+//! goes through the degraded network. Tcp retransmits the lost segments, so they are not dropped: they arrive late,
+//! and hold back the ones behind them
+use bytes::Bytes;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::debug;
+
+/// Bytes read at once, the size of a tcp segment on an ethernet link
+const SEGMENT_LEN: usize = 1460;
+/// Segments in flight in each direction, before the sender has to wait
+const QUEUE_LEN: usize = 512;
+/// Least time before a lost segment is sent again, like the minimum RTO of linux
+const MIN_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetworkSim {
+    /// Added to each direction, so the round trip time grows by twice as much
+    pub latency: Duration,
+    /// Ratio of the segments lost, between 0 and 1
+    pub loss: f64,
+    /// Bytes per second of each direction, unlimited if None
+    pub rate: Option<u64>,
+    /// Seed of the losses, so they hit the same segments on every run and connection. Random if None
+    pub seed: Option<u64>,
+}
+
+impl NetworkSim {
+    fn retransmit_timeout(&self) -> Duration {
+        MIN_RETRANSMIT_TIMEOUT + self.latency * 2
+    }
+
+    fn rng(&self, direction: u64) -> StdRng {
+        match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(direction)),
+            None => StdRng::from_rng(&mut rand::rng()),
+        }
+    }
+}
+
+/// Schedule of the segments going in one direction
+struct Link {
+    sim: NetworkSim,
+    rng: StdRng,
+    /// When the link is done sending the previous segments, with a limited rate
+    free_at: Instant,
+    /// When the previous segment is received, the next ones cannot be received before
+    received_at: Instant,
+}
+
+impl Link {
+    fn new(sim: NetworkSim, direction: u64, now: Instant) -> Self {
+        Self {
+            sim,
+            rng: sim.rng(direction),
+            free_at: now,
+            received_at: now,
+        }
+    }
+
+    /// When the segment of `len` bytes sent at `now` is received on the other side
+    fn receive_at(&mut self, now: Instant, len: usize) -> Instant {
+        self.free_at = self.free_at.max(now);
+        if let Some(rate) = self.sim.rate {
+            self.free_at += Duration::from_secs_f64(len as f64 / rate as f64);
+        }
+
+        let mut received_at = self.free_at + self.sim.latency;
+        if self.sim.loss > 0.0 && self.rng.random_bool(self.sim.loss) {
+            received_at += self.sim.retransmit_timeout();
+        }
+        self.received_at = self.received_at.max(received_at);
+        self.received_at
+    }
+}
+
+/// Relay `tcp` through a degraded network, and return the connection to use instead of it
+pub async fn degrade(tcp: TcpStream, sim: NetworkSim) -> io::Result<TcpStream> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let (local, (relay, peer)) = tokio::try_join!(TcpStream::connect(listener.local_addr()?), listener.accept())?;
+    if peer != local.local_addr()? {
+        return Err(io::Error::other(
+            "another process connected to the relay of the simulated network",
+        ));
+    }
+    local.set_nodelay(true)?;
+    relay.set_nodelay(true)?;
+
+    tokio::spawn(async move {
+        let (tcp_rx, tcp_tx) = tcp.into_split();
+        let (relay_rx, relay_tx) = relay.into_split();
+        tokio::join!(
+            forward(relay_rx, tcp_tx, Link::new(sim, 0, Instant::now())),
+            forward(tcp_rx, relay_tx, Link::new(sim, 1, Instant::now())),
+        );
+        debug!("Simulated network connection closed");
+    });
+
+    Ok(local)
+}
+
+async fn forward(mut rx: impl AsyncRead + Unpin, mut tx: impl AsyncWrite + Unpin, mut link: Link) {
+    let (segments_tx, mut segments_rx) = mpsc::channel::<(Instant, Bytes)>(QUEUE_LEN);
+    let send = async move {
+        let mut buf = [0u8; SEGMENT_LEN];
+        loop {
+            let len = match rx.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(len) => len,
+            };
+            let segment = (link.receive_at(Instant::now(), len), Bytes::copy_from_slice(&buf[..len]));
+            if segments_tx.send(segment).await.is_err() {
+                return;
+            }
+        }
+    };
+    let receive = async move {
+        while let Some((received_at, segment)) = segments_rx.recv().await {
+            tokio::time::sleep_until(received_at).await;
+            if tx.write_all(&segment).await.is_err() {
+                return;
+            }
+        }
+        let _ = tx.shutdown().await;
+    };
+    tokio::join!(send, receive);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sim(loss: f64) -> NetworkSim {
+        NetworkSim {
+            latency: Duration::from_millis(50),
+            loss,
+            rate: Some(10_000),
+            seed: Some(42),
+        }
+    }
+
+    #[test]
+    fn test_link_schedule() {
+        let now = Instant::now();
+        let mut link = Link::new(sim(0.0), 0, now);
+        let received_at: Vec<_> = (0..10).map(|_| link.receive_at(now, 1000)).collect();
+        // 1000 bytes take 100ms at 10000 bytes/s, and 50ms more to cross the link
+        assert_eq!(received_at[0], now + Duration::from_millis(150));
+        assert_eq!(received_at[9], now + Duration::from_millis(1050));
+
+        // The same seed loses the same segments, and the segments behind a lost one wait for it
+        let schedule = |loss, direction| {
+            let mut link = Link::new(sim(loss), direction, now);
+            (0..100).map(|_| link.receive_at(now, 1000)).collect::<Vec<_>>()
+        };
+        let received_at = schedule(0.2, 0);
+        assert_eq!(received_at, schedule(0.2, 0));
+        assert_ne!(received_at, schedule(0.2, 1));
+        assert!(received_at.is_sorted());
+        let lossless = schedule(0.0, 0);
+        assert!(
+            received_at
+                .iter()
+                .zip(&lossless)
+                .all(|(at, lossless_at)| at >= lossless_at)
+        );
+        assert!(
+            received_at
+                .iter()
+                .zip(&lossless)
+                .any(|(at, lossless_at)| at > lossless_at)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_degrade() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let echo = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut rx, mut tx) = stream.into_split();
+            tokio::io::copy(&mut rx, &mut tx).await.unwrap();
+        });
+
+        let sim = NetworkSim { rate: None, ..sim(0.1) };
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let (mut rx, mut tx) = degrade(tcp, sim).await.unwrap().into_split();
+        let payload: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let started_at = Instant::now();
+        let writer = tokio::spawn({
+            let payload = payload.clone();
+            async move {
+                tx.write_all(&payload).await.unwrap();
+                tx.shutdown().await.unwrap();
+            }
+        });
+
+        let mut received = vec![];
+        rx.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+        assert!(started_at.elapsed() >= 2 * sim.latency);
+        writer.await.unwrap();
+        echo.await.unwrap();
+    }
+}
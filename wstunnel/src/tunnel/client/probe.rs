@@ -0,0 +1,98 @@
+//! probe - reverse tunnels probed by the server while they wait for a connection, with --reverse-tunnel-probe-interval.
+//! The client answers the pings of the server until it opens the tunnel, see [`crate::tunnel::protocol::probe_record`]
+use crate::tunnel::protocol::probe_record::{MAX_MISSED_PROBES, PROBE_HEADER, ProbeRecord};
+use anyhow::{Context, anyhow};
+use bytes::{Bytes, BytesMut};
+use hyper::http::response::Parts;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Interval the server probes the reverse tunnel at, None if it waited for a connection before answering
+pub(super) fn probe_interval(response: &Parts) -> Option<Duration> {
+    let secs: u64 = response.headers.get(PROBE_HEADER)?.to_str().ok()?.parse().ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+/// Answer the pings of the server until it opens the tunnel for a connection. Return the cookie of the open record,
+/// along with the bytes of the tunnel already received after it
+pub(super) async fn wait_connection<S>(transport: &mut S, interval: Duration) -> anyhow::Result<(Bytes, BytesMut)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let max_silence = interval * MAX_MISSED_PROBES;
+    let mut buf = BytesMut::with_capacity(64);
+    let mut pong = BytesMut::with_capacity(1);
+    loop {
+        while let Some(record) = ProbeRecord::decode(&mut buf)? {
+            match record {
+                ProbeRecord::Ping => {
+                    ProbeRecord::Pong.encode(&mut pong);
+                    transport.write_all(&pong.split()).await?;
+                }
+                ProbeRecord::Open(cookie) => return Ok((cookie, buf)),
+                ProbeRecord::Pong => return Err(anyhow!("server answered a probe it should have sent")),
+            }
+        }
+
+        let read = tokio::time::timeout(max_silence, transport.read_buf(&mut buf))
+            .await
+            .with_context(|| format!("server did not probe the reverse tunnel for {max_silence:?}"))??;
+        if read == 0 {
+            return Err(anyhow!("server closed the reverse tunnel while waiting for a connection"));
+        }
+    }
+}
+
+/// Forward the tunnel opened by the server between it and the local connection, starting with the bytes received
+/// along with the open record
+pub(super) async fn forward<S, R, W>(transport: S, received: BytesMut, mut local_rx: R, mut local_tx: W)
+where
+    S: AsyncRead + AsyncWrite,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut transport_rx, mut transport_tx) = tokio::io::split(transport);
+    let to_local = async {
+        if local_tx.write_all(&received).await.is_ok() {
+            let _ = tokio::io::copy(&mut transport_rx, &mut local_tx).await;
+        }
+        let _ = local_tx.shutdown().await;
+    };
+    let to_server = async {
+        let _ = tokio::io::copy(&mut local_rx, &mut transport_tx).await;
+        let _ = transport_tx.shutdown().await;
+    };
+    tokio::join!(to_local, to_server);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_connection() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let mut records = BytesMut::new();
+        ProbeRecord::Ping.encode(&mut records);
+        ProbeRecord::Ping.encode(&mut records);
+        ProbeRecord::Open(Bytes::from_static(b"jwt")).encode(&mut records);
+        records.extend_from_slice(b"data");
+        server.write_all(&records).await.unwrap();
+
+        let (cookie, received) = wait_connection(&mut client, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(cookie, Bytes::from_static(b"jwt"));
+        assert_eq!(&received[..], b"data");
+        let mut pongs = [0; 2];
+        server.read_exact(&mut pongs).await.unwrap();
+        assert_eq!(pongs, [1, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_wait_connection_server_gone() {
+        let (mut client, _server) = tokio::io::duplex(1024);
+        let err = wait_connection(&mut client, Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("did not probe"), "{err:?}");
+    }
+}
@@ -0,0 +1,198 @@
+use crate::protocols::http_client;
+use crate::protocols::http_client::HttpClientConfig;
+use anyhow::{Context, anyhow};
+use bytes::Bytes;
+use hyper::Method;
+use hyper::header::{CONTENT_TYPE, HeaderValue};
+use rand::Rng;
+use serde::Serialize;
+use std::cmp::{max, min};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::LazyLock;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+use url::Url;
+
+const BASE_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Longest time the hook can take to handle an event
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Notified once a reverse tunnel gave up reconnecting to the server, to exit the client
+pub static RECONNECT_GAVE_UP: LazyLock<Notify> = LazyLock::new(Notify::new);
+/// Set before the notification when the server was refusing the credentials of the reverse tunnel that gave up
+pub static RECONNECT_GAVE_UP_UNAUTHORIZED: AtomicBool = AtomicBool::new(false);
+
+/// Decorrelated jitter backoff: each delay is picked at random between the base delay and 3 times the previous one,
+/// up to `max_delay`, so the clients that lost the server at the same time do not all come back at the same time
+pub fn new_reconnect_delay(max_delay: Duration) -> impl FnMut() -> Duration {
+    let mut reconnect_delay = BASE_RECONNECT_DELAY;
+    let mut first = true;
+
+    move || -> Duration {
+        // The first retry is done right after the base delay, as most failures are transient
+        if !first {
+            let upper = max(BASE_RECONNECT_DELAY, reconnect_delay * 3);
+            reconnect_delay = rand::rng().random_range(BASE_RECONNECT_DELAY..=upper);
+        }
+        first = false;
+        reconnect_delay = min(reconnect_delay, max_delay);
+        reconnect_delay
+    }
+}
+
+/// Program or url told when a reverse tunnel loses or recovers its connection with the server
+#[derive(Debug, Clone)]
+pub enum ReconnectHook {
+    /// Run the program with the event as json on its stdin, and its kind in the WSTUNNEL_EVENT env var
+    Command(PathBuf),
+    /// POST the event as json to the url
+    Webhook(Url),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconnectEventKind {
+    /// The server cannot be reached anymore
+    Disconnected,
+    /// The server can be reached again
+    Reconnected,
+    /// The client stops retrying after --reverse-tunnel-connection-max-retries, and exits
+    GaveUp,
+}
+
+impl ReconnectEventKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Disconnected => "disconnected",
+            Self::Reconnected => "reconnected",
+            Self::GaveUp => "gave_up",
+        }
+    }
+}
+
+/// Event sent to the reconnect hook
+#[derive(Debug, Serialize)]
+pub struct ReconnectEvent {
+    pub event: ReconnectEventKind,
+    /// Address the server listens on for the reverse tunnel
+    pub tunnel: String,
+    pub label: Option<String>,
+    /// Consecutive failed attempts to connect to the server
+    pub retries: u32,
+    pub error: Option<String>,
+}
+
+impl ReconnectHook {
+    pub async fn notify(&self, event: &ReconnectEvent, http_cfg: &HttpClientConfig) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(event).context("cannot serialize reconnect event")?;
+        let fut = async {
+            match self {
+                Self::Command(path) => run_command(path, event.event, payload).await,
+                Self::Webhook(url) => call_webhook(url, payload, http_cfg).await,
+            }
+        };
+
+        tokio::time::timeout(HOOK_TIMEOUT, fut)
+            .await
+            .map_err(|_| anyhow!("reconnect hook did not finish after {}s", HOOK_TIMEOUT.as_secs()))?
+    }
+}
+
+async fn run_command(path: &Path, event: ReconnectEventKind, payload: Vec<u8>) -> anyhow::Result<()> {
+    let mut child = tokio::process::Command::new(path)
+        .env("WSTUNNEL_EVENT", event.as_str())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("cannot execute reconnect hook {path:?}"))?;
+
+    if let Some(mut stdin) = child.stdin.take()
+        && let Err(err) = stdin.write_all(&payload).await
+    {
+        debug!("cannot write event to reconnect hook stdin: {err}");
+    }
+
+    let status = child.wait().await?;
+    if !status.success() {
+        warn!("reconnect hook {path:?} exited with {status}");
+    }
+    Ok(())
+}
+
+async fn call_webhook(url: &Url, payload: Vec<u8>, http_cfg: &HttpClientConfig) -> anyhow::Result<()> {
+    let headers = [(CONTENT_TYPE, HeaderValue::from_static("application/json"))];
+    let (status, _) = http_client::request(http_cfg, Method::POST, url, &headers, Bytes::from(payload)).await?;
+    if !status.is_success() {
+        warn!("reconnect hook {url} responded with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconnect_delay_stays_within_bounds() {
+        let max_delay = Duration::from_secs(30);
+        let mut reconnect_delay = new_reconnect_delay(max_delay);
+        assert_eq!(reconnect_delay(), BASE_RECONNECT_DELAY);
+
+        let mut previous = BASE_RECONNECT_DELAY;
+        for _ in 0..100 {
+            let delay = reconnect_delay();
+            assert!(delay >= BASE_RECONNECT_DELAY);
+            assert!(delay <= min(max_delay, previous * 3));
+            previous = delay;
+        }
+
+        let mut reconnect_delay = new_reconnect_delay(Duration::from_millis(500));
+        assert!((0..10).all(|_| reconnect_delay() == Duration::from_millis(500)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_hook_gets_the_event() {
+        let out = std::env::temp_dir().join(format!("wstunnel-reconnect-hook-{}", std::process::id()));
+        let script = std::env::temp_dir().join(format!("wstunnel-reconnect-hook-{}.sh", std::process::id()));
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho $WSTUNNEL_EVENT > {}\ncat >> {}\n",
+                out.display(),
+                out.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let event = ReconnectEvent {
+            event: ReconnectEventKind::Disconnected,
+            tunnel: "0.0.0.0:2222".to_string(),
+            label: Some("ssh".to_string()),
+            retries: 1,
+            error: Some("connection refused".to_string()),
+        };
+        let http_cfg = HttpClientConfig {
+            so_mark: crate::somark::SoMark::new(None),
+            timeout: HOOK_TIMEOUT,
+            dns_resolver: crate::protocols::dns::DnsResolver::System,
+        };
+        ReconnectHook::Command(script.clone())
+            .notify(&event, &http_cfg)
+            .await
+            .unwrap();
+
+        let out_content = std::fs::read_to_string(&out).unwrap();
+        assert!(out_content.starts_with("disconnected\n"));
+        assert!(out_content.contains(r#""event":"disconnected""#));
+        assert!(out_content.contains(r#""label":"ssh""#));
+        std::fs::remove_file(&script).unwrap();
+        std::fs::remove_file(&out).unwrap();
+    }
+}
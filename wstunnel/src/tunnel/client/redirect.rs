@@ -0,0 +1,281 @@
+//! redirect - follow the redirects answered to the upgrade requests by the server, or by a load balancer in front of it,
+//! i.e: one redirecting ws:// to wss://
+use crate::protocols::tls;
+use crate::protocols::tls::TlsFingerprint;
+use crate::tunnel::client::{TlsClientConfig, WsClientConfig};
+use crate::tunnel::transport::{TransportAddr, TransportScheme};
+use anyhow::{Context, anyhow};
+use hyper::http::{HeaderName, HeaderValue};
+use parking_lot::RwLock;
+use std::str::FromStr;
+use std::sync::Arc;
+use url::Url;
+
+/// Same for all the requests of a client, so a load balancer in front of the servers can keep its tunnels on one of them
+pub const AFFINITY_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-affinity");
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    /// Only the path can change
+    SameOrigin,
+    /// The scheme and the port can change too, but never from tls to plain text
+    #[default]
+    SameHost,
+    Any,
+}
+
+impl FromStr for RedirectPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "same-origin" => Ok(Self::SameOrigin),
+            "same-host" => Ok(Self::SameHost),
+            "any" => Ok(Self::Any),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Config of the client sending its upgrade requests to `location`, which is relative to the server of `config`
+pub fn redirect_config(config: &WsClientConfig, location: &HeaderValue) -> anyhow::Result<WsClientConfig> {
+    let current = &config.remote_addr;
+    let current_tls = current.tls().is_some();
+    let base = Url::parse(&format!(
+        "{}://{}:{}/{}/events",
+        if current_tls { "https" } else { "http" },
+        current.host(),
+        current.port(),
+        config.http_upgrade_path_prefix
+    ))?;
+    let url = base
+        .join(location.to_str()?)
+        .with_context(|| format!("invalid redirect location {location:?}"))?;
+
+    let tls = match url.scheme() {
+        "https" | "wss" => true,
+        "http" | "ws" => false,
+        scheme => return Err(anyhow!("cannot follow a redirect to a {scheme} url: {url}")),
+    };
+    let host = url
+        .host()
+        .with_context(|| format!("redirect to {url} has no host"))?
+        .to_owned();
+    let port = url
+        .port_or_known_default()
+        .with_context(|| format!("redirect to {url} has no port"))?;
+    let same_host = host == *current.host();
+    let allowed = match config.upgrade_redirect_policy {
+        RedirectPolicy::SameOrigin => same_host && tls == current_tls && port == current.port(),
+        RedirectPolicy::SameHost => same_host && (tls || !current_tls),
+        RedirectPolicy::Any => true,
+    };
+    if !allowed {
+        return Err(anyhow!(
+            "redirect to {url} is not allowed by the redirect policy {:?}",
+            config.upgrade_redirect_policy
+        ));
+    }
+
+    let mut path_prefix = url
+        .path()
+        .strip_prefix('/')
+        .and_then(|path| path.strip_suffix("/events"))
+        .filter(|prefix| !prefix.is_empty())
+        .with_context(|| format!("redirect to {url} is not an upgrade path of a wstunnel server"))?;
+    // The random segment of the path is not part of the prefix
+    if config.camouflage.random_path
+        && path_prefix.matches('/').count() > config.http_upgrade_path_prefix.matches('/').count()
+    {
+        path_prefix = path_prefix.rsplit_once('/').map_or(path_prefix, |(prefix, _)| prefix);
+    }
+
+    let scheme = match (current.scheme(), tls) {
+        (TransportScheme::Ws | TransportScheme::Wss, false) => TransportScheme::Ws,
+        (TransportScheme::Ws | TransportScheme::Wss, true) => TransportScheme::Wss,
+        (TransportScheme::Http1 | TransportScheme::Https1, false) => TransportScheme::Http1,
+        (TransportScheme::Http1 | TransportScheme::Https1, true) => TransportScheme::Https1,
+        (TransportScheme::Http | TransportScheme::Https, false) => TransportScheme::Http,
+        (TransportScheme::Http | TransportScheme::Https, true) => TransportScheme::Https,
+        (TransportScheme::Grpc | TransportScheme::Grpcs, false) => TransportScheme::Grpc,
+        (TransportScheme::Grpc | TransportScheme::Grpcs, true) => TransportScheme::Grpcs,
+        #[cfg(any(feature = "dns-transport", feature = "icmp-transport", feature = "ssh-transport"))]
+        (scheme, _) => return Err(anyhow!("the {scheme} transport cannot follow redirects")),
+    };
+    let tls_config = match (tls, current.tls()) {
+        (false, _) => None,
+        (true, Some(tls)) => {
+            let mut tls = tls.clone();
+            if !same_host {
+                tls.tls_sni_override = None;
+            }
+            Some(tls)
+        }
+        // Redirected from plain text, so with the defaults of the command line
+        (true, None) => Some(TlsClientConfig {
+            tls_sni_disabled: false,
+            tls_sni_override: None,
+            tls_fingerprint: TlsFingerprint::default(),
+            tls_verify_certificate: false,
+            tls_connector: Arc::new(RwLock::new(tls::tls_connector(
+                false,
+                scheme.alpn_protocols(),
+                true,
+                TlsFingerprint::default(),
+                None,
+                None,
+                None,
+            )?)),
+            tls_certificate_path: None,
+            tls_key_path: None,
+        }),
+    };
+
+    let mut redirected = config.clone();
+    redirected.remote_addr = TransportAddr::new(scheme, host.clone(), port, tls_config)
+        .with_context(|| format!("invalid redirect to {url}"))?;
+    redirected.http_upgrade_path_prefix = path_prefix.to_string();
+    // The host header follows the server, unless it was set explicitly
+    if config.http_header_host == config.host_header(&current.host().to_string())? {
+        redirected.http_header_host = redirected.host_header(&host.to_string())?;
+    }
+    if !same_host {
+        redirected.camouflage.hosts.clear();
+    }
+
+    Ok(redirected)
+}
+
+/// Config of the client sending its upgrade requests to the standby `server`, with the same path prefix as `config`.
+/// Standby servers are given explicitly by the user, so they are not restricted by the redirect policy
+pub fn standby_config(config: &WsClientConfig, server: &Url) -> anyhow::Result<WsClientConfig> {
+    let mut location = server.clone();
+    location.set_path(&format!("{}/events", config.http_upgrade_path_prefix));
+    location.set_query(None);
+
+    let mut any = config.clone();
+    any.upgrade_redirect_policy = RedirectPolicy::Any;
+    let mut standby = redirect_config(&any, &HeaderValue::from_str(location.as_str())?)?;
+    standby.upgrade_redirect_policy = config.upgrade_redirect_policy;
+    Ok(standby)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::dns::DnsResolver;
+    use crate::somark::SoMark;
+    use crate::source_bind::SourceBind;
+    use crate::tunnel::client::{Camouflage, SplitRequests};
+    use crate::tunnel::protocol::close_reason::WsCloseCodes;
+    use std::time::Duration;
+    use url::Host;
+
+    fn config(policy: RedirectPolicy) -> WsClientConfig {
+        WsClientConfig {
+            remote_addr: TransportAddr::new(TransportScheme::Ws, Host::Domain("example.com".to_string()), 80, None)
+                .unwrap(),
+            socket_so_mark: SoMark::new(None),
+            http_upgrade_path_prefix: "v1".to_string(),
+            device_id: None,
+            http_upgrade_credentials: None,
+            oidc_token_cache: None,
+            psk: None,
+            noise: None,
+            http_headers: Default::default(),
+            http_headers_file: None,
+            http_header_host: HeaderValue::from_static("example.com"),
+            timeout_connect: Duration::from_secs(10),
+            connection_health_check_interval: None,
+            connection_max_idle_age: None,
+            connection_warmup_timeout: Duration::ZERO,
+            websocket_ping_frequency: None,
+            websocket_mask_frame: false,
+            websocket_max_frame_size: 64 * 1024,
+            websocket_close_timeout: Duration::ZERO,
+            websocket_close_codes: WsCloseCodes::default(),
+            max_inflight_per_tunnel: 64 * 1024,
+            http_split_requests: SplitRequests::Never,
+            mux: false,
+            transport_max_lifetime: None,
+            transport_max_bytes: None,
+            early_data: false,
+            camouflage: Camouflage::default(),
+            upgrade_max_redirects: 5,
+            upgrade_redirect_policy: policy,
+            standby_servers: vec![],
+            pcap_dir: None,
+            integrity_check_interval: None,
+            network_sim: None,
+            tcp_fastopen: false,
+            dscp: None,
+            source_bind: SourceBind::default(),
+            http_proxy: None,
+            dns_resolver: DnsResolver::System,
+            reverse_tunnel_max_retries: None,
+            reverse_tunnel_hook: None,
+            reverse_tunnel_probe_interval: None,
+            #[cfg(feature = "dns-transport")]
+            dns_transport_resolver: None,
+        }
+    }
+
+    #[test]
+    fn test_redirect_to_tls() {
+        // The tests are built with both crypto providers, so none is picked by default
+        #[cfg(feature = "aws-lc-rs")]
+        let _ = tokio_rustls::rustls::crypto::aws_lc_rs::default_provider().install_default();
+        #[cfg(not(feature = "aws-lc-rs"))]
+        let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
+
+        let redirected = redirect_config(
+            &config(RedirectPolicy::SameHost),
+            &HeaderValue::from_static("wss://example.com/v1/events"),
+        )
+        .unwrap();
+        assert!(matches!(redirected.remote_addr.scheme(), TransportScheme::Wss));
+        assert_eq!(redirected.remote_addr.port(), 443);
+        assert!(redirected.remote_addr.tls().is_some());
+        assert_eq!(redirected.http_header_host, "example.com");
+
+        assert!(
+            redirect_config(
+                &config(RedirectPolicy::SameOrigin),
+                &HeaderValue::from_static("wss://example.com/v1/events"),
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_redirect_path_and_host() {
+        let redirected = redirect_config(
+            &config(RedirectPolicy::SameOrigin),
+            &HeaderValue::from_static("/tunnel/v2/events"),
+        )
+        .unwrap();
+        assert_eq!(redirected.http_upgrade_path_prefix, "tunnel/v2");
+        assert_eq!(redirected.remote_addr.port(), 80);
+
+        let location = HeaderValue::from_static("http://other.com:8080/v1/events");
+        assert!(redirect_config(&config(RedirectPolicy::SameHost), &location).is_err());
+        let redirected = redirect_config(&config(RedirectPolicy::Any), &location).unwrap();
+        assert_eq!(redirected.http_header_host, "other.com:8080");
+
+        assert!(redirect_config(&config(RedirectPolicy::Any), &HeaderValue::from_static("/login")).is_err());
+    }
+
+    #[test]
+    fn test_standby_server_ignores_the_redirect_policy() {
+        let standby = standby_config(
+            &config(RedirectPolicy::SameOrigin),
+            &Url::parse("ws://standby.example.com:8080").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(standby.remote_addr.host().to_string(), "standby.example.com");
+        assert_eq!(standby.remote_addr.port(), 8080);
+        assert_eq!(standby.http_upgrade_path_prefix, "v1");
+        assert_eq!(standby.http_header_host, "standby.example.com:8080");
+        assert_eq!(standby.upgrade_redirect_policy, RedirectPolicy::SameOrigin);
+    }
+}
@@ -0,0 +1,93 @@
+//! rotation - replace the connections with the server before a CDN kills them, once they are too old or carried too
+//! many bytes. Only the connections tunnels can move away from are replaced: the one of the mux, and the ones of the
+//! resumable tunnels
+use crate::tunnel::client::WsClientConfig;
+use pin_project::pin_project;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+/// How often the bytes carried by a connection are compared to the limit
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Count the bytes going through the connection with the server, in both directions
+#[pin_project]
+pub(super) struct CountedStream<S> {
+    #[pin]
+    inner: S,
+    bytes: Arc<AtomicU64>,
+}
+
+impl<S> CountedStream<S> {
+    pub(super) fn new(inner: S) -> (Self, Arc<AtomicU64>) {
+        let bytes = Arc::new(AtomicU64::new(0));
+        (
+            Self {
+                inner,
+                bytes: bytes.clone(),
+            },
+            bytes,
+        )
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for CountedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        this.bytes
+            .fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for CountedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let written = ready!(this.inner.poll_write(cx, buf))?;
+        this.bytes.fetch_add(written as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+pub(super) fn is_enabled(config: &WsClientConfig) -> bool {
+    config.transport_max_lifetime.is_some() || config.transport_max_bytes.is_some()
+}
+
+/// Resolve once the connection opened now, whose bytes are counted in `bytes`, reached one of the limits. Never if
+/// there are none
+pub(super) async fn limit_reached(config: &WsClientConfig, bytes: &AtomicU64) {
+    let opened_at = Instant::now();
+    loop {
+        let remaining_lifetime = config
+            .transport_max_lifetime
+            .map(|max_lifetime| max_lifetime.saturating_sub(opened_at.elapsed()));
+        let bytes_exceeded = config
+            .transport_max_bytes
+            .is_some_and(|max_bytes| bytes.load(Ordering::Relaxed) >= max_bytes);
+        if bytes_exceeded || remaining_lifetime == Some(Duration::ZERO) {
+            return;
+        }
+
+        let next_check = match (remaining_lifetime, config.transport_max_bytes) {
+            (Some(remaining_lifetime), None) => remaining_lifetime,
+            (remaining_lifetime, Some(_)) => remaining_lifetime.map_or(CHECK_INTERVAL, |r| r.min(CHECK_INTERVAL)),
+            (None, None) => return std::future::pending().await,
+        };
+        tokio::time::sleep(next_check).await;
+    }
+}
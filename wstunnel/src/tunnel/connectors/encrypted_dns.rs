@@ -0,0 +1,76 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls;
+use tracing::{Instrument, Span, warn};
+
+use crate::embedded_certificate;
+use crate::protocols::dns::{DnsResolver, alpn_protocols, serve_encrypted_dns};
+use crate::protocols::tls;
+use crate::tunnel::connectors::TunnelConnector;
+use crate::tunnel::{EncryptedDns, RemoteAddr};
+
+/// Size of the in memory pipe between the tunnel and the dns endpoint
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Connect the tunnel to a DNS-over-HTTPS/TLS endpoint running inside the client, instead of a remote destination
+pub struct EncryptedDnsConnector {
+    transport: EncryptedDns,
+    tls_acceptor: TlsAcceptor,
+    dns_resolver: DnsResolver,
+}
+
+impl EncryptedDnsConnector {
+    pub fn new(
+        transport: EncryptedDns,
+        tls_certificate: Option<&Path>,
+        tls_private_key: Option<&Path>,
+        dns_resolver: DnsResolver,
+    ) -> anyhow::Result<Self> {
+        let (certificates, private_key) = match (tls_certificate, tls_private_key) {
+            (Some(certificate), Some(private_key)) => (
+                tls::load_certificates_from_pem(certificate)?,
+                tls::load_private_key_from_file(private_key)?,
+            ),
+            _ => (
+                embedded_certificate::TLS_CERTIFICATE.0.clone(),
+                embedded_certificate::TLS_CERTIFICATE.1.clone_key(),
+            ),
+        };
+
+        let mut config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificates, private_key)?;
+        config.alpn_protocols = alpn_protocols(transport);
+
+        Ok(Self {
+            transport,
+            tls_acceptor: TlsAcceptor::from(Arc::new(config)),
+            dns_resolver,
+        })
+    }
+}
+
+impl TunnelConnector for EncryptedDnsConnector {
+    type Reader = ReadHalf<DuplexStream>;
+    type Writer = WriteHalf<DuplexStream>;
+
+    async fn connect(&self, _: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        let (local, remote) = tokio::io::duplex(PIPE_CAPACITY);
+        let transport = self.transport;
+        let tls_acceptor = self.tls_acceptor.clone();
+        let dns_resolver = self.dns_resolver.clone();
+        tokio::spawn(
+            async move {
+                if let Err(err) = serve_encrypted_dns(transport, tls_acceptor, dns_resolver, remote).await {
+                    warn!("{err:?}");
+                }
+            }
+            .instrument(Span::current()),
+        );
+
+        Ok(tokio::io::split(local))
+    }
+}
@@ -2,19 +2,26 @@ use anyhow::anyhow;
 use tokio::io::{AsyncRead, AsyncWrite};
 use url::Url;
 
+pub use encrypted_dns::EncryptedDnsConnector;
+#[cfg(target_os = "linux")]
+pub use sctp::SctpTunnelConnector;
 pub use sock5::Socks5TunnelConnector;
 pub use tcp::TcpTunnelConnector;
 pub use udp::UdpTunnelConnector;
 
 use crate::tunnel::RemoteAddr;
+use crate::tunnel::transport::io::Resettable;
 
+mod encrypted_dns;
+#[cfg(target_os = "linux")]
+mod sctp;
 mod sock5;
 mod tcp;
 mod udp;
 
 pub trait TunnelConnector {
     type Reader: AsyncRead + Send + 'static;
-    type Writer: AsyncWrite + Send + 'static;
+    type Writer: AsyncWrite + Resettable + Send + 'static;
 
     #[allow(async_fn_in_trait)]
     async fn connect(&self, remote: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)>;
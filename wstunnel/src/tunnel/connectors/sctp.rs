@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use url::Host;
+
+use crate::protocols;
+use crate::protocols::dns::DnsResolver;
+use crate::somark::SoMark;
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::connectors::TunnelConnector;
+
+pub struct SctpTunnelConnector<'a> {
+    host: &'a Host,
+    port: u16,
+    so_mark: SoMark,
+    connect_timeout: Duration,
+    dns_resolver: &'a DnsResolver,
+}
+
+impl<'a> SctpTunnelConnector<'a> {
+    pub fn new(
+        host: &'a Host,
+        port: u16,
+        so_mark: SoMark,
+        connect_timeout: Duration,
+        dns_resolver: &'a DnsResolver,
+    ) -> SctpTunnelConnector<'a> {
+        SctpTunnelConnector {
+            host,
+            port,
+            so_mark,
+            connect_timeout,
+            dns_resolver,
+        }
+    }
+}
+
+impl TunnelConnector for SctpTunnelConnector<'_> {
+    type Reader = OwnedReadHalf;
+    type Writer = OwnedWriteHalf;
+
+    async fn connect(&self, _: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        let stream =
+            protocols::sctp::connect(self.host, self.port, self.so_mark, self.connect_timeout, self.dns_resolver)
+                .await?;
+
+        Ok(stream.into_split())
+    }
+}
@@ -13,7 +13,9 @@ use crate::protocols::dns::DnsResolver;
 use crate::protocols::udp;
 use crate::protocols::udp::WsUdpSocket;
 use crate::somark::SoMark;
+use crate::source_bind::UNBOUND;
 use crate::tunnel::connectors::TunnelConnector;
+use crate::tunnel::transport::io::{LocalReset, Resettable};
 use crate::tunnel::{LocalProtocol, RemoteAddr};
 
 pub struct Socks5TunnelConnector<'a> {
@@ -42,11 +44,13 @@ impl TunnelConnector for Socks5TunnelConnector<'_> {
         };
 
         match remote.protocol {
-            LocalProtocol::Tcp { proxy_protocol: _ } => {
+            LocalProtocol::Tcp { .. } => {
                 let stream = protocols::tcp::connect(
                     &remote.host,
                     remote.port,
                     self.so_mark,
+                    &UNBOUND,
+                    false,
                     self.connect_timeout,
                     self.dns_resolver,
                 )
@@ -55,9 +59,15 @@ impl TunnelConnector for Socks5TunnelConnector<'_> {
                 Ok((Socks5Reader::Tcp(reader), Socks5Writer::Tcp(writer)))
             }
             LocalProtocol::Udp { .. } => {
-                let stream =
-                    udp::connect(&remote.host, remote.port, self.connect_timeout, self.so_mark, self.dns_resolver)
-                        .await?;
+                let stream = udp::connect(
+                    &remote.host,
+                    remote.port,
+                    self.connect_timeout,
+                    self.so_mark,
+                    &UNBOUND,
+                    self.dns_resolver,
+                )
+                .await?;
                 Ok((Socks5Reader::Udp(stream.clone()), Socks5Writer::Udp(stream)))
             }
             _ => Err(anyhow!("Invalid protocol for reverse socks5 {:?}", remote.protocol)),
@@ -74,12 +84,13 @@ impl TunnelConnector for Socks5TunnelConnector<'_> {
         };
 
         match remote.protocol {
-            LocalProtocol::Tcp { proxy_protocol: _ } => {
+            LocalProtocol::Tcp { .. } => {
                 let stream = protocols::tcp::connect_with_http_proxy(
                     proxy,
                     &remote.host,
                     remote.port,
                     self.so_mark,
+                    &UNBOUND,
                     self.connect_timeout,
                     self.dns_resolver,
                 )
@@ -111,6 +122,15 @@ pub enum Socks5Writer {
     Udp(WsUdpSocket),
 }
 
+impl Resettable for Socks5Writer {
+    fn local_reset(&self) -> LocalReset {
+        match self {
+            Socks5Writer::Tcp(writer) => writer.local_reset(),
+            Socks5Writer::Udp(_) => LocalReset::default(),
+        }
+    }
+}
+
 impl AsyncWrite for Socks5Writer {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
         match self.get_mut() {
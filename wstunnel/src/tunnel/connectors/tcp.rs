@@ -1,20 +1,26 @@
+use anyhow::Context;
+use socket2::SockRef;
 use std::time::Duration;
 
+use tokio::net::TcpStream;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use url::{Host, Url};
 
 use crate::protocols;
 use crate::protocols::dns::DnsResolver;
 use crate::somark::SoMark;
-use crate::tunnel::RemoteAddr;
+use crate::source_bind::{SourceBind, UNBOUND};
 use crate::tunnel::connectors::TunnelConnector;
+use crate::tunnel::{RemoteAddr, TunnelKeepalive};
 
 pub struct TcpTunnelConnector<'a> {
     host: &'a Host,
     port: u16,
     so_mark: SoMark,
+    source_bind: &'a SourceBind,
     connect_timeout: Duration,
     dns_resolver: &'a DnsResolver,
+    keepalive: Option<TunnelKeepalive>,
 }
 
 impl<'a> TcpTunnelConnector<'a> {
@@ -29,10 +35,33 @@ impl<'a> TcpTunnelConnector<'a> {
             host,
             port,
             so_mark,
+            source_bind: &UNBOUND,
             connect_timeout,
             dns_resolver,
+            keepalive: None,
         }
     }
+
+    /// Connect from this interface or source ip, instead of letting the routing table choose
+    pub fn with_source_bind(mut self, source_bind: &'a SourceBind) -> Self {
+        self.source_bind = source_bind;
+        self
+    }
+
+    /// Keepalive of the tunnel, instead of the default one of the connections
+    pub fn with_keepalive(mut self, keepalive: Option<TunnelKeepalive>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    fn set_keepalive(&self, stream: &TcpStream) -> anyhow::Result<()> {
+        if let Some(keepalive) = self.keepalive {
+            keepalive
+                .set_on(SockRef::from(stream))
+                .context("cannot set the keepalive of the tunnel")?;
+        }
+        Ok(())
+    }
 }
 
 impl TunnelConnector for TcpTunnelConnector<'_> {
@@ -45,7 +74,17 @@ impl TunnelConnector for TcpTunnelConnector<'_> {
             None => (self.host, self.port),
         };
 
-        let stream = protocols::tcp::connect(host, port, self.so_mark, self.connect_timeout, self.dns_resolver).await?;
+        let stream = protocols::tcp::connect(
+            host,
+            port,
+            self.so_mark,
+            self.source_bind,
+            false,
+            self.connect_timeout,
+            self.dns_resolver,
+        )
+        .await?;
+        self.set_keepalive(&stream)?;
         Ok(stream.into_split())
     }
 
@@ -64,10 +103,12 @@ impl TunnelConnector for TcpTunnelConnector<'_> {
             host,
             port,
             self.so_mark,
+            self.source_bind,
             self.connect_timeout,
             self.dns_resolver,
         )
         .await?;
+        self.set_keepalive(&stream)?;
         Ok(stream.into_split())
     }
 }
@@ -6,6 +6,7 @@ use crate::protocols;
 use crate::protocols::dns::DnsResolver;
 use crate::protocols::udp::WsUdpSocket;
 use crate::somark::SoMark;
+use crate::source_bind::{SourceBind, UNBOUND};
 use crate::tunnel::RemoteAddr;
 use crate::tunnel::connectors::TunnelConnector;
 
@@ -13,6 +14,7 @@ pub struct UdpTunnelConnector<'a> {
     host: &'a Host,
     port: u16,
     so_mark: SoMark,
+    source_bind: &'a SourceBind,
     connect_timeout: Duration,
     dns_resolver: &'a DnsResolver,
 }
@@ -29,10 +31,17 @@ impl<'a> UdpTunnelConnector<'a> {
             host,
             port,
             so_mark,
+            source_bind: &UNBOUND,
             connect_timeout,
             dns_resolver,
         }
     }
+
+    /// Connect from this interface or source ip, instead of letting the routing table choose
+    pub fn with_source_bind(mut self, source_bind: &'a SourceBind) -> Self {
+        self.source_bind = source_bind;
+        self
+    }
 }
 
 impl TunnelConnector for UdpTunnelConnector<'_> {
@@ -40,9 +49,15 @@ impl TunnelConnector for UdpTunnelConnector<'_> {
     type Writer = WsUdpSocket;
 
     async fn connect(&self, _: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)> {
-        let stream =
-            protocols::udp::connect(self.host, self.port, self.connect_timeout, self.so_mark, self.dns_resolver)
-                .await?;
+        let stream = protocols::udp::connect(
+            self.host,
+            self.port,
+            self.connect_timeout,
+            self.so_mark,
+            self.source_bind,
+            self.dns_resolver,
+        )
+        .await?;
 
         Ok((stream.clone(), stream))
     }
@@ -0,0 +1,213 @@
+//! Integrity check of the data of the tunnels, end to end between the client and the server, with
+//! --integrity-check-interval. A debug mode, to find out whether a proxy/middlebox in between corrupts the tunnels.
+//!
+//! Like noise, the local side of the tunnel is plugged to one end of an in-memory pipe, whose other end is carried by the
+//! transport. Each side checksums the data it sends and receives, and the sides compare their checksums every interval
+//! bytes, see [`crate::tunnel::protocol::integrity_record`]. On a mismatch the tunnel is aborted, and the transport
+//! fails with the [`IntegrityError`], so the peer is told the `corrupted` close reason when it has the error codes
+use crate::TokioExecutorRef;
+use crate::tunnel::protocol::integrity_record::{Checksum, IntegrityError, IntegrityRecord, MAX_DATA_LEN};
+use bytes::{Bytes, BytesMut};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+use tracing::{Instrument, Span, debug, error, warn};
+
+/// Lowest interval the checksums are compared at, so a tunnel does not send more checksums than data
+pub const MIN_INTERVAL: u64 = 4096;
+const PIPE_SIZE: usize = 2 * (MAX_DATA_LEN + 3);
+
+type TunnelRx = Pin<Box<dyn AsyncRead + Send>>;
+type TunnelTx = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// Check the integrity of the local side of a tunnel every `interval` bytes if it is set, the transport then carries the
+/// returned streams
+pub fn channel(
+    interval: Option<u64>,
+    executor: &impl TokioExecutorRef,
+    local_rx: impl AsyncRead + Send + 'static,
+    local_tx: impl AsyncWrite + Send + 'static,
+) -> (TunnelRx, TunnelTx) {
+    let Some(interval) = interval else {
+        return (Box::pin(local_rx), Box::pin(local_tx));
+    };
+
+    let (transport, local_side) = tokio::io::duplex(PIPE_SIZE);
+    let failure = Arc::new(OnceLock::new());
+    executor.spawn({
+        let failure = failure.clone();
+        async move {
+            let (mut transport_rx, mut transport_tx) = tokio::io::split(local_side);
+            let ret = run_channel(interval, local_rx, local_tx, &mut transport_rx, &mut transport_tx).await;
+            match ret.map_err(|err| err.downcast::<IntegrityError>()) {
+                Ok(()) => debug!("Integrity of the tunnel checked up to its end"),
+                Err(Ok(err)) => {
+                    error!("Aborting tunnel, {err}");
+                    let _ = failure.set(err);
+                }
+                Err(Err(err)) => warn!("Closing integrity checked tunnel: {err:#}"),
+            }
+            // The transport only sees the end of the pipe once the failure is set
+            drop((transport_rx, transport_tx));
+        }
+        .instrument(Span::current())
+    });
+
+    let (transport_rx, transport_tx) = tokio::io::split(transport);
+    (
+        Box::pin(TransportRx {
+            inner: transport_rx,
+            failure,
+        }),
+        Box::pin(transport_tx),
+    )
+}
+
+async fn run_channel(
+    interval: u64,
+    local_rx: impl AsyncRead,
+    local_tx: impl AsyncWrite,
+    transport_rx: &mut ReadHalf<DuplexStream>,
+    transport_tx: &mut WriteHalf<DuplexStream>,
+) -> anyhow::Result<()> {
+    let send = async {
+        tokio::pin!(local_rx);
+        let mut checksum = Checksum::new();
+        let mut checked_at = 0;
+        let mut data = vec![0; MAX_DATA_LEN];
+        let mut records = BytesMut::with_capacity(PIPE_SIZE);
+        loop {
+            let read = local_rx.read(&mut data).await?;
+            checksum.update(&data[..read]);
+            if read > 0 {
+                IntegrityRecord::Data(Bytes::copy_from_slice(&data[..read])).encode(&mut records);
+            }
+            // The last checksum covers the whole stream, so a tunnel shorter than the interval is checked too
+            if read == 0 || checksum.offset() - checked_at >= interval {
+                IntegrityRecord::Checksum {
+                    offset: checksum.offset(),
+                    checksum: checksum.value(),
+                }
+                .encode(&mut records);
+                checked_at = checksum.offset();
+            }
+            transport_tx.write_all(&records.split()).await?;
+            if read == 0 {
+                transport_tx.shutdown().await?;
+                break;
+            }
+        }
+        anyhow::Ok(())
+    };
+
+    let receive = async {
+        tokio::pin!(local_tx);
+        let mut checksum = Checksum::new();
+        let mut records = BytesMut::with_capacity(PIPE_SIZE);
+        loop {
+            while let Some(record) = IntegrityRecord::decode(&mut records)? {
+                match record {
+                    IntegrityRecord::Data(data) => {
+                        checksum.update(&data);
+                        local_tx.write_all(&data).await?;
+                    }
+                    IntegrityRecord::Checksum {
+                        offset,
+                        checksum: expected,
+                    } => checksum.verify(offset, expected)?,
+                }
+            }
+            local_tx.flush().await?;
+
+            if transport_rx.read_buf(&mut records).await? == 0 {
+                local_tx.shutdown().await?;
+                break;
+            }
+        }
+        anyhow::Ok(())
+    };
+
+    tokio::try_join!(send, receive)?;
+    Ok(())
+}
+
+/// Side of the pipe read by the transport, failing with the integrity error of the tunnel instead of ending cleanly
+struct TransportRx {
+    inner: ReadHalf<DuplexStream>,
+    failure: Arc<OnceLock<IntegrityError>>,
+}
+
+impl AsyncRead for TransportRx {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let ret = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = ret
+            && buf.filled().len() == filled
+            && let Some(err) = self.failure.get()
+        {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, *err)));
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::DefaultTokioExecutor;
+    use crate::tunnel::protocol::close_reason::CloseReason;
+
+    #[tokio::test]
+    async fn test_integrity_channel() {
+        let executor = DefaultTokioExecutor::default();
+        let (mut app, client_local) = tokio::io::duplex(1024);
+        let (mut destination, server_local) = tokio::io::duplex(1024);
+        let (client_local_rx, client_local_tx) = tokio::io::split(client_local);
+        let (server_local_rx, server_local_tx) = tokio::io::split(server_local);
+        let client = channel(Some(MIN_INTERVAL), &executor, client_local_rx, client_local_tx);
+        let server = channel(Some(MIN_INTERVAL), &executor, server_local_rx, server_local_tx);
+        let mut client_transport = tokio::io::join(client.0, client.1);
+        let mut server_transport = tokio::io::join(server.0, server.1);
+        tokio::spawn(async move {
+            let _ = tokio::io::copy_bidirectional(&mut client_transport, &mut server_transport).await;
+        });
+
+        let data: Vec<u8> = (0..3 * MIN_INTERVAL).map(|i| i as u8).collect();
+        let sent = data.clone();
+        tokio::spawn(async move {
+            app.write_all(&sent).await.unwrap();
+            app.shutdown().await.unwrap();
+        });
+        let mut received = vec![];
+        destination.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn test_integrity_channel_detects_corruption() {
+        let executor = DefaultTokioExecutor::default();
+        let (_app, client_local) = tokio::io::duplex(1024);
+        let (client_local_rx, client_local_tx) = tokio::io::split(client_local);
+        let (mut transport_rx, mut transport_tx) =
+            channel(Some(MIN_INTERVAL), &executor, client_local_rx, client_local_tx);
+
+        // The server sent "hello", a middlebox turned it into "hellO"
+        let mut checksum = Checksum::new();
+        checksum.update(b"hello");
+        let mut records = BytesMut::new();
+        IntegrityRecord::Data(Bytes::from_static(b"hellO")).encode(&mut records);
+        IntegrityRecord::Checksum {
+            offset: 5,
+            checksum: checksum.value(),
+        }
+        .encode(&mut records);
+        transport_tx.write_all(&records).await.unwrap();
+
+        let mut buf = vec![];
+        let err = transport_rx.read_to_end(&mut buf).await.unwrap_err();
+        assert!(err.get_ref().unwrap().is::<IntegrityError>());
+        assert_eq!(CloseReason::of_io_error(&err), CloseReason::Corrupted);
+    }
+}
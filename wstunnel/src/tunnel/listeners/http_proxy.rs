@@ -1,49 +1,69 @@
 use crate::protocols::http_proxy;
 use crate::protocols::http_proxy::HttpProxyListener;
-use crate::tunnel::{LocalProtocol, RemoteAddr};
+use crate::tunnel::{AccessList, LocalProtocol, RemoteAddr, TunnelResume};
 use anyhow::{Context, anyhow};
+use bytes::Bytes;
+use std::io::Cursor;
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::pin::Pin;
 use std::task::{Poll, ready};
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, Chain};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio_stream::Stream;
 
 pub struct HttpProxyTunnelListener {
     listener: HttpProxyListener,
     proxy_protocol: bool,
+    resume: Option<TunnelResume>,
 }
 
 impl HttpProxyTunnelListener {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         bind_addr: SocketAddr,
+        v6only: Option<bool>,
         timeout: Option<Duration>,
         credentials: Option<(String, String)>,
         proxy_protocol: bool,
+        resume: Option<TunnelResume>,
+        connect_ports: Vec<RangeInclusive<u16>>,
+        path_headers: bool,
+        access: AccessList,
     ) -> anyhow::Result<Self> {
-        let listener = http_proxy::run_server(bind_addr, timeout, credentials)
-            .await
-            .with_context(|| anyhow!("Cannot start http proxy server on {bind_addr}"))?;
+        let listener =
+            http_proxy::run_server(bind_addr, v6only, timeout, credentials, connect_ports, path_headers, access)
+                .await
+                .with_context(|| anyhow!("Cannot start http proxy server on {bind_addr}"))?;
 
         Ok(Self {
             listener,
             proxy_protocol,
+            resume,
         })
     }
 }
 
 impl Stream for HttpProxyTunnelListener {
-    type Item = anyhow::Result<((OwnedReadHalf, OwnedWriteHalf), RemoteAddr)>;
+    type Item = anyhow::Result<((Chain<Cursor<Bytes>, OwnedReadHalf>, OwnedWriteHalf), RemoteAddr)>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
         let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
         let ret = match ret {
-            Some(Ok((stream, (host, port)))) => {
+            Some(Ok((stream, head, (host, port)))) => {
                 let protocol = LocalProtocol::Tcp {
                     proxy_protocol: this.proxy_protocol,
+                    resume: this.resume,
+                    idle_timeout: None,
+                    mirror: None,
+                    balancing: None,
+                    keepalive: None,
                 };
-                Some(anyhow::Ok((stream.into_split(), RemoteAddr { protocol, host, port })))
+                let (rx, tx) = stream.into_split();
+                let rx = Cursor::new(head).chain(rx);
+                Some(anyhow::Ok(((rx, tx), RemoteAddr { protocol, host, port })))
             }
             Some(Err(err)) => Some(Err(err)),
             None => None,
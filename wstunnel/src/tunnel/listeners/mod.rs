@@ -1,3 +1,5 @@
+#[cfg(target_os = "linux")]
+mod sctp;
 mod tcp;
 #[cfg(target_os = "linux")]
 mod tproxy;
@@ -8,35 +10,43 @@ mod stdio;
 mod udp;
 #[cfg(unix)]
 mod unix_sock;
+#[cfg(all(target_os = "linux", feature = "vsock"))]
+mod vsock;
 
 #[cfg(target_os = "linux")]
 pub use tproxy::TproxyTcpTunnelListener;
 #[cfg(target_os = "linux")]
 pub use tproxy::new_tproxy_udp;
 
+#[cfg(target_os = "linux")]
+pub use sctp::SctpTunnelListener;
+
 pub use http_proxy::HttpProxyTunnelListener;
 pub use socks5::Socks5TunnelListener;
-pub use stdio::new_stdio_listener;
+pub use stdio::{new_stdio_listener, new_stdio_udp_listener};
 pub use tcp::TcpTunnelListener;
 pub use udp::UdpTunnelListener;
 
 #[cfg(unix)]
 pub use unix_sock::UnixTunnelListener;
+#[cfg(all(target_os = "linux", feature = "vsock"))]
+pub use vsock::VsockTunnelListener;
 
 use crate::tunnel::RemoteAddr;
+use crate::tunnel::transport::io::Resettable;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_stream::Stream;
 
 pub trait TunnelListener: Stream<Item = anyhow::Result<((Self::Reader, Self::Writer), RemoteAddr)>> {
     type Reader: AsyncRead + Send + 'static;
-    type Writer: AsyncWrite + Send + 'static;
+    type Writer: AsyncWrite + Resettable + Send + 'static;
 }
 
 impl<T, R, W> TunnelListener for T
 where
     T: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
     R: AsyncRead + Send + 'static,
-    W: AsyncWrite + Send + 'static,
+    W: AsyncWrite + Resettable + Send + 'static,
 {
     type Reader = R;
     type Writer = W;
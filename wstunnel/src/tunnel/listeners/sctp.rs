@@ -0,0 +1,50 @@
+use crate::protocols;
+use crate::tunnel::{LocalProtocol, RemoteAddr};
+use anyhow::{Context, anyhow};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Poll, ready};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio_stream::Stream;
+use tokio_stream::wrappers::TcpListenerStream;
+use url::Host;
+
+pub struct SctpTunnelListener {
+    listener: TcpListenerStream,
+    dest: (Host, u16),
+}
+
+impl SctpTunnelListener {
+    pub async fn new(bind_addr: SocketAddr, dest: (Host, u16)) -> anyhow::Result<Self> {
+        let listener = protocols::sctp::run_server(bind_addr)
+            .await
+            .with_context(|| anyhow!("Cannot start SCTP server on {bind_addr}"))?;
+
+        Ok(Self { listener, dest })
+    }
+}
+
+impl Stream for SctpTunnelListener {
+    type Item = anyhow::Result<((OwnedReadHalf, OwnedWriteHalf), RemoteAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
+        let ret = match ret {
+            Some(Ok(stream)) => {
+                let (host, port) = this.dest.clone();
+                Some(anyhow::Ok((
+                    stream.into_split(),
+                    RemoteAddr {
+                        protocol: LocalProtocol::Sctp,
+                        host,
+                        port,
+                    },
+                )))
+            }
+            Some(Err(err)) => Some(Err(anyhow::Error::new(err))),
+            None => None,
+        };
+        Poll::Ready(ret)
+    }
+}
@@ -1,6 +1,6 @@
 use crate::protocols::socks5;
-use crate::protocols::socks5::{Socks5Listener, Socks5ReadHalf, Socks5WriteHalf};
-use crate::tunnel::RemoteAddr;
+use crate::protocols::socks5::{Socks5Listener, Socks5ReadHalf, Socks5Resolver, Socks5WriteHalf};
+use crate::tunnel::{AccessList, LocalProtocol, RemoteAddr, TunnelResume};
 use anyhow::{Context, anyhow};
 use std::net::SocketAddr;
 use std::pin::Pin;
@@ -10,6 +10,7 @@ use tokio_stream::Stream;
 
 pub struct Socks5TunnelListener {
     listener: Socks5Listener,
+    resume: Option<TunnelResume>,
 }
 
 impl Socks5TunnelListener {
@@ -17,12 +18,15 @@ impl Socks5TunnelListener {
         bind_addr: SocketAddr,
         timeout: Option<Duration>,
         credentials: Option<(String, String)>,
+        resume: Option<TunnelResume>,
+        resolver: Socks5Resolver,
+        access: AccessList,
     ) -> anyhow::Result<Self> {
-        let listener = socks5::run_server(bind_addr, timeout, credentials)
+        let listener = socks5::run_server(bind_addr, timeout, credentials, resolver, access)
             .await
             .with_context(|| anyhow!("Cannot start Socks5 server on {bind_addr}"))?;
 
-        Ok(Self { listener })
+        Ok(Self { listener, resume })
     }
 }
 
@@ -34,7 +38,17 @@ impl Stream for Socks5TunnelListener {
         let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
         let ret = match ret {
             Some(Ok((stream, (host, port)))) => {
-                let protocol = stream.local_protocol();
+                let protocol = match stream.local_protocol() {
+                    LocalProtocol::Tcp { proxy_protocol, .. } => LocalProtocol::Tcp {
+                        proxy_protocol,
+                        resume: this.resume,
+                        idle_timeout: None,
+                        mirror: None,
+                        balancing: None,
+                        keepalive: None,
+                    },
+                    protocol => protocol,
+                };
                 Some(anyhow::Ok((stream.into_split(), RemoteAddr { protocol, host, port })))
             }
             Some(Err(err)) => Some(Err(err)),
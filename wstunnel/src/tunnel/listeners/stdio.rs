@@ -1,8 +1,11 @@
 use crate::protocols::stdio;
+use crate::protocols::stdio::{LengthPrefixedReader, LengthPrefixedWriter};
+use crate::tunnel::transport::io::Resettable;
 use crate::tunnel::{LocalProtocol, RemoteAddr};
 use anyhow::{Context, anyhow};
 use std::pin::Pin;
 use std::task::Poll;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::oneshot;
 use tokio_stream::Stream;
@@ -15,14 +18,14 @@ where
 {
     listener: Option<(R, W)>,
     dest: (Host, u16),
-    proxy_protocol: bool,
+    protocol: LocalProtocol,
 }
 
 pub async fn new_stdio_listener(
     dest: (Host, u16),
     proxy_protocol: bool,
 ) -> anyhow::Result<(
-    StdioTunnelListener<impl AsyncRead + Send, impl AsyncWrite + Send>,
+    StdioTunnelListener<impl AsyncRead + Send, impl AsyncWrite + Resettable + Send>,
     oneshot::Sender<()>,
 )> {
     let (listener, handle) = stdio::run_server()
@@ -31,8 +34,37 @@ pub async fn new_stdio_listener(
     Ok((
         StdioTunnelListener {
             listener: Some(listener),
-            proxy_protocol,
             dest,
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
+        },
+        handle,
+    ))
+}
+
+/// Forward datagrams read from stdio, each one prefixed by its length (u16 big endian), to an udp destination.
+/// Received datagrams are written to stdout with the same framing
+pub async fn new_stdio_udp_listener(
+    dest: (Host, u16),
+    timeout: Option<Duration>,
+) -> anyhow::Result<(
+    StdioTunnelListener<impl AsyncRead + Send, impl AsyncWrite + Resettable + Send>,
+    oneshot::Sender<()>,
+)> {
+    let ((reader, writer), handle) = stdio::run_server()
+        .await
+        .with_context(|| anyhow!("Cannot start STDIO server"))?;
+    Ok((
+        StdioTunnelListener {
+            listener: Some((LengthPrefixedReader::new(reader), LengthPrefixedWriter::new(writer))),
+            dest,
+            protocol: LocalProtocol::Udp { timeout },
         },
         handle,
     ))
@@ -54,9 +86,7 @@ where
                 Some(Ok((
                     stream,
                     RemoteAddr {
-                        protocol: LocalProtocol::Tcp {
-                            proxy_protocol: this.proxy_protocol,
-                        },
+                        protocol: this.protocol.clone(),
                         host,
                         port,
                     },
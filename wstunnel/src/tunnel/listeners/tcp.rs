@@ -1,9 +1,16 @@
 use crate::protocols;
-use crate::tunnel::{LocalProtocol, RemoteAddr};
+use crate::somark::SoMark;
+use crate::tunnel::{
+    AccessList, LoadBalancing, LoadBalancingStrategy, LocalProtocol, RemoteAddr, TunnelKeepalive, TunnelResume,
+};
 use anyhow::{Context, anyhow};
+use log::warn;
+use rand::Rng;
+use socket2::SockRef;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Poll, ready};
+use std::time::Duration;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio_stream::Stream;
 use tokio_stream::wrappers::TcpListenerStream;
@@ -13,11 +20,30 @@ pub struct TcpTunnelListener {
     listener: TcpListenerStream,
     dest: (Host, u16),
     proxy_protocol: bool,
+    resume: Option<TunnelResume>,
+    idle_timeout: Option<Duration>,
+    mirror: Option<(Host, u16)>,
+    balancing: Option<LoadBalancing>,
+    keepalive: Option<TunnelKeepalive>,
+    access: AccessList,
+    next_dest: usize,
 }
 
 impl TcpTunnelListener {
-    pub async fn new(bind_addr: SocketAddr, dest: (Host, u16), proxy_protocol: bool) -> anyhow::Result<Self> {
-        let listener = protocols::tcp::run_server(bind_addr, false)
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        bind_addr: SocketAddr,
+        v6only: Option<bool>,
+        dest: (Host, u16),
+        proxy_protocol: bool,
+        resume: Option<TunnelResume>,
+        idle_timeout: Option<Duration>,
+        mirror: Option<(Host, u16)>,
+        balancing: Option<LoadBalancing>,
+        keepalive: Option<TunnelKeepalive>,
+        access: AccessList,
+    ) -> anyhow::Result<Self> {
+        let listener = protocols::tcp::run_server(bind_addr, false, v6only)
             .await
             .with_context(|| anyhow!("Cannot start TCP server on {bind_addr}"))?;
 
@@ -25,24 +51,98 @@ impl TcpTunnelListener {
             listener,
             dest,
             proxy_protocol,
+            resume,
+            idle_timeout,
+            mirror,
+            balancing,
+            keepalive,
+            access,
+            next_dest: 0,
         })
     }
 }
 
+impl TcpTunnelListener {
+    /// Address the listener is bound to, useful to know which port was picked when binding on port 0
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.as_ref().local_addr()
+    }
+
+    fn accept_peer(&self, stream: &tokio::net::TcpStream) -> bool {
+        if self.access.is_empty() {
+            return true;
+        }
+
+        match stream.peer_addr() {
+            Ok(peer) if self.access.accepts(peer.ip()) => true,
+            peer => {
+                warn!("Rejecting tcp cnx of {peer:?}, it is not allowed by the access list of the listener");
+                false
+            }
+        }
+    }
+
+    /// Destination of the next connection, with the other destinations of the tunnel as its fallbacks
+    fn next_dest(&mut self) -> ((Host, u16), Option<LoadBalancing>) {
+        let Some(balancing) = &self.balancing else {
+            return (self.dest.clone(), None);
+        };
+
+        let mut dests: Vec<(Host, u16)> = std::iter::once(self.dest.clone())
+            .chain(balancing.fallbacks.iter().cloned())
+            .collect();
+        let first = match balancing.strategy {
+            LoadBalancingStrategy::RoundRobin => {
+                let first = self.next_dest % dests.len();
+                self.next_dest = self.next_dest.wrapping_add(1);
+                first
+            }
+            LoadBalancingStrategy::Random => rand::rng().random_range(0..dests.len()),
+        };
+        dests.rotate_left(first);
+        let dest = dests.remove(0);
+
+        (
+            dest,
+            Some(LoadBalancing {
+                fallbacks: dests,
+                ..balancing.clone()
+            }),
+        )
+    }
+}
+
 impl Stream for TcpTunnelListener {
     type Item = anyhow::Result<((OwnedReadHalf, OwnedWriteHalf), RemoteAddr)>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
-        let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
+        let ret = loop {
+            match ready!(Pin::new(&mut this.listener).poll_next(cx)) {
+                Some(Ok(stream)) if !this.accept_peer(&stream) => continue,
+                ret => break ret,
+            }
+        };
         let ret = match ret {
             Some(Ok(strean)) => {
-                let (host, port) = this.dest.clone();
+                // Detect peers that vanished without closing the connection (killed laptop, NAT expiry)
+                let _ = protocols::tcp::configure_socket(SockRef::from(&strean), SoMark::new(None));
+                if let Some(keepalive) = this.keepalive
+                    && let Err(err) = keepalive.set_on(SockRef::from(&strean))
+                {
+                    warn!("Cannot set the keepalive of the tunnel on the tcp cnx: {err}");
+                }
+                let ((host, port), balancing) = this.next_dest();
                 Some(anyhow::Ok((
                     strean.into_split(),
                     RemoteAddr {
                         protocol: LocalProtocol::Tcp {
                             proxy_protocol: this.proxy_protocol,
+                            resume: this.resume,
+                            idle_timeout: this.idle_timeout,
+                            mirror: this.mirror.clone(),
+                            balancing,
+                            keepalive: this.keepalive,
                         },
                         host,
                         port,
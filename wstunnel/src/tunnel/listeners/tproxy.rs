@@ -1,7 +1,7 @@
 use crate::protocols;
 use crate::protocols::udp;
 use crate::protocols::udp::{UdpStream, UdpStreamWriter};
-use crate::tunnel::{LocalProtocol, RemoteAddr, to_host_port};
+use crate::tunnel::{LocalProtocol, RemoteAddr, UdpFlowEviction, to_host_port};
 use anyhow::{Context, anyhow};
 use std::io;
 use std::net::SocketAddr;
@@ -19,7 +19,7 @@ pub struct TproxyTcpTunnelListener {
 
 impl TproxyTcpTunnelListener {
     pub async fn new(bind_addr: SocketAddr, proxy_protocol: bool) -> anyhow::Result<Self> {
-        let listener = protocols::tcp::run_server(bind_addr, true)
+        let listener = protocols::tcp::run_server(bind_addr, true, None)
             .await
             .with_context(|| anyhow!("Cannot start TProxy TCP server on {bind_addr}"))?;
 
@@ -44,6 +44,11 @@ impl Stream for TproxyTcpTunnelListener {
                     RemoteAddr {
                         protocol: LocalProtocol::Tcp {
                             proxy_protocol: this.proxy_protocol,
+                            resume: None,
+                            idle_timeout: None,
+                            mirror: None,
+                            balancing: None,
+                            keepalive: None,
                         },
                         host,
                         port,
@@ -70,9 +75,17 @@ pub async fn new_tproxy_udp(
     bind_addr: SocketAddr,
     timeout: Option<Duration>,
 ) -> anyhow::Result<TProxyUdpTunnelListener<impl Stream<Item = io::Result<UdpStream>>>> {
-    let listener = udp::run_server(bind_addr, timeout, udp::configure_tproxy, udp::mk_send_socket_tproxy)
-        .await
-        .with_context(|| anyhow!("Cannot start TProxy UDP server on {bind_addr}"))?;
+    let listener = udp::run_server(
+        bind_addr,
+        None,
+        timeout,
+        None,
+        UdpFlowEviction::DropNew,
+        udp::configure_tproxy,
+        udp::mk_send_socket_tproxy,
+    )
+    .await
+    .with_context(|| anyhow!("Cannot start TProxy UDP server on {bind_addr}"))?;
 
     Ok(TProxyUdpTunnelListener { listener, timeout })
 }
@@ -1,6 +1,6 @@
 use crate::protocols::udp;
 use crate::protocols::udp::{UdpStream, UdpStreamWriter};
-use crate::tunnel::{LocalProtocol, RemoteAddr};
+use crate::tunnel::{LocalProtocol, RemoteAddr, UdpFlowEviction};
 use anyhow::{Context, anyhow};
 use std::io;
 use std::net::SocketAddr;
@@ -19,12 +19,23 @@ pub struct UdpTunnelListener {
 impl UdpTunnelListener {
     pub async fn new(
         bind_addr: SocketAddr,
+        v6only: Option<bool>,
         dest: (Host, u16),
         timeout: Option<Duration>,
+        max_flows: Option<usize>,
+        flow_eviction: UdpFlowEviction,
     ) -> anyhow::Result<UdpTunnelListener> {
-        let listener = udp::run_server(bind_addr, timeout, |_| Ok(()), |s| Ok(s.clone()))
-            .await
-            .with_context(|| anyhow!("Cannot start UDP server on {bind_addr}"))?;
+        let listener = udp::run_server(
+            bind_addr,
+            v6only,
+            timeout,
+            max_flows,
+            flow_eviction,
+            |_| Ok(()),
+            |s| Ok(s.clone()),
+        )
+        .await
+        .with_context(|| anyhow!("Cannot start UDP server on {bind_addr}"))?;
 
         Ok(UdpTunnelListener {
             listener: Box::pin(listener),
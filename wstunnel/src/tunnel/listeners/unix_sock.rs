@@ -1,23 +1,32 @@
 use crate::protocols::unix_sock;
 use crate::protocols::unix_sock::UnixListenerStream;
-use crate::tunnel::{LocalProtocol, RemoteAddr};
+use crate::tunnel::{LocalProtocol, RemoteAddr, UnixSocketLifecycle, UnixSocketPermissions};
 use anyhow::{Context, anyhow};
 use std::path::Path;
 use std::pin::Pin;
 use std::task::{Poll, ready};
-use tokio::net::unix;
+use tokio::net::{UnixStream, unix};
 use tokio_stream::Stream;
+use tracing::{info, warn};
 use url::Host;
 
 pub struct UnixTunnelListener {
     listener: UnixListenerStream,
     dest: (Host, u16),
     proxy_protocol: bool,
+    allowed_uids: Vec<u32>,
 }
 
 impl UnixTunnelListener {
-    pub async fn new(path: &Path, dest: (Host, u16), proxy_protocol: bool) -> anyhow::Result<Self> {
-        let listener = unix_sock::run_server(path)
+    pub async fn new(
+        path: &Path,
+        dest: (Host, u16),
+        proxy_protocol: bool,
+        allowed_uids: Vec<u32>,
+        permissions: &UnixSocketPermissions,
+        lifecycle: UnixSocketLifecycle,
+    ) -> anyhow::Result<Self> {
+        let listener = unix_sock::run_server(path, permissions, lifecycle)
             .await
             .with_context(|| anyhow!("Cannot start Unix domain server on {}", path.display()))?;
 
@@ -25,15 +34,43 @@ impl UnixTunnelListener {
             listener,
             dest,
             proxy_protocol,
+            allowed_uids,
         })
     }
+
+    /// Log who connected, and tell whether its user is allowed to use the socket
+    fn accept_peer(&self, stream: &UnixStream) -> bool {
+        let cred = match stream.peer_cred() {
+            Ok(cred) => cred,
+            Err(err) => {
+                warn!("Cannot get the credentials of the unix socket peer: {err}");
+                return self.allowed_uids.is_empty();
+            }
+        };
+        let pid = cred.pid().map_or_else(|| "unknown".to_string(), |pid| pid.to_string());
+        if !self.allowed_uids.is_empty() && !self.allowed_uids.contains(&cred.uid()) {
+            warn!(
+                "Rejecting unix socket cnx of uid={} gid={} pid={pid}, the uid is not allowed",
+                cred.uid(),
+                cred.gid()
+            );
+            return false;
+        }
+        info!("Accepted unix socket cnx of uid={} gid={} pid={pid}", cred.uid(), cred.gid());
+        true
+    }
 }
 impl Stream for UnixTunnelListener {
     type Item = anyhow::Result<((unix::OwnedReadHalf, unix::OwnedWriteHalf), RemoteAddr)>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
-        let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
+        let ret = loop {
+            match ready!(Pin::new(&mut this.listener).poll_next(cx)) {
+                Some(Ok(stream)) if !this.accept_peer(&stream) => continue,
+                ret => break ret,
+            }
+        };
         let ret = match ret {
             Some(Ok(stream)) => {
                 let stream = stream.into_split();
@@ -43,6 +80,11 @@ impl Stream for UnixTunnelListener {
                     RemoteAddr {
                         protocol: LocalProtocol::Tcp {
                             proxy_protocol: this.proxy_protocol,
+                            resume: None,
+                            idle_timeout: None,
+                            mirror: None,
+                            balancing: None,
+                            keepalive: None,
                         },
                         host,
                         port,
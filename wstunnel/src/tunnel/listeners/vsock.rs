@@ -0,0 +1,56 @@
+use crate::protocols::vsock;
+use crate::protocols::vsock::{VsockListenerStream, VsockStream};
+use crate::tunnel::{LocalProtocol, RemoteAddr};
+use anyhow::{Context, anyhow};
+use std::pin::Pin;
+use std::task::{Poll, ready};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio_stream::Stream;
+use url::Host;
+
+pub struct VsockTunnelListener {
+    listener: VsockListenerStream,
+    dest: (Host, u16),
+}
+
+impl VsockTunnelListener {
+    pub fn new(cid: u32, port: u32, dest: (Host, u16)) -> anyhow::Result<Self> {
+        let listener =
+            vsock::run_server(cid, port).with_context(|| anyhow!("Cannot start vsock server on {cid}:{port}"))?;
+
+        Ok(Self { listener, dest })
+    }
+}
+
+impl Stream for VsockTunnelListener {
+    type Item = anyhow::Result<((ReadHalf<VsockStream>, WriteHalf<VsockStream>), RemoteAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
+        let ret = match ret {
+            Some(Ok(stream)) => {
+                let stream = tokio::io::split(stream);
+                let (host, port) = this.dest.clone();
+                Some(anyhow::Ok((
+                    stream,
+                    RemoteAddr {
+                        protocol: LocalProtocol::Tcp {
+                            proxy_protocol: false,
+                            resume: None,
+                            idle_timeout: None,
+                            mirror: None,
+                            balancing: None,
+                            keepalive: None,
+                        },
+                        host,
+                        port,
+                    },
+                )))
+            }
+            Some(Err(err)) => Some(Err(anyhow::Error::new(err))),
+            None => None,
+        };
+        Poll::Ready(ret)
+    }
+}
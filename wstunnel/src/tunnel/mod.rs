@@ -1,21 +1,48 @@
 pub mod client;
 pub mod connectors;
+mod integrity;
 pub mod listeners;
+mod mux;
+pub mod noise;
+pub mod pcap;
+pub mod protocol;
+mod resume;
 pub mod server;
 mod tls_reloader;
 pub mod transport;
 
+use crate::protocols;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ipnet::IpNet;
+use jsonwebtoken::{Algorithm, EncodingKey};
 use serde::{Deserialize, Serialize};
+use socket2::SockRef;
 use std::fmt::Debug;
 use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::time::Duration;
-use url::Host;
+use url::{Host, Url};
+use uuid::Uuid;
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum LocalProtocol {
     Tcp {
         proxy_protocol: bool,
+        #[serde(default)]
+        resume: Option<TunnelResume>,
+        #[serde(default)]
+        idle_timeout: Option<Duration>,
+        /// Secondary destination receiving a copy of the bytes sent to the destination, its responses are ignored
+        #[serde(default)]
+        mirror: Option<(Host, u16)>,
+        /// Other destinations the connections of the tunnel are spread over
+        #[serde(default)]
+        balancing: Option<LoadBalancing>,
+        /// Keepalive of the connections accepted by the client and of the ones made by the server to the destination
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keepalive: Option<TunnelKeepalive>,
     },
     Udp {
         timeout: Option<Duration>,
@@ -23,9 +50,16 @@ pub enum LocalProtocol {
     Stdio {
         proxy_protocol: bool,
     },
+    StdioUdp {
+        timeout: Option<Duration>,
+    },
     Socks5 {
         timeout: Option<Duration>,
         credentials: Option<(String, String)>,
+        #[serde(default)]
+        resume: Option<TunnelResume>,
+        #[serde(default)]
+        resolve: Socks5Resolve,
     },
     TProxyTcp,
     TProxyUdp {
@@ -35,45 +69,281 @@ pub enum LocalProtocol {
         timeout: Option<Duration>,
         credentials: Option<(String, String)>,
         proxy_protocol: bool,
+        #[serde(default)]
+        resume: Option<TunnelResume>,
+        /// Ports the CONNECT requests may reach, all of them when empty
+        #[serde(default)]
+        connect_ports: Vec<RangeInclusive<u16>>,
+        /// Answer the CONNECT requests with the X-Wstunnel-RTT and Via headers, to diagnose a slow path
+        #[serde(default)]
+        path_headers: bool,
+    },
+    ReverseTcp {
+        #[serde(default)]
+        resume: Option<TunnelResume>,
+        #[serde(default)]
+        idle_timeout: Option<Duration>,
+        /// Listen on ipv6 only, or on both ipv4 and ipv6, instead of the default of the OS of the server
+        #[serde(default)]
+        v6only: Option<bool>,
+        #[serde(default, skip_serializing_if = "AccessList::is_empty")]
+        access: AccessList,
+        /// Keepalive of the connections accepted by the server and of the ones made by the client to the destination
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keepalive: Option<TunnelKeepalive>,
     },
-    ReverseTcp,
     ReverseUdp {
         timeout: Option<Duration>,
+        #[serde(default)]
+        max_flows: Option<usize>,
+        #[serde(default)]
+        flow_eviction: UdpFlowEviction,
+        /// Listen on ipv6 only, or on both ipv4 and ipv6, instead of the default of the OS of the server
+        #[serde(default)]
+        v6only: Option<bool>,
     },
     ReverseSocks5 {
         timeout: Option<Duration>,
         credentials: Option<(String, String)>,
+        #[serde(default, skip_serializing_if = "AccessList::is_empty")]
+        access: AccessList,
     },
     ReverseHttpProxy {
         timeout: Option<Duration>,
         credentials: Option<(String, String)>,
+        /// Listen on ipv6 only, or on both ipv4 and ipv6, instead of the default of the OS of the server
+        #[serde(default)]
+        v6only: Option<bool>,
+        #[serde(default, skip_serializing_if = "AccessList::is_empty")]
+        access: AccessList,
     },
     ReverseUnix {
         path: PathBuf,
+        /// Only accept the connections of the local processes run by these users. Empty to accept them all
+        #[serde(default)]
+        allowed_uids: Vec<u32>,
+        #[serde(default)]
+        permissions: UnixSocketPermissions,
+        #[serde(default)]
+        lifecycle: UnixSocketLifecycle,
+    },
+    /// DNS-over-HTTPS or DNS-over-TLS endpoint served by the client with its resolvers. The server listens for it like
+    /// for a reverse tcp tunnel
+    ReverseEncryptedDns {
+        transport: EncryptedDns,
+        /// Certificate of the endpoint, a self-signed one when missing
+        tls_certificate: Option<PathBuf>,
+        tls_private_key: Option<PathBuf>,
+    },
+    /// Http requests received by the server for this virtual host, forwarded to the local web app of the client
+    ReverseHttpIngress {
+        vhost: String,
+        /// Secret of the client owning the vhost, when it is a subdomain allocated under the ingress domain of the server
+        #[serde(default)]
+        session: Option<Uuid>,
+        /// Authentication the server requires from the visitors before forwarding their requests
+        #[serde(default)]
+        auth: Option<HttpIngressAuth>,
     },
     Unix {
         path: PathBuf,
         proxy_protocol: bool,
+        /// Only accept the connections of the local processes run by these users. Empty to accept them all
+        #[serde(default)]
+        allowed_uids: Vec<u32>,
+        #[serde(default)]
+        permissions: UnixSocketPermissions,
+        #[serde(default)]
+        lifecycle: UnixSocketLifecycle,
+    },
+    Vsock {
+        cid: u32,
+        port: u32,
     },
+    /// SCTP association, carried as a byte stream through the tunnel
+    Sctp,
+    /// Connection carrying the tunnels of a client multiplexed with --mux, each one opened with its own tunnel config
+    Mux,
 }
 
 impl LocalProtocol {
     pub const fn is_reverse_tunnel(&self) -> bool {
         matches!(
             self,
-            Self::ReverseTcp
+            Self::ReverseTcp { .. }
                 | Self::ReverseUdp { .. }
                 | Self::ReverseSocks5 { .. }
                 | Self::ReverseUnix { .. }
                 | Self::ReverseHttpProxy { .. }
+                | Self::ReverseHttpIngress { .. }
+                | Self::ReverseEncryptedDns { .. }
         )
     }
 
+    /// Resume configuration of the tunnel, when it can survive the loss of the connection with the server
+    pub fn resume_mut(&mut self) -> Option<&mut TunnelResume> {
+        match self {
+            Self::Tcp { resume, .. } | Self::ReverseTcp { resume, .. } => resume.as_mut(),
+            _ => None,
+        }
+    }
+
     pub const fn is_dynamic_reverse_tunnel(&self) -> bool {
         matches!(self, Self::ReverseSocks5 { .. } | Self::ReverseHttpProxy { .. })
     }
 }
 
+/// What a UDP server does when a new peer shows up while it already tracks `max_flows` flows
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum UdpFlowEviction {
+    /// Discard the packets of the new peer until a flow slot is freed
+    #[default]
+    DropNew,
+    /// Close the flow that has been idle for the longest time to make room for the new peer
+    EvictIdlest,
+}
+
+/// Destinations, besides the one of the tunnel, a TCP tunnel spreads its connections over.
+/// i.e: tcp://8080:backend1:80,backend2:80?lb=round_robin
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LoadBalancing {
+    /// Tried in order by the server when it cannot reach the destination of the connection
+    pub fallbacks: Vec<(Host, u16)>,
+    /// How the client picks the destination of each new connection
+    #[serde(default)]
+    pub strategy: LoadBalancingStrategy,
+    /// How long the server skips a destination it could not reach. None to try it again on the next connection
+    #[serde(default)]
+    pub health_check: Option<Duration>,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum LoadBalancingStrategy {
+    /// Each destination in turn
+    #[default]
+    RoundRobin,
+    /// A destination picked at random
+    Random,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum EncryptedDns {
+    /// DNS-over-HTTPS, RFC 8484
+    Https,
+    /// DNS-over-TLS, RFC 7858
+    Tls,
+}
+
+/// Where the hostnames of the requests received by a socks5 tunnel are resolved
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Socks5Resolve {
+    /// By the server, the hostname goes through the tunnel
+    #[default]
+    Remote,
+    /// By the client, only the ip goes through the tunnel
+    Local,
+    /// The client answers the dns queries received on this address with fake ips, and turns them back into their
+    /// hostname when an app connects to them. For the apps that resolve the hostname before connecting
+    FakeIp(SocketAddr),
+}
+
+/// Permissions applied to the file of a unix socket created by a tunnel. Abstract sockets, whose path starts with '@',
+/// have no file
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UnixSocketPermissions {
+    /// i.e: 0o660
+    pub mode: Option<u32>,
+    /// User name or uid
+    pub owner: Option<String>,
+    /// Group name or gid
+    pub group: Option<String>,
+}
+
+/// How the server authenticates the visitors of a reverse http ingress, so a shared dev server is not open to everyone
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum HttpIngressAuth {
+    /// Http basic auth, with these `user:password` credentials
+    Basic(String),
+    /// Ask this url, i.e: the /oauth2/auth endpoint of oauth2-proxy, with the cookies and authorization of the request.
+    /// A 2xx response lets the request through, any other is returned to the visitor, i.e: a redirect to the sign-in page
+    Forward(Url),
+}
+
+impl UnixSocketPermissions {
+    pub const fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.owner.is_none() && self.group.is_none()
+    }
+}
+
+/// What a tunnel does with the file of its unix socket besides creating it. Abstract sockets have no file
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UnixSocketLifecycle {
+    /// Remove the socket left at the path by a process that is gone, nothing listening on it, instead of failing to bind
+    #[serde(default)]
+    pub remove_stale: bool,
+    /// Create the socket again, with its permissions, when its file is removed, i.e: its directory on a tmpfs is cleaned
+    /// up and recreated
+    #[serde(default)]
+    pub recreate: bool,
+}
+
+/// Peers allowed to connect to a listener, checked when their connection is accepted, so a listener bound on 0.0.0.0
+/// is not open to the whole network. A peer in `allow` is accepted, otherwise it is refused if it is in `deny` or if
+/// `allow` is not empty. Empty lists accept everyone
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AccessList {
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn accepts(&self, peer: IpAddr) -> bool {
+        // An ipv4 peer of a dual stack listener is seen as an ipv4 mapped ipv6 address
+        let peer = peer.to_canonical();
+        if self.allow.iter().any(|net| net.contains(&peer)) {
+            return true;
+        }
+        self.allow.is_empty() && !self.deny.iter().any(|net| net.contains(&peer))
+    }
+}
+
+/// Keep a TCP tunnel alive when the connection with the server drops, by replaying the bytes the peer did not receive
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TunnelResume {
+    /// Maximum number of bytes, in each direction, kept until the peer acknowledges them
+    pub buffer_size: usize,
+    /// How long the tunnel can stay disconnected before being closed
+    pub timeout: Duration,
+    /// Token handed out by the server when the tunnel is opened. The client presents it to resume the tunnel,
+    /// possibly from another network
+    #[serde(default)]
+    pub session: Option<Uuid>,
+}
+
+/// TCP keepalive of both ends of a tunnel, to keep the long idle connections, i.e: of a database, open through the
+/// middleboxes that drop the silent ones. The other TCP connections use the keepalive of [`protocols::tcp::configure_socket`]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TunnelKeepalive {
+    /// Time without traffic before the first probe
+    pub idle: Duration,
+    /// Time between the probes
+    pub interval: Duration,
+    /// Unanswered probes before giving up on the peer
+    pub count: u32,
+}
+
+impl TunnelKeepalive {
+    pub fn set_on(&self, socket: SockRef) -> std::io::Result<()> {
+        protocols::tcp::set_tcp_keepalive(socket, self.idle, self.interval, self.count)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RemoteAddr {
     pub protocol: LocalProtocol,
@@ -81,6 +351,25 @@ pub struct RemoteAddr {
     pub port: u16,
 }
 
+/// Labels tag tunnels in the logs and metrics of the server, keep them short and safe to print
+pub const MAX_LABEL_LEN: usize = 64;
+
+pub fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= MAX_LABEL_LEN
+        && label
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'.' | b'_' | b'-'))
+}
+
+/// Subdomain allocated to a reverse http ingress session, derived from its secret so no other client can claim it
+pub fn http_ingress_subdomain(session: &Uuid) -> String {
+    let key = EncodingKey::from_secret(session.as_bytes());
+    let signature = jsonwebtoken::crypto::sign(b"wstunnel-http-ingress", &key, Algorithm::HS256).unwrap_or_default();
+    let digest = URL_SAFE_NO_PAD.decode(signature).unwrap_or_default();
+    digest.iter().take(8).map(|b| format!("{b:02x}")).collect()
+}
+
 pub fn to_host_port(addr: SocketAddr) -> (Host, u16) {
     match addr.ip() {
         IpAddr::V4(ip) => (Host::Ipv4(ip), addr.port()),
@@ -0,0 +1,555 @@
+//! mux - carry many tunnels over the connection of a single one with the server, to save a handshake per tunnel
+//!
+//! The frames are spec'd in [`crate::tunnel::protocol::mux_frame`]. A stream only sends the bytes its peer granted it
+//! with window frames, so a slow tunnel never blocks the other ones sharing the connection
+
+use crate::executor::TokioExecutorRef;
+use crate::tunnel::protocol::close_reason::CloseReason;
+use crate::tunnel::protocol::mux_frame::{FrameKind, HEADER_LEN, MAX_DATA_LEN, MuxFrame};
+use ahash::AHashMap;
+use anyhow::{Context, anyhow};
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Semaphore, mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+/// Bytes a stream can send before its peer acknowledges them
+const WINDOW_SIZE: usize = 256 * 1024;
+/// Bytes of frames written to the connection at once
+const WRITE_BATCH_LEN: usize = 256 * 1024;
+/// How often a draining connection checks whether its streams are all closed
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Stream {
+    /// Data received from the peer, until it is written to the local side
+    data_tx: Option<mpsc::UnboundedSender<Bytes>>,
+    data_rx: Option<mpsc::UnboundedReceiver<Bytes>>,
+    /// Bytes received and not written to the local side yet, the peer must not send more than the window
+    buffered: Arc<AtomicUsize>,
+    /// Bytes the peer accepts to receive
+    credit: Arc<Semaphore>,
+    reset: CancellationToken,
+    /// Resolved once the peer accepted or rejected the stream we opened
+    opened: Option<oneshot::Sender<bool>>,
+    /// Opened by the peer, and not accepted yet
+    to_accept: bool,
+}
+
+impl Stream {
+    fn new() -> Self {
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        Self {
+            data_tx: Some(data_tx),
+            data_rx: Some(data_rx),
+            buffered: Arc::new(AtomicUsize::new(0)),
+            credit: Arc::new(Semaphore::new(WINDOW_SIZE)),
+            reset: CancellationToken::new(),
+            opened: None,
+            to_accept: false,
+        }
+    }
+
+    fn abort(mut self) {
+        self.reset.cancel();
+        self.credit.close();
+        if let Some(opened) = self.opened.take() {
+            let _ = opened.send(false);
+        }
+    }
+}
+
+struct Shared {
+    streams: Mutex<AHashMap<u32, Stream>>,
+    frames: mpsc::Sender<MuxFrame>,
+    next_id: AtomicU32,
+    closed: CancellationToken,
+}
+
+impl Shared {
+    async fn send(&self, frame: MuxFrame) -> anyhow::Result<()> {
+        self.frames
+            .send(frame)
+            .await
+            .map_err(|_| anyhow!("mux connection with the peer is closed"))
+    }
+
+    fn remove(&self, id: u32) {
+        if let Some(stream) = self.streams.lock().remove(&id) {
+            stream.abort();
+        }
+    }
+
+    async fn reset(&self, id: u32) {
+        self.remove(id);
+        let _ = self.send(MuxFrame::new(id, FrameKind::Reset)).await;
+    }
+
+    async fn handle(&self, frame: MuxFrame, incoming: &mpsc::Sender<IncomingStream>) {
+        let id = frame.id;
+        match frame.kind {
+            FrameKind::Open => {
+                let mut stream = Stream::new();
+                stream.to_accept = true;
+                if self.streams.lock().insert(id, stream).is_some() {
+                    warn!("mux peer opened stream {id} twice");
+                    return self.reset(id).await;
+                }
+                let payload = frame.payload;
+                if incoming.try_send(IncomingStream { id, payload }).is_err() {
+                    self.reset(id).await;
+                }
+            }
+            FrameKind::Accept => {
+                if let Some(opened) = self.streams.lock().get_mut(&id).and_then(|s| s.opened.take()) {
+                    let _ = opened.send(true);
+                }
+            }
+            FrameKind::Data => {
+                let overflow = {
+                    let streams = self.streams.lock();
+                    let Some(stream) = streams.get(&id) else {
+                        return;
+                    };
+                    let len = frame.payload.len();
+                    if stream.buffered.fetch_add(len, Ordering::Relaxed) + len > WINDOW_SIZE {
+                        true
+                    } else {
+                        if let Some(data_tx) = &stream.data_tx {
+                            let _ = data_tx.send(frame.payload);
+                        }
+                        false
+                    }
+                };
+                if overflow {
+                    warn!("mux peer sent more data than allowed on stream {id}");
+                    self.reset(id).await;
+                }
+            }
+            FrameKind::Window => {
+                let Ok(len) = <[u8; 4]>::try_from(frame.payload.as_ref()).map(u32::from_be_bytes) else {
+                    return;
+                };
+                if let Some(stream) = self.streams.lock().get(&id) {
+                    // A peer never grants more than what it received, do not let it overflow the semaphore
+                    let len = (len as usize).min(WINDOW_SIZE.saturating_sub(stream.credit.available_permits()));
+                    stream.credit.add_permits(len);
+                }
+            }
+            FrameKind::Fin => {
+                if let Some(stream) = self.streams.lock().get_mut(&id) {
+                    stream.data_tx = None;
+                }
+            }
+            FrameKind::Reset => self.remove(id),
+        }
+    }
+
+    /// The connection with the peer is lost, every stream is aborted
+    fn close(&self) {
+        self.closed.cancel();
+        for (_, stream) in self.streams.lock().drain() {
+            stream.abort();
+        }
+    }
+}
+
+/// Stream opened by the peer, to be accepted with [`MuxSession::forward`] or refused with [`MuxSession::reject`]
+pub struct IncomingStream {
+    pub id: u32,
+    /// Description of the tunnel, as sent by the peer
+    pub payload: Bytes,
+}
+
+/// Streams multiplexed over a single connection with the peer
+pub struct MuxSession<E: TokioExecutorRef> {
+    shared: Arc<Shared>,
+    executor: E,
+}
+
+impl<E: TokioExecutorRef> Clone for MuxSession<E> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            executor: self.executor.clone(),
+        }
+    }
+}
+
+impl<E: TokioExecutorRef> MuxSession<E> {
+    /// Start the session over the connection. The receiver yields the streams opened by the peer
+    pub fn new(
+        cnx: impl AsyncRead + AsyncWrite + Send + 'static,
+        executor: E,
+    ) -> (Self, mpsc::Receiver<IncomingStream>) {
+        let (frames_tx, frames_rx) = mpsc::channel(1024);
+        let (incoming_tx, incoming_rx) = mpsc::channel(1024);
+        let shared = Arc::new(Shared {
+            streams: Mutex::new(AHashMap::new()),
+            frames: frames_tx,
+            next_id: AtomicU32::new(1),
+            closed: CancellationToken::new(),
+        });
+
+        let (cnx_rx, cnx_tx) = tokio::io::split(cnx);
+        executor.spawn(write_frames(cnx_tx, frames_rx, shared.closed.clone()));
+        executor.spawn(read_frames(cnx_rx, shared.clone(), incoming_tx));
+        (Self { shared, executor }, incoming_rx)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.is_cancelled()
+    }
+
+    /// Resolve once the connection with the peer is lost or closed
+    pub async fn closed(&self) {
+        self.shared.closed.cancelled().await
+    }
+
+    /// True if both are the same session, and not two over different connections
+    pub fn is_same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    /// Close the connection with the peer, aborting the streams still open
+    pub fn close(&self) {
+        self.shared.close();
+    }
+
+    /// Close the connection with the peer once the streams opened on it are all closed, so no new stream should be
+    /// opened on it meanwhile
+    pub async fn close_when_idle(&self) {
+        while !self.is_closed() && !self.shared.streams.lock().is_empty() {
+            tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+        }
+        self.close();
+    }
+
+    /// Ask the peer to open a stream for the tunnel described by the payload, and return its id once accepted
+    pub async fn open(&self, payload: Bytes) -> anyhow::Result<u32> {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let mut stream = Stream::new();
+        stream.opened = Some(opened_tx);
+        self.shared.streams.lock().insert(id, stream);
+        if self.is_closed() {
+            self.shared.remove(id);
+            return Err(anyhow!("mux connection with the peer is closed"));
+        }
+
+        self.shared
+            .send(MuxFrame {
+                id,
+                kind: FrameKind::Open,
+                payload,
+            })
+            .await?;
+        match opened_rx.await {
+            Ok(true) => Ok(id),
+            _ => {
+                self.shared.remove(id);
+                Err(anyhow!("tunnel rejected by the peer"))
+            }
+        }
+    }
+
+    /// Refuse a stream opened by the peer
+    pub async fn reject(&self, id: u32) {
+        self.shared.reset(id).await;
+    }
+
+    /// Accept the stream if it was opened by the peer, and forward its data with the local side until both directions
+    /// are closed
+    pub async fn forward<R, W>(&self, id: u32, local_rx: R, local_tx: W) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Send,
+        W: AsyncWrite + Send,
+    {
+        let (data_rx, buffered, credit, reset, to_accept) = {
+            let mut streams = self.shared.streams.lock();
+            let stream = streams.get_mut(&id).context("mux stream is already closed")?;
+            let data_rx = stream.data_rx.take().context("mux stream is already forwarded")?;
+            let to_accept = std::mem::take(&mut stream.to_accept);
+            (
+                data_rx,
+                stream.buffered.clone(),
+                stream.credit.clone(),
+                stream.reset.clone(),
+                to_accept,
+            )
+        };
+        if to_accept {
+            self.shared.send(MuxFrame::new(id, FrameKind::Accept)).await?;
+        }
+
+        tokio::join!(
+            send_data(&self.shared, id, local_rx, &credit, &reset),
+            receive_data(&self.shared, id, local_tx, data_rx, &buffered, &reset),
+        );
+        self.shared.remove(id);
+        Ok(())
+    }
+}
+
+/// Read the local side, and send it to the peer as long as it grants us window
+async fn send_data(shared: &Shared, id: u32, local_rx: impl AsyncRead, credit: &Semaphore, reset: &CancellationToken) {
+    tokio::pin!(local_rx);
+    let mut buf = vec![0; MAX_DATA_LEN];
+    loop {
+        let len = tokio::select! {
+            ret = local_rx.read(&mut buf) => ret,
+            _ = reset.cancelled() => return,
+        };
+        let len = match len {
+            Ok(0) => {
+                let _ = shared.send(MuxFrame::new(id, FrameKind::Fin)).await;
+                return;
+            }
+            Ok(len) => len,
+            // Reaped by the idle timeout, the stream ends cleanly
+            Err(err) if !CloseReason::of_io_error(&err).is_failure() => {
+                debug!("local side of mux stream {id} closed: {err}");
+                let _ = shared.send(MuxFrame::new(id, FrameKind::Fin)).await;
+                return;
+            }
+            Err(err) => {
+                debug!("error while reading local side of mux stream {id}: {err}");
+                shared.reset(id).await;
+                return;
+            }
+        };
+
+        match credit.acquire_many(len as u32).await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return,
+        }
+        let frame = MuxFrame {
+            id,
+            kind: FrameKind::Data,
+            payload: Bytes::copy_from_slice(&buf[..len]),
+        };
+        if shared.send(frame).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Write the data of the peer to the local side, and grant it window again once written
+async fn receive_data(
+    shared: &Shared,
+    id: u32,
+    local_tx: impl AsyncWrite,
+    mut data_rx: mpsc::UnboundedReceiver<Bytes>,
+    buffered: &AtomicUsize,
+    reset: &CancellationToken,
+) {
+    tokio::pin!(local_tx);
+    while let Some(data) = data_rx.recv().await {
+        if let Err(err) = local_tx.write_all(&data).await.and(local_tx.flush().await) {
+            debug!("error while writing local side of mux stream {id}: {err}");
+            reset.cancel();
+            shared.reset(id).await;
+            return;
+        }
+        buffered.fetch_sub(data.len(), Ordering::Relaxed);
+        let window = MuxFrame {
+            id,
+            kind: FrameKind::Window,
+            payload: Bytes::copy_from_slice(&(data.len() as u32).to_be_bytes()),
+        };
+        if shared.send(window).await.is_err() {
+            return;
+        }
+    }
+    let _ = local_tx.shutdown().await;
+}
+
+async fn read_frames(cnx_rx: impl AsyncRead, shared: Arc<Shared>, incoming: mpsc::Sender<IncomingStream>) {
+    tokio::pin!(cnx_rx);
+    let ret: anyhow::Result<()> = async {
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + MAX_DATA_LEN);
+        loop {
+            while let Some(frame) = MuxFrame::decode(&mut buf)? {
+                shared.handle(frame, &incoming).await;
+            }
+            if cnx_rx.read_buf(&mut buf).await? == 0 {
+                return Err(anyhow!("connection closed"));
+            }
+        }
+    }
+    .await;
+
+    if let Err(err) = ret {
+        debug!("mux connection with the peer closed: {err}");
+    }
+    shared.close();
+}
+
+async fn write_frames(cnx_tx: impl AsyncWrite, mut frames: mpsc::Receiver<MuxFrame>, closed: CancellationToken) {
+    tokio::pin!(cnx_tx);
+    let mut buf = BytesMut::with_capacity(WRITE_BATCH_LEN);
+    loop {
+        let frame = tokio::select! {
+            frame = frames.recv() => frame,
+            _ = closed.cancelled() => None,
+        };
+        let Some(frame) = frame else {
+            break;
+        };
+
+        // Write the frames queued meanwhile in one go
+        frame.encode(&mut buf);
+        while buf.len() < WRITE_BATCH_LEN
+            && let Ok(frame) = frames.try_recv()
+        {
+            frame.encode(&mut buf);
+        }
+        if let Err(err) = cnx_tx.write_all(&buf).await.and(cnx_tx.flush().await) {
+            debug!("cannot write to the mux connection with the peer: {err}");
+            break;
+        }
+        buf.clear();
+    }
+
+    closed.cancel();
+    let _ = cnx_tx.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::DefaultTokioExecutor;
+    use tokio::io::duplex;
+
+    fn sessions() -> (
+        MuxSession<DefaultTokioExecutor>,
+        MuxSession<DefaultTokioExecutor>,
+        mpsc::Receiver<IncomingStream>,
+    ) {
+        let (client_cnx, server_cnx) = duplex(64 * 1024);
+        let (client, _) = MuxSession::new(client_cnx, DefaultTokioExecutor::default());
+        let (server, incoming) = MuxSession::new(server_cnx, DefaultTokioExecutor::default());
+        (client, server, incoming)
+    }
+
+    #[tokio::test]
+    async fn test_mux_streams() {
+        let (client, server, mut incoming) = sessions();
+
+        // The server echoes every stream it accepts
+        let echo = tokio::spawn(async move {
+            while let Some(stream) = incoming.recv().await {
+                if stream.payload.as_ref() == b"rejected" {
+                    server.reject(stream.id).await;
+                    continue;
+                }
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let (local, remote) = duplex(1024);
+                    let (remote_rx, mut remote_tx) = tokio::io::split(remote);
+                    tokio::spawn(async move {
+                        let mut remote_rx = remote_rx;
+                        let _ = tokio::io::copy(&mut remote_rx, &mut remote_tx).await;
+                        let _ = remote_tx.shutdown().await;
+                    });
+                    let (local_rx, local_tx) = tokio::io::split(local);
+                    server.forward(stream.id, local_rx, local_tx).await.unwrap();
+                });
+            }
+        });
+
+        assert!(client.open(Bytes::from_static(b"rejected")).await.is_err());
+
+        // More data than the window on several streams at once
+        let payload: Vec<u8> = (0..3 * WINDOW_SIZE).map(|i| i as u8).collect();
+        let mut tunnels = vec![];
+        for _ in 0..4 {
+            let client = client.clone();
+            let payload = payload.clone();
+            tunnels.push(tokio::spawn(async move {
+                let id = client.open(Bytes::from_static(b"tunnel")).await.unwrap();
+                let (local, remote) = duplex(1024);
+                let (local_rx, local_tx) = tokio::io::split(local);
+                let forward = tokio::spawn(async move { client.forward(id, local_rx, local_tx).await });
+
+                let (mut remote_rx, mut remote_tx) = tokio::io::split(remote);
+                let expected = payload.clone();
+                let writer = tokio::spawn(async move {
+                    remote_tx.write_all(&payload).await.unwrap();
+                    remote_tx.shutdown().await.unwrap();
+                });
+                let mut received = vec![];
+                remote_rx.read_to_end(&mut received).await.unwrap();
+                writer.await.unwrap();
+                forward.await.unwrap().unwrap();
+                assert_eq!(received, expected);
+            }));
+        }
+        for tunnel in tunnels {
+            tunnel.await.unwrap();
+        }
+
+        assert!(client.shared.streams.lock().is_empty());
+        echo.abort();
+    }
+
+    #[tokio::test]
+    async fn test_mux_connection_lost() {
+        let (client, server, incoming) = sessions();
+        drop(incoming);
+        drop(server);
+
+        // Nobody accepts the streams on the other side
+        assert!(client.open(Bytes::from_static(b"tunnel")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mux_close_when_idle() {
+        let (client, server, mut incoming) = sessions();
+        let echo = tokio::spawn(async move {
+            let stream = incoming.recv().await.unwrap();
+            let (local, remote) = duplex(1024);
+            let (mut remote_rx, mut remote_tx) = tokio::io::split(remote);
+            tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut remote_rx, &mut remote_tx).await;
+                let _ = remote_tx.shutdown().await;
+            });
+            let (local_rx, local_tx) = tokio::io::split(local);
+            server.forward(stream.id, local_rx, local_tx).await.unwrap();
+            server
+        });
+
+        let id = client.open(Bytes::from_static(b"tunnel")).await.unwrap();
+        let (local, remote) = duplex(1024);
+        let (local_rx, local_tx) = tokio::io::split(local);
+        let forward = tokio::spawn({
+            let client = client.clone();
+            async move { client.forward(id, local_rx, local_tx).await }
+        });
+
+        // The connection stays open while the tunnel is
+        let close = tokio::spawn({
+            let client = client.clone();
+            async move { client.close_when_idle().await }
+        });
+        tokio::time::sleep(IDLE_CHECK_INTERVAL * 2).await;
+        assert!(!close.is_finished());
+        assert!(!client.is_closed());
+
+        let (mut remote_rx, mut remote_tx) = tokio::io::split(remote);
+        remote_tx.shutdown().await.unwrap();
+        let mut received = vec![];
+        remote_rx.read_to_end(&mut received).await.unwrap();
+        forward.await.unwrap().unwrap();
+        close.await.unwrap();
+        assert!(client.is_closed());
+        let server = echo.await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server.closed())
+            .await
+            .unwrap();
+    }
+}
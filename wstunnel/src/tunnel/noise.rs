@@ -0,0 +1,354 @@
+//! Noise_IK encryption of the data of the tunnels, end to end between the client and the server.
+//! It keeps the payloads confidential when TLS is terminated by a third party in the middle (i.e: a CDN).
+//! The client knows the public key of the server beforehand, and the server can restrict the client keys it accepts.
+//! Keys are base64 x25519 keys, the same as wireguard ones, so `wg genkey` and `wg pubkey` can generate them.
+//!
+//! The local side of the tunnel is plugged to one end of an in-memory pipe, whose other end is carried by the transport.
+//! Each noise message goes through the pipe prefixed by its length, on 2 bytes.
+//! The end of the stream is an encrypted empty message, so that the transport being cut cannot pass for the end of the
+//! data. A transport ending without it fails the tunnel.
+use crate::TokioExecutorRef;
+use anyhow::{Context, anyhow};
+use base64::Engine;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState};
+use std::fmt::{Debug, Formatter};
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tracing::{Instrument, Span, info, warn};
+
+const NOISE_PARAMS: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+const PROLOGUE: &[u8] = b"wstunnel noise v1";
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+const PIPE_SIZE: usize = 2 * (MAX_MESSAGE_LEN + 2);
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct NoiseKey([u8; 32]);
+
+impl NoiseKey {
+    pub fn from_base64(key: &str) -> anyhow::Result<Self> {
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(key.trim())
+            .context("noise key is not valid base64")?;
+        let key = key
+            .try_into()
+            .map_err(|key: Vec<u8>| anyhow!("noise key must be 32 bytes long, got {}", key.len()))?;
+        Ok(Self(key))
+    }
+
+    /// Public key of this private key
+    pub fn public_key(&self) -> Self {
+        let mut dh = DefaultResolver
+            .resolve_dh(&snow::params::DHChoice::Curve25519)
+            .expect("bug: curve25519 is always supported");
+        dh.set(&self.0);
+        Self(
+            dh.pubkey()
+                .try_into()
+                .expect("bug: curve25519 public keys are 32 bytes"),
+        )
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.0)
+    }
+}
+
+// Private keys end up in this type too, so never print them
+impl Debug for NoiseKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if crate::config::secret::secrets_shown() {
+            write!(f, "NoiseKey({})", self.to_base64())
+        } else {
+            f.write_str("NoiseKey(****)")
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NoiseClientConfig {
+    pub private_key: NoiseKey,
+    pub server_public_key: NoiseKey,
+}
+
+#[derive(Debug, Clone)]
+pub struct NoiseServerConfig {
+    pub private_key: NoiseKey,
+    /// Public keys of the clients allowed to open tunnels. Any client is accepted if empty
+    pub client_public_keys: Vec<NoiseKey>,
+}
+
+type TunnelRx = Pin<Box<dyn AsyncRead + Send>>;
+type TunnelTx = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// Encrypt the local side of a tunnel of the client if noise is enabled, the transport then carries the returned streams
+pub fn client_channel(
+    config: Option<&NoiseClientConfig>,
+    executor: &impl TokioExecutorRef,
+    local_rx: impl AsyncRead + Send + 'static,
+    local_tx: impl AsyncWrite + Send + 'static,
+) -> anyhow::Result<(TunnelRx, TunnelTx)> {
+    let Some(config) = config else {
+        return Ok((Box::pin(local_rx), Box::pin(local_tx)));
+    };
+
+    let handshake = Builder::new(NOISE_PARAMS.parse()?)
+        .local_private_key(&config.private_key.0)
+        .remote_public_key(&config.server_public_key.0)
+        .prologue(PROLOGUE)
+        .build_initiator()?;
+    Ok(spawn_channel(executor, handshake, vec![], local_rx, local_tx))
+}
+
+/// Encrypt the local side of a tunnel of the server if noise is enabled, the transport then carries the returned streams
+pub fn server_channel(
+    config: Option<&NoiseServerConfig>,
+    executor: &impl TokioExecutorRef,
+    local_rx: impl AsyncRead + Send + 'static,
+    local_tx: impl AsyncWrite + Send + 'static,
+) -> anyhow::Result<(TunnelRx, TunnelTx)> {
+    let Some(config) = config else {
+        return Ok((Box::pin(local_rx), Box::pin(local_tx)));
+    };
+
+    let handshake = Builder::new(NOISE_PARAMS.parse()?)
+        .local_private_key(&config.private_key.0)
+        .prologue(PROLOGUE)
+        .build_responder()?;
+    Ok(spawn_channel(
+        executor,
+        handshake,
+        config.client_public_keys.clone(),
+        local_rx,
+        local_tx,
+    ))
+}
+
+fn spawn_channel(
+    executor: &impl TokioExecutorRef,
+    handshake: HandshakeState,
+    authorized_keys: Vec<NoiseKey>,
+    local_rx: impl AsyncRead + Send + 'static,
+    local_tx: impl AsyncWrite + Send + 'static,
+) -> (TunnelRx, TunnelTx) {
+    let (transport, local_side) = tokio::io::duplex(PIPE_SIZE);
+    executor.spawn(
+        async move {
+            if let Err(err) = run_channel(handshake, &authorized_keys, local_rx, local_tx, local_side).await {
+                warn!("Closing noise encrypted tunnel: {err:#}");
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    let (transport_rx, transport_tx) = tokio::io::split(transport);
+    (Box::pin(transport_rx), Box::pin(transport_tx))
+}
+
+async fn run_channel(
+    mut handshake: HandshakeState,
+    authorized_keys: &[NoiseKey],
+    local_rx: impl AsyncRead,
+    local_tx: impl AsyncWrite,
+    transport: DuplexStream,
+) -> anyhow::Result<()> {
+    let (mut transport_rx, mut transport_tx) = tokio::io::split(transport);
+    let mut message = vec![0; MAX_MESSAGE_LEN];
+
+    if handshake.is_initiator() {
+        let len = handshake.write_message(&[], &mut message)?;
+        write_frame(&mut transport_tx, &message[..len]).await?;
+        let frame = read_frame(&mut transport_rx).await?;
+        handshake
+            .read_message(&frame, &mut message)
+            .context("noise handshake failed, is the public key of the server right?")?;
+    } else {
+        let frame = read_frame(&mut transport_rx).await?;
+        handshake
+            .read_message(&frame, &mut message)
+            .context("noise handshake failed, is the client using the public key of this server?")?;
+        let client_key = NoiseKey(
+            handshake
+                .get_remote_static()
+                .and_then(|key| key.try_into().ok())
+                .context("client did not send its static key")?,
+        );
+        if !authorized_keys.is_empty() && !authorized_keys.contains(&client_key) {
+            return Err(anyhow!("client noise key {} is not allowed", client_key.to_base64()));
+        }
+        info!("Noise handshake done with client key {}", client_key.to_base64());
+        let len = handshake.write_message(&[], &mut message)?;
+        write_frame(&mut transport_tx, &message[..len]).await?;
+    }
+    let cipher = handshake.into_stateless_transport_mode()?;
+
+    let encrypt = async {
+        tokio::pin!(local_rx);
+        let mut payload = vec![0; MAX_PAYLOAD_LEN];
+        let mut message = vec![0; MAX_MESSAGE_LEN];
+        for nonce in 0.. {
+            let read = local_rx.read(&mut payload).await?;
+            if read == 0 {
+                let len = cipher.write_message(nonce, &[], &mut message)?;
+                write_frame(&mut transport_tx, &message[..len]).await?;
+                transport_tx.shutdown().await?;
+                break;
+            }
+            let len = cipher.write_message(nonce, &payload[..read], &mut message)?;
+            write_frame(&mut transport_tx, &message[..len]).await?;
+        }
+        anyhow::Ok(())
+    };
+
+    let decrypt = async {
+        tokio::pin!(local_tx);
+        let mut payload = vec![0; MAX_MESSAGE_LEN];
+        for nonce in 0.. {
+            let frame = read_frame(&mut transport_rx)
+                .await
+                .context("noise tunnel closed without its end of stream")?;
+            // Each message is written at once, so datagrams are forwarded as they were received
+            let len = cipher
+                .read_message(nonce, &frame, &mut payload)
+                .context("cannot decrypt noise message")?;
+            if len == 0 {
+                local_tx.shutdown().await?;
+                break;
+            }
+            local_tx.write_all(&payload[..len]).await?;
+            local_tx.flush().await?;
+        }
+        anyhow::Ok(())
+    };
+
+    tokio::try_join!(encrypt, decrypt)?;
+    Ok(())
+}
+
+async fn write_frame(writer: &mut (impl AsyncWrite + Unpin), message: &[u8]) -> std::io::Result<()> {
+    writer.write_u16(message.len() as u16).await?;
+    writer.write_all(message).await
+}
+
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Vec<u8>> {
+    let len = reader.read_u16().await?;
+    let mut frame = vec![0; len as usize];
+    reader.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::DefaultTokioExecutor;
+
+    fn generate_key() -> NoiseKey {
+        let keypair = Builder::new(NOISE_PARAMS.parse().unwrap()).generate_keypair().unwrap();
+        NoiseKey(keypair.private.try_into().unwrap())
+    }
+
+    /// Plug a client and a server channel together, as the transport would
+    async fn connect(client: &NoiseClientConfig, server: &NoiseServerConfig) -> (DuplexStream, DuplexStream) {
+        let executor = DefaultTokioExecutor::default();
+        let (app, client_local) = tokio::io::duplex(1024);
+        let (destination, server_local) = tokio::io::duplex(1024);
+        let (client_local_rx, client_local_tx) = tokio::io::split(client_local);
+        let (server_local_rx, server_local_tx) = tokio::io::split(server_local);
+        let (client_rx, client_tx) = client_channel(Some(client), &executor, client_local_rx, client_local_tx).unwrap();
+        let (server_rx, server_tx) = server_channel(Some(server), &executor, server_local_rx, server_local_tx).unwrap();
+
+        let mut client_transport = tokio::io::join(client_rx, client_tx);
+        let mut server_transport = tokio::io::join(server_rx, server_tx);
+        tokio::spawn(async move {
+            let _ = tokio::io::copy_bidirectional(&mut client_transport, &mut server_transport).await;
+        });
+        (app, destination)
+    }
+
+    #[tokio::test]
+    async fn test_noise_channel() {
+        let server_key = generate_key();
+        let client_key = generate_key();
+        let client = NoiseClientConfig {
+            private_key: client_key.clone(),
+            server_public_key: server_key.public_key(),
+        };
+        let server = NoiseServerConfig {
+            private_key: server_key.clone(),
+            client_public_keys: vec![client_key.public_key()],
+        };
+
+        let (mut app, mut destination) = connect(&client, &server).await;
+        app.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        destination.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        destination.write_all(b"world").await.unwrap();
+        app.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        // The end of the stream goes through the channel
+        app.shutdown().await.unwrap();
+        assert_eq!(destination.read(&mut buf).await.unwrap(), 0);
+
+        // A client whose key is not allowed cannot reach the destination
+        let server = NoiseServerConfig {
+            private_key: server_key,
+            client_public_keys: vec![generate_key().public_key()],
+        };
+        let (mut app, mut destination) = connect(&client, &server).await;
+        let _ = app.write_all(b"hello").await;
+        assert_eq!(destination.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_noise_truncation() {
+        let server_key = generate_key();
+        let (transport, mut peer) = tokio::io::duplex(PIPE_SIZE);
+        let (mut destination, server_local) = tokio::io::duplex(1024);
+        let (local_rx, local_tx) = tokio::io::split(server_local);
+        let handshake = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&server_key.0)
+            .prologue(PROLOGUE)
+            .build_responder()
+            .unwrap();
+        let server = tokio::spawn(async move { run_channel(handshake, &[], local_rx, local_tx, transport).await });
+
+        let mut client = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&generate_key().0)
+            .remote_public_key(&server_key.public_key().0)
+            .prologue(PROLOGUE)
+            .build_initiator()
+            .unwrap();
+        let mut message = vec![0; MAX_MESSAGE_LEN];
+        let len = client.write_message(&[], &mut message).unwrap();
+        write_frame(&mut peer, &message[..len]).await.unwrap();
+        let frame = read_frame(&mut peer).await.unwrap();
+        client.read_message(&frame, &mut message).unwrap();
+        let cipher = client.into_stateless_transport_mode().unwrap();
+        let len = cipher.write_message(0, b"hello", &mut message).unwrap();
+        write_frame(&mut peer, &message[..len]).await.unwrap();
+        let mut buf = [0; 5];
+        destination.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The transport ends without the encrypted end of stream, the data may have been cut
+        drop(peer);
+        let err = server.await.unwrap().unwrap_err();
+        assert!(format!("{err:#}").contains("without its end of stream"));
+    }
+
+    #[test]
+    fn test_noise_key() {
+        // Alice keys of the test vectors of RFC 7748
+        let private_key = NoiseKey::from_base64("dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=").unwrap();
+        assert_eq!(
+            private_key.public_key().to_base64(),
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo="
+        );
+        assert!(NoiseKey::from_base64("not base64").is_err());
+        assert!(NoiseKey::from_base64("aGVsbG8=").is_err());
+    }
+}
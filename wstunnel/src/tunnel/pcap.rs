@@ -0,0 +1,371 @@
+//! Debug recording of the traffic of a tunnel to a pcap file, that can be opened with wireshark or tcpdump.
+//! The traffic is captured before/after going through the tunnel, so ip and tcp/udp headers are made up
+//! from the addresses of both ends of the tunnel
+use crate::tunnel::LocalProtocol;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{info, warn};
+use url::Host;
+
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+/// Max payload of a packet, so that it fits with its headers in the 16 bits length of ip packets
+const MAX_PAYLOAD: usize = 65_000;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// Way the data goes through the tunnel. The origin is the side that opened the tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToDestination,
+    ToOrigin,
+}
+
+pub struct PcapRecorder {
+    file: Mutex<PcapFile>,
+}
+
+struct PcapFile {
+    out: BufWriter<File>,
+    transport: Transport,
+    origin: SocketAddr,
+    destination: SocketAddr,
+    /// Next tcp sequence number of the origin and of the destination
+    seq: [u32; 2],
+}
+
+impl PcapRecorder {
+    /// Create the pcap file of the tunnel, with a made up tcp handshake for wireshark to follow the stream
+    pub fn create(
+        path: &Path,
+        transport: Transport,
+        origin: SocketAddr,
+        destination: SocketAddr,
+    ) -> io::Result<Arc<Self>> {
+        // Ip headers of both ends must be of the same family
+        let destination = match (origin.ip(), destination.ip()) {
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(IpAddr::V4(ip), destination.port()),
+                None => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), destination.port()),
+            },
+            (IpAddr::V6(_), IpAddr::V4(ip)) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), destination.port()),
+            _ => destination,
+        };
+
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        out.write_all(&0i32.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(&SNAPLEN.to_le_bytes())?;
+        out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+        let mut file = PcapFile {
+            out,
+            transport,
+            origin,
+            destination,
+            seq: [0, 0],
+        };
+        if transport == Transport::Tcp {
+            file.write_tcp(Direction::ToDestination, TCP_SYN, &[])?;
+            file.write_tcp(Direction::ToOrigin, TCP_SYN | TCP_ACK, &[])?;
+            file.write_tcp(Direction::ToDestination, TCP_ACK, &[])?;
+        }
+
+        Ok(Arc::new(Self { file: Mutex::new(file) }))
+    }
+
+    pub fn record(&self, direction: Direction, data: &[u8]) {
+        let mut file = self.file.lock();
+        let _ = match file.transport {
+            Transport::Tcp => data
+                .chunks(MAX_PAYLOAD)
+                .try_for_each(|chunk| file.write_tcp(direction, TCP_PSH | TCP_ACK, chunk)),
+            Transport::Udp => file.write_udp(direction, &data[..data.len().min(MAX_PAYLOAD)]),
+        };
+    }
+}
+
+/// Create the pcap file of a tunnel in `dir`, if the recording of tunnels is enabled
+pub fn recorder(
+    dir: Option<&Path>,
+    tunnel_id: &str,
+    protocol: &LocalProtocol,
+    origin: SocketAddr,
+    (host, port): (&Host, u16),
+) -> Option<Arc<PcapRecorder>> {
+    let path = dir?.join(format!("{tunnel_id}.pcap"));
+    let transport = match protocol {
+        LocalProtocol::Udp { .. }
+        | LocalProtocol::ReverseUdp { .. }
+        | LocalProtocol::StdioUdp { .. }
+        | LocalProtocol::TProxyUdp { .. } => Transport::Udp,
+        _ => Transport::Tcp,
+    };
+    let destination = match host {
+        Host::Ipv4(ip) => SocketAddr::new(IpAddr::V4(*ip), port),
+        Host::Ipv6(ip) => SocketAddr::new(IpAddr::V6(*ip), port),
+        Host::Domain(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+    };
+
+    match PcapRecorder::create(&path, transport, origin, destination) {
+        Ok(recorder) => {
+            info!("Recording tunnel traffic to {}", path.display());
+            Some(recorder)
+        }
+        Err(err) => {
+            warn!("Cannot create pcap file {}: {err}", path.display());
+            None
+        }
+    }
+}
+
+/// Record the traffic going through both halves of a stream, the reader receiving the data going in `read_direction`
+pub fn record<R, W>(
+    recorder: Option<Arc<PcapRecorder>>,
+    rx: R,
+    tx: W,
+    read_direction: Direction,
+) -> (PcapReader<R>, PcapWriter<W>) {
+    let write_direction = match read_direction {
+        Direction::ToDestination => Direction::ToOrigin,
+        Direction::ToOrigin => Direction::ToDestination,
+    };
+    let reader = PcapReader {
+        inner: rx,
+        recorder: recorder.clone(),
+        direction: read_direction,
+    };
+    let writer = PcapWriter {
+        inner: tx,
+        recorder,
+        direction: write_direction,
+    };
+    (reader, writer)
+}
+
+impl Drop for PcapRecorder {
+    fn drop(&mut self) {
+        let file = self.file.get_mut();
+        if file.transport == Transport::Tcp {
+            let _ = file.write_tcp(Direction::ToDestination, TCP_FIN | TCP_ACK, &[]);
+            let _ = file.write_tcp(Direction::ToOrigin, TCP_FIN | TCP_ACK, &[]);
+        }
+        let _ = file.out.flush();
+    }
+}
+
+impl PcapFile {
+    fn endpoints(&self, direction: Direction) -> (SocketAddr, SocketAddr) {
+        match direction {
+            Direction::ToDestination => (self.origin, self.destination),
+            Direction::ToOrigin => (self.destination, self.origin),
+        }
+    }
+
+    fn write_tcp(&mut self, direction: Direction, flags: u8, payload: &[u8]) -> io::Result<()> {
+        let (from, to) = match direction {
+            Direction::ToDestination => (0, 1),
+            Direction::ToOrigin => (1, 0),
+        };
+        let (src, dst) = self.endpoints(direction);
+        let seq = self.seq[from];
+        let ack = if flags & TCP_ACK != 0 { self.seq[to] } else { 0 };
+        // SYN and FIN consume a sequence number
+        let consumed = payload.len() as u32 + u32::from(flags & (TCP_SYN | TCP_FIN) != 0);
+        self.seq[from] = seq.wrapping_add(consumed);
+
+        let mut segment = Vec::with_capacity(20 + payload.len());
+        segment.extend_from_slice(&src.port().to_be_bytes());
+        segment.extend_from_slice(&dst.port().to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&ack.to_be_bytes());
+        segment.extend_from_slice(&[5 << 4, flags]);
+        segment.extend_from_slice(&u16::MAX.to_be_bytes()); // window
+        segment.extend_from_slice(&[0, 0, 0, 0]); // checksum and urgent pointer
+        segment.extend_from_slice(payload);
+        self.write_packet(src.ip(), dst.ip(), 6, &segment)
+    }
+
+    fn write_udp(&mut self, direction: Direction, payload: &[u8]) -> io::Result<()> {
+        let (src, dst) = self.endpoints(direction);
+        let mut datagram = Vec::with_capacity(8 + payload.len());
+        datagram.extend_from_slice(&src.port().to_be_bytes());
+        datagram.extend_from_slice(&dst.port().to_be_bytes());
+        datagram.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]); // checksum
+        datagram.extend_from_slice(payload);
+        self.write_packet(src.ip(), dst.ip(), 17, &datagram)
+    }
+
+    fn write_packet(&mut self, src: IpAddr, dst: IpAddr, protocol: u8, payload: &[u8]) -> io::Result<()> {
+        let packet = match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => ipv4_packet(src, dst, protocol, payload),
+            (IpAddr::V6(src), IpAddr::V6(dst)) => ipv6_packet(src, dst, protocol, payload),
+            _ => unreachable!("both ends are of the same ip family"),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.out.write_all(&(now.as_secs() as u32).to_le_bytes())?;
+        self.out.write_all(&now.subsec_micros().to_le_bytes())?;
+        self.out.write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.out.write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.out.write_all(&packet)
+    }
+}
+
+fn ipv4_packet(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(20 + payload.len());
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]); // id, don't fragment, ttl, protocol, checksum
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+
+    let sum = packet
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    let sum = (sum & 0xffff) + (sum >> 16);
+    let checksum = !(((sum & 0xffff) + (sum >> 16)) as u16);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv6_packet(src: Ipv6Addr, dst: Ipv6Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(40 + payload.len());
+    packet.extend_from_slice(&[0x60, 0, 0, 0]);
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[protocol, 64]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Record the data read from the inner stream
+#[pin_project]
+pub struct PcapReader<R> {
+    #[pin]
+    inner: R,
+    recorder: Option<Arc<PcapRecorder>>,
+    direction: Direction,
+}
+
+impl<R: AsyncRead> AsyncRead for PcapReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        if let Some(recorder) = this.recorder
+            && buf.filled().len() > filled
+        {
+            recorder.record(*this.direction, &buf.filled()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Record the data written to the inner stream
+#[pin_project]
+pub struct PcapWriter<W> {
+    #[pin]
+    inner: W,
+    recorder: Option<Arc<PcapRecorder>>,
+    direction: Direction,
+}
+
+impl<W: AsyncWrite> AsyncWrite for PcapWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let written = ready!(this.inner.poll_write(cx, buf))?;
+        if let Some(recorder) = this.recorder {
+            recorder.record(*this.direction, &buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_recording() {
+        let dir = std::env::temp_dir().join(format!("wstunnel-pcap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tunnel.pcap");
+
+        let recorder = PcapRecorder::create(
+            &path,
+            Transport::Tcp,
+            "10.0.0.1:41000".parse().unwrap(),
+            "[::ffff:10.0.0.2]:443".parse().unwrap(),
+        )
+        .unwrap();
+        recorder.record(Direction::ToDestination, b"hello");
+        recorder.record(Direction::ToOrigin, b"world!");
+        drop(recorder);
+
+        let pcap = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&pcap[..4], &0xa1b2c3d4u32.to_le_bytes());
+        assert_eq!(&pcap[20..24], &LINKTYPE_RAW.to_le_bytes());
+
+        // Walk the packets: syn, syn-ack, ack, hello, world!, fin, fin
+        let mut packets = vec![];
+        let mut offset = 24;
+        while offset < pcap.len() {
+            let len = u32::from_le_bytes(pcap[offset + 8..offset + 12].try_into().unwrap()) as usize;
+            packets.push(&pcap[offset + 16..offset + 16 + len]);
+            offset += 16 + len;
+        }
+        assert_eq!(packets.len(), 7);
+
+        let hello = packets[3];
+        assert_eq!(hello[0], 0x45);
+        assert_eq!(&hello[12..16], &[10, 0, 0, 1]);
+        assert_eq!(&hello[16..20], &[10, 0, 0, 2]);
+        assert_eq!(&hello[40..], b"hello");
+        // the seq of the data follows the syn
+        assert_eq!(&hello[24..28], &1u32.to_be_bytes());
+
+        let world = packets[4];
+        assert_eq!(&world[20..22], &443u16.to_be_bytes());
+        assert_eq!(&world[28..32], &6u32.to_be_bytes()); // acks the syn and hello
+        assert_eq!(&world[40..], b"world!");
+    }
+}
@@ -0,0 +1,77 @@
+//! Release of wstunnel the client runs, sent in the [`CLIENT_VERSION_HEADER`] of the requests opening the tunnels, i.e:
+//! `10.5.2`. Only the `major.minor.patch` part is compared, a pre-release or build suffix is ignored
+use derive_more::{Display, Error};
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
+use std::str::FromStr;
+
+pub const CLIENT_VERSION_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-client-version");
+
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, PartialOrd, Ord)]
+#[display("{major}.{minor}.{patch}")]
+pub struct ClientVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+#[derive(Debug, Display, Error, Clone, PartialEq, Eq)]
+#[display("invalid version {_0:?}, expected MAJOR.MINOR.PATCH")]
+pub struct InvalidClientVersion(#[error(not(source))] String);
+
+impl FromStr for ClientVersion {
+    type Err = InvalidClientVersion;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidClientVersion(version.to_string());
+        let release = version.split(['-', '+']).next().unwrap_or_default();
+        let mut parts = release
+            .split('.')
+            .map(|part| part.parse::<u32>().map_err(|_| invalid()));
+        let version = Self {
+            major: parts.next().ok_or_else(invalid)??,
+            minor: parts.next().unwrap_or(Ok(0))?,
+            patch: parts.next().unwrap_or(Ok(0))?,
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(version)
+    }
+}
+
+/// Value of the [`CLIENT_VERSION_HEADER`] sent by this client
+pub fn client_version_header() -> HeaderValue {
+    HeaderValue::from_static(env!("CARGO_PKG_VERSION"))
+}
+
+/// Version told by the client, None if it is too old to tell it or if it is invalid
+pub fn decode_client_version(headers: &HeaderMap) -> Option<ClientVersion> {
+    headers.get(CLIENT_VERSION_HEADER)?.to_str().ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_client_version() {
+        let version = |major, minor, patch| ClientVersion { major, minor, patch };
+        assert_eq!("10.5.2".parse(), Ok(version(10, 5, 2)));
+        assert_eq!("10.5".parse(), Ok(version(10, 5, 0)));
+        assert_eq!("11".parse(), Ok(version(11, 0, 0)));
+        assert_eq!("10.6.0-rc.1+build".parse(), Ok(version(10, 6, 0)));
+        assert!("".parse::<ClientVersion>().is_err());
+        assert!("v10.5.2".parse::<ClientVersion>().is_err());
+        assert!("10.5.2.1".parse::<ClientVersion>().is_err());
+        assert!(version(10, 5, 2) < version(10, 10, 0));
+        assert_eq!(version(10, 5, 2).to_string(), "10.5.2");
+    }
+
+    #[test]
+    fn test_client_version_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(decode_client_version(&headers), None);
+        headers.insert(CLIENT_VERSION_HEADER, client_version_header());
+        assert_eq!(decode_client_version(&headers), env!("CARGO_PKG_VERSION").parse().ok());
+    }
+}
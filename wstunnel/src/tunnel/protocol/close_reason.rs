@@ -0,0 +1,261 @@
+//! Reason a side closed a tunnel, told to the other side when both have the `error-codes` capability.
+//!
+//! A side whose local stream failed, i.e: a destination that reset its connection, closes the tunnel with the reason
+//! instead of a clean close, and the other side resets its own local stream, so the application at each end sees the
+//! failure instead of the clean end of the stream. A tunnel closed by the idle timeout is told too, but ends cleanly.
+//! The reason is told:
+//! - websocket: as the code of the close frame followed by its name. The code is [`WS_CLOSE_CODE_BASE`] + the code of
+//!   the reason by default, in the range of the codes private to the applications, or the one set for the reason with
+//!   --websocket-close-code, see [`WsCloseCodes`]. The reason is read from the name, and from the code without it
+//! - http2/grpc: in the [`CLOSE_REASON_HEADER`] of the trailers ending the body of the stream, by its name
+//!
+//! The other transports, and the tunnels to older peers, close the tunnel cleanly instead
+use crate::tunnel::protocol::integrity_record::IntegrityError;
+use derive_more::Error;
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::str::FromStr;
+
+pub const CLOSE_REASON_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-close-reason");
+/// Close code of a websocket closed with the reason of code 0
+pub const WS_CLOSE_CODE_BASE: u16 = 4000;
+
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CloseReason {
+    /// The local streams ended cleanly
+    Normal = 0,
+    /// The local stream was reset, or written after its peer was gone
+    Reset = 1,
+    /// The keepalive of the local stream gave up on its peer
+    #[serde(rename = "timeout")]
+    TimedOut = 2,
+    /// Any other failure of the local stream
+    Error = 3,
+    /// The data of the tunnel was altered in between, see [`IntegrityError`]
+    Corrupted = 4,
+    /// No data went through the tunnel for its idle timeout
+    Idle = 5,
+}
+
+impl CloseReason {
+    const ALL: [Self; 6] = [
+        Self::Normal,
+        Self::Reset,
+        Self::TimedOut,
+        Self::Error,
+        Self::Corrupted,
+        Self::Idle,
+    ];
+
+    pub const fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Reset => "reset",
+            Self::TimedOut => "timeout",
+            Self::Error => "error",
+            Self::Corrupted => "corrupted",
+            Self::Idle => "idle",
+        }
+    }
+
+    /// Whether the other side resets its local stream when told this reason. The tunnels closed on purpose end cleanly
+    pub const fn is_failure(self) -> bool {
+        !matches!(self, Self::Normal | Self::Idle)
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|reason| reason.code() == code)
+    }
+
+    /// Reason of the failure of a local stream with this error, the one it carries if it was made from a reason
+    pub fn of_io_error(err: &io::Error) -> Self {
+        if let Some(reason) = err.get_ref().and_then(|err| err.downcast_ref::<Self>()) {
+            return *reason;
+        }
+        match err.kind() {
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe => {
+                Self::Reset
+            }
+            io::ErrorKind::TimedOut => Self::TimedOut,
+            io::ErrorKind::InvalidData if err.get_ref().is_some_and(|err| err.is::<IntegrityError>()) => {
+                Self::Corrupted
+            }
+            _ => Self::Error,
+        }
+    }
+
+    /// Error of the transport of a tunnel closed by the peer for this reason
+    pub fn into_io_error(self) -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, self)
+    }
+
+    /// Reason the peer closed the tunnel, if the transport failed with the error of [`Self::into_io_error`]
+    pub fn from_io_error(err: &io::Error) -> Option<Self> {
+        if err.kind() != io::ErrorKind::ConnectionReset {
+            return None;
+        }
+        err.get_ref()?.downcast_ref::<Self>().copied()
+    }
+
+    pub const fn ws_close_code(self) -> u16 {
+        WS_CLOSE_CODE_BASE + self.code() as u16
+    }
+
+    /// Reason of the payload of a websocket close frame, None for the close frames that do not tell one
+    pub fn decode_ws_close(payload: &[u8]) -> Option<Self> {
+        let code = ws_close_code(payload)?;
+        if let Some(reason) = std::str::from_utf8(&payload[2..])
+            .ok()
+            .and_then(|name| name.parse().ok())
+        {
+            return Some(reason);
+        }
+        Self::from_code(u8::try_from(code.checked_sub(WS_CLOSE_CODE_BASE)?).ok()?)
+    }
+
+    /// Tell the reason in the trailers ending an http2 stream
+    pub fn encode_trailer(self, trailers: &mut HeaderMap) {
+        trailers.insert(CLOSE_REASON_HEADER, HeaderValue::from_static(self.name()));
+    }
+
+    /// Reason told in the trailers of an http2 stream, None if they end it cleanly
+    pub fn decode_trailers(trailers: &HeaderMap) -> Option<Self> {
+        trailers.get(CLOSE_REASON_HEADER)?.to_str().ok()?.parse().ok()
+    }
+}
+
+/// Code of the payload of a websocket close frame, None if it has none
+pub fn ws_close_code(payload: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(payload.get(..2)?.try_into().ok()?))
+}
+
+/// Code of the websocket close frames telling each reason, so the proxies in between and the logs of the peer tell the
+/// reasons apart with the codes they expect
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WsCloseCodes([u16; CloseReason::ALL.len()]);
+
+impl WsCloseCodes {
+    /// Codes that can be set: the ones for the libraries and the ones private to the applications
+    pub const VALID_CODES: std::ops::RangeInclusive<u16> = 3000..=4999;
+
+    pub fn code(&self, reason: CloseReason) -> u16 {
+        self.0[reason.code() as usize]
+    }
+
+    /// The code must be in [`Self::VALID_CODES`], and a normal close is always told with the normal close code
+    pub fn set(&mut self, reason: CloseReason, code: u16) {
+        debug_assert!(reason != CloseReason::Normal && Self::VALID_CODES.contains(&code));
+        self.0[reason.code() as usize] = code;
+    }
+}
+
+impl Default for WsCloseCodes {
+    fn default() -> Self {
+        Self(CloseReason::ALL.map(CloseReason::ws_close_code))
+    }
+}
+
+/// The default codes, with the ones set by --websocket-close-code
+impl FromIterator<(CloseReason, u16)> for WsCloseCodes {
+    fn from_iter<T: IntoIterator<Item = (CloseReason, u16)>>(iter: T) -> Self {
+        let mut codes = Self::default();
+        for (reason, code) in iter {
+            codes.set(reason, code);
+        }
+        codes
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CloseReason {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|reason| reason.name() == s).ok_or(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_reason() {
+        for reason in CloseReason::ALL {
+            assert_eq!(CloseReason::from_code(reason.code()), Some(reason));
+            assert_eq!(reason.to_string().parse(), Ok(reason));
+            assert_eq!(CloseReason::from_io_error(&reason.into_io_error()), Some(reason));
+
+            let mut payload = reason.ws_close_code().to_be_bytes().to_vec();
+            payload.extend_from_slice(reason.to_string().as_bytes());
+            assert_eq!(CloseReason::decode_ws_close(&payload), Some(reason));
+
+            let mut trailers = HeaderMap::new();
+            reason.encode_trailer(&mut trailers);
+            assert_eq!(CloseReason::decode_trailers(&trailers), Some(reason));
+        }
+        assert_eq!(CloseReason::decode_trailers(&HeaderMap::new()), None);
+        assert_eq!(CloseReason::from_code(6), None);
+        assert_eq!(CloseReason::decode_ws_close(&1000u16.to_be_bytes()), None);
+        assert_eq!(CloseReason::decode_ws_close(&4006u16.to_be_bytes()), None);
+        assert_eq!(CloseReason::decode_ws_close(&[0x0f]), None);
+        assert_eq!(CloseReason::decode_ws_close(&[]), None);
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(CloseReason::of_io_error(&reset), CloseReason::Reset);
+        assert_eq!(CloseReason::from_io_error(&reset), None);
+        assert_eq!(CloseReason::of_io_error(&io::ErrorKind::TimedOut.into()), CloseReason::TimedOut);
+        assert_eq!(CloseReason::of_io_error(&io::ErrorKind::Other.into()), CloseReason::Error);
+        assert_eq!(CloseReason::of_io_error(&io::ErrorKind::InvalidData.into()), CloseReason::Error);
+        let corrupted = IntegrityError {
+            received: 2,
+            actual: 0,
+            sent: 2,
+            expected: 1,
+        };
+        assert_eq!(
+            CloseReason::of_io_error(&io::Error::new(io::ErrorKind::InvalidData, corrupted)),
+            CloseReason::Corrupted
+        );
+        assert_eq!(
+            CloseReason::of_io_error(&io::Error::other(CloseReason::Idle)),
+            CloseReason::Idle
+        );
+        assert!(CloseReason::Reset.is_failure());
+        assert!(!CloseReason::Idle.is_failure());
+    }
+
+    #[test]
+    fn test_ws_close_codes() {
+        let mut codes = WsCloseCodes::default();
+        assert_eq!(codes.code(CloseReason::Normal), 4000);
+        assert_eq!(codes.code(CloseReason::Idle), 4005);
+
+        // The reason of a code set for it is told by its name
+        codes.set(CloseReason::Idle, 4408);
+        assert_eq!(codes.code(CloseReason::Idle), 4408);
+        assert_eq!(WsCloseCodes::from_iter([(CloseReason::Idle, 4408)]), codes);
+        let mut payload = codes.code(CloseReason::Idle).to_be_bytes().to_vec();
+        payload.extend_from_slice(b"idle");
+        assert_eq!(ws_close_code(&payload), Some(4408));
+        assert_eq!(CloseReason::decode_ws_close(&payload), Some(CloseReason::Idle));
+        assert_eq!(CloseReason::decode_ws_close(&4408u16.to_be_bytes()), None);
+
+        // Close frames of the proxies in between tell no reason
+        let mut going_away = 1001u16.to_be_bytes().to_vec();
+        going_away.extend_from_slice(b"Going Away");
+        assert_eq!(CloseReason::decode_ws_close(&going_away), None);
+    }
+}
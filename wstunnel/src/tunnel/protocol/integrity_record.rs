@@ -0,0 +1,254 @@
+//! Records of a tunnel whose data is checked end to end, when the client asked for it.
+//!
+//! The client asks for it with the interval in bytes in the [`INTEGRITY_HEADER`] of its request, and a server checking
+//! the tunnel answers the interval it uses in the same header. Each side then sends its data in records, in both
+//! directions, instead of as is:
+//! ```text
+//! DATA:     tag 0: u8 | length: u16 | data: [u8; length]
+//! CHECKSUM: tag 1: u8 | offset: u64 | checksum: u32       adler-32 of the first `offset` bytes of data sent
+//! ```
+//! A checksum is sent every interval bytes of data, and once more before the end of the stream. The receiver compares it
+//! with the checksum of the data it received, and aborts the tunnel with an [`IntegrityError`] if they differ, i.e: a
+//! middlebox in between altered the data. Integers are big endian. The early data sent along with the request is not
+//! checked, it goes to the destination before the records
+use crate::tunnel::protocol::ProtocolError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use derive_more::{Display, Error};
+use hyper::http::HeaderName;
+
+pub const INTEGRITY_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-integrity");
+/// Length of the data of a record
+pub const MAX_DATA_LEN: usize = u16::MAX as usize;
+
+const DATA: u8 = 0;
+const CHECKSUM: u8 = 1;
+const CHECKSUM_LEN: usize = 1 + 8 + 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IntegrityRecord {
+    Data(Bytes),
+    Checksum { offset: u64, checksum: u32 },
+}
+
+impl IntegrityRecord {
+    /// The data of a record must not be longer than [`MAX_DATA_LEN`]
+    pub fn encode(&self, out: &mut BytesMut) {
+        match self {
+            Self::Data(data) => {
+                debug_assert!(data.len() <= MAX_DATA_LEN);
+                out.put_u8(DATA);
+                out.put_u16(data.len() as u16);
+                out.put_slice(data);
+            }
+            Self::Checksum { offset, checksum } => {
+                out.put_u8(CHECKSUM);
+                out.put_u64(*offset);
+                out.put_u32(*checksum);
+            }
+        }
+    }
+
+    /// Take the first record out of the bytes received so far, or None if it is not fully received yet
+    pub fn decode(buf: &mut BytesMut) -> Result<Option<Self>, ProtocolError> {
+        let Some(&tag) = buf.first() else {
+            return Ok(None);
+        };
+        let record = match tag {
+            DATA => {
+                let Some(mut header) = buf.get(1..3) else {
+                    return Ok(None);
+                };
+                let len = header.get_u16() as usize;
+                if buf.len() < 3 + len {
+                    return Ok(None);
+                }
+                buf.advance(3);
+                Self::Data(buf.split_to(len).freeze())
+            }
+            CHECKSUM => {
+                if buf.len() < CHECKSUM_LEN {
+                    return Ok(None);
+                }
+                buf.advance(1);
+                Self::Checksum {
+                    offset: buf.get_u64(),
+                    checksum: buf.get_u32(),
+                }
+            }
+            tag => return Err(ProtocolError::UnknownIntegrityRecord(tag)),
+        };
+
+        Ok(Some(record))
+    }
+}
+
+/// Adler-32 of the data of one direction of a tunnel, updated as it goes through
+#[derive(Clone, Copy, Debug)]
+pub struct Checksum {
+    a: u32,
+    b: u32,
+    len: u64,
+}
+
+impl Checksum {
+    const MOD: u32 = 65521;
+    /// Bytes that can be summed before the sums overflow a u32
+    const NMAX: usize = 5552;
+
+    pub const fn new() -> Self {
+        Self { a: 1, b: 0, len: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(Self::NMAX) {
+            for &byte in chunk {
+                self.a += u32::from(byte);
+                self.b += self.a;
+            }
+            self.a %= Self::MOD;
+            self.b %= Self::MOD;
+        }
+        self.len += data.len() as u64;
+    }
+
+    /// Bytes of data checksummed so far
+    pub const fn offset(&self) -> u64 {
+        self.len
+    }
+
+    pub const fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+
+    /// Compare with the checksum record sent by the peer
+    pub fn verify(&self, offset: u64, checksum: u32) -> Result<(), IntegrityError> {
+        if offset == self.len && checksum == self.value() {
+            return Ok(());
+        }
+        Err(IntegrityError {
+            received: self.len,
+            actual: self.value(),
+            sent: offset,
+            expected: checksum,
+        })
+    }
+}
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Data of a tunnel received different from what the peer sent
+#[derive(Clone, Copy, Debug, Display, Error, PartialEq, Eq)]
+#[display(
+    "tunnel data corrupted in transit, received {received} bytes with checksum {actual:08x} while the peer sent {sent} bytes with checksum {expected:08x}"
+)]
+pub struct IntegrityError {
+    pub received: u64,
+    pub actual: u32,
+    pub sent: u64,
+    pub expected: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn record() -> impl Strategy<Value = IntegrityRecord> {
+        prop_oneof![
+            proptest::collection::vec(any::<u8>(), 0..1024).prop_map(|data| IntegrityRecord::Data(Bytes::from(data))),
+            (any::<u64>(), any::<u32>()).prop_map(|(offset, checksum)| IntegrityRecord::Checksum { offset, checksum }),
+        ]
+    }
+
+    #[test]
+    fn test_decode_integrity_records() {
+        let mut buf = BytesMut::from(&[2][..]);
+        assert_eq!(IntegrityRecord::decode(&mut buf), Err(ProtocolError::UnknownIntegrityRecord(2)));
+
+        let mut buf = BytesMut::from(&[DATA, 0, 2, b'a', b'b', CHECKSUM, 0, 0, 0, 0, 0, 0, 0, 2, 0x01, 0x26, 0][..]);
+        assert_eq!(
+            IntegrityRecord::decode(&mut buf),
+            Ok(Some(IntegrityRecord::Data(Bytes::from_static(b"ab"))))
+        );
+        assert_eq!(IntegrityRecord::decode(&mut buf), Ok(None));
+        buf.put_u8(0xc4);
+        assert_eq!(
+            IntegrityRecord::decode(&mut buf),
+            Ok(Some(IntegrityRecord::Checksum {
+                offset: 2,
+                checksum: 0x0126_00c4
+            }))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let mut checksum = Checksum::new();
+        assert_eq!(checksum.value(), 1);
+        checksum.update(b"Wikipedia");
+        assert_eq!(checksum.value(), 0x11e6_0398);
+        assert_eq!(checksum.verify(9, 0x11e6_0398), Ok(()));
+        assert_eq!(
+            checksum.verify(9, 0x11e6_0399),
+            Err(IntegrityError {
+                received: 9,
+                actual: 0x11e6_0398,
+                sent: 9,
+                expected: 0x11e6_0399
+            })
+        );
+        assert!(checksum.verify(10, 0x11e6_0398).is_err());
+
+        // The sums are reduced before they overflow
+        let mut checksum = Checksum::new();
+        checksum.update(&[0xff; 100_000]);
+        assert_eq!(checksum.value(), 0x149a_302c);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_records_round_trip(records in proptest::collection::vec(record(), 0..16), split in any::<prop::sample::Index>()) {
+            let mut encoded = BytesMut::new();
+            for record in &records {
+                record.encode(&mut encoded);
+            }
+
+            // Received in two parts, cut anywhere
+            let split = split.index(encoded.len() + 1);
+            let mut buf = BytesMut::from(&encoded[..split]);
+            let mut decoded = vec![];
+            while let Some(record) = IntegrityRecord::decode(&mut buf).unwrap() {
+                decoded.push(record);
+            }
+            buf.extend_from_slice(&encoded[split..]);
+            while let Some(record) = IntegrityRecord::decode(&mut buf).unwrap() {
+                decoded.push(record);
+            }
+            prop_assert_eq!(decoded, records);
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn prop_checksum_is_rolling(data in proptest::collection::vec(any::<u8>(), 0..20_000), split in any::<prop::sample::Index>()) {
+            let mut whole = Checksum::new();
+            whole.update(&data);
+            let split = split.index(data.len() + 1);
+            let mut parts = Checksum::new();
+            parts.update(&data[..split]);
+            parts.update(&data[split..]);
+            prop_assert_eq!(parts.value(), whole.value());
+            prop_assert_eq!(parts.offset(), data.len() as u64);
+        }
+
+        #[test]
+        fn prop_decode_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let mut buf = BytesMut::from(&bytes[..]);
+            while let Ok(Some(_)) = IntegrityRecord::decode(&mut buf) {}
+        }
+    }
+}
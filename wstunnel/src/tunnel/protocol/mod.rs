@@ -0,0 +1,355 @@
+//! protocol - what the client and the server exchange to set up the tunnels, and the framing of the tunnels carried
+//! over a connection of their own. Every decoder here only works on bytes, so it can be fuzzed.
+//!
+//! # Tunnel request
+//! Each tunnel is asked for with a JWT, its signature is not checked. Its claims are a json object:
+//! ```text
+//! {
+//!   "id": "0190b4d2-...",       uuid of the tunnel, to follow it in the logs of both sides
+//!   "p":  {"Tcp": {...}},       protocol of the tunnel, LocalProtocol serialized by serde
+//!   "r":  "example.com",        host of the destination, a domain or an ip (in brackets for ipv6)
+//!   "rp": 443,                  port of the destination
+//!   "l":  "ci-job-1234",        optional, label to tag the tunnel with in the logs and the metrics
+//!   "v":  [1]                   optional, protocol versions the client speaks. [1] if missing
+//!   "c":  "mux,resume"          optional, capabilities of the client, unknown ones are ignored. None if missing
+//! }
+//! ```
+//! The JWT is sent:
+//! - websocket: in the `Sec-WebSocket-Protocol` header, as `v1, authorization.bearer.<jwt>`
+//! - http1/http2/grpc/ssh: in the `Cookie` header of the request opening the tunnel, sent over an ssh channel for ssh
+//! - dns/icmp: in the open request starting the datagram session
+//! - mux: as the payload of the OPEN frame of the stream, see [`mux_frame`]
+//!
+//! # Versions
+//! The server serves the tunnel with the highest version both sides speak, and rejects it if there is none. The http based
+//! transports answer the chosen version and the capabilities of the server in the [`PROTOCOL_HEADER`] of the response,
+//! i.e: `v=1; caps=mux,resume`. A server not sending it is older, and speaks 1 without telling its capabilities.
+//! A new version is needed when a side cannot ignore what the other sends, i.e: a new record or frame kind. New optional
+//! claims do not need one, as they are ignored by the sides that do not know them
+//!
+//! # Capabilities
+//! The optional features a side implements, that a tunnel may rely on:
+//! - `compression`: compressed tunnel data
+//! - `mux`: tunnels multiplexed over a single connection, see [`mux_frame`]
+//! - `resume`: tunnels surviving the loss of their connection, see [`resume_record`]
+//! - `error-codes`: the reason of a closed tunnel, see [`close_reason`]
+//! - `probe`: reverse tunnels probed by the server while they wait for a connection, see [`probe_record`]
+//! - `half-close`: the end of each direction of a tunnel told to the other side, see [Half-close](#half-close)
+//! - `integrity`: the data of a tunnel checksummed at both ends, to find out corruption in between, see [`integrity_record`]
+//!
+//! The client warns about the capabilities of its tunnel the server does not announce, so a tunnel failing on what an
+//! older server does not understand is explained in the logs
+//!
+//! # Half-close
+//! When both sides have the `half-close` capability, a side whose local stream has nothing more to send tells it to the
+//! other side, which shuts down the writes of its own local stream, i.e: a TCP FIN, and keeps forwarding the other
+//! direction. The tunnel is closed once both directions are done. The end of a direction is:
+//! - websocket: an empty binary frame, never sent otherwise
+//! - http2/grpc/http1: the end of the body of the stream
+//! - ssh: the eof of the channel
+//!
+//! It is only used for the tunnels carrying a byte stream, see [`carries_stream`], and not over the datagram
+//! transports. Without it, the end of a direction closes the whole tunnel
+//!
+//! # Error codes
+//! When both sides have the `error-codes` capability, a side whose local stream failed closes the tunnel with the
+//! reason, see [`close_reason`], and the other side resets its own local stream, i.e: a TCP RST instead of a FIN. A
+//! tunnel closed for being idle is told too, but the other side ends its local stream cleanly. Like half-close, it is only used for the tunnels carrying a byte stream
+//!
+//! # Client version
+//! The http based transports also send the release of the client, see [`client_version`], so a server can refuse the
+//! clients older than a security fix with --require-min-client-version
+//!
+//! # Framing
+//! - [`mux_frame`]: the streams of the tunnels sharing a single connection
+//! - [`resume_record`]: the records of the tunnels that survive the loss of their connection
+//! - [`probe_record`]: the records of the reverse tunnels probed while they wait for a connection
+//! - [`integrity_record`]: the records of the tunnels whose data is checked end to end
+pub mod client_version;
+pub mod close_reason;
+pub mod integrity_record;
+pub mod mux_frame;
+pub mod probe_record;
+pub mod resume_record;
+
+use crate::tunnel::LocalProtocol;
+use derive_more::{Display, Error};
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
+use std::fmt;
+
+/// Version spoken by the sides that do not tell theirs
+pub const DEFAULT_PROTOCOL_VERSION: u8 = 1;
+/// Versions this side speaks, from the oldest to the newest
+pub const PROTOCOL_VERSIONS: &[u8] = &[1];
+/// Version and capabilities of the server, in the response accepting a tunnel
+pub const PROTOCOL_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-protocol");
+/// Capabilities this side implements. Compression is only known by name, to tell it in the logs
+pub const CAPABILITIES: Capabilities = Capabilities::MUX
+    .union(Capabilities::RESUME)
+    .union(Capabilities::ERROR_CODES)
+    .union(Capabilities::PROBE)
+    .union(Capabilities::HALF_CLOSE)
+    .union(Capabilities::INTEGRITY);
+
+#[derive(Debug, Display, Error, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    #[display("no protocol version in common, the peer speaks {_0:?} and we speak {PROTOCOL_VERSIONS:?}")]
+    UnsupportedVersions(#[error(not(source))] Vec<u8>),
+    #[display("unknown mux frame kind {_0}")]
+    UnknownFrameKind(#[error(not(source))] u8),
+    #[display("mux frame of {_0} bytes is too big")]
+    FrameTooBig(#[error(not(source))] usize),
+    #[display("invalid resumable tunnel record {_0}")]
+    UnknownRecord(#[error(not(source))] u8),
+    #[display("invalid resumable tunnel record length {_0}")]
+    InvalidRecordLength(#[error(not(source))] usize),
+    #[display("invalid reverse tunnel probe record {_0}")]
+    UnknownProbeRecord(#[error(not(source))] u8),
+    #[display("invalid integrity checked tunnel record {_0}")]
+    UnknownIntegrityRecord(#[error(not(source))] u8),
+}
+
+/// Highest version spoken by both sides, given the versions the peer speaks if it told them
+pub fn negotiate_version(peer_versions: Option<&[u8]>) -> Result<u8, ProtocolError> {
+    let peer_versions = peer_versions.unwrap_or(&[DEFAULT_PROTOCOL_VERSION]);
+    PROTOCOL_VERSIONS
+        .iter()
+        .rev()
+        .find(|version| peer_versions.contains(version))
+        .copied()
+        .ok_or_else(|| ProtocolError::UnsupportedVersions(peer_versions.to_vec()))
+}
+
+/// Set of the optional features of a side
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    pub const COMPRESSION: Self = Self(1);
+    pub const MUX: Self = Self(1 << 1);
+    pub const RESUME: Self = Self(1 << 2);
+    pub const ERROR_CODES: Self = Self(1 << 3);
+    pub const PROBE: Self = Self(1 << 4);
+    pub const HALF_CLOSE: Self = Self(1 << 5);
+    pub const INTEGRITY: Self = Self(1 << 6);
+    const NAMES: [(Self, &'static str); 7] = [
+        (Self::COMPRESSION, "compression"),
+        (Self::MUX, "mux"),
+        (Self::RESUME, "resume"),
+        (Self::ERROR_CODES, "error-codes"),
+        (Self::PROBE, "probe"),
+        (Self::HALF_CLOSE, "half-close"),
+        (Self::INTEGRITY, "integrity"),
+    ];
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Capabilities of self that other does not have
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Comma separated names of the capabilities. The unknown ones come from a newer peer, and are ignored
+    pub fn parse(names: &str) -> Self {
+        names
+            .split(',')
+            .filter_map(|name| {
+                Self::NAMES
+                    .iter()
+                    .find(|(_, known)| *known == name.trim())
+                    .map(|(capability, _)| *capability)
+            })
+            .fold(Self::NONE, Self::union)
+    }
+
+    /// Capabilities the server must have to serve a tunnel of this protocol
+    pub fn required_by(protocol: &LocalProtocol) -> Self {
+        match protocol {
+            LocalProtocol::Mux => Self::MUX,
+            LocalProtocol::Tcp { resume: Some(_), .. } | LocalProtocol::ReverseTcp { resume: Some(_), .. } => {
+                Self::RESUME
+            }
+            _ => Self::NONE,
+        }
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name);
+        if let Some(name) = names.next() {
+            f.write_str(name)?;
+        }
+        for name in names {
+            write!(f, ",{name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a tunnel of this protocol carries the byte stream of its local side as is. The datagram tunnels carry
+/// messages, and the mux and resumable ones carry records of their own
+pub fn carries_stream(protocol: &LocalProtocol) -> bool {
+    match protocol {
+        LocalProtocol::Udp { .. }
+        | LocalProtocol::StdioUdp { .. }
+        | LocalProtocol::TProxyUdp { .. }
+        | LocalProtocol::ReverseUdp { .. }
+        | LocalProtocol::Mux => false,
+        LocalProtocol::Tcp { resume, .. } | LocalProtocol::ReverseTcp { resume, .. } => resume.is_none(),
+        LocalProtocol::Socks5 { resume, .. } | LocalProtocol::HttpProxy { resume, .. } => resume.is_none(),
+        _ => true,
+    }
+}
+
+/// How the ends of a tunnel of this protocol tell each other about the end of their local streams, half-close and
+/// error codes, given the capabilities of the peer. Only the tunnels that [`carries_stream`] have an end to tell
+pub fn close_capabilities(peer_capabilities: Capabilities, protocol: &LocalProtocol) -> Capabilities {
+    if !carries_stream(protocol) {
+        return Capabilities::NONE;
+    }
+    peer_capabilities.intersection(Capabilities::HALF_CLOSE.union(Capabilities::ERROR_CODES))
+}
+
+/// Value of the [`PROTOCOL_HEADER`], for the version serving the tunnel
+pub fn encode_protocol_header(version: u8) -> HeaderValue {
+    HeaderValue::from_str(&format!("v={version}; caps={CAPABILITIES}")).expect("bug: invalid protocol header")
+}
+
+/// Version and capabilities announced by the server, None if it is too old to announce them
+pub fn decode_protocol_header(headers: &HeaderMap) -> Option<(u8, Capabilities)> {
+    let header = headers.get(PROTOCOL_HEADER)?.to_str().ok()?;
+    let mut version = None;
+    let mut capabilities = Capabilities::NONE;
+    for param in header.split(';') {
+        match param.trim().split_once('=') {
+            Some(("v", value)) => version = value.parse().ok(),
+            Some(("caps", value)) => capabilities = Capabilities::parse(value),
+            _ => {}
+        }
+    }
+    Some((version?, capabilities))
+}
+
+/// Capabilities announced by the server in its response, none for the older servers
+pub fn server_capabilities(headers: &HeaderMap) -> Capabilities {
+    decode_protocol_header(headers).map_or(Capabilities::NONE, |(_, caps)| caps)
+}
+
+/// Capabilities the tunnel relies on that the server did not announce in its response
+pub fn missing_capabilities(headers: &HeaderMap, protocol: &LocalProtocol) -> Capabilities {
+    Capabilities::required_by(protocol).difference(server_capabilities(headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_negotiate_version() {
+        assert_eq!(negotiate_version(None), Ok(1));
+        assert_eq!(negotiate_version(Some(&[1])), Ok(1));
+        assert_eq!(negotiate_version(Some(&[1, 2, 3])), Ok(1));
+        assert_eq!(
+            negotiate_version(Some(&[2, 3])),
+            Err(ProtocolError::UnsupportedVersions(vec![2, 3]))
+        );
+        assert!(negotiate_version(Some(&[])).is_err());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = Capabilities::parse("mux, resume,zstd,probe,half-close,error-codes,integrity");
+        assert_eq!(capabilities, CAPABILITIES);
+        assert_eq!(capabilities.to_string(), "mux,resume,error-codes,probe,half-close,integrity");
+        assert_eq!(Capabilities::parse(""), Capabilities::NONE);
+        assert_eq!(Capabilities::NONE.to_string(), "");
+        assert!(CAPABILITIES.contains(Capabilities::MUX));
+        assert!(!CAPABILITIES.contains(Capabilities::COMPRESSION.union(Capabilities::MUX)));
+        assert_eq!(
+            CAPABILITIES.difference(Capabilities::MUX),
+            Capabilities::RESUME
+                .union(Capabilities::ERROR_CODES)
+                .union(Capabilities::PROBE)
+                .union(Capabilities::HALF_CLOSE)
+                .union(Capabilities::INTEGRITY)
+        );
+        assert_eq!(
+            CAPABILITIES.intersection(Capabilities::MUX.union(Capabilities::COMPRESSION)),
+            Capabilities::MUX
+        );
+    }
+
+    #[test]
+    fn test_close_capabilities() {
+        let tcp = LocalProtocol::Tcp {
+            proxy_protocol: false,
+            resume: None,
+            idle_timeout: None,
+            mirror: None,
+            balancing: None,
+            keepalive: None,
+        };
+        let stream = Capabilities::HALF_CLOSE.union(Capabilities::ERROR_CODES);
+        assert_eq!(close_capabilities(CAPABILITIES, &tcp), stream);
+        assert_eq!(close_capabilities(Capabilities::MUX, &tcp), Capabilities::NONE);
+        assert_eq!(
+            close_capabilities(Capabilities::HALF_CLOSE, &LocalProtocol::Sctp),
+            Capabilities::HALF_CLOSE
+        );
+        assert_eq!(
+            close_capabilities(CAPABILITIES, &LocalProtocol::Udp { timeout: None }),
+            Capabilities::NONE
+        );
+        assert_eq!(close_capabilities(CAPABILITIES, &LocalProtocol::Mux), Capabilities::NONE);
+    }
+
+    #[test]
+    fn test_protocol_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(decode_protocol_header(&headers), None);
+        assert_eq!(missing_capabilities(&headers, &LocalProtocol::Mux), Capabilities::MUX);
+        assert_eq!(missing_capabilities(&headers, &LocalProtocol::Sctp), Capabilities::NONE);
+
+        headers.insert(PROTOCOL_HEADER, encode_protocol_header(1));
+        assert_eq!(decode_protocol_header(&headers), Some((1, CAPABILITIES)));
+        assert_eq!(missing_capabilities(&headers, &LocalProtocol::Mux), Capabilities::NONE);
+
+        // A newer server, with capabilities unknown here
+        headers.insert(PROTOCOL_HEADER, HeaderValue::from_static("v=2; caps=resume,zstd; extra"));
+        assert_eq!(decode_protocol_header(&headers), Some((2, Capabilities::RESUME)));
+        assert_eq!(missing_capabilities(&headers, &LocalProtocol::Mux), Capabilities::MUX);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_negotiated_version_is_spoken_by_both(peer_versions in proptest::collection::vec(any::<u8>(), 0..8)) {
+            match negotiate_version(Some(&peer_versions)) {
+                Ok(version) => {
+                    prop_assert!(peer_versions.contains(&version));
+                    prop_assert!(PROTOCOL_VERSIONS.contains(&version));
+                }
+                Err(_) => prop_assert!(!peer_versions.iter().any(|version| PROTOCOL_VERSIONS.contains(version))),
+            }
+        }
+    }
+}
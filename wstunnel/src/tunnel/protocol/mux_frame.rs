@@ -0,0 +1,192 @@
+//! Frames of the streams multiplexed over a single connection with the peer.
+//!
+//! ```text
+//! stream id: u32 | kind: u8 | length: u32 | payload: [u8; length]       (big endian)
+//! ```
+//! | kind       | payload                                                                        |
+//! |------------|--------------------------------------------------------------------------------|
+//! | 0 OPEN     | jwt of the tunnel, followed by `\n` and the pre-shared key proof bound to it if any |
+//! | 1 ACCEPT   | empty                                                                          |
+//! | 2 DATA     | bytes of the stream, at most [`MAX_DATA_LEN`]                                  |
+//! | 3 WINDOW   | u32, bytes more the peer can send on the stream                                |
+//! | 4 FIN      | empty, no more data in this direction                                          |
+//! | 5 RESET    | empty, stream rejected or aborted                                              |
+//!
+//! The client opens the streams with odd ids, and the payload of a frame is never longer than [`MAX_FRAME_LEN`]
+use crate::tunnel::protocol::ProtocolError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+pub const HEADER_LEN: usize = 9;
+/// Biggest data frame sent
+pub const MAX_DATA_LEN: usize = 16 * 1024;
+/// Biggest frame accepted from the peer. Open frames carry the description of the tunnel, data frames MAX_DATA_LEN
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Ask the peer to open a stream, the payload describes the tunnel
+    Open,
+    Accept,
+    Data,
+    /// Grant the peer to send this many more bytes, as a u32 payload
+    Window,
+    /// No more data in this direction
+    Fin,
+    /// Stream rejected or aborted
+    Reset,
+}
+
+impl FrameKind {
+    pub const fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(Self::Open),
+            1 => Some(Self::Accept),
+            2 => Some(Self::Data),
+            3 => Some(Self::Window),
+            4 => Some(Self::Fin),
+            5 => Some(Self::Reset),
+            _ => None,
+        }
+    }
+
+    pub const fn as_u8(self) -> u8 {
+        match self {
+            Self::Open => 0,
+            Self::Accept => 1,
+            Self::Data => 2,
+            Self::Window => 3,
+            Self::Fin => 4,
+            Self::Reset => 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MuxFrame {
+    pub id: u32,
+    pub kind: FrameKind,
+    pub payload: Bytes,
+}
+
+impl MuxFrame {
+    pub const fn new(id: u32, kind: FrameKind) -> Self {
+        Self {
+            id,
+            kind,
+            payload: Bytes::new(),
+        }
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.id);
+        buf.put_u8(self.kind.as_u8());
+        buf.put_u32(self.payload.len() as u32);
+        buf.put_slice(&self.payload);
+    }
+
+    /// Take the first frame out of the bytes received so far, or None if it is not fully received yet
+    pub fn decode(buf: &mut BytesMut) -> Result<Option<Self>, ProtocolError> {
+        let Some(mut header) = buf.get(..HEADER_LEN) else {
+            return Ok(None);
+        };
+        let id = header.get_u32();
+        let kind = header.get_u8();
+        let kind = FrameKind::from_u8(kind).ok_or(ProtocolError::UnknownFrameKind(kind))?;
+        let len = header.get_u32() as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(ProtocolError::FrameTooBig(len));
+        }
+        if buf.len() < HEADER_LEN + len {
+            buf.reserve(HEADER_LEN + len - buf.len());
+            return Ok(None);
+        }
+
+        buf.advance(HEADER_LEN);
+        let payload = buf.split_to(len).freeze();
+        Ok(Some(Self { id, kind, payload }))
+    }
+}
+
+/// Payload of the open frame of a tunnel: its jwt, and the pre-shared key proof bound to it if any, on separate lines
+pub fn open_payload(tunnel_token: &str, psk_proof: Option<&str>) -> Bytes {
+    match psk_proof {
+        Some(psk_proof) => Bytes::from(format!("{tunnel_token}\n{psk_proof}")),
+        None => Bytes::from(tunnel_token.to_string()),
+    }
+}
+
+pub fn parse_open_payload(payload: &[u8]) -> Option<(&str, Option<&str>)> {
+    let payload = std::str::from_utf8(payload).ok()?;
+    match payload.split_once('\n') {
+        Some((tunnel_token, psk_proof)) => Some((tunnel_token, Some(psk_proof))),
+        None => Some((payload, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn frame() -> impl Strategy<Value = MuxFrame> {
+        (any::<u32>(), 0..=5u8, proptest::collection::vec(any::<u8>(), 0..1024)).prop_map(|(id, kind, payload)| {
+            MuxFrame {
+                id,
+                kind: FrameKind::from_u8(kind).unwrap(),
+                payload: Bytes::from(payload),
+            }
+        })
+    }
+
+    #[test]
+    fn test_decode_invalid_frames() {
+        let mut buf = BytesMut::from(&[0, 0, 0, 1, 6, 0, 0, 0, 0][..]);
+        assert_eq!(MuxFrame::decode(&mut buf), Err(ProtocolError::UnknownFrameKind(6)));
+
+        let mut buf = BytesMut::new();
+        buf.put_u32(1);
+        buf.put_u8(FrameKind::Data.as_u8());
+        buf.put_u32(MAX_FRAME_LEN as u32 + 1);
+        assert_eq!(MuxFrame::decode(&mut buf), Err(ProtocolError::FrameTooBig(MAX_FRAME_LEN + 1)));
+    }
+
+    #[test]
+    fn test_open_payload() {
+        assert_eq!(parse_open_payload(&open_payload("a.b.c", None)), Some(("a.b.c", None)));
+        assert_eq!(
+            parse_open_payload(&open_payload("a.b.c", Some("proof"))),
+            Some(("a.b.c", Some("proof")))
+        );
+        assert_eq!(parse_open_payload(&[0xff]), None);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_frames_round_trip(frames in proptest::collection::vec(frame(), 0..16), split in any::<prop::sample::Index>()) {
+            let mut encoded = BytesMut::new();
+            for frame in &frames {
+                frame.encode(&mut encoded);
+            }
+
+            // Received in two parts, cut anywhere
+            let split = split.index(encoded.len() + 1);
+            let mut buf = BytesMut::from(&encoded[..split]);
+            let mut decoded = vec![];
+            while let Some(frame) = MuxFrame::decode(&mut buf).unwrap() {
+                decoded.push(frame);
+            }
+            buf.extend_from_slice(&encoded[split..]);
+            while let Some(frame) = MuxFrame::decode(&mut buf).unwrap() {
+                decoded.push(frame);
+            }
+            prop_assert_eq!(decoded, frames);
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn prop_decode_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let mut buf = BytesMut::from(&bytes[..]);
+            while let Ok(Some(_)) = MuxFrame::decode(&mut buf) {}
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Records of a reverse tunnel waiting for a connection on the server, when the client asked to be probed.
+//!
+//! The client asks for it with the interval in seconds in the [`PROBE_HEADER`] of its request, and a server probing the
+//! tunnel answers it right away with the interval it uses in the same header, instead of when a connection arrives.
+//! The records below then go first on the tunnel, before its data:
+//! ```text
+//! PING: tag 0: u8                                        server -> client, answered right away with a PONG
+//! PONG: tag 1: u8                                        client -> server
+//! OPEN: tag 2: u8 | length: u16 | cookie: [u8; length]   server -> client, a connection arrived and its data follows
+//! ```
+//! The cookie is the jwt describing the destination of a dynamic reverse tunnel (socks5, http proxy), empty for the
+//! other ones. Integers are big endian. A side that got nothing from the other for [`MAX_MISSED_PROBES`] intervals
+//! closes the tunnel, so the server unbinds the listener of a client that is gone instead of waiting for its idle timeout
+use crate::tunnel::protocol::ProtocolError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hyper::http::HeaderName;
+
+pub const PROBE_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-probe");
+/// Intervals without a record from the peer before it is considered gone
+pub const MAX_MISSED_PROBES: u32 = 3;
+
+const PING: u8 = 0;
+const PONG: u8 = 1;
+const OPEN: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProbeRecord {
+    Ping,
+    Pong,
+    Open(Bytes),
+}
+
+impl ProbeRecord {
+    /// The cookie of an open record must not be longer than u16::MAX
+    pub fn encode(&self, out: &mut BytesMut) {
+        match self {
+            Self::Ping => out.put_u8(PING),
+            Self::Pong => out.put_u8(PONG),
+            Self::Open(cookie) => {
+                debug_assert!(cookie.len() <= u16::MAX as usize);
+                out.put_u8(OPEN);
+                out.put_u16(cookie.len() as u16);
+                out.put_slice(cookie);
+            }
+        }
+    }
+
+    /// Take the first record out of the bytes received so far, or None if it is not fully received yet
+    pub fn decode(buf: &mut BytesMut) -> Result<Option<Self>, ProtocolError> {
+        let Some(&tag) = buf.first() else {
+            return Ok(None);
+        };
+        let record = match tag {
+            PING => {
+                buf.advance(1);
+                Self::Ping
+            }
+            PONG => {
+                buf.advance(1);
+                Self::Pong
+            }
+            OPEN => {
+                let Some(mut header) = buf.get(1..3) else {
+                    return Ok(None);
+                };
+                let len = header.get_u16() as usize;
+                if buf.len() < 3 + len {
+                    return Ok(None);
+                }
+                buf.advance(3);
+                Self::Open(buf.split_to(len).freeze())
+            }
+            tag => return Err(ProtocolError::UnknownProbeRecord(tag)),
+        };
+
+        Ok(Some(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn record() -> impl Strategy<Value = ProbeRecord> {
+        prop_oneof![
+            Just(ProbeRecord::Ping),
+            Just(ProbeRecord::Pong),
+            proptest::collection::vec(any::<u8>(), 0..1024).prop_map(|cookie| ProbeRecord::Open(Bytes::from(cookie))),
+        ]
+    }
+
+    #[test]
+    fn test_decode_probe_records() {
+        let mut buf = BytesMut::from(&[3][..]);
+        assert_eq!(ProbeRecord::decode(&mut buf), Err(ProtocolError::UnknownProbeRecord(3)));
+
+        // The data of the tunnel follows the open record, it is left as is
+        let mut buf = BytesMut::from(&[PING, OPEN, 0, 2, b'a', b'b', b'd', b'a', b't', b'a'][..]);
+        assert_eq!(ProbeRecord::decode(&mut buf), Ok(Some(ProbeRecord::Ping)));
+        assert_eq!(
+            ProbeRecord::decode(&mut buf),
+            Ok(Some(ProbeRecord::Open(Bytes::from_static(b"ab"))))
+        );
+        assert_eq!(&buf[..], b"data");
+    }
+
+    proptest! {
+        #[test]
+        fn prop_records_round_trip(records in proptest::collection::vec(record(), 0..16), split in any::<prop::sample::Index>()) {
+            let mut encoded = BytesMut::new();
+            for record in &records {
+                record.encode(&mut encoded);
+            }
+
+            // Received in two parts, cut anywhere
+            let split = split.index(encoded.len() + 1);
+            let mut buf = BytesMut::from(&encoded[..split]);
+            let mut decoded = vec![];
+            while let Some(record) = ProbeRecord::decode(&mut buf).unwrap() {
+                decoded.push(record);
+            }
+            buf.extend_from_slice(&encoded[split..]);
+            while let Some(record) = ProbeRecord::decode(&mut buf).unwrap() {
+                decoded.push(record);
+            }
+            prop_assert_eq!(decoded, records);
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn prop_decode_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let mut buf = BytesMut::from(&bytes[..]);
+            while let Ok(Some(_)) = ProbeRecord::decode(&mut buf) {}
+        }
+    }
+}
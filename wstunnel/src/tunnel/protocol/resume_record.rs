@@ -0,0 +1,171 @@
+//! Records of the tcp tunnels that survive the loss of their connection with the peer.
+//!
+//! ```text
+//! HELLO: tag 0: u8 | received bytes: u64 | fin received: u8       always the first record of a connection
+//! DATA:  tag 1: u8 | length: u32 | payload: [u8; length]            1 <= length <= MAX_DATA_LEN
+//! ACK:   tag 2: u8 | received bytes: u64 | fin received: u8
+//! FIN:   tag 3: u8                                                  no more data will be sent
+//! ```
+//! Integers are big endian, and `fin received` is 0 for false, anything else for true
+use crate::tunnel::protocol::ProtocolError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+const HELLO: u8 = 0;
+const DATA: u8 = 1;
+const ACK: u8 = 2;
+const FIN: u8 = 3;
+/// Biggest payload of a DATA record
+pub const MAX_DATA_LEN: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResumeRecord {
+    Hello { received: u64, fin_received: bool },
+    Data(Bytes),
+    Ack { received: u64, fin_received: bool },
+    Fin,
+}
+
+impl ResumeRecord {
+    pub fn encode(&self, out: &mut BytesMut) {
+        match self {
+            Self::Hello { received, fin_received } => put_hello(out, *received, *fin_received),
+            Self::Data(data) => put_data(out, data),
+            Self::Ack { received, fin_received } => put_ack(out, *received, *fin_received),
+            Self::Fin => put_fin(out),
+        }
+    }
+
+    /// Take the first record out of the bytes received so far, or None if it is not fully received yet
+    pub fn decode(buf: &mut BytesMut) -> Result<Option<Self>, ProtocolError> {
+        let Some(&tag) = buf.first() else {
+            return Ok(None);
+        };
+        let record = match tag {
+            HELLO | ACK => {
+                if buf.len() < 10 {
+                    return Ok(None);
+                }
+                buf.advance(1);
+                let received = buf.get_u64();
+                let fin_received = buf.get_u8() != 0;
+                if tag == HELLO {
+                    Self::Hello { received, fin_received }
+                } else {
+                    Self::Ack { received, fin_received }
+                }
+            }
+            DATA => {
+                let Some(mut header) = buf.get(1..5) else {
+                    return Ok(None);
+                };
+                let len = header.get_u32() as usize;
+                if len == 0 || len > MAX_DATA_LEN {
+                    return Err(ProtocolError::InvalidRecordLength(len));
+                }
+                if buf.len() < 5 + len {
+                    buf.reserve(5 + len - buf.len());
+                    return Ok(None);
+                }
+                buf.advance(5);
+                Self::Data(buf.split_to(len).freeze())
+            }
+            FIN => {
+                buf.advance(1);
+                Self::Fin
+            }
+            tag => return Err(ProtocolError::UnknownRecord(tag)),
+        };
+
+        Ok(Some(record))
+    }
+}
+
+pub fn put_hello(out: &mut BytesMut, received: u64, fin_received: bool) {
+    put_received(out, HELLO, received, fin_received);
+}
+
+pub fn put_ack(out: &mut BytesMut, received: u64, fin_received: bool) {
+    put_received(out, ACK, received, fin_received);
+}
+
+fn put_received(out: &mut BytesMut, tag: u8, received: u64, fin_received: bool) {
+    out.put_u8(tag);
+    out.put_u64(received);
+    out.put_u8(fin_received as u8);
+}
+
+/// `data` must not be empty nor longer than MAX_DATA_LEN
+pub fn put_data(out: &mut BytesMut, data: &[u8]) {
+    debug_assert!(!data.is_empty() && data.len() <= MAX_DATA_LEN);
+    out.put_u8(DATA);
+    out.put_u32(data.len() as u32);
+    out.put_slice(data);
+}
+
+pub fn put_fin(out: &mut BytesMut) {
+    out.put_u8(FIN);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn record() -> impl Strategy<Value = ResumeRecord> {
+        prop_oneof![
+            (any::<u64>(), any::<bool>())
+                .prop_map(|(received, fin_received)| ResumeRecord::Hello { received, fin_received }),
+            proptest::collection::vec(any::<u8>(), 1..1024).prop_map(|data| ResumeRecord::Data(Bytes::from(data))),
+            (any::<u64>(), any::<bool>())
+                .prop_map(|(received, fin_received)| ResumeRecord::Ack { received, fin_received }),
+            Just(ResumeRecord::Fin),
+        ]
+    }
+
+    #[test]
+    fn test_decode_invalid_records() {
+        let mut buf = BytesMut::from(&[4][..]);
+        assert_eq!(ResumeRecord::decode(&mut buf), Err(ProtocolError::UnknownRecord(4)));
+
+        let mut buf = BytesMut::from(&[DATA, 0, 0, 0, 0][..]);
+        assert_eq!(ResumeRecord::decode(&mut buf), Err(ProtocolError::InvalidRecordLength(0)));
+
+        let mut buf = BytesMut::new();
+        buf.put_u8(DATA);
+        buf.put_u32(MAX_DATA_LEN as u32 + 1);
+        assert_eq!(
+            ResumeRecord::decode(&mut buf),
+            Err(ProtocolError::InvalidRecordLength(MAX_DATA_LEN + 1))
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_records_round_trip(records in proptest::collection::vec(record(), 0..16), split in any::<prop::sample::Index>()) {
+            let mut encoded = BytesMut::new();
+            for record in &records {
+                record.encode(&mut encoded);
+            }
+
+            // Received in two parts, cut anywhere
+            let split = split.index(encoded.len() + 1);
+            let mut buf = BytesMut::from(&encoded[..split]);
+            let mut decoded = vec![];
+            while let Some(record) = ResumeRecord::decode(&mut buf).unwrap() {
+                decoded.push(record);
+            }
+            buf.extend_from_slice(&encoded[split..]);
+            while let Some(record) = ResumeRecord::decode(&mut buf).unwrap() {
+                decoded.push(record);
+            }
+            prop_assert_eq!(decoded, records);
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn prop_decode_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let mut buf = BytesMut::from(&bytes[..]);
+            while let Ok(Some(_)) = ResumeRecord::decode(&mut buf) {}
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! Tcp tunnels that survive the loss of the connection with the server.
+//! Both ends keep the bytes they sent until the peer acknowledges them, and when a new connection is established
+//! they exchange how much they received and replay the rest.
+//! The records exchanged on the connection are spec'd in [`crate::tunnel::protocol::resume_record`]
+use crate::tunnel::protocol::resume_record::{MAX_DATA_LEN, ResumeRecord, put_ack, put_data, put_fin, put_hello};
+use anyhow::anyhow;
+use bytes::{Buf, BytesMut};
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf};
+use tokio::select;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Size of the in-memory pipe standing between a resumable stream and the transport carrying it
+pub const TRANSPORT_PIPE_SIZE: usize = 2 * MAX_DATA_LEN;
+
+pub enum Outcome {
+    /// Both sides closed the stream and acknowledged it
+    Finished,
+    /// The connection was lost before the stream was finished
+    Disconnected,
+    /// A new connection has taken over the current one
+    Replaced(DuplexStream),
+}
+
+pub struct ResumableStream {
+    local_rx: Pin<Box<dyn AsyncRead + Send>>,
+    local_tx: Pin<Box<dyn AsyncWrite + Send>>,
+    max_buffer: usize,
+    // Bytes sent but not yet acknowledged by the peer, the first one being at offset `acked` of the stream
+    replay: BytesMut,
+    acked: u64,
+    local_eof: bool,
+    fin_acked: bool,
+    received: u64,
+    peer_fin: bool,
+}
+
+impl ResumableStream {
+    pub fn new(
+        local_rx: impl AsyncRead + Send + 'static,
+        local_tx: impl AsyncWrite + Send + 'static,
+        max_buffer: usize,
+    ) -> Self {
+        Self {
+            local_rx: Box::pin(local_rx),
+            local_tx: Box::pin(local_tx),
+            max_buffer: max_buffer.max(1),
+            replay: BytesMut::new(),
+            acked: 0,
+            local_eof: false,
+            fin_acked: false,
+            received: 0,
+            peer_fin: false,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.fin_acked && self.peer_fin
+    }
+
+    fn on_ack(&mut self, received: u64, fin_received: bool) -> anyhow::Result<()> {
+        let sent = self.acked + self.replay.len() as u64;
+        if received < self.acked || received > sent {
+            return Err(anyhow!(
+                "peer acknowledged {received} bytes while {} are acknowledged and {sent} sent",
+                self.acked
+            ));
+        }
+        if fin_received && !self.local_eof {
+            return Err(anyhow!("peer acknowledged a FIN that was never sent"));
+        }
+
+        self.replay.advance((received - self.acked) as usize);
+        self.acked = received;
+        self.fin_acked |= fin_received;
+        Ok(())
+    }
+
+    /// Drive the stream over the given connection until it is finished, lost, or replaced by one received on `next`
+    pub async fn run(
+        &mut self,
+        transport: DuplexStream,
+        mut next: Option<&mut mpsc::Receiver<DuplexStream>>,
+    ) -> anyhow::Result<Outcome> {
+        let (transport_rx, mut transport_tx) = tokio::io::split(transport);
+        let (records_tx, mut records) = mpsc::channel(16);
+        let reader = read_records(transport_rx, records_tx);
+        tokio::pin!(reader);
+        let mut reader_done = false;
+
+        let mut out = BytesMut::with_capacity(TRANSPORT_PIPE_SIZE);
+        put_hello(&mut out, self.received, self.peer_fin);
+        let mut handshake_done = false;
+        let mut ack_pending = false;
+        let mut read_buf = vec![0; MAX_DATA_LEN];
+
+        loop {
+            if ack_pending && records.is_empty() {
+                put_ack(&mut out, self.received, self.peer_fin);
+                ack_pending = false;
+            }
+
+            if self.is_finished() && out.is_empty() {
+                let _ = transport_tx.shutdown().await;
+                return Ok(Outcome::Finished);
+            }
+
+            let can_read_local = handshake_done
+                && !self.local_eof
+                && self.replay.len() < self.max_buffer
+                && out.len() < TRANSPORT_PIPE_SIZE;
+            select! {
+                biased;
+
+                Some(transport) = async { next.as_mut()?.recv().await }, if next.is_some() => {
+                    return Ok(Outcome::Replaced(transport));
+                }
+
+                ret = &mut reader, if !reader_done => {
+                    reader_done = true;
+                    ret?;
+                }
+
+                ret = transport_tx.write_buf(&mut out), if !out.is_empty() => {
+                    if let Err(err) = ret {
+                        debug!("cannot write to resumable tunnel connection: {err}");
+                        return Ok(Outcome::Disconnected);
+                    }
+                }
+
+                record = records.recv() => {
+                    let Some(record) = record else {
+                        return Ok(Outcome::Disconnected);
+                    };
+
+                    match record {
+                        ResumeRecord::Hello { received, fin_received } => {
+                            if handshake_done {
+                                return Err(anyhow!("unexpected HELLO on resumable tunnel"));
+                            }
+                            self.on_ack(received, fin_received)?;
+                            handshake_done = true;
+
+                            // Everything not acknowledged may have been lost with the previous connection
+                            for chunk in self.replay.chunks(MAX_DATA_LEN) {
+                                put_data(&mut out, chunk);
+                            }
+                            if self.local_eof && !self.fin_acked {
+                                put_fin(&mut out);
+                            }
+                        }
+                        _ if !handshake_done => return Err(anyhow!("resumable tunnel record received before HELLO")),
+                        ResumeRecord::Ack { received, fin_received } => self.on_ack(received, fin_received)?,
+                        ResumeRecord::Data(data) => {
+                            if self.peer_fin {
+                                return Err(anyhow!("resumable tunnel data received after FIN"));
+                            }
+                            self.local_tx.write_all(&data).await?;
+                            self.received += data.len() as u64;
+                            ack_pending = true;
+                        }
+                        ResumeRecord::Fin => {
+                            if !self.peer_fin {
+                                self.peer_fin = true;
+                                let _ = self.local_tx.shutdown().await;
+                            }
+                            ack_pending = true;
+                        }
+                    }
+                }
+
+                ret = self.local_rx.read(&mut read_buf[..MAX_DATA_LEN.min(self.max_buffer - self.replay.len())]), if can_read_local => {
+                    match ret {
+                        Ok(0) => {
+                            self.local_eof = true;
+                            put_fin(&mut out);
+                        }
+                        Ok(len) => {
+                            self.replay.extend_from_slice(&read_buf[..len]);
+                            put_data(&mut out, &read_buf[..len]);
+                        }
+                        Err(err) => {
+                            warn!("error while reading from local side of resumable tunnel: {err}");
+                            self.local_eof = true;
+                            put_fin(&mut out);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Decode the records of the connection until it is closed. Only an invalid record is an error
+async fn read_records(mut rx: ReadHalf<DuplexStream>, records: mpsc::Sender<ResumeRecord>) -> anyhow::Result<()> {
+    let mut buf = BytesMut::with_capacity(TRANSPORT_PIPE_SIZE);
+    loop {
+        while let Some(record) = ResumeRecord::decode(&mut buf)? {
+            if records.send(record).await.is_err() {
+                return Ok(());
+            }
+        }
+
+        match rx.read_buf(&mut buf).await {
+            Ok(0) => {
+                debug!("resumable tunnel connection closed");
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(err) => {
+                debug!("resumable tunnel connection closed: {err}");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// Forward bytes between the two connections, and drop them after `budget` bytes went through one direction
+    async fn relay(left: DuplexStream, right: DuplexStream, budget: Option<usize>) {
+        async fn copy(mut rx: ReadHalf<DuplexStream>, mut tx: impl AsyncWrite + Unpin, budget: Option<usize>) {
+            let mut buf = [0; 4096];
+            let mut total = 0;
+            loop {
+                let len = rx.read(&mut buf).await.unwrap_or(0);
+                if len == 0 || tx.write_all(&buf[..len]).await.is_err() {
+                    let _ = tx.shutdown().await;
+                    return;
+                }
+                total += len;
+                if budget.is_some_and(|budget| total >= budget) {
+                    return;
+                }
+            }
+        }
+
+        let (left_rx, left_tx) = tokio::io::split(left);
+        let (right_rx, right_tx) = tokio::io::split(right);
+        let left_to_right = copy(left_rx, right_tx, budget);
+        let right_to_left = copy(right_rx, left_tx, budget);
+        if budget.is_some() {
+            select! {
+                _ = left_to_right => {},
+                _ = right_to_left => {},
+            }
+        } else {
+            tokio::join!(left_to_right, right_to_left);
+        }
+    }
+
+    fn spawn_app(app: DuplexStream, data: Vec<u8>) -> tokio::task::JoinHandle<Vec<u8>> {
+        tokio::spawn(async move {
+            let (mut rx, mut tx) = tokio::io::split(app);
+            let writer = async {
+                tx.write_all(&data).await.unwrap();
+                tx.shutdown().await.unwrap();
+            };
+            let reader = async {
+                let mut received = vec![];
+                rx.read_to_end(&mut received).await.unwrap();
+                received
+            };
+            tokio::join!(writer, reader).1
+        })
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_disconnect() {
+        let data_a: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let data_b: Vec<u8> = (0..700_000u32).map(|i| (i % 241) as u8).collect();
+
+        let (app_a, local_a) = duplex(4096);
+        let (app_b, local_b) = duplex(4096);
+        let app_a = spawn_app(app_a, data_a.clone());
+        let app_b = spawn_app(app_b, data_b.clone());
+        let (rx, tx) = tokio::io::split(local_a);
+        let mut stream_a = ResumableStream::new(rx, tx, 64 * 1024);
+        let (rx, tx) = tokio::io::split(local_b);
+        let mut stream_b = ResumableStream::new(rx, tx, 64 * 1024);
+
+        for budget in [Some(100_000), Some(150_000), None] {
+            let (transport_a, relay_a) = duplex(8192);
+            let (transport_b, relay_b) = duplex(8192);
+            let (outcome_a, outcome_b, _) = tokio::join!(
+                stream_a.run(transport_a, None),
+                stream_b.run(transport_b, None),
+                relay(relay_a, relay_b, budget)
+            );
+            let finished = budget.is_none();
+            assert_eq!(matches!(outcome_a.unwrap(), Outcome::Finished), finished);
+            assert_eq!(matches!(outcome_b.unwrap(), Outcome::Finished), finished);
+        }
+
+        assert_eq!(app_b.await.unwrap(), data_a);
+        assert_eq!(app_a.await.unwrap(), data_b);
+    }
+
+    #[tokio::test]
+    async fn test_replace_connection() {
+        let (app, local) = duplex(4096);
+        let (rx, tx) = tokio::io::split(local);
+        let mut stream = ResumableStream::new(rx, tx, 1024);
+        let (next_tx, mut next_rx) = mpsc::channel(1);
+
+        let (transport, _peer) = duplex(1024);
+        let (replacement, _) = duplex(1024);
+        next_tx.send(replacement).await.unwrap();
+        assert!(matches!(
+            stream.run(transport, Some(&mut next_rx)).await.unwrap(),
+            Outcome::Replaced(_)
+        ));
+        drop(app);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::protocols::dns::DnsResolver;
+use crate::protocols::http_client;
+use crate::protocols::http_client::HttpClientConfig;
+use crate::somark::SoMark;
+use crate::tunnel::LocalProtocol;
+use anyhow::{Context, anyhow};
+use bytes::Bytes;
+use hyper::Method;
+use hyper::header::{CONTENT_TYPE, HeaderValue};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+use url::Url;
+
+/// External authority asked to validate every upgrade request, on top of the restrictions rules
+#[derive(Debug, Clone)]
+pub enum AuthHook {
+    /// Run the program with the request metadata as json on its stdin. Exit code 0 accepts the request
+    Command(PathBuf),
+    /// POST the request metadata as json to the url. A 2xx response accepts the request
+    Webhook(Url),
+}
+
+/// Metadata of an upgrade request sent to the auth hook
+#[derive(Debug, Serialize)]
+pub struct AuthHookRequest<'a> {
+    pub client_addr: SocketAddr,
+    pub path_prefix: &'a str,
+    pub client_certificate_cn: Option<&'a str>,
+    pub headers: BTreeMap<&'a str, &'a str>,
+    pub remote_protocol: &'a LocalProtocol,
+    pub remote_host: String,
+    pub remote_port: u16,
+}
+
+impl AuthHook {
+    /// Returns Ok(true) if the hook accepted the request
+    pub async fn authorize(
+        &self,
+        req: &AuthHookRequest<'_>,
+        timeout: Duration,
+        so_mark: SoMark,
+        dns_resolver: &DnsResolver,
+    ) -> anyhow::Result<bool> {
+        let payload = serde_json::to_vec(req).context("cannot serialize auth hook request")?;
+        let fut = async {
+            match self {
+                Self::Command(path) => run_command(path, payload).await,
+                Self::Webhook(url) => call_webhook(url, payload, so_mark, timeout, dns_resolver).await,
+            }
+        };
+
+        tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| anyhow!("auth hook did not answer after {}s", timeout.as_secs()))?
+    }
+}
+
+async fn run_command(path: &Path, payload: Vec<u8>) -> anyhow::Result<bool> {
+    let mut child = tokio::process::Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("cannot execute auth hook {path:?}"))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The program may exit without reading its stdin, only its exit code matters
+        if let Err(err) = stdin.write_all(&payload).await {
+            debug!("cannot write request to auth hook stdin: {err}");
+        }
+        // drop stdin to send EOF to the program
+    }
+
+    let status = child.wait().await?;
+    debug!("auth hook {path:?} exited with {status}");
+    Ok(status.success())
+}
+
+async fn call_webhook(
+    url: &Url,
+    payload: Vec<u8>,
+    so_mark: SoMark,
+    timeout: Duration,
+    dns_resolver: &DnsResolver,
+) -> anyhow::Result<bool> {
+    let http_cfg = HttpClientConfig {
+        so_mark,
+        timeout,
+        dns_resolver: dns_resolver.clone(),
+    };
+    let headers = [(CONTENT_TYPE, HeaderValue::from_static("application/json"))];
+    let (status, _) = http_client::request(&http_cfg, Method::POST, url, &headers, Bytes::from(payload)).await?;
+    debug!("auth hook responded with {status}");
+    Ok(status.is_success())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tunnel::AccessList;
+    use test_case::test_case;
+
+    #[test_case("/bin/true" => true ; "with accepting command")]
+    #[test_case("/bin/false" => false ; "with denying command")]
+    #[tokio::test]
+    async fn test_command_auth_hook(cmd: &str) -> bool {
+        let req = AuthHookRequest {
+            client_addr: "127.0.0.1:1234".parse().unwrap(),
+            path_prefix: "v1",
+            client_certificate_cn: None,
+            headers: BTreeMap::new(),
+            remote_protocol: &LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            },
+            remote_host: "localhost".to_string(),
+            remote_port: 80,
+        };
+
+        AuthHook::Command(PathBuf::from(cmd))
+            .authorize(&req, Duration::from_secs(5), SoMark::new(None), &DnsResolver::System)
+            .await
+            .unwrap()
+    }
+}
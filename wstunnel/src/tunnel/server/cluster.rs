@@ -0,0 +1,560 @@
+//! cluster - servers scaled horizontally behind a load balancer, sharing which of them holds the reverse tunnels.
+//! Each server announces its reverse tcp ports and http ingress virtual hosts to its peers, every few seconds, and
+//! forgets the ones of a peer that stopped announcing them. A server that does not hold a reverse tunnel forwards its
+//! incoming connections to the one holding it: it listens on the reverse tcp ports of its peers, and proxies the
+//! requests of their virtual hosts.
+//! The announcements are signed with the cluster secret, over a timestamp and a nonce, so a recorded one cannot be
+//! replayed to bring back the reverse tunnels of a peer that does not hold them anymore
+use crate::executor::TokioExecutorRef;
+use crate::protocols;
+use crate::protocols::dns::DnsResolver;
+use crate::protocols::http_client;
+use crate::protocols::http_client::HttpClientConfig;
+use crate::protocols::tls;
+use crate::protocols::tls::TlsFingerprint;
+use crate::somark::SoMark;
+use crate::source_bind::UNBOUND;
+use crate::tunnel::server::WsServer;
+use crate::tunnel::server::http_ingress::{local_vhosts, proxy, request_vhost};
+use crate::tunnel::server::service::RequestBody;
+use crate::tunnel::server::utils::HttpResponse;
+use crate::tunnel::transport::{PreSharedKey, ReplayCache};
+use ahash::{AHashMap, AHashSet};
+use anyhow::{Context, anyhow};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Either, Limited};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tracing::{Instrument, Span, debug, info, warn};
+use url::{Host, Url};
+
+/// Path the announcements of the peers are POSTed to
+const ANNOUNCE_PATH: &str = "/_wstunnel/cluster";
+/// Proof that the announcement comes from a server knowing the cluster secret, see [`PreSharedKey::cluster_proof`]
+const SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-cluster-signature");
+/// Set on the requests proxied to a peer, which must serve them itself
+const HOP_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-cluster-hop");
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+/// The reverse tunnels of a peer are forgotten once it missed this many announcements
+const ANNOUNCE_TTL: Duration = Duration::from_secs(15);
+const MAX_ANNOUNCE_LEN: usize = 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    /// Url the peers reach this server with
+    pub advertise: Url,
+    /// Urls of the other servers of the cluster
+    pub peers: Vec<Url>,
+    pub secret: String,
+}
+
+/// Reverse tunnels held by a server of the cluster
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Announcement {
+    node: Url,
+    ports: Vec<SocketAddr>,
+    vhosts: Vec<String>,
+}
+
+struct Peer {
+    announcement: Announcement,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Registry {
+    /// Bind addresses of the reverse tcp tunnels held by this server
+    local_ports: AHashSet<SocketAddr>,
+    peers: AHashMap<Url, Peer>,
+    /// Listeners forwarding the connections of the reverse tcp ports of the peers
+    forwarders: AHashMap<SocketAddr, JoinHandle<()>>,
+}
+
+/// State of the cluster seen by a server
+#[derive(Default)]
+pub(super) struct ClusterRegistry {
+    registry: Mutex<Registry>,
+    /// Nonces of the announcements accepted from the peers
+    replays: ReplayCache,
+}
+
+impl ClusterRegistry {
+    /// Take over the port of a reverse tcp tunnel from the peers, to listen on it for a client of this server
+    pub(super) async fn register_port(self: &Arc<Self>, bind: SocketAddr) -> PortRegistration {
+        let forwarder = {
+            let mut registry = self.registry.lock();
+            registry.local_ports.insert(bind);
+            registry.forwarders.remove(&bind)
+        };
+        if let Some(forwarder) = forwarder {
+            forwarder.abort();
+            // The listener of the forwarder must be closed before binding the port again
+            let _ = forwarder.await;
+        }
+        PortRegistration(self.clone(), bind)
+    }
+}
+
+/// Announced to the peers as long as the reverse tcp tunnel listens on its port
+pub(super) struct PortRegistration(Arc<ClusterRegistry>, SocketAddr);
+
+impl Drop for PortRegistration {
+    fn drop(&mut self) {
+        self.0.registry.lock().local_ports.remove(&self.1);
+    }
+}
+
+/// What the server must do with a request for the cluster
+pub(super) enum ClusterRequest {
+    /// Record the reverse tunnels of a peer
+    Announce,
+    /// Proxy the request to the peer holding its virtual host
+    Vhost(Url),
+}
+
+/// Whether the request is for the cluster, to be checked after the virtual hosts of the server
+pub(super) fn find_request<E: TokioExecutorRef, B>(server: &WsServer<E>, req: &Request<B>) -> Option<ClusterRequest> {
+    server.config.cluster.as_ref()?;
+    if req.uri().path() == ANNOUNCE_PATH && req.method() == Method::POST {
+        return Some(ClusterRequest::Announce);
+    }
+    if req.headers().contains_key(HOP_HEADER) {
+        return None;
+    }
+
+    let vhost = request_vhost(req)?;
+    server
+        .cluster
+        .registry
+        .lock()
+        .peers
+        .iter()
+        .find(|(_, peer)| peer.expires > Instant::now() && peer.announcement.vhosts.contains(&vhost))
+        .map(|(node, _)| ClusterRequest::Vhost(node.clone()))
+}
+
+pub(super) async fn serve_request<E: TokioExecutorRef>(
+    server: &WsServer<E>,
+    request: ClusterRequest,
+    client_addr: SocketAddr,
+    req: Request<impl RequestBody>,
+) -> HttpResponse {
+    let Some(cluster) = &server.config.cluster else {
+        return response(StatusCode::NOT_FOUND, "Not found");
+    };
+    let result = match request {
+        ClusterRequest::Announce => receive_announcement(server, cluster, req).await,
+        ClusterRequest::Vhost(node) => forward_request(server, &node, client_addr, req).await,
+    };
+    result.unwrap_or_else(|err| {
+        warn!("Cluster request failed: {err:?}");
+        response(StatusCode::BAD_GATEWAY, "Bad gateway")
+    })
+}
+
+fn response(status: StatusCode, body: &str) -> HttpResponse {
+    Response::builder()
+        .status(status)
+        .body(Either::Left(body.to_string()))
+        .unwrap()
+}
+
+async fn receive_announcement<E: TokioExecutorRef>(
+    server: &WsServer<E>,
+    cluster: &ClusterConfig,
+    req: Request<impl RequestBody>,
+) -> anyhow::Result<HttpResponse> {
+    let Some(proof) = req.headers().get(SIGNATURE_HEADER).and_then(|h| h.to_str().ok()) else {
+        warn!("Rejecting cluster announcement without signature");
+        return Ok(response(StatusCode::FORBIDDEN, "Forbidden"));
+    };
+    let proof = proof.to_string();
+
+    let body = Limited::new(req.into_body(), MAX_ANNOUNCE_LEN)
+        .collect()
+        .await
+        .map_err(|err| anyhow!("cannot read cluster announcement: {err}"))?
+        .to_bytes();
+    let body = std::str::from_utf8(&body).context("invalid cluster announcement")?;
+    let psk = PreSharedKey::new(cluster.secret.as_bytes());
+    if let Err(err) = psk.verify_cluster_proof(&proof, body, &server.cluster.replays) {
+        warn!("Rejecting cluster announcement: {err:#}");
+        return Ok(response(StatusCode::FORBIDDEN, "Forbidden"));
+    }
+    let announcement: Announcement = serde_json::from_str(body).context("invalid cluster announcement")?;
+    let node = announcement.node.clone();
+
+    {
+        let mut registry = server.cluster.registry.lock();
+        let expires = Instant::now() + ANNOUNCE_TTL;
+        match registry.peers.get_mut(&node) {
+            Some(peer) if peer.announcement == announcement => peer.expires = expires,
+            _ => {
+                debug!(
+                    "Peer {node} holds the reverse ports {:?} and virtual hosts {:?}",
+                    announcement.ports, announcement.vhosts
+                );
+                registry.peers.insert(node, Peer { announcement, expires });
+            }
+        }
+    }
+    reconcile_forwarders(server);
+
+    Ok(response(StatusCode::OK, "OK"))
+}
+
+/// Listen on the reverse tcp ports of the peers, and stop listening on the ones they do not hold anymore
+fn reconcile_forwarders<E: TokioExecutorRef>(server: &WsServer<E>) {
+    let now = Instant::now();
+    let mut registry = server.cluster.registry.lock();
+    registry.peers.retain(|node, peer| {
+        let alive = peer.expires > now;
+        if !alive {
+            info!("Peer {node} stopped announcing its reverse tunnels, forgetting them");
+        }
+        alive
+    });
+
+    let mut wanted: AHashMap<SocketAddr, Url> = AHashMap::new();
+    for (node, peer) in &registry.peers {
+        for port in &peer.announcement.ports {
+            if !registry.local_ports.contains(port) {
+                wanted.entry(*port).or_insert_with(|| node.clone());
+            }
+        }
+    }
+
+    registry.forwarders.retain(|bind, forwarder| {
+        let keep = wanted.contains_key(bind);
+        if !keep {
+            forwarder.abort();
+        }
+        keep
+    });
+    for (bind, node) in wanted {
+        if registry.forwarders.contains_key(&bind) {
+            continue;
+        }
+        let forwarder = forward_port(
+            bind,
+            node,
+            server.config.socket_so_mark,
+            server.config.timeout_connect,
+            server.config.dns_resolver.clone(),
+        );
+        registry
+            .forwarders
+            .insert(bind, tokio::spawn(forwarder.instrument(Span::current())));
+    }
+}
+
+async fn forward_port(bind: SocketAddr, node: Url, so_mark: SoMark, timeout: Duration, dns_resolver: DnsResolver) {
+    let Some(host) = node.host().map(|host| host.to_owned()) else {
+        warn!("Cannot forward reverse port {bind} to peer {node} without host");
+        return;
+    };
+    let listener = match protocols::tcp::bind_listener(bind, None) {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("Cannot listen on reverse port {bind} of peer {node}: {err}");
+            return;
+        }
+    };
+    info!("Forwarding reverse port {bind} to peer {node}");
+
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("Error while accepting connection on reverse port {bind} of peer {node}: {err:?}");
+                continue;
+            }
+        };
+        let host = host.clone();
+        let dns_resolver = dns_resolver.clone();
+        tokio::spawn(
+            async move {
+                let peer =
+                    protocols::tcp::connect(&host, bind.port(), so_mark, &UNBOUND, false, timeout, &dns_resolver);
+                match peer.await {
+                    Ok(mut peer) => {
+                        let _ = tokio::io::copy_bidirectional(&mut stream, &mut peer).await;
+                    }
+                    Err(err) => warn!("Cannot forward connection to peer {host}:{}: {err:?}", bind.port()),
+                }
+            }
+            .instrument(Span::current()),
+        );
+    }
+}
+
+/// Proxy the request to the peer holding its virtual host, which serves it as any request of its own
+async fn forward_request<E: TokioExecutorRef>(
+    server: &WsServer<E>,
+    node: &Url,
+    client_addr: SocketAddr,
+    mut req: Request<impl RequestBody>,
+) -> anyhow::Result<HttpResponse> {
+    let host = node
+        .host()
+        .with_context(|| format!("peer {node} has no host"))?
+        .to_owned();
+    let port = node.port_or_known_default().unwrap_or(80);
+    let stream = protocols::tcp::connect(
+        &host,
+        port,
+        server.config.socket_so_mark,
+        &UNBOUND,
+        false,
+        server.config.timeout_connect,
+        &server.config.dns_resolver,
+    )
+    .await?;
+    req.headers_mut().insert(HOP_HEADER, HeaderValue::from_static("1"));
+    let tls = server.config.tls.is_some();
+
+    match node.scheme() {
+        "https" => {
+            let tls_connector = tls::tls_connector(
+                true,
+                vec![b"http/1.1".to_vec()],
+                true,
+                TlsFingerprint::default(),
+                None,
+                None,
+                None,
+            )?;
+            let server_name = match &host {
+                Host::Domain(domain) => ServerName::try_from(domain.clone())?,
+                Host::Ipv4(ip) => ServerName::from(IpAddr::V4(*ip)),
+                Host::Ipv6(ip) => ServerName::from(IpAddr::V6(*ip)),
+            };
+            let stream = tls_connector.connect(server_name, stream).await?;
+            proxy(&server.executor, stream, client_addr, tls, req).await
+        }
+        _ => proxy(&server.executor, stream, client_addr, tls, req).await,
+    }
+}
+
+/// Announce the reverse tunnels of this server to its peers, forever
+pub(super) async fn run_announcer<E: TokioExecutorRef>(server: WsServer<E>) {
+    let Some(cluster) = server.config.cluster.clone() else {
+        return;
+    };
+    let http_cfg = HttpClientConfig {
+        so_mark: server.config.socket_so_mark,
+        timeout: ANNOUNCE_INTERVAL,
+        dns_resolver: server.config.dns_resolver.clone(),
+    };
+    let psk = PreSharedKey::new(cluster.secret.as_bytes());
+
+    let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        reconcile_forwarders(&server);
+
+        let announcement = Announcement {
+            node: cluster.advertise.clone(),
+            ports: server.cluster.registry.lock().local_ports.iter().copied().collect(),
+            vhosts: local_vhosts(),
+        };
+        let body = match serde_json::to_string(&announcement) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Cannot serialize cluster announcement: {err}");
+                continue;
+            }
+        };
+        // Each peer keeps its own nonces, so they can all be sent the same proof
+        let headers = [
+            (hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/json")),
+            (
+                SIGNATURE_HEADER,
+                HeaderValue::from_str(&psk.cluster_proof(&body)).unwrap_or(HeaderValue::from_static("")),
+            ),
+        ];
+        let body = Bytes::from(body);
+
+        let announces = cluster.peers.iter().map(|peer| {
+            let body = body.clone();
+            let http_cfg = &http_cfg;
+            let headers = &headers;
+            async move {
+                let Ok(url) = peer.join(ANNOUNCE_PATH) else {
+                    return;
+                };
+                match http_client::request(http_cfg, Method::POST, &url, headers, body).await {
+                    Ok((status, _)) if status.is_success() => {}
+                    Ok((status, _)) => warn!("Peer {peer} refused the cluster announcement: {status}"),
+                    Err(err) => debug!("Cannot announce reverse tunnels to peer {peer}: {err:?}"),
+                }
+            }
+        });
+        futures_util::future::join_all(announces).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_integrations::{dns_resolver, server};
+    use http_body_util::Full;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const SECRET: &str = "s3cret";
+
+    fn cluster_server() -> WsServer {
+        let mut server = server(dns_resolver(), None);
+        Arc::get_mut(&mut server.config).unwrap().cluster = Some(ClusterConfig {
+            advertise: Url::parse("http://10.0.0.1:8080").unwrap(),
+            peers: vec![Url::parse("http://10.0.0.2:8080").unwrap()],
+            secret: SECRET.to_string(),
+        });
+        server
+    }
+
+    fn announce_request(announcement: &Announcement, secret: &str) -> Request<Full<Bytes>> {
+        let body = serde_json::to_string(announcement).unwrap();
+        Request::builder()
+            .method(Method::POST)
+            .uri(ANNOUNCE_PATH)
+            .header(SIGNATURE_HEADER, PreSharedKey::new(secret.as_bytes()).cluster_proof(&body))
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    }
+
+    async fn announce(server: &WsServer, req: Request<Full<Bytes>>) -> StatusCode {
+        let client_addr = "127.0.0.1:1234".parse().unwrap();
+        serve_request(server, ClusterRequest::Announce, client_addr, req)
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_find_vhost_of_peer() {
+        let server_without_cluster = server(dns_resolver(), None);
+        let server = cluster_server();
+        let node = Url::parse("http://10.0.0.2:8080").unwrap();
+        server.cluster.registry.lock().peers.insert(
+            node.clone(),
+            Peer {
+                announcement: Announcement {
+                    node: node.clone(),
+                    ports: vec![],
+                    vhosts: vec!["myapp.cluster.example.com".to_string()],
+                },
+                expires: Instant::now() + ANNOUNCE_TTL,
+            },
+        );
+        let req = |host: &str, hop: bool| {
+            let mut req = Request::builder().uri("/").header(hyper::header::HOST, host);
+            if hop {
+                req = req.header(HOP_HEADER, "1");
+            }
+            req.body(()).unwrap()
+        };
+
+        assert!(matches!(
+            find_request(&server, &req("MyApp.cluster.example.com:443", false)),
+            Some(ClusterRequest::Vhost(peer)) if peer == node
+        ));
+        assert!(find_request(&server, &req("myapp.cluster.example.com", true)).is_none());
+        assert!(find_request(&server, &req("other.cluster.example.com", false)).is_none());
+        assert!(find_request(&server_without_cluster, &req("myapp.cluster.example.com", false)).is_none());
+
+        let announce = Request::builder()
+            .method(Method::POST)
+            .uri(ANNOUNCE_PATH)
+            .body(())
+            .unwrap();
+        assert!(matches!(find_request(&server, &announce), Some(ClusterRequest::Announce)));
+    }
+
+    #[tokio::test]
+    async fn test_signed_announcements() {
+        let server = cluster_server();
+        let node = Url::parse("http://10.0.0.2:8080").unwrap();
+        let announcement = Announcement {
+            node: node.clone(),
+            ports: vec![],
+            vhosts: vec!["myapp.cluster.example.com".to_string()],
+        };
+
+        assert_eq!(
+            announce(&server, announce_request(&announcement, "other secret")).await,
+            StatusCode::FORBIDDEN
+        );
+        let mut unsigned = announce_request(&announcement, SECRET);
+        unsigned.headers_mut().remove(SIGNATURE_HEADER);
+        assert_eq!(announce(&server, unsigned).await, StatusCode::FORBIDDEN);
+        assert!(server.cluster.registry.lock().peers.is_empty());
+
+        let req = announce_request(&announcement, SECRET);
+        let replayed = Request::builder()
+            .method(Method::POST)
+            .uri(ANNOUNCE_PATH)
+            .header(SIGNATURE_HEADER, req.headers()[SIGNATURE_HEADER].clone())
+            .body(req.body().clone())
+            .unwrap();
+        assert_eq!(announce(&server, req).await, StatusCode::OK);
+        assert!(server.cluster.registry.lock().peers.contains_key(&node));
+
+        // Once the peer is forgotten, a recorded announcement does not bring its tunnels back
+        server.cluster.registry.lock().peers.clear();
+        assert_eq!(announce(&server, replayed).await, StatusCode::FORBIDDEN);
+        assert!(server.cluster.registry.lock().peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forward_port_of_peer() {
+        let server = cluster_server();
+        // The peer listens on the same port as the forwarder, on another address of the loopback
+        let peer = TcpListener::bind("127.0.0.2:0").await.unwrap();
+        let port = peer.local_addr().unwrap().port();
+        let bind: SocketAddr = ([127, 0, 0, 1], port).into();
+        let node = Url::parse("http://127.0.0.2:8080").unwrap();
+        let announcement = Announcement {
+            node: node.clone(),
+            ports: vec![bind],
+            vhosts: vec![],
+        };
+        assert_eq!(announce(&server, announce_request(&announcement, SECRET)).await, StatusCode::OK);
+
+        tokio::task::yield_now().await;
+        let mut client = TcpStream::connect(bind).await.unwrap();
+        client.write_all(b"Hello").await.unwrap();
+        let (mut forwarded, _) = peer.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        forwarded.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello");
+        forwarded.write_all(b"world").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        // A client of this server taking the port over stops the forwarder
+        let registration = server.cluster.register_port(bind).await;
+        assert!(server.cluster.registry.lock().forwarders.is_empty());
+        tokio::task::yield_now().await;
+        let listener = TcpListener::bind(bind).await.unwrap();
+        drop(listener);
+        drop(registration);
+        assert!(server.cluster.registry.lock().local_ports.is_empty());
+
+        // The port goes back to the peer, until it stops announcing it
+        reconcile_forwarders(&server);
+        assert!(server.cluster.registry.lock().forwarders.contains_key(&bind));
+        server.cluster.registry.lock().peers.get_mut(&node).unwrap().expires = Instant::now();
+        reconcile_forwarders(&server);
+        let registry = server.cluster.registry.lock();
+        assert!(registry.peers.is_empty());
+        assert!(registry.forwarders.is_empty());
+    }
+}
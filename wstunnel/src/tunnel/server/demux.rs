@@ -0,0 +1,286 @@
+//! Recognize the protocol of the connections accepted on the listening port from their first bytes, so tls, plain
+//! http, h2 with prior knowledge and ssh clients can share it, and other servers can be reached through it.
+use crate::executor::TokioExecutorRef;
+use crate::protocols;
+use crate::protocols::tls::{ClientHelloServerName, MAX_RECORD_LEN, client_hello_server_name};
+use crate::tunnel::server::{WsServer, WsServerConfig};
+use anyhow::anyhow;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+use url::Host;
+
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const SSH_BANNER: &[u8] = b"SSH-";
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+// Clients sending their first bytes one by one are given up to 1s to send enough of them
+const SNIFF_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+const SNIFF_MAX_RETRIES: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SniffedProtocol {
+    Tls,
+    Http,
+    /// Http2 without TLS, started with the connection preface (prior knowledge)
+    H2,
+    Ssh,
+    Unknown,
+}
+
+impl SniffedProtocol {
+    pub const fn to_str(self) -> &'static str {
+        match self {
+            Self::Tls => "tls",
+            Self::Http => "http",
+            Self::H2 => "h2",
+            Self::Ssh => "ssh",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+impl Display for SniffedProtocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.to_str())
+    }
+}
+
+impl FromStr for SniffedProtocol {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tls" => Ok(Self::Tls),
+            "http" => Ok(Self::Http),
+            "h2" => Ok(Self::H2),
+            "ssh" => Ok(Self::Ssh),
+            "unknown" => Ok(Self::Unknown),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do with the connections of a protocol
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProtocolHandler {
+    /// Served by wstunnel itself
+    Wstunnel,
+    /// Closed right away
+    Reject,
+    /// Forwarded as is, first bytes included, to this server
+    Passthrough(Host<String>, u16),
+}
+
+/// Forward the tls connections for other server names than the ones of wstunnel, i.e: to the https site it shares port
+/// 443 with. The connections without server name stay with wstunnel
+#[derive(Clone, Debug)]
+pub struct SniPassthrough {
+    /// Names served by wstunnel, lowercase. `*.example.com` matches the subdomains of example.com
+    pub server_names: Vec<String>,
+    pub backend: (Host<String>, u16),
+}
+
+impl SniPassthrough {
+    fn is_served(&self, server_name: &str) -> bool {
+        self.server_names.iter().any(|name| match name.strip_prefix("*.") {
+            Some(domain) => server_name
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+            None => name == server_name,
+        })
+    }
+}
+
+/// Protocol the first bytes of a connection belong to, or None if more bytes are needed to tell
+fn sniff(first_bytes: &[u8]) -> Option<SniffedProtocol> {
+    let first_byte = *first_bytes.first()?;
+    if first_byte == TLS_HANDSHAKE_RECORD {
+        return Some(SniffedProtocol::Tls);
+    }
+
+    for (prefix, protocol) in [(H2_PREFACE, SniffedProtocol::H2), (SSH_BANNER, SniffedProtocol::Ssh)] {
+        if first_bytes.starts_with(prefix) {
+            return Some(protocol);
+        }
+        if prefix.starts_with(first_bytes) {
+            return None;
+        }
+    }
+
+    // Http1 requests start with their method, an uppercase token followed by a space
+    let method_len = first_bytes.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    match first_bytes.get(method_len) {
+        Some(b' ') if method_len > 0 => Some(SniffedProtocol::Http),
+        None if first_bytes.len() < H2_PREFACE.len() => None,
+        _ => Some(SniffedProtocol::Unknown),
+    }
+}
+
+/// Wait for the first bytes of the client, like the tls and http servers do before answering, and recognize its protocol.
+/// They are only peeked, so they are still read by the handler of the connection
+pub(super) async fn sniff_protocol(stream: &TcpStream) -> SniffedProtocol {
+    let mut first_bytes = [0u8; H2_PREFACE.len()];
+    for _ in 0..SNIFF_MAX_RETRIES {
+        let len = match stream.peek(&mut first_bytes).await {
+            Ok(0) | Err(_) => return SniffedProtocol::Unknown,
+            Ok(len) => len,
+        };
+        if let Some(protocol) = sniff(&first_bytes[..len]) {
+            return protocol;
+        }
+        tokio::time::sleep(SNIFF_RETRY_INTERVAL).await;
+    }
+
+    SniffedProtocol::Unknown
+}
+
+/// Wstunnel, or the passthrough backend if the client hello asks for a server name wstunnel does not serve
+pub(super) async fn route_server_name(sni_passthrough: &SniPassthrough, stream: &TcpStream) -> ProtocolHandler {
+    let mut first_bytes = vec![0u8; MAX_RECORD_LEN];
+    for _ in 0..SNIFF_MAX_RETRIES {
+        let len = match stream.peek(&mut first_bytes).await {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+        match client_hello_server_name(&first_bytes[..len]) {
+            ClientHelloServerName::Incomplete => tokio::time::sleep(SNIFF_RETRY_INTERVAL).await,
+            ClientHelloServerName::Missing => break,
+            ClientHelloServerName::Found(server_name) if sni_passthrough.is_served(&server_name) => break,
+            ClientHelloServerName::Found(server_name) => {
+                info!("Server name {server_name} is not served by wstunnel");
+                let (host, port) = sni_passthrough.backend.clone();
+                return ProtocolHandler::Passthrough(host, port);
+            }
+        }
+    }
+
+    ProtocolHandler::Wstunnel
+}
+
+/// True if wstunnel has what it needs to serve the connections of this protocol
+fn can_serve(config: &WsServerConfig, protocol: SniffedProtocol) -> bool {
+    match protocol {
+        SniffedProtocol::Tls => config.tls.is_some(),
+        SniffedProtocol::Http | SniffedProtocol::H2 => true,
+        #[cfg(feature = "ssh-transport")]
+        SniffedProtocol::Ssh => config.ssh_transport.is_some(),
+        #[cfg(not(feature = "ssh-transport"))]
+        SniffedProtocol::Ssh => false,
+        SniffedProtocol::Unknown => false,
+    }
+}
+
+/// Fail if a protocol is handed to wstunnel while it cannot serve it
+pub(super) fn check_protocol_handlers(config: &WsServerConfig) -> anyhow::Result<()> {
+    for (protocol, handler) in &config.protocol_handlers {
+        if *handler == ProtocolHandler::Wstunnel && !can_serve(config, *protocol) {
+            return Err(anyhow!(
+                "Cannot serve the {protocol} connections with wstunnel, its transport is not enabled on the server"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The handler configured for the protocol, or wstunnel for the protocol of the listening url (and ssh when its
+/// transport is enabled). Plain http clients are not served by a TLS server unless asked, as they carry no certificate
+pub(super) fn protocol_handler(config: &WsServerConfig, protocol: SniffedProtocol) -> ProtocolHandler {
+    if let Some((_, handler)) = config.protocol_handlers.iter().find(|(p, _)| *p == protocol) {
+        return handler.clone();
+    }
+
+    let is_default = match protocol {
+        SniffedProtocol::Http | SniffedProtocol::H2 => config.tls.is_none(),
+        _ => true,
+    };
+    if is_default && can_serve(config, protocol) {
+        ProtocolHandler::Wstunnel
+    } else {
+        ProtocolHandler::Reject
+    }
+}
+
+/// Forward the connection as is to another server, until one of them closes it
+pub(super) async fn passthrough(
+    server: &WsServer<impl TokioExecutorRef>,
+    mut stream: TcpStream,
+    host: &Host,
+    port: u16,
+) {
+    let config = &server.config;
+    let mut backend = match protocols::tcp::connect(
+        host,
+        port,
+        config.socket_so_mark,
+        &config.source_bind,
+        config.tcp_fastopen,
+        config.timeout_connect,
+        &config.dns_resolver,
+    )
+    .await
+    {
+        Ok(backend) => backend,
+        Err(err) => {
+            warn!("Cannot connect to the passthrough server {host}:{port}: {err:?}");
+            return;
+        }
+    };
+
+    match tokio::io::copy_bidirectional(&mut stream, &mut backend).await {
+        Ok((sent, received)) => info!("Passthrough to {host}:{port} closed, sent {sent} bytes and received {received}"),
+        Err(err) => info!("Passthrough to {host}:{port} closed: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(b"\x16\x03\x01\x02\x00"), Some(SniffedProtocol::Tls));
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\n"), Some(SniffedProtocol::Http));
+        assert_eq!(sniff(b"POST /events"), Some(SniffedProtocol::Http));
+        assert_eq!(sniff(H2_PREFACE), Some(SniffedProtocol::H2));
+        assert_eq!(sniff(b"SSH-2.0-OpenSSH_9.6\r\n"), Some(SniffedProtocol::Ssh));
+        assert_eq!(sniff(b"\x05\x01\x00"), Some(SniffedProtocol::Unknown));
+        assert_eq!(sniff(b"get / HTTP/1.1"), Some(SniffedProtocol::Unknown));
+
+        // Not enough bytes yet
+        assert_eq!(sniff(b"P"), None);
+        assert_eq!(sniff(b"PRI * HTTP"), None);
+        assert_eq!(sniff(b"SS"), None);
+        assert_eq!(sniff(b"GET"), None);
+        assert_eq!(sniff(b"PROPFIND "), Some(SniffedProtocol::Http));
+    }
+
+    #[test]
+    fn test_sni_passthrough() {
+        let sni_passthrough = SniPassthrough {
+            server_names: vec!["tunnel.example.com".to_string(), "*.wstunnel.example.com".to_string()],
+            backend: (Host::Domain("localhost".to_string()), 8443),
+        };
+        assert!(sni_passthrough.is_served("tunnel.example.com"));
+        assert!(sni_passthrough.is_served("a.wstunnel.example.com"));
+        assert!(!sni_passthrough.is_served("wstunnel.example.com"));
+        assert!(!sni_passthrough.is_served("awstunnel.example.com"));
+        assert!(!sni_passthrough.is_served("www.example.com"));
+    }
+
+    #[test]
+    fn test_protocol_from_str() {
+        for protocol in [
+            SniffedProtocol::Tls,
+            SniffedProtocol::Http,
+            SniffedProtocol::H2,
+            SniffedProtocol::Ssh,
+            SniffedProtocol::Unknown,
+        ] {
+            assert_eq!(SniffedProtocol::from_str(protocol.to_str()), Ok(protocol));
+        }
+        assert!(SniffedProtocol::from_str("quic").is_err());
+    }
+}
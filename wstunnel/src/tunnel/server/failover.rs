@@ -0,0 +1,59 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use url::Host;
+
+/// Destinations of load balanced tunnels the server could not reach, skipped until the recorded instant
+static UNREACHABLE: LazyLock<Mutex<HashMap<(Host, u16), Instant>>> = LazyLock::new(Default::default);
+
+/// The destinations to try in order for a connection of a load balanced tunnel. The ones that failed their health
+/// check are moved last, so they are only tried when all the others are unreachable too
+pub fn destinations(dest: (Host, u16), fallbacks: &[(Host, u16)]) -> Vec<(Host, u16)> {
+    let now = Instant::now();
+    let mut unreachable = UNREACHABLE.lock();
+    unreachable.retain(|_, until| *until > now);
+
+    let (mut healthy, down): (Vec<_>, Vec<_>) = std::iter::once(dest)
+        .chain(fallbacks.iter().cloned())
+        .partition(|dest| !unreachable.contains_key(dest));
+    healthy.extend(down);
+    healthy
+}
+
+/// Skip `dest` for the duration of the health check of its tunnel, if it has one
+pub fn mark_unreachable(dest: &(Host, u16), health_check: Option<Duration>) {
+    let Some(health_check) = health_check else {
+        return;
+    };
+    UNREACHABLE.lock().insert(dest.clone(), Instant::now() + health_check);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unreachable_destinations_are_tried_last() {
+        let dest = |name: &str| (Host::Domain(name.to_string()), 80);
+        let fallbacks = [dest("failover-b.lan"), dest("failover-c.lan")];
+
+        mark_unreachable(&dest("failover-a.lan"), None);
+        assert_eq!(
+            destinations(dest("failover-a.lan"), &fallbacks),
+            vec![dest("failover-a.lan"), dest("failover-b.lan"), dest("failover-c.lan")]
+        );
+
+        mark_unreachable(&dest("failover-a.lan"), Some(Duration::from_secs(60)));
+        assert_eq!(
+            destinations(dest("failover-a.lan"), &fallbacks),
+            vec![dest("failover-b.lan"), dest("failover-c.lan"), dest("failover-a.lan")]
+        );
+
+        mark_unreachable(&dest("failover-b.lan"), Some(Duration::ZERO));
+        assert_eq!(
+            destinations(dest("failover-b.lan"), &[dest("failover-c.lan")]),
+            vec![dest("failover-b.lan"), dest("failover-c.lan")]
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use crate::executor::TokioExecutorRef;
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::protocol::Capabilities;
+use crate::tunnel::server::WsServer;
+use crate::tunnel::server::server::mk_span;
+use crate::tunnel::transport;
+use crate::tunnel::transport::datagram::{
+    DatagramTunnelRead, DatagramTunnelWrite, Frame, OpenRequest, OpenResponse, ServerSession,
+};
+use crate::tunnel::transport::tunnel_to_jwt_token;
+use ahash::AHashMap;
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use hyper::Request;
+use hyper::header::{AUTHORIZATION, COOKIE};
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{Instrument, Span, debug, warn};
+use uuid::Uuid;
+
+const OPEN_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum time the server keeps a poll of the client waiting for data to send back.
+/// Must stay well under the timeout of dns resolvers/ping
+const LONG_POLL: Duration = Duration::from_millis(200);
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sessions of the datagram transports (dns, icmp), indexed by the session id chosen by the client
+pub(super) struct DatagramSessions<E: TokioExecutorRef> {
+    server: WsServer<E>,
+    restrictions: Arc<ArcSwap<RestrictionsRules>>,
+    sessions: Arc<Mutex<AHashMap<u64, Arc<tokio::sync::Mutex<ServerSession>>>>>,
+}
+
+impl<E: TokioExecutorRef> Clone for DatagramSessions<E> {
+    fn clone(&self) -> Self {
+        Self {
+            server: self.server.clone(),
+            restrictions: self.restrictions.clone(),
+            sessions: self.sessions.clone(),
+        }
+    }
+}
+
+impl<E: TokioExecutorRef> DatagramSessions<E> {
+    pub fn new(server: WsServer<E>, restrictions: Arc<ArcSwap<RestrictionsRules>>) -> Self {
+        let sessions: Arc<Mutex<AHashMap<u64, Arc<tokio::sync::Mutex<ServerSession>>>>> = Arc::default();
+
+        // Release sessions that are closed or that the client abandoned
+        let weak_sessions = Arc::downgrade(&sessions);
+        server.executor.spawn(async move {
+            let mut timer = tokio::time::interval(SESSION_IDLE_TIMEOUT / 4);
+            loop {
+                timer.tick().await;
+                let Some(sessions) = weak_sessions.upgrade() else {
+                    break;
+                };
+                sessions.lock().retain(|_, session| {
+                    session
+                        .try_lock()
+                        .map_or(true, |s| !s.is_finished() && s.idle_for() < SESSION_IDLE_TIMEOUT)
+                });
+            }
+        });
+
+        Self {
+            server,
+            restrictions,
+            sessions,
+        }
+    }
+
+    /// Process a frame of a client, and returns the frame to answer with
+    pub async fn handle(&self, client_addr: SocketAddr, frame: Frame, max_payload: usize) -> Frame {
+        let session_id = frame.session_id;
+        let session = self.sessions.lock().get(&session_id).cloned();
+        let session = match session {
+            Some(session) => session,
+            // New sessions start with the open request
+            None if frame.seq == 0 && frame.carries_data() => {
+                let (session, up_rx, down_tx) = ServerSession::new(session_id);
+                let session = Arc::new(tokio::sync::Mutex::new(session));
+                self.sessions.lock().insert(session_id, session.clone());
+                self.server.executor.spawn(
+                    datagram_server_upgrade(
+                        self.server.clone(),
+                        self.restrictions.load().clone(),
+                        client_addr,
+                        up_rx,
+                        down_tx,
+                    )
+                    .instrument(mk_span()),
+                );
+                session
+            }
+            None => {
+                debug!("Received frame for unknown datagram session {session_id}");
+                return Frame::rst(session_id);
+            }
+        };
+
+        session.lock().await.handle(frame, max_payload, LONG_POLL).await
+    }
+}
+
+/// Serve a tunnel over a datagram session, once the client sent its open request.
+/// The open request is turned into an upgrade request, to go through the same validation than websocket/http2 tunnels
+async fn datagram_server_upgrade(
+    server: WsServer<impl TokioExecutorRef>,
+    restrictions: Arc<RestrictionsRules>,
+    client_addr: SocketAddr,
+    up_rx: mpsc::Receiver<Bytes>,
+    down_tx: mpsc::Sender<Bytes>,
+) {
+    let mut ws_rx = DatagramTunnelRead::new(up_rx);
+    let open_request = match tokio::time::timeout(OPEN_REQUEST_TIMEOUT, OpenRequest::read(&mut ws_rx)).await {
+        Ok(Ok(req)) => req,
+        Ok(Err(err)) => {
+            warn!("Rejecting datagram session with invalid open request: {err}");
+            return;
+        }
+        Err(_) => {
+            warn!(
+                "Rejecting datagram session without open request after {}s",
+                OPEN_REQUEST_TIMEOUT.as_secs()
+            );
+            return;
+        }
+    };
+
+    let mut req = Request::builder()
+        .uri(format!("/{}/events", open_request.path_prefix))
+        .header(COOKIE, open_request.jwt);
+    if let Some(authorization) = open_request.authorization {
+        req = req.header(AUTHORIZATION, authorization);
+    }
+    let Ok(req) = req.body(()) else {
+        warn!("Rejecting datagram session with invalid open request");
+        return;
+    };
+
+    let (remote_addr, local_rx, local_tx, _, need_cookie) = match server
+        .handle_tunnel_request(restrictions, None, client_addr, &req)
+        .await
+    {
+        Ok(ret) => ret,
+        Err(_) => {
+            let response = OpenResponse {
+                accepted: false,
+                cookie: None,
+            };
+            let _ = down_tx.send(response.encode()).await;
+            return;
+        }
+    };
+
+    let response = OpenResponse {
+        accepted: true,
+        cookie: need_cookie.then(|| tunnel_to_jwt_token(Uuid::from_u128(0), &remote_addr, None, None)),
+    };
+    if down_tx.send(response.encode()).await.is_err() {
+        return;
+    }
+
+    let (close_tx, close_rx) = transport::io::close_channel(Capabilities::NONE);
+    server
+        .executor
+        .spawn(transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).instrument(Span::current()));
+    server.executor.spawn(
+        transport::io::propagate_local_to_remote(local_rx, DatagramTunnelWrite::new(down_tx), close_tx, None)
+            .instrument(Span::current()),
+    );
+}
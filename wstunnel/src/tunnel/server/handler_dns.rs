@@ -0,0 +1,127 @@
+use crate::executor::TokioExecutorRef;
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::server::WsServer;
+use crate::tunnel::server::handler_datagram::DatagramSessions;
+use crate::tunnel::transport::datagram::{FRAME_HEADER_LEN, Frame};
+use crate::tunnel::transport::dns::{EDNS_MAX_PAYLOAD, decode_query_frame, txt_data};
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use hickory_resolver::proto::op::{Edns, Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::rdata::TXT;
+use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone)]
+pub struct DnsTransportConfig {
+    /// Address to listen for dns queries on
+    pub bind: SocketAddr,
+    /// Domain delegated to the wstunnel server
+    pub domain: Name,
+}
+
+pub(super) async fn run_dns_server(
+    server: WsServer<impl TokioExecutorRef>,
+    restrictions: Arc<ArcSwap<RestrictionsRules>>,
+    cfg: DnsTransportConfig,
+) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(cfg.bind)
+        .await
+        .with_context(|| format!("Failed to bind dns transport to socket on {}", cfg.bind))?;
+    let socket = Arc::new(socket);
+    info!("Starting dns transport listening on {} for domain {}", cfg.bind, cfg.domain);
+
+    let sessions = DatagramSessions::new(server.clone(), restrictions);
+
+    let mut buf = vec![0u8; u16::MAX as usize];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(ret) => ret,
+            Err(err) => {
+                warn!("Error while receiving dns query {err:?}");
+                continue;
+            }
+        };
+        let Ok(query) = Message::from_vec(&buf[..len]) else {
+            debug!("Ignoring invalid dns query from {peer}");
+            continue;
+        };
+
+        let sessions = sessions.clone();
+        let socket = socket.clone();
+        let domain = cfg.domain.clone();
+        server.executor.spawn(async move {
+            let response = handle_query(&sessions, &domain, peer, query).await;
+            match response.to_vec() {
+                Ok(response) => {
+                    if let Err(err) = socket.send_to(&response, peer).await {
+                        warn!("Cannot send dns response to {peer}: {err}");
+                    }
+                }
+                Err(err) => warn!("Cannot encode dns response: {err}"),
+            }
+        });
+    }
+}
+
+async fn handle_query(
+    sessions: &DatagramSessions<impl TokioExecutorRef>,
+    domain: &Name,
+    peer: SocketAddr,
+    query: Message,
+) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_authoritative(true)
+        .set_recursion_desired(query.recursion_desired())
+        .add_queries(query.queries().iter().cloned());
+    if query.extensions().is_some() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(EDNS_MAX_PAYLOAD);
+        response.set_edns(edns);
+    }
+
+    let Some(question) = query.queries().first() else {
+        response.set_response_code(ResponseCode::FormErr);
+        return response;
+    };
+    if !domain.zone_of(question.name()) {
+        response.set_response_code(ResponseCode::Refused);
+        return response;
+    }
+    // Not a tunnel query (i.e: resolver doing qname minimization), answer without data
+    let frame = match question.query_type() {
+        RecordType::TXT => decode_query_frame(question.name(), domain),
+        _ => None,
+    };
+    let Some(frame) = frame else {
+        return response;
+    };
+
+    let max_payload = max_response_payload(&query, question.name());
+    let answer = sessions.handle(peer, frame, max_payload).await;
+    with_frame(response, question.name(), &answer)
+}
+
+fn with_frame(mut response: Message, name: &Name, frame: &Frame) -> Message {
+    let data = frame.encode();
+    let record = Record::from_rdata(name.clone(), 0, RData::TXT(TXT::from_bytes(txt_data(&data))));
+    response.add_answer(record);
+    response
+}
+
+/// Payload that fits in an answer, given the size the client accepts
+fn max_response_payload(query: &Message, name: &Name) -> usize {
+    let max_message_len = query.max_payload().min(EDNS_MAX_PAYLOAD) as usize;
+    let name_len = name.len() + 1;
+    // header + question + answer (with a compressed name) + opt record
+    let overhead = 12 + name_len + 4 + 12 + 11;
+    let available = max_message_len.saturating_sub(overhead);
+    // each TXT string of 255 bytes has a length prefix
+    (available - available.div_ceil(256)).saturating_sub(FRAME_HEADER_LEN)
+}
@@ -0,0 +1,514 @@
+use crate::executor::TokioExecutorRef;
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::protocol::PROTOCOL_HEADER;
+use crate::tunnel::protocol::integrity_record::INTEGRITY_HEADER;
+use crate::tunnel::protocol::probe_record::PROBE_HEADER;
+use crate::tunnel::server::WsServer;
+use crate::tunnel::server::probe;
+use crate::tunnel::server::service::RequestBody;
+use crate::tunnel::server::utils::{
+    HttpResponse, bad_request, check_path_prefix, close_capabilities, early_data_ack, extract_path_prefix,
+    health_probe, inject_cookie, integrity_header, protocol_header, psk_proof, sticky_session,
+};
+use crate::tunnel::transport;
+use crate::tunnel::transport::http1::{
+    MAX_CHUNK_LEN, SEQ_HEADER, SESSION_HEADER, SESSION_TOKEN_HEADER, SessionUploadRead, UPLOAD_MAC_HEADER, UploadChunk,
+    session_token, verify_upload_mac,
+};
+use crate::tunnel::transport::http2;
+use crate::tunnel::transport::{EARLY_DATA_HEADER, PSK_HEADER, STICKY_SESSION_HEADER};
+use ahash::AHashMap;
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, BodyStream, Either, Limited, StreamBody};
+use hyper::header::{CACHE_CONTROL, HeaderName, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tracing::{Instrument, Span, warn};
+use uuid::Uuid;
+
+/// Upload side of a session, fed by the requests of the client
+struct Upload {
+    tx: Option<mpsc::Sender<Bytes>>,
+    next_seq: u64,
+    /// Token returned in the response of the download request, that keys the HMAC of the upload requests
+    token: String,
+    /// Path prefix of the download request, the restrictions of the tunnel were matched against
+    path_prefix: String,
+}
+
+/// Sessions of the tunnels served over split requests, indexed by the session id chosen by the client
+#[derive(Default)]
+pub(super) struct HttpSessions {
+    uploads: Mutex<AHashMap<Uuid, Arc<tokio::sync::Mutex<Upload>>>>,
+}
+
+/// Serve the requests of a session, the download one opening the tunnel and the upload ones feeding it.
+/// Used by the http1 transport, and by the http2 transport when the client splits its requests
+pub(super) async fn http1_server_session(
+    server: WsServer<impl TokioExecutorRef>,
+    restrictions: Arc<RestrictionsRules>,
+    restrict_path_prefix: Option<String>,
+    client_addr: SocketAddr,
+    req: Request<impl RequestBody>,
+) -> HttpResponse {
+    if let Some(response) = health_probe(&req) {
+        return response;
+    }
+    let (parts, body) = req.into_parts();
+    let req = Request::from_parts(parts, ());
+    let Some(session_id) = req
+        .headers()
+        .get(SESSION_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| Uuid::from_str(header).ok())
+    else {
+        warn!("Rejecting session request with invalid session id: {}", req.uri());
+        return bad_request();
+    };
+
+    match *req.method() {
+        Method::GET => download(server, restrictions, restrict_path_prefix, client_addr, session_id, req).await,
+        Method::POST => upload(server, restrict_path_prefix, session_id, &req, body).await,
+        _ => {
+            warn!("Rejecting session request with unexpected method {}", req.method());
+            bad_request()
+        }
+    }
+}
+
+/// Open the tunnel, and stream the data of the destination in the response
+async fn download(
+    server: WsServer<impl TokioExecutorRef>,
+    restrictions: Arc<RestrictionsRules>,
+    restrict_path_prefix: Option<String>,
+    client_addr: SocketAddr,
+    session_id: Uuid,
+    req: Request<()>,
+) -> HttpResponse {
+    if server.http_sessions().uploads.lock().contains_key(&session_id) {
+        warn!("Rejecting download request of already existing session {session_id}");
+        return bad_request();
+    }
+    let (remote_addr, local_rx, local_tx, local_reset, need_cookie) = match server
+        .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
+        .await
+    {
+        Ok(ret) => ret,
+        Err(err) => return err,
+    };
+    let psk_proof = psk_proof(server.config.psk.as_ref(), &req);
+    let sticky_session = sticky_session(server.config.sticky_session.as_ref(), &req);
+    let early_data_ack = early_data_ack(&req);
+    let protocol_header = protocol_header(&req);
+    let probe_header = probe::probe_header(&req);
+    let integrity_header = integrity_header(&req);
+    let close_capabilities = close_capabilities(&req);
+    let Ok(path_prefix) = extract_path_prefix(req.uri().path()) else {
+        return bad_request();
+    };
+
+    let token = session_token();
+    let (upload_tx, upload_rx) = mpsc::channel::<Bytes>(32);
+    let upload = Upload {
+        tx: Some(upload_tx),
+        next_seq: 0,
+        token: token.clone(),
+        path_prefix: path_prefix.to_string(),
+    };
+    if server
+        .http_sessions()
+        .uploads
+        .lock()
+        .insert(session_id, Arc::new(tokio::sync::Mutex::new(upload)))
+        .is_some()
+    {
+        warn!("Rejecting download request of already existing session {session_id}");
+        return bad_request();
+    }
+
+    let (ws_tx, body) = http2::body_channel(server.config.max_inflight_per_tunnel);
+    let (close_tx, close_rx) = transport::io::close_channel(close_capabilities);
+    let close_rx = close_rx.with_local_reset(local_reset);
+    let sessions = server.clone();
+    server.executor.spawn(
+        async move {
+            let ws_rx = SessionUploadRead::new(upload_rx);
+            let _ = transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).await;
+            sessions.http_sessions().uploads.lock().remove(&session_id);
+        }
+        .instrument(Span::current()),
+    );
+
+    server
+        .executor
+        .spawn(transport::io::propagate_local_to_remote(local_rx, ws_tx, close_tx, None).instrument(Span::current()));
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CACHE_CONTROL, "no-store")
+        // Ask reverse proxies (i.e: nginx) to forward the data as it comes, instead of buffering the response
+        .header(HeaderName::from_static("x-accel-buffering"), HeaderValue::from_static("no"))
+        .header(SESSION_TOKEN_HEADER, token)
+        .body(Either::Right(BoxBody::new(StreamBody::new(body))))
+        .expect("bug: failed to build response");
+
+    if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
+        return bad_request();
+    }
+    if let Some(psk_proof) = psk_proof {
+        response.headers_mut().insert(PSK_HEADER, psk_proof);
+    }
+    if let Some(early_data_ack) = early_data_ack {
+        response.headers_mut().insert(EARLY_DATA_HEADER, early_data_ack);
+    }
+    if let Some(protocol_header) = protocol_header {
+        response.headers_mut().insert(PROTOCOL_HEADER, protocol_header);
+    }
+    if let Some(probe_header) = probe_header {
+        response.headers_mut().insert(PROBE_HEADER, probe_header);
+    }
+    if let Some(integrity_header) = integrity_header {
+        response.headers_mut().insert(INTEGRITY_HEADER, integrity_header);
+    }
+    if let Some(sticky_session) = sticky_session {
+        response.headers_mut().insert(STICKY_SESSION_HEADER, sticky_session);
+    }
+
+    response
+}
+
+/// Feed the session with the body of the request. Either the whole upload streamed in a single request, or a part of it
+/// when the request is numbered by its sequence header
+async fn upload(
+    server: WsServer<impl TokioExecutorRef>,
+    restrict_path_prefix: Option<String>,
+    session_id: Uuid,
+    req: &Request<()>,
+    body: impl RequestBody,
+) -> HttpResponse {
+    let Some(path_prefix) = check_path_prefix(restrict_path_prefix.as_deref(), req) else {
+        return bad_request();
+    };
+    let Some(upload) = server.http_sessions().uploads.lock().get(&session_id).cloned() else {
+        warn!("Rejecting upload request of unknown session {session_id}");
+        return bad_request();
+    };
+
+    let mac = req
+        .headers()
+        .get(UPLOAD_MAC_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or_default();
+    let seq = match req.headers().get(SEQ_HEADER) {
+        None => None,
+        Some(seq) => match seq.to_str().ok().and_then(|seq| seq.parse::<u64>().ok()) {
+            Some(seq) => Some(seq),
+            None => {
+                warn!("Rejecting upload request of session {session_id} with invalid sequence number");
+                return bad_request();
+            }
+        },
+    };
+
+    let Some(seq) = seq else {
+        let mut upload = upload.lock().await;
+        if !is_authentic(&upload, session_id, path_prefix, None, mac) || !server.check_psk_proof(req, mac) {
+            return bad_request();
+        }
+        let Some(tx) = upload.tx.take() else {
+            warn!("Rejecting upload request of already closed session {session_id}");
+            return bad_request();
+        };
+
+        let mut body = BodyStream::new(body);
+        while let Some(Ok(frame)) = body.next().await {
+            if let Ok(data) = frame.into_data()
+                && tx.send(data).await.is_err()
+            {
+                break;
+            }
+        }
+        return ok();
+    };
+
+    let data = match Limited::new(body, MAX_CHUNK_LEN).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(err) => {
+            warn!("Rejecting upload request of session {session_id}: {err}");
+            return bad_request();
+        }
+    };
+
+    let mut upload = upload.lock().await;
+    if !is_authentic(&upload, session_id, path_prefix, Some((seq, &data)), mac) {
+        return bad_request();
+    }
+    // The proxy sent the request again, after the data was already received
+    if seq < upload.next_seq {
+        return ok();
+    }
+    if seq > upload.next_seq {
+        warn!(
+            "Rejecting upload request of session {session_id} with sequence number {seq}, while expecting {}",
+            upload.next_seq
+        );
+        return bad_request();
+    }
+    if !server.check_psk_proof(req, mac) {
+        return bad_request();
+    }
+    upload.next_seq += 1;
+
+    // An empty request ends the upload
+    if data.is_empty() {
+        upload.tx = None;
+        return ok();
+    }
+    match &upload.tx {
+        Some(tx) if tx.send(data).await.is_ok() => ok(),
+        _ => bad_request(),
+    }
+}
+
+/// Only the client which received the token of the session can feed it, through the same path as its download
+fn is_authentic(upload: &Upload, session_id: Uuid, path_prefix: &str, chunk: Option<UploadChunk>, mac: &str) -> bool {
+    if !verify_upload_mac(&upload.token, &session_id, chunk, mac) {
+        warn!("Rejecting upload request of session {session_id} with invalid mac");
+        return false;
+    }
+    if upload.path_prefix != path_prefix {
+        warn!("Rejecting upload request of session {session_id} with another path prefix than its download");
+        return false;
+    }
+    true
+}
+
+fn ok() -> HttpResponse {
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(Either::Right(BoxBody::default()))
+        .expect("bug: failed to build response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LocalProtocol;
+    use crate::executor::DefaultTokioExecutor;
+    use crate::test_integrations::{dns_resolver, no_restrictions, server};
+    use crate::tunnel::RemoteAddr;
+    use crate::tunnel::transport::http1::upload_mac;
+    use crate::tunnel::transport::{PreSharedKey, tunnel_to_jwt_token};
+    use http_body_util::Full;
+    use hyper::header::COOKIE;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use url::Host;
+
+    const PATH: &str = "/wstunnel/events";
+
+    fn request(method: Method, session_id: &Uuid, body: &'static [u8]) -> Request<Full<Bytes>> {
+        Request::builder()
+            .method(method)
+            .uri(PATH)
+            .header(SESSION_HEADER, session_id.simple().to_string())
+            .body(Full::new(Bytes::from_static(body)))
+            .unwrap()
+    }
+
+    fn upload_request(session_id: &Uuid, token: &str, body: &'static [u8]) -> Request<Full<Bytes>> {
+        let mut req = request(Method::POST, session_id, body);
+        req.headers_mut().insert(
+            UPLOAD_MAC_HEADER,
+            HeaderValue::from_str(&upload_mac(token, session_id, None)).unwrap(),
+        );
+        req
+    }
+
+    fn chunk_request(session_id: &Uuid, token: &str, seq: u64, body: &'static [u8]) -> Request<Full<Bytes>> {
+        let mut req = request(Method::POST, session_id, body);
+        let mac = upload_mac(token, session_id, Some((seq, body)));
+        req.headers_mut().insert(SEQ_HEADER, HeaderValue::from(seq));
+        req.headers_mut()
+            .insert(UPLOAD_MAC_HEADER, HeaderValue::from_str(&mac).unwrap());
+        req
+    }
+
+    async fn send(server: &WsServer, req: Request<Full<Bytes>>) -> HttpResponse {
+        let restrictions = Arc::new(no_restrictions());
+        http1_server_session(server.clone(), restrictions, None, "127.0.0.1:1234".parse().unwrap(), req).await
+    }
+
+    /// Open a session tunneled to a local destination, returning its token and the connection of the destination
+    async fn download(server: &WsServer, session_id: &Uuid, psk: Option<&PreSharedKey>) -> (String, TcpStream) {
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
+            host: Host::Ipv4("127.0.0.1".parse().unwrap()),
+            port: dest.local_addr().unwrap().port(),
+        };
+        let tunnel_token = tunnel_to_jwt_token(Uuid::now_v7(), &remote, None, None);
+        let mut req = request(Method::GET, session_id, b"");
+        if let Some(psk) = psk {
+            let proof = psk.client_proof(PATH, &tunnel_token);
+            req.headers_mut()
+                .insert(PSK_HEADER, HeaderValue::from_str(&proof).unwrap());
+        }
+        req.headers_mut()
+            .insert(COOKIE, HeaderValue::from_str(&tunnel_token).unwrap());
+
+        let response = send(server, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let token = response.headers()[SESSION_TOKEN_HEADER].to_str().unwrap().to_string();
+        let (dest, _) = dest.accept().await.unwrap();
+        (token, dest)
+    }
+
+    async fn read_dest(dest: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        tokio::time::timeout(Duration::from_secs(5), dest.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_upload_of_unknown_session() {
+        let server = server(dns_resolver(), None);
+        let session_id = Uuid::new_v4();
+
+        let response = send(&server, upload_request(&session_id, "token", b"hello")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(server.http_sessions().uploads.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upload_before_download() {
+        let server = server(dns_resolver(), None);
+        let session_id = Uuid::new_v4();
+
+        // The upload arriving first is rejected, without preventing the session from being opened afterward
+        let response = send(&server, upload_request(&session_id, "token", b"early")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let (token, mut dest) = download(&server, &session_id, None).await;
+        let response = send(&server, upload_request(&session_id, &token, b"hello")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_dest(&mut dest, 5).await, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_session() {
+        let server = server(dns_resolver(), None);
+        let session_id = Uuid::new_v4();
+        let (token, mut dest) = download(&server, &session_id, None).await;
+
+        let response = send(&server, request(Method::GET, &session_id, b"")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // The first download still owns the session
+        let response = send(&server, upload_request(&session_id, &token, b"hello")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_dest(&mut dest, 5).await, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_upload_authentication() {
+        let server = server(dns_resolver(), None);
+        let session_id = Uuid::new_v4();
+        let (token, mut dest) = download(&server, &session_id, None).await;
+
+        // Without the token of the session
+        let response = send(&server, request(Method::POST, &session_id, b"forged")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = send(&server, upload_request(&session_id, "guessed", b"forged")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        // nor with the token of another session
+        let response = send(&server, upload_request(&Uuid::new_v4(), &token, b"forged")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // On another path prefix than the download
+        let mut req = upload_request(&session_id, &token, b"forged");
+        *req.uri_mut() = "/other/events".parse().unwrap();
+        let response = send(&server, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(&server, upload_request(&session_id, &token, b"hello")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_dest(&mut dest, 5).await, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_upload_in_chunks() {
+        let server = server(dns_resolver(), None);
+        let session_id = Uuid::new_v4();
+        let (token, mut dest) = download(&server, &session_id, None).await;
+
+        // Out of order
+        let response = send(&server, chunk_request(&session_id, &token, 1, b"world")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(&server, chunk_request(&session_id, &token, 0, b"hello")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Replayed, acknowledged without its data being written twice
+        let response = send(&server, chunk_request(&session_id, &token, 0, b"hello")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The mac covers the body and the sequence number of the chunk
+        let mut req = chunk_request(&session_id, &token, 1, b"world");
+        *req.body_mut() = Full::new(Bytes::from_static(b"w0rld"));
+        let response = send(&server, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let mut req = chunk_request(&session_id, &token, 0, b"world");
+        req.headers_mut().insert(SEQ_HEADER, HeaderValue::from(1));
+        let response = send(&server, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(&server, chunk_request(&session_id, &token, 1, b"world")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_dest(&mut dest, 10).await, b"helloworld");
+
+        // An empty chunk ends the upload
+        let response = send(&server, chunk_request(&session_id, &token, 2, b"")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&server, chunk_request(&session_id, &token, 3, b"late")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_upload_with_psk() {
+        let psk = PreSharedKey::new(b"secret");
+        let mut config = Arc::into_inner(server(dns_resolver(), None).config).unwrap();
+        config.psk = Some(psk.clone());
+        let server = WsServer::new(config, DefaultTokioExecutor::default());
+        let session_id = Uuid::new_v4();
+        let (token, mut dest) = download(&server, &session_id, Some(&psk)).await;
+        let mac = upload_mac(&token, &session_id, None);
+
+        // The token of the session alone is not enough
+        let response = send(&server, upload_request(&session_id, &token, b"forged")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let mut req = upload_request(&session_id, &token, b"hello");
+        req.headers_mut()
+            .insert(PSK_HEADER, HeaderValue::from_str(&psk.client_proof(PATH, &mac)).unwrap());
+        let response = send(&server, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_dest(&mut dest, 5).await, b"hello");
+    }
+}
@@ -1,20 +1,28 @@
 use crate::executor::TokioExecutorRef;
 use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::protocol::PROTOCOL_HEADER;
+use crate::tunnel::protocol::integrity_record::INTEGRITY_HEADER;
+use crate::tunnel::protocol::probe_record::PROBE_HEADER;
 use crate::tunnel::server::WsServer;
-use crate::tunnel::server::utils::{HttpResponse, bad_request, inject_cookie};
+use crate::tunnel::server::probe;
+use crate::tunnel::server::service::RequestBody;
+use crate::tunnel::server::utils::{
+    HttpResponse, bad_request, close_capabilities, early_data_ack, health_probe, inject_cookie, integrity_header,
+    protocol_header, psk_proof, sticky_session,
+};
 use crate::tunnel::transport;
-use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite};
-use bytes::Bytes;
-use futures_util::StreamExt;
+use crate::tunnel::transport::grpc::{GrpcTunnelRead, GrpcTunnelWrite};
+use crate::tunnel::transport::http2::Http2TunnelRead;
+use crate::tunnel::transport::{EARLY_DATA_HEADER, PSK_HEADER, STICKY_SESSION_HEADER};
+use crate::tunnel::transport::{grpc, http2};
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyStream, Either, StreamBody};
-use hyper::body::{Frame, Incoming};
+use hyper::body::Frame;
 use hyper::header::CONTENT_TYPE;
 use hyper::{Request, Response, StatusCode};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
-use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
 use tracing::{Instrument, Span};
 
 pub(super) async fn http_server_upgrade(
@@ -22,38 +30,66 @@ pub(super) async fn http_server_upgrade(
     restrictions: Arc<RestrictionsRules>,
     restrict_path_prefix: Option<String>,
     client_addr: SocketAddr,
-    mut req: Request<Incoming>,
+    req: Request<impl RequestBody>,
 ) -> HttpResponse {
-    let (remote_addr, local_rx, local_tx, need_cookie) = match server
+    if let Some(response) = health_probe(&req) {
+        return response;
+    }
+    let (parts, body) = req.into_parts();
+    let mut req = Request::from_parts(parts, ());
+    let (remote_addr, local_rx, local_tx, local_reset, need_cookie) = match server
         .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
         .await
     {
         Ok(ret) => ret,
         Err(err) => return err,
     };
+    let psk_proof = psk_proof(server.config.psk.as_ref(), &req);
+    let sticky_session = sticky_session(server.config.sticky_session.as_ref(), &req);
+    let early_data_ack = early_data_ack(&req);
+    let protocol_header = protocol_header(&req);
+    let probe_header = probe::probe_header(&req);
+    let integrity_header = integrity_header(&req);
+    let close_capabilities = close_capabilities(&req);
 
+    let is_grpc = grpc::is_grpc_request(&req);
     let req_content_type = req.headers_mut().remove(CONTENT_TYPE);
-    let ws_rx = BodyStream::new(req.into_body());
-    let (ws_tx, rx) = mpsc::channel::<Bytes>(1024);
-    let body = BoxBody::new(StreamBody::new(
-        ReceiverStream::new(rx).map(|s| -> anyhow::Result<Frame<Bytes>> { Ok(Frame::data(s)) }),
-    ));
+    let ws_rx = BodyStream::new(body);
+    let (ws_tx, body) = http2::body_channel(server.config.max_inflight_per_tunnel);
+    // A gRPC call only completes with the status in the trailers of its response
+    let body = if is_grpc {
+        BoxBody::new(StreamBody::new(
+            body.chain(tokio_stream::once(Ok(Frame::trailers(grpc::ok_trailers())))),
+        ))
+    } else {
+        BoxBody::new(StreamBody::new(body))
+    };
 
     let mut response = Response::builder()
         .status(StatusCode::OK)
         .body(Either::Right(body))
         .expect("bug: failed to build response");
 
-    let (close_tx, close_rx) = oneshot::channel::<()>();
-    server.executor.spawn(
-        transport::io::propagate_remote_to_local(local_tx, Http2TunnelRead::new(ws_rx, None), close_rx)
-            .instrument(Span::current()),
-    );
-
-    server.executor.spawn(
-        transport::io::propagate_local_to_remote(local_rx, Http2TunnelWrite::new(ws_tx), close_tx, None)
-            .instrument(Span::current()),
-    );
+    let (close_tx, close_rx) = transport::io::close_channel(close_capabilities);
+    let close_rx = close_rx.with_local_reset(local_reset);
+    if is_grpc {
+        server.executor.spawn(
+            transport::io::propagate_remote_to_local(local_tx, GrpcTunnelRead::new(ws_rx), close_rx)
+                .instrument(Span::current()),
+        );
+        server.executor.spawn(
+            transport::io::propagate_local_to_remote(local_rx, GrpcTunnelWrite::new(ws_tx), close_tx, None)
+                .instrument(Span::current()),
+        );
+    } else {
+        server.executor.spawn(
+            transport::io::propagate_remote_to_local(local_tx, Http2TunnelRead::new(ws_rx, None), close_rx)
+                .instrument(Span::current()),
+        );
+        server.executor.spawn(
+            transport::io::propagate_local_to_remote(local_rx, ws_tx, close_tx, None).instrument(Span::current()),
+        );
+    }
 
     if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
         return bad_request();
@@ -62,6 +98,24 @@ pub(super) async fn http_server_upgrade(
     if let Some(content_type) = req_content_type {
         response.headers_mut().insert(CONTENT_TYPE, content_type);
     }
+    if let Some(psk_proof) = psk_proof {
+        response.headers_mut().insert(PSK_HEADER, psk_proof);
+    }
+    if let Some(early_data_ack) = early_data_ack {
+        response.headers_mut().insert(EARLY_DATA_HEADER, early_data_ack);
+    }
+    if let Some(protocol_header) = protocol_header {
+        response.headers_mut().insert(PROTOCOL_HEADER, protocol_header);
+    }
+    if let Some(probe_header) = probe_header {
+        response.headers_mut().insert(PROBE_HEADER, probe_header);
+    }
+    if let Some(integrity_header) = integrity_header {
+        response.headers_mut().insert(INTEGRITY_HEADER, integrity_header);
+    }
+    if let Some(sticky_session) = sticky_session {
+        response.headers_mut().insert(STICKY_SESSION_HEADER, sticky_session);
+    }
 
     response
 }
@@ -0,0 +1,62 @@
+use crate::executor::TokioExecutorRef;
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::server::WsServer;
+use crate::tunnel::server::handler_datagram::DatagramSessions;
+use crate::tunnel::transport::datagram::Frame;
+use crate::tunnel::transport::icmp::{EchoPacket, MAX_FRAME_PAYLOAD, raw_socket};
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct IcmpTransportConfig {
+    /// Address to receive the echo requests on
+    pub bind: Ipv4Addr,
+}
+
+pub(super) async fn run_icmp_server(
+    server: WsServer<impl TokioExecutorRef>,
+    restrictions: Arc<ArcSwap<RestrictionsRules>>,
+    cfg: IcmpTransportConfig,
+) -> anyhow::Result<()> {
+    let socket = raw_socket(cfg.bind, server.config.socket_so_mark)
+        .with_context(|| format!("Failed to open icmp transport on {}", cfg.bind))?;
+    let socket = Arc::new(socket);
+    info!("Starting icmp transport listening on {}", cfg.bind);
+
+    let sessions = DatagramSessions::new(server.clone(), restrictions);
+
+    let mut buf = vec![0u8; u16::MAX as usize];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(ret) => ret,
+            Err(err) => {
+                warn!("Error while receiving icmp packet {err:?}");
+                continue;
+            }
+        };
+        // The raw socket receives every icmp packet of the host, only keep the ones of wstunnel clients
+        let Some(request) = EchoPacket::decode(&buf[..len]).filter(|packet| packet.is_request) else {
+            continue;
+        };
+        let Some(frame) = Frame::decode(request.frame.clone()) else {
+            continue;
+        };
+
+        let sessions = sessions.clone();
+        let socket = socket.clone();
+        server.executor.spawn(async move {
+            let answer = sessions.handle(peer, frame, MAX_FRAME_PAYLOAD).await;
+            let reply = EchoPacket {
+                is_request: false,
+                frame: answer.encode(),
+                ..request
+            };
+            if let Err(err) = socket.send_to(&reply.encode(), peer).await {
+                warn!("Cannot send icmp echo reply to {peer}: {err}");
+            }
+        });
+    }
+}
@@ -0,0 +1,129 @@
+use crate::executor::TokioExecutorRef;
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::mux::{IncomingStream, MuxSession};
+use crate::tunnel::protocol::mux_frame::parse_open_payload;
+use crate::tunnel::server::WsServer;
+use crate::tunnel::server::server::mk_span;
+use crate::tunnel::server::utils::{HttpResponse, extract_path_prefix};
+use crate::tunnel::transport::PSK_HEADER;
+use crate::tunnel::transport::io::LocalReset;
+use hyper::header::{COOKIE, HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use hyper::{Request, Uri};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadHalf, WriteHalf};
+use tracing::{Instrument, Span, warn};
+
+/// Pipe between the connection of the client and the mux session reading it
+const MUX_PIPE_SIZE: usize = 256 * 1024;
+
+type TunnelRequest<'a> = Pin<
+    Box<
+        dyn Future<
+                Output = Result<
+                    (
+                        RemoteAddr,
+                        Pin<Box<dyn AsyncRead + Send>>,
+                        Pin<Box<dyn AsyncWrite + Send>>,
+                        LocalReset,
+                        bool,
+                    ),
+                    HttpResponse,
+                >,
+            > + Send
+            + 'a,
+    >,
+>;
+
+/// Serve the tunnels a client multiplexes on its connection. Each one goes through the same checks as a tunnel with a
+/// connection of its own, with the upgrade request of the mux connection carrying the tunnel config of the stream
+pub(super) fn mux_server_session<E: TokioExecutorRef, B>(
+    server: WsServer<E>,
+    restrictions: Arc<RestrictionsRules>,
+    restrict_path_prefix: Option<String>,
+    client_addr: SocketAddr,
+    req: &Request<B>,
+) -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>) {
+    let mut upgrade_req = Request::new(());
+    *upgrade_req.method_mut() = req.method().clone();
+    *upgrade_req.uri_mut() = req.uri().clone();
+    *upgrade_req.version_mut() = req.version();
+    *upgrade_req.headers_mut() = req.headers().clone();
+    upgrade_req.headers_mut().remove(SEC_WEBSOCKET_PROTOCOL);
+    upgrade_req.headers_mut().remove(PSK_HEADER);
+    let upgrade_req = Arc::new(upgrade_req);
+
+    let (cnx, mux_cnx) = tokio::io::duplex(MUX_PIPE_SIZE);
+    let (session, mut incoming) = MuxSession::new(mux_cnx, server.executor.clone());
+    let executor = server.executor.clone();
+    executor.spawn(
+        async move {
+            while let Some(stream) = incoming.recv().await {
+                let fut = serve_stream(
+                    server.clone(),
+                    restrictions.clone(),
+                    restrict_path_prefix.clone(),
+                    client_addr,
+                    upgrade_req.clone(),
+                    session.clone(),
+                    stream,
+                );
+                server.executor.spawn(fut.instrument(mk_span()));
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    tokio::io::split(cnx)
+}
+
+async fn serve_stream<E: TokioExecutorRef>(
+    server: WsServer<E>,
+    restrictions: Arc<RestrictionsRules>,
+    restrict_path_prefix: Option<String>,
+    client_addr: SocketAddr,
+    upgrade_req: Arc<Request<()>>,
+    session: MuxSession<E>,
+    stream: IncomingStream,
+) {
+    let Some(req) = stream_request(&upgrade_req, &stream.payload) else {
+        warn!("Rejecting mux stream with invalid tunnel config");
+        return session.reject(stream.id).await;
+    };
+
+    // Boxed, as the tunnel request of a stream could be the one of a mux connection too
+    let tunnel: TunnelRequest =
+        Box::pin(server.handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req));
+    match tunnel.await {
+        Ok((_, local_rx, local_tx, _, _)) => {
+            if let Err(err) = session.forward(stream.id, local_rx, local_tx).await {
+                warn!("Error on mux stream: {err:?}");
+            }
+        }
+        Err(_) => session.reject(stream.id).await,
+    }
+}
+
+/// Upgrade request of the mux connection, carrying the tunnel config of the stream instead of its own
+fn stream_request(upgrade_req: &Request<()>, payload: &[u8]) -> Option<Request<()>> {
+    let (tunnel_token, psk_proof) = parse_open_payload(payload)?;
+    let mut req = Request::new(());
+    *req.method_mut() = upgrade_req.method().clone();
+    // The proofs of the streams are over the default upgrade path, the one of the mux connection can be randomized
+    let prefix = extract_path_prefix(upgrade_req.uri().path()).ok()?;
+    let mut uri = upgrade_req.uri().clone().into_parts();
+    uri.path_and_query = Some(format!("/{prefix}/events").parse().ok()?);
+    *req.uri_mut() = Uri::from_parts(uri).ok()?;
+    *req.version_mut() = upgrade_req.version();
+    *req.headers_mut() = upgrade_req.headers().clone();
+    req.headers_mut()
+        .insert(COOKIE, HeaderValue::from_str(tunnel_token).ok()?);
+    if let Some(psk_proof) = psk_proof {
+        req.headers_mut()
+            .insert(PSK_HEADER, HeaderValue::from_str(psk_proof).ok()?);
+    }
+    Some(req)
+}
@@ -0,0 +1,222 @@
+use crate::executor::TokioExecutorRef;
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::protocol::PROTOCOL_HEADER;
+use crate::tunnel::protocol::integrity_record::INTEGRITY_HEADER;
+use crate::tunnel::protocol::probe_record::PROBE_HEADER;
+use crate::tunnel::server::WsServer;
+use crate::tunnel::server::probe;
+use crate::tunnel::server::reject::replace_rejected;
+use crate::tunnel::server::utils::{
+    HttpResponse, bad_request, close_capabilities, early_data_ack, inject_cookie, integrity_header, protocol_header,
+    psk_proof, sticky_session,
+};
+use crate::tunnel::transport;
+use crate::tunnel::transport::ssh::{RESPONSE_HEAD_STREAM, SshTunnelRead, SshTunnelWrite};
+use crate::tunnel::transport::{EARLY_DATA_HEADER, PSK_HEADER, STICKY_SESSION_HEADER, ssh};
+use ahash::AHashMap;
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{Request, Response};
+use russh::keys::ssh_key::private::Ed25519Keypair;
+use russh::keys::{HashAlg, PrivateKey};
+use russh::server::{Auth, ChannelOpenHandle, Msg, Session};
+use russh::{Channel, ChannelId};
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::{Instrument, Span, debug, warn};
+
+#[derive(Clone)]
+pub struct SshTransportConfig {
+    /// Key the server proves its identity with during the ssh handshakes
+    pub host_key: PrivateKey,
+}
+
+impl Debug for SshTransportConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SshTransportConfig")
+            .field("host_key", &self.host_key.public_key().fingerprint(HashAlg::Sha256).to_string())
+            .finish()
+    }
+}
+
+impl SshTransportConfig {
+    /// Host key read from an openssh private key file, or a new one living as long as the server
+    pub fn new(host_key: Option<&Path>) -> anyhow::Result<Self> {
+        let host_key = match host_key {
+            Some(path) => russh::keys::load_secret_key(path, None)
+                .with_context(|| format!("cannot load ssh host key from {}", path.display()))?,
+            None => PrivateKey::from(Ed25519Keypair::from_seed(&rand::random())),
+        };
+        Ok(Self { host_key })
+    }
+
+    pub(super) fn server_config(&self, ping_frequency: Option<Duration>) -> russh::server::Config {
+        russh::server::Config {
+            keys: vec![self.host_key.clone()],
+            keepalive_interval: ping_frequency,
+            inactivity_timeout: None,
+            auth_rejection_time_initial: Some(Duration::ZERO),
+            nodelay: true,
+            ..Default::default()
+        }
+    }
+}
+
+pub(super) async fn ssh_server_session(
+    server: WsServer<impl TokioExecutorRef>,
+    ssh_config: Arc<russh::server::Config>,
+    restrictions: Arc<ArcSwap<RestrictionsRules>>,
+    client_addr: SocketAddr,
+    stream: TcpStream,
+) {
+    let handler = SshTunnelHandler {
+        server,
+        restrictions,
+        client_addr,
+        channels: AHashMap::new(),
+    };
+    let session = match russh::server::run_stream(ssh_config, stream, handler).await {
+        Ok(session) => session,
+        Err(err) => {
+            warn!("Ssh handshake with {client_addr} failed: {err:?}");
+            return;
+        }
+    };
+    if let Err(err) = session.await {
+        debug!("Ssh session with {client_addr} ended: {err:?}");
+    }
+}
+
+struct SshTunnelHandler<E: TokioExecutorRef> {
+    server: WsServer<E>,
+    restrictions: Arc<ArcSwap<RestrictionsRules>>,
+    client_addr: SocketAddr,
+    /// Channels opened by the client, waiting for the request of their tunnel
+    channels: AHashMap<ChannelId, Channel<Msg>>,
+}
+
+impl<E: TokioExecutorRef> russh::server::Handler for SshTunnelHandler<E> {
+    type Error = russh::Error;
+
+    // The tunnels are authorized one by one, like the upgrade requests of the other transports
+    async fn auth_none(&mut self, _user: &str) -> Result<Auth, Self::Error> {
+        Ok(Auth::Accept)
+    }
+
+    async fn auth_password(&mut self, _user: &str, _password: &str) -> Result<Auth, Self::Error> {
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        reply: ChannelOpenHandle,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        reply.accept().await;
+        Ok(())
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel_id: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let (Some(channel), Some(req)) = (self.channels.remove(&channel_id), ssh::decode_request(data)) else {
+            session.channel_failure(channel_id)?;
+            return Ok(());
+        };
+        session.channel_success(channel_id)?;
+
+        let tunnel = serve_tunnel(
+            self.server.clone(),
+            self.restrictions.load().clone(),
+            self.client_addr,
+            req,
+            channel,
+        );
+        self.server.executor.spawn(tunnel.instrument(Span::current()));
+        Ok(())
+    }
+
+    async fn channel_close(&mut self, channel_id: ChannelId, _session: &mut Session) -> Result<(), Self::Error> {
+        self.channels.remove(&channel_id);
+        Ok(())
+    }
+}
+
+async fn serve_tunnel(
+    server: WsServer<impl TokioExecutorRef>,
+    restrictions: Arc<RestrictionsRules>,
+    client_addr: SocketAddr,
+    req: Request<()>,
+    channel: Channel<Msg>,
+) {
+    let (remote_addr, local_rx, local_tx, local_reset, need_cookie) = match server
+        .handle_tunnel_request(restrictions, None, client_addr, &req)
+        .await
+    {
+        Ok(ret) => ret,
+        Err(err) => {
+            let err = replace_rejected(server.config.reject_response.as_ref(), err);
+            return reject(&channel, err).await;
+        }
+    };
+
+    let mut response = Response::new(Empty::<Bytes>::new());
+    if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
+        return reject(&channel, bad_request()).await;
+    }
+    if let Some(psk_proof) = psk_proof(server.config.psk.as_ref(), &req) {
+        response.headers_mut().insert(PSK_HEADER, psk_proof);
+    }
+    if let Some(early_data_ack) = early_data_ack(&req) {
+        response.headers_mut().insert(EARLY_DATA_HEADER, early_data_ack);
+    }
+    if let Some(protocol_header) = protocol_header(&req) {
+        response.headers_mut().insert(PROTOCOL_HEADER, protocol_header);
+    }
+    if let Some(probe_header) = probe::probe_header(&req) {
+        response.headers_mut().insert(PROBE_HEADER, probe_header);
+    }
+    if let Some(integrity_header) = integrity_header(&req) {
+        response.headers_mut().insert(INTEGRITY_HEADER, integrity_header);
+    }
+    if let Some(sticky_session) = sticky_session(server.config.sticky_session.as_ref(), &req) {
+        response.headers_mut().insert(STICKY_SESSION_HEADER, sticky_session);
+    }
+    let head = ssh::encode_response(response.status(), response.headers(), "");
+    if let Err(err) = channel.extended_data_bytes(RESPONSE_HEAD_STREAM, head).await {
+        warn!("Cannot answer the tunnel request on the ssh channel: {err:?}");
+        return;
+    }
+
+    let (ws_rx, ws_tx) = tokio::io::split(channel.into_stream());
+    let (close_tx, close_rx) = transport::io::close_channel(close_capabilities(&req));
+    let close_rx = close_rx.with_local_reset(local_reset);
+    server.executor.spawn(
+        transport::io::propagate_remote_to_local(local_tx, SshTunnelRead::new(ws_rx), close_rx)
+            .instrument(Span::current()),
+    );
+    let _ = transport::io::propagate_local_to_remote(local_rx, SshTunnelWrite::new(ws_tx), close_tx, None).await;
+}
+
+/// Send the response refusing the tunnel, and close its channel
+async fn reject(channel: &Channel<Msg>, response: HttpResponse) {
+    let (parts, body) = response.into_parts();
+    let body = match body.collect().await {
+        Ok(body) => String::from_utf8_lossy(&body.to_bytes()).into_owned(),
+        Err(_) => String::new(),
+    };
+    let head = ssh::encode_response(parts.status, &parts.headers, &body);
+    let _ = channel.extended_data_bytes(RESPONSE_HEAD_STREAM, head).await;
+    let _ = channel.close().await;
+}
@@ -1,18 +1,28 @@
 use crate::executor::TokioExecutorRef;
 use crate::restrictions::types::RestrictionsRules;
+use crate::stats::Side;
+use crate::tunnel::protocol::PROTOCOL_HEADER;
+use crate::tunnel::protocol::integrity_record::INTEGRITY_HEADER;
+use crate::tunnel::protocol::probe_record::PROBE_HEADER;
 use crate::tunnel::server::WsServer;
-use crate::tunnel::server::utils::{HttpResponse, bad_request, inject_cookie};
+use crate::tunnel::server::probe;
+use crate::tunnel::server::service::RequestBody;
+use crate::tunnel::server::utils::{
+    HttpResponse, bad_request, close_capabilities, early_data_ack, extract_tunnel_info, health_probe, inject_cookie,
+    integrity_header, protocol_header, psk_proof, sticky_session,
+};
 use crate::tunnel::transport;
-use crate::tunnel::transport::websocket::mk_websocket_tunnel;
+use crate::tunnel::transport::websocket::{
+    MAX_FRAME_SIZE_HEADER, max_frame_size_header, mk_websocket_tunnel, peer_max_frame_size,
+};
+use crate::tunnel::transport::{EARLY_DATA_HEADER, PSK_HEADER, STICKY_SESSION_HEADER};
 use fastwebsockets::Role;
 use http_body_util::Either;
 use http_body_util::combinators::BoxBody;
-use hyper::body::Incoming;
 use hyper::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
 use hyper::{Request, Response};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::oneshot;
 use tracing::{Instrument, Span, error, warn};
 
 pub(super) async fn ws_server_upgrade(
@@ -20,21 +30,36 @@ pub(super) async fn ws_server_upgrade(
     restrictions: Arc<RestrictionsRules>,
     restrict_path_prefix: Option<String>,
     client_addr: SocketAddr,
-    mut req: Request<Incoming>,
+    req: Request<impl RequestBody>,
 ) -> HttpResponse {
+    if let Some(response) = health_probe(&req) {
+        return response;
+    }
+    // The body of an upgrade request is empty, only keep its head that can be shared between threads
+    let mut req = req.map(|_| ());
     if !fastwebsockets::upgrade::is_upgrade_request(&req) {
         warn!("Rejecting connection with bad upgrade request: {}", req.uri());
         return bad_request();
     }
 
     let mask_frame = server.config.websocket_mask_frame;
-    let (remote_addr, local_rx, local_tx, need_cookie) = match server
+    let max_frame_size = server.config.websocket_max_frame_size;
+    let client_max_frame_size = peer_max_frame_size(req.headers());
+    let (remote_addr, local_rx, local_tx, local_reset, need_cookie) = match server
         .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
         .await
     {
         Ok(ret) => ret,
         Err(err) => return err,
     };
+    let psk_proof = psk_proof(server.config.psk.as_ref(), &req);
+    let sticky_session = sticky_session(server.config.sticky_session.as_ref(), &req);
+    let early_data_ack = early_data_ack(&req);
+    let protocol_header = protocol_header(&req);
+    let probe_header = probe::probe_header(&req);
+    let integrity_header = integrity_header(&req);
+    let close_capabilities = close_capabilities(&req);
+    let tunnel_id = extract_tunnel_info(&req).map(|jwt| jwt.claims.id).unwrap_or_default();
 
     let (response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
         Ok(ret) => ret,
@@ -48,19 +73,28 @@ pub(super) async fn ws_server_upgrade(
     server.executor.spawn(
         async move {
             let (ws_rx, ws_tx) = match fut.await {
-                Ok(ws) => match mk_websocket_tunnel(ws, Role::Server, mask_frame) {
-                    Ok(ws) => ws,
-                    Err(err) => {
-                        error!("Error during http upgrade request: {:?}", err);
-                        return Err(err);
+                Ok(ws) => {
+                    match mk_websocket_tunnel(ws, Role::Server, mask_frame, max_frame_size, client_max_frame_size) {
+                        Ok((ws_rx, ws_tx)) => (
+                            ws_rx,
+                            ws_tx
+                                .with_stats_of(Side::Server, tunnel_id)
+                                .with_obfuscation(server.config.traffic_obfuscation)
+                                .with_close(server.config.websocket_close_timeout, server.config.websocket_close_codes),
+                        ),
+                        Err(err) => {
+                            error!("Error during http upgrade request: {:?}", err);
+                            return Err(err);
+                        }
                     }
-                },
+                }
                 Err(err) => {
                     error!("Error during http upgrade request: {:?}", err);
                     return Err(anyhow::Error::from(err));
                 }
             };
-            let (close_tx, close_rx) = oneshot::channel::<()>();
+            let (close_tx, close_rx) = transport::io::close_channel(close_capabilities);
+            let close_rx = close_rx.with_local_reset(local_reset);
 
             executor
                 .spawn(transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).instrument(Span::current()));
@@ -85,6 +119,27 @@ pub(super) async fn ws_server_upgrade(
     response
         .headers_mut()
         .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1"));
+    response
+        .headers_mut()
+        .insert(MAX_FRAME_SIZE_HEADER, max_frame_size_header(max_frame_size));
+    if let Some(psk_proof) = psk_proof {
+        response.headers_mut().insert(PSK_HEADER, psk_proof);
+    }
+    if let Some(early_data_ack) = early_data_ack {
+        response.headers_mut().insert(EARLY_DATA_HEADER, early_data_ack);
+    }
+    if let Some(protocol_header) = protocol_header {
+        response.headers_mut().insert(PROTOCOL_HEADER, protocol_header);
+    }
+    if let Some(probe_header) = probe_header {
+        response.headers_mut().insert(PROBE_HEADER, probe_header);
+    }
+    if let Some(integrity_header) = integrity_header {
+        response.headers_mut().insert(INTEGRITY_HEADER, integrity_header);
+    }
+    if let Some(sticky_session) = sticky_session {
+        response.headers_mut().insert(STICKY_SESSION_HEADER, sticky_session);
+    }
 
     response
 }
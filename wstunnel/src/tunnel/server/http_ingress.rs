@@ -0,0 +1,450 @@
+use crate::executor::TokioExecutorRef;
+use crate::protocols::http_client;
+use crate::protocols::http_client::HttpClientConfig;
+use crate::tunnel::server::WsServer;
+use crate::tunnel::server::service::RequestBody;
+use crate::tunnel::server::utils::HttpResponse;
+use crate::tunnel::{HttpIngressAuth, LocalProtocol, RemoteAddr, http_ingress_subdomain};
+use ahash::AHashMap;
+use anyhow::{Context, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use futures_util::Stream;
+use http_body_util::{BodyExt, Either};
+use hyper::header::{
+    AUTHORIZATION, CONTENT_TYPE, COOKIE, HOST, HeaderName, HeaderValue, LOCATION, SET_COOKIE, UPGRADE, WWW_AUTHENTICATE,
+};
+use hyper::http::uri::PathAndQuery;
+use hyper::{Method, Request, Response, StatusCode, Uri, Version};
+use hyper_util::rt::TokioIo;
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadHalf, WriteHalf};
+use tracing::{info, warn};
+use url::{Host, Url};
+use uuid::Uuid;
+
+/// Virtual hosts exposed by the clients
+static VHOSTS: LazyLock<Mutex<AHashMap<String, VirtualHost>>> = LazyLock::new(|| Mutex::new(AHashMap::new()));
+
+/// Channel handing the connections of the requests to the client exposing the virtual host
+#[derive(Clone)]
+pub(super) struct VirtualHost {
+    sender: async_channel::Sender<DuplexStream>,
+    auth: Option<HttpIngressAuth>,
+}
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Wildcard domain owned by the server, under which the clients get a subdomain for their reverse http ingress
+#[derive(Clone, Debug)]
+pub struct HttpIngressDomain {
+    /// i.e: tunnels.example.com, with a wildcard dns record pointing to the server
+    pub domain: String,
+    /// Names of the domain reserved for a client identity, its upgrade path prefix or mTLS certificate common name followed
+    /// by its device id if it has one
+    pub reserved: Vec<(String, String)>,
+}
+
+impl HttpIngressDomain {
+    /// A subdomain can only be exposed by the client it is reserved for, or by the session it is allocated to
+    pub(super) fn check(&self, vhost: &str, session: Option<&Uuid>, identity: &str) -> anyhow::Result<()> {
+        let Some(name) = vhost
+            .strip_suffix(self.domain.as_str())
+            .and_then(|name| name.strip_suffix('.'))
+        else {
+            return Ok(());
+        };
+
+        if let Some((_, owner)) = self.reserved.iter().find(|(reserved, _)| reserved == name) {
+            return if owner == identity {
+                Ok(())
+            } else {
+                Err(anyhow!("{vhost} is reserved for another client"))
+            };
+        }
+        if session.is_some_and(|session| http_ingress_subdomain(session) == name) {
+            return Ok(());
+        }
+
+        Err(anyhow!("{vhost} is not allocated to the client"))
+    }
+}
+
+/// Yield a connection for each http request received for its virtual host, to be forwarded to the client
+pub struct HttpIngressListener {
+    vhost: String,
+    sender: async_channel::Sender<DuplexStream>,
+    receiver: Pin<Box<async_channel::Receiver<DuplexStream>>>,
+}
+
+impl HttpIngressListener {
+    pub fn new(vhost: &str, auth: Option<HttpIngressAuth>) -> anyhow::Result<Self> {
+        let (sender, receiver) = async_channel::bounded(1);
+        let mut vhosts = VHOSTS.lock();
+        if vhosts.contains_key(vhost) {
+            return Err(anyhow!("virtual host {vhost} is already exposed"));
+        }
+        info!(
+            "Exposing virtual host {vhost}{}",
+            match &auth {
+                Some(HttpIngressAuth::Basic(_)) => " with basic auth",
+                Some(HttpIngressAuth::Forward(_)) => " with forward auth",
+                None => "",
+            }
+        );
+        let virtual_host = VirtualHost {
+            sender: sender.clone(),
+            auth,
+        };
+        vhosts.insert(vhost.to_string(), virtual_host);
+
+        Ok(Self {
+            vhost: vhost.to_string(),
+            sender,
+            receiver: Box::pin(receiver),
+        })
+    }
+}
+
+impl Drop for HttpIngressListener {
+    fn drop(&mut self) {
+        let mut vhosts = VHOSTS.lock();
+        if vhosts
+            .get(&self.vhost)
+            .is_some_and(|v| v.sender.same_channel(&self.sender))
+        {
+            vhosts.remove(&self.vhost);
+        }
+    }
+}
+
+impl Stream for HttpIngressListener {
+    type Item = anyhow::Result<((ReadHalf<DuplexStream>, WriteHalf<DuplexStream>), RemoteAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.receiver.as_mut().poll_next(cx).map(|cnx| {
+            cnx.map(|cnx| {
+                let remote = RemoteAddr {
+                    protocol: LocalProtocol::ReverseHttpIngress {
+                        vhost: this.vhost.clone(),
+                        session: None,
+                        auth: None,
+                    },
+                    host: Host::Domain(this.vhost.clone()),
+                    port: 0,
+                };
+                Ok((tokio::io::split(cnx), remote))
+            })
+        })
+    }
+}
+
+/// Host the request is sent to, without its port, as the virtual hosts are registered
+pub(super) fn request_vhost<B>(req: &Request<B>) -> Option<String> {
+    // http2 requests carry the host in the uri
+    let host = match req.uri().host() {
+        Some(host) => host,
+        None => req.headers().get(HOST)?.to_str().ok()?,
+    };
+    if host.starts_with('[') {
+        return None;
+    }
+    let host = host.split_once(':').map_or(host, |(host, _)| host);
+    Some(host.to_ascii_lowercase())
+}
+
+/// Client exposing the virtual host of the request, if any
+pub(super) fn find_vhost<B>(req: &Request<B>) -> Option<VirtualHost> {
+    VHOSTS.lock().get(&request_vhost(req)?).cloned()
+}
+
+/// Virtual hosts exposed by the clients connected to this server
+pub(super) fn local_vhosts() -> Vec<String> {
+    VHOSTS.lock().keys().cloned().collect()
+}
+
+/// Forward the request to the local web app of a client through its reverse tunnel, upgrades included
+pub(super) async fn forward_request<E: TokioExecutorRef>(
+    server: &WsServer<E>,
+    vhost: VirtualHost,
+    client_addr: SocketAddr,
+    req: Request<impl RequestBody>,
+) -> HttpResponse {
+    let tls = server.config.tls.is_some();
+    let denied = match &vhost.auth {
+        None => None,
+        Some(HttpIngressAuth::Basic(credentials)) => basic_auth(credentials, &req),
+        Some(HttpIngressAuth::Forward(url)) => {
+            let headers = forward_auth_headers(&req, client_addr, tls);
+            forward_auth(server, url, &headers).await
+        }
+    };
+    if let Some(response) = denied {
+        return response;
+    }
+
+    match forward(
+        &server.executor,
+        vhost.sender,
+        client_addr,
+        tls,
+        server.config.timeout_connect,
+        req,
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(err) => {
+            warn!("Cannot forward http request to the client: {err:?}");
+            Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(Either::Left("Bad gateway".to_string()))
+                .unwrap()
+        }
+    }
+}
+
+/// Check the credentials of the visitor, or return the response asking for them
+fn basic_auth<B>(credentials: &str, req: &Request<B>) -> Option<HttpResponse> {
+    let expected = format!("Basic {}", STANDARD.encode(credentials));
+    let authorization = req.headers().get(AUTHORIZATION).map(HeaderValue::as_bytes);
+    if authorization.is_some_and(|authorization| constant_time_eq(authorization, expected.as_bytes())) {
+        return None;
+    }
+
+    Some(
+        Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, "Basic realm=\"wstunnel\"")
+            .body(Either::Left("Unauthorized".to_string()))
+            .unwrap(),
+    )
+}
+
+/// Headers sent to the forward auth server, with the same x-forwarded-* names as the main reverse proxies
+fn forward_auth_headers<B>(req: &Request<B>, client_addr: SocketAddr, tls: bool) -> Vec<(HeaderName, HeaderValue)> {
+    let mut headers: Vec<(HeaderName, HeaderValue)> = [AUTHORIZATION, COOKIE]
+        .into_iter()
+        .filter_map(|name| Some((name.clone(), req.headers().get(name)?.clone())))
+        .collect();
+    let host = match req.uri().authority() {
+        Some(authority) => HeaderValue::from_str(authority.as_str()).ok(),
+        None => req.headers().get(HOST).cloned(),
+    };
+    headers.extend(host.map(|host| (HeaderName::from_static("x-forwarded-host"), host)));
+    let uri = req.uri().path_and_query().map_or("/", PathAndQuery::as_str);
+    headers.extend(
+        HeaderValue::from_str(uri)
+            .ok()
+            .map(|uri| (HeaderName::from_static("x-forwarded-uri"), uri)),
+    );
+    headers.extend(
+        HeaderValue::from_str(req.method().as_str())
+            .ok()
+            .map(|method| (HeaderName::from_static("x-forwarded-method"), method)),
+    );
+    headers.push((
+        HeaderName::from_static("x-forwarded-proto"),
+        HeaderValue::from_static(if tls { "https" } else { "http" }),
+    ));
+    headers.extend(
+        HeaderValue::from_str(&client_addr.ip().to_string())
+            .ok()
+            .map(|ip| (HeaderName::from_static("x-forwarded-for"), ip)),
+    );
+    headers
+}
+
+/// Ask the auth server if the visitor is allowed, or return its response, i.e: a redirect to its sign-in page
+async fn forward_auth<E: TokioExecutorRef>(
+    server: &WsServer<E>,
+    url: &Url,
+    headers: &[(HeaderName, HeaderValue)],
+) -> Option<HttpResponse> {
+    let http_cfg = HttpClientConfig {
+        so_mark: server.config.socket_so_mark,
+        timeout: server.config.timeout_connect,
+        dns_resolver: server.config.dns_resolver.clone(),
+    };
+    let response = match http_client::fetch(&http_cfg, Method::GET, url, headers, Bytes::new()).await {
+        Ok(response) if response.status().is_success() => return None,
+        Ok(response) => response,
+        Err(err) => {
+            warn!("Cannot reach http ingress auth {url}: {err:?}");
+            return Some(
+                Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Either::Left("Bad gateway".to_string()))
+                    .unwrap(),
+            );
+        }
+    };
+
+    let mut denied = Response::builder().status(response.status());
+    for (name, value) in response.headers() {
+        if [LOCATION, SET_COOKIE, WWW_AUTHENTICATE, CONTENT_TYPE].contains(name) {
+            denied = denied.header(name, value);
+        }
+    }
+    let body = String::from_utf8_lossy(response.body()).into_owned();
+    Some(denied.body(Either::Left(body)).unwrap())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn forward(
+    executor: &impl TokioExecutorRef,
+    vhost: async_channel::Sender<DuplexStream>,
+    client_addr: SocketAddr,
+    tls: bool,
+    timeout: Duration,
+    req: Request<impl RequestBody>,
+) -> anyhow::Result<HttpResponse> {
+    let (cnx, client_cnx) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::time::timeout(timeout, vhost.send(client_cnx))
+        .await
+        .context("no client available for the virtual host")?
+        .context("virtual host is not exposed anymore")?;
+
+    proxy(executor, cnx, client_addr, tls, req).await
+}
+
+/// Send the request on the connection, as a reverse proxy would, upgrades included
+pub(super) async fn proxy(
+    executor: &impl TokioExecutorRef,
+    cnx: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    client_addr: SocketAddr,
+    tls: bool,
+    mut req: Request<impl RequestBody>,
+) -> anyhow::Result<HttpResponse> {
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(cnx)).await?;
+    executor.spawn(async move {
+        if let Err(err) = conn.with_upgrades().await {
+            warn!("Error on http ingress connection: {err:?}");
+        }
+    });
+
+    // The web app always receives an http1 request in origin form, with the headers of a reverse proxy
+    let host = match req.uri().authority() {
+        Some(authority) => HeaderValue::from_str(authority.as_str())?,
+        None => req.headers().get(HOST).cloned().unwrap_or(HeaderValue::from_static("")),
+    };
+    *req.uri_mut() = Uri::from(
+        req.uri()
+            .path_and_query()
+            .cloned()
+            .unwrap_or(PathAndQuery::from_static("/")),
+    );
+    *req.version_mut() = Version::HTTP_11;
+    let forwarded_for = match req.headers().get("x-forwarded-for").and_then(|h| h.to_str().ok()) {
+        Some(forwarded_for) => format!("{forwarded_for}, {}", client_addr.ip()),
+        None => client_addr.ip().to_string(),
+    };
+    let headers = req.headers_mut();
+    headers.insert("x-forwarded-for", HeaderValue::from_str(&forwarded_for)?);
+    headers.insert("x-forwarded-host", host.clone());
+    headers.insert(
+        "x-forwarded-proto",
+        HeaderValue::from_static(if tls { "https" } else { "http" }),
+    );
+    headers.insert(HOST, host);
+
+    let downstream = req
+        .headers()
+        .contains_key(UPGRADE)
+        .then(|| hyper::upgrade::on(&mut req));
+    let mut response = sender.send_request(req).await?;
+    if let Some(downstream) = downstream
+        && response.status() == StatusCode::SWITCHING_PROTOCOLS
+    {
+        let upstream = hyper::upgrade::on(&mut response);
+        executor.spawn(async move {
+            let (Ok(downstream), Ok(upstream)) = tokio::join!(downstream, upstream) else {
+                warn!("Cannot upgrade http ingress connection");
+                return;
+            };
+            let _ = tokio::io::copy_bidirectional(&mut TokioIo::new(downstream), &mut TokioIo::new(upstream)).await;
+        });
+    }
+
+    Ok(response.map(|body| Either::Right(body.map_err(anyhow::Error::from).boxed())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_ingress_domain_check() {
+        let domain = HttpIngressDomain {
+            domain: "tunnels.example.com".to_string(),
+            reserved: vec![("myapp".to_string(), "team-a".to_string())],
+        };
+        let session = Uuid::new_v4();
+        let allocated = format!("{}.tunnels.example.com", http_ingress_subdomain(&session));
+
+        assert!(domain.check("myapp.tunnels.example.com", None, "team-a").is_ok());
+        assert!(
+            domain
+                .check("myapp.tunnels.example.com", Some(&session), "team-b")
+                .is_err()
+        );
+        assert!(domain.check(&allocated, Some(&session), "team-b").is_ok());
+        assert!(domain.check(&allocated, Some(&Uuid::new_v4()), "team-b").is_err());
+        assert!(domain.check(&allocated, None, "team-b").is_err());
+        assert!(domain.check("app.example.com", None, "team-b").is_ok());
+    }
+
+    #[test]
+    fn test_http_ingress_basic_auth() {
+        let req = |authorization: Option<&str>| {
+            let mut req = Request::builder().uri("/");
+            if let Some(authorization) = authorization {
+                req = req.header(AUTHORIZATION, authorization);
+            }
+            req.body(()).unwrap()
+        };
+
+        assert!(basic_auth("alice:s3cret", &req(Some("Basic YWxpY2U6czNjcmV0"))).is_none());
+        assert!(basic_auth("alice:s3cret", &req(Some("Basic YWxpY2U6d3Jvbmc="))).is_some());
+        let response = basic_auth("alice:s3cret", &req(None)).unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+    }
+
+    #[test]
+    fn test_forward_auth_headers() {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/api?x=1")
+            .header(HOST, "myapp.example.com")
+            .header(COOKIE, "_oauth2_proxy=abc")
+            .header("x-custom", "not forwarded")
+            .body(())
+            .unwrap();
+        let headers = forward_auth_headers(&req, "10.0.0.1:1234".parse().unwrap(), true);
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.to_str().unwrap().to_string())
+        };
+
+        assert_eq!(header("cookie").as_deref(), Some("_oauth2_proxy=abc"));
+        assert_eq!(header("x-forwarded-host").as_deref(), Some("myapp.example.com"));
+        assert_eq!(header("x-forwarded-uri").as_deref(), Some("/api?x=1"));
+        assert_eq!(header("x-forwarded-method").as_deref(), Some("POST"));
+        assert_eq!(header("x-forwarded-proto").as_deref(), Some("https"));
+        assert_eq!(header("x-forwarded-for").as_deref(), Some("10.0.0.1"));
+        assert_eq!(header("x-custom"), None);
+    }
+}
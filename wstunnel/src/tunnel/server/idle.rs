@@ -0,0 +1,137 @@
+use crate::metrics::{METRICS, Metrics};
+use crate::tunnel::protocol::close_reason::CloseReason;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+use tracing::info;
+
+/// Wrap both halves of a tunnel, so that the reader fails with the [`CloseReason::Idle`] once no data went through either
+/// of them for `timeout`. The peer is told the reason, so the tunnel is known to be reaped, and still closes gracefully,
+/// the same way as if the destination closed the connection. The reader reaches EOF after it
+pub fn with_idle_timeout<R, W>(rx: R, tx: W, timeout: Duration) -> (IdleReader<R>, IdleWriter<W>) {
+    let last_activity = Arc::new(Mutex::new(Instant::now()));
+    let reader = IdleReader {
+        inner: rx,
+        last_activity: last_activity.clone(),
+        timeout,
+        sleep: tokio::time::sleep(timeout),
+        reaped: false,
+    };
+    let writer = IdleWriter {
+        inner: tx,
+        last_activity,
+    };
+
+    (reader, writer)
+}
+
+#[pin_project]
+pub struct IdleReader<R> {
+    #[pin]
+    inner: R,
+    last_activity: Arc<Mutex<Instant>>,
+    timeout: Duration,
+    #[pin]
+    sleep: Sleep,
+    reaped: bool,
+}
+
+impl<R: AsyncRead> AsyncRead for IdleReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        if *this.reaped {
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        if let Poll::Ready(ret) = this.inner.poll_read(cx, buf) {
+            if buf.filled().len() > filled {
+                *this.last_activity.lock() = Instant::now();
+            }
+            return Poll::Ready(ret);
+        }
+
+        // The writer may have seen some traffic since the timer was armed, so re-arm it until it really expires
+        loop {
+            let deadline = *this.last_activity.lock() + *this.timeout;
+            if this.sleep.deadline() != deadline {
+                this.sleep.as_mut().reset(deadline);
+            }
+            ready!(this.sleep.as_mut().poll(cx));
+            if *this.last_activity.lock() + *this.timeout <= Instant::now() {
+                break;
+            }
+        }
+
+        info!("Closing tunnel, no data went through it for {:?}", this.timeout);
+        Metrics::inc(&METRICS.tunnels_reaped_idle);
+        *this.reaped = true;
+        Poll::Ready(Err(io::Error::other(CloseReason::Idle)))
+    }
+}
+
+#[pin_project]
+pub struct IdleWriter<W> {
+    #[pin]
+    inner: W,
+    last_activity: Arc<Mutex<Instant>>,
+}
+
+impl<W: AsyncWrite> AsyncWrite for IdleWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let ret = ready!(this.inner.poll_write(cx, buf));
+        if matches!(ret, Ok(len) if len > 0) {
+            *this.last_activity.lock() = Instant::now();
+        }
+        Poll::Ready(ret)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (rx, mut peer_tx) = tokio::io::duplex(1024);
+        let (mut peer_rx, tx) = tokio::io::duplex(1024);
+        let (rx, tx) = with_idle_timeout(rx, tx, Duration::from_millis(200));
+        tokio::pin!(rx, tx);
+        let start = Instant::now();
+
+        // Traffic in the other direction keeps the tunnel alive
+        let writer = async {
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                tx.write_all(b"ping").await.unwrap();
+            }
+            tx
+        };
+        let mut buf = [0u8; 16];
+        let (read, _tx) = tokio::join!(rx.read(&mut buf), writer);
+        assert_eq!(CloseReason::of_io_error(&read.unwrap_err()), CloseReason::Idle);
+        assert!(start.elapsed() >= Duration::from_millis(650));
+        assert_eq!(peer_rx.read(&mut buf).await.unwrap(), 12);
+
+        // Once reaped, the reader stays closed even if data arrives
+        peer_tx.write_all(b"late").await.unwrap();
+        assert_eq!(rx.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,136 @@
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    MaxClients(usize),
+    MaxTunnelsPerClient(usize),
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MaxClients(max) => write!(f, "too many clients, max is {max}"),
+            Self::MaxTunnelsPerClient(max) => write!(f, "too many tunnels for this client, max is {max}"),
+        }
+    }
+}
+
+/// Number of opened tunnels of each client (ip address) of the server, to bound them
+pub struct ClientLimits {
+    max_clients: Option<usize>,
+    max_tunnels_per_client: Option<usize>,
+    tunnels: Arc<Mutex<AHashMap<IpAddr, usize>>>,
+}
+
+impl ClientLimits {
+    pub fn new(max_clients: Option<usize>, max_tunnels_per_client: Option<usize>) -> Self {
+        Self {
+            max_clients,
+            max_tunnels_per_client,
+            tunnels: Arc::new(Mutex::new(AHashMap::new())),
+        }
+    }
+
+    /// Reserve a tunnel for the client, it is released when the returned permit is dropped
+    pub fn acquire(&self, client: IpAddr) -> Result<TunnelPermit, LimitExceeded> {
+        let mut tunnels = self.tunnels.lock();
+        match tunnels.get_mut(&client) {
+            Some(count) => {
+                if let Some(max) = self.max_tunnels_per_client
+                    && *count >= max
+                {
+                    return Err(LimitExceeded::MaxTunnelsPerClient(max));
+                }
+                *count += 1;
+            }
+            None => {
+                if let Some(max) = self.max_clients
+                    && tunnels.len() >= max
+                {
+                    return Err(LimitExceeded::MaxClients(max));
+                }
+                if self.max_tunnels_per_client == Some(0) {
+                    return Err(LimitExceeded::MaxTunnelsPerClient(0));
+                }
+                tunnels.insert(client, 1);
+            }
+        }
+
+        Ok(TunnelPermit {
+            client,
+            tunnels: self.tunnels.clone(),
+        })
+    }
+}
+
+pub struct TunnelPermit {
+    client: IpAddr,
+    tunnels: Arc<Mutex<AHashMap<IpAddr, usize>>>,
+}
+
+impl Drop for TunnelPermit {
+    fn drop(&mut self) {
+        let mut tunnels = self.tunnels.lock();
+        if let Some(count) = tunnels.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                tunnels.remove(&self.client);
+            }
+        }
+    }
+}
+
+/// Keep the permit of a tunnel for as long as its local side is being read
+#[pin_project]
+pub struct WithPermit<R> {
+    #[pin]
+    inner: R,
+    _permit: TunnelPermit,
+}
+
+impl<R> WithPermit<R> {
+    pub const fn new(inner: R, permit: TunnelPermit) -> Self {
+        Self { inner, _permit: permit }
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for WithPermit<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_client_limits() {
+        let limits = ClientLimits::new(Some(2), Some(2));
+        let client = |i| IpAddr::V4(Ipv4Addr::new(10, 0, 0, i));
+
+        let a1 = limits.acquire(client(1)).unwrap();
+        let _a2 = limits.acquire(client(1)).unwrap();
+        assert_eq!(limits.acquire(client(1)).err(), Some(LimitExceeded::MaxTunnelsPerClient(2)));
+
+        let b1 = limits.acquire(client(2)).unwrap();
+        assert_eq!(limits.acquire(client(3)).err(), Some(LimitExceeded::MaxClients(2)));
+
+        // Released tunnels free their slot
+        drop(a1);
+        let _a3 = limits.acquire(client(1)).unwrap();
+        drop(b1);
+        let _c1 = limits.acquire(client(3)).unwrap();
+    }
+}
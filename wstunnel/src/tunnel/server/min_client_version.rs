@@ -0,0 +1,55 @@
+//! min_client_version - refuse the tunnels of the clients older than --require-min-client-version, so the operators of a
+//! fleet can force the upgrade of the clients after a security fix. The refusal tells the version to upgrade to
+use crate::tunnel::protocol::client_version::{ClientVersion, decode_client_version};
+use crate::tunnel::server::utils::HttpResponse;
+use http_body_util::Either;
+use hyper::{Request, StatusCode, http};
+use tracing::warn;
+
+/// Refusal of the request of a client older than `min_version`. Not replaced by --reject-*, the clients must see why
+/// they are refused, it is only sent to the requests carrying a tunnel
+pub(super) fn check<B>(min_version: ClientVersion, req: &Request<B>) -> Option<HttpResponse> {
+    let message = match decode_client_version(req.headers()) {
+        Some(version) if version >= min_version => return None,
+        Some(version) => format!("wstunnel client {version} is too old, upgrade it to {min_version} or newer"),
+        None => format!("wstunnel client does not tell its version, upgrade it to {min_version} or newer"),
+    };
+
+    warn!("Rejecting connection: {message}");
+    Some(
+        http::Response::builder()
+            .status(StatusCode::UPGRADE_REQUIRED)
+            .body(Either::Left(message))
+            .unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::protocol::client_version::CLIENT_VERSION_HEADER;
+
+    #[test]
+    fn test_check_min_client_version() {
+        let min_version = "10.5.0".parse().unwrap();
+        let request = |version: Option<&str>| {
+            let mut req = Request::builder();
+            if let Some(version) = version {
+                req = req.header(CLIENT_VERSION_HEADER, version);
+            }
+            req.body(()).unwrap()
+        };
+
+        assert!(check(min_version, &request(Some("10.5.0"))).is_none());
+        assert!(check(min_version, &request(Some("11.0.0-rc.1"))).is_none());
+
+        let response = check(min_version, &request(Some("10.4.9"))).unwrap();
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        let Either::Left(body) = response.body() else {
+            panic!("expected a text body");
+        };
+        assert_eq!(body, "wstunnel client 10.4.9 is too old, upgrade it to 10.5.0 or newer");
+        assert!(check(min_version, &request(None)).is_some());
+        assert!(check(min_version, &request(Some("garbage"))).is_some());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::TokioExecutorRef;
+use crate::protocols::dns::DnsResolver;
+use crate::somark::SoMark;
+use crate::tunnel::connectors::{TcpTunnelConnector, TunnelConnector};
+use bytes::Bytes;
+use pin_project::pin_project;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{Instrument, Span, info, warn};
+use url::Host;
+
+/// Number of writes queued for the mirror before it is considered too slow
+const MIRROR_QUEUE_SIZE: usize = 256;
+
+/// Copy everything written to `tx` to a tcp connection with `host:port`, whose responses are discarded.
+/// The mirror is best effort, it never slows down nor fails the tunnel
+pub fn with_mirror<W>(
+    executor: &impl TokioExecutorRef,
+    tx: W,
+    (host, port): (Host, u16),
+    so_mark: SoMark,
+    dns_resolver: DnsResolver,
+) -> MirrorWriter<W> {
+    let (mirror_tx, mirror_rx) = mpsc::channel(MIRROR_QUEUE_SIZE);
+    executor.spawn(run_mirror(host, port, so_mark, dns_resolver, mirror_rx).instrument(Span::current()));
+    MirrorWriter::new(tx, mirror_tx)
+}
+
+async fn run_mirror(host: Host, port: u16, so_mark: SoMark, dns_resolver: DnsResolver, mut rx: mpsc::Receiver<Bytes>) {
+    let connector = TcpTunnelConnector::new(&host, port, so_mark, Duration::from_secs(10), &dns_resolver);
+    let (mut mirror_rx, mut mirror_tx) = match connector.connect(&None).await {
+        Ok(ret) => ret,
+        Err(err) => {
+            warn!("Cannot connect to mirror {host}:{port}, traffic is not mirrored: {err:?}");
+            return;
+        }
+    };
+    info!("Mirroring traffic to {host}:{port}");
+
+    let discard_responses = async {
+        let _ = tokio::io::copy(&mut mirror_rx, &mut tokio::io::sink()).await;
+        std::future::pending::<()>().await;
+    };
+    let forward = async {
+        while let Some(data) = rx.recv().await {
+            if let Err(err) = mirror_tx.write_all(&data).await {
+                warn!("Stop mirroring traffic to {host}:{port}: {err}");
+                return;
+            }
+        }
+        let _ = mirror_tx.shutdown().await;
+    };
+
+    // The mirror is closed once the tunnel is, whatever the mirror is still answering
+    tokio::select! {
+        _ = discard_responses => {},
+        _ = forward => {},
+    }
+}
+
+#[pin_project]
+pub struct MirrorWriter<W> {
+    #[pin]
+    inner: W,
+    mirror: Option<mpsc::Sender<Bytes>>,
+}
+
+impl<W> MirrorWriter<W> {
+    pub const fn new(inner: W, mirror: mpsc::Sender<Bytes>) -> Self {
+        Self {
+            inner,
+            mirror: Some(mirror),
+        }
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for MirrorWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let written = ready!(this.inner.poll_write(cx, buf))?;
+
+        if let Some(mirror) = this.mirror {
+            match mirror.try_send(Bytes::copy_from_slice(&buf[..written])) {
+                Ok(()) => {}
+                // Dropping some bytes would corrupt the mirrored stream, so stop mirroring altogether
+                Err(TrySendError::Full(_)) => {
+                    warn!("Mirror is too slow to keep up with the tunnel, stop mirroring it");
+                    *this.mirror = None;
+                }
+                Err(TrySendError::Closed(_)) => *this.mirror = None,
+            }
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        *this.mirror = None;
+        this.inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_mirror_writer() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let (mirror_tx, mut mirror_rx) = mpsc::channel(2);
+        let mut writer = MirrorWriter::new(client, mirror_tx);
+
+        writer.write_all(b"hello").await.unwrap();
+        writer.write_all(b"world").await.unwrap();
+        assert_eq!(mirror_rx.recv().await.unwrap(), Bytes::from_static(b"hello"));
+        assert_eq!(mirror_rx.recv().await.unwrap(), Bytes::from_static(b"world"));
+
+        // A mirror that does not keep up is dropped, without failing the tunnel
+        for _ in 0..3 {
+            writer.write_all(b"!").await.unwrap();
+        }
+        assert!(writer.mirror.is_none());
+        writer.shutdown().await.unwrap();
+
+        let mut received = vec![];
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"helloworld!!!");
+    }
+}
@@ -1,10 +1,49 @@
 #![allow(clippy::module_inception)]
+mod auth_hook;
+mod cluster;
+mod demux;
+mod failover;
+#[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+mod handler_datagram;
+#[cfg(feature = "dns-transport")]
+mod handler_dns;
+mod handler_http1;
 mod handler_http2;
+#[cfg(feature = "icmp-transport")]
+mod handler_icmp;
+mod handler_mux;
+#[cfg(feature = "ssh-transport")]
+mod handler_ssh;
 mod handler_websocket;
+mod http_ingress;
+mod idle;
+mod limits;
+mod min_client_version;
+mod mirror;
+mod probe;
+mod reject;
+mod resume;
 mod reverse_tunnel;
 mod server;
+mod service;
+mod standby;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 mod utils;
 
+pub use auth_hook::AuthHook;
+pub use auth_hook::AuthHookRequest;
+pub use cluster::ClusterConfig;
+pub use demux::{ProtocolHandler, SniPassthrough, SniffedProtocol};
+#[cfg(feature = "dns-transport")]
+pub use handler_dns::DnsTransportConfig;
+#[cfg(feature = "icmp-transport")]
+pub use handler_icmp::IcmpTransportConfig;
+#[cfg(feature = "ssh-transport")]
+pub use handler_ssh::SshTransportConfig;
+pub use http_ingress::HttpIngressDomain;
+pub use reject::RejectResponse;
 pub use server::TlsServerConfig;
 pub use server::WsServer;
 pub use server::WsServerConfig;
+pub use service::{RequestBody, WsServerService};
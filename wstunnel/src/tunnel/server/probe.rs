@@ -0,0 +1,254 @@
+//! probe - reverse tunnels probed by the server while they wait for a connection, when the client asks for it. The
+//! upgrade is answered right away, and the server pings the client on the tunnel until a connection arrives, so a
+//! listener whose client is gone is unbound after a few missed probes instead of after --remote-to-local-server-idle-timeout.
+//! See [`crate::tunnel::protocol::probe_record`] for the records exchanged
+use crate::executor::TokioExecutorRef;
+use crate::tunnel::protocol::probe_record::{MAX_MISSED_PROBES, PROBE_HEADER, ProbeRecord};
+use crate::tunnel::server::utils::{HttpResponse, extract_tunnel_info};
+use crate::tunnel::transport::tunnel_to_jwt_token;
+use crate::tunnel::{LocalProtocol, RemoteAddr};
+use bytes::{Bytes, BytesMut};
+use hyper::Request;
+use hyper::http::HeaderValue;
+use std::future::Future;
+use std::pin::{Pin, pin};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::select;
+use tokio::time::{Instant, interval_at};
+use tracing::{Instrument, Span, debug, info, warn};
+use uuid::Uuid;
+
+const MIN_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Interval the client asked to be probed at, only honored for the reverse tunnels that are not resumable, as the other
+/// ones do not wait for a connection or already survive the loss of their client
+pub(super) fn probe_interval<B>(req: &Request<B>, protocol: &LocalProtocol) -> Option<Duration> {
+    if !protocol.is_reverse_tunnel() || matches!(protocol, LocalProtocol::ReverseTcp { resume: Some(_), .. }) {
+        return None;
+    }
+    let secs: u64 = req.headers().get(PROBE_HEADER)?.to_str().ok()?.parse().ok()?;
+    Some(Duration::from_secs(secs).max(MIN_PROBE_INTERVAL))
+}
+
+/// Interval the server probes the tunnel of the accepted upgrade request at, to answer it in the [`PROBE_HEADER`]
+pub(super) fn probe_header<B>(req: &Request<B>) -> Option<HeaderValue> {
+    let jwt = extract_tunnel_info(req).ok()?;
+    let interval = probe_interval(req, &jwt.claims.p)?;
+    Some(HeaderValue::from(interval.as_secs()))
+}
+
+/// Tunnel to hand to the client right away, while `connect` waits for the connection of the reverse tunnel.
+/// The client is probed every `interval` until then, and the wait is dropped, which unbinds the listener if no other
+/// client waits on it, once the client missed [`MAX_MISSED_PROBES`] probes or closed the tunnel
+pub(super) fn probed_tunnel<F>(
+    executor: &impl TokioExecutorRef,
+    interval: Duration,
+    inject_cookie: bool,
+    connect: F,
+) -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>)
+where
+    F: Future<
+            Output = Result<
+                (RemoteAddr, Pin<Box<dyn AsyncRead + Send>>, Pin<Box<dyn AsyncWrite + Send>>),
+                HttpResponse,
+            >,
+        > + Send
+        + 'static,
+{
+    let (transport, tunnel) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+    let fut = async move {
+        let (mut tunnel_rx, mut tunnel_tx) = tokio::io::split(tunnel);
+        let mut connect = pin!(connect);
+        let mut ticker = interval_at(Instant::now() + interval, interval);
+        let mut buf = BytesMut::with_capacity(64);
+        let mut record = BytesMut::with_capacity(64);
+        let mut unanswered: u32 = 0;
+
+        let (remote, mut local_rx, mut local_tx) = loop {
+            select! {
+                connected = &mut connect => match connected {
+                    Ok(connected) => break connected,
+                    Err(_) => return,
+                },
+                _ = ticker.tick() => {
+                    if unanswered >= MAX_MISSED_PROBES {
+                        info!("Client of the reverse tunnel missed {unanswered} probes. Closing the tunnel");
+                        return;
+                    }
+                    unanswered += 1;
+                    ProbeRecord::Ping.encode(&mut record);
+                    if tunnel_tx.write_all(&record.split()).await.is_err() {
+                        return;
+                    }
+                },
+                read = tunnel_rx.read_buf(&mut buf) => {
+                    if !matches!(read, Ok(n) if n > 0) {
+                        info!("Client of the reverse tunnel is gone while waiting for a connection");
+                        return;
+                    }
+                    while let Some(answer) = ProbeRecord::decode(&mut buf).transpose() {
+                        match answer {
+                            Ok(ProbeRecord::Pong) => unanswered = unanswered.saturating_sub(1),
+                            answer => {
+                                warn!("Closing reverse tunnel, invalid probe answer from the client: {answer:?}");
+                                return;
+                            }
+                        }
+                    }
+                },
+            }
+        };
+
+        let cookie = match inject_cookie {
+            true => Bytes::from(tunnel_to_jwt_token(Uuid::from_u128(0), &remote, None, None)),
+            false => Bytes::new(),
+        };
+        ProbeRecord::Open(cookie).encode(&mut record);
+        if tunnel_tx.write_all(&record.split()).await.is_err() {
+            return;
+        }
+
+        // The client answers every ping sent before the open record, its data only comes after these answers
+        while unanswered > 0 {
+            match ProbeRecord::decode(&mut buf) {
+                Ok(Some(ProbeRecord::Pong)) => unanswered -= 1,
+                Ok(None) => match tunnel_rx.read_buf(&mut buf).await {
+                    Ok(n) if n > 0 => {}
+                    _ => return,
+                },
+                answer => {
+                    warn!("Closing reverse tunnel, invalid probe answer from the client: {answer:?}");
+                    return;
+                }
+            }
+        }
+        if !buf.is_empty() && local_tx.write_all(&buf).await.is_err() {
+            return;
+        }
+
+        let to_local = async {
+            let _ = tokio::io::copy(&mut tunnel_rx, &mut local_tx).await;
+            let _ = local_tx.shutdown().await;
+        };
+        let to_client = async {
+            let _ = tokio::io::copy(&mut local_rx, &mut tunnel_tx).await;
+            let _ = tunnel_tx.shutdown().await;
+        };
+        tokio::join!(to_local, to_client);
+        debug!("Probed reverse tunnel closed");
+    }
+    .instrument(Span::current());
+
+    executor.spawn(fut);
+    tokio::io::split(transport)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::DefaultTokioExecutor;
+    use crate::tunnel::AccessList;
+    use crate::tunnel::server::utils::bad_request;
+    use std::io;
+
+    fn request(interval: &str) -> Request<()> {
+        Request::builder().header(PROBE_HEADER, interval).body(()).unwrap()
+    }
+
+    #[test]
+    fn test_probe_interval() {
+        let reverse = LocalProtocol::ReverseTcp {
+            resume: None,
+            idle_timeout: None,
+            v6only: None,
+            access: AccessList::default(),
+            keepalive: None,
+        };
+        assert_eq!(probe_interval(&request("10"), &reverse), Some(Duration::from_secs(10)));
+        assert_eq!(probe_interval(&request("0"), &reverse), Some(MIN_PROBE_INTERVAL));
+        assert_eq!(probe_interval(&request("ten"), &reverse), None);
+        assert_eq!(probe_interval(&Request::new(()), &reverse), None);
+        assert_eq!(probe_interval(&request("10"), &LocalProtocol::Sctp), None);
+        let resumable = LocalProtocol::ReverseTcp {
+            resume: Some(crate::tunnel::TunnelResume {
+                buffer_size: 1024,
+                timeout: Duration::from_secs(30),
+                session: None,
+            }),
+            idle_timeout: None,
+            v6only: None,
+            access: AccessList::default(),
+            keepalive: None,
+        };
+        assert_eq!(probe_interval(&request("10"), &resumable), None);
+    }
+
+    #[tokio::test]
+    async fn test_client_missing_probes_is_dropped() {
+        let (dropped_tx, dropped_rx) = tokio::sync::oneshot::channel::<()>();
+        let connect = async move {
+            let _dropped = dropped_tx;
+            std::future::pending::<()>().await;
+            Err(bad_request())
+        };
+        let executor = DefaultTokioExecutor::default();
+        let (mut rx, _tx) = probed_tunnel(&executor, Duration::from_millis(10), false, connect);
+
+        // Never answered, the wait for a connection is dropped after the missed probes
+        let mut pings = vec![0; MAX_MISSED_PROBES as usize];
+        rx.read_exact(&mut pings).await.unwrap();
+        assert!(pings.iter().all(|&tag| tag == 0));
+        assert!(dropped_rx.await.is_err());
+        assert_eq!(rx.read_u8().await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_answered_probes_until_open() {
+        let (connected_tx, connected_rx) = tokio::sync::oneshot::channel::<()>();
+        let (local, mut peer) = tokio::io::duplex(1024);
+        let connect = async move {
+            connected_rx.await.unwrap();
+            let (local_rx, local_tx) = tokio::io::split(local);
+            let local_rx: Pin<Box<dyn AsyncRead + Send>> = Box::pin(local_rx);
+            let local_tx: Pin<Box<dyn AsyncWrite + Send>> = Box::pin(local_tx);
+            let remote = RemoteAddr {
+                protocol: LocalProtocol::Sctp,
+                host: url::Host::Domain("localhost".to_string()),
+                port: 80,
+            };
+            Ok((remote, local_rx, local_tx))
+        };
+        let executor = DefaultTokioExecutor::default();
+        let (mut rx, mut tx) = probed_tunnel(&executor, Duration::from_millis(10), false, connect);
+
+        // Answered for longer than the missed probes, the tunnel is kept until the connection arrives
+        for _ in 0..2 * MAX_MISSED_PROBES {
+            assert_eq!(rx.read_u8().await.unwrap(), 0);
+            tx.write_all(&[1]).await.unwrap();
+        }
+        connected_tx.send(()).unwrap();
+        let mut buf = BytesMut::new();
+        loop {
+            match ProbeRecord::decode(&mut buf).unwrap() {
+                Some(ProbeRecord::Ping) => tx.write_all(&[1]).await.unwrap(),
+                Some(record) => {
+                    assert_eq!(record, ProbeRecord::Open(Bytes::new()));
+                    break;
+                }
+                None => {
+                    rx.read_buf(&mut buf).await.unwrap();
+                }
+            }
+        }
+
+        tx.write_all(b"hello").await.unwrap();
+        let mut data = [0; 5];
+        peer.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+        peer.write_all(b"world").await.unwrap();
+        rx.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"world");
+    }
+}
@@ -0,0 +1,91 @@
+//! reject - answer the requests the server refuses like the website it is hidden behind would, i.e: with its 404 page,
+//! instead of the plain error of wstunnel that tells a prober what is listening
+use crate::tunnel::server::utils::HttpResponse;
+use http_body_util::Either;
+use hyper::header::{CONTENT_TYPE, HeaderName, HeaderValue, LOCATION};
+use hyper::{StatusCode, http};
+
+/// Marker of the responses of the server refusing a request, that [`RejectResponse`] replaces
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Rejected;
+
+#[derive(Clone, Debug)]
+pub struct RejectResponse {
+    pub status: StatusCode,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    pub body: String,
+    /// Sent as the location header, for a redirect status
+    pub redirect: Option<HeaderValue>,
+}
+
+/// The configured response if `response` refuses the request, else `response` as is
+pub(super) fn replace_rejected(reject: Option<&RejectResponse>, response: HttpResponse) -> HttpResponse {
+    match reject {
+        Some(reject) if response.extensions().get::<Rejected>().is_some() => reject.response(),
+        _ => response,
+    }
+}
+
+impl RejectResponse {
+    fn response(&self) -> HttpResponse {
+        let mut response = http::Response::builder().status(self.status);
+        if !self.body.is_empty() && !self.headers.iter().any(|(name, _)| name == CONTENT_TYPE) {
+            response = response.header(CONTENT_TYPE, "text/html; charset=utf-8");
+        }
+        if let Some(location) = &self.redirect {
+            response = response.header(LOCATION, location);
+        }
+        for (name, value) in &self.headers {
+            response = response.header(name, value);
+        }
+
+        response.body(Either::Left(self.body.clone())).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::server::utils::bad_request;
+
+    #[test]
+    fn test_replace_rejected() {
+        let reject = RejectResponse {
+            status: StatusCode::NOT_FOUND,
+            headers: vec![(HeaderName::from_static("server"), HeaderValue::from_static("nginx"))],
+            body: "<h1>Not Found</h1>".to_string(),
+            redirect: None,
+        };
+
+        let response = replace_rejected(Some(&reject), bad_request());
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["server"], "nginx");
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert!(matches!(response.body(), Either::Left(body) if body == "<h1>Not Found</h1>"));
+
+        let response = replace_rejected(None, bad_request());
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let ok = http::Response::builder()
+            .status(StatusCode::OK)
+            .body(Either::Left(String::new()))
+            .unwrap();
+        let response = replace_rejected(Some(&reject), ok);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_redirect() {
+        let reject = RejectResponse {
+            status: StatusCode::FOUND,
+            headers: vec![],
+            body: String::new(),
+            redirect: Some(HeaderValue::from_static("https://example.com/")),
+        };
+
+        let response = replace_rejected(Some(&reject), bad_request());
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[LOCATION], "https://example.com/");
+        assert!(!response.headers().contains_key(CONTENT_TYPE));
+    }
+}
@@ -0,0 +1,136 @@
+use crate::executor::TokioExecutorRef;
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::resume::{Outcome, ResumableStream, TRANSPORT_PIPE_SIZE};
+use ahash::AHashMap;
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tracing::{Instrument, Span, info, warn};
+use uuid::Uuid;
+
+/// Upper bound of the replay buffer a client can request, as it is allocated by the server for each direction
+pub const MAX_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+struct ResumableTunnel {
+    remote: RemoteAddr,
+    session: Uuid,
+    client_addr: SocketAddr,
+    next_transport: mpsc::Sender<DuplexStream>,
+}
+
+/// Resumable tcp tunnels of the server, waiting for their client to come back when its connection drops
+pub struct ResumableTunnels {
+    tunnels: Arc<Mutex<AHashMap<String, ResumableTunnel>>>,
+}
+
+impl ResumableTunnels {
+    pub fn new() -> Self {
+        Self {
+            tunnels: Arc::new(Mutex::new(AHashMap::new())),
+        }
+    }
+
+    /// Keep the tunnel to the destination alive, under the given id, and return the connection to use with the client
+    /// along with the session token the client must present to resume it
+    pub fn register(
+        &self,
+        executor: &impl TokioExecutorRef,
+        id: String,
+        remote: RemoteAddr,
+        client_addr: SocketAddr,
+        mut stream: ResumableStream,
+        timeout: Duration,
+    ) -> anyhow::Result<(DuplexStream, Uuid)> {
+        let session = Uuid::new_v4();
+        let (next_tx, mut next_rx) = mpsc::channel(1);
+        {
+            let mut tunnels = self.tunnels.lock();
+            if tunnels.contains_key(&id) {
+                return Err(anyhow!("resumable tunnel {id} already exists"));
+            }
+            tunnels.insert(
+                id.clone(),
+                ResumableTunnel {
+                    remote,
+                    session,
+                    client_addr,
+                    next_transport: next_tx,
+                },
+            );
+        }
+
+        let (transport, client_side) = tokio::io::duplex(TRANSPORT_PIPE_SIZE);
+        let tunnels = self.tunnels.clone();
+        let fut = async move {
+            scopeguard::defer!({
+                tunnels.lock().remove(&id);
+            });
+
+            let mut transport = transport;
+            loop {
+                match stream.run(transport, Some(&mut next_rx)).await {
+                    Ok(Outcome::Finished) => return,
+                    Ok(Outcome::Replaced(next)) => transport = next,
+                    Ok(Outcome::Disconnected) => {
+                        info!("Client disconnected, waiting up to {timeout:?} for it to resume the tunnel");
+                        match tokio::time::timeout(timeout, next_rx.recv()).await {
+                            Ok(Some(next)) => transport = next,
+                            _ => {
+                                warn!("Closing resumable tunnel, client did not come back after {timeout:?}");
+                                return;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        warn!("Closing resumable tunnel: {err:?}");
+                        return;
+                    }
+                }
+                info!("Resuming tunnel");
+            }
+        };
+        executor.spawn(fut.instrument(Span::current()));
+
+        Ok((client_side, session))
+    }
+
+    /// Return the connection to use with the client, for a tunnel that the client is resuming.
+    /// The client may come back from another address, e.g. after switching network, as long as it presents the session token
+    pub fn reattach(
+        &self,
+        id: &str,
+        remote: &RemoteAddr,
+        session: Uuid,
+        client_addr: SocketAddr,
+    ) -> anyhow::Result<DuplexStream> {
+        let next_tx = match self.tunnels.lock().get_mut(id) {
+            Some(tunnel)
+                if tunnel.session == session
+                    && tunnel.remote.host == remote.host
+                    && tunnel.remote.port == remote.port =>
+            {
+                if tunnel.client_addr.ip() != client_addr.ip() {
+                    info!(
+                        "Client of resumable tunnel moved from {} to {}",
+                        tunnel.client_addr, client_addr
+                    );
+                }
+                tunnel.client_addr = client_addr;
+                tunnel.next_transport.clone()
+            }
+            Some(_) => return Err(anyhow!("resumable tunnel {id} does not match the session or destination")),
+            None => return Err(anyhow!("unknown resumable tunnel {id}")),
+        };
+
+        let (transport, client_side) = tokio::io::duplex(TRANSPORT_PIPE_SIZE);
+        next_tx
+            .try_send(transport)
+            .map_err(|_| anyhow!("resumable tunnel {id} is already being resumed"))?;
+
+        Ok(client_side)
+    }
+}
@@ -1,6 +1,8 @@
-use crate::executor::TokioExecutorRef;
+use crate::executor::{AbortHandle, TokioExecutorRef};
 use crate::tunnel::RemoteAddr;
 use crate::tunnel::listeners::TunnelListener;
+use crate::tunnel::server::cluster::ClusterRegistry;
+use crate::tunnel::server::standby;
 use ahash::AHashMap;
 use anyhow::anyhow;
 use futures_util::{StreamExt, pin_mut};
@@ -11,7 +13,7 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
-use tokio::task::AbortHandle;
+use tokio::sync::Notify;
 use tokio::{select, time};
 use tracing::{Instrument, Span, info};
 
@@ -19,7 +21,10 @@ struct ReverseTunnelItem<T: TunnelListener> {
     #[allow(clippy::type_complexity)]
     receiver: async_channel::Receiver<((<T as TunnelListener>::Reader, <T as TunnelListener>::Writer), RemoteAddr)>,
     nb_seen_clients: Arc<AtomicUsize>,
+    gone_clients: Arc<Notify>,
     server_task: AbortHandle,
+    /// Identity the port is reserved for, if its first client owns it
+    owner: Option<String>,
 }
 
 impl<T: TunnelListener> ReverseTunnelItem<T> {
@@ -54,23 +59,38 @@ impl<T: TunnelListener> ReverseTunnelServer<T> {
         executor: &impl TokioExecutorRef,
         bind_addr: SocketAddr,
         idle_timeout: Duration,
+        cluster: Option<&Arc<ClusterRegistry>>,
+        owner: Option<&str>,
         gen_listening_server: impl Future<Output = anyhow::Result<T>>,
     ) -> anyhow::Result<((<T as TunnelListener>::Reader, <T as TunnelListener>::Writer), RemoteAddr)>
     where
         T: TunnelListener + Send + 'static,
     {
-        let listening_server = self
-            .servers
-            .lock()
-            .get(&bind_addr)
-            .map(|server| server.get_cnx_awaiter());
+        let listening_server = match self.servers.lock().get(&bind_addr) {
+            // An owned port is not shared, neither is a shared one owned later on
+            Some(server) if server.owner.as_deref() != owner => {
+                return Err(match &server.owner {
+                    Some(_) => anyhow!("reverse tunnel port {bind_addr} is reserved for another client"),
+                    None => anyhow!("reverse tunnel port {bind_addr} is shared, it cannot be owned"),
+                });
+            }
+            Some(server) => Some((server.get_cnx_awaiter(), server.gone_clients.clone())),
+            None => None,
+        };
         let cnx = if let Some(listening_server) = listening_server {
             listening_server
         } else {
+            // Taken over from the peers of the cluster before listening on it
+            let registration = match cluster {
+                Some(cluster) => Some(cluster.register_port(bind_addr).await),
+                None => None,
+            };
             let listening_server = gen_listening_server.await?;
             let (tx, rx) = async_channel::bounded(10);
             let nb_seen_clients = Arc::new(AtomicUsize::new(0));
             let seen_clients = nb_seen_clients.clone();
+            let gone_clients = Arc::new(Notify::new());
+            let client_gone = gone_clients.clone();
             let server = self.servers.clone();
             let local_srv2 = bind_addr;
 
@@ -78,6 +98,7 @@ impl<T: TunnelListener> ReverseTunnelServer<T> {
                 scopeguard::defer!({
                     server.lock().remove(&local_srv2);
                 });
+                let _registration = registration;
 
                 let mut timer = time::interval(idle_timeout);
                 pin_mut!(listening_server);
@@ -99,6 +120,17 @@ impl<T: TunnelListener> ReverseTunnelServer<T> {
                                 }
                             }
                         },
+                        _ = standby::wait_standby() => {
+                            info!("Server is on standby. Closing reverse tunnel server");
+                            break;
+                        },
+                        _ = client_gone.notified() => {
+                            // A probed client is gone, no need to wait for the idle timeout if it was the last one
+                            if tx.receiver_count() <= 1 {
+                                info!("No client waiting anymore on reverse tunnel server. Closing reverse tunnel server");
+                                break;
+                            }
+                        },
                         _ = timer.tick() => {
 
                             // if no client connected to the reverse tunnel server, close it
@@ -116,17 +148,32 @@ impl<T: TunnelListener> ReverseTunnelServer<T> {
             let item = ReverseTunnelItem {
                 receiver: rx,
                 nb_seen_clients,
+                gone_clients: gone_clients.clone(),
                 server_task: executor.spawn(fut),
+                owner: owner.map(str::to_string),
             };
             let cnx_awaiter = item.get_cnx_awaiter();
             self.servers.lock().insert(bind_addr, item);
-            cnx_awaiter
+            (cnx_awaiter, gone_clients)
         };
 
-        let cnx = cnx
+        // Dropped before getting a connection, the client gave up waiting. The receiver goes first, so the server sees
+        // it is gone when notified
+        let (cnx, gone_clients) = cnx;
+        let mut waiting = scopeguard::guard(Some(cnx), move |cnx| {
+            if cnx.is_some() {
+                drop(cnx);
+                gone_clients.notify_one();
+            }
+        });
+        let cnx = waiting
+            .as_ref()
+            .expect("bug: reverse tunnel receiver taken")
             .recv()
-            .await
-            .map_err(|_| anyhow!("listening reverse server stopped"))?;
+            .await;
+        // Received or stopped, the listening server does not need to know
+        drop(waiting.take());
+        let cnx = cnx.map_err(|_| anyhow!("listening reverse server stopped"))?;
         Ok(cnx)
     }
 }
@@ -1,33 +1,73 @@
+use crate::dscp;
 use crate::executor::DefaultTokioExecutor;
+use crate::failure::FailureKind;
+use crate::health::HEALTH;
+use crate::metrics;
+use crate::oidc::OidcValidator;
 use crate::protocols;
 use crate::protocols::dns::DnsResolver;
+use crate::protocols::socks5::Socks5Resolver;
 use crate::protocols::tls;
 use crate::restrictions::config_reloader::RestrictionsRulesReloader;
 use crate::restrictions::types::{RestrictionConfig, RestrictionsRules};
 use crate::somark::SoMark;
+use crate::source_bind::SourceBind;
+use crate::stats;
+use crate::stats::{STATS, Side};
 use crate::tunnel::connectors::{TcpTunnelConnector, TunnelConnector, UdpTunnelConnector};
 use crate::tunnel::listeners::{HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, UdpTunnelListener};
+use crate::tunnel::noise::NoiseServerConfig;
+use crate::tunnel::protocol::client_version::ClientVersion;
+use crate::tunnel::protocol::close_reason::WsCloseCodes;
+use crate::tunnel::protocol::{Capabilities, negotiate_version};
+use crate::tunnel::resume::ResumableStream;
+use crate::tunnel::server::auth_hook::{AuthHook, AuthHookRequest};
+use crate::tunnel::server::cluster::{ClusterConfig, ClusterRegistry};
+use crate::tunnel::server::demux;
+use crate::tunnel::server::demux::{ProtocolHandler, SniPassthrough, SniffedProtocol};
+#[cfg(feature = "dns-transport")]
+use crate::tunnel::server::handler_dns::{DnsTransportConfig, run_dns_server};
+use crate::tunnel::server::handler_http1::{HttpSessions, http1_server_session};
 use crate::tunnel::server::handler_http2::http_server_upgrade;
+#[cfg(feature = "icmp-transport")]
+use crate::tunnel::server::handler_icmp::{IcmpTransportConfig, run_icmp_server};
+use crate::tunnel::server::handler_mux::mux_server_session;
+#[cfg(feature = "ssh-transport")]
+use crate::tunnel::server::handler_ssh::{SshTransportConfig, ssh_server_session};
 use crate::tunnel::server::handler_websocket::ws_server_upgrade;
+use crate::tunnel::server::http_ingress::{HttpIngressDomain, HttpIngressListener, find_vhost, forward_request};
+use crate::tunnel::server::idle;
+use crate::tunnel::server::limits::{ClientLimits, WithPermit};
+use crate::tunnel::server::reject::{RejectResponse, replace_rejected};
+use crate::tunnel::server::resume;
+use crate::tunnel::server::resume::ResumableTunnels;
 use crate::tunnel::server::reverse_tunnel::ReverseTunnelServer;
+use crate::tunnel::server::service::serve_request;
 use crate::tunnel::server::utils::{
-    HttpResponse, bad_request, extract_authorization, extract_path_prefix, extract_tunnel_info,
-    extract_x_forwarded_for, find_mapped_port, validate_tunnel,
+    HttpResponse, bad_request, check_path_prefix, extract_authorization, extract_tunnel_info, extract_tunnel_token,
+    extract_x_forwarded_for, find_bind_host, find_mapped_port, find_port_owner, integrity_interval,
+    resolve_destination_alias, too_many_requests, validate_tunnel,
 };
+use crate::tunnel::server::{cluster, failover, min_client_version, mirror, probe, standby};
 use crate::tunnel::tls_reloader::TlsReloader;
-use crate::tunnel::{LocalProtocol, RemoteAddr, try_to_sock_addr};
+use crate::tunnel::transport::http1::is_session_request;
+use crate::tunnel::transport::http2::{h2_max_frame_size, h2_window_size};
+use crate::tunnel::transport::io::{LocalReset, Resettable};
+use crate::tunnel::transport::obfuscation::TrafficObfuscation;
+use crate::tunnel::transport::{EARLY_DATA_HEADER, PSK_HEADER, PreSharedKey, ReplayCache, StickySession, early_data};
+use crate::tunnel::{LocalProtocol, RemoteAddr, integrity, is_valid_label, noise, pcap, try_to_sock_addr};
 use ahash::AHasher;
 use anyhow::{Context, anyhow};
 use arc_swap::ArcSwap;
 use futures_util::FutureExt;
-use http_body_util::Either;
 use hyper::body::Incoming;
 use hyper::server::conn::{http1, http2};
 use hyper::service::service_fn;
-use hyper::{Request, StatusCode, Version, http};
+use hyper::{Request, Uri};
 use hyper_util::rt::{TokioExecutor, TokioTimer};
 use parking_lot::Mutex;
 use socket2::SockRef;
+use std::borrow::Cow;
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::hash::{Hash, Hasher};
@@ -38,9 +78,10 @@ use std::sync::{Arc, LazyLock};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
+use tokio::runtime::Handle;
 use tokio_rustls::TlsAcceptor;
 use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use tracing::{Instrument, Level, Span, error, info, span, warn};
+use tracing::{Instrument, Level, Span, debug, error, info, span, warn};
 use url::{Host, Url};
 
 #[derive(Debug)]
@@ -51,6 +92,8 @@ pub struct TlsServerConfig {
     pub tls_certificate_path: Option<PathBuf>,
     pub tls_key_path: Option<PathBuf>,
     pub tls_client_ca_certs_path: Option<PathBuf>,
+    /// Hand the TLS connections over to the kernel once their handshake is done, on linux
+    pub ktls: bool,
 }
 
 pub struct WsServerConfig {
@@ -59,38 +102,122 @@ pub struct WsServerConfig {
     pub websocket_ping_frequency: Option<Duration>,
     pub timeout_connect: Duration,
     pub websocket_mask_frame: bool,
+    pub websocket_max_frame_size: usize,
+    /// How long to wait for the client to answer the close frame of a websocket tunnel
+    pub websocket_close_timeout: Duration,
+    pub websocket_close_codes: WsCloseCodes,
+    pub tcp_fastopen: bool,
+    pub tcp_defer_accept: Option<Duration>,
+    /// Number of listeners bound with SO_REUSEPORT on the listening port, each with its own accept task.
+    /// 0 for one per worker thread of the runtime
+    pub accept_shards: usize,
+    /// DSCP codepoint of the packets of the connections to the clients
+    pub dscp: Option<u8>,
+    /// Interface or source ip of the connections to the destinations of the tunnels
+    pub source_bind: SourceBind,
+    pub auth_hook: Option<AuthHook>,
+    pub auth_hook_timeout: Duration,
+    pub oidc: Option<OidcValidator>,
+    /// Key the upgrade requests must be signed with
+    pub psk: Option<PreSharedKey>,
+    /// Encrypt the data of the tunnels end to end with the clients
+    pub noise: Option<NoiseServerConfig>,
+    #[cfg(feature = "dns-transport")]
+    pub dns_transport: Option<DnsTransportConfig>,
+    #[cfg(feature = "icmp-transport")]
+    pub icmp_transport: Option<IcmpTransportConfig>,
+    /// Also serve the ssh transport on the listening port
+    #[cfg(feature = "ssh-transport")]
+    pub ssh_transport: Option<SshTransportConfig>,
     pub tls: Option<TlsServerConfig>,
+    /// What to do with the connections of each protocol recognized on the listening port, see [`demux`]
+    pub protocol_handlers: Vec<(SniffedProtocol, ProtocolHandler)>,
+    pub tls_sni_passthrough: Option<SniPassthrough>,
     pub dns_resolver: DnsResolver,
     pub restriction_config: Option<PathBuf>,
     pub http_proxy: Option<Url>,
     pub remote_server_idle_timeout: Duration,
+    pub tunnel_resume_max_timeout: Duration,
+    pub tunnel_idle_timeout: Option<Duration>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub max_clients: Option<usize>,
+    pub max_tunnels_per_client: Option<usize>,
+    /// Reject the tunnels of the clients older than this version
+    pub require_min_client_version: Option<ClientVersion>,
+    pub max_inflight_per_tunnel: usize,
+    /// Directory where the traffic of each tunnel is recorded as a pcap file
+    pub pcap_dir: Option<PathBuf>,
+    /// Domain under which the reverse http ingress tunnels get a subdomain
+    pub http_ingress: Option<HttpIngressDomain>,
+    /// Padding and jitter of the websocket frames sent to the clients
+    pub traffic_obfuscation: Option<TrafficObfuscation>,
+    /// Response to the requests the server refuses, instead of its plain errors
+    pub reject_response: Option<RejectResponse>,
+    /// The server is on standby while this file exists
+    pub standby_file: Option<PathBuf>,
+    /// Peers the reverse tunnels are shared with
+    pub cluster: Option<ClusterConfig>,
+    /// Sign a token sticking the clients to this server behind a load balancer
+    pub sticky_session: Option<StickySession>,
 }
 
 #[derive(Clone)]
 pub struct WsServer<E: crate::TokioExecutorRef = DefaultTokioExecutor> {
     pub config: Arc<WsServerConfig>,
     pub executor: E,
+    client_limits: Arc<ClientLimits>,
+    psk_replays: Arc<ReplayCache>,
+    pub(super) cluster: Arc<ClusterRegistry>,
+    /// Sessions of the tunnels served over split http requests
+    http_sessions: Arc<HttpSessions>,
 }
 
 impl<E: crate::TokioExecutorRef> WsServer<E> {
     pub fn new(config: WsServerConfig, executor: E) -> Self {
+        let client_limits = ClientLimits::new(config.max_clients, config.max_tunnels_per_client);
         Self {
             config: Arc::new(config),
             executor,
+            client_limits: Arc::new(client_limits),
+            psk_replays: Arc::new(ReplayCache::new()),
+            cluster: Arc::new(ClusterRegistry::default()),
+            http_sessions: Arc::new(HttpSessions::default()),
         }
     }
 
-    pub(super) async fn handle_tunnel_request(
+    pub(super) fn http_sessions(&self) -> &HttpSessions {
+        &self.http_sessions
+    }
+
+    /// Check the pre-shared key proof of the request, signed by the client over its path and `message`
+    pub(super) fn check_psk_proof<B>(&self, req: &Request<B>, message: &str) -> bool {
+        let Some(psk) = &self.config.psk else {
+            return true;
+        };
+        let proof = req
+            .headers()
+            .get(PSK_HEADER)
+            .and_then(|header| header.to_str().ok())
+            .unwrap_or_default();
+        if let Err(err) = psk.verify_client_proof(proof, req.uri().path(), message, &self.psk_replays) {
+            warn!("Rejecting connection with invalid pre-shared key proof: {err}");
+            return false;
+        }
+        true
+    }
+
+    pub(super) async fn handle_tunnel_request<B>(
         &self,
         restrictions: Arc<RestrictionsRules>,
         restrict_path_prefix: Option<String>,
         mut client_addr: SocketAddr,
-        req: &Request<Incoming>,
+        req: &Request<B>,
     ) -> Result<
         (
             RemoteAddr,
             Pin<Box<dyn AsyncRead + Send>>,
             Pin<Box<dyn AsyncWrite + Send>>,
+            LocalReset,
             bool,
         ),
         HttpResponse,
@@ -101,17 +228,18 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
             client_addr.set_ip(x_forward_for);
         };
 
-        let path_prefix = extract_path_prefix(req.uri().path()).map_err(|err| {
-            warn!("Rejecting connection with {err}: {}", req.uri());
-            bad_request()
+        if standby::is_standby() {
+            warn!("Rejecting connection of {}, the server is on standby", client_addr.ip());
+            return Err(standby::standby_response());
+        }
+
+        let permit = self.client_limits.acquire(client_addr.ip()).map_err(|err| {
+            warn!("Rejecting connection of {}: {err}", client_addr.ip());
+            too_many_requests()
         })?;
 
-        if let Some(restrict_path) = restrict_path_prefix
-            && path_prefix != restrict_path
-        {
-            warn!(
-                "Client requested upgrade path '{path_prefix}' does not match upgrade path restriction '{restrict_path}' (mTLS, etc.)"
-            );
+        let path_prefix = check_path_prefix(restrict_path_prefix.as_deref(), req).ok_or_else(bad_request)?;
+        if !self.check_psk_proof(req, extract_tunnel_token(req)) {
             return Err(bad_request());
         }
 
@@ -120,13 +248,63 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
             bad_request()
         })?;
 
+        if let Some(min_version) = self.config.require_min_client_version
+            && let Some(response) = min_client_version::check(min_version, req)
+        {
+            return Err(response);
+        }
+
         Span::current().record("id", &jwt.claims.id);
         Span::current().record("remote", format!("{}:{}", jwt.claims.r, jwt.claims.rp));
+        if let Err(err) = negotiate_version(jwt.claims.v.as_deref()) {
+            warn!("Rejecting connection: {err}");
+            return Err(bad_request());
+        }
+        match &jwt.claims.c {
+            Some(capabilities) => debug!("Client capabilities: {}", Capabilities::parse(capabilities)),
+            None => debug!("Client does not tell its capabilities, it is older than the server"),
+        }
+        let label = jwt.claims.l.clone();
+        if let Some(label) = &label {
+            if !is_valid_label(label) {
+                warn!("Rejecting connection with invalid label {label:?}");
+                return Err(bad_request());
+            }
+            Span::current().record("label", label);
+        }
+        let device = jwt.claims.d.clone();
+        if let Some(device) = &device {
+            if !is_valid_label(device) {
+                warn!("Rejecting connection with invalid device id {device:?}");
+                return Err(bad_request());
+            }
+            Span::current().record("device", device);
+        }
+        // The device id is chosen by the client, it only tells apart the devices sharing an authenticated path prefix
+        let identity = match &device {
+            Some(device) => Cow::Owned(format!("{path_prefix}/{device}")),
+            None => Cow::Borrowed(path_prefix),
+        };
+        let tunnel_id = jwt.claims.id.clone();
         let remote = RemoteAddr::try_from(jwt.claims).map_err(|err| {
             warn!("Rejecting connection with bad tunnel info: {err} {}", req.uri());
             bad_request()
         })?;
 
+        // The tunnels multiplexed on the connection are checked one by one, when the client opens them
+        if remote.protocol == LocalProtocol::Mux {
+            info!("Serving tunnels multiplexed by the client");
+            let (local_rx, local_tx) =
+                mux_server_session(self.clone(), restrictions, restrict_path_prefix, client_addr, req);
+            return Ok((
+                remote,
+                Box::pin(WithPermit::new(local_rx, permit)),
+                Box::pin(local_tx),
+                LocalReset::default(),
+                false,
+            ));
+        }
+
         let authorization = extract_authorization(req);
         let restriction = validate_tunnel(&remote, path_prefix, authorization, &restrictions).ok_or_else(|| {
             warn!("Rejecting connection with not allowed destination: {remote:?}");
@@ -134,27 +312,337 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
         })?;
         info!("Tunnel accepted due to matched restriction: {}", restriction.name);
 
+        // The mirror is a destination of its own, that the restrictions must allow too
+        if let LocalProtocol::Tcp {
+            mirror: Some((host, port)),
+            ..
+        } = &remote.protocol
+        {
+            let mirror = RemoteAddr {
+                protocol: remote.protocol.clone(),
+                host: host.clone(),
+                port: *port,
+            };
+            if validate_tunnel(&mirror, path_prefix, authorization, &restrictions).is_none() {
+                warn!("Rejecting connection with not allowed mirror destination: {host}:{port}");
+                return Err(bad_request());
+            }
+        }
+
+        // So are the other destinations of a load balanced tunnel
+        if let LocalProtocol::Tcp {
+            balancing: Some(balancing),
+            ..
+        } = &remote.protocol
+        {
+            for (host, port) in &balancing.fallbacks {
+                let fallback = RemoteAddr {
+                    protocol: remote.protocol.clone(),
+                    host: host.clone(),
+                    port: *port,
+                };
+                if validate_tunnel(&fallback, path_prefix, authorization, &restrictions).is_none() {
+                    warn!("Rejecting connection with not allowed fallback destination: {host}:{port}");
+                    return Err(bad_request());
+                }
+            }
+        }
+
+        if let LocalProtocol::ReverseHttpIngress { vhost, session, .. } = &remote.protocol
+            && let Some(http_ingress) = &self.config.http_ingress
+            && let Err(err) = http_ingress.check(vhost, session.as_ref(), &identity)
+        {
+            warn!("Rejecting http ingress tunnel: {err}");
+            return Err(bad_request());
+        }
+
+        if let Some(oidc) = &self.config.oidc {
+            let Some(token) = authorization.and_then(|auth| auth.strip_prefix("Bearer ")) else {
+                warn!("Rejecting connection without oidc bearer token: {remote:?}");
+                return Err(bad_request());
+            };
+            match oidc.validate(token.trim()).await {
+                Ok(subject) => info!("Tunnel accepted for oidc subject {subject}"),
+                Err(err) => {
+                    warn!("Rejecting connection with invalid oidc token: {err:?}");
+                    return Err(bad_request());
+                }
+            }
+        }
+
+        if let Some(auth_hook) = &self.config.auth_hook {
+            let auth_req = AuthHookRequest {
+                client_addr,
+                path_prefix,
+                client_certificate_cn: restrict_path_prefix.as_deref(),
+                headers: req
+                    .headers()
+                    .iter()
+                    .filter_map(|(k, v)| Some((k.as_str(), v.to_str().ok()?)))
+                    .collect(),
+                remote_protocol: &remote.protocol,
+                remote_host: remote.host.to_string(),
+                remote_port: remote.port,
+            };
+            match auth_hook
+                .authorize(
+                    &auth_req,
+                    self.config.auth_hook_timeout,
+                    self.config.socket_so_mark,
+                    &self.config.dns_resolver,
+                )
+                .await
+            {
+                Ok(true) => info!("Tunnel accepted by auth hook"),
+                Ok(false) => {
+                    warn!("Rejecting connection denied by auth hook: {remote:?}");
+                    return Err(bad_request());
+                }
+                Err(err) => {
+                    warn!("Rejecting connection due to auth hook failure: {err:?}");
+                    return Err(bad_request());
+                }
+            }
+        }
+
+        if let Some(label) = &label {
+            metrics::METRICS.inc_label(label);
+        }
+        if let Some(device) = &device {
+            metrics::METRICS.inc_device(device);
+        }
+
+        let mut remote = resolve_destination_alias(remote, restriction);
+        if let Some(resume) = remote.protocol.resume_mut().copied() {
+            static TUNNELS: LazyLock<ResumableTunnels> = LazyLock::new(ResumableTunnels::new);
+
+            // The session token is sent back to the client, so it can resume the tunnel after losing its connection
+            let transport = match resume.session {
+                Some(session) => TUNNELS
+                    .reattach(&tunnel_id, &remote, session, client_addr)
+                    .inspect(|_| STATS.reconnected(Side::Server, &tunnel_id)),
+                None => {
+                    let timeout = resume.timeout.min(self.config.tunnel_resume_max_timeout);
+                    match self
+                        .exec_tunnel(restriction, &identity, remote.clone(), client_addr)
+                        .await
+                    {
+                        Ok((_, local_rx, local_tx, _)) => {
+                            let (local_rx, local_tx) =
+                                self.record_pcap(&tunnel_id, &remote, client_addr, local_rx, local_tx);
+                            let registration = STATS.register(
+                                Side::Server,
+                                &tunnel_id,
+                                &remote,
+                                Some(client_addr),
+                                label.as_deref(),
+                                device.as_deref(),
+                            );
+                            let (local_rx, local_tx) = stats::track(registration, local_rx, local_tx);
+                            let buffer_size = resume.buffer_size.min(resume::MAX_BUFFER_SIZE);
+                            noise::server_channel(self.config.noise.as_ref(), &self.executor, local_rx, local_tx)
+                                .and_then(|(local_rx, local_tx)| {
+                                    let stream = ResumableStream::new(local_rx, local_tx, buffer_size);
+                                    TUNNELS.register(
+                                        &self.executor,
+                                        tunnel_id,
+                                        remote.clone(),
+                                        client_addr,
+                                        stream,
+                                        timeout,
+                                    )
+                                })
+                                .map(|(transport, session)| {
+                                    if let Some(resume) = remote.protocol.resume_mut() {
+                                        resume.session = Some(session);
+                                    }
+                                    transport
+                                })
+                        }
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            .map_err(|err| {
+                warn!("Rejecting resumable tunnel: {err} {}", req.uri());
+                bad_request()
+            })?;
+
+            info!("connected to resumable tunnel {}:{}", remote.host, remote.port);
+            let (local_rx, local_tx) = tokio::io::split(transport);
+            return Ok((
+                remote,
+                Box::pin(WithPermit::new(local_rx, permit)),
+                Box::pin(local_tx),
+                LocalReset::default(),
+                true,
+            ));
+        }
+
+        let early_data = req
+            .headers()
+            .get(EARLY_DATA_HEADER)
+            .map(early_data::decode)
+            .transpose()
+            .map_err(|err| {
+                warn!("Rejecting connection with bad early data: {err:?}");
+                bad_request()
+            })?;
+        let inject_cookie = remote.protocol.is_dynamic_reverse_tunnel();
+        let integrity = integrity_interval(req, &remote.protocol);
+
+        // A reverse tunnel probed while it waits is accepted right away, its connection is told later on the tunnel
+        if let Some(interval) = probe::probe_interval(req, &remote.protocol) {
+            let server = self.clone();
+            let restriction = restriction.clone();
+            let identity = identity.to_string();
+            let uri = req.uri().clone();
+            let connect = {
+                let remote = remote.clone();
+                async move {
+                    server
+                        .connect_tunnel(
+                            &restriction,
+                            &identity,
+                            remote,
+                            client_addr,
+                            &tunnel_id,
+                            label.as_deref(),
+                            device.as_deref(),
+                            early_data,
+                            &uri,
+                        )
+                        .await
+                        .map(|(remote, local_rx, local_tx, _)| {
+                            let (local_rx, local_tx) =
+                                integrity::channel(integrity, &server.executor, local_rx, local_tx);
+                            (remote, local_rx, local_tx)
+                        })
+                }
+            };
+            let (local_rx, local_tx) = probe::probed_tunnel(&self.executor, interval, inject_cookie, connect);
+            return Ok((
+                remote,
+                Box::pin(WithPermit::new(local_rx, permit)),
+                Box::pin(local_tx),
+                LocalReset::default(),
+                false,
+            ));
+        }
+
+        let (remote_addr, local_rx, local_tx, local_reset) = self
+            .connect_tunnel(
+                restriction,
+                &identity,
+                remote,
+                client_addr,
+                &tunnel_id,
+                label.as_deref(),
+                device.as_deref(),
+                early_data,
+                req.uri(),
+            )
+            .await?;
+        let (local_rx, local_tx) = integrity::channel(integrity, &self.executor, local_rx, local_tx);
+        Ok((
+            remote_addr,
+            Box::pin(WithPermit::new(local_rx, permit)),
+            local_tx,
+            local_reset,
+            inject_cookie,
+        ))
+    }
+
+    /// Connect the tunnel to its destination, or wait for the connection of a reverse tunnel, and wrap it with the
+    /// recording, the statistics and the encryption of the server
+    #[allow(clippy::too_many_arguments)]
+    async fn connect_tunnel(
+        &self,
+        restriction: &RestrictionConfig,
+        identity: &str,
+        remote: RemoteAddr,
+        client_addr: SocketAddr,
+        tunnel_id: &str,
+        label: Option<&str>,
+        device: Option<&str>,
+        early_data: Option<Vec<u8>>,
+        uri: &Uri,
+    ) -> Result<
+        (
+            RemoteAddr,
+            Pin<Box<dyn AsyncRead + Send>>,
+            Pin<Box<dyn AsyncWrite + Send>>,
+            LocalReset,
+        ),
+        HttpResponse,
+    > {
         let req_protocol = remote.protocol.clone();
-        let inject_cookie = req_protocol.is_dynamic_reverse_tunnel();
         let tunnel = self
-            .exec_tunnel(restriction, remote, client_addr)
+            .exec_tunnel(restriction, identity, remote, client_addr)
             .await
             .map_err(|err| {
-                warn!("Rejecting connection with bad upgrade request: {err} {}", req.uri());
+                warn!("Rejecting connection with bad upgrade request: {err} {uri}");
                 bad_request()
             })?;
 
-        let (remote_addr, local_rx, local_tx) = tunnel;
+        let (remote_addr, local_rx, local_tx, local_reset) = tunnel;
         info!("connected to {:?} {}:{}", req_protocol, remote_addr.host, remote_addr.port);
-        Ok((remote_addr, local_rx, local_tx, inject_cookie))
+        let (local_rx, local_tx) = self.record_pcap(tunnel_id, &remote_addr, client_addr, local_rx, local_tx);
+        let registration = STATS.register(Side::Server, tunnel_id, &remote_addr, Some(client_addr), label, device);
+        let (local_rx, local_tx) = stats::track(registration, local_rx, local_tx);
+        let (local_rx, mut local_tx) =
+            noise::server_channel(self.config.noise.as_ref(), &self.executor, local_rx, local_tx).map_err(|err| {
+                warn!("Rejecting connection, cannot setup noise encryption: {err:?}");
+                bad_request()
+            })?;
+        // The client sent the first bytes of the tunnel along with its request, they go first to the destination
+        if let Some(early_data) = early_data {
+            local_tx.write_all(&early_data).await.map_err(|err| {
+                warn!("Rejecting connection, cannot write early data to {remote_addr:?}: {err:?}");
+                bad_request()
+            })?;
+        }
+        Ok((remote_addr, local_rx, local_tx, local_reset))
+    }
+
+    fn record_pcap<R, W>(
+        &self,
+        tunnel_id: &str,
+        remote: &RemoteAddr,
+        client_addr: SocketAddr,
+        local_rx: R,
+        local_tx: W,
+    ) -> (pcap::PcapReader<R>, pcap::PcapWriter<W>) {
+        let recorder = pcap::recorder(
+            self.config.pcap_dir.as_deref(),
+            tunnel_id,
+            &remote.protocol,
+            client_addr,
+            (&remote.host, remote.port),
+        );
+        pcap::record(recorder, local_rx, local_tx, pcap::Direction::ToOrigin)
+    }
+
+    /// Idle timeout of a tcp tunnel, the lowest of the one requested by the client and the one of the server
+    fn idle_timeout(&self, requested: Option<Duration>) -> Option<Duration> {
+        match (requested, self.config.tunnel_idle_timeout) {
+            (Some(requested), Some(max)) => Some(requested.min(max)),
+            (requested, max) => requested.or(max),
+        }
     }
 
     async fn exec_tunnel(
         &self,
         restriction: &RestrictionConfig,
-        remote: RemoteAddr,
+        identity: &str,
+        mut remote: RemoteAddr,
         client_address: SocketAddr,
-    ) -> anyhow::Result<(RemoteAddr, Pin<Box<dyn AsyncRead + Send>>, Pin<Box<dyn AsyncWrite + Send>>)> {
+    ) -> anyhow::Result<(
+        RemoteAddr,
+        Pin<Box<dyn AsyncRead + Send>>,
+        Pin<Box<dyn AsyncWrite + Send>>,
+        LocalReset,
+    )> {
         match remote.protocol {
             LocalProtocol::Udp { timeout, .. } => {
                 let connector = UdpTunnelConnector::new(
@@ -163,27 +651,65 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                     self.config.socket_so_mark,
                     timeout.unwrap_or(Duration::from_secs(10)),
                     &self.config.dns_resolver,
-                );
+                )
+                .with_source_bind(&self.config.source_bind);
                 let (rx, tx) = match &self.config.http_proxy {
                     None => connector.connect(&None).await?,
                     Some(_) => Err(anyhow!("UDP tunneling is not supported with HTTP proxy"))?,
                 };
 
-                Ok((remote, Box::pin(rx), Box::pin(tx)))
+                Ok((remote, Box::pin(rx), Box::pin(tx), LocalReset::default()))
             }
-            LocalProtocol::Tcp { proxy_protocol } => {
-                let connector = TcpTunnelConnector::new(
-                    &remote.host,
-                    remote.port,
-                    self.config.socket_so_mark,
-                    Duration::from_secs(10),
-                    &self.config.dns_resolver,
-                );
-                let (rx, mut tx) = match &self.config.http_proxy {
-                    None => connector.connect(&None).await?,
-                    Some(proxy_url) => connector.connect_with_http_proxy(proxy_url, &None).await?,
+            LocalProtocol::Tcp {
+                proxy_protocol,
+                idle_timeout,
+                ref mirror,
+                ref balancing,
+                keepalive,
+                ..
+            } => {
+                let (fallbacks, health_check) = match balancing {
+                    Some(balancing) => (balancing.fallbacks.as_slice(), balancing.health_check),
+                    None => (&[][..], None),
+                };
+                let mut dests = failover::destinations((remote.host.clone(), remote.port), fallbacks).into_iter();
+                let (rx, mut tx) = loop {
+                    let Some(dest) = dests.next() else {
+                        return Err(anyhow!("Cannot reach any destination of the tunnel"));
+                    };
+                    let connector = TcpTunnelConnector::new(
+                        &dest.0,
+                        dest.1,
+                        self.config.socket_so_mark,
+                        Duration::from_secs(10),
+                        &self.config.dns_resolver,
+                    )
+                    .with_source_bind(&self.config.source_bind)
+                    .with_keepalive(keepalive);
+                    let ret = match &self.config.http_proxy {
+                        None => connector.connect(&None).await,
+                        Some(proxy_url) => connector.connect_with_http_proxy(proxy_url, &None).await,
+                    };
+                    match ret {
+                        Ok(ret) => {
+                            (remote.host, remote.port) = dest;
+                            break ret;
+                        }
+                        Err(err) => {
+                            failover::mark_unreachable(&dest, health_check);
+                            // Without other destination to fail over to, the error is the one of the tunnel
+                            if dests.len() == 0 {
+                                return Err(err);
+                            }
+                            warn!(
+                                "Cannot reach {}:{}, failing over to the next destination: {err:?}",
+                                dest.0, dest.1
+                            );
+                        }
+                    }
                 };
 
+                let local_reset = tx.local_reset();
                 if proxy_protocol {
                     let header = ppp::v2::Builder::with_addresses(
                         ppp::v2::Version::Two | ppp::v2::Command::Proxy,
@@ -194,85 +720,192 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                     let _ = tx.write_all(&header).await;
                 }
 
-                Ok((remote, Box::pin(rx), Box::pin(tx)))
+                #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                let (rx, tx) = match super::uring::into_split(rx.reunite(tx)?)? {
+                    Ok((rx, tx)) => (
+                        Box::pin(rx) as Pin<Box<dyn AsyncRead + Send>>,
+                        Box::pin(tx) as Pin<Box<dyn AsyncWrite + Send>>,
+                    ),
+                    Err(stream) => {
+                        let (rx, tx) = stream.into_split();
+                        (Box::pin(rx) as _, Box::pin(tx) as _)
+                    }
+                };
+
+                let tx: Pin<Box<dyn AsyncWrite + Send>> = match mirror {
+                    Some(mirror) => Box::pin(mirror::with_mirror(
+                        &self.executor,
+                        tx,
+                        mirror.clone(),
+                        self.config.socket_so_mark,
+                        self.config.dns_resolver.clone(),
+                    )),
+                    None => Box::pin(tx),
+                };
+                if let Some(timeout) = self.idle_timeout(idle_timeout) {
+                    let (rx, tx) = idle::with_idle_timeout(rx, tx, timeout);
+                    return Ok((remote, Box::pin(rx), Box::pin(tx), local_reset));
+                }
+                Ok((remote, Box::pin(rx), tx, local_reset))
             }
-            LocalProtocol::ReverseTcp => {
+            #[cfg(target_os = "linux")]
+            LocalProtocol::Sctp => {
+                use crate::tunnel::connectors::SctpTunnelConnector;
+                let connector = SctpTunnelConnector::new(
+                    &remote.host,
+                    remote.port,
+                    self.config.socket_so_mark,
+                    Duration::from_secs(10),
+                    &self.config.dns_resolver,
+                );
+                let (rx, tx) = match &self.config.http_proxy {
+                    None => connector.connect(&None).await?,
+                    Some(_) => Err(anyhow!("SCTP tunneling is not supported with HTTP proxy"))?,
+                };
+
+                Ok((remote, Box::pin(rx), Box::pin(tx), LocalReset::default()))
+            }
+            #[cfg(not(target_os = "linux"))]
+            LocalProtocol::Sctp => {
+                error!("Received an unsupported target protocol {:?}", remote);
+                Err(anyhow::anyhow!("Invalid upgrade request"))
+            }
+            LocalProtocol::ReverseTcp {
+                idle_timeout,
+                v6only,
+                access,
+                keepalive,
+                ..
+            } => {
                 static SERVERS: LazyLock<ReverseTunnelServer<TcpTunnelListener>> =
                     LazyLock::new(ReverseTunnelServer::new);
 
                 let remote_port = find_mapped_port(remote.port, restriction);
-                let local_srv = (remote.host, remote_port);
+                let local_srv = (find_bind_host(remote.host, restriction), remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
-                let listening_server = async { TcpTunnelListener::new(bind, local_srv.clone(), false).await };
+                let listening_server = async {
+                    TcpTunnelListener::new(
+                        bind,
+                        v6only,
+                        local_srv.clone(),
+                        false,
+                        None,
+                        None,
+                        None,
+                        None,
+                        keepalive,
+                        access,
+                    )
+                    .await
+                };
                 let ((local_rx, local_tx), remote) = SERVERS
                     .run_listening_server(
                         &self.executor,
                         bind,
                         self.config.remote_server_idle_timeout,
+                        self.config.cluster.is_some().then_some(&self.cluster),
+                        find_port_owner(identity, restriction),
                         listening_server,
                     )
                     .await?;
 
-                Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
+                let local_reset = local_tx.local_reset();
+                if let Some(timeout) = self.idle_timeout(idle_timeout) {
+                    let (local_rx, local_tx) = idle::with_idle_timeout(local_rx, local_tx, timeout);
+                    return Ok((remote, Box::pin(local_rx), Box::pin(local_tx), local_reset));
+                }
+                Ok((remote, Box::pin(local_rx), Box::pin(local_tx), local_reset))
             }
-            LocalProtocol::ReverseUdp { timeout } => {
+            LocalProtocol::ReverseUdp {
+                timeout,
+                max_flows,
+                flow_eviction,
+                v6only,
+            } => {
                 static SERVERS: LazyLock<ReverseTunnelServer<UdpTunnelListener>> =
                     LazyLock::new(ReverseTunnelServer::new);
 
                 let remote_port = find_mapped_port(remote.port, restriction);
-                let local_srv = (remote.host, remote_port);
+                let local_srv = (find_bind_host(remote.host, restriction), remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
-                let listening_server = async { UdpTunnelListener::new(bind, local_srv.clone(), timeout).await };
+                let listening_server = async {
+                    UdpTunnelListener::new(bind, v6only, local_srv.clone(), timeout, max_flows, flow_eviction).await
+                };
                 let ((local_rx, local_tx), remote) = SERVERS
                     .run_listening_server(
                         &self.executor,
                         bind,
                         self.config.remote_server_idle_timeout,
+                        None,
+                        find_port_owner(identity, restriction),
                         listening_server,
                     )
                     .await?;
-                Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
+                Ok((remote, Box::pin(local_rx), Box::pin(local_tx), LocalReset::default()))
             }
-            LocalProtocol::ReverseSocks5 { timeout, credentials } => {
+            LocalProtocol::ReverseSocks5 {
+                timeout,
+                credentials,
+                access,
+            } => {
                 static SERVERS: LazyLock<ReverseTunnelServer<Socks5TunnelListener>> =
                     LazyLock::new(ReverseTunnelServer::new);
 
                 let remote_port = find_mapped_port(remote.port, restriction);
-                let local_srv = (remote.host, remote_port);
+                let local_srv = (find_bind_host(remote.host, restriction), remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
-                let listening_server = async { Socks5TunnelListener::new(bind, timeout, credentials).await };
+                let listening_server = async {
+                    Socks5TunnelListener::new(bind, timeout, credentials, None, Socks5Resolver::Remote, access).await
+                };
                 let ((local_rx, local_tx), remote) = SERVERS
                     .run_listening_server(
                         &self.executor,
                         bind,
                         self.config.remote_server_idle_timeout,
+                        None,
+                        find_port_owner(identity, restriction),
                         listening_server,
                     )
                     .await?;
 
-                Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
+                Ok((remote, Box::pin(local_rx), Box::pin(local_tx), LocalReset::default()))
             }
-            LocalProtocol::ReverseHttpProxy { timeout, credentials } => {
+            LocalProtocol::ReverseHttpProxy {
+                timeout,
+                credentials,
+                v6only,
+                access,
+            } => {
                 static SERVERS: LazyLock<ReverseTunnelServer<HttpProxyTunnelListener>> =
                     LazyLock::new(ReverseTunnelServer::new);
 
                 let remote_port = find_mapped_port(remote.port, restriction);
-                let local_srv = (remote.host, remote_port);
+                let local_srv = (find_bind_host(remote.host, restriction), remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
-                let listening_server = async { HttpProxyTunnelListener::new(bind, timeout, credentials, false).await };
+                let listening_server = async {
+                    HttpProxyTunnelListener::new(bind, v6only, timeout, credentials, false, None, vec![], false, access)
+                        .await
+                };
                 let ((local_rx, local_tx), remote) = SERVERS
                     .run_listening_server(
                         &self.executor,
                         bind,
                         self.config.remote_server_idle_timeout,
+                        None,
+                        find_port_owner(identity, restriction),
                         listening_server,
                     )
                     .await?;
 
-                Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
+                Ok((remote, Box::pin(local_rx), Box::pin(local_tx), LocalReset::default()))
             }
             #[cfg(unix)]
-            LocalProtocol::ReverseUnix { ref path } => {
+            LocalProtocol::ReverseUnix {
+                ref path,
+                ref allowed_uids,
+                ref permissions,
+                lifecycle,
+            } => {
                 use crate::tunnel::listeners::UnixTunnelListener;
                 static SERVERS: LazyLock<ReverseTunnelServer<UnixTunnelListener>> =
                     LazyLock::new(ReverseTunnelServer::new);
@@ -286,17 +919,49 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
 
                 let local_srv = (host, 0);
                 let bind = try_to_sock_addr(local_srv.clone())?;
-                let listening_server = async { UnixTunnelListener::new(path, local_srv, false).await };
+                let listening_server = async {
+                    UnixTunnelListener::new(path, local_srv, false, allowed_uids.clone(), permissions, lifecycle).await
+                };
                 let ((local_rx, local_tx), remote) = SERVERS
                     .run_listening_server(
                         &self.executor,
                         bind,
                         self.config.remote_server_idle_timeout,
+                        None,
+                        None,
                         listening_server,
                     )
                     .await?;
 
-                Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
+                Ok((remote, Box::pin(local_rx), Box::pin(local_tx), LocalReset::default()))
+            }
+            LocalProtocol::ReverseHttpIngress {
+                ref vhost, ref auth, ..
+            } => {
+                static SERVERS: LazyLock<ReverseTunnelServer<HttpIngressListener>> =
+                    LazyLock::new(ReverseTunnelServer::new);
+
+                // like for unix sockets, we hash the virtual host to generate a unique bind address
+                let host = {
+                    let mut hasher = AHasher::default();
+                    vhost.hash(&mut hasher);
+                    Host::Ipv6(Ipv6Addr::from(hasher.finish() as u128))
+                };
+
+                let bind = try_to_sock_addr((host, 0))?;
+                let listening_server = async { HttpIngressListener::new(vhost, auth.clone()) };
+                let ((local_rx, local_tx), remote) = SERVERS
+                    .run_listening_server(
+                        &self.executor,
+                        bind,
+                        self.config.remote_server_idle_timeout,
+                        None,
+                        None,
+                        listening_server,
+                    )
+                    .await?;
+
+                Ok((remote, Box::pin(local_rx), Box::pin(local_tx), LocalReset::default()))
             }
             #[cfg(not(unix))]
             LocalProtocol::ReverseUnix { .. } => {
@@ -304,11 +969,15 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                 Err(anyhow::anyhow!("Invalid upgrade request"))
             }
             LocalProtocol::Stdio { .. }
+            | LocalProtocol::StdioUdp { .. }
             | LocalProtocol::Socks5 { .. }
             | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
-            | LocalProtocol::Unix { .. } => {
+            | LocalProtocol::Unix { .. }
+            | LocalProtocol::Vsock { .. }
+            | LocalProtocol::ReverseEncryptedDns { .. }
+            | LocalProtocol::Mux => {
                 error!("Received an unsupported target protocol {:?}", remote);
                 Err(anyhow::anyhow!("Invalid upgrade request"))
             }
@@ -317,6 +986,13 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
 
     pub async fn serve(self, restrictions: RestrictionsRules) -> anyhow::Result<()> {
         info!("Starting wstunnel server listening on {}", self.config.bind);
+        demux::check_protocol_handlers(&self.config).context(FailureKind::Config)?;
+        if let Some(standby_file) = &self.config.standby_file {
+            self.executor.spawn(standby::watch_standby_file(standby_file.clone()));
+        }
+        if self.config.cluster.is_some() {
+            self.executor.spawn(cluster::run_announcer(self.clone()));
+        }
 
         // setup upgrade request handler
         let mk_websocket_upgrade_fn = |server: WsServer<_>,
@@ -324,14 +1000,22 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                                        restrict_path: Option<String>,
                                        client_addr: SocketAddr| {
             move |req: Request<Incoming>| {
-                ws_server_upgrade(
-                    server.clone(),
-                    restrictions.load().clone(),
-                    restrict_path.clone(),
-                    client_addr,
-                    req,
-                )
-                .map::<anyhow::Result<_>, _>(Ok)
+                let server = server.clone();
+                let restrictions = restrictions.load().clone();
+                let restrict_path = restrict_path.clone();
+                async move {
+                    let config = server.config.clone();
+                    let response = if let Some(vhost) = find_vhost(&req) {
+                        forward_request(&server, vhost, client_addr, req).await
+                    } else if let Some(request) = cluster::find_request(&server, &req) {
+                        cluster::serve_request(&server, request, client_addr, req).await
+                    } else if is_session_request(&req) {
+                        http1_server_session(server, restrictions, restrict_path, client_addr, req).await
+                    } else {
+                        ws_server_upgrade(server, restrictions, restrict_path, client_addr, req).await
+                    };
+                    anyhow::Ok(replace_rejected(config.reject_response.as_ref(), response))
+                }
                 .instrument(mk_span())
             }
         };
@@ -341,14 +1025,22 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                                   restrict_path: Option<String>,
                                   client_addr: SocketAddr| {
             move |req: Request<Incoming>| {
-                http_server_upgrade(
-                    server.clone(),
-                    restrictions.load().clone(),
-                    restrict_path.clone(),
-                    client_addr,
-                    req,
-                )
-                .map::<anyhow::Result<_>, _>(Ok)
+                let server = server.clone();
+                let restrictions = restrictions.load().clone();
+                let restrict_path = restrict_path.clone();
+                async move {
+                    let config = server.config.clone();
+                    let response = if let Some(vhost) = find_vhost(&req) {
+                        forward_request(&server, vhost, client_addr, req).await
+                    } else if let Some(request) = cluster::find_request(&server, &req) {
+                        cluster::serve_request(&server, request, client_addr, req).await
+                    } else if is_session_request(&req) {
+                        http1_server_session(server, restrictions, restrict_path, client_addr, req).await
+                    } else {
+                        http_server_upgrade(server, restrictions, restrict_path, client_addr, req).await
+                    };
+                    anyhow::Ok(replace_rejected(config.reject_response.as_ref(), response))
+                }
                 .instrument(mk_span())
             }
         };
@@ -362,177 +1054,349 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                 let restrictions = restrictions.clone();
                 let restrict_path = restrict_path.clone();
                 async move {
-                    if fastwebsockets::upgrade::is_upgrade_request(&req) {
-                        ws_server_upgrade(server.clone(), restrictions.load().clone(), restrict_path, client_addr, req)
-                            .map::<anyhow::Result<_>, _>(Ok)
-                            .await
-                    } else if req.version() == Version::HTTP_2 {
-                        http_server_upgrade(
-                            server.clone(),
-                            restrictions.load().clone(),
-                            restrict_path.clone(),
-                            client_addr,
-                            req,
-                        )
+                    serve_request(server, restrictions.load().clone(), restrict_path, client_addr, req)
                         .map::<anyhow::Result<_>, _>(Ok)
                         .await
-                    } else {
-                        error!("Invalid protocol version request, got {:?} while expecting either websocket http1 upgrade or http2", req.version());
-                        Ok(http::Response::builder()
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(Either::Left("Invalid protocol request".to_string()))
-                            .unwrap())
-                    }
                 }
-                    .instrument(mk_span())
+                .instrument(mk_span())
             }
         };
 
         // Init TLS if needed
-        let mut tls_context = if let Some(tls_config) = &self.config.tls {
+        let tls_context = if let Some(tls_config) = &self.config.tls {
             let tls_context = TlsContext {
-                tls_acceptor: Arc::new(tls::tls_acceptor(
-                    tls_config,
-                    Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()]),
-                )?),
-                tls_reloader: TlsReloader::new_for_server(self.config.clone())?,
-                tls_config,
+                tls_acceptor: Arc::new(
+                    tls::tls_acceptor(tls_config, Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()]))
+                        .context(FailureKind::Tls)?,
+                ),
+                tls_reloader: TlsReloader::new_for_server(self.config.clone()).context(FailureKind::Tls)?,
+                server_config: self.config.clone(),
             };
-            Some(tls_context)
+            // Shared by the accept tasks of all the listeners
+            Some(Arc::new(Mutex::new(tls_context)))
         } else {
             None
         };
 
         // Bind server and run forever to serve incoming connections.
-        let restrictions = RestrictionsRulesReloader::new(restrictions, self.config.restriction_config.clone())?;
-        let listener = TcpListener::bind(&self.config.bind)
-            .await
-            .with_context(|| format!("Failed to bind to socket on {}", self.config.bind))?;
-
-        loop {
-            let (stream, peer_addr) = match listener.accept().await {
-                Ok(ret) => ret,
-                Err(err) => {
-                    warn!("Error while accepting connection {:?}", err);
-                    continue;
+        let restrictions = RestrictionsRulesReloader::new(restrictions, self.config.restriction_config.clone())
+            .context(FailureKind::Config)?;
+        if let Some(metrics_listen) = self.config.metrics_listen {
+            self.executor.spawn(async move {
+                if let Err(err) = metrics::run_metrics_server(metrics_listen).await {
+                    error!("Metrics server stopped: {err:?}");
                 }
-            };
-
-            let span = span!(Level::INFO, "cnx", peer = peer_addr.to_string());
-            info!(parent: &span, "Accepting connection");
-            if let Err(err) = protocols::tcp::configure_socket(SockRef::from(&stream), SoMark::new(None)) {
-                warn!("Error while configuring server socket {:?}", err);
-            }
+            });
+        }
+        #[cfg(feature = "dns-transport")]
+        if let Some(dns_transport) = self.config.dns_transport.clone() {
+            let dns_server = run_dns_server(self.clone(), restrictions.restrictions_rules().clone(), dns_transport);
+            self.executor.spawn(async move {
+                if let Err(err) = dns_server.await {
+                    error!("DNS transport server stopped: {err:?}");
+                }
+            });
+        }
+        #[cfg(feature = "icmp-transport")]
+        if let Some(icmp_transport) = self.config.icmp_transport.clone() {
+            let icmp_server = run_icmp_server(self.clone(), restrictions.restrictions_rules().clone(), icmp_transport);
+            self.executor.spawn(async move {
+                if let Err(err) = icmp_server.await {
+                    error!("ICMP transport server stopped: {err:?}");
+                }
+            });
+        }
+        let mut listeners = self.bind_listeners().await?;
+        #[cfg(feature = "ssh-transport")]
+        let ssh_config = self
+            .config
+            .ssh_transport
+            .as_ref()
+            .map(|ssh| Arc::new(ssh.server_config(self.config.websocket_ping_frequency)));
+        HEALTH.set_listening(true);
 
-            let server = self.clone();
+        let accept_connections = |listener: TcpListener| {
+            let this = self.clone();
             let restrictions = restrictions.restrictions_rules().clone();
+            let tls_context = tls_context.clone();
+            #[cfg(feature = "ssh-transport")]
+            let ssh_config = ssh_config.clone();
+            async move {
+                loop {
+                    let (stream, peer_addr) = match listener.accept().await {
+                        Ok(ret) => ret,
+                        Err(err) => {
+                            warn!("Error while accepting connection {:?}", err);
+                            continue;
+                        }
+                    };
+
+                    let span = span!(Level::INFO, "cnx", peer = peer_addr.to_string());
+                    info!(parent: &span, "Accepting connection");
+                    if let Err(err) = protocols::tcp::configure_socket(SockRef::from(&stream), SoMark::new(None)) {
+                        warn!("Error while configuring server socket {:?}", err);
+                    }
+                    if let Some(dscp) = this.config.dscp
+                        && let Err(err) = dscp::set_dscp(SockRef::from(&stream), dscp)
+                    {
+                        warn!("Cannot set dscp {dscp} of the connection: {err:?}");
+                    }
 
-            // Check if we need to enable TLS or not
-            match tls_context.as_mut() {
-                Some(tls) => {
+                    let server = this.clone();
+                    let restrictions = restrictions.clone();
                     // Reload TLS certificate if needed
-                    let tls_acceptor = tls.tls_acceptor().clone();
+                    let tls_acceptor = tls_context.as_ref().map(|tls| tls.lock().tls_acceptor().clone());
+                    #[cfg(feature = "ssh-transport")]
+                    let ssh_config = ssh_config.clone();
                     let fut = async move {
-                        info!("Doing TLS handshake");
-                        let tls_stream = match tls_acceptor.accept(stream).await {
-                            Ok(tls_stream) => hyper_util::rt::TokioIo::new(tls_stream),
-                            Err(err) => {
-                                error!("error while accepting TLS connection {}", err);
+                        let protocol = demux::sniff_protocol(&stream).await;
+                        let mut handler = demux::protocol_handler(&server.config, protocol);
+                        if protocol == SniffedProtocol::Tls
+                            && handler == ProtocolHandler::Wstunnel
+                            && let Some(sni_passthrough) = &server.config.tls_sni_passthrough
+                        {
+                            handler = demux::route_server_name(sni_passthrough, &stream).await;
+                        }
+                        match handler {
+                            ProtocolHandler::Wstunnel => {}
+                            ProtocolHandler::Reject => {
+                                info!("Closing connection, {protocol} is not served");
                                 return;
                             }
-                        };
-
-                        let tls_ctx = tls_stream.inner().get_ref().1;
-                        // extract client certificate common name if any
-                        let restrict_path = tls_ctx
-                            .peer_certificates()
-                            .and_then(tls::find_leaf_certificate)
-                            .and_then(|c| tls::cn_from_certificate(&c));
-                        match tls_ctx.alpn_protocol() {
-                            // http2
-                            Some(b"h2") => {
-                                let mut conn_builder = http2::Builder::new(TokioExecutor::new());
-                                conn_builder.timer(TokioTimer::new());
-                                if let Some(ping) = server.config.websocket_ping_frequency {
-                                    conn_builder.keep_alive_interval(ping);
-                                }
+                            ProtocolHandler::Passthrough(host, port) => {
+                                info!("Passing {protocol} connection through to {host}:{port}");
+                                return demux::passthrough(&server, stream, &host, port).await;
+                            }
+                        }
+
+                        match (protocol, tls_acceptor) {
+                            (SniffedProtocol::Tls, Some(tls_acceptor)) => {
+                                info!("Doing TLS handshake");
+                                let tls_stream = match tls::accept(&tls_acceptor, stream).await {
+                                    Ok(tls_stream) => hyper_util::rt::TokioIo::new(tls_stream),
+                                    Err(err) => {
+                                        error!("error while accepting TLS connection {}", err);
+                                        return;
+                                    }
+                                };
+
+                                let tls_ctx = tls_stream.inner();
+                                // extract client certificate common name if any
+                                let restrict_path = tls_ctx
+                                    .peer_certificates()
+                                    .and_then(tls::find_leaf_certificate)
+                                    .and_then(|c| tls::cn_from_certificate(&c));
+                                match tls_ctx.alpn_protocol() {
+                                    // http2
+                                    Some(b"h2") => {
+                                        let mut conn_builder = http2::Builder::new(TokioExecutor::new());
+                                        conn_builder
+                                            .timer(TokioTimer::new())
+                                            .max_frame_size(h2_max_frame_size(server.config.websocket_max_frame_size))
+                                            .initial_stream_window_size(h2_window_size(
+                                                server.config.max_inflight_per_tunnel,
+                                            ))
+                                            .initial_connection_window_size(h2_window_size(
+                                                server.config.max_inflight_per_tunnel,
+                                            ));
+                                        if let Some(ping) = server.config.websocket_ping_frequency {
+                                            conn_builder.keep_alive_interval(ping);
+                                        }
 
-                                let http_upgrade_fn =
-                                    mk_http_upgrade_fn(server, restrictions, restrict_path, peer_addr);
-                                let con_fut = conn_builder.serve_connection(tls_stream, service_fn(http_upgrade_fn));
-                                if let Err(e) = con_fut.await {
-                                    error!("Error while upgrading cnx to http: {:?}", e);
+                                        let http_upgrade_fn =
+                                            mk_http_upgrade_fn(server, restrictions, restrict_path, peer_addr);
+                                        let con_fut =
+                                            conn_builder.serve_connection(tls_stream, service_fn(http_upgrade_fn));
+                                        if let Err(e) = con_fut.await {
+                                            error!("Error while upgrading cnx to http: {:?}", e);
+                                        }
+                                    }
+                                    // websocket
+                                    _ => {
+                                        let websocket_upgrade_fn =
+                                            mk_websocket_upgrade_fn(server, restrictions, restrict_path, peer_addr);
+                                        let conn_fut = http1::Builder::new()
+                                            .timer(TokioTimer::new())
+                                            // https://github.com/erebe/wstunnel/issues/358
+                                            // disabled, to avoid conflict with --connection-min-idle flag, that open idle connections
+                                            .header_read_timeout(None)
+                                            .serve_connection(tls_stream, service_fn(websocket_upgrade_fn))
+                                            .with_upgrades();
+
+                                        if let Err(e) = conn_fut.await {
+                                            error!("Error while upgrading cnx: {:?}", e);
+                                        }
+                                    }
+                                };
+                            }
+                            #[cfg(feature = "ssh-transport")]
+                            (SniffedProtocol::Ssh, _) => {
+                                if let Some(ssh_config) = ssh_config {
+                                    ssh_server_session(server, ssh_config, restrictions, peer_addr, stream).await;
                                 }
                             }
-                            // websocket
-                            _ => {
-                                let websocket_upgrade_fn =
-                                    mk_websocket_upgrade_fn(server, restrictions, restrict_path, peer_addr);
-                                let conn_fut = http1::Builder::new()
+                            // HTTP without TLS
+                            (SniffedProtocol::Http | SniffedProtocol::H2, _) => {
+                                let stream = hyper_util::rt::TokioIo::new(stream);
+                                let mut conn_fut = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+                                conn_fut
+                                    .http2()
                                     .timer(TokioTimer::new())
-                                    // https://github.com/erebe/wstunnel/issues/358
-                                    // disabled, to avoid conflict with --connection-min-idle flag, that open idle connections
-                                    .header_read_timeout(None)
-                                    .serve_connection(tls_stream, service_fn(websocket_upgrade_fn))
-                                    .with_upgrades();
-
-                                if let Err(e) = conn_fut.await {
-                                    error!("Error while upgrading cnx: {:?}", e);
+                                    .max_frame_size(h2_max_frame_size(server.config.websocket_max_frame_size))
+                                    .initial_stream_window_size(h2_window_size(server.config.max_inflight_per_tunnel))
+                                    .initial_connection_window_size(h2_window_size(
+                                        server.config.max_inflight_per_tunnel,
+                                    ));
+                                if let Some(ping) = server.config.websocket_ping_frequency {
+                                    conn_fut.http2().keep_alive_interval(ping);
+                                }
+
+                                let websocket_upgrade_fn = mk_auto_upgrade_fn(server, restrictions, None, peer_addr);
+                                let upgradable =
+                                    conn_fut.serve_connection_with_upgrades(stream, service_fn(websocket_upgrade_fn));
+
+                                if let Err(e) = upgradable.await {
+                                    error!("Error while upgrading cnx to websocket: {:?}", e);
                                 }
                             }
-                        };
+                            _ => {}
+                        }
                     }
                     .instrument(span);
 
-                    self.executor.spawn(fut);
+                    this.executor.spawn(fut);
                 }
-                // HTTP without TLS
-                None => {
-                    let fut = async move {
-                        let stream = hyper_util::rt::TokioIo::new(stream);
-                        let mut conn_fut = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
-                        if let Some(ping) = server.config.websocket_ping_frequency {
-                            conn_fut.http2().keep_alive_interval(ping);
-                        }
+            }
+        };
 
-                        let websocket_upgrade_fn = mk_auto_upgrade_fn(server, restrictions, None, peer_addr);
-                        let upgradable =
-                            conn_fut.serve_connection_with_upgrades(stream, service_fn(websocket_upgrade_fn));
+        // The first listener is served by this task, the others by their own ones to spread the accepts on the workers
+        let listener = listeners.remove(0);
+        for listener in listeners {
+            self.executor.spawn(accept_connections(listener));
+        }
+        accept_connections(listener).await
+    }
 
-                        if let Err(e) = upgradable.await {
-                            error!("Error while upgrading cnx to websocket: {:?}", e);
-                        }
-                    }
-                    .instrument(span);
+    /// Bind the listening port, once per accept shard with SO_REUSEPORT for the kernel to spread the new connections
+    /// between the listeners
+    async fn bind_listeners(&self) -> anyhow::Result<Vec<TcpListener>> {
+        let shards = match self.config.accept_shards {
+            0 => Handle::try_current().map_or(1, |handle| handle.metrics().num_workers()),
+            shards => shards,
+        };
+        let listeners = if shards <= 1 {
+            let listener = TcpListener::bind(&self.config.bind)
+                .await
+                .with_context(|| format!("Failed to bind to socket on {}", self.config.bind))
+                .context(FailureKind::Bind)?;
+            vec![listener]
+        } else {
+            info!("Accepting connections on {} with {shards} listeners", self.config.bind);
+            self.bind_reuse_port_listeners(shards).context(FailureKind::Bind)?
+        };
 
-                    self.executor.spawn(fut);
-                }
+        for listener in &listeners {
+            if self.config.tcp_fastopen {
+                protocols::tcp::set_tcp_fastopen_listener(SockRef::from(listener), 256)
+                    .with_context(|| format!("Cannot enable TCP fast open on {}", self.config.bind))
+                    .context(FailureKind::Bind)?;
+            }
+            if let Some(defer_accept) = self.config.tcp_defer_accept {
+                protocols::tcp::set_tcp_defer_accept(SockRef::from(listener), defer_accept)
+                    .with_context(|| format!("Cannot enable TCP defer accept on {}", self.config.bind))
+                    .context(FailureKind::Bind)?;
             }
         }
+        Ok(listeners)
+    }
+
+    #[cfg(unix)]
+    fn bind_reuse_port_listeners(&self, shards: usize) -> anyhow::Result<Vec<TcpListener>> {
+        let bind = |addr| {
+            protocols::tcp::bind_reuse_port_listener(addr)
+                .with_context(|| format!("Failed to bind to socket on {addr} with SO_REUSEPORT"))
+        };
+        // With port 0, the other listeners take the port picked for the first one
+        let first = bind(self.config.bind)?;
+        let addr = first.local_addr()?;
+        let mut listeners = vec![first];
+        for _ in 1..shards {
+            listeners.push(bind(addr)?);
+        }
+        Ok(listeners)
+    }
+
+    #[cfg(not(unix))]
+    fn bind_reuse_port_listeners(&self, _shards: usize) -> anyhow::Result<Vec<TcpListener>> {
+        Err(anyhow!(
+            "Sharding the accept of the connections needs SO_REUSEPORT, it is only supported on unix"
+        ))
     }
 }
 
-fn mk_span() -> Span {
+pub(super) fn mk_span() -> Span {
     span!(
         Level::INFO,
         "tunnel",
         id = tracing::field::Empty,
         remote = tracing::field::Empty,
+        label = tracing::field::Empty,
+        device = tracing::field::Empty,
         forwarded_for = tracing::field::Empty
     )
 }
 
 impl Debug for WsServerConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.debug_struct("WsServerConfig")
-            .field("socket_so_mark", &self.socket_so_mark)
+        let mut f = f.debug_struct("WsServerConfig");
+        f.field("socket_so_mark", &self.socket_so_mark)
             .field("bind", &self.bind)
             .field("websocket_ping_frequency", &self.websocket_ping_frequency)
             .field("timeout_connect", &self.timeout_connect)
             .field("websocket_mask_frame", &self.websocket_mask_frame)
-            .field("restriction_config", &self.restriction_config)
+            .field("websocket_max_frame_size", &self.websocket_max_frame_size)
+            .field("websocket_close_timeout", &self.websocket_close_timeout)
+            .field("websocket_close_codes", &self.websocket_close_codes)
+            .field("tcp_fastopen", &self.tcp_fastopen)
+            .field("tcp_defer_accept", &self.tcp_defer_accept)
+            .field("accept_shards", &self.accept_shards)
+            .field("dscp", &self.dscp)
+            .field("source_bind", &self.source_bind)
+            .field("auth_hook", &self.auth_hook)
+            .field("auth_hook_timeout", &self.auth_hook_timeout)
+            .field("oidc_issuer", &self.oidc.as_ref().map(|oidc| oidc.issuer()))
+            .field("psk", &self.psk.is_some())
+            .field(
+                "noise",
+                &self
+                    .noise
+                    .as_ref()
+                    .map(|noise| noise.private_key.public_key().to_base64()),
+            );
+        #[cfg(feature = "dns-transport")]
+        f.field("dns_transport", &self.dns_transport);
+        #[cfg(feature = "icmp-transport")]
+        f.field("icmp_transport", &self.icmp_transport);
+        #[cfg(feature = "ssh-transport")]
+        f.field("ssh_transport", &self.ssh_transport);
+        f.field("restriction_config", &self.restriction_config)
             .field("tls", &self.tls.is_some())
+            .field("protocol_handlers", &self.protocol_handlers)
+            .field("tls_sni_passthrough", &self.tls_sni_passthrough)
             .field("remote_server_idle_timeout", &self.remote_server_idle_timeout)
+            .field("tunnel_resume_max_timeout", &self.tunnel_resume_max_timeout)
+            .field("tunnel_idle_timeout", &self.tunnel_idle_timeout)
+            .field("metrics_listen", &self.metrics_listen)
+            .field("max_clients", &self.max_clients)
+            .field("max_tunnels_per_client", &self.max_tunnels_per_client)
+            .field("require_min_client_version", &self.require_min_client_version)
+            .field("max_inflight_per_tunnel", &self.max_inflight_per_tunnel)
+            .field("pcap_dir", &self.pcap_dir)
+            .field("http_ingress", &self.http_ingress)
+            .field("reject_response", &self.reject_response.as_ref().map(|reject| reject.status))
+            .field("standby_file", &self.standby_file)
+            .field("cluster", &self.cluster)
+            .field("sticky_session", &self.sticky_session)
             .field(
                 "mTLS",
                 &self
@@ -545,16 +1409,18 @@ impl Debug for WsServerConfig {
     }
 }
 
-struct TlsContext<'a> {
+struct TlsContext {
     tls_acceptor: Arc<TlsAcceptor>,
     tls_reloader: TlsReloader,
-    tls_config: &'a TlsServerConfig,
+    server_config: Arc<WsServerConfig>,
 }
-impl TlsContext<'_> {
+impl TlsContext {
     #[inline]
     pub fn tls_acceptor(&mut self) -> &Arc<TlsAcceptor> {
-        if self.tls_reloader.should_reload_certificate() {
-            match tls::tls_acceptor(self.tls_config, Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()])) {
+        if self.tls_reloader.should_reload_certificate()
+            && let Some(tls_config) = &self.server_config.tls
+        {
+            match tls::tls_acceptor(tls_config, Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()])) {
                 Ok(acceptor) => self.tls_acceptor = Arc::new(acceptor),
                 Err(err) => error!("Cannot reload TLS certificate {:?}", err),
             };
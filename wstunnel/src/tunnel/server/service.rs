@@ -0,0 +1,139 @@
+use crate::executor::{DefaultTokioExecutor, TokioExecutorRef};
+use crate::restrictions::config_reloader::RestrictionsRulesReloader;
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::server::WsServer;
+use crate::tunnel::server::cluster;
+use crate::tunnel::server::handler_http1::http1_server_session;
+use crate::tunnel::server::handler_http2::http_server_upgrade;
+use crate::tunnel::server::handler_websocket::ws_server_upgrade;
+use crate::tunnel::server::http_ingress::{find_vhost, forward_request};
+use crate::tunnel::server::reject::{Rejected, replace_rejected};
+use crate::tunnel::server::server::mk_span;
+use crate::tunnel::server::utils::{HttpResponse, health_probe};
+use crate::tunnel::transport::http1::is_session_request;
+use bytes::Bytes;
+use http_body_util::Either;
+use hyper::body::Body;
+use hyper::{Request, Response, StatusCode, Version};
+use std::convert::Infallible;
+use std::error::Error;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tracing::{Instrument, error};
+
+/// Body of the requests the server accepts, hyper's own or the one of the framework it is mounted in,
+/// i.e: `axum::body::Body`
+pub trait RequestBody:
+    Body<Data = Bytes, Error: Into<Box<dyn Error + Send + Sync>> + Send> + Send + Unpin + 'static
+{
+}
+
+impl<B> RequestBody for B where
+    B: Body<Data = Bytes, Error: Into<Box<dyn Error + Send + Sync>> + Send> + Send + Unpin + 'static
+{
+}
+
+/// Serve a request of a client, whatever its transport: websocket, http1 session or http2
+pub(super) async fn serve_request<E: TokioExecutorRef>(
+    server: WsServer<E>,
+    restrictions: Arc<RestrictionsRules>,
+    restrict_path: Option<String>,
+    client_addr: SocketAddr,
+    req: Request<impl RequestBody>,
+) -> HttpResponse {
+    let config = server.config.clone();
+    let response = if let Some(vhost) = find_vhost(&req) {
+        forward_request(&server, vhost, client_addr, req).await
+    } else if let Some(request) = cluster::find_request(&server, &req) {
+        cluster::serve_request(&server, request, client_addr, req).await
+    } else if fastwebsockets::upgrade::is_upgrade_request(&req) {
+        ws_server_upgrade(server, restrictions, restrict_path, client_addr, req).await
+    } else if is_session_request(&req) {
+        http1_server_session(server, restrictions, restrict_path, client_addr, req).await
+    } else if req.version() == Version::HTTP_2 {
+        http_server_upgrade(server, restrictions, restrict_path, client_addr, req).await
+    } else if let Some(response) = health_probe(&req) {
+        response
+    } else {
+        error!(
+            "Invalid protocol version request, got {:?} while expecting either websocket http1 upgrade, http1 session or http2",
+            req.version()
+        );
+        Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .extension(Rejected)
+            .body(Either::Left("Invalid protocol request".to_string()))
+            .unwrap()
+    };
+
+    replace_rejected(config.reject_response.as_ref(), response)
+}
+
+/// Answer the requests of the wstunnel clients from an existing http server, to share its port and TLS.
+/// Implement both [`hyper::service::Service`] and [`tower_service::Service`], i.e: with axum
+/// `Router::new().route_service("/v1/events", server.service(restrictions)?)` for the default upgrade path prefix.
+///
+/// The http server must serve the connections with upgrades, for the websocket transport. The address of the client,
+/// used by the restrictions and shown in the logs, is read from the [`SocketAddr`] of the request extensions if any
+#[derive(Clone)]
+pub struct WsServerService<E: TokioExecutorRef = DefaultTokioExecutor> {
+    server: WsServer<E>,
+    restrictions: RestrictionsRulesReloader,
+}
+
+impl<E: TokioExecutorRef> WsServer<E> {
+    /// Service to mount the tunnel endpoint in an existing http server, instead of running [`WsServer::serve`]
+    pub fn service(&self, restrictions: RestrictionsRules) -> anyhow::Result<WsServerService<E>> {
+        let restrictions = RestrictionsRulesReloader::new(restrictions, self.config.restriction_config.clone())?;
+        Ok(WsServerService {
+            server: self.clone(),
+            restrictions,
+        })
+    }
+}
+
+impl<E: TokioExecutorRef> WsServerService<E> {
+    fn serve(
+        &self,
+        req: Request<impl RequestBody>,
+    ) -> Pin<Box<dyn Future<Output = Result<HttpResponse, Infallible>> + Send>> {
+        let server = self.server.clone();
+        let restrictions = self.restrictions.restrictions_rules().load().clone();
+        let client_addr = req
+            .extensions()
+            .get::<SocketAddr>()
+            .copied()
+            .unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+
+        Box::pin(
+            async move { Ok(serve_request(server, restrictions, None, client_addr, req).await) }.instrument(mk_span()),
+        )
+    }
+}
+
+impl<E: TokioExecutorRef, B: RequestBody> hyper::service::Service<Request<B>> for WsServerService<E> {
+    type Response = HttpResponse;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<HttpResponse, Infallible>> + Send>>;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        self.serve(req)
+    }
+}
+
+impl<E: TokioExecutorRef, B: RequestBody> tower_service::Service<Request<B>> for WsServerService<E> {
+    type Response = HttpResponse;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<HttpResponse, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        self.serve(req)
+    }
+}
@@ -0,0 +1,51 @@
+//! standby - passive server of an active-standby pair, i.e: with keepalived. While its standby file exists, it refuses
+//! the tunnels with a 503 and the x-wstunnel-standby header, so the clients switch right away to their next
+//! --standby-server, and it closes its reverse tunnels, so their clients reconnect to the active server
+use crate::tunnel::server::utils::HttpResponse;
+use crate::tunnel::transport::STANDBY_HEADER;
+use http_body_util::Either;
+use hyper::{StatusCode, http};
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// How often the presence of the standby file is checked
+const STANDBY_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+static STANDBY: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+pub(super) fn is_standby() -> bool {
+    *STANDBY.borrow()
+}
+
+/// Resolves once the server is on standby
+pub(super) async fn wait_standby() {
+    let _ = STANDBY.subscribe().wait_for(|standby| *standby).await;
+}
+
+pub(super) async fn watch_standby_file(path: PathBuf) {
+    let mut interval = tokio::time::interval(STANDBY_FILE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let standby = path.exists();
+        if STANDBY.send_replace(standby) == standby {
+            continue;
+        }
+        if standby {
+            warn!("Standby file {path:?} exists, refusing tunnels and closing the reverse ones");
+        } else {
+            info!("Standby file {path:?} removed, accepting tunnels");
+        }
+    }
+}
+
+/// Not replaced by --reject-*, the clients must see it to switch to another server
+pub(super) fn standby_response() -> HttpResponse {
+    http::Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(STANDBY_HEADER, "1")
+        .body(Either::Left("Server is on standby".to_string()))
+        .unwrap()
+}
@@ -0,0 +1,362 @@
+//! io_uring backend of the tcp connections of the server to the destinations of the tunnels, `io-uring` feature.
+//! Their reads and writes are submitted to a ring shared by the whole process, whose completions are reaped by a
+//! dedicated thread, instead of waiting for the readiness of each socket with epoll before doing its syscall.
+//! The buffers of the operations are owned by them, so a tunnel closed with an operation in flight does not leave the
+//! kernel write in freed memory. Falls back to tokio when the kernel refuses to create the ring
+use io_uring::{IoUring, opcode, squeue, types};
+use nix::sys::socket::MsgFlags;
+use parking_lot::Mutex;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll, Waker, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{info, warn};
+
+const RING_ENTRIES: u32 = 4096;
+/// Bytes read or written by a single operation
+const OP_BUFFER_LEN: usize = 64 * 1024;
+/// user_data of the operations whose completion is not awaited, i.e: the cancellations
+const IGNORED_OP: u64 = 0;
+
+struct Driver {
+    ring: IoUring,
+    /// The submission queue is shared by all the threads of the runtime
+    submission: Mutex<()>,
+}
+
+/// The ring of the process, None if io_uring is not available, i.e: old kernel or forbidden by seccomp
+static DRIVER: LazyLock<Option<&'static Driver>> = LazyLock::new(|| {
+    let ring = match IoUring::builder().build(RING_ENTRIES) {
+        Ok(ring) => ring,
+        Err(err) => {
+            warn!("Cannot create an io_uring, falling back to epoll for the connections to the destinations: {err}");
+            return None;
+        }
+    };
+    let driver: &'static Driver = Box::leak(Box::new(Driver {
+        ring,
+        submission: Mutex::new(()),
+    }));
+    if let Err(err) = std::thread::Builder::new()
+        .name("wstunnel-uring".to_string())
+        .spawn(|| driver.reap_completions())
+    {
+        warn!("Cannot start the io_uring completion thread, falling back to epoll: {err}");
+        return None;
+    }
+    info!("Using io_uring for the connections to the destinations of the tunnels");
+    Some(driver)
+});
+
+/// State of an operation, shared with the ring until its completion
+struct Op {
+    state: Mutex<OpState>,
+}
+
+struct OpState {
+    /// Result of the operation, once completed
+    result: Option<i32>,
+    waker: Option<Waker>,
+    /// Read into or written from by the kernel until the completion
+    buf: Vec<u8>,
+}
+
+impl Driver {
+    /// Submit an operation on `buf`, the completion wakes the task of `cx`
+    fn submit(&self, entry: squeue::Entry, buf: Vec<u8>, cx: &Context<'_>) -> io::Result<Arc<Op>> {
+        let op = Arc::new(Op {
+            state: Mutex::new(OpState {
+                result: None,
+                waker: Some(cx.waker().clone()),
+                buf,
+            }),
+        });
+        // The ring holds a reference to the operation until its completion
+        let user_data = Arc::into_raw(op.clone()) as u64;
+        if let Err(err) = self.push(entry.user_data(user_data)) {
+            drop(unsafe { Arc::from_raw(user_data as *const Op) });
+            return Err(err);
+        }
+        Ok(op)
+    }
+
+    fn cancel(&self, op: &Arc<Op>) {
+        let entry = opcode::AsyncCancel::new(Arc::as_ptr(op) as u64)
+            .build()
+            .user_data(IGNORED_OP);
+        if let Err(err) = self.push(entry) {
+            warn!("Cannot cancel io_uring operation: {err}");
+        }
+    }
+
+    fn push(&self, entry: squeue::Entry) -> io::Result<()> {
+        let _lock = self.submission.lock();
+        // Safety: the submission queue is only accessed with the lock held
+        let mut queue = unsafe { self.ring.submission_shared() };
+        // Safety: the buffers of the entries are owned by their operation, alive until their completion
+        while unsafe { queue.push(&entry) }.is_err() {
+            // Full, the kernel takes the pending entries
+            queue.sync();
+            self.ring.submit()?;
+            queue.sync();
+        }
+        queue.sync();
+        drop(queue);
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    fn reap_completions(&self) {
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    // The connections with an operation in flight stay stuck, nothing else to do
+                    warn!("io_uring completion thread stopped: {err}");
+                    return;
+                }
+            }
+
+            // Safety: the completion queue is only accessed by this thread
+            for cqe in unsafe { self.ring.completion_shared() } {
+                if cqe.user_data() == IGNORED_OP {
+                    continue;
+                }
+                // Safety: reference leaked by the submission of the operation
+                let op = unsafe { Arc::from_raw(cqe.user_data() as *const Op) };
+                let waker = {
+                    let mut state = op.state.lock();
+                    state.result = Some(cqe.result());
+                    state.waker.take()
+                };
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+/// Result of the operation and its buffer back, or register the task to be woken on its completion
+fn poll_op(op: &Op, cx: &Context<'_>) -> Poll<(i32, Vec<u8>)> {
+    let mut state = op.state.lock();
+    match state.result {
+        Some(result) => Poll::Ready((result, std::mem::take(&mut state.buf))),
+        None => {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+fn op_result(result: i32) -> io::Result<usize> {
+    if result < 0 {
+        Err(io::Error::from_raw_os_error(-result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Split the connection to have its reads and writes go through io_uring, or give it back if io_uring is not available
+pub fn into_split(
+    stream: tokio::net::TcpStream,
+) -> io::Result<Result<(UringReadHalf, UringWriteHalf), tokio::net::TcpStream>> {
+    let Some(driver) = *DRIVER else {
+        return Ok(Err(stream));
+    };
+    let stream = stream.into_std()?;
+    // io_uring honors O_NONBLOCK and would fail with EAGAIN, instead of polling the socket itself
+    stream.set_nonblocking(false)?;
+    let stream = Arc::new(stream);
+    let reader = UringReadHalf {
+        driver,
+        stream: stream.clone(),
+        op: None,
+        buf: Vec::new(),
+        pos: 0,
+        eof: false,
+    };
+    let writer = UringWriteHalf {
+        driver,
+        stream,
+        op: None,
+        buf: Vec::new(),
+        written: 0,
+    };
+    Ok(Ok((reader, writer)))
+}
+
+pub struct UringReadHalf {
+    driver: &'static Driver,
+    stream: Arc<TcpStream>,
+    op: Option<Arc<Op>>,
+    /// Received by the last operation, not yet read
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl AsyncRead for UringReadHalf {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos < this.buf.len() {
+                let len = out.remaining().min(this.buf.len() - this.pos);
+                out.put_slice(&this.buf[this.pos..this.pos + len]);
+                this.pos += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            match &this.op {
+                Some(op) => {
+                    let (result, mut buf) = ready!(poll_op(op, cx));
+                    this.op = None;
+                    let len = op_result(result)?;
+                    buf.truncate(len);
+                    this.eof = len == 0;
+                    this.buf = buf;
+                    this.pos = 0;
+                }
+                None => {
+                    let mut buf = std::mem::take(&mut this.buf);
+                    buf.resize(OP_BUFFER_LEN, 0);
+                    let entry =
+                        opcode::Recv::new(types::Fd(this.stream.as_raw_fd()), buf.as_mut_ptr(), buf.len() as u32)
+                            .build();
+                    this.op = Some(this.driver.submit(entry, buf, cx)?);
+                    this.pos = 0;
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for UringReadHalf {
+    fn drop(&mut self) {
+        // A recv waits for data forever, and holds the socket open meanwhile
+        if let Some(op) = &self.op {
+            self.driver.cancel(op);
+        }
+    }
+}
+
+pub struct UringWriteHalf {
+    driver: &'static Driver,
+    stream: Arc<TcpStream>,
+    op: Option<Arc<Op>>,
+    /// Being written by the in flight operation
+    buf: Vec<u8>,
+    written: usize,
+}
+
+impl UringWriteHalf {
+    fn submit_send(&mut self, buf: Vec<u8>, cx: &Context<'_>) -> io::Result<()> {
+        let remaining = &buf[self.written..];
+        let entry = opcode::Send::new(types::Fd(self.stream.as_raw_fd()), remaining.as_ptr(), remaining.len() as u32)
+            // Retry the short sends in the kernel, the remaining bytes are submitted again by the next poll otherwise
+            .flags((MsgFlags::MSG_NOSIGNAL | MsgFlags::MSG_WAITALL).bits())
+            .build();
+        self.op = Some(self.driver.submit(entry, buf, cx)?);
+        Ok(())
+    }
+
+    /// Wait for the bytes accepted by the previous writes to be sent
+    fn poll_sent(&mut self, cx: &Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(op) = &self.op {
+            let (result, buf) = ready!(poll_op(op, cx));
+            self.op = None;
+            self.written += op_result(result)?;
+            if self.written < buf.len() {
+                self.submit_send(buf, cx)?;
+            } else {
+                self.buf = buf;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UringWriteHalf {
+    /// Like the files of tokio, the bytes are accepted right away and sent in the background, an error is returned by
+    /// the next write or flush. A send in flight is not cancelled when the writer is dropped, for the last bytes to be sent
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_sent(cx))?;
+        let len = data.len().min(OP_BUFFER_LEN);
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        buf.extend_from_slice(&data[..len]);
+        self.written = 0;
+        self.submit_send(buf, cx)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_sent(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_sent(cx))?;
+        Poll::Ready(self.stream.shutdown(Shutdown::Write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_copy_through_uring() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+        let (mut peer, _) = server.unwrap();
+        // io_uring may be forbidden where the tests run, i.e: in a container
+        let Ok((mut rx, mut tx)) = into_split(client.unwrap()).unwrap() else {
+            return;
+        };
+
+        let data: Vec<u8> = (0..OP_BUFFER_LEN * 3).map(|i| i as u8).collect();
+        tx.write_all(&data).await.unwrap();
+        tx.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        peer.write_all(b"hello").await.unwrap();
+        drop(peer);
+        let mut received = Vec::new();
+        rx.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_drop_with_read_in_flight() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+        let (mut peer, _) = server.unwrap();
+        let Ok((mut rx, tx)) = into_split(client.unwrap()).unwrap() else {
+            return;
+        };
+
+        let mut buf = [0u8; 16];
+        let read = tokio::time::timeout(std::time::Duration::from_millis(50), rx.read(&mut buf)).await;
+        assert!(read.is_err());
+        drop((rx, tx));
+
+        // The recv in flight is cancelled, and the connection closed
+        let mut received = Vec::new();
+        let ret = tokio::time::timeout(std::time::Duration::from_secs(5), peer.read_to_end(&mut received)).await;
+        assert!(matches!(ret, Ok(Ok(0))));
+    }
+}
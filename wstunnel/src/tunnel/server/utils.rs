@@ -1,33 +1,62 @@
 use crate::LocalProtocol;
+use crate::health::HEALTH;
 use crate::restrictions::types::{
     AllowConfig, AllowReverseTunnelConfig, AllowTunnelConfig, MatchConfig, RestrictionConfig, RestrictionsRules,
-    ReverseTunnelConfigProtocol, TunnelConfigProtocol,
+    ReverseTunnelBind, ReverseTunnelConfigProtocol, TunnelConfigProtocol,
 };
-use crate::tunnel::RemoteAddr;
-use crate::tunnel::transport::{JWT_HEADER_PREFIX, JwtTunnelConfig, jwt_token_to_tunnel, tunnel_to_jwt_token};
+use crate::tunnel::protocol::integrity_record::INTEGRITY_HEADER;
+use crate::tunnel::protocol::{Capabilities, encode_protocol_header, negotiate_version};
+use crate::tunnel::server::probe;
+use crate::tunnel::server::reject::Rejected;
+use crate::tunnel::transport::{
+    EARLY_DATA_HEADER, JWT_HEADER_PREFIX, JwtTunnelConfig, PSK_HEADER, PreSharedKey, STICKY_SESSION_HEADER,
+    StickySession, early_data, jwt_token_to_tunnel, tunnel_to_jwt_token,
+};
+use crate::tunnel::{RemoteAddr, integrity, protocol};
 use anyhow::Context;
 use bytes::Bytes;
 use derive_more::{Display, Error};
 use http_body_util::Either;
 use http_body_util::combinators::BoxBody;
-use hyper::body::{Body, Incoming};
-use hyper::header::{AUTHORIZATION, COOKIE, HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use hyper::body::Body;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE, HeaderValue, SEC_WEBSOCKET_PROTOCOL};
 use hyper::{Request, Response, StatusCode, http};
 use jsonwebtoken::TokenData;
-use std::net::IpAddr;
-use tracing::{error, info};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tracing::{error, info, warn};
 use url::Host;
 use uuid::Uuid;
 
 pub type HttpResponse = Response<Either<String, BoxBody<Bytes, anyhow::Error>>>;
 
+/// Answer the liveness and readiness probes, that are plain http requests on the server bind
+pub(super) fn health_probe<B>(req: &Request<B>) -> Option<HttpResponse> {
+    let (status, body) = HEALTH.probe(req.uri().path())?;
+    Some(
+        http::Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Either::Left(body))
+            .unwrap(),
+    )
+}
+
 pub(super) fn bad_request() -> HttpResponse {
     http::Response::builder()
         .status(StatusCode::BAD_REQUEST)
+        .extension(Rejected)
         .body(Either::Left("Invalid request".to_string()))
         .unwrap()
 }
 
+pub(super) fn too_many_requests() -> HttpResponse {
+    http::Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .extension(Rejected)
+        .body(Either::Left("Too many tunnels".to_string()))
+        .unwrap()
+}
+
 /// Checks if the requested (remote) port has been mapped in the configuration to another port.
 /// If it is not mapped the original port number is returned.
 #[inline]
@@ -51,13 +80,74 @@ pub(super) fn find_mapped_port(req_port: u16, restriction: &RestrictionConfig) -
     remote_port
 }
 
+/// Address a reverse tunnel listens on, the one requested by the client unless the configuration binds its own, of
+/// the same address family
+pub(super) fn find_bind_host(req_host: Host, restriction: &RestrictionConfig) -> Host {
+    let bind = restriction
+        .allow
+        .iter()
+        .find_map(|allow| match allow {
+            AllowConfig::ReverseTunnel(allow) => Some(allow.bind),
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    let host = match (bind, &req_host) {
+        (ReverseTunnelBind::ClientSpecified, host) => host.clone(),
+        (ReverseTunnelBind::Loopback, Host::Ipv6(_)) => Host::Ipv6(Ipv6Addr::LOCALHOST),
+        (ReverseTunnelBind::Loopback, _) => Host::Ipv4(Ipv4Addr::LOCALHOST),
+        (ReverseTunnelBind::Any, Host::Ipv6(_)) => Host::Ipv6(Ipv6Addr::UNSPECIFIED),
+        (ReverseTunnelBind::Any, _) => Host::Ipv4(Ipv4Addr::UNSPECIFIED),
+    };
+
+    if host != req_host {
+        info!("Client requested bind address {} was replaced by {}", req_host, host);
+    }
+
+    host
+}
+
+/// Identity owning the ports of the reverse tunnels, if the configuration reserves them for the client binding them
+pub(super) fn find_port_owner<'a>(identity: &'a str, restriction: &RestrictionConfig) -> Option<&'a str> {
+    let owned = restriction.allow.iter().find_map(|allow| match allow {
+        AllowConfig::ReverseTunnel(allow) => Some(allow.owned),
+        _ => None,
+    });
+
+    owned.unwrap_or(false).then_some(identity)
+}
+
+/// Checks if the requested (remote) destination is an alias defined in the configuration, and rewrites it to the real destination.
+/// Only the allow rules that accepted the tunnel are considered. If no alias matches, the remote is returned unchanged.
+pub(super) fn resolve_destination_alias(mut remote: RemoteAddr, restriction: &RestrictionConfig) -> RemoteAddr {
+    let alias = restriction.allow.iter().find_map(|allow| match allow {
+        AllowConfig::Tunnel(allow) if allow.is_allowed(&remote) => allow
+            .alias
+            .iter()
+            .find(|alias| alias.host == remote.host && alias.port.is_none_or(|port| port == remote.port)),
+        _ => None,
+    });
+
+    if let Some(alias) = alias {
+        let port = alias.target_port.unwrap_or(remote.port);
+        info!(
+            "Client requested destination {}:{} was aliased to {}:{}",
+            remote.host, remote.port, alias.target_host, port
+        );
+        remote.host = alias.target_host.clone();
+        remote.port = port;
+    }
+
+    remote
+}
+
 #[inline]
-pub(super) fn extract_authorization(req: &Request<Incoming>) -> Option<&str> {
+pub(super) fn extract_authorization<B>(req: &Request<B>) -> Option<&str> {
     req.headers().get(AUTHORIZATION)?.to_str().ok()
 }
 
 #[inline]
-pub(super) fn extract_x_forwarded_for(req: &Request<Incoming>) -> Option<(IpAddr, &str)> {
+pub(super) fn extract_x_forwarded_for<B>(req: &Request<B>) -> Option<(IpAddr, &str)> {
     let x_forward_for = req.headers().get("X-Forwarded-For")?;
 
     // X-Forwarded-For: <client>, <proxy1>, <proxy2>
@@ -81,6 +171,23 @@ pub(super) fn extract_path_prefix(path: &str) -> Result<&str, PathPrefixErr> {
     }
 }
 
+/// Path prefix of the request, none when it does not match the one the client is restricted to (mTLS, etc.)
+pub(super) fn check_path_prefix<'a, B>(restrict_path_prefix: Option<&str>, req: &'a Request<B>) -> Option<&'a str> {
+    let path_prefix = extract_path_prefix(req.uri().path())
+        .map_err(|err| warn!("Rejecting connection with {err}: {}", req.uri()))
+        .ok()?;
+
+    if let Some(restrict_path) = restrict_path_prefix
+        && path_prefix != restrict_path
+    {
+        warn!(
+            "Client requested upgrade path '{path_prefix}' does not match upgrade path restriction '{restrict_path}' (mTLS, etc.)"
+        );
+        return None;
+    }
+    Some(path_prefix)
+}
+
 #[derive(Debug, Display, Error)]
 #[cfg_attr(test, derive(PartialEq, Eq))]
 pub(super) enum PathPrefixErr {
@@ -91,16 +198,74 @@ pub(super) enum PathPrefixErr {
 }
 
 #[inline]
-pub(super) fn extract_tunnel_info(req: &Request<Incoming>) -> anyhow::Result<TokenData<JwtTunnelConfig>> {
-    let jwt = req
-        .headers()
+/// Raw jwt describing the tunnel, sent in the websocket protocol header or in the cookie for http2
+pub(super) fn extract_tunnel_token<B>(req: &Request<B>) -> &str {
+    req.headers()
         .get(SEC_WEBSOCKET_PROTOCOL)
         .and_then(|header| header.to_str().ok())
         .and_then(|header| header.split_once(JWT_HEADER_PREFIX))
         .map(|(_prefix, jwt)| jwt)
         .or_else(|| req.headers().get(COOKIE).and_then(|header| header.to_str().ok()))
-        .unwrap_or_default();
+        .unwrap_or_default()
+}
+
+/// Proof of the server knowing the pre-shared key, answering the one of the accepted upgrade request
+pub(super) fn psk_proof<B>(psk: Option<&PreSharedKey>, req: &Request<B>) -> Option<HeaderValue> {
+    let client_proof = req.headers().get(PSK_HEADER)?.to_str().ok()?;
+    let proof = psk?.server_proof(client_proof).ok()?;
+    HeaderValue::from_str(&proof).ok()
+}
+
+/// Token sticking the client to this server, when it does not already have a fresh one
+pub(super) fn sticky_session<B>(sticky: Option<&StickySession>, req: &Request<B>) -> Option<HeaderValue> {
+    sticky?.renew(req.headers().get(STICKY_SESSION_HEADER))
+}
+
+/// Acknowledge the early data of the accepted upgrade request, already written to the destination, with its length
+pub(super) fn early_data_ack<B>(req: &Request<B>) -> Option<HeaderValue> {
+    let early_data = early_data::decode(req.headers().get(EARLY_DATA_HEADER)?).ok()?;
+    Some(HeaderValue::from(early_data.len()))
+}
 
+/// Version serving the tunnel of the accepted upgrade request, along with the capabilities of the server
+pub(super) fn protocol_header<B>(req: &Request<B>) -> Option<HeaderValue> {
+    let jwt = extract_tunnel_info(req).ok()?;
+    let version = negotiate_version(jwt.claims.v.as_deref()).ok()?;
+    Some(encode_protocol_header(version))
+}
+
+/// How the tunnel of the accepted upgrade request tells the end of its streams, half-close and error codes. Neither
+/// for the probed tunnels, whose destination is a pipe that only ends with the tunnel
+pub(super) fn close_capabilities<B>(req: &Request<B>) -> Capabilities {
+    let Ok(jwt) = extract_tunnel_info(req) else {
+        return Capabilities::NONE;
+    };
+    if probe::probe_interval(req, &jwt.claims.p).is_some() {
+        return Capabilities::NONE;
+    }
+    let client_capabilities = jwt.claims.c.as_deref().map_or(Capabilities::NONE, Capabilities::parse);
+    protocol::close_capabilities(client_capabilities, &jwt.claims.p)
+}
+
+/// Interval the client asked the integrity of its tunnel to be checked at, only honored for the tunnels carrying the
+/// stream of their destination as is
+pub(super) fn integrity_interval<B>(req: &Request<B>, protocol: &LocalProtocol) -> Option<u64> {
+    if !protocol::carries_stream(protocol) {
+        return None;
+    }
+    let interval: u64 = req.headers().get(INTEGRITY_HEADER)?.to_str().ok()?.parse().ok()?;
+    Some(interval.max(integrity::MIN_INTERVAL))
+}
+
+/// Interval the server checks the tunnel of the accepted upgrade request at, to answer it in the [`INTEGRITY_HEADER`]
+pub(super) fn integrity_header<B>(req: &Request<B>) -> Option<HeaderValue> {
+    let jwt = extract_tunnel_info(req).ok()?;
+    let interval = integrity_interval(req, &jwt.claims.p)?;
+    Some(HeaderValue::from(interval))
+}
+
+pub(super) fn extract_tunnel_info<B>(req: &Request<B>) -> anyhow::Result<TokenData<JwtTunnelConfig>> {
+    let jwt = extract_tunnel_token(req);
     jwt_token_to_tunnel(jwt).with_context(|| {
         let msg = format!(
             "error while decoding jwt for tunnel info header {:?}",
@@ -130,12 +295,18 @@ impl AllowReverseTunnelConfig {
         }
 
         // For ReverseUnix tunnels there is no port or cidr to check
-        if let LocalProtocol::ReverseUnix { path } = &remote.protocol {
+        if let LocalProtocol::ReverseUnix { path, .. } = &remote.protocol {
             return self
                 .unix_path
                 .is_match(path.to_str().unwrap_or("####INVALID_UNIX_PATH####"));
         }
 
+        // For ReverseHttpIngress tunnels the server does not listen, only the virtual host is checked
+        if let LocalProtocol::ReverseHttpIngress { vhost, .. } = &remote.protocol {
+            return (self.protocol.is_empty() || self.protocol.contains(&ReverseTunnelConfigProtocol::HttpIngress))
+                && self.vhost.is_match(vhost);
+        }
+
         if !self.port.is_empty() && !self.port.iter().any(|range| range.contains(&remote.port)) {
             return false;
         }
@@ -185,6 +356,7 @@ impl AllowConfig {
         match self {
             AllowConfig::ReverseTunnel(config) => config.is_allowed(remote),
             AllowConfig::Tunnel(config) => config.is_allowed(remote),
+            AllowConfig::Group(_) => false,
         }
     }
 }
@@ -212,7 +384,8 @@ pub(super) fn validate_tunnel<'a>(
 }
 
 pub(super) fn inject_cookie(response: &mut http::Response<impl Body>, remote_addr: &RemoteAddr) -> Result<(), ()> {
-    let Ok(header_val) = HeaderValue::from_str(&tunnel_to_jwt_token(Uuid::from_u128(0), remote_addr)) else {
+    let Ok(header_val) = HeaderValue::from_str(&tunnel_to_jwt_token(Uuid::from_u128(0), remote_addr, None, None))
+    else {
         error!("Bad header value for reverse socks5: {} {}", remote_addr.host, remote_addr.port);
         return Err(());
     };
@@ -225,10 +398,9 @@ pub(super) fn inject_cookie(response: &mut http::Response<impl Body>, remote_add
 mod tests {
     use super::*;
     use crate::restrictions::types::{AllowReverseTunnelConfig, AllowTunnelConfig, default_cidr, default_host};
-    use crate::tunnel::LocalProtocol;
+    use crate::tunnel::{AccessList, LocalProtocol, UdpFlowEviction};
     use ipnet::{IpNet, Ipv4Net};
     use regex::Regex;
-    use std::net::Ipv6Addr;
     use std::path::PathBuf;
 
     #[test]
@@ -238,17 +410,20 @@ mod tests {
                 // tunnel
                 RestrictionConfig {
                     name: "restrict1".into(),
+                    description: None,
                     r#match: vec![MatchConfig::Any],
                     allow: vec![AllowConfig::Tunnel(AllowTunnelConfig {
                         protocol: vec![TunnelConfigProtocol::Tcp],
                         port: vec![80..=80],
                         cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 24).unwrap())],
                         host: Regex::new("example.com").unwrap(),
+                        alias: vec![],
                     })],
                 },
                 // reverse tunnel
                 RestrictionConfig {
                     name: "restrict2".into(),
+                    description: None,
                     r#match: vec![MatchConfig::Any],
                     allow: vec![AllowConfig::ReverseTunnel(AllowReverseTunnelConfig {
                         protocol: vec![ReverseTunnelConfigProtocol::Tcp],
@@ -256,13 +431,23 @@ mod tests {
                         cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 24).unwrap())],
                         port_mapping: Default::default(),
                         unix_path: default_host(),
+                        vhost: default_host(),
+                        bind: Default::default(),
+                        owned: false,
                     })],
                 },
             ],
         };
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
@@ -274,7 +459,13 @@ mod tests {
         );
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::ReverseTcp,
+            protocol: LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
@@ -286,21 +477,42 @@ mod tests {
         );
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 81,
         };
         assert!(validate_tunnel(&remote, "/doesnt/matter", None, &restrictions).is_none());
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 1, 1].into()),
             port: 80,
         };
         assert!(validate_tunnel(&remote, "/doesnt/matter", None, &restrictions).is_none());
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Domain("example.com".into()),
             port: 80,
         };
@@ -312,14 +524,28 @@ mod tests {
         );
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Domain("not.com".into()),
             port: 80,
         };
         assert!(validate_tunnel(&remote, "/doesnt/matter", None, &restrictions).is_none());
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv6(Ipv6Addr::LOCALHOST),
             port: 80,
         };
@@ -331,6 +557,7 @@ mod tests {
         let restrictions = RestrictionsRules {
             restrictions: vec![RestrictionConfig {
                 name: "restrict1".into(),
+                description: None,
                 r#match: vec![MatchConfig::Authorization(
                     Regex::new("^[Bb]earer +the-bearer-token$").unwrap(),
                 )],
@@ -339,12 +566,20 @@ mod tests {
                     port: vec![],
                     cidr: default_cidr(),
                     host: default_host(),
+                    alias: vec![],
                 })],
             }],
         };
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
@@ -358,6 +593,85 @@ mod tests {
         assert!(validate_tunnel(&remote, "/doesnt/matter", None, &restrictions).is_none());
     }
 
+    #[test]
+    fn test_find_bind_host() {
+        let restriction = |bind| RestrictionConfig {
+            name: "bind".into(),
+            description: None,
+            r#match: vec![MatchConfig::Any],
+            allow: vec![AllowConfig::ReverseTunnel(AllowReverseTunnelConfig {
+                protocol: vec![],
+                port: vec![],
+                cidr: default_cidr(),
+                port_mapping: Default::default(),
+                unix_path: default_host(),
+                vhost: default_host(),
+                bind,
+                owned: false,
+            })],
+        };
+        let any_v4 = Host::Ipv4(Ipv4Addr::UNSPECIFIED);
+        let any_v6 = Host::Ipv6(Ipv6Addr::UNSPECIFIED);
+        let loopback_v4 = Host::Ipv4(Ipv4Addr::LOCALHOST);
+        let loopback_v6 = Host::Ipv6(Ipv6Addr::LOCALHOST);
+
+        let client_specified = restriction(ReverseTunnelBind::ClientSpecified);
+        assert_eq!(find_bind_host(any_v4.clone(), &client_specified), any_v4);
+        assert_eq!(find_bind_host(loopback_v6.clone(), &client_specified), loopback_v6);
+
+        let loopback = restriction(ReverseTunnelBind::Loopback);
+        assert_eq!(find_bind_host(any_v4.clone(), &loopback), loopback_v4);
+        assert_eq!(find_bind_host(any_v6.clone(), &loopback), loopback_v6);
+
+        let any = restriction(ReverseTunnelBind::Any);
+        assert_eq!(find_bind_host(loopback_v4.clone(), &any), any_v4);
+        assert_eq!(find_bind_host(loopback_v6.clone(), &any), any_v6);
+    }
+
+    #[test]
+    fn test_find_port_owner() {
+        let restriction = |owned| RestrictionConfig {
+            name: "owned".into(),
+            description: None,
+            r#match: vec![MatchConfig::Any],
+            allow: vec![AllowConfig::ReverseTunnel(AllowReverseTunnelConfig {
+                protocol: vec![],
+                port: vec![8000..=8100],
+                cidr: default_cidr(),
+                port_mapping: Default::default(),
+                unix_path: default_host(),
+                vhost: default_host(),
+                bind: Default::default(),
+                owned,
+            })],
+        };
+
+        assert_eq!(find_port_owner("team-a", &restriction(true)), Some("team-a"));
+        assert_eq!(find_port_owner("team-a", &restriction(false)), None);
+    }
+
+    #[test]
+    fn test_integrity_interval() {
+        let request = |interval: &str| Request::builder().header(INTEGRITY_HEADER, interval).body(()).unwrap();
+        let tcp = LocalProtocol::Tcp {
+            proxy_protocol: false,
+            resume: None,
+            idle_timeout: None,
+            mirror: None,
+            balancing: None,
+            keepalive: None,
+        };
+        assert_eq!(integrity_interval(&request("65536"), &tcp), Some(65536));
+        assert_eq!(integrity_interval(&request("0"), &tcp), Some(integrity::MIN_INTERVAL));
+        assert_eq!(integrity_interval(&request("1m"), &tcp), None);
+        assert_eq!(integrity_interval(&Request::new(()), &tcp), None);
+        assert_eq!(integrity_interval(&request("65536"), &LocalProtocol::Mux), None);
+        assert_eq!(
+            integrity_interval(&request("65536"), &LocalProtocol::Udp { timeout: None }),
+            None
+        );
+    }
+
     #[test]
     fn test_reverse_tunnel_is_allowed() {
         let config = AllowReverseTunnelConfig {
@@ -366,10 +680,19 @@ mod tests {
             cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 8).unwrap())],
             port_mapping: Default::default(),
             unix_path: default_host(),
+            vhost: default_host(),
+            bind: Default::default(),
+            owned: false,
         };
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::ReverseTcp,
+            protocol: LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
@@ -378,7 +701,13 @@ mod tests {
 
         // another ip on the same subnet
         let remote = RemoteAddr {
-            protocol: LocalProtocol::ReverseTcp,
+            protocol: LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 1, 1].into()),
             port: 80,
         };
@@ -394,11 +723,20 @@ mod tests {
             cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 24).unwrap())],
             port_mapping: Default::default(),
             unix_path: default_host(),
+            vhost: default_host(),
+            bind: Default::default(),
+            owned: false,
         };
 
         // wrong IP
         let remote = RemoteAddr {
-            protocol: LocalProtocol::ReverseTcp,
+            protocol: LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 1, 1].into()),
             port: 80,
         };
@@ -407,7 +745,13 @@ mod tests {
 
         // ipv6
         let remote = RemoteAddr {
-            protocol: LocalProtocol::ReverseTcp,
+            protocol: LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            },
             host: Host::Ipv6(Ipv6Addr::LOCALHOST),
             port: 80,
         };
@@ -416,7 +760,13 @@ mod tests {
 
         // wrong port
         let remote = RemoteAddr {
-            protocol: LocalProtocol::ReverseTcp,
+            protocol: LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 81,
         };
@@ -425,7 +775,12 @@ mod tests {
 
         // wrong protocol - remote
         let remote = RemoteAddr {
-            protocol: LocalProtocol::ReverseUdp { timeout: None },
+            protocol: LocalProtocol::ReverseUdp {
+                timeout: None,
+                max_flows: None,
+                flow_eviction: UdpFlowEviction::DropNew,
+                v6only: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
@@ -434,7 +789,14 @@ mod tests {
 
         // wrong protocol - local
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
@@ -443,7 +805,13 @@ mod tests {
 
         // host is domain
         let remote = RemoteAddr {
-            protocol: LocalProtocol::ReverseTcp,
+            protocol: LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            },
             host: Host::Domain("example.com".into()),
             port: 80,
         };
@@ -459,11 +827,20 @@ mod tests {
             cidr: vec![],
             port_mapping: Default::default(),
             unix_path: Regex::new("^/tmp/tutu$").unwrap(),
+            vhost: default_host(),
+            bind: Default::default(),
+            owned: false,
         };
 
         // wrong protocol
         let remote = RemoteAddr {
-            protocol: LocalProtocol::ReverseTcp,
+            protocol: LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 1, 1].into()),
             port: 80,
         };
@@ -473,6 +850,9 @@ mod tests {
         let remote = RemoteAddr {
             protocol: LocalProtocol::ReverseUnix {
                 path: PathBuf::from("/tmp/toto"),
+                allowed_uids: vec![],
+                permissions: Default::default(),
+                lifecycle: Default::default(),
             },
             host: Host::Domain("test.com".to_string()),
             port: 12,
@@ -483,6 +863,9 @@ mod tests {
         let remote = RemoteAddr {
             protocol: LocalProtocol::ReverseUnix {
                 path: PathBuf::from("/tmp/tutu"),
+                allowed_uids: vec![],
+                permissions: Default::default(),
+                lifecycle: Default::default(),
             },
             host: Host::Domain("test.com".to_string()),
             port: 12,
@@ -490,6 +873,39 @@ mod tests {
         assert!(config.is_allowed(&remote));
     }
 
+    #[test]
+    fn test_reverse_http_ingress_is_allowed() {
+        let config = AllowReverseTunnelConfig {
+            protocol: vec![ReverseTunnelConfigProtocol::HttpIngress],
+            port: vec![],
+            cidr: vec![],
+            port_mapping: Default::default(),
+            unix_path: default_host(),
+            vhost: Regex::new(r"^[a-z]+\.apps\.example\.com$").unwrap(),
+            bind: Default::default(),
+            owned: false,
+        };
+
+        let ingress = |vhost: &str| RemoteAddr {
+            protocol: LocalProtocol::ReverseHttpIngress {
+                vhost: vhost.to_string(),
+                session: None,
+                auth: None,
+            },
+            host: Host::Domain(vhost.to_string()),
+            port: 0,
+        };
+        assert!(config.is_allowed(&ingress("myapp.apps.example.com")));
+        assert!(!config.is_allowed(&ingress("example.com")));
+
+        // wrong protocol
+        let config = AllowReverseTunnelConfig {
+            protocol: vec![ReverseTunnelConfigProtocol::Tcp],
+            ..config
+        };
+        assert!(!config.is_allowed(&ingress("myapp.apps.example.com")));
+    }
+
     #[test]
     fn test_tunnel_is_allowed() {
         let config = AllowTunnelConfig {
@@ -497,10 +913,18 @@ mod tests {
             port: vec![80..=80],
             cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 8).unwrap())],
             host: Regex::new(".*").unwrap(),
+            alias: vec![],
         };
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
@@ -509,7 +933,14 @@ mod tests {
 
         // another ip on the same subnet
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 1, 1].into()),
             port: 80,
         };
@@ -518,7 +949,14 @@ mod tests {
 
         // host is domain
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Domain("example.com".into()),
             port: 80,
         };
@@ -533,11 +971,19 @@ mod tests {
             port: vec![80..=80],
             cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 24).unwrap())],
             host: Regex::new("example.com").unwrap(),
+            alias: vec![],
         };
 
         // wrong IP
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 1, 1].into()),
             port: 80,
         };
@@ -546,7 +992,14 @@ mod tests {
 
         // ipv6
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv6(Ipv6Addr::LOCALHOST),
             port: 80,
         };
@@ -555,7 +1008,14 @@ mod tests {
 
         // wrong port
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 81,
         };
@@ -564,7 +1024,13 @@ mod tests {
 
         // wrong protocol - remote
         let remote = RemoteAddr {
-            protocol: LocalProtocol::ReverseTcp,
+            protocol: LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
@@ -582,7 +1048,14 @@ mod tests {
 
         // wrong host
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
             host: Host::Domain("not.com".into()),
             port: 80,
         };
@@ -616,4 +1089,49 @@ mod tests {
         assert_eq!(extract_path_prefix("prefix/a/events"), Err(PathPrefixErr::BadPathPrefix));
         assert_eq!(extract_path_prefix("prefix/a/b/events"), Err(PathPrefixErr::BadPathPrefix));
     }
+
+    #[test]
+    fn test_resolve_destination_alias() {
+        let restrictions: RestrictionsRules = serde_yaml::from_str(
+            r#"
+restrictions:
+  - name: "aliases"
+    match:
+      - !Any
+    allow:
+      - !Tunnel
+        host: ^db$
+        alias:
+          - "db:5432=10.0.3.7:5433"
+          - "db=10.0.3.8"
+"#,
+        )
+        .unwrap();
+        let restriction = &restrictions.restrictions[0];
+        let remote = |host: &str, port: u16| RemoteAddr {
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                resume: None,
+                idle_timeout: None,
+                mirror: None,
+                balancing: None,
+                keepalive: None,
+            },
+            host: Host::parse(host).unwrap(),
+            port,
+        };
+
+        let aliased = resolve_destination_alias(remote("db", 5432), restriction);
+        assert_eq!(aliased.host.to_string(), "10.0.3.7");
+        assert_eq!(aliased.port, 5433);
+
+        let aliased = resolve_destination_alias(remote("db", 6379), restriction);
+        assert_eq!(aliased.host.to_string(), "10.0.3.8");
+        assert_eq!(aliased.port, 6379);
+
+        // not allowed by the rule, so the alias does not apply
+        let aliased = resolve_destination_alias(remote("cache", 6379), restriction);
+        assert_eq!(aliased.host.to_string(), "cache");
+        assert_eq!(aliased.port, 6379);
+    }
 }
@@ -288,6 +288,7 @@ impl TlsReloader {
                             tls.tls_verify_certificate,
                             this.client_config.remote_addr.scheme().alpn_protocols(),
                             !tls.tls_sni_disabled,
+                            tls.tls_fingerprint,
                             None,
                             Some(tls_certs),
                             Some(tls_key),
@@ -332,6 +333,7 @@ impl TlsReloader {
                             tls.tls_verify_certificate,
                             this.client_config.remote_addr.scheme().alpn_protocols(),
                             !tls.tls_sni_disabled,
+                            tls.tls_fingerprint,
                             None,
                             Some(tls_certs),
                             Some(tls_key),
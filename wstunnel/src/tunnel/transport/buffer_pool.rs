@@ -0,0 +1,160 @@
+//! Copy buffers of the tunnels, taken from a pool shared by all of them instead of being allocated by each one, and
+//! capped by `--max-memory-buffers` so the memory of a server with many tunnels does not grow with their throughput
+use super::io::MAX_PACKET_LENGTH;
+use crate::metrics::{METRICS, Metrics};
+use bytes::BytesMut;
+use parking_lot::Mutex;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Buffers of the closed tunnels kept for the next ones, past it they are freed
+const MAX_FREE_BUFFERS: usize = 1024;
+
+/// Buffers grown past this size are freed once their tunnel is closed, not kept for the next tunnels
+const MAX_REUSED_CAPACITY: usize = MAX_PACKET_LENGTH * 2;
+
+pub struct BufferPool {
+    free: Mutex<Vec<BytesMut>>,
+    /// Memory the buffers of the tunnels and of the pool can take, u64::MAX if unlimited
+    max_bytes: AtomicU64,
+}
+
+pub static BUFFER_POOL: BufferPool = BufferPool::new();
+
+impl BufferPool {
+    const fn new() -> Self {
+        Self {
+            free: Mutex::new(Vec::new()),
+            max_bytes: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    pub fn set_max_bytes(&self, max_bytes: u64) {
+        self.max_bytes.store(max_bytes, Ordering::Relaxed);
+    }
+
+    /// A buffer of at least `capacity` bytes. A tunnel cannot work without one, so it is given even past the max memory
+    pub fn take(&'static self, capacity: usize) -> PooledBuffer {
+        let reused = {
+            let mut free = self.free.lock();
+            free.iter()
+                .rposition(|buf| buf.capacity() >= capacity)
+                .map(|ix| free.swap_remove(ix))
+        };
+        let buf = match reused {
+            Some(buf) => {
+                Metrics::inc(&METRICS.tunnel_buffers_reused);
+                buf
+            }
+            None => {
+                Metrics::inc(&METRICS.tunnel_buffers_allocated);
+                let buf = BytesMut::with_capacity(capacity);
+                Metrics::add(&METRICS.tunnel_buffer_bytes, buf.capacity() as u64);
+                buf
+            }
+        };
+
+        PooledBuffer { pool: self, buf }
+    }
+
+    /// Reserve memory for `additional` more bytes of buffers, if it stays below the max memory
+    fn reserve(&self, additional: usize) -> bool {
+        let max_bytes = self.max_bytes.load(Ordering::Relaxed);
+        let reserved = METRICS
+            .tunnel_buffer_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(additional as u64).filter(|used| *used <= max_bytes)
+            })
+            .is_ok();
+        if !reserved {
+            Metrics::inc(&METRICS.tunnel_buffer_grows_denied);
+        }
+        reserved
+    }
+
+    fn give_back(&self, mut buf: BytesMut) {
+        let capacity = buf.capacity();
+        let over_max = METRICS.tunnel_buffer_bytes.load(Ordering::Relaxed) > self.max_bytes.load(Ordering::Relaxed);
+        if capacity <= MAX_REUSED_CAPACITY && !over_max {
+            let mut free = self.free.lock();
+            if free.len() < MAX_FREE_BUFFERS {
+                buf.clear();
+                free.push(buf);
+                return;
+            }
+        }
+        Metrics::sub(&METRICS.tunnel_buffer_bytes, capacity as u64);
+    }
+}
+
+/// A copy buffer of a tunnel, given back to the pool once dropped
+pub struct PooledBuffer {
+    pool: &'static BufferPool,
+    buf: BytesMut,
+}
+
+impl PooledBuffer {
+    /// Grow the buffer for it to hold `additional` more bytes, unless the buffers already take the max memory
+    pub fn grow(&mut self, additional: usize) -> bool {
+        let capacity = self.buf.capacity();
+        if additional <= capacity - self.buf.len() {
+            return true;
+        }
+        // Reserving may allocate a new buffer bigger than asked, accounted once it is known
+        let wanted = self.buf.len() + additional;
+        if !self.pool.reserve(wanted - capacity) {
+            return false;
+        }
+        self.buf.reserve(additional);
+        let extra = self.buf.capacity().saturating_sub(wanted);
+        Metrics::add(&METRICS.tunnel_buffer_bytes, extra as u64);
+        true
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_reused_and_capped() {
+        let pool: &'static BufferPool = Box::leak(Box::new(BufferPool::new()));
+        let buf = pool.take(MAX_PACKET_LENGTH);
+        assert!(buf.capacity() >= MAX_PACKET_LENGTH);
+        drop(buf);
+        assert_eq!(pool.free.lock().len(), 1);
+        let buf = pool.take(MAX_PACKET_LENGTH);
+        assert!(pool.free.lock().is_empty());
+
+        // The counters are global, other tests take buffers concurrently
+        let used = METRICS.tunnel_buffer_bytes.load(Ordering::Relaxed);
+        pool.set_max_bytes(used + 256 * 1024 * 1024);
+        let mut buf = buf;
+        assert!(buf.grow(MAX_PACKET_LENGTH * 4));
+        assert!(!buf.grow(512 * 1024 * 1024));
+
+        // Grown past the size of the buffers of the new tunnels, it is freed instead of kept
+        drop(buf);
+        assert!(pool.free.lock().is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+const FLAG_FIN: u8 = 0x01;
+const FLAG_RST: u8 = 0x02;
+
+/// session_id(u64) seq(u16) ack(u16) flags(u8)
+pub const FRAME_HEADER_LEN: usize = 13;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Random id chosen by the client, the frames of a session carry no other authentication
+    pub session_id: u64,
+    /// Sequence number of the payload, only meaningful if the frame carries data
+    pub seq: u16,
+    /// Next sequence number expected from the peer
+    pub ack: u16,
+    flags: u8,
+    pub payload: Bytes,
+}
+
+impl Frame {
+    pub fn data(session_id: u64, seq: u16, payload: Bytes) -> Self {
+        Self {
+            session_id,
+            seq,
+            ack: 0,
+            flags: 0,
+            payload,
+        }
+    }
+
+    pub fn fin(session_id: u64, seq: u16) -> Self {
+        Self {
+            session_id,
+            seq,
+            ack: 0,
+            flags: FLAG_FIN,
+            payload: Bytes::new(),
+        }
+    }
+
+    pub fn rst(session_id: u64) -> Self {
+        Self {
+            session_id,
+            seq: 0,
+            ack: 0,
+            flags: FLAG_RST,
+            payload: Bytes::new(),
+        }
+    }
+
+    pub const fn is_fin(&self) -> bool {
+        self.flags & FLAG_FIN != 0
+    }
+
+    pub const fn is_rst(&self) -> bool {
+        self.flags & FLAG_RST != 0
+    }
+
+    /// Frames without payload nor fin are only used to poll/ack, and do not consume a sequence number
+    pub fn carries_data(&self) -> bool {
+        !self.payload.is_empty() || self.is_fin()
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(FRAME_HEADER_LEN + self.payload.len());
+        buf.put_u64(self.session_id);
+        buf.put_u16(self.seq);
+        buf.put_u16(self.ack);
+        buf.put_u8(self.flags);
+        buf.put_slice(&self.payload);
+        buf.freeze()
+    }
+
+    pub fn decode(mut buf: Bytes) -> Option<Self> {
+        if buf.len() < FRAME_HEADER_LEN {
+            return None;
+        }
+
+        Some(Self {
+            session_id: buf.get_u64(),
+            seq: buf.get_u16(),
+            ack: buf.get_u16(),
+            flags: buf.get_u8(),
+            payload: buf,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut frame = Frame::data(u64::MAX - 42, 65535, Bytes::from_static(b"hello"));
+        frame.ack = 7;
+        assert_eq!(Frame::decode(frame.encode()), Some(frame));
+
+        let frame = Frame::fin(1, 2);
+        let decoded = Frame::decode(frame.encode()).unwrap();
+        assert!(decoded.is_fin() && decoded.carries_data() && !decoded.is_rst());
+
+        assert_eq!(Frame::decode(Bytes::from_static(b"short")), None);
+    }
+}
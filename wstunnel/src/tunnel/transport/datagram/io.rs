@@ -0,0 +1,121 @@
+use crate::tunnel::protocol::close_reason::CloseReason;
+use crate::tunnel::transport::io::{MAX_PACKET_LENGTH, TunnelRead, TunnelWrite};
+use bytes::{Bytes, BytesMut};
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Notify, mpsc};
+
+pub struct DatagramTunnelRead {
+    inner: mpsc::Receiver<Bytes>,
+    buf: BytesMut,
+}
+
+impl DatagramTunnelRead {
+    pub fn new(inner: mpsc::Receiver<Bytes>) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Read exactly `len` bytes from the stream, used to decode the session handshake
+    pub async fn read_exact(&mut self, len: usize) -> io::Result<Bytes> {
+        while self.buf.len() < len {
+            match self.inner.recv().await {
+                Some(data) => self.buf.extend_from_slice(&data),
+                None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "closed")),
+            }
+        }
+
+        Ok(self.buf.split_to(len).freeze())
+    }
+}
+
+impl TunnelRead for DatagramTunnelRead {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+        let data = if self.buf.is_empty() {
+            match self.inner.recv().await {
+                Some(data) => data,
+                None => return Err(io::Error::new(ErrorKind::BrokenPipe, "closed")),
+            }
+        } else {
+            self.buf.split().freeze()
+        };
+
+        match writer.write_all(&data).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+        }
+    }
+}
+
+pub struct DatagramTunnelWrite {
+    inner: Option<mpsc::Sender<Bytes>>,
+    buf: BytesMut,
+}
+
+impl DatagramTunnelWrite {
+    pub fn new(inner: mpsc::Sender<Bytes>) -> Self {
+        Self {
+            inner: Some(inner),
+            buf: BytesMut::with_capacity(MAX_PACKET_LENGTH),
+        }
+    }
+}
+
+impl TunnelWrite for DatagramTunnelWrite {
+    fn buf_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    async fn write(&mut self) -> Result<(), io::Error> {
+        let data = self.buf.split().freeze();
+        let ret = match &self.inner {
+            Some(inner) => inner
+                .send(data)
+                .await
+                .map_err(|err| io::Error::new(ErrorKind::ConnectionAborted, err)),
+            None => Err(io::Error::new(ErrorKind::BrokenPipe, "closed")),
+        };
+
+        if self.buf.capacity() < MAX_PACKET_LENGTH {
+            self.buf.reserve(MAX_PACKET_LENGTH)
+        }
+
+        ret
+    }
+
+    async fn ping(&mut self) -> Result<(), io::Error> {
+        // The session is already polled continuously
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), io::Error> {
+        // Dropping the sender makes the session send a fin to the peer
+        self.inner = None;
+        Ok(())
+    }
+
+    async fn half_close(&mut self) -> Result<(), io::Error> {
+        // The fin of the session closes both directions
+        Err(io::Error::new(ErrorKind::Unsupported, "datagram tunnels cannot be half-closed"))
+    }
+
+    async fn abort(&mut self, _reason: CloseReason) -> Result<(), io::Error> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "datagram tunnels cannot tell why they are closed",
+        ))
+    }
+
+    fn pending_operations_notify(&mut self) -> Arc<Notify> {
+        Arc::new(Notify::new())
+    }
+
+    fn handle_pending_operations(&mut self) -> impl Future<Output = Result<(), io::Error>> + Send {
+        std::future::ready(Ok(()))
+    }
+}
@@ -0,0 +1,185 @@
+//! Reliable byte stream over transports that can only exchange small request/response datagrams (i.e: dns queries, pings).
+//! The client drives everything: each frame it sends is answered by exactly one frame from the server, so the server
+//! can only push data to the client when it is polled. Delivery and ordering rely on a stop-and-wait protocol.
+
+mod frame;
+mod io;
+mod session;
+
+pub use frame::{FRAME_HEADER_LEN, Frame};
+pub use io::{DatagramTunnelRead, DatagramTunnelWrite};
+pub use session::{DatagramCarrier, ServerSession, SessionConfig, run_client_session};
+
+use crate::oidc;
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::client::WsClient;
+use crate::tunnel::transport::jwt::tunnel_to_jwt_token;
+use anyhow::{Context, anyhow};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hyper::Response;
+use hyper::header::COOKIE;
+use hyper::http::response::Parts;
+use std::io::ErrorKind;
+use tokio::sync::mpsc;
+use tracing::{Instrument, Span, error};
+use uuid::Uuid;
+
+/// First bytes sent by the client on a new session, equivalent of the http upgrade request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenRequest {
+    pub path_prefix: String,
+    pub jwt: String,
+    pub authorization: Option<String>,
+}
+
+impl OpenRequest {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        for field in [
+            self.path_prefix.as_str(),
+            self.jwt.as_str(),
+            self.authorization.as_deref().unwrap_or_default(),
+        ] {
+            buf.put_u16(field.len() as u16);
+            buf.put_slice(field.as_bytes());
+        }
+        buf.freeze()
+    }
+
+    pub async fn read(reader: &mut DatagramTunnelRead) -> std::io::Result<Self> {
+        let path_prefix = read_string(reader).await?;
+        let jwt = read_string(reader).await?;
+        let authorization = read_string(reader).await?;
+        Ok(Self {
+            path_prefix,
+            jwt,
+            authorization: Some(authorization).filter(|auth| !auth.is_empty()),
+        })
+    }
+}
+
+/// First bytes sent by the server on a new session, equivalent of the http upgrade response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenResponse {
+    pub accepted: bool,
+    pub cookie: Option<String>,
+}
+
+impl OpenResponse {
+    pub fn encode(&self) -> Bytes {
+        let cookie = self.cookie.as_deref().unwrap_or_default();
+        let mut buf = BytesMut::with_capacity(3 + cookie.len());
+        buf.put_u8(self.accepted as u8);
+        buf.put_u16(cookie.len() as u16);
+        buf.put_slice(cookie.as_bytes());
+        buf.freeze()
+    }
+
+    pub async fn read(reader: &mut DatagramTunnelRead) -> std::io::Result<Self> {
+        let accepted = reader.read_exact(1).await?.get_u8() == 1;
+        let cookie = read_string(reader).await?;
+        Ok(Self {
+            accepted,
+            cookie: Some(cookie).filter(|cookie| !cookie.is_empty()),
+        })
+    }
+}
+
+/// Start a session through the carrier and ask the server to open the tunnel
+pub async fn connect(
+    request_id: Uuid,
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    dest_addr: &RemoteAddr,
+    carrier: impl DatagramCarrier,
+) -> anyhow::Result<(DatagramTunnelRead, DatagramTunnelWrite, Parts)> {
+    let authorization = match (&client.config.oidc_token_cache, &client.config.http_upgrade_credentials) {
+        (Some(token_cache), _) => {
+            Some(oidc::bearer_from_cache(token_cache, &client.config.http_client_config()).await?)
+        }
+        (None, Some(credentials)) => Some(credentials.clone()),
+        (None, None) => None,
+    };
+    let open_request = OpenRequest {
+        path_prefix: client.config.http_upgrade_path_prefix.clone(),
+        jwt: tunnel_to_jwt_token(
+            request_id,
+            dest_addr,
+            client.label.as_deref(),
+            client.config.device_id.as_deref(),
+        ),
+        authorization: authorization.and_then(|auth| auth.to_str().ok().map(str::to_string)),
+    };
+
+    let (up_tx, up_rx) = mpsc::channel::<Bytes>(1024);
+    let (down_tx, down_rx) = mpsc::channel::<Bytes>(1024);
+    // Random, as the frames of the session are only bound to it by its id
+    let session_id = rand::random::<u64>();
+    let session = run_client_session(carrier, session_id, up_rx, down_tx, SessionConfig::default());
+    client.executor.spawn(
+        async move {
+            if let Err(err) = session.await {
+                error!("datagram session failed: {err:?}");
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    up_tx
+        .send(open_request.encode())
+        .await
+        .map_err(|_| anyhow!("datagram session closed"))?;
+    let mut reader = DatagramTunnelRead::new(down_rx);
+    let response = OpenResponse::read(&mut reader)
+        .await
+        .context("server did not answer the open request")?;
+    if !response.accepted {
+        return Err(anyhow!("server rejected the tunnel"));
+    }
+
+    let mut parts = Response::builder().body(()).map(|r| r.into_parts().0)?;
+    if let Some(cookie) = response.cookie {
+        parts.headers.insert(COOKIE, cookie.parse()?);
+    }
+
+    Ok((reader, DatagramTunnelWrite::new(up_tx), parts))
+}
+
+async fn read_string(reader: &mut DatagramTunnelRead) -> std::io::Result<String> {
+    let len = reader.read_exact(2).await?.get_u16() as usize;
+    let data = reader.read_exact(len).await?;
+    String::from_utf8(data.to_vec()).map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_open_request_roundtrip() {
+        let req = OpenRequest {
+            path_prefix: "v1".to_string(),
+            jwt: "a.b.c".to_string(),
+            authorization: Some("Bearer token".to_string()),
+        };
+        let (tx, rx) = mpsc::channel(10);
+        // split the message to check it is reassembled
+        let data = req.encode();
+        tx.send(data.slice(..3)).await.unwrap();
+        tx.send(data.slice(3..)).await.unwrap();
+        tx.send(
+            OpenResponse {
+                accepted: true,
+                cookie: None,
+            }
+            .encode(),
+        )
+        .await
+        .unwrap();
+
+        let mut reader = DatagramTunnelRead::new(rx);
+        assert_eq!(OpenRequest::read(&mut reader).await.unwrap(), req);
+        let response = OpenResponse::read(&mut reader).await.unwrap();
+        assert!(response.accepted);
+        assert_eq!(response.cookie, None);
+    }
+}
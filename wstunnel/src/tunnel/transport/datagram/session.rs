@@ -0,0 +1,361 @@
+use super::frame::Frame;
+use anyhow::anyhow;
+use bytes::{Bytes, BytesMut};
+use std::cmp::min;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Moves frames between the client and the server. The server answers every frame with exactly one frame
+pub trait DatagramCarrier: Send + 'static {
+    /// Maximum payload size of a frame sent by the client
+    fn max_payload(&self) -> usize;
+
+    /// Send a frame to the server and wait for its answer
+    fn exchange(&mut self, frame: Bytes) -> impl Future<Output = io::Result<Bytes>> + Send;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SessionConfig {
+    /// Time to wait for the answer of the server before sending the frame again
+    pub retransmit_timeout: Duration,
+    /// Number of retransmissions before considering the server unreachable
+    pub max_retransmits: u32,
+    /// When there is no traffic, the client polls the server less and less often, up to this interval
+    pub max_poll_interval: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            retransmit_timeout: Duration::from_secs(2),
+            max_retransmits: 10,
+            max_poll_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Drive the client side of a session until both directions are closed.
+/// Data received from `up_rx` is sent to the server, and data received from the server is sent to `down_tx`
+pub async fn run_client_session(
+    mut carrier: impl DatagramCarrier,
+    session_id: u64,
+    mut up_rx: mpsc::Receiver<Bytes>,
+    down_tx: mpsc::Sender<Bytes>,
+    cfg: SessionConfig,
+) -> anyhow::Result<()> {
+    let mut down_tx = Some(down_tx);
+    let mut up_buf = BytesMut::new();
+    let mut up_closed = false;
+    let mut up_seq: u16 = 0;
+    let mut up_pending: Option<Frame> = None;
+    let mut up_fin_acked = false;
+    let mut down_expected: u16 = 0;
+    let mut down_fin = false;
+    let mut poll_interval = MIN_POLL_INTERVAL;
+
+    loop {
+        while !up_closed {
+            match up_rx.try_recv() {
+                Ok(data) => up_buf.extend_from_slice(&data),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => up_closed = true,
+            }
+        }
+
+        if up_pending.is_none() && !up_fin_acked {
+            if !up_buf.is_empty() {
+                let len = min(up_buf.len(), carrier.max_payload());
+                up_pending = Some(Frame::data(session_id, up_seq, up_buf.split_to(len).freeze()));
+            } else if up_closed {
+                up_pending = Some(Frame::fin(session_id, up_seq));
+            }
+        }
+
+        let mut frame = up_pending
+            .clone()
+            .unwrap_or_else(|| Frame::data(session_id, up_seq, Bytes::new()));
+        frame.ack = down_expected;
+
+        let response = round_trip(&mut carrier, frame.encode(), &cfg).await?;
+        let Some(response) = Frame::decode(response).filter(|f| f.session_id == session_id) else {
+            warn!("Received invalid frame from the server, ignoring it");
+            continue;
+        };
+        if response.is_rst() {
+            return Err(anyhow!("session has been reset by the server"));
+        }
+
+        let mut has_traffic = false;
+        if up_pending.is_some() && response.ack == up_seq.wrapping_add(1) {
+            up_fin_acked = up_pending.as_ref().is_some_and(Frame::is_fin);
+            up_pending = None;
+            up_seq = up_seq.wrapping_add(1);
+            has_traffic = true;
+        }
+
+        if response.carries_data() && response.seq == down_expected {
+            down_expected = down_expected.wrapping_add(1);
+            has_traffic = true;
+            if !response.payload.is_empty()
+                && let Some(tx) = &down_tx
+                && tx.send(response.payload.clone()).await.is_err()
+            {
+                debug!("Local side does not read anymore, discarding data from the server");
+                down_tx = None;
+            }
+            if response.is_fin() {
+                down_fin = true;
+                down_tx = None;
+            }
+        }
+
+        if up_fin_acked && down_fin {
+            // Best effort to ack the fin of the server, so it can release the session without waiting for its expiration
+            let mut ack = Frame::data(session_id, up_seq, Bytes::new());
+            ack.ack = down_expected;
+            let _ = tokio::time::timeout(cfg.retransmit_timeout, carrier.exchange(ack.encode())).await;
+            return Ok(());
+        }
+
+        if has_traffic || up_pending.is_some() || !up_buf.is_empty() {
+            poll_interval = MIN_POLL_INTERVAL;
+            continue;
+        }
+
+        // Nothing in flight, wait for local data or poll the server later
+        select! {
+            data = up_rx.recv(), if !up_closed => match data {
+                Some(data) => up_buf.extend_from_slice(&data),
+                None => up_closed = true,
+            },
+            _ = tokio::time::sleep(poll_interval) => {
+                poll_interval = min(poll_interval * 2, cfg.max_poll_interval);
+            }
+        }
+    }
+}
+
+async fn round_trip(carrier: &mut impl DatagramCarrier, frame: Bytes, cfg: &SessionConfig) -> anyhow::Result<Bytes> {
+    for retry in 0..=cfg.max_retransmits {
+        match tokio::time::timeout(cfg.retransmit_timeout, carrier.exchange(frame.clone())).await {
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(err)) => debug!("Cannot exchange frame with the server (retry {retry}): {err}"),
+            Err(_) => debug!("No answer from the server (retry {retry})"),
+        }
+    }
+
+    Err(anyhow!("server did not answer after {} retransmissions", cfg.max_retransmits))
+}
+
+/// Server side of a session. It answers each frame received from the client
+pub struct ServerSession {
+    session_id: u64,
+    up_tx: Option<mpsc::Sender<Bytes>>,
+    up_expected: u16,
+    up_fin: bool,
+    down_rx: mpsc::Receiver<Bytes>,
+    down_buf: BytesMut,
+    down_closed: bool,
+    down_seq: u16,
+    down_pending: Option<Frame>,
+    down_fin_acked: bool,
+    last_seen: Instant,
+}
+
+impl ServerSession {
+    /// Returns the session, with the receiver of the data sent by the client and the sender of the data to send to it
+    pub fn new(session_id: u64) -> (Self, mpsc::Receiver<Bytes>, mpsc::Sender<Bytes>) {
+        let (up_tx, up_rx) = mpsc::channel(1024);
+        let (down_tx, down_rx) = mpsc::channel(1024);
+        let session = Self {
+            session_id,
+            up_tx: Some(up_tx),
+            up_expected: 0,
+            up_fin: false,
+            down_rx,
+            down_buf: BytesMut::new(),
+            down_closed: false,
+            down_seq: 0,
+            down_pending: None,
+            down_fin_acked: false,
+            last_seen: Instant::now(),
+        };
+
+        (session, up_rx, down_tx)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.up_fin && self.down_fin_acked
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last_seen.elapsed()
+    }
+
+    /// Process a frame from the client and build the answer.
+    /// If the client has nothing to send, wait up to `long_poll` for data to send back, to reduce polling
+    pub async fn handle(&mut self, frame: Frame, max_payload: usize, long_poll: Duration) -> Frame {
+        self.last_seen = Instant::now();
+
+        let client_has_data = frame.carries_data();
+        if client_has_data {
+            if frame.seq == self.up_expected {
+                self.up_expected = self.up_expected.wrapping_add(1);
+                if !frame.payload.is_empty()
+                    && let Some(tx) = &self.up_tx
+                    && tx.send(frame.payload.clone()).await.is_err()
+                {
+                    self.up_tx = None;
+                }
+                if frame.is_fin() {
+                    self.up_fin = true;
+                    self.up_tx = None;
+                }
+            } else if frame.seq != self.up_expected.wrapping_sub(1) {
+                // Not a retransmission of the previous frame, the client is out of sync
+                warn!(
+                    "Resetting session {}, received seq {} while expecting {}",
+                    self.session_id, frame.seq, self.up_expected
+                );
+                return Frame::rst(self.session_id);
+            }
+        }
+
+        if self.down_pending.is_some() && frame.ack == self.down_seq.wrapping_add(1) {
+            self.down_fin_acked = self.down_pending.as_ref().is_some_and(Frame::is_fin);
+            self.down_pending = None;
+            self.down_seq = self.down_seq.wrapping_add(1);
+        }
+
+        if self.down_pending.is_none() && !self.down_fin_acked {
+            let wait = if client_has_data { Duration::ZERO } else { long_poll };
+            self.down_pending = self.next_down_frame(max_payload, wait).await;
+        }
+
+        let mut response = self
+            .down_pending
+            .clone()
+            .unwrap_or_else(|| Frame::data(self.session_id, self.down_seq, Bytes::new()));
+        response.ack = self.up_expected;
+        response
+    }
+
+    async fn next_down_frame(&mut self, max_payload: usize, wait: Duration) -> Option<Frame> {
+        if self.down_buf.is_empty() && !self.down_closed && !wait.is_zero() {
+            match tokio::time::timeout(wait, self.down_rx.recv()).await {
+                Ok(Some(data)) => self.down_buf.extend_from_slice(&data),
+                Ok(None) => self.down_closed = true,
+                Err(_) => {}
+            }
+        }
+
+        while self.down_buf.len() < max_payload && !self.down_closed {
+            match self.down_rx.try_recv() {
+                Ok(data) => self.down_buf.extend_from_slice(&data),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.down_closed = true,
+            }
+        }
+
+        if !self.down_buf.is_empty() {
+            let len = min(self.down_buf.len(), max_payload);
+            return Some(Frame::data(
+                self.session_id,
+                self.down_seq,
+                self.down_buf.split_to(len).freeze(),
+            ));
+        }
+
+        if self.down_closed {
+            return Some(Frame::fin(self.session_id, self.down_seq));
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Deliver frames directly to a server session, dropping every `drop_every` answers
+    struct LocalCarrier {
+        session: Arc<tokio::sync::Mutex<ServerSession>>,
+        drop_every: usize,
+        nb_exchanges: Arc<AtomicUsize>,
+    }
+
+    impl DatagramCarrier for LocalCarrier {
+        fn max_payload(&self) -> usize {
+            7
+        }
+
+        async fn exchange(&mut self, frame: Bytes) -> io::Result<Bytes> {
+            let frame = Frame::decode(frame).unwrap();
+            let response = self
+                .session
+                .lock()
+                .await
+                .handle(frame, 5, Duration::from_millis(10))
+                .await;
+            let nb = self.nb_exchanges.fetch_add(1, Ordering::Relaxed) + 1;
+            if self.drop_every > 0 && nb.is_multiple_of(self.drop_every) {
+                // simulate a lost answer
+                std::future::pending::<()>().await;
+            }
+            Ok(response.encode())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_transfer_with_losses() {
+        let (session, server_rx, server_tx) = ServerSession::new(1);
+        let session = Arc::new(tokio::sync::Mutex::new(session));
+        let carrier = LocalCarrier {
+            session: session.clone(),
+            drop_every: 3,
+            nb_exchanges: Arc::new(AtomicUsize::new(0)),
+        };
+        let cfg = SessionConfig {
+            retransmit_timeout: Duration::from_millis(50),
+            max_retransmits: 5,
+            max_poll_interval: Duration::from_millis(20),
+        };
+
+        let (client_tx, up_rx) = mpsc::channel(10);
+        let (down_tx, client_rx) = mpsc::channel(10);
+        let client = tokio::spawn(run_client_session(carrier, 1, up_rx, down_tx, cfg));
+
+        let upload: Vec<u8> = (0..100u8).collect();
+        let download: Vec<u8> = (100..=255u8).collect();
+        client_tx.send(Bytes::from(upload.clone())).await.unwrap();
+        drop(client_tx);
+        server_tx.send(Bytes::from(download.clone())).await.unwrap();
+        drop(server_tx);
+
+        // Both sides must be read concurrently, as a full channel blocks the session
+        let read_all = |mut rx: mpsc::Receiver<Bytes>| async move {
+            let mut received = vec![];
+            while let Some(data) = rx.recv().await {
+                received.extend_from_slice(&data);
+            }
+            received
+        };
+        let (server_received, client_received) = tokio::join!(read_all(server_rx), read_all(client_rx));
+        assert_eq!(server_received, upload);
+        assert_eq!(client_received, download);
+
+        client.await.unwrap().unwrap();
+        assert!(session.lock().await.is_finished());
+    }
+}
@@ -0,0 +1,251 @@
+//! Experimental transport encoding the tunnel in dns queries, for networks where only dns traffic escapes.
+//! The wstunnel server acts as the authoritative name server of a (sub)domain delegated to it.
+//! Client data is base32 encoded in the labels of TXT queries, server data is sent back in the TXT answers.
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::client::WsClient;
+use crate::tunnel::transport::datagram;
+use crate::tunnel::transport::datagram::{DatagramCarrier, DatagramTunnelRead, DatagramTunnelWrite, Frame};
+use anyhow::{Context, anyhow};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hickory_resolver::proto::op::{Edns, Message, MessageType, OpCode, Query};
+use hickory_resolver::proto::rr::{Name, RData, RecordType};
+use hyper::http::response::Parts;
+use socket2::SockRef;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use url::Host;
+use uuid::Uuid;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+/// Each query starts with a nonce, so resolvers never answer from their cache
+pub const NONCE_LEN: usize = 2;
+/// EDNS payload size that does not fragment on most networks
+pub const EDNS_MAX_PAYLOAD: u16 = 1232;
+
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+/// Decoding is case-insensitive, as resolvers may randomize the case of the names (dns 0x20)
+pub fn base32_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in data {
+        let value = BASE32_ALPHABET.iter().position(|x| *x == c.to_ascii_lowercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Maximum number of bytes that can be encoded in a query name under `domain`
+pub fn max_query_data_len(domain: &Name) -> usize {
+    let domain_len = domain.to_ascii().trim_end_matches('.').len();
+    let available = MAX_NAME_LEN.saturating_sub(domain_len + 1);
+    // every label of 63 chars needs a dot to separate it from the next one
+    let nb_chars = available - available / (MAX_LABEL_LEN + 1);
+    nb_chars * 5 / 8
+}
+
+pub fn encode_query_name(data: &[u8], domain: &Name) -> anyhow::Result<Name> {
+    let encoded = base32_encode(data);
+    let labels = encoded.as_bytes().chunks(MAX_LABEL_LEN);
+    let name = Name::from_labels(labels).context("cannot encode data in dns name")?;
+    name.append_domain(domain).context("dns name too long")
+}
+
+pub fn decode_query_name(name: &Name, domain: &Name) -> Option<Bytes> {
+    if !domain.zone_of(name) || name.num_labels() <= domain.num_labels() {
+        return None;
+    }
+
+    let nb_labels = (name.num_labels() - domain.num_labels()) as usize;
+    let encoded: Vec<u8> = name.iter().take(nb_labels).flatten().copied().collect();
+    base32_decode(&encoded).map(Bytes::from)
+}
+
+/// Payload bytes carried by a TXT record are split in strings of at most 255 bytes
+pub fn txt_data(data: &[u8]) -> Vec<&[u8]> {
+    if data.is_empty() {
+        return vec![&[]];
+    }
+    data.chunks(255).collect()
+}
+
+struct DnsCarrier {
+    socket: UdpSocket,
+    domain: Name,
+    max_payload: usize,
+    query_id: u16,
+    buf: Vec<u8>,
+}
+
+impl DnsCarrier {
+    fn new(socket: UdpSocket, domain: Name) -> Self {
+        let max_payload = max_query_data_len(&domain).saturating_sub(NONCE_LEN + super::datagram::FRAME_HEADER_LEN);
+        Self {
+            socket,
+            domain,
+            max_payload,
+            query_id: Uuid::now_v7().as_u128() as u16,
+            buf: vec![0; u16::MAX as usize],
+        }
+    }
+
+    fn encode_query(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        let mut data = BytesMut::with_capacity(NONCE_LEN + frame.len());
+        data.put_u16(self.query_id);
+        data.put_slice(frame);
+        let name =
+            encode_query_name(&data, &self.domain).map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+
+        let mut edns = Edns::new();
+        edns.set_max_payload(EDNS_MAX_PAYLOAD);
+        let mut msg = Message::new();
+        msg.set_id(self.query_id)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name, RecordType::TXT))
+            .set_edns(edns);
+        msg.to_vec().map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+
+    fn decode_response(&self, response: &[u8]) -> Option<Bytes> {
+        let msg = Message::from_vec(response).ok()?;
+        if msg.id() != self.query_id || msg.message_type() != MessageType::Response {
+            return None;
+        }
+
+        msg.answers().iter().find_map(|record| match record.data() {
+            RData::TXT(txt) => {
+                let mut data = BytesMut::new();
+                for chunk in txt.txt_data() {
+                    data.extend_from_slice(chunk);
+                }
+                Some(data.freeze())
+            }
+            _ => None,
+        })
+    }
+}
+
+impl DatagramCarrier for DnsCarrier {
+    fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    async fn exchange(&mut self, frame: Bytes) -> io::Result<Bytes> {
+        self.query_id = self.query_id.wrapping_add(1);
+        let query = self.encode_query(&frame)?;
+        self.socket.send(&query).await?;
+
+        // Skip late answers of previous queries
+        loop {
+            let len = self.socket.recv(&mut self.buf).await?;
+            if let Some(response) = self.decode_response(&self.buf[..len]) {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+pub async fn connect(
+    request_id: Uuid,
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    dest_addr: &RemoteAddr,
+) -> anyhow::Result<(DatagramTunnelRead, DatagramTunnelWrite, Parts)> {
+    let Host::Domain(domain) = client.config.remote_addr.host() else {
+        return Err(anyhow!("dns transport requires a domain name, not an ip"));
+    };
+    let mut domain = Name::from_ascii(domain).with_context(|| format!("invalid dns transport domain {domain}"))?;
+    domain.set_fqdn(true);
+    let resolver = client
+        .config
+        .dns_transport_resolver
+        .ok_or_else(|| anyhow!("no dns resolver configured for the dns transport"))?;
+
+    let bind: SocketAddr = if resolver.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    client.config.socket_so_mark.set_mark(SockRef::from(&socket))?;
+    socket
+        .connect(resolver)
+        .await
+        .with_context(|| format!("cannot reach dns resolver {resolver}"))?;
+
+    datagram::connect(request_id, client, dest_addr, DnsCarrier::new(socket, domain))
+        .await
+        .with_context(|| format!("cannot open tunnel through dns resolver {resolver}"))
+}
+
+/// Frame sent by the client, decoded from the query name
+pub fn decode_query_frame(name: &Name, domain: &Name) -> Option<Frame> {
+    let mut data = decode_query_name(name, domain)?;
+    if data.len() < NONCE_LEN {
+        return None;
+    }
+    data.advance(NONCE_LEN);
+    Frame::decode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(b"" ; "empty")]
+    #[test_case(b"f" ; "one byte")]
+    #[test_case(b"foobar" ; "several blocks")]
+    #[test_case(&[0, 255, 128, 7, 42] ; "binary")]
+    fn test_base32_roundtrip(data: &[u8]) {
+        let encoded = base32_encode(data);
+        assert_eq!(base32_decode(encoded.as_bytes()).unwrap(), data);
+        assert_eq!(base32_decode(encoded.to_uppercase().as_bytes()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_query_name_roundtrip() {
+        let domain = Name::from_ascii("t.example.com.").unwrap();
+        let max_len = max_query_data_len(&domain);
+        let data: Vec<u8> = (0..max_len).map(|x| x as u8).collect();
+
+        let name = encode_query_name(&data, &domain).unwrap();
+        assert!(name.to_ascii().trim_end_matches('.').len() <= MAX_NAME_LEN);
+        assert_eq!(decode_query_name(&name, &domain).unwrap(), data);
+
+        // too much data to fit in a name
+        let data: Vec<u8> = (0..max_len + 10).map(|x| x as u8).collect();
+        assert!(encode_query_name(&data, &domain).is_err());
+
+        // not our domain
+        let other = Name::from_ascii("t.example.org.").unwrap();
+        assert!(decode_query_name(&name, &other).is_none());
+    }
+}
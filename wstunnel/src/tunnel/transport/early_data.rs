@@ -0,0 +1,83 @@
+//! early_data - send the first bytes of a new tunnel along with the request opening it, to save a round trip.
+//! The server writes them to the destination as soon as it is connected, and acknowledges them in its response with
+//! their length. A server not knowing the header does not acknowledge them, so the client sends them again as regular data
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::http::response::Parts;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const EARLY_DATA_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-early-data");
+/// Enough for a tls client hello or the head of a http request
+pub const MAX_EARLY_DATA_LEN: usize = 4096;
+/// Time to wait for the first bytes of a new tunnel. Protocols where the destination speaks first (i.e: ssh) are delayed by it
+pub const EARLY_DATA_WAIT: Duration = Duration::from_millis(10);
+
+/// First bytes of the local side of the tunnel, empty if nothing was sent in time
+pub async fn read(local_rx: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; MAX_EARLY_DATA_LEN];
+    match tokio::time::timeout(EARLY_DATA_WAIT, local_rx.read(&mut buf)).await {
+        Ok(Ok(len)) => {
+            buf.truncate(len);
+            Ok(buf)
+        }
+        Ok(Err(err)) => Err(err),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+pub fn encode(data: &[u8]) -> Option<HeaderValue> {
+    if data.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&STANDARD.encode(data)).ok()
+}
+
+pub fn decode(header: &HeaderValue) -> anyhow::Result<Vec<u8>> {
+    let data = STANDARD
+        .decode(header.as_bytes())
+        .context("early data is not valid base64")?;
+    if data.len() > MAX_EARLY_DATA_LEN {
+        anyhow::bail!("early data of {} bytes is bigger than {MAX_EARLY_DATA_LEN}", data.len());
+    }
+    Ok(data)
+}
+
+/// Whether the server wrote the early data to the destination, or if it must be sent again with the rest of the tunnel
+pub fn is_acknowledged(response: &Parts, data: &[u8]) -> bool {
+    response
+        .headers
+        .get(EARLY_DATA_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.parse::<usize>().ok())
+        == Some(data.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Response;
+
+    #[test]
+    fn test_early_data_roundtrip() {
+        assert_eq!(encode(b""), None);
+        let header = encode(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(decode(&header).unwrap(), b"GET / HTTP/1.1\r\n\r\n");
+        assert!(decode(&HeaderValue::from_static("not base64 !")).is_err());
+        let too_big = encode(&[0; MAX_EARLY_DATA_LEN + 1]).unwrap();
+        assert!(decode(&too_big).is_err());
+
+        let (response, _) = Response::builder()
+            .header(EARLY_DATA_HEADER, "18")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert!(is_acknowledged(&response, b"GET / HTTP/1.1\r\n\r\n"));
+        assert!(!is_acknowledged(&response, b"hello"));
+        let (response, _) = Response::new(()).into_parts();
+        assert!(!is_acknowledged(&response, b"hello"));
+    }
+}
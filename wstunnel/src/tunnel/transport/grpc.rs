@@ -0,0 +1,422 @@
+//! Tunnels over bidirectional gRPC streams, for the egress proxies that let gRPC through but not websocket or plain
+//! http2 streaming. Each tunnel is a call of the streaming method below, and all of them are multiplexed as streams on
+//! the same http2 connection to the server, like the calls of a gRPC channel. The service is equivalent to
+//!
+//! ```protobuf
+//! message Chunk {
+//!   bytes data = 1;
+//! }
+//!
+//! // Served on /{http-upgrade-path-prefix}/events, so a tonic client needs a service named like the prefix
+//! service <prefix> {
+//!   rpc events(stream Chunk) returns (stream Chunk);
+//! }
+//! ```
+//!
+//! The tunnel token is sent in the metadata, like the other transports send it in their headers.
+use super::buffer_pool::{BUFFER_POOL, PooledBuffer};
+use super::http2::{self, Http2TunnelWrite, body_channel};
+use super::io::{MAX_PACKET_LENGTH, TunnelRead, TunnelWrite};
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::client::WsClient;
+use crate::tunnel::protocol::close_reason::CloseReason;
+use crate::tunnel::transport::jwt::tunnel_to_jwt_token;
+use crate::tunnel::transport::{EARLY_DATA_HEADER, PSK_HEADER, early_data};
+use anyhow::{Context, anyhow};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http_body_util::{BodyStream, StreamBody};
+use hyper::body::{Body, Frame, Incoming};
+use hyper::client::conn::http2::SendRequest;
+use hyper::header::{CONTENT_TYPE, COOKIE, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::{Method, Request, Uri};
+use log::debug;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+use tokio_stream::{Stream, StreamExt};
+use uuid::Uuid;
+
+pub const GRPC_CONTENT_TYPE: HeaderValue = HeaderValue::from_static("application/grpc");
+const GRPC_STATUS: HeaderName = HeaderName::from_static("grpc-status");
+const GRPC_MESSAGE: HeaderName = HeaderName::from_static("grpc-message");
+const TE: HeaderName = HeaderName::from_static("te");
+
+/// Length prefix of the gRPC messages: the compression flag and the length of the message
+const MESSAGE_HEADER_LEN: usize = 5;
+/// Key of the `data` field of `Chunk`: field 1 with the length-delimited wire type
+const DATA_FIELD_KEY: u8 = 0x0A;
+/// Biggest message accepted from the peer, so it cannot make us buffer without limit
+const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+pub type GrpcBody = StreamBody<Pin<Box<dyn Stream<Item = anyhow::Result<Frame<Bytes>>> + Send>>>;
+
+/// Http2 connection with the server the grpc tunnels are multiplexed on, with the uri it was opened for
+pub(crate) type GrpcChannel = Arc<tokio::sync::Mutex<Option<(SendRequest<GrpcBody>, Uri)>>>;
+
+/// Whether the request of a tunnel is a gRPC call, whose data must be framed as gRPC messages
+pub fn is_grpc_request<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/grpc"))
+}
+
+/// Trailers ending the response of a gRPC call that completed
+pub fn ok_trailers() -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert(GRPC_STATUS, HeaderValue::from_static("0"));
+    trailers
+}
+
+/// Trailers ending the response of a gRPC call aborted because the local stream of the tunnel failed
+fn aborted_trailers(reason: CloseReason) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert(GRPC_STATUS, HeaderValue::from_static("10"));
+    trailers.insert(GRPC_MESSAGE, HeaderValue::from_static(reason.name()));
+    reason.encode_trailer(&mut trailers);
+    trailers
+}
+
+/// Frame `data` as a gRPC message holding a `Chunk`
+fn encode(data: &[u8], out: &mut BytesMut) {
+    let mut len_prefix = [0u8; 10];
+    let len_prefix = encode_varint(data.len() as u64, &mut len_prefix);
+    out.reserve(MESSAGE_HEADER_LEN + 1 + len_prefix.len() + data.len());
+    out.put_u8(0); // not compressed
+    out.put_u32((1 + len_prefix.len() + data.len()) as u32);
+    out.put_u8(DATA_FIELD_KEY);
+    out.put_slice(len_prefix);
+    out.put_slice(data);
+}
+
+/// Take the data of the first complete gRPC message of `buf`, if there is one yet
+fn decode(buf: &mut BytesMut) -> Result<Option<Bytes>, io::Error> {
+    if buf.len() < MESSAGE_HEADER_LEN {
+        return Ok(None);
+    }
+    if buf[0] != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "compressed grpc messages are not supported",
+        ));
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("grpc message of {len} bytes is too big"),
+        ));
+    }
+    if buf.len() < MESSAGE_HEADER_LEN + len {
+        return Ok(None);
+    }
+
+    buf.advance(MESSAGE_HEADER_LEN);
+    let mut message = buf.split_to(len).freeze();
+    // The last occurrence of a field wins, unknown fields are skipped
+    let mut data = Bytes::new();
+    while message.has_remaining() {
+        let key = decode_varint(&mut message)?;
+        let field_len = match key & 0x7 {
+            0 => {
+                decode_varint(&mut message)?;
+                0
+            }
+            1 => 8,
+            2 => decode_varint(&mut message)? as usize,
+            5 => 4,
+            wire_type => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid protobuf wire type {wire_type}"),
+                ));
+            }
+        };
+        if field_len > message.remaining() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "truncated protobuf field"));
+        }
+        let field = message.split_to(field_len);
+        if key == DATA_FIELD_KEY as u64 {
+            data = field;
+        }
+    }
+
+    Ok(Some(data))
+}
+
+fn encode_varint(mut value: u64, buf: &mut [u8; 10]) -> &[u8] {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            return &buf[..=len];
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+fn decode_varint(buf: &mut Bytes) -> Result<u64, io::Error> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            break;
+        }
+        let byte = buf.get_u8();
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "invalid protobuf varint"))
+}
+
+/// Error of a gRPC call that failed, from the status sent in its trailers or in the headers of its response
+fn grpc_error(headers: &HeaderMap) -> Option<io::Error> {
+    let status = headers.get(GRPC_STATUS)?.to_str().unwrap_or("2");
+    if status == "0" {
+        return None;
+    }
+    let message = headers
+        .get(GRPC_MESSAGE)
+        .and_then(|message| message.to_str().ok())
+        .unwrap_or_default();
+    Some(io::Error::new(
+        ErrorKind::ConnectionAborted,
+        format!("grpc call failed with status {status}: {message}"),
+    ))
+}
+
+/// Read the data of a tunnel from the gRPC messages of an http2 stream
+pub struct GrpcTunnelRead<B = Incoming> {
+    inner: BodyStream<B>,
+    buf: BytesMut,
+}
+
+impl<B> GrpcTunnelRead<B> {
+    pub fn new(inner: BodyStream<B>) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::new(),
+        }
+    }
+}
+
+impl<B> TunnelRead for GrpcTunnelRead<B>
+where
+    B: Body<Data = Bytes, Error: Into<Box<dyn Error + Send + Sync>> + Send> + Send + Unpin + 'static,
+{
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+        loop {
+            if let Some(data) = decode(&mut self.buf)? {
+                return match writer.write_all(data.as_ref()).await {
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+                };
+            }
+
+            match self.inner.next().await {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => self.buf.extend_from_slice(&data),
+                    Err(frame) => {
+                        if let Some(reason) = frame.trailers_ref().and_then(CloseReason::decode_trailers) {
+                            return Err(reason.into_io_error());
+                        }
+                        if let Some(err) = frame.trailers_ref().and_then(grpc_error) {
+                            return Err(err);
+                        }
+                    }
+                },
+                Some(Err(err)) => {
+                    return Err(io::Error::new(ErrorKind::ConnectionAborted, err));
+                }
+                None => return Err(io::Error::new(ErrorKind::BrokenPipe, "closed")),
+            }
+        }
+    }
+}
+
+/// Write the data of a tunnel as gRPC messages in an http2 stream
+pub struct GrpcTunnelWrite {
+    inner: Http2TunnelWrite,
+    buf: PooledBuffer,
+}
+
+impl GrpcTunnelWrite {
+    pub fn new(inner: Http2TunnelWrite) -> Self {
+        Self {
+            inner,
+            buf: BUFFER_POOL.take(MAX_PACKET_LENGTH * 2),
+        }
+    }
+}
+
+impl TunnelWrite for GrpcTunnelWrite {
+    fn buf_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    async fn write(&mut self) -> Result<(), io::Error> {
+        encode(&self.buf, self.inner.buf_mut());
+        self.buf.clear();
+        if self.buf.capacity() < MAX_PACKET_LENGTH {
+            self.buf.reserve(MAX_PACKET_LENGTH)
+        }
+        self.inner.write().await
+    }
+
+    async fn ping(&mut self) -> Result<(), io::Error> {
+        self.inner.ping().await
+    }
+
+    async fn close(&mut self) -> Result<(), io::Error> {
+        self.inner.close().await
+    }
+
+    async fn half_close(&mut self) -> Result<(), io::Error> {
+        self.inner.half_close().await
+    }
+
+    async fn abort(&mut self, reason: CloseReason) -> Result<(), io::Error> {
+        self.inner.end_with_trailers(aborted_trailers(reason)).await
+    }
+
+    fn pending_operations_notify(&mut self) -> Arc<Notify> {
+        self.inner.pending_operations_notify()
+    }
+
+    fn handle_pending_operations(&mut self) -> impl Future<Output = Result<(), io::Error>> + Send {
+        self.inner.handle_pending_operations()
+    }
+}
+
+pub async fn connect(
+    request_id: Uuid,
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    dest_addr: &RemoteAddr,
+    early_data: &[u8],
+) -> anyhow::Result<(GrpcTunnelRead, GrpcTunnelWrite, Parts)> {
+    let path = client
+        .config
+        .camouflage
+        .upgrade_path(&client.config.http_upgrade_path_prefix);
+    let tunnel_token = tunnel_to_jwt_token(
+        request_id,
+        dest_addr,
+        client.label.as_deref(),
+        client.config.device_id.as_deref(),
+    );
+    let psk_proof = client
+        .config
+        .psk
+        .as_ref()
+        .map(|psk| psk.client_proof(&path, &tunnel_token));
+    let mut req = http2::mk_request(client, Method::POST, &path).await?;
+    let headers = req.headers_mut();
+    headers.insert(COOKIE, HeaderValue::from_str(&tunnel_token)?);
+    headers.insert(CONTENT_TYPE, GRPC_CONTENT_TYPE);
+    headers.insert(TE, HeaderValue::from_static("trailers"));
+    if let Some(psk_proof) = &psk_proof {
+        headers.insert(PSK_HEADER, HeaderValue::from_str(psk_proof)?);
+    }
+    if let Some(early_data) = early_data::encode(early_data) {
+        headers.insert(EARLY_DATA_HEADER, early_data);
+    }
+    client.add_probe_interval(headers, dest_addr);
+    client.add_integrity_interval(headers, dest_addr);
+
+    let mut request_sender = channel(client, &mut req).await?;
+    let (writer, body) = body_channel(client.config.max_inflight_per_tunnel);
+    debug!("with gRPC request {req:?}");
+    let body: Pin<Box<dyn Stream<Item = anyhow::Result<Frame<Bytes>>> + Send>> = Box::pin(body);
+    let req = req.map(|_| StreamBody::new(body));
+
+    request_sender
+        .ready()
+        .await
+        .with_context(|| format!("gRPC connection with the server {:?} is closed", client.config.remote_addr))?;
+    let response = request_sender
+        .send_request(req)
+        .await
+        .with_context(|| format!("failed to send gRPC request with the server {:?}", client.config.remote_addr))?;
+    let response = http2::check_response(client, response, psk_proof.as_deref()).await?;
+    if let Some(err) = grpc_error(response.headers()) {
+        return Err(anyhow!(err));
+    }
+
+    let (parts, body) = response.into_parts();
+    Ok((GrpcTunnelRead::new(BodyStream::new(body)), GrpcTunnelWrite::new(writer), parts))
+}
+
+/// Connection the tunnels are multiplexed on, opened again if it was lost.
+/// The authority of `req` is set to the host the connection was opened for
+async fn channel(
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    req: &mut Request<()>,
+) -> anyhow::Result<SendRequest<GrpcBody>> {
+    let mut channel = client.grpc_channel.lock().await;
+    if let Some((request_sender, uri)) = channel.as_ref().filter(|(sender, _)| !sender.is_closed()) {
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = req.uri().path_and_query().cloned();
+        *req.uri_mut() = Uri::from_parts(parts)?;
+        return Ok(request_sender.clone());
+    }
+
+    // The connection lives as long as the channel, or any of the tunnels still using it
+    let (request_sender, _cnx_poller) = http2::handshake(client, req).await?;
+    *channel = Some((request_sender.clone(), req.uri().clone()));
+    Ok(request_sender)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grpc_framing() {
+        let mut buf = BytesMut::new();
+        encode(b"hello", &mut buf);
+        assert_eq!(&buf[..], b"\x00\x00\x00\x00\x07\x0A\x05hello");
+
+        let data = vec![42u8; 300];
+        encode(&data, &mut buf);
+        // Complete messages only, whatever the way they were split in frames
+        let mut partial = buf.split_to(10);
+        assert_eq!(decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert_eq!(decode(&mut partial).unwrap().unwrap(), Bytes::from_static(b"hello"));
+        assert_eq!(decode(&mut partial).unwrap().unwrap(), Bytes::from(data));
+        assert!(partial.is_empty());
+
+        // Unknown fields of a newer peer are skipped
+        let mut buf = BytesMut::from(&b"\x00\x00\x00\x00\x06\x10\x01\x0A\x02hi"[..]);
+        assert_eq!(decode(&mut buf).unwrap().unwrap(), Bytes::from_static(b"hi"));
+
+        let mut compressed = BytesMut::from(&b"\x01\x00\x00\x00\x00"[..]);
+        assert!(decode(&mut compressed).is_err());
+    }
+
+    #[test]
+    fn test_grpc_status() {
+        assert!(grpc_error(&ok_trailers()).is_none());
+        assert!(grpc_error(&HeaderMap::new()).is_none());
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert(GRPC_STATUS, HeaderValue::from_static("14"));
+        trailers.insert(GRPC_MESSAGE, HeaderValue::from_static("unavailable"));
+        assert!(
+            grpc_error(&trailers)
+                .unwrap()
+                .to_string()
+                .contains("status 14: unavailable")
+        );
+    }
+}
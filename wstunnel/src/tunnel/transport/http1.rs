@@ -0,0 +1,450 @@
+//! Last resort transport over plain HTTP/1.1 requests, for networks where websocket upgrades are stripped and http2 is
+//! downgraded. A tunnel uses two half-duplex requests, each on its own connection: the server streams the data for
+//! the client in the chunked response of a GET request, and the client streams its data in the chunked body of a POST
+//! request. Both requests carry the same session id, so the server can pair them.
+//!
+//! When a proxy/CDN buffers the request bodies, the client can instead split its upload in a series of short POST
+//! requests, numbered by a sequence header. An empty one ends the upload. The http2 transport uses the same requests.
+//!
+//! The server answers the download request with a random token of the session. The upload requests carry an HMAC of
+//! the session keyed by this token, so only the client which opened the session can feed it, and a new pre-shared key
+//! proof when the server requires one. The HMAC of a numbered request also covers its sequence number and its body, so
+//! a proxy can neither alter nor reorder them.
+use super::http2::{Http2TunnelRead, Http2TunnelWrite, body_channel};
+use super::io::TunnelRead;
+use crate::executor::AbortHandle;
+use crate::oidc;
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::client::{SplitRequests, WsClient};
+use crate::tunnel::protocol::client_version::{CLIENT_VERSION_HEADER, client_version_header};
+use crate::tunnel::transport::jwt::tunnel_to_jwt_token;
+use crate::tunnel::transport::{
+    EARLY_DATA_HEADER, PSK_HEADER, PreSharedKey, UpgradeRejected, early_data, headers_from_file,
+};
+use anyhow::{Context, anyhow};
+use bytes::{Bytes, BytesMut};
+use futures_util::{Stream, StreamExt, pin_mut};
+use http_body_util::{BodyExt, BodyStream, Empty, Full, StreamBody};
+use hyper::body::{Body, Frame, Incoming};
+use hyper::client::conn::{http1, http2};
+use hyper::header::{AUTHORIZATION, COOKIE, HOST, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::{Method, Request, Response};
+use hyper_util::rt::TokioIo;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use log::{debug, error, warn};
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::ops::DerefMut;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{Instrument, Span};
+use uuid::Uuid;
+
+pub const SESSION_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-session");
+pub const SEQ_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-seq");
+/// Token of the session, returned by the server in the response of the download request
+pub const SESSION_TOKEN_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-session-token");
+/// HMAC of the session keyed by its token, and of the chunk for numbered requests, sent with the upload requests
+pub const UPLOAD_MAC_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-upload-mac");
+/// Maximum size of the body of an upload request, when the upload is split in multiple requests
+pub const MAX_CHUNK_LEN: usize = 8 * 1024 * 1024;
+/// Writes of the tunnel merged in one upload request. A write is at most ~1MiB, so it stays under `MAX_CHUNK_LEN`
+const MAX_WRITES_PER_CHUNK: usize = 4;
+
+/// Requests of a session, either its download or its upload
+pub fn is_session_request<B>(req: &Request<B>) -> bool {
+    req.headers().contains_key(SESSION_HEADER)
+}
+
+/// Data uploaded by the client of a session, received by the server
+pub struct SessionUploadRead {
+    inner: mpsc::Receiver<Bytes>,
+}
+
+impl SessionUploadRead {
+    pub const fn new(inner: mpsc::Receiver<Bytes>) -> Self {
+        Self { inner }
+    }
+}
+
+impl TunnelRead for SessionUploadRead {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+        let Some(data) = self.inner.recv().await else {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "closed"));
+        };
+
+        match writer.write_all(&data).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+        }
+    }
+}
+
+/// Random token of a new session
+pub fn session_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Chunk of the upload, with its sequence number
+pub type UploadChunk<'a> = (u64, &'a [u8]);
+
+pub fn upload_mac(token: &str, session_id: &Uuid, chunk: Option<UploadChunk>) -> String {
+    jsonwebtoken::crypto::sign(
+        &upload_message(session_id, chunk),
+        &EncodingKey::from_secret(token.as_bytes()),
+        Algorithm::HS256,
+    )
+    .unwrap_or_default()
+}
+
+pub fn verify_upload_mac(token: &str, session_id: &Uuid, chunk: Option<UploadChunk>, mac: &str) -> bool {
+    jsonwebtoken::crypto::verify(
+        mac,
+        &upload_message(session_id, chunk),
+        &DecodingKey::from_secret(token.as_bytes()),
+        Algorithm::HS256,
+    )
+    .unwrap_or(false)
+}
+
+fn upload_message(session_id: &Uuid, chunk: Option<UploadChunk>) -> Vec<u8> {
+    let mut message = format!("wstunnel-upload\n{}", session_id.simple()).into_bytes();
+    if let Some((seq, data)) = chunk {
+        message.extend_from_slice(format!("\n{seq}\n").as_bytes());
+        message.extend_from_slice(data);
+    }
+    message
+}
+
+/// Credentials of the upload requests of a session
+pub(super) struct UploadAuth {
+    session_id: Uuid,
+    token: String,
+    psk: Option<PreSharedKey>,
+    path: String,
+}
+
+impl UploadAuth {
+    /// Take the token of the session from the response of its download request
+    pub(super) fn new(
+        session_id: &Uuid,
+        response: &Response<Incoming>,
+        psk: Option<&PreSharedKey>,
+        path: &str,
+    ) -> anyhow::Result<Self> {
+        let token = response
+            .headers()
+            .get(SESSION_TOKEN_HEADER)
+            .and_then(|header| header.to_str().ok())
+            .ok_or_else(|| anyhow!("server did not return the token of the session"))?;
+        Ok(Self {
+            session_id: *session_id,
+            token: token.to_string(),
+            psk: psk.cloned(),
+            path: path.to_string(),
+        })
+    }
+
+    /// Sign an upload request, each one with its own pre-shared key proof as the server rejects replayed ones
+    pub(super) fn sign(&self, headers: &mut HeaderMap, chunk: Option<UploadChunk>) -> anyhow::Result<()> {
+        let mac = upload_mac(&self.token, &self.session_id, chunk);
+        headers.insert(UPLOAD_MAC_HEADER, HeaderValue::from_str(&mac)?);
+        if let Some(psk) = &self.psk {
+            headers.insert(PSK_HEADER, HeaderValue::from_str(&psk.client_proof(&self.path, &mac))?);
+        }
+        Ok(())
+    }
+}
+
+/// Connection the upload requests of a session are sent on
+pub(super) trait UploadSender: Send + 'static {
+    fn send(&mut self, req: Request<Full<Bytes>>) -> impl Future<Output = hyper::Result<Response<Incoming>>> + Send;
+}
+
+impl UploadSender for http1::SendRequest<Full<Bytes>> {
+    async fn send(&mut self, req: Request<Full<Bytes>>) -> hyper::Result<Response<Incoming>> {
+        // Requests are sent one after the other on the connection
+        self.ready().await?;
+        self.send_request(req).await
+    }
+}
+
+impl UploadSender for http2::SendRequest<Full<Bytes>> {
+    async fn send(&mut self, req: Request<Full<Bytes>>) -> hyper::Result<Response<Incoming>> {
+        self.ready().await?;
+        self.send_request(req).await
+    }
+}
+
+/// Send the data of the tunnel to the server in a series of short requests, copies of `template` numbered by their
+/// sequence header. Data written while a request is in flight is sent in the next one
+pub(super) async fn upload_in_chunks(
+    mut sender: impl UploadSender,
+    template: Request<()>,
+    auth: UploadAuth,
+    body: impl Stream<Item = anyhow::Result<Frame<Bytes>>>,
+) -> anyhow::Result<()> {
+    let body = body.ready_chunks(MAX_WRITES_PER_CHUNK);
+    pin_mut!(body);
+
+    let mut seq: u64 = 0;
+    loop {
+        // An empty request ends the upload
+        let data = match body.next().await {
+            Some(frames) => {
+                let data = frames
+                    .into_iter()
+                    .filter_map(|frame| frame.ok()?.into_data().ok())
+                    .fold(BytesMut::new(), |mut data, frame| {
+                        data.extend_from_slice(&frame);
+                        data
+                    });
+                if data.is_empty() {
+                    continue;
+                }
+                data.freeze()
+            }
+            None => Bytes::new(),
+        };
+        let is_last = data.is_empty();
+
+        let mut req = Request::new(());
+        *req.method_mut() = template.method().clone();
+        *req.uri_mut() = template.uri().clone();
+        *req.version_mut() = template.version();
+        *req.headers_mut() = template.headers().clone();
+        req.headers_mut().insert(SEQ_HEADER, HeaderValue::from(seq));
+        auth.sign(req.headers_mut(), Some((seq, &data)))?;
+        let req = req.map(|_| Full::new(data));
+
+        let response = sender.send(req).await.context("failed to send upload request")?;
+        if !response.status().is_success() {
+            return Err(anyhow!("server rejected the upload request: {:?}", response.status()));
+        }
+        let _ = response.into_body().collect().await;
+
+        if is_last {
+            return Ok(());
+        }
+        seq += 1;
+    }
+}
+
+pub async fn connect(
+    request_id: Uuid,
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    dest_addr: &RemoteAddr,
+    early_data: &[u8],
+) -> anyhow::Result<(Http2TunnelRead, Http2TunnelWrite, Parts)> {
+    let client_cfg = &client.config;
+    let session_id = Uuid::new_v4();
+    let path = client_cfg.camouflage.upgrade_path(&client_cfg.http_upgrade_path_prefix);
+    let tunnel_token = tunnel_to_jwt_token(
+        request_id,
+        dest_addr,
+        client.label.as_deref(),
+        client.config.device_id.as_deref(),
+    );
+    let psk_proof = client_cfg
+        .psk
+        .as_ref()
+        .map(|psk| psk.client_proof(&path, &tunnel_token));
+
+    // The download request opens the tunnel, so it is the one carrying the tunnel token
+    let mut req = session_request(client, Method::GET, &path, session_id).await?;
+    req.headers_mut().insert(COOKIE, HeaderValue::from_str(&tunnel_token)?);
+    if let Some(psk_proof) = &psk_proof {
+        req.headers_mut().insert(PSK_HEADER, HeaderValue::from_str(psk_proof)?);
+    }
+    if let Some(early_data) = early_data::encode(early_data) {
+        req.headers_mut().insert(EARLY_DATA_HEADER, early_data);
+    }
+    client.add_probe_interval(req.headers_mut(), dest_addr);
+    client.add_integrity_interval(req.headers_mut(), dest_addr);
+    debug!("with HTTP download request {req:?}");
+    let (mut request_sender, cnx_poller) = handshake(client, &mut req).await?;
+    let response = request_sender
+        .send_request(req.map(|_| Empty::<Bytes>::new()))
+        .await
+        .with_context(|| format!("failed to send http1 request with the server {:?}", client_cfg.remote_addr))?;
+
+    if !response.status().is_success() {
+        cnx_poller.abort();
+        return Err(UpgradeRejected::from_response("Http1", response).await.into());
+    }
+
+    if let (Some(psk), Some(psk_proof)) = (&client_cfg.psk, &psk_proof) {
+        let server_proof = response
+            .headers()
+            .get(PSK_HEADER)
+            .and_then(|header| header.to_str().ok())
+            .unwrap_or_default();
+        if let Err(err) = psk.verify_server_proof(psk_proof, server_proof) {
+            cnx_poller.abort();
+            return Err(err);
+        }
+    }
+    let auth = match UploadAuth::new(&session_id, &response, client_cfg.psk.as_ref(), &path) {
+        Ok(auth) => auth,
+        Err(err) => {
+            cnx_poller.abort();
+            return Err(err);
+        }
+    };
+    let (parts, body) = response.into_parts();
+    let reader = Http2TunnelRead::new(BodyStream::new(body), Some(cnx_poller));
+
+    // The upload lasts until the local side of the tunnel is closed
+    let (writer, body) = body_channel(client_cfg.max_inflight_per_tunnel);
+    let mut req = session_request(client, Method::POST, &path, session_id).await?;
+    if client_cfg.http_split_requests == SplitRequests::Always {
+        let (request_sender, _) = handshake(client, &mut req).await?;
+        debug!("with HTTP upload request {req:?}");
+        client.executor.spawn(
+            async move {
+                if let Err(err) = upload_in_chunks(request_sender, req, auth, body).await {
+                    warn!("Http1 upload of the tunnel failed: {err:?}");
+                }
+            }
+            .instrument(Span::current()),
+        );
+    } else {
+        auth.sign(req.headers_mut(), None)?;
+        let (mut request_sender, _) = handshake(client, &mut req).await?;
+        debug!("with HTTP upload request {req:?}");
+        client.executor.spawn(
+            async move {
+                match request_sender.send_request(req.map(|_| StreamBody::new(body))).await {
+                    Ok(response) if response.status().is_success() => {}
+                    Ok(response) => warn!("Http1 server rejected the upload of the tunnel: {:?}", response.status()),
+                    Err(err) => warn!("Http1 upload of the tunnel failed: {err:?}"),
+                }
+            }
+            .instrument(Span::current()),
+        );
+    }
+
+    Ok((reader, writer, parts))
+}
+
+/// Request of a session, with the headers the client sends to the server with all its requests
+async fn session_request(
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    method: Method,
+    path: &str,
+    session_id: Uuid,
+) -> anyhow::Result<Request<()>> {
+    let client_cfg = &client.config;
+    let mut req = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(HOST, &client_cfg.http_header_host)
+        .header(SESSION_HEADER, session_id.simple().to_string())
+        .version(hyper::Version::HTTP_11)
+        .body(())
+        .with_context(|| {
+            format!(
+                "failed to build HTTP request to contact the server {:?}. Most likely path_prefix `{}` or http headers is not valid",
+                client_cfg.remote_addr, client_cfg.http_upgrade_path_prefix
+            )
+        })?;
+
+    let headers = req.headers_mut();
+    for (k, v) in &client_cfg.http_headers {
+        let _ = headers.remove(k);
+        headers.append(k, v.clone());
+    }
+    headers.insert(CLIENT_VERSION_HEADER, client_version_header());
+    client.add_sticky_session(headers);
+
+    if let Some(auth) = &client_cfg.http_upgrade_credentials {
+        let _ = headers.remove(AUTHORIZATION);
+        headers.append(AUTHORIZATION, auth.clone());
+    }
+
+    if let Some(token_cache) = &client_cfg.oidc_token_cache {
+        let _ = headers.remove(AUTHORIZATION);
+        headers.append(
+            AUTHORIZATION,
+            oidc::bearer_from_cache(token_cache, &client_cfg.http_client_config()).await?,
+        );
+    }
+
+    if let Some(headers_file_path) = &client_cfg.http_headers_file {
+        let (host, headers_file) = headers_from_file(headers_file_path);
+        for (k, v) in headers_file {
+            let _ = headers.remove(&k);
+            headers.append(k, v);
+        }
+        if let Some((host, val)) = host {
+            let _ = headers.remove(&host);
+            headers.append(host, val);
+        }
+    }
+    client_cfg.camouflage.add_browser_headers(headers, None, false);
+
+    Ok(req)
+}
+
+/// Take a connection to the server from the pool, and drive it in the background until it is closed.
+/// The host of `req` is the one the connection was opened for
+async fn handshake<B>(
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    req: &mut Request<()>,
+) -> anyhow::Result<(http1::SendRequest<B>, AbortHandle)>
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let mut pooled_cnx = match client.cnx_pool.get().await {
+        Ok(cnx) => Ok(cnx),
+        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
+    }?;
+
+    let transport = pooled_cnx.deref_mut().take().unwrap();
+    if let Some(host) = transport.host() {
+        req.headers_mut().insert(HOST, host.clone());
+    }
+    client.mark_transport(&transport);
+    let (request_sender, cnx) = http1::Builder::new()
+        .handshake(TokioIo::new(transport))
+        .await
+        .with_context(|| format!("failed to do http1 handshake with the server {:?}", client.config.remote_addr))?;
+    let cnx_poller = client.executor.spawn(async move {
+        if let Err(err) = cnx.await {
+            error!("{err:?}")
+        }
+    });
+
+    Ok((request_sender, cnx_poller))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_mac() {
+        let session_id = Uuid::new_v4();
+        let token = session_token();
+        assert_ne!(token, session_token());
+
+        let mac = upload_mac(&token, &session_id, None);
+        assert!(verify_upload_mac(&token, &session_id, None, &mac));
+        // The mac is bound to the token and to the session
+        assert!(!verify_upload_mac(&session_token(), &session_id, None, &mac));
+        assert!(!verify_upload_mac(&token, &Uuid::new_v4(), None, &mac));
+        assert!(!verify_upload_mac(&token, &session_id, None, ""));
+
+        // and to the sequence number and the data of a chunk
+        let mac = upload_mac(&token, &session_id, Some((1, b"hello")));
+        assert!(verify_upload_mac(&token, &session_id, Some((1, b"hello")), &mac));
+        assert!(!verify_upload_mac(&token, &session_id, None, &mac));
+        assert!(!verify_upload_mac(&token, &session_id, Some((2, b"hello")), &mac));
+        assert!(!verify_upload_mac(&token, &session_id, Some((1, b"hellO")), &mac));
+    }
+}
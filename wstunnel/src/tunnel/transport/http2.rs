@@ -1,42 +1,56 @@
 use super::io::{MAX_PACKET_LENGTH, TunnelRead, TunnelWrite};
+use crate::executor::AbortHandle;
+use crate::metrics::{METRICS, Metrics};
+use crate::oidc;
 use crate::tunnel::RemoteAddr;
-use crate::tunnel::client::WsClient;
+use crate::tunnel::client::{SplitRequests, WsClient};
+use crate::tunnel::protocol::client_version::{CLIENT_VERSION_HEADER, client_version_header};
+use crate::tunnel::protocol::close_reason::CloseReason;
+use crate::tunnel::transport::http1;
+use crate::tunnel::transport::http1::{SESSION_HEADER, UploadAuth};
 use crate::tunnel::transport::jwt::tunnel_to_jwt_token;
-use crate::tunnel::transport::{TransportScheme, headers_from_file};
+use crate::tunnel::transport::{
+    EARLY_DATA_HEADER, PSK_HEADER, TransportScheme, UpgradeRejected, early_data, headers_from_file,
+};
 use anyhow::{Context, anyhow};
 use bytes::{Bytes, BytesMut};
-use http_body_util::{BodyExt, BodyStream, StreamBody};
-use hyper::Request;
-use hyper::body::{Frame, Incoming};
-use hyper::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE};
+use http_body_util::{BodyStream, Full, StreamBody};
+use hyper::body::{Body, Frame, Incoming};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE, HeaderMap, HeaderValue};
 use hyper::http::response::Parts;
+use hyper::{Method, Request, Response};
 use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
 use log::{debug, error, warn};
+use std::error::Error;
+use std::fmt::Debug;
 use std::future::Future;
 use std::io;
 use std::io::ErrorKind;
 use std::ops::DerefMut;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
-use tokio::sync::{Notify, mpsc};
-use tokio::task::AbortHandle;
-use tokio_stream::StreamExt;
+use tokio::sync::{Notify, Semaphore, mpsc};
 use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{Instrument, Span};
 use uuid::Uuid;
 
-pub struct Http2TunnelRead {
-    inner: BodyStream<Incoming>,
+/// Read the data of a tunnel from the body of an http2 stream. The server reads it from the body type of the framework
+/// it is mounted in, when it is not hyper's own
+pub struct Http2TunnelRead<B = Incoming> {
+    inner: BodyStream<B>,
     cnx_poller: Option<AbortHandle>,
 }
 
-impl Http2TunnelRead {
-    pub const fn new(inner: BodyStream<Incoming>, cnx_poller: Option<AbortHandle>) -> Self {
+impl<B> Http2TunnelRead<B> {
+    pub const fn new(inner: BodyStream<B>, cnx_poller: Option<AbortHandle>) -> Self {
         Self { inner, cnx_poller }
     }
 }
 
-impl Drop for Http2TunnelRead {
+impl<B> Drop for Http2TunnelRead<B> {
     fn drop(&mut self) {
         if let Some(t) = self.cnx_poller.as_ref() {
             t.abort()
@@ -44,7 +58,10 @@ impl Drop for Http2TunnelRead {
     }
 }
 
-impl TunnelRead for Http2TunnelRead {
+impl<B> TunnelRead for Http2TunnelRead<B>
+where
+    B: Body<Data = Bytes, Error: Into<Box<dyn Error + Send + Sync>> + Send> + Send + Unpin + 'static,
+{
     async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
         loop {
             match self.inner.next().await {
@@ -55,8 +72,11 @@ impl TunnelRead for Http2TunnelRead {
                             Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
                         };
                     }
-                    Err(err) => {
-                        warn!("{err:?}");
+                    Err(frame) => {
+                        if let Some(reason) = frame.trailers_ref().and_then(CloseReason::decode_trailers) {
+                            return Err(reason.into_io_error());
+                        }
+                        warn!("{frame:?}");
                         continue;
                     }
                 },
@@ -69,16 +89,75 @@ impl TunnelRead for Http2TunnelRead {
     }
 }
 
+/// Data of a tunnel queued for its http2 stream. Its bytes stop counting as in flight once hyper takes it,
+/// which only happens when the flow control window of the stream lets it send more
+#[derive(Debug)]
+pub struct InflightChunk {
+    data: Bytes,
+    /// Trailers ending the stream instead of data
+    trailers: Option<HeaderMap>,
+    len: usize,
+    permits: u32,
+    semaphore: Arc<Semaphore>,
+}
+
+impl Drop for InflightChunk {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits as usize);
+        Metrics::sub(&METRICS.tunnel_buffered_bytes, self.len as u64);
+    }
+}
+
+/// Create the body of an http2 stream, along with its writer, that never holds more than `max_inflight` bytes not yet sent.
+/// A peer slower than the local side makes the writer wait, instead of buffering the whole tunnel in memory
+pub fn body_channel(
+    max_inflight: usize,
+) -> (
+    Http2TunnelWrite,
+    impl Stream<Item = anyhow::Result<Frame<Bytes>>> + Debug + Send + 'static,
+) {
+    let max_inflight = max_inflight.clamp(MAX_PACKET_LENGTH, Semaphore::MAX_PERMITS.min(u32::MAX as usize));
+    let (tx, rx) = mpsc::channel::<InflightChunk>(1024);
+    let writer = Http2TunnelWrite {
+        inner: Some(tx),
+        buf: BytesMut::with_capacity(max_inflight.min(MAX_PACKET_LENGTH * 20)), // ~ 1Mb
+        inflight: Arc::new(Semaphore::new(max_inflight)),
+        max_inflight: max_inflight as u32,
+    };
+    let body = ReceiverStream::new(rx).map(|mut chunk| -> anyhow::Result<Frame<Bytes>> {
+        Ok(match chunk.trailers.take() {
+            Some(trailers) => Frame::trailers(trailers),
+            None => Frame::data(std::mem::take(&mut chunk.data)),
+        })
+    });
+
+    (writer, body)
+}
+
 pub struct Http2TunnelWrite {
-    inner: mpsc::Sender<Bytes>,
+    /// None once half-closed, which ends the body of the stream
+    inner: Option<mpsc::Sender<InflightChunk>>,
     buf: BytesMut,
+    inflight: Arc<Semaphore>,
+    max_inflight: u32,
 }
 
 impl Http2TunnelWrite {
-    pub fn new(inner: mpsc::Sender<Bytes>) -> Self {
-        Self {
-            inner,
-            buf: BytesMut::with_capacity(MAX_PACKET_LENGTH * 20), // ~ 1Mb
+    /// End the body of the stream with `trailers`, once the data still queued is sent
+    pub(super) async fn end_with_trailers(&mut self, trailers: HeaderMap) -> Result<(), io::Error> {
+        let Some(inner) = self.inner.take() else {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "closed"));
+        };
+        let chunk = InflightChunk {
+            data: Bytes::new(),
+            trailers: Some(trailers),
+            len: 0,
+            permits: 0,
+            semaphore: self.inflight.clone(),
+        };
+        match inner.send(chunk).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
         }
     }
 }
@@ -89,8 +168,27 @@ impl TunnelWrite for Http2TunnelWrite {
     }
 
     async fn write(&mut self) -> Result<(), io::Error> {
+        let Some(inner) = &self.inner else {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "closed"));
+        };
         let data = self.buf.split().freeze();
-        let ret = match self.inner.send(data).await {
+        // A chunk bigger than the max in flight waits for the stream to be drained, but cannot wait forever
+        let len = data.len();
+        let permits = u32::try_from(len).unwrap_or(u32::MAX).min(self.max_inflight);
+        match self.inflight.acquire_many(permits).await {
+            Ok(permits) => permits.forget(),
+            Err(err) => return Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+        }
+        Metrics::add(&METRICS.tunnel_buffered_bytes, len as u64);
+
+        let chunk = InflightChunk {
+            data,
+            trailers: None,
+            len,
+            permits,
+            semaphore: self.inflight.clone(),
+        };
+        let ret = match inner.send(chunk).await {
             Ok(_) => Ok(()),
             Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
         };
@@ -111,6 +209,18 @@ impl TunnelWrite for Http2TunnelWrite {
         Ok(())
     }
 
+    async fn half_close(&mut self) -> Result<(), io::Error> {
+        // Dropping the sender ends the body, once the data still queued is sent
+        self.inner = None;
+        Ok(())
+    }
+
+    async fn abort(&mut self, reason: CloseReason) -> Result<(), io::Error> {
+        let mut trailers = HeaderMap::new();
+        reason.encode_trailer(&mut trailers);
+        self.end_with_trailers(trailers).await
+    }
+
     fn pending_operations_notify(&mut self) -> Arc<Notify> {
         Arc::new(Notify::new())
     }
@@ -120,16 +230,189 @@ impl TunnelWrite for Http2TunnelWrite {
     }
 }
 
+/// Time to wait for the answer of the server to a tunnel request, before considering that a proxy/CDN in the middle
+/// buffers the request body. Longer than the default timeout of the server to connect to the destination
+pub(crate) const STALL_TIMEOUT: Duration = Duration::from_secs(15);
+/// Bounds of the settings of HTTP/2, RFC 9113 section 6.5.2
+const H2_MIN_FRAME_SIZE: usize = 16 * 1024;
+const H2_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024 - 1;
+const H2_MAX_WINDOW_SIZE: usize = (1 << 31) - 1;
+
+/// HTTP/2 negotiates the frame size and the flow control by itself, each side advertising in its settings the biggest
+/// frame and the most unacknowledged data it accepts to receive, which its peer respects. They come from
+/// --websocket-max-frame-size and --max-inflight-per-tunnel
+pub fn h2_max_frame_size(max_frame_size: usize) -> u32 {
+    max_frame_size.clamp(H2_MIN_FRAME_SIZE, H2_MAX_FRAME_SIZE) as u32
+}
+
+pub fn h2_window_size(max_inflight: usize) -> u32 {
+    max_inflight.min(H2_MAX_WINDOW_SIZE) as u32
+}
+
 pub async fn connect(
     request_id: Uuid,
     client: &WsClient<impl crate::TokioExecutorRef>,
     dest_addr: &RemoteAddr,
+    early_data: &[u8],
 ) -> anyhow::Result<(Http2TunnelRead, Http2TunnelWrite, Parts)> {
-    let mut pooled_cnx = match client.cnx_pool.get().await {
-        Ok(cnx) => Ok(cnx),
-        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
-    }?;
+    let split = match client.config.http_split_requests {
+        SplitRequests::Never => false,
+        SplitRequests::Always => true,
+        SplitRequests::Auto => client.http_split_detected.load(Ordering::Relaxed),
+    };
+    if split {
+        return connect_split(request_id, client, dest_addr, early_data).await;
+    }
+
+    let path = client
+        .config
+        .camouflage
+        .upgrade_path(&client.config.http_upgrade_path_prefix);
+    let tunnel_token = tunnel_to_jwt_token(
+        request_id,
+        dest_addr,
+        client.label.as_deref(),
+        client.config.device_id.as_deref(),
+    );
+    let psk_proof = client
+        .config
+        .psk
+        .as_ref()
+        .map(|psk| psk.client_proof(&path, &tunnel_token));
+    let mut req = mk_request(client, Method::POST, &path).await?;
+    let headers = req.headers_mut();
+    headers.insert(COOKIE, HeaderValue::from_str(&tunnel_token)?);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if let Some(psk_proof) = &psk_proof {
+        headers.insert(PSK_HEADER, HeaderValue::from_str(psk_proof)?);
+    }
+    if let Some(early_data) = early_data::encode(early_data) {
+        headers.insert(EARLY_DATA_HEADER, early_data);
+    }
+    client.add_probe_interval(headers, dest_addr);
+    client.add_integrity_interval(headers, dest_addr);
+
+    let (mut request_sender, cnx_poller) = handshake(client, &mut req).await?;
+    let (writer, body) = body_channel(client.config.max_inflight_per_tunnel);
+    let req = req.map(|_| StreamBody::new(body));
+    debug!("with HTTP upgrade request {req:?}");
+
+    let response = request_sender.send_request(req);
+    let response = if client.config.http_split_requests == SplitRequests::Auto {
+        match tokio::time::timeout(client.http_stall_timeout, response).await {
+            Ok(response) => response,
+            Err(_) => {
+                cnx_poller.abort();
+                warn!(
+                    "Server did not answer the tunnel request after {}s, a proxy is likely buffering the requests. Splitting the requests of the tunnels from now on",
+                    client.http_stall_timeout.as_secs()
+                );
+                client.http_split_detected.store(true, Ordering::Relaxed);
+                return connect_split(request_id, client, dest_addr, early_data).await;
+            }
+        }
+    } else {
+        response.await
+    }
+    .with_context(|| format!("failed to send http2 request with the server {:?}", client.config.remote_addr))?;
+
+    let response = match check_response(client, response, psk_proof.as_deref()).await {
+        Ok(response) => response,
+        Err(err) => {
+            cnx_poller.abort();
+            return Err(err);
+        }
+    };
+    let (parts, body) = response.into_parts();
+    Ok((Http2TunnelRead::new(BodyStream::new(body), Some(cnx_poller)), writer, parts))
+}
+
+/// Receive the data of the tunnel in the response of a GET request, and send it in a series of POST requests, all
+/// multiplexed on the same connection. For proxies/CDN buffering the body of the requests
+async fn connect_split(
+    request_id: Uuid,
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    dest_addr: &RemoteAddr,
+    early_data: &[u8],
+) -> anyhow::Result<(Http2TunnelRead, Http2TunnelWrite, Parts)> {
+    let session_id = Uuid::new_v4();
+    let path = client
+        .config
+        .camouflage
+        .upgrade_path(&client.config.http_upgrade_path_prefix);
+    let tunnel_token = tunnel_to_jwt_token(
+        request_id,
+        dest_addr,
+        client.label.as_deref(),
+        client.config.device_id.as_deref(),
+    );
+    let psk_proof = client
+        .config
+        .psk
+        .as_ref()
+        .map(|psk| psk.client_proof(&path, &tunnel_token));
+
+    // The download request opens the tunnel, so it is the one carrying the tunnel token
+    let mut req = mk_request(client, Method::GET, &path).await?;
+    let headers = req.headers_mut();
+    headers.insert(SESSION_HEADER, HeaderValue::from_str(&session_id.simple().to_string())?);
+    headers.insert(COOKIE, HeaderValue::from_str(&tunnel_token)?);
+    if let Some(psk_proof) = &psk_proof {
+        headers.insert(PSK_HEADER, HeaderValue::from_str(psk_proof)?);
+    }
+    if let Some(early_data) = early_data::encode(early_data) {
+        headers.insert(EARLY_DATA_HEADER, early_data);
+    }
+    client.add_probe_interval(headers, dest_addr);
+    client.add_integrity_interval(headers, dest_addr);
+    let (mut request_sender, cnx_poller) = handshake(client, &mut req).await?;
+    let uri = req.uri().clone();
+    debug!("with HTTP download request {req:?}");
+    let response = request_sender
+        .send_request(req.map(|_| Full::new(Bytes::new())))
+        .await
+        .with_context(|| format!("failed to send http2 request with the server {:?}", client.config.remote_addr))?;
+    let response = match check_response(client, response, psk_proof.as_deref()).await {
+        Ok(response) => response,
+        Err(err) => {
+            cnx_poller.abort();
+            return Err(err);
+        }
+    };
 
+    let auth = match UploadAuth::new(&session_id, &response, client.config.psk.as_ref(), &path) {
+        Ok(auth) => auth,
+        Err(err) => {
+            cnx_poller.abort();
+            return Err(err);
+        }
+    };
+
+    let (writer, body) = body_channel(client.config.max_inflight_per_tunnel);
+    let mut req = mk_request(client, Method::POST, &path).await?;
+    // Sent on the same connection, so to the same host
+    *req.uri_mut() = uri;
+    req.headers_mut()
+        .insert(SESSION_HEADER, HeaderValue::from_str(&session_id.simple().to_string())?);
+    client.executor.spawn(
+        async move {
+            if let Err(err) = http1::upload_in_chunks(request_sender, req, auth, body).await {
+                warn!("Http2 upload of the tunnel failed: {err:?}");
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    let (parts, body) = response.into_parts();
+    Ok((Http2TunnelRead::new(BodyStream::new(body), Some(cnx_poller)), writer, parts))
+}
+
+/// Request to the server, with the headers the client sends with all its requests
+pub(super) async fn mk_request(
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    method: Method,
+    path: &str,
+) -> anyhow::Result<Request<()>> {
     // In http2 HOST header does not exist, it is explicitly set in the authority from the request uri
     let (headers_file, authority) =
         client
@@ -140,7 +423,8 @@ pub async fn connect(
                 let (host, headers) = headers_from_file(headers_file_path);
                 let host = if let Some((_, v)) = host {
                     match (client.config.remote_addr.scheme(), client.config.remote_addr.port()) {
-                        (TransportScheme::Http, 80) | (TransportScheme::Https, 443) => {
+                        (TransportScheme::Http | TransportScheme::Grpc, 80)
+                        | (TransportScheme::Https | TransportScheme::Grpcs, 443) => {
                             Some(v.to_str().unwrap_or("").to_string())
                         }
                         (_, port) => Some(format!("{}:{}", v.to_str().unwrap_or(""), port)),
@@ -153,58 +437,88 @@ pub async fn connect(
             });
 
     let mut req = Request::builder()
-        .method("POST")
+        .method(method)
         .uri(format!(
-            "{}://{}/{}/events",
-            client.config.remote_addr.scheme(),
+            "{}://{}{}",
+            client.config.remote_addr.scheme().uri_scheme(),
             authority
                 .as_deref()
                 .unwrap_or_else(|| client.config.http_header_host.to_str().unwrap_or("")),
-            &client.config.http_upgrade_path_prefix
+            path
         ))
-        .header(COOKIE, tunnel_to_jwt_token(request_id, dest_addr))
-        .header(CONTENT_TYPE, "application/json")
-        .version(hyper::Version::HTTP_2);
-
-    let headers = match req.headers_mut() {
-        Some(h) => h,
-        None => {
-            return Err(anyhow!(
+        .version(hyper::Version::HTTP_2)
+        .body(())
+        .with_context(|| {
+            format!(
                 "failed to build HTTP request to contact the server {:?}. Most likely path_prefix `{}` or http headers is not valid",
-                req,
-                client.config.http_upgrade_path_prefix
-            ));
-        }
-    };
+                client.config.remote_addr, client.config.http_upgrade_path_prefix
+            )
+        })?;
+
+    let headers = req.headers_mut();
     for (k, v) in &client.config.http_headers {
         let _ = headers.remove(k);
         headers.append(k, v.clone());
     }
+    headers.insert(CLIENT_VERSION_HEADER, client_version_header());
+    client.add_sticky_session(headers);
 
     if let Some(auth) = &client.config.http_upgrade_credentials {
         let _ = headers.remove(AUTHORIZATION);
         headers.append(AUTHORIZATION, auth.clone());
     }
 
+    if let Some(token_cache) = &client.config.oidc_token_cache {
+        let _ = headers.remove(AUTHORIZATION);
+        headers.append(
+            AUTHORIZATION,
+            oidc::bearer_from_cache(token_cache, &client.config.http_client_config()).await?,
+        );
+    }
+
     if let Some(headers_file) = headers_file {
         for (k, v) in headers_file {
             let _ = headers.remove(&k);
             headers.append(k, v);
         }
     }
+    client.config.camouflage.add_browser_headers(headers, None, false);
+
+    Ok(req)
+}
+
+/// Take a connection to the server from the pool, and drive it in the background until it is closed.
+/// The authority of `req` is the host the connection was opened for
+pub(super) async fn handshake<B>(
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    req: &mut Request<()>,
+) -> anyhow::Result<(hyper::client::conn::http2::SendRequest<B>, AbortHandle)>
+where
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let mut pooled_cnx = match client.cnx_pool.get().await {
+        Ok(cnx) => Ok(cnx),
+        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
+    }?;
 
-    let (tx, rx) = mpsc::channel::<Bytes>(1024);
-    let body = StreamBody::new(ReceiverStream::new(rx).map(|s| -> anyhow::Result<Frame<Bytes>> { Ok(Frame::data(s)) }));
-    let req = req.body(body).with_context(|| {
-        format!(
-            "failed to build HTTP request to contact the server {:?}",
-            client.config.remote_addr
-        )
-    })?;
-    debug!("with HTTP upgrade request {req:?}");
     let transport = pooled_cnx.deref_mut().take().unwrap();
-    let (mut request_sender, cnx) = hyper::client::conn::http2::Builder::new(TokioExecutor::new())
+    if let Some(host) = transport.host() {
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        *req.uri_mut() = format!(
+            "{}://{}{}",
+            client.config.remote_addr.scheme().uri_scheme(),
+            host.to_str()?,
+            path
+        )
+        .parse()?;
+    }
+    client.mark_transport(&transport);
+    let (request_sender, cnx) = hyper::client::conn::http2::Builder::new(TokioExecutor::new())
         .timer(TokioTimer::new())
+        .max_frame_size(h2_max_frame_size(client.config.websocket_max_frame_size))
+        // The windows of the client follow the bandwidth of the connection
         .adaptive_window(true)
         .keep_alive_interval(client.config.websocket_ping_frequency)
         .keep_alive_timeout(Duration::from_secs(10))
@@ -218,23 +532,67 @@ pub async fn connect(
         }
     });
 
-    let response = request_sender
-        .send_request(req)
-        .await
-        .with_context(|| format!("failed to send http2 request with the server {:?}", client.config.remote_addr))?;
+    Ok((request_sender, cnx_poller))
+}
 
+/// Check the server accepted the tunnel, and that it knows the pre-shared key
+pub(super) async fn check_response(
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    response: Response<Incoming>,
+    psk_proof: Option<&str>,
+) -> anyhow::Result<Response<Incoming>> {
     if !response.status().is_success() {
-        return Err(anyhow!(
-            "Http2 server rejected the connection: {:?}: {:?}",
-            response.status(),
-            String::from_utf8(response.into_body().collect().await?.to_bytes().to_vec()).unwrap_or_default()
-        ));
+        return Err(UpgradeRejected::from_response("Http2", response).await.into());
     }
 
-    let (parts, body) = response.into_parts();
-    Ok((
-        Http2TunnelRead::new(BodyStream::new(body), Some(cnx_poller)),
-        Http2TunnelWrite::new(tx),
-        parts,
-    ))
+    if let (Some(psk), Some(psk_proof)) = (&client.config.psk, psk_proof) {
+        let server_proof = response
+            .headers()
+            .get(PSK_HEADER)
+            .and_then(|header| header.to_str().ok())
+            .unwrap_or_default();
+        psk.verify_server_proof(psk_proof, server_proof)?;
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    #[tokio::test]
+    async fn test_body_channel_backpressure() {
+        let (mut writer, body) = body_channel(MAX_PACKET_LENGTH);
+        tokio::pin!(body);
+
+        writer.buf_mut().put_bytes(1, MAX_PACKET_LENGTH);
+        writer.write().await.unwrap();
+
+        // Nothing was sent yet, so the writer must wait before queuing more
+        writer.buf_mut().put_u8(2);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), writer.write())
+                .await
+                .is_err()
+        );
+
+        let frame = body.next().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap().len(), MAX_PACKET_LENGTH);
+        writer.buf_mut().put_u8(2);
+        tokio::time::timeout(Duration::from_millis(100), writer.write())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_h2_settings() {
+        assert_eq!(h2_max_frame_size(1024), H2_MIN_FRAME_SIZE as u32);
+        assert_eq!(h2_max_frame_size(64 * 1024), 64 * 1024);
+        assert_eq!(h2_max_frame_size(32 * 1024 * 1024), H2_MAX_FRAME_SIZE as u32);
+        assert_eq!(h2_window_size(1024 * 1024), 1024 * 1024);
+        assert_eq!(h2_window_size(usize::MAX), H2_MAX_WINDOW_SIZE as u32);
+    }
 }
@@ -0,0 +1,228 @@
+//! Experimental transport carrying the tunnel in the payload of ICMP echo request/reply, for networks that allow
+//! ping but nothing else. Only IPv4 is supported, and both the client and the server need to be allowed to open raw sockets
+//! (root or CAP_NET_RAW on linux).
+//! Every packet carries a marker with its direction, so the echo reply the kernel of the server sends by itself is ignored.
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::client::WsClient;
+use crate::tunnel::transport::datagram;
+use crate::tunnel::transport::datagram::{DatagramCarrier, DatagramTunnelRead, DatagramTunnelWrite, FRAME_HEADER_LEN};
+use anyhow::{Context, anyhow};
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::http::response::Parts;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+use url::Host;
+use uuid::Uuid;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_HEADER_LEN: usize = 8;
+const MAGIC: &[u8; 3] = b"wst";
+const DIRECTION_CLIENT: u8 = 0;
+const DIRECTION_SERVER: u8 = 1;
+const MARKER_LEN: usize = MAGIC.len() + 1;
+/// Stay well under the usual 1500 bytes MTU, as fragmented pings are often dropped
+const MAX_ICMP_DATA_LEN: usize = 1200;
+
+/// Maximum payload of a frame carried by a single echo packet
+pub const MAX_FRAME_PAYLOAD: usize = MAX_ICMP_DATA_LEN - MARKER_LEN - FRAME_HEADER_LEN;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoPacket {
+    /// Echo request are sent by the client, echo reply by the server
+    pub is_request: bool,
+    pub identifier: u16,
+    pub sequence: u16,
+    pub frame: Bytes,
+}
+
+impl EchoPacket {
+    pub fn encode(&self) -> Bytes {
+        let (icmp_type, direction) = if self.is_request {
+            (ICMP_ECHO_REQUEST, DIRECTION_CLIENT)
+        } else {
+            (ICMP_ECHO_REPLY, DIRECTION_SERVER)
+        };
+
+        let mut buf = BytesMut::with_capacity(ICMP_HEADER_LEN + MARKER_LEN + self.frame.len());
+        buf.put_u8(icmp_type);
+        buf.put_u8(0);
+        buf.put_u16(0);
+        buf.put_u16(self.identifier);
+        buf.put_u16(self.sequence);
+        buf.put_slice(MAGIC);
+        buf.put_u8(direction);
+        buf.put_slice(&self.frame);
+
+        let checksum = checksum(&buf);
+        buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+        buf.freeze()
+    }
+
+    /// Decode a packet received on a raw socket, which starts with the IPv4 header
+    pub fn decode(packet: &[u8]) -> Option<Self> {
+        let ip_header_len = (*packet.first()? & 0x0f) as usize * 4;
+        let icmp = packet.get(ip_header_len..)?;
+        if icmp.len() < ICMP_HEADER_LEN + MARKER_LEN || checksum(icmp) != 0 {
+            return None;
+        }
+
+        let is_request = match (icmp[0], icmp[1]) {
+            (ICMP_ECHO_REQUEST, 0) => true,
+            (ICMP_ECHO_REPLY, 0) => false,
+            _ => return None,
+        };
+        let data = &icmp[ICMP_HEADER_LEN..];
+        let expected_direction = if is_request { DIRECTION_CLIENT } else { DIRECTION_SERVER };
+        if data[..MAGIC.len()] != *MAGIC || data[MAGIC.len()] != expected_direction {
+            return None;
+        }
+
+        Some(Self {
+            is_request,
+            identifier: u16::from_be_bytes([icmp[4], icmp[5]]),
+            sequence: u16::from_be_bytes([icmp[6], icmp[7]]),
+            frame: Bytes::copy_from_slice(&data[MARKER_LEN..]),
+        })
+    }
+}
+
+/// Internet checksum (rfc1071). Computing it over data that contains a valid checksum returns 0
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Raw ICMPv4 socket, driven by tokio as if it was an udp socket
+pub fn raw_socket(bind: Ipv4Addr, so_mark: crate::somark::SoMark) -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))
+        .context("cannot open raw icmp socket, it requires root or CAP_NET_RAW")?;
+    socket.set_nonblocking(true)?;
+    so_mark.set_mark(SockRef::from(&socket))?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(bind, 0)).into())?;
+
+    Ok(UdpSocket::from_std(std::net::UdpSocket::from(socket))?)
+}
+
+struct IcmpCarrier {
+    socket: UdpSocket,
+    identifier: u16,
+    sequence: u16,
+    buf: Vec<u8>,
+}
+
+impl DatagramCarrier for IcmpCarrier {
+    fn max_payload(&self) -> usize {
+        MAX_FRAME_PAYLOAD
+    }
+
+    async fn exchange(&mut self, frame: Bytes) -> io::Result<Bytes> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let packet = EchoPacket {
+            is_request: true,
+            identifier: self.identifier,
+            sequence: self.sequence,
+            frame,
+        };
+        self.socket.send(&packet.encode()).await?;
+
+        // The raw socket receives every icmp packet of the host, skip the ones that are not for us
+        loop {
+            let len = self.socket.recv(&mut self.buf).await?;
+            match EchoPacket::decode(&self.buf[..len]) {
+                Some(reply)
+                    if !reply.is_request && reply.identifier == self.identifier && reply.sequence == self.sequence =>
+                {
+                    return Ok(reply.frame);
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+pub async fn connect(
+    request_id: Uuid,
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    dest_addr: &RemoteAddr,
+) -> anyhow::Result<(DatagramTunnelRead, DatagramTunnelWrite, Parts)> {
+    let server_ip = match client.config.remote_addr.host() {
+        Host::Ipv4(ip) => *ip,
+        Host::Ipv6(_) => return Err(anyhow!("icmp transport only supports IPv4")),
+        Host::Domain(domain) => client
+            .config
+            .dns_resolver
+            .lookup_host(domain, 0)
+            .await?
+            .into_iter()
+            .find_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(*addr.ip()),
+                SocketAddr::V6(_) => None,
+            })
+            .ok_or_else(|| anyhow!("cannot resolve an IPv4 address for {domain}"))?,
+    };
+
+    let socket = raw_socket(Ipv4Addr::UNSPECIFIED, client.config.socket_so_mark)?;
+    socket.connect((server_ip, 0)).await?;
+    let carrier = IcmpCarrier {
+        socket,
+        identifier: Uuid::now_v7().as_u128() as u16,
+        sequence: 0,
+        buf: vec![0; u16::MAX as usize],
+    };
+
+    datagram::connect(request_id, client, dest_addr, carrier)
+        .await
+        .with_context(|| format!("cannot open tunnel with icmp to {server_ip}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_ip_header(icmp: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45];
+        packet.extend_from_slice(&[0; 19]);
+        packet.extend_from_slice(icmp);
+        packet
+    }
+
+    #[test]
+    fn test_echo_packet_roundtrip() {
+        let packet = EchoPacket {
+            is_request: true,
+            identifier: 42,
+            sequence: 65535,
+            frame: Bytes::from_static(b"hello world"),
+        };
+        let encoded = packet.encode();
+        assert_eq!(checksum(&encoded), 0);
+        assert_eq!(EchoPacket::decode(&with_ip_header(&encoded)), Some(packet));
+    }
+
+    #[test]
+    fn test_echo_packet_rejects_kernel_reply() {
+        let request = EchoPacket {
+            is_request: true,
+            identifier: 1,
+            sequence: 2,
+            frame: Bytes::from_static(b"data"),
+        };
+        // The kernel answers with the same payload, only changing the type
+        let mut reply = request.encode().to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        reply[2..4].copy_from_slice(&[0, 0]);
+        let sum = checksum(&reply);
+        reply[2..4].copy_from_slice(&sum.to_be_bytes());
+
+        assert_eq!(EchoPacket::decode(&with_ip_header(&reply)), None);
+    }
+}
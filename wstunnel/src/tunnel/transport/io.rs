@@ -1,16 +1,28 @@
+use crate::metrics::{METRICS, Metrics};
+use crate::protocols;
+use crate::tunnel::protocol::Capabilities;
+use crate::tunnel::protocol::close_reason::CloseReason;
+#[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+use crate::tunnel::transport::datagram::{DatagramTunnelRead, DatagramTunnelWrite};
+use crate::tunnel::transport::grpc::{GrpcTunnelRead, GrpcTunnelWrite};
 use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite};
+#[cfg(feature = "ssh-transport")]
+use crate::tunnel::transport::ssh::{SshTunnelRead, SshTunnelWrite};
 use crate::tunnel::transport::websocket::{WebsocketTunnelRead, WebsocketTunnelWrite};
 use bytes::{BufMut, BytesMut};
 use futures_util::{FutureExt, pin_mut};
+use socket2::SockRef;
 use std::future::Future;
 use std::io::ErrorKind;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
 use tokio::select;
 use tokio::sync::{Notify, oneshot};
 use tokio::time::Instant;
+use tokio_util::either::Either;
 use tracing::log::debug;
 use tracing::{error, info, warn};
 
@@ -21,11 +33,18 @@ pub trait TunnelWrite: Send + 'static {
     fn write(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
     fn ping(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
     fn close(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+    /// Tell the peer that nothing more will be written, while still reading what it sends. Unsupported by the
+    /// transports that cannot tell it, for which the whole tunnel is closed instead
+    fn half_close(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+    /// Close the tunnel telling the peer why the local stream failed. Unsupported by the transports that cannot tell
+    /// it, for which the tunnel is closed normally instead
+    fn abort(&mut self, reason: CloseReason) -> impl Future<Output = Result<(), std::io::Error>> + Send;
     fn pending_operations_notify(&mut self) -> Arc<Notify>;
     fn handle_pending_operations(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
 }
 
 pub trait TunnelRead: Send + 'static {
+    /// Copy the next data of the peer into `writer`. Fails with `BrokenPipe` once the peer has nothing more to send
     fn copy(
         &mut self,
         writer: impl AsyncWrite + Unpin + Send,
@@ -35,6 +54,11 @@ pub trait TunnelRead: Send + 'static {
 pub enum TunnelReader {
     Websocket(WebsocketTunnelRead),
     Http2(Http2TunnelRead),
+    Grpc(GrpcTunnelRead),
+    #[cfg(feature = "ssh-transport")]
+    Ssh(SshTunnelRead),
+    #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+    Datagram(DatagramTunnelRead),
 }
 
 impl TunnelRead for TunnelReader {
@@ -42,6 +66,11 @@ impl TunnelRead for TunnelReader {
         match self {
             Self::Websocket(s) => s.copy(writer).await,
             Self::Http2(s) => s.copy(writer).await,
+            Self::Grpc(s) => s.copy(writer).await,
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh(s) => s.copy(writer).await,
+            #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+            Self::Datagram(s) => s.copy(writer).await,
         }
     }
 }
@@ -49,6 +78,24 @@ impl TunnelRead for TunnelReader {
 pub enum TunnelWriter {
     Websocket(WebsocketTunnelWrite),
     Http2(Http2TunnelWrite),
+    Grpc(GrpcTunnelWrite),
+    #[cfg(feature = "ssh-transport")]
+    Ssh(SshTunnelWrite),
+    #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+    Datagram(DatagramTunnelWrite),
+}
+
+impl TunnelWriter {
+    /// Whether the transport can tell the peer that nothing more will be written, see [`TunnelWrite::half_close`]
+    pub const fn can_half_close(&self) -> bool {
+        match self {
+            Self::Websocket(_) | Self::Http2(_) | Self::Grpc(_) => true,
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh(_) => true,
+            #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+            Self::Datagram(_) => false,
+        }
+    }
 }
 
 impl TunnelWrite for TunnelWriter {
@@ -56,6 +103,11 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.buf_mut(),
             Self::Http2(s) => s.buf_mut(),
+            Self::Grpc(s) => s.buf_mut(),
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh(s) => s.buf_mut(),
+            #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+            Self::Datagram(s) => s.buf_mut(),
         }
     }
 
@@ -63,6 +115,11 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.write().await,
             Self::Http2(s) => s.write().await,
+            Self::Grpc(s) => s.write().await,
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh(s) => s.write().await,
+            #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+            Self::Datagram(s) => s.write().await,
         }
     }
 
@@ -70,6 +127,11 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.ping().await,
             Self::Http2(s) => s.ping().await,
+            Self::Grpc(s) => s.ping().await,
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh(s) => s.ping().await,
+            #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+            Self::Datagram(s) => s.ping().await,
         }
     }
 
@@ -77,6 +139,35 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.close().await,
             Self::Http2(s) => s.close().await,
+            Self::Grpc(s) => s.close().await,
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh(s) => s.close().await,
+            #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+            Self::Datagram(s) => s.close().await,
+        }
+    }
+
+    async fn half_close(&mut self) -> Result<(), std::io::Error> {
+        match self {
+            Self::Websocket(s) => s.half_close().await,
+            Self::Http2(s) => s.half_close().await,
+            Self::Grpc(s) => s.half_close().await,
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh(s) => s.half_close().await,
+            #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+            Self::Datagram(s) => s.half_close().await,
+        }
+    }
+
+    async fn abort(&mut self, reason: CloseReason) -> Result<(), std::io::Error> {
+        match self {
+            Self::Websocket(s) => s.abort(reason).await,
+            Self::Http2(s) => s.abort(reason).await,
+            Self::Grpc(s) => s.abort(reason).await,
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh(s) => s.abort(reason).await,
+            #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+            Self::Datagram(s) => s.abort(reason).await,
         }
     }
 
@@ -84,6 +175,11 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.pending_operations_notify(),
             Self::Http2(s) => s.pending_operations_notify(),
+            Self::Grpc(s) => s.pending_operations_notify(),
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh(s) => s.pending_operations_notify(),
+            #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+            Self::Datagram(s) => s.pending_operations_notify(),
         }
     }
 
@@ -91,14 +187,107 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.handle_pending_operations().await,
             Self::Http2(s) => s.handle_pending_operations().await,
+            Self::Grpc(s) => s.handle_pending_operations().await,
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh(s) => s.handle_pending_operations().await,
+            #[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+            Self::Datagram(s) => s.handle_pending_operations().await,
+        }
+    }
+}
+
+/// Links the two directions of a tunnel, so that one of them stops when the other one does. With half-close, the end of
+/// the local stream is told to the peer instead, and the tunnel stops once both sides are done sending. With error codes,
+/// the failure of the local stream is told to the peer instead of closing the tunnel cleanly
+pub fn close_channel(capabilities: Capabilities) -> (CloseTx, CloseRx) {
+    let (close_tx, close_rx) = oneshot::channel();
+    let (peer_eof_tx, peer_eof_rx) = if capabilities.contains(Capabilities::HALF_CLOSE) {
+        let (tx, rx) = oneshot::channel();
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+    (
+        CloseTx {
+            closed: close_tx,
+            peer_eof: peer_eof_rx,
+            error_codes: capabilities.contains(Capabilities::ERROR_CODES),
+        },
+        CloseRx {
+            closed: close_rx,
+            peer_eof: peer_eof_tx,
+            local_reset: LocalReset::default(),
+        },
+    )
+}
+
+/// End of the local => remote direction, dropped when it stops
+pub struct CloseTx {
+    closed: oneshot::Sender<()>,
+    /// Resolved once the peer has nothing more to send, with half-close
+    peer_eof: Option<oneshot::Receiver<()>>,
+    error_codes: bool,
+}
+
+/// End of the local <= remote direction, dropped when it stops
+pub struct CloseRx {
+    closed: oneshot::Receiver<()>,
+    peer_eof: Option<oneshot::Sender<()>>,
+    local_reset: LocalReset,
+}
+
+impl CloseRx {
+    /// Reset this connection when the peer tells that its own local stream failed
+    pub fn with_local_reset(mut self, local_reset: LocalReset) -> Self {
+        self.local_reset = local_reset;
+        self
+    }
+}
+
+/// TCP connection of the local side of a tunnel, to reset when the peer tells that its own local stream failed. The
+/// other local streams have nothing to reset
+#[derive(Default)]
+pub struct LocalReset(Option<socket2::Socket>);
+
+impl LocalReset {
+    pub fn new(socket: SockRef) -> Self {
+        // A handle of its own, as the writer is wrapped and moved away before the tunnel is forwarded
+        Self(socket.try_clone().ok())
+    }
+
+    fn reset(self) {
+        if let Some(socket) = self.0
+            && let Err(err) = protocols::tcp::reset(SockRef::from(&socket))
+        {
+            debug!("cannot reset the local tcp connection: {err}");
         }
     }
 }
 
+/// Write half of the local stream of a tunnel, handing out the TCP connection it writes to, if any, to reset it
+pub trait Resettable {
+    fn local_reset(&self) -> LocalReset {
+        LocalReset::default()
+    }
+}
+
+impl Resettable for OwnedWriteHalf {
+    fn local_reset(&self) -> LocalReset {
+        LocalReset::new(SockRef::from(self.as_ref()))
+    }
+}
+
+impl<T> Resettable for tokio::io::WriteHalf<T> {}
+impl Resettable for tokio::io::DuplexStream {}
+#[cfg(unix)]
+impl Resettable for tokio::net::unix::OwnedWriteHalf {}
+#[cfg(unix)]
+impl Resettable for tokio_fd::AsyncFd {}
+
 pub async fn propagate_local_to_remote(
     local_rx: impl AsyncRead,
     mut ws_tx: impl TunnelWrite,
-    mut close_tx: oneshot::Sender<()>,
+    close_tx: CloseTx,
     ping_frequency: Option<Duration>,
 ) -> anyhow::Result<()> {
     let _guard = scopeguard::guard((), |_| {
@@ -112,6 +301,23 @@ pub async fn propagate_local_to_remote(
     let frequency = ping_frequency.unwrap_or(Duration::from_secs(3600 * 24));
     let start_at = Instant::now().checked_add(frequency).unwrap_or_else(Instant::now);
     let timeout = tokio::time::interval_at(start_at, frequency);
+    let CloseTx {
+        closed: mut close_tx,
+        peer_eof,
+        error_codes,
+    } = close_tx;
+    let half_close = peer_eof.is_some();
+    let peer_eof = async move {
+        match peer_eof {
+            Some(peer_eof) => {
+                let _ = peer_eof.await;
+            }
+            None => std::future::pending().await,
+        }
+    };
+    // With half-close, the local stream is done once it told the peer, the tunnel stops once the peer is done too
+    let mut local_eof = false;
+    let mut failure = None;
     let should_close = close_tx.closed().fuse();
     let notify = ws_tx.pending_operations_notify();
     let mut has_pending_operations = notify.notified();
@@ -119,6 +325,7 @@ pub async fn propagate_local_to_remote(
 
     pin_mut!(timeout);
     pin_mut!(should_close);
+    pin_mut!(peer_eof);
     pin_mut!(local_rx);
     loop {
         debug_assert!(
@@ -141,22 +348,52 @@ pub async fn propagate_local_to_remote(
                 }
             },
 
-            read_len = local_rx.read_buf(ws_tx.buf_mut()) => read_len,
+            read_len = local_rx.read_buf(ws_tx.buf_mut()), if !local_eof => read_len,
 
             _ = &mut should_close => break,
 
+            _ = &mut peer_eof, if local_eof => {
+                debug!("Both sides of the tunnel are done sending");
+                break;
+            }
+
             _ = timeout.tick(), if ping_frequency.is_some() => {
                 debug!("sending ping to keep connection alive");
-                ws_tx.ping().await?;
+                if let Err(err) = ws_tx.ping().await {
+                    if err.kind() == ErrorKind::ConnectionAborted {
+                        Metrics::inc(&METRICS.tunnels_reaped_dead_peer);
+                    }
+                    return Err(err.into());
+                }
                 continue;
             }
         };
 
         let _read_len = match read_len {
+            Ok(0) if half_close => match ws_tx.half_close().await {
+                Ok(()) => {
+                    debug!("Local side is done sending, telling the remote side");
+                    local_eof = true;
+                    continue;
+                }
+                Err(err) if err.kind() == ErrorKind::Unsupported => break,
+                Err(err) => {
+                    warn!("error while half-closing tx tunnel {}", err);
+                    break;
+                }
+            },
             Ok(0) => break,
             Ok(read_len) => read_len,
             Err(err) => {
-                warn!("error while reading incoming bytes from local tx tunnel: {}", err);
+                // Tcp keepalive gave up on the peer
+                if err.kind() == ErrorKind::TimedOut {
+                    Metrics::inc(&METRICS.tunnels_reaped_dead_peer);
+                }
+                let reason = CloseReason::of_io_error(&err);
+                if reason.is_failure() {
+                    warn!("error while reading incoming bytes from local tx tunnel: {}", err);
+                }
+                failure = Some(reason);
                 break;
             }
         };
@@ -168,8 +405,18 @@ pub async fn propagate_local_to_remote(
         }
     }
 
-    // Send normal close
-    let _ = ws_tx.close().await;
+    // Tell the peer why the local stream failed, so it resets its own, or send normal close
+    match failure.filter(|_| error_codes) {
+        Some(reason) => {
+            info!("Local side closed with {reason}, telling the remote side");
+            if ws_tx.abort(reason).await.is_err() {
+                let _ = ws_tx.close().await;
+            }
+        }
+        None => {
+            let _ = ws_tx.close().await;
+        }
+    }
 
     Ok(())
 }
@@ -177,21 +424,63 @@ pub async fn propagate_local_to_remote(
 pub async fn propagate_remote_to_local(
     local_tx: impl AsyncWrite + Send,
     mut ws_rx: impl TunnelRead,
-    mut close_rx: oneshot::Receiver<()>,
+    close_rx: CloseRx,
 ) -> anyhow::Result<()> {
     let _guard = scopeguard::guard((), |_| {
         info!("Closing local <= remote tunnel");
     });
 
+    let CloseRx {
+        closed: mut close_rx,
+        mut peer_eof,
+        mut local_reset,
+    } = close_rx;
+    // Once the peer is done sending, the transport is still read for its control messages, i.e: websocket pings,
+    // until it is closed for good
+    let mut remote_eof = false;
+    let mut reading = true;
     pin_mut!(local_tx);
     loop {
+        let writer = if remote_eof {
+            Either::Right(tokio::io::sink())
+        } else {
+            Either::Left(&mut local_tx)
+        };
         let msg = select! {
             biased;
-            msg = ws_rx.copy(&mut local_tx) => msg,
+            msg = ws_rx.copy(writer), if reading => msg,
             _ = &mut close_rx => break,
         };
 
+        match &msg {
+            Err(err) if err.kind() == ErrorKind::BrokenPipe && remote_eof => {
+                reading = false;
+                continue;
+            }
+            Err(err) if err.kind() == ErrorKind::BrokenPipe && peer_eof.is_some() => {
+                debug!("Remote side is done sending, shutting down the local side");
+                remote_eof = true;
+                if let Err(err) = local_tx.shutdown().await {
+                    debug!("error while shutting down local tx {err}");
+                }
+                if let Some(peer_eof) = peer_eof.take() {
+                    let _ = peer_eof.send(());
+                }
+                continue;
+            }
+            _ => {}
+        }
+
         if let Err(err) = msg {
+            if let Some(reason) = CloseReason::from_io_error(&err) {
+                if reason.is_failure() {
+                    info!("Remote side closed the tunnel with {reason}, resetting the local side");
+                    std::mem::take(&mut local_reset).reset();
+                } else {
+                    info!("Remote side closed the tunnel with {reason}");
+                }
+                break;
+            }
             match err.kind() {
                 ErrorKind::NotConnected => debug!("Connection closed frame received"),
                 ErrorKind::BrokenPipe => debug!("Remote side closed connection"),
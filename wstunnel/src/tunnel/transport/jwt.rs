@@ -1,7 +1,7 @@
-use crate::tunnel::{LocalProtocol, RemoteAddr};
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use crate::tunnel::protocol::{CAPABILITIES, PROTOCOL_VERSIONS};
+use crate::tunnel::{AccessList, LocalProtocol, RemoteAddr};
+use jsonwebtoken::{Algorithm, EncodingKey, Header, TokenData};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::ops::Deref;
 use std::sync::LazyLock;
 use std::time::SystemTime;
@@ -18,55 +18,84 @@ static JWT_KEY: LazyLock<(Header, EncodingKey)> = LazyLock::new(|| {
     (Header::new(Algorithm::HS256), EncodingKey::from_secret(&now))
 });
 
-static JWT_DECODE: LazyLock<(Validation, DecodingKey)> = LazyLock::new(|| {
-    let mut validation = Validation::new(Algorithm::HS256);
-    validation.required_spec_claims = HashSet::with_capacity(0);
-    validation.insecure_disable_signature_validation();
-    (validation, DecodingKey::from_secret(b"champignonfrais"))
-});
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JwtTunnelConfig {
-    pub id: String,       // tunnel id
+    pub id: String, // tunnel id
+    #[serde(deserialize_with = "deserialize_protocol")]
     pub p: LocalProtocol, // protocol to use
-    pub r: String,        // remote host
-    pub rp: u16,          // remote port
+    pub r: String,  // remote host
+    pub rp: u16,    // remote port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub l: Option<String>, // label of the tunnel, only used to tag it in logs and metrics
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub d: Option<String>, // device id of the client, stable across its restarts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<Vec<u8>>, // protocol versions spoken by the client, only 1 if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub c: Option<String>, // capabilities of the client, comma separated. Not told by older clients
+}
+
+/// ReverseTcp used to be a unit variant, keep accepting it from older clients
+fn deserialize_protocol<'de, D: Deserializer<'de>>(deserializer: D) -> Result<LocalProtocol, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(variant) if variant == "ReverseTcp" => Ok(LocalProtocol::ReverseTcp {
+            resume: None,
+            idle_timeout: None,
+            v6only: None,
+            access: AccessList::default(),
+            keepalive: None,
+        }),
+        value => serde_json::from_value(value).map_err(serde::de::Error::custom),
+    }
 }
 
 impl JwtTunnelConfig {
-    fn new(request_id: Uuid, dest: &RemoteAddr) -> Self {
+    fn new(request_id: Uuid, dest: &RemoteAddr, label: Option<&str>, device: Option<&str>) -> Self {
         Self {
             id: request_id.to_string(),
             p: match dest.protocol {
                 LocalProtocol::Tcp { .. } => dest.protocol.clone(),
                 LocalProtocol::Udp { .. } => dest.protocol.clone(),
-                LocalProtocol::ReverseTcp => dest.protocol.clone(),
+                LocalProtocol::Sctp => dest.protocol.clone(),
+                LocalProtocol::ReverseTcp { .. } => dest.protocol.clone(),
                 LocalProtocol::ReverseUdp { .. } => dest.protocol.clone(),
                 LocalProtocol::ReverseSocks5 { .. } => dest.protocol.clone(),
                 LocalProtocol::ReverseUnix { .. } => dest.protocol.clone(),
                 LocalProtocol::ReverseHttpProxy { .. } => dest.protocol.clone(),
+                LocalProtocol::ReverseHttpIngress { .. } => dest.protocol.clone(),
+                LocalProtocol::Mux => dest.protocol.clone(),
                 LocalProtocol::TProxyTcp => unreachable!("cannot use tproxy tcp as destination protocol"),
                 LocalProtocol::TProxyUdp { .. } => unreachable!("cannot use tproxy udp as destination protocol"),
                 LocalProtocol::Stdio { .. } => unreachable!("cannot use stdio as destination protocol"),
+                LocalProtocol::StdioUdp { .. } => unreachable!("cannot use stdio udp as destination protocol"),
                 LocalProtocol::Unix { .. } => unreachable!("canont use unix as destination protocol"),
+                LocalProtocol::Vsock { .. } => unreachable!("cannot use vsock as destination protocol"),
                 LocalProtocol::Socks5 { .. } => unreachable!("cannot use socks5 as destination protocol"),
                 LocalProtocol::HttpProxy { .. } => unreachable!("cannot use http proxy as destination protocol"),
+                LocalProtocol::ReverseEncryptedDns { .. } => {
+                    unreachable!("cannot use encrypted dns as destination protocol")
+                }
             },
             r: dest.host.to_string(),
             rp: dest.port,
+            l: label.map(str::to_string),
+            d: device.map(str::to_string),
+            v: Some(PROTOCOL_VERSIONS.to_vec()),
+            c: Some(CAPABILITIES.to_string()),
         }
     }
 }
 
-pub fn tunnel_to_jwt_token(request_id: Uuid, tunnel: &RemoteAddr) -> String {
-    let cfg = JwtTunnelConfig::new(request_id, tunnel);
+pub fn tunnel_to_jwt_token(request_id: Uuid, tunnel: &RemoteAddr, label: Option<&str>, device: Option<&str>) -> String {
+    let cfg = JwtTunnelConfig::new(request_id, tunnel, label, device);
     let (alg, secret) = JWT_KEY.deref();
     jsonwebtoken::encode(alg, &cfg, secret).unwrap_or_default()
 }
 
+/// The token is signed with a random key of the client, only to be a valid jwt. Its claims are not authenticated, the
+/// server must not trust them more than the rest of the upgrade request
 pub fn jwt_token_to_tunnel(token: &str) -> anyhow::Result<TokenData<JwtTunnelConfig>> {
-    let (validation, decode_key) = JWT_DECODE.deref();
-    let jwt: TokenData<JwtTunnelConfig> = jsonwebtoken::decode(token, decode_key, validation)?;
+    let jwt: TokenData<JwtTunnelConfig> = jsonwebtoken::dangerous::insecure_decode(token)?;
     Ok(jwt)
 }
 
@@ -80,3 +109,51 @@ impl TryFrom<JwtTunnelConfig> for RemoteAddr {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_unit_reverse_tcp() {
+        let jwt: JwtTunnelConfig =
+            serde_json::from_str(r#"{"id":"1","p":"ReverseTcp","r":"localhost","rp":8080}"#).unwrap();
+        assert_eq!(
+            jwt.p,
+            LocalProtocol::ReverseTcp {
+                resume: None,
+                idle_timeout: None,
+                v6only: None,
+                access: AccessList::default(),
+                keepalive: None,
+            }
+        );
+        assert_eq!(jwt.l, None);
+        assert_eq!(jwt.d, None);
+        assert_eq!(jwt.v, None);
+        assert_eq!(jwt.c, None);
+    }
+
+    #[test]
+    fn test_label_and_device_round_trip() {
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Udp { timeout: None },
+            host: Host::Domain("localhost".to_string()),
+            port: 53,
+        };
+        let token = tunnel_to_jwt_token(Uuid::from_u128(1), &remote, Some("ci-job-1234"), Some("sensor-42"));
+        let jwt = jwt_token_to_tunnel(&token).unwrap();
+        assert_eq!(jwt.claims.l.as_deref(), Some("ci-job-1234"));
+        assert_eq!(jwt.claims.d.as_deref(), Some("sensor-42"));
+        assert_eq!(jwt.claims.v.as_deref(), Some(PROTOCOL_VERSIONS));
+        assert_eq!(
+            jwt.claims.c.as_deref(),
+            Some("mux,resume,error-codes,probe,half-close,integrity")
+        );
+
+        let token = tunnel_to_jwt_token(Uuid::from_u128(1), &remote, None, None);
+        let jwt = jwt_token_to_tunnel(&token).unwrap();
+        assert_eq!(jwt.claims.l, None);
+        assert_eq!(jwt.claims.d, None);
+    }
+}
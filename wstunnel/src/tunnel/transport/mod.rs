@@ -1,3 +1,4 @@
+use crate::config::secret::mark_sensitive_header;
 use hyper::header::HOST;
 use hyper::http::{HeaderName, HeaderValue};
 use std::io::{BufRead, BufReader};
@@ -6,16 +7,38 @@ use std::str::FromStr;
 
 use tracing::error;
 
+pub mod buffer_pool;
+#[cfg(any(feature = "dns-transport", feature = "icmp-transport"))]
+pub mod datagram;
+#[cfg(feature = "dns-transport")]
+pub mod dns;
+pub mod early_data;
+pub mod grpc;
+pub mod http1;
 pub mod http2;
+#[cfg(feature = "icmp-transport")]
+pub mod icmp;
 pub mod io;
 mod jwt;
+pub mod obfuscation;
+mod psk;
+mod rejected;
+#[cfg(feature = "ssh-transport")]
+pub mod ssh;
+mod sticky;
 mod types;
 pub mod websocket;
 
+pub use early_data::EARLY_DATA_HEADER;
 pub use jwt::JWT_HEADER_PREFIX;
 pub use jwt::JwtTunnelConfig;
 pub use jwt::jwt_token_to_tunnel;
 pub use jwt::tunnel_to_jwt_token;
+pub use psk::PSK_HEADER;
+pub use psk::PreSharedKey;
+pub use psk::ReplayCache;
+pub use rejected::{STANDBY_HEADER, UpgradeRejected};
+pub use sticky::{STICKY_SESSION_HEADER, StickySession};
 pub use types::TransportAddr;
 pub use types::TransportScheme;
 
@@ -37,7 +60,8 @@ pub fn headers_from_file(path: &Path) -> (Option<(HeaderName, HeaderValue)>, Vec
             let line = line.ok()?;
             let (header, value) = line.split_once(':')?;
             let header = HeaderName::from_str(header.trim()).ok()?;
-            let value = HeaderValue::from_str(value.trim()).ok()?;
+            let mut value = HeaderValue::from_str(value.trim()).ok()?;
+            mark_sensitive_header(&header, &mut value);
             if header == HOST {
                 host_header = Some((header, value));
                 return None;
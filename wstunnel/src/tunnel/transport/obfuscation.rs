@@ -0,0 +1,98 @@
+//! obfuscation - random padding and timing jitter of the websocket frames, to blur the sizes and the timings that
+//! traffic analysis uses to classify the flows.
+//! The padding travels in the payload of ping frames, that every websocket peer answers without looking at it, so it
+//! needs nothing from the other side. The pongs echo it back, and are ignored too
+use rand::Rng;
+use std::time::Duration;
+
+/// Control frames cannot carry more than 125 bytes
+pub const MAX_PADDING_FRAME_LEN: usize = 125;
+/// Most padding sent after a single write, so the credit earned by a big transfer is not spent in a single burst
+const MAX_PADDING_LEN: usize = 8 * MAX_PADDING_FRAME_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficObfuscation {
+    /// Padding added to the traffic of the tunnels, in percent of its size
+    pub padding_percent: u8,
+    /// Maximum random delay before each write
+    pub max_jitter: Duration,
+}
+
+impl TrafficObfuscation {
+    pub fn jitter(&self) -> Duration {
+        if self.max_jitter.is_zero() {
+            return Duration::ZERO;
+        }
+        rand::rng().random_range(Duration::ZERO..=self.max_jitter)
+    }
+}
+
+/// Padding budget of a tunnel, earned by the data it sends
+#[derive(Debug, Default)]
+pub struct Padding {
+    /// In hundredths of bytes, for the small writes to earn some credit too
+    credit: usize,
+}
+
+impl Padding {
+    /// Payloads of the padding frames to send after `data_len` bytes of data. A random part of the credit is spent each
+    /// time, so the padding never exceeds the percentage of the traffic but the frames sizes vary
+    pub fn frames(&mut self, padding_percent: u8, data_len: usize) -> Vec<Vec<u8>> {
+        self.credit = self
+            .credit
+            .saturating_add(data_len.saturating_mul(padding_percent as usize));
+        let available = (self.credit / 100).min(MAX_PADDING_LEN);
+        if available == 0 {
+            return Vec::new();
+        }
+
+        let mut rng = rand::rng();
+        let mut len = rng.random_range(0..=available);
+        self.credit -= len * 100;
+        let mut frames = Vec::with_capacity(len.div_ceil(MAX_PADDING_FRAME_LEN));
+        while len > 0 {
+            let mut payload = vec![0; len.min(MAX_PADDING_FRAME_LEN)];
+            rng.fill(payload.as_mut_slice());
+            len -= payload.len();
+            frames.push(payload);
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padding_stays_under_budget() {
+        let mut padding = Padding::default();
+        let mut padded = 0;
+        for _ in 0..10_000 {
+            for frame in padding.frames(10, 1000) {
+                assert!(!frame.is_empty() && frame.len() <= MAX_PADDING_FRAME_LEN);
+                padded += frame.len();
+            }
+        }
+        assert!(padded <= 10_000 * 1000 / 10);
+        assert!(padded > 10_000 * 1000 / 20);
+
+        assert!(Padding::default().frames(10, 5).is_empty());
+    }
+
+    #[test]
+    fn test_jitter() {
+        let obfuscation = TrafficObfuscation {
+            padding_percent: 0,
+            max_jitter: Duration::from_millis(20),
+        };
+        for _ in 0..100 {
+            assert!(obfuscation.jitter() <= Duration::from_millis(20));
+        }
+        let obfuscation = TrafficObfuscation {
+            padding_percent: 0,
+            max_jitter: Duration::ZERO,
+        };
+        assert_eq!(obfuscation.jitter(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,302 @@
+//! Pre-shared key authentication of the upgrade requests, for deployments where TLS is terminated in front of the server
+//! (i.e: a CDN) and the secret path prefix travels in clear past it.
+//! The client signs each upgrade request with an HMAC over a timestamp, a nonce, the path and the tunnel token, and the
+//! server answers with an HMAC over the nonce of the client, so both sides prove they know the key.
+//! The server remembers the nonces it accepted for as long as their timestamp is valid, to reject replayed requests.
+//! The servers of a cluster sign the announcements they send to each other the same way, with the cluster secret.
+//!
+//! Only the requests opening the tunnels are authenticated. The frames of the tunnels are not, someone able to see and
+//! change the traffic past the TLS termination can still read or alter the data of an accepted tunnel. Noise, see
+//! [`crate::tunnel::noise`], protects the data end to end.
+use anyhow::{Context, anyhow};
+use hyper::header::HeaderName;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use parking_lot::Mutex;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+pub const PSK_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-psk");
+/// Max difference between the clocks of the client and of the server
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct PreSharedKey {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl Debug for PreSharedKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PreSharedKey(****)")
+    }
+}
+
+impl PreSharedKey {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+
+    /// Proof sent by the client with its upgrade request, as `<unix timestamp>.<nonce>.<hmac>`
+    pub fn client_proof(&self, path: &str, tunnel_token: &str) -> String {
+        self.client_proof_at(unix_timestamp(), &Uuid::new_v4().simple().to_string(), path, tunnel_token)
+    }
+
+    fn client_proof_at(&self, timestamp: u64, nonce: &str, path: &str, tunnel_token: &str) -> String {
+        let message = client_message(timestamp, nonce, path, tunnel_token);
+        format!("{timestamp}.{nonce}.{}", self.sign(&message))
+    }
+
+    /// Check the proof of an upgrade request, and record its nonce so the request cannot be replayed
+    pub fn verify_client_proof(
+        &self,
+        proof: &str,
+        path: &str,
+        tunnel_token: &str,
+        replays: &ReplayCache,
+    ) -> anyhow::Result<()> {
+        let (timestamp, nonce, signature) = parse_client_proof(proof)?;
+        if !self.verify(signature, &client_message(timestamp, nonce, path, tunnel_token)) {
+            return Err(anyhow!("invalid pre-shared key signature"));
+        }
+        accept_once(timestamp, nonce, replays)
+    }
+
+    /// Proof sent by a server of a cluster with its announcement to its peers, as `<unix timestamp>.<nonce>.<hmac>`
+    pub fn cluster_proof(&self, announcement: &str) -> String {
+        let timestamp = unix_timestamp();
+        let nonce = Uuid::new_v4().simple().to_string();
+        let message = cluster_message(timestamp, &nonce, announcement);
+        format!("{timestamp}.{nonce}.{}", self.sign(&message))
+    }
+
+    /// Check the proof of the announcement of a peer, and record its nonce so the announcement cannot be replayed
+    pub fn verify_cluster_proof(&self, proof: &str, announcement: &str, replays: &ReplayCache) -> anyhow::Result<()> {
+        let (timestamp, nonce, signature) = parse_client_proof(proof)?;
+        if !self.verify(signature, &cluster_message(timestamp, nonce, announcement)) {
+            return Err(anyhow!("invalid cluster announcement signature"));
+        }
+        accept_once(timestamp, nonce, replays)
+    }
+
+    /// Proof sent back by the server, bound to the nonce of the client proof it answers
+    pub fn server_proof(&self, client_proof: &str) -> anyhow::Result<String> {
+        let (_, nonce, _) = parse_client_proof(client_proof)?;
+        Ok(self.sign(&server_message(nonce)))
+    }
+
+    pub fn verify_server_proof(&self, client_proof: &str, server_proof: &str) -> anyhow::Result<()> {
+        let (_, nonce, _) = parse_client_proof(client_proof)?;
+        if !self.verify(server_proof, &server_message(nonce)) {
+            return Err(anyhow!("server does not know the pre-shared key"));
+        }
+        Ok(())
+    }
+
+    fn sign(&self, message: &str) -> String {
+        jsonwebtoken::crypto::sign(message.as_bytes(), &self.encoding, Algorithm::HS256).unwrap_or_default()
+    }
+
+    fn verify(&self, signature: &str, message: &str) -> bool {
+        jsonwebtoken::crypto::verify(signature, message.as_bytes(), &self.decoding, Algorithm::HS256).unwrap_or(false)
+    }
+}
+
+/// Nonces of the accepted requests, until their timestamp is too old to be accepted again
+pub struct ReplayCache {
+    nonces: Mutex<Nonces>,
+}
+
+#[derive(Default)]
+struct Nonces {
+    accepted: HashSet<String>,
+    /// The accepted nonces by expiration, the first to expire on top
+    expirations: BinaryHeap<Reverse<(u64, String)>>,
+}
+
+impl ReplayCache {
+    pub fn new() -> Self {
+        Self {
+            nonces: Mutex::new(Nonces::default()),
+        }
+    }
+
+    fn insert(&self, nonce: &str, expiration: u64, now: u64) -> anyhow::Result<()> {
+        let mut nonces = self.nonces.lock();
+        while let Some(Reverse((expiration, _))) = nonces.expirations.peek()
+            && *expiration < now
+        {
+            if let Some(Reverse((_, nonce))) = nonces.expirations.pop() {
+                nonces.accepted.remove(&nonce);
+            }
+        }
+        if !nonces.accepted.insert(nonce.to_string()) {
+            return Err(anyhow!("replayed pre-shared key proof"));
+        }
+        nonces.expirations.push(Reverse((expiration, nonce.to_string())));
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.nonces.lock().accepted.len()
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Accept a proof whose timestamp is close enough to the local clock, and whose nonce was not seen before
+fn accept_once(timestamp: u64, nonce: &str, replays: &ReplayCache) -> anyhow::Result<()> {
+    let now = unix_timestamp();
+    if timestamp.abs_diff(now) > MAX_CLOCK_SKEW.as_secs() {
+        return Err(anyhow!(
+            "pre-shared key proof is {}s away from the server clock",
+            timestamp.abs_diff(now)
+        ));
+    }
+    replays.insert(nonce, timestamp + MAX_CLOCK_SKEW.as_secs(), now)
+}
+
+fn parse_client_proof(proof: &str) -> anyhow::Result<(u64, &str, &str)> {
+    let mut parts = proof.splitn(3, '.');
+    let (Some(timestamp), Some(nonce), Some(signature)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(anyhow!("malformed pre-shared key proof"));
+    };
+    let timestamp = timestamp
+        .parse::<u64>()
+        .context("malformed pre-shared key proof timestamp")?;
+    Ok((timestamp, nonce, signature))
+}
+
+// The messages start with a different context, so the proof of one side cannot be reflected as the proof of the other,
+// nor an announcement as an upgrade request
+fn client_message(timestamp: u64, nonce: &str, path: &str, tunnel_token: &str) -> String {
+    format!("wstunnel-psk-client\n{timestamp}\n{nonce}\n{path}\n{tunnel_token}")
+}
+
+fn server_message(nonce: &str) -> String {
+    format!("wstunnel-psk-server\n{nonce}")
+}
+
+fn cluster_message(timestamp: u64, nonce: &str, announcement: &str) -> String {
+    format!("wstunnel-cluster-announcement\n{timestamp}\n{nonce}\n{announcement}")
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_psk_handshake() {
+        let psk = PreSharedKey::new(b"secret");
+        let replays = ReplayCache::new();
+
+        let proof = psk.client_proof("/v1/events", "a.b.c");
+        psk.verify_client_proof(&proof, "/v1/events", "a.b.c", &replays)
+            .unwrap();
+        let server_proof = psk.server_proof(&proof).unwrap();
+        psk.verify_server_proof(&proof, &server_proof).unwrap();
+
+        // The same request cannot be accepted twice
+        assert!(
+            psk.verify_client_proof(&proof, "/v1/events", "a.b.c", &replays)
+                .is_err()
+        );
+
+        // The proof is bound to the key, the path and the tunnel token
+        let proof = psk.client_proof("/v1/events", "a.b.c");
+        let other_psk = PreSharedKey::new(b"other secret");
+        assert!(
+            other_psk
+                .verify_client_proof(&proof, "/v1/events", "a.b.c", &replays)
+                .is_err()
+        );
+        assert!(
+            psk.verify_client_proof(&proof, "/v2/events", "a.b.c", &replays)
+                .is_err()
+        );
+        assert!(
+            psk.verify_client_proof(&proof, "/v1/events", "a.b.d", &replays)
+                .is_err()
+        );
+        assert!(
+            other_psk
+                .verify_server_proof(&proof, &psk.server_proof(&proof).unwrap())
+                .is_err()
+        );
+        // nor can the client proof be reflected as the one of the server
+        let (_, _, signature) = parse_client_proof(&proof).unwrap();
+        assert!(psk.verify_server_proof(&proof, signature).is_err());
+
+        // Old proofs are rejected
+        let expired = unix_timestamp() - MAX_CLOCK_SKEW.as_secs() - 10;
+        let proof = psk.client_proof_at(expired, "nonce", "/v1/events", "a.b.c");
+        assert!(
+            psk.verify_client_proof(&proof, "/v1/events", "a.b.c", &replays)
+                .is_err()
+        );
+        assert!(
+            psk.verify_client_proof("garbage", "/v1/events", "a.b.c", &replays)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_cluster_proof() {
+        let psk = PreSharedKey::new(b"secret");
+        let replays = ReplayCache::new();
+
+        let proof = psk.cluster_proof(r#"{"ports":[]}"#);
+        psk.verify_cluster_proof(&proof, r#"{"ports":[]}"#, &replays).unwrap();
+        // Nor replayed, nor tampered with, nor signed by a server outside the cluster
+        assert!(psk.verify_cluster_proof(&proof, r#"{"ports":[]}"#, &replays).is_err());
+        let proof = psk.cluster_proof(r#"{"ports":[]}"#);
+        assert!(
+            psk.verify_cluster_proof(&proof, r#"{"ports":[8080]}"#, &replays)
+                .is_err()
+        );
+        assert!(
+            PreSharedKey::new(b"other secret")
+                .verify_cluster_proof(&proof, r#"{"ports":[]}"#, &replays)
+                .is_err()
+        );
+
+        // An announcement is not an upgrade request
+        let proof = psk.client_proof("/v1/events", "a.b.c");
+        assert!(psk.verify_cluster_proof(&proof, "/v1/events", &replays).is_err());
+    }
+
+    #[test]
+    fn test_replay_cache_expiration() {
+        let replays = ReplayCache::new();
+        replays.insert("a", 20, 0).unwrap();
+        replays.insert("b", 10, 0).unwrap();
+        replays.insert("c", 30, 0).unwrap();
+        assert!(replays.insert("a", 20, 5).is_err());
+        assert_eq!(replays.len(), 3);
+
+        // Only the expired nonces are forgotten, and can be seen again
+        replays.insert("d", 40, 15).unwrap();
+        assert_eq!(replays.len(), 3);
+        replays.insert("b", 40, 15).unwrap();
+        assert!(replays.insert("c", 40, 25).is_err());
+        replays.insert("a", 50, 25).unwrap();
+        assert_eq!(replays.len(), 4);
+    }
+}
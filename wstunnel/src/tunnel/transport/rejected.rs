@@ -0,0 +1,141 @@
+//! rejected - response of the server, or of a proxy in front of it, refusing to open a tunnel. It is kept whole, so the
+//! client can follow a redirect or wait as long as it is asked to before trying again
+use derive_more::{Display, Error};
+use http_body_util::BodyExt;
+use hyper::Response;
+use hyper::body::Incoming;
+use hyper::header::{LOCATION, RETRY_AFTER};
+use hyper::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use std::time::{Duration, SystemTime};
+
+/// Set by a server on standby, to tell the clients to switch to another server
+pub const STANDBY_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-standby");
+
+#[derive(Debug, Display, Error)]
+#[display("{transport} server rejected the connection: {status:?}: {body:?}")]
+pub struct UpgradeRejected {
+    transport: &'static str,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl UpgradeRejected {
+    pub async fn from_response(transport: &'static str, response: Response<Incoming>) -> Self {
+        let (parts, body) = response.into_parts();
+        let body = match body.collect().await {
+            Ok(body) => String::from_utf8_lossy(&body.to_bytes()).into_owned(),
+            Err(_) => String::new(),
+        };
+        Self {
+            transport,
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        }
+    }
+
+    /// Rejection received out of a http response, its head and body carried by another protocol
+    pub fn from_parts(transport: &'static str, parts: hyper::http::response::Parts, body: String) -> Self {
+        Self {
+            transport,
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        }
+    }
+
+    /// Where the request must be sent instead. 303 is not followed, it asks for a GET and not for the same request
+    pub fn redirect_location(&self) -> Option<&HeaderValue> {
+        match self.status {
+            StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT => self.headers.get(LOCATION),
+            _ => None,
+        }
+    }
+
+    pub fn is_permanent_redirect(&self) -> bool {
+        matches!(self.status, StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT)
+    }
+
+    /// The credentials of the client are refused, retrying with the same ones is pointless
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self.status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+    }
+
+    /// The server is the passive one of an active-standby pair, another one must be used
+    pub fn is_standby(&self) -> bool {
+        self.status == StatusCode::SERVICE_UNAVAILABLE && self.headers.contains_key(STANDBY_HEADER)
+    }
+
+    /// How long the server asks to wait before trying again, as a number of seconds or as a date
+    pub fn retry_after(&self) -> Option<Duration> {
+        if !matches!(self.status, StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE) {
+            return None;
+        }
+
+        let retry_after = self.headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+        if let Ok(secs) = retry_after.parse::<u64>() {
+            return Some(Duration::from_secs(secs));
+        }
+        let date = httpdate::parse_http_date(retry_after).ok()?;
+        Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(status: StatusCode, headers: &[(hyper::header::HeaderName, &str)]) -> UpgradeRejected {
+        UpgradeRejected {
+            transport: "Websocket",
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+                .collect(),
+            body: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_redirect_location() {
+        let redirect = rejected(StatusCode::PERMANENT_REDIRECT, &[(LOCATION, "wss://example.com/v1/events")]);
+        assert_eq!(redirect.redirect_location().unwrap(), "wss://example.com/v1/events");
+        assert!(redirect.is_permanent_redirect());
+
+        let redirect = rejected(StatusCode::FOUND, &[(LOCATION, "/v2/events")]);
+        assert!(redirect.redirect_location().is_some());
+        assert!(!redirect.is_permanent_redirect());
+
+        let see_other = rejected(StatusCode::SEE_OTHER, &[(LOCATION, "/login")]);
+        assert!(see_other.redirect_location().is_none());
+        assert!(rejected(StatusCode::FOUND, &[]).redirect_location().is_none());
+    }
+
+    #[test]
+    fn test_standby() {
+        assert!(rejected(StatusCode::SERVICE_UNAVAILABLE, &[(STANDBY_HEADER, "1")]).is_standby());
+        assert!(!rejected(StatusCode::SERVICE_UNAVAILABLE, &[]).is_standby());
+        assert!(!rejected(StatusCode::FORBIDDEN, &[(STANDBY_HEADER, "1")]).is_standby());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let busy = rejected(StatusCode::SERVICE_UNAVAILABLE, &[(RETRY_AFTER, "120")]);
+        assert_eq!(busy.retry_after(), Some(Duration::from_secs(120)));
+
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let busy = rejected(StatusCode::TOO_MANY_REQUESTS, &[(RETRY_AFTER, &date)]);
+        assert!(busy.retry_after().unwrap() > Duration::from_secs(55));
+
+        let past = rejected(StatusCode::TOO_MANY_REQUESTS, &[(RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT")]);
+        assert_eq!(past.retry_after(), Some(Duration::ZERO));
+
+        assert_eq!(rejected(StatusCode::SERVICE_UNAVAILABLE, &[]).retry_after(), None);
+        assert_eq!(rejected(StatusCode::FORBIDDEN, &[(RETRY_AFTER, "10")]).retry_after(), None);
+    }
+}
@@ -0,0 +1,325 @@
+//! Tunnels inside the channels of an ssh connection, for the networks letting port 22 out while they intercept https.
+//! The server speaks ssh on its usual port, recognizing the ssh clients by their banner. Each tunnel is a session
+//! channel, whose exec request carries the http head the other transports send as upgrade request. The server answers
+//! with the head of its response on the stderr stream of the channel, then the data of the tunnel flows on the channel.
+//! All the tunnels of a client are multiplexed on the same ssh connection.
+//!
+//! The host key of the server is not checked, use --psk to make sure the tunnels are opened with the right server.
+use super::buffer_pool::{BUFFER_POOL, PooledBuffer};
+use super::io::{MAX_PACKET_LENGTH, TunnelRead, TunnelWrite};
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::client::WsClient;
+use crate::tunnel::protocol::close_reason::CloseReason;
+use crate::tunnel::transport::jwt::tunnel_to_jwt_token;
+use crate::tunnel::transport::{EARLY_DATA_HEADER, PSK_HEADER, UpgradeRejected, early_data, http2};
+use anyhow::{Context, anyhow};
+use bytes::BytesMut;
+use hyper::header::{COOKIE, HeaderName, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use log::debug;
+use russh::keys::PublicKeyOrCertificate;
+use russh::{ChannelMsg, ChannelStream, client};
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::ops::DerefMut;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// Name the clients log in with. The server accepts any, the tunnels are authorized one by one
+pub const SSH_USER: &str = "wstunnel";
+/// Extended data stream of a channel the server sends the head of its response on, the stderr one
+pub const RESPONSE_HEAD_STREAM: u32 = 1;
+const MAX_HEADERS: usize = 64;
+
+/// Ssh connection with the server the tunnels are multiplexed on
+pub(crate) type SshSession = Arc<tokio::sync::Mutex<Option<Arc<client::Handle<SshClient>>>>>;
+
+pub struct SshClient;
+
+impl client::Handler for SshClient {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _server_public_key: &PublicKeyOrCertificate) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+/// Head of the upgrade request of a tunnel, as sent by a http1 client
+pub fn encode_request<B>(req: &Request<B>) -> Vec<u8> {
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    encode_head(&format!("{} {path} HTTP/1.1", req.method()), req.headers(), "")
+}
+
+pub fn decode_request(head: &[u8]) -> Option<Request<()>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    if !request.parse(head).ok()?.is_complete() {
+        return None;
+    }
+
+    let mut req = Request::builder()
+        .method(request.method?)
+        .uri(request.path?)
+        .body(())
+        .ok()?;
+    for header in request.headers.iter() {
+        req.headers_mut().append(
+            HeaderName::from_bytes(header.name.as_bytes()).ok()?,
+            HeaderValue::from_bytes(header.value).ok()?,
+        );
+    }
+    Some(req)
+}
+
+/// Head of the response of the server, followed by its body for the rejected tunnels
+pub fn encode_response(status: StatusCode, headers: &HeaderMap, body: &str) -> Vec<u8> {
+    encode_head(&format!("HTTP/1.1 {}", status.as_str()), headers, body)
+}
+
+fn decode_response(head: &[u8]) -> Option<(Parts, String)> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    let httparse::Status::Complete(head_len) = response.parse(head).ok()? else {
+        return None;
+    };
+
+    let mut builder = Response::builder().status(response.code?);
+    for header in response.headers.iter() {
+        builder = builder.header(header.name, header.value);
+    }
+    let (parts, _) = builder.body(()).ok()?.into_parts();
+    Some((parts, String::from_utf8_lossy(&head[head_len..]).into_owned()))
+}
+
+fn encode_head(first_line: &str, headers: &HeaderMap, body: &str) -> Vec<u8> {
+    let mut head = Vec::with_capacity(1024);
+    head.extend_from_slice(first_line.as_bytes());
+    head.extend_from_slice(b"\r\n");
+    for (name, value) in headers {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head.extend_from_slice(body.as_bytes());
+    head
+}
+
+/// Read the data of a tunnel from an ssh channel
+pub struct SshTunnelRead<S = ChannelStream<client::Msg>> {
+    inner: ReadHalf<S>,
+    buf: PooledBuffer,
+}
+
+impl<S> SshTunnelRead<S> {
+    pub fn new(inner: ReadHalf<S>) -> Self {
+        Self {
+            inner,
+            buf: BUFFER_POOL.take(MAX_PACKET_LENGTH),
+        }
+    }
+}
+
+impl<S: tokio::io::AsyncRead + Send + 'static> TunnelRead for SshTunnelRead<S> {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+        self.buf.clear();
+        match self.inner.read_buf(&mut *self.buf).await {
+            Ok(0) => Err(io::Error::new(ErrorKind::BrokenPipe, "closed")),
+            Ok(_) => match writer.write_all(&self.buf).await {
+                Ok(_) => Ok(()),
+                Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+            },
+            Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+        }
+    }
+}
+
+/// Write the data of a tunnel in an ssh channel
+pub struct SshTunnelWrite<S = ChannelStream<client::Msg>> {
+    inner: WriteHalf<S>,
+    buf: PooledBuffer,
+}
+
+impl<S> SshTunnelWrite<S> {
+    pub fn new(inner: WriteHalf<S>) -> Self {
+        Self {
+            inner,
+            buf: BUFFER_POOL.take(MAX_PACKET_LENGTH * 2),
+        }
+    }
+}
+
+impl<S: tokio::io::AsyncWrite + Send + 'static> TunnelWrite for SshTunnelWrite<S> {
+    fn buf_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    async fn write(&mut self) -> Result<(), io::Error> {
+        let ret = self.inner.write_all(&self.buf).await;
+        self.buf.clear();
+        if self.buf.capacity() < MAX_PACKET_LENGTH {
+            self.buf.reserve(MAX_PACKET_LENGTH)
+        }
+        ret.map_err(|err| io::Error::new(ErrorKind::ConnectionAborted, err))
+    }
+
+    async fn ping(&mut self) -> Result<(), io::Error> {
+        // The ssh connection has its own keepalives
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), io::Error> {
+        self.inner.shutdown().await
+    }
+
+    async fn abort(&mut self, _reason: CloseReason) -> Result<(), io::Error> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "ssh tunnels cannot tell why they are closed",
+        ))
+    }
+
+    async fn half_close(&mut self) -> Result<(), io::Error> {
+        // Sends the eof of the channel, which stays open for the data of the peer
+        self.inner.shutdown().await
+    }
+
+    fn pending_operations_notify(&mut self) -> Arc<Notify> {
+        Arc::new(Notify::new())
+    }
+
+    fn handle_pending_operations(&mut self) -> impl Future<Output = Result<(), io::Error>> + Send {
+        std::future::ready(Ok(()))
+    }
+}
+
+pub async fn connect(
+    request_id: Uuid,
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    dest_addr: &RemoteAddr,
+    early_data: &[u8],
+) -> anyhow::Result<(SshTunnelRead, SshTunnelWrite, Parts)> {
+    let path = client
+        .config
+        .camouflage
+        .upgrade_path(&client.config.http_upgrade_path_prefix);
+    let tunnel_token = tunnel_to_jwt_token(
+        request_id,
+        dest_addr,
+        client.label.as_deref(),
+        client.config.device_id.as_deref(),
+    );
+    let psk_proof = client
+        .config
+        .psk
+        .as_ref()
+        .map(|psk| psk.client_proof(&path, &tunnel_token));
+    let mut req = http2::mk_request(client, Method::POST, &path).await?;
+    let headers = req.headers_mut();
+    headers.insert(COOKIE, HeaderValue::from_str(&tunnel_token)?);
+    if let Some(psk_proof) = &psk_proof {
+        headers.insert(PSK_HEADER, HeaderValue::from_str(psk_proof)?);
+    }
+    if let Some(early_data) = early_data::encode(early_data) {
+        headers.insert(EARLY_DATA_HEADER, early_data);
+    }
+    client.add_probe_interval(headers, dest_addr);
+    client.add_integrity_interval(headers, dest_addr);
+    debug!("with ssh exec request {req:?}");
+
+    let session = session(client).await?;
+    let mut channel = session
+        .channel_open_session()
+        .await
+        .with_context(|| format!("failed to open ssh channel with the server {:?}", client.config.remote_addr))?;
+    channel.exec(true, encode_request(&req)).await?;
+    let head = loop {
+        match channel.wait().await {
+            Some(ChannelMsg::ExtendedData { data, ext }) if ext == RESPONSE_HEAD_STREAM => break data,
+            Some(ChannelMsg::Failure) => return Err(anyhow!("ssh server refused the tunnel request")),
+            Some(ChannelMsg::Eof | ChannelMsg::Close) | None => {
+                return Err(anyhow!("ssh channel closed before the server answered the tunnel request"));
+            }
+            Some(_) => continue,
+        }
+    };
+    let (parts, body) = decode_response(&head).with_context(|| "invalid response head from the ssh server")?;
+
+    if !parts.status.is_success() {
+        return Err(UpgradeRejected::from_parts("Ssh", parts, body).into());
+    }
+    if let (Some(psk), Some(psk_proof)) = (&client.config.psk, &psk_proof) {
+        let server_proof = parts
+            .headers
+            .get(PSK_HEADER)
+            .and_then(|header| header.to_str().ok())
+            .unwrap_or_default();
+        psk.verify_server_proof(psk_proof, server_proof)?;
+    }
+
+    let (reader, writer) = tokio::io::split(channel.into_stream());
+    Ok((SshTunnelRead::new(reader), SshTunnelWrite::new(writer), parts))
+}
+
+/// Ssh connection the tunnels are multiplexed on, opened again if it was lost
+async fn session(client: &WsClient<impl crate::TokioExecutorRef>) -> anyhow::Result<Arc<client::Handle<SshClient>>> {
+    let mut session = client.ssh_session.lock().await;
+    if let Some(session) = session.as_ref().filter(|session| !session.is_closed()) {
+        return Ok(session.clone());
+    }
+
+    let mut pooled_cnx = match client.cnx_pool.get().await {
+        Ok(cnx) => Ok(cnx),
+        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
+    }?;
+    let transport = pooled_cnx.deref_mut().take().unwrap();
+    client.mark_transport(&transport);
+
+    let config = client::Config {
+        keepalive_interval: client.config.websocket_ping_frequency,
+        nodelay: true,
+        ..Default::default()
+    };
+    let mut handle = client::connect_stream(Arc::new(config), transport, SshClient)
+        .await
+        .with_context(|| format!("failed to do ssh handshake with the server {:?}", client.config.remote_addr))?;
+    if !handle.authenticate_none(SSH_USER).await?.success() {
+        return Err(anyhow!("ssh server {:?} refused the login", client.config.remote_addr));
+    }
+
+    let handle = Arc::new(handle);
+    *session = Some(handle.clone());
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ssh_heads() {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("ssh://example.com/v1/events")
+            .header(COOKIE, "token")
+            .body(())
+            .unwrap();
+        let decoded = decode_request(&encode_request(&req)).unwrap();
+        assert_eq!(decoded.uri().path(), "/v1/events");
+        assert_eq!(decoded.method(), Method::POST);
+        assert_eq!(decoded.headers().get(COOKIE).unwrap(), "token");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(PSK_HEADER, HeaderValue::from_static("proof"));
+        let (parts, body) = decode_response(&encode_response(StatusCode::FORBIDDEN, &headers, "denied")).unwrap();
+        assert_eq!(parts.status, StatusCode::FORBIDDEN);
+        assert_eq!(parts.headers.get(PSK_HEADER).unwrap(), "proof");
+        assert_eq!(body, "denied");
+        assert!(decode_response(b"HTTP/1.1 200\r\n").is_none());
+    }
+}
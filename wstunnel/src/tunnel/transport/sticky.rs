@@ -0,0 +1,128 @@
+//! Sticky sessions for servers behind a round-robin load balancer. The server signs a token naming itself in the
+//! response to the first upgrade request of a client, and the client sends it back with all its next requests, so the
+//! load balancer can route them to the same server, i.e: with the stick tables of haproxy. Otherwise the requests of a
+//! http1 session or of a pooled connection can land on a server that does not know them.
+use hyper::header::{HeaderName, HeaderValue};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use std::fmt::{Debug, Formatter};
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+pub const STICKY_SESSION_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-sticky");
+
+#[derive(Clone)]
+pub struct StickySession {
+    instance: String,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    max_age: Duration,
+}
+
+impl Debug for StickySession {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StickySession")
+            .field("instance", &self.instance)
+            .field("max_age", &self.max_age)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StickySession {
+    /// `secret` is shared by all the servers behind the load balancer, so any of them can check the tokens
+    pub fn new(instance: &str, secret: &[u8], max_age: Duration) -> Self {
+        Self {
+            instance: instance.to_string(),
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            max_age,
+        }
+    }
+
+    /// Token naming this server, as `<instance>.<expiration unix timestamp>.<hmac>`
+    fn issue_at(&self, now: u64) -> String {
+        let expires = now + self.max_age.as_secs();
+        let signature =
+            jsonwebtoken::crypto::sign(message(&self.instance, expires).as_bytes(), &self.encoding, Algorithm::HS256)
+                .unwrap_or_default();
+        format!("{}.{expires}.{signature}", self.instance)
+    }
+
+    /// Instance named by a token signed by a server of the deployment, and its expiration, unless it is forged
+    fn verify<'a>(&self, token: &'a str) -> Option<(&'a str, u64)> {
+        let mut parts = token.rsplitn(3, '.');
+        let (Some(signature), Some(expires), Some(instance)) = (parts.next(), parts.next(), parts.next()) else {
+            return None;
+        };
+        let expires = expires.parse::<u64>().ok()?;
+        jsonwebtoken::crypto::verify(
+            signature,
+            message(instance, expires).as_bytes(),
+            &self.decoding,
+            Algorithm::HS256,
+        )
+        .unwrap_or(false)
+        .then_some((instance, expires))
+    }
+
+    /// Token to send back to the client, unless the one of its request still sticks it to this server for a while
+    pub fn renew(&self, token: Option<&HeaderValue>) -> Option<HeaderValue> {
+        self.renew_at(token, unix_timestamp())
+    }
+
+    fn renew_at(&self, token: Option<&HeaderValue>, now: u64) -> Option<HeaderValue> {
+        match token.and_then(|token| self.verify(token.to_str().ok()?)) {
+            Some((instance, expires)) if instance == self.instance && expires > now + self.max_age.as_secs() / 2 => {
+                return None;
+            }
+            Some((instance, _)) if instance != self.instance => {
+                debug!(
+                    "Client sticking to server {instance} was routed to {}, sticking it here",
+                    self.instance
+                );
+            }
+            _ => {}
+        }
+        HeaderValue::from_str(&self.issue_at(now)).ok()
+    }
+}
+
+fn message(instance: &str, expires: u64) -> String {
+    format!("wstunnel-sticky\n{instance}\n{expires}")
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sticky_session_renewal() {
+        let max_age = Duration::from_secs(3600);
+        let server_a = StickySession::new("server-a.lan", b"secret", max_age);
+        let server_b = StickySession::new("server-b.lan", b"secret", max_age);
+        let now = unix_timestamp();
+
+        let token = server_a.renew_at(None, now).unwrap();
+        assert!(token.to_str().unwrap().starts_with("server-a.lan."));
+        assert_eq!(server_a.verify(token.to_str().unwrap()), Some(("server-a.lan", now + 3600)));
+
+        // Kept as long as it is valid for more than half of its age
+        assert!(server_a.renew_at(Some(&token), now + 600).is_none());
+        assert!(server_a.renew_at(Some(&token), now + 2400).is_some());
+
+        // Replaced when the client lands on another server, or with a forged token
+        let token_b = server_b.renew_at(Some(&token), now).unwrap();
+        assert!(token_b.to_str().unwrap().starts_with("server-b.lan."));
+        let forged = StickySession::new("server-a.lan", b"other secret", max_age)
+            .renew_at(None, now)
+            .unwrap();
+        assert!(server_a.verify(forged.to_str().unwrap()).is_none());
+        assert!(server_a.renew_at(Some(&forged), now).is_some());
+    }
+}
@@ -9,12 +9,36 @@ pub enum TransportScheme {
     Wss,
     Http,
     Https,
+    Http1,
+    Https1,
+    Grpc,
+    Grpcs,
+    #[cfg(feature = "ssh-transport")]
+    Ssh,
+    #[cfg(feature = "dns-transport")]
+    Dns,
+    #[cfg(feature = "icmp-transport")]
+    Icmp,
 }
 
 impl TransportScheme {
-    #[cfg(feature = "clap")] // this is only used inside a clap value parser
     pub const fn values() -> &'static [Self] {
-        &[Self::Ws, Self::Wss, Self::Http, Self::Https]
+        &[
+            Self::Ws,
+            Self::Wss,
+            Self::Http,
+            Self::Https,
+            Self::Http1,
+            Self::Https1,
+            Self::Grpc,
+            Self::Grpcs,
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh,
+            #[cfg(feature = "dns-transport")]
+            Self::Dns,
+            #[cfg(feature = "icmp-transport")]
+            Self::Icmp,
+        ]
     }
     pub const fn to_str(self) -> &'static str {
         match self {
@@ -22,6 +46,16 @@ impl TransportScheme {
             Self::Wss => "wss",
             Self::Http => "http",
             Self::Https => "https",
+            Self::Http1 => "http1",
+            Self::Https1 => "https1",
+            Self::Grpc => "grpc",
+            Self::Grpcs => "grpcs",
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh => "ssh",
+            #[cfg(feature = "dns-transport")]
+            Self::Dns => "dns",
+            #[cfg(feature = "icmp-transport")]
+            Self::Icmp => "icmp",
         }
     }
 
@@ -31,6 +65,25 @@ impl TransportScheme {
             Self::Wss => vec![b"http/1.1".to_vec()],
             Self::Http => vec![],
             Self::Https => vec![b"h2".to_vec()],
+            Self::Http1 => vec![],
+            Self::Https1 => vec![b"http/1.1".to_vec()],
+            Self::Grpc => vec![],
+            Self::Grpcs => vec![b"h2".to_vec()],
+            #[cfg(feature = "ssh-transport")]
+            Self::Ssh => vec![],
+            #[cfg(feature = "dns-transport")]
+            Self::Dns => vec![],
+            #[cfg(feature = "icmp-transport")]
+            Self::Icmp => vec![],
+        }
+    }
+
+    /// Scheme of the uri of the http requests sent to the server, as the grpc ones are plain http2 requests
+    pub const fn uri_scheme(self) -> &'static str {
+        match self {
+            Self::Grpc => "http",
+            Self::Grpcs => "https",
+            _ => self.to_str(),
         }
     }
 }
@@ -41,8 +94,18 @@ impl FromStr for TransportScheme {
         match s {
             "https" => Ok(Self::Https),
             "http" => Ok(Self::Http),
+            "https1" => Ok(Self::Https1),
+            "http1" => Ok(Self::Http1),
+            "grpcs" => Ok(Self::Grpcs),
+            "grpc" => Ok(Self::Grpc),
+            #[cfg(feature = "ssh-transport")]
+            "ssh" => Ok(Self::Ssh),
             "wss" => Ok(Self::Wss),
             "ws" => Ok(Self::Ws),
+            #[cfg(feature = "dns-transport")]
+            "dns" => Ok(Self::Dns),
+            #[cfg(feature = "icmp-transport")]
+            "icmp" => Ok(Self::Icmp),
             _ => Err(()),
         }
     }
@@ -78,6 +141,18 @@ pub enum TransportAddr {
         host: Host,
         port: u16,
     },
+    #[cfg(feature = "dns-transport")]
+    Dns {
+        scheme: TransportScheme,
+        host: Host,
+        port: u16,
+    },
+    #[cfg(feature = "icmp-transport")]
+    Icmp {
+        scheme: TransportScheme,
+        host: Host,
+        port: u16,
+    },
 }
 
 impl Debug for TransportAddr {
@@ -100,6 +175,34 @@ impl TransportAddr {
                 host,
                 port,
             }),
+            TransportScheme::Https1 => Some(Self::Https {
+                scheme: TransportScheme::Https1,
+                tls: tls?,
+                host,
+                port,
+            }),
+            TransportScheme::Http1 => Some(Self::Http {
+                scheme: TransportScheme::Http1,
+                host,
+                port,
+            }),
+            TransportScheme::Grpcs => Some(Self::Https {
+                scheme: TransportScheme::Grpcs,
+                tls: tls?,
+                host,
+                port,
+            }),
+            TransportScheme::Grpc => Some(Self::Http {
+                scheme: TransportScheme::Grpc,
+                host,
+                port,
+            }),
+            #[cfg(feature = "ssh-transport")]
+            TransportScheme::Ssh => Some(Self::Http {
+                scheme: TransportScheme::Ssh,
+                host,
+                port,
+            }),
             TransportScheme::Wss => Some(Self::Wss {
                 scheme: TransportScheme::Wss,
                 tls: tls?,
@@ -111,6 +214,18 @@ impl TransportAddr {
                 host,
                 port,
             }),
+            #[cfg(feature = "dns-transport")]
+            TransportScheme::Dns => Some(Self::Dns {
+                scheme: TransportScheme::Dns,
+                host,
+                port,
+            }),
+            #[cfg(feature = "icmp-transport")]
+            TransportScheme::Icmp => Some(Self::Icmp {
+                scheme: TransportScheme::Icmp,
+                host,
+                port,
+            }),
         }
     }
 
@@ -120,6 +235,10 @@ impl TransportAddr {
             Self::Https { tls, .. } => Some(tls),
             Self::Ws { .. } => None,
             Self::Http { .. } => None,
+            #[cfg(feature = "dns-transport")]
+            Self::Dns { .. } => None,
+            #[cfg(feature = "icmp-transport")]
+            Self::Icmp { .. } => None,
         }
     }
 
@@ -129,6 +248,10 @@ impl TransportAddr {
             Self::Ws { host, .. } => host,
             Self::Https { host, .. } => host,
             Self::Http { host, .. } => host,
+            #[cfg(feature = "dns-transport")]
+            Self::Dns { host, .. } => host,
+            #[cfg(feature = "icmp-transport")]
+            Self::Icmp { host, .. } => host,
         }
     }
 
@@ -138,6 +261,10 @@ impl TransportAddr {
             Self::Ws { port, .. } => *port,
             Self::Https { port, .. } => *port,
             Self::Http { port, .. } => *port,
+            #[cfg(feature = "dns-transport")]
+            Self::Dns { port, .. } => *port,
+            #[cfg(feature = "icmp-transport")]
+            Self::Icmp { port, .. } => *port,
         }
     }
 
@@ -147,6 +274,10 @@ impl TransportAddr {
             Self::Ws { scheme, .. } => scheme,
             Self::Https { scheme, .. } => scheme,
             Self::Http { scheme, .. } => scheme,
+            #[cfg(feature = "dns-transport")]
+            Self::Dns { scheme, .. } => scheme,
+            #[cfg(feature = "icmp-transport")]
+            Self::Icmp { scheme, .. } => scheme,
         }
     }
 }
@@ -1,56 +1,169 @@
+use super::buffer_pool::{BUFFER_POOL, PooledBuffer};
 use super::io::{MAX_PACKET_LENGTH, TunnelRead, TunnelWrite};
+use crate::health::SERVER_REACHABILITY;
+use crate::oidc;
+use crate::protocols::tls::ServerTlsStream;
+use crate::stats::{STATS, Side};
 use crate::tunnel::RemoteAddr;
 use crate::tunnel::client::WsClient;
 use crate::tunnel::client::l4_transport_stream::{TransportReadHalf, TransportStream, TransportWriteHalf};
-use crate::tunnel::transport::headers_from_file;
+use crate::tunnel::protocol::client_version::{CLIENT_VERSION_HEADER, client_version_header};
+use crate::tunnel::protocol::close_reason::{CloseReason, WsCloseCodes, ws_close_code};
 use crate::tunnel::transport::jwt::{JWT_HEADER_PREFIX, tunnel_to_jwt_token};
+use crate::tunnel::transport::obfuscation::{Padding, TrafficObfuscation};
+use crate::tunnel::transport::{EARLY_DATA_HEADER, PSK_HEADER, UpgradeRejected, early_data, headers_from_file};
 use anyhow::{Context, anyhow};
 use bytes::{Bytes, BytesMut};
 use fastwebsockets::{CloseCode, Frame, OpCode, Payload, Role, WebSocket, WebSocketRead, WebSocketWrite};
 use http_body_util::Empty;
-use hyper::Request;
+use hyper::body::Incoming;
 use hyper::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
 use hyper::header::{CONNECTION, HOST, SEC_WEBSOCKET_KEY};
 use hyper::http::response::Parts;
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
 use hyper::upgrade::Upgraded;
-use hyper_util::rt::TokioExecutor;
+use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use log::debug;
+use std::cmp::{max, min};
 use std::io;
 use std::io::ErrorKind;
 use std::ops::DerefMut;
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::Notify;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio_rustls::server::TlsStream;
-use tracing::trace;
+use tracing::{trace, warn};
 use uuid::Uuid;
 
+/// Header used by client and server to advertise the biggest websocket frame they accept to receive
+pub const MAX_FRAME_SIZE_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-max-frame-size");
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+/// A frame must be able to hold a whole UDP packet, as each frame is forwarded as a single datagram
+pub const MIN_MAX_FRAME_SIZE: usize = MAX_PACKET_LENGTH;
+/// How long a side waits for the peer to answer its close frame by default
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Biggest frame the peers that do not advertise their max frame size may send, the default limit of fastwebsockets
+const LEGACY_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Max frame size advertised by the peer, if any. Peers running older versions don't advertise it
+pub fn peer_max_frame_size(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(MAX_FRAME_SIZE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .map(|v| v.max(MIN_MAX_FRAME_SIZE))
+}
+
+pub fn max_frame_size_header(max_frame_size: usize) -> HeaderValue {
+    HeaderValue::from(max_frame_size)
+}
+
+/// Max size of the frames received and sent, in this order. The limit of a side only lowers what its peer sends, so the
+/// peers that do not advertise theirs, being older, keep sending the frames they always did
+fn frame_size_limits(max_frame_size: usize, peer_max_frame_size: Option<usize>) -> (usize, usize) {
+    match peer_max_frame_size {
+        Some(peer_max_frame_size) => (max_frame_size, min(peer_max_frame_size, max_frame_size)),
+        None => (
+            max(max_frame_size, LEGACY_MAX_FRAME_SIZE),
+            min(max_frame_size, LEGACY_MAX_FRAME_SIZE),
+        ),
+    }
+}
+
 pub struct WebsocketTunnelWrite {
     inner: WebSocketWrite<TransportWriteHalf>,
-    buf: BytesMut,
+    buf: PooledBuffer,
+    max_frame_size: usize,
     pending_operations: Receiver<Frame<'static>>,
     pending_ops_notify: Arc<Notify>,
     in_flight_ping: AtomicUsize,
+    /// When the oldest unanswered ping was sent, to measure the round trip time with the peer
+    ping_sent_at: Option<Instant>,
+    /// Tunnel whose statistics get the measured round trip time
+    tunnel: Option<(Side, String)>,
+    obfuscation: Option<(TrafficObfuscation, Padding)>,
+    close_codes: WsCloseCodes,
+    close_timeout: Duration,
+    /// A close frame was sent, the websocket must not send anything after it
+    close_sent: bool,
 }
 
 impl WebsocketTunnelWrite {
     pub fn new(
         ws: WebSocketWrite<TransportWriteHalf>,
+        max_frame_size: usize,
         (pending_operations, notify): (Receiver<Frame<'static>>, Arc<Notify>),
     ) -> Self {
         Self {
             inner: ws,
-            buf: BytesMut::with_capacity(MAX_PACKET_LENGTH),
+            buf: BUFFER_POOL.take(MAX_PACKET_LENGTH),
+            max_frame_size,
             pending_operations,
             pending_ops_notify: notify,
             in_flight_ping: AtomicUsize::new(0),
+            ping_sent_at: None,
+            tunnel: None,
+            obfuscation: None,
+            close_codes: WsCloseCodes::default(),
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            close_sent: false,
         }
     }
+
+    /// Report the round trip time of the pings in the statistics of the given tunnel
+    pub fn with_stats_of(mut self, side: Side, tunnel_id: String) -> Self {
+        self.tunnel = Some((side, tunnel_id));
+        self
+    }
+
+    /// Pad the frames and delay the writes of the tunnel at random
+    pub fn with_obfuscation(mut self, obfuscation: Option<TrafficObfuscation>) -> Self {
+        self.obfuscation = obfuscation.map(|obfuscation| (obfuscation, Padding::default()));
+        self
+    }
+
+    /// Wait up to `timeout` for the peer to answer the close frame of the tunnel, and tell the close reasons with `codes`
+    pub fn with_close(mut self, timeout: Duration, codes: WsCloseCodes) -> Self {
+        self.close_timeout = timeout;
+        self.close_codes = codes;
+        self
+    }
+
+    /// Close handshake: send the close frame, unless the peer closed first and it was answered already, then wait for the
+    /// close frame of the peer for the close timeout, so the proxies in between see the websocket end cleanly
+    async fn close_with(&mut self, frame: Frame<'_>) -> Result<(), io::Error> {
+        self.handle_pending_operations().await?;
+        if self.close_sent {
+            return Ok(());
+        }
+
+        if let Err(err) = self.inner.write_frame(frame).await {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, err));
+        }
+        self.close_sent = true;
+        if self.close_timeout.is_zero() {
+            return Ok(());
+        }
+
+        let close_ack = async {
+            // None when the reading side of the tunnel is gone, nothing can read the answer anymore
+            while let Some(frame) = self.pending_operations.recv().await {
+                if frame.opcode == OpCode::Close {
+                    return;
+                }
+            }
+        };
+        if tokio::time::timeout(self.close_timeout, close_ack).await.is_err() {
+            debug!("peer did not answer the close frame within {:?}", self.close_timeout);
+        }
+
+        Ok(())
+    }
 }
 
 impl TunnelWrite for WebsocketTunnelWrite {
@@ -62,13 +175,31 @@ impl TunnelWrite for WebsocketTunnelWrite {
         let read_len = self.buf.len();
         let buf = &mut self.buf;
 
-        let ret = self
-            .inner
-            .write_frame(Frame::binary(Payload::BorrowedMut(&mut buf[..read_len])))
-            .await;
+        if let Some((obfuscation, _)) = &self.obfuscation {
+            let jitter = obfuscation.jitter();
+            if !jitter.is_zero() {
+                tokio::time::sleep(jitter).await;
+            }
+        }
 
-        if let Err(err) = ret {
-            return Err(io::Error::new(ErrorKind::ConnectionAborted, err));
+        // Never send frames bigger than what the peer agreed to receive
+        for chunk in buf[..read_len].chunks_mut(self.max_frame_size) {
+            let ret = self.inner.write_frame(Frame::binary(Payload::BorrowedMut(chunk))).await;
+            if let Err(err) = ret {
+                return Err(io::Error::new(ErrorKind::ConnectionAborted, err));
+            }
+        }
+
+        if let Some((obfuscation, padding)) = &mut self.obfuscation {
+            for payload in padding.frames(obfuscation.padding_percent, read_len) {
+                let ret = self
+                    .inner
+                    .write_frame(Frame::new(true, OpCode::Ping, None, Payload::Owned(payload)))
+                    .await;
+                if let Err(err) = ret {
+                    return Err(io::Error::new(ErrorKind::ConnectionAborted, err));
+                }
+            }
         }
 
         // It is needed to call poll_flush to ensure that the data is written to the underlying stream.
@@ -80,13 +211,12 @@ impl TunnelWrite for WebsocketTunnelWrite {
 
         // If the buffer has been completely filled with previous read, Grows it !
         // For the buffer to not be a bottleneck when the TCP window scale.
-        // We clamp it to 32Mb to avoid unbounded growth and as websocket max frame size is 64Mb by default
-        // For udp, the buffer will never grow.
-        const _32_MB: usize = 32 * 1024 * 1024;
+        // We clamp it to the max frame size to avoid unbounded growth, as there is no gain to read more than a frame
+        // For udp, the buffer will never grow. Nor past --max-memory-buffers, the tunnel just gets a lower throughput
         buf.clear();
-        if buf.capacity() == read_len && buf.capacity() < _32_MB {
+        if buf.capacity() == read_len && buf.capacity() < self.max_frame_size {
             let new_size = buf.capacity() + (buf.capacity() / 4); // grow buffer by 1.25 %
-            buf.reserve(new_size);
+            buf.grow(new_size);
             trace!(
                 "Buffer {} Mb {} {} {}",
                 buf.capacity() as f64 / 1024.0 / 1024.0,
@@ -114,13 +244,27 @@ impl TunnelWrite for WebsocketTunnelWrite {
         {
             return Err(io::Error::new(ErrorKind::BrokenPipe, err));
         }
+        self.ping_sent_at.get_or_insert_with(Instant::now);
 
         Ok(())
     }
 
     async fn close(&mut self) -> Result<(), io::Error> {
-        if let Err(err) = self.inner.write_frame(Frame::close(1000, &[])).await {
-            return Err(io::Error::new(ErrorKind::BrokenPipe, err));
+        self.close_with(Frame::close(CloseCode::Normal.into(), &[])).await
+    }
+
+    async fn abort(&mut self, reason: CloseReason) -> Result<(), io::Error> {
+        let code = self.close_codes.code(reason);
+        self.close_with(Frame::close(code, reason.name().as_bytes())).await
+    }
+
+    async fn half_close(&mut self) -> Result<(), io::Error> {
+        // An empty binary frame, the frames of the data are never empty
+        if let Err(err) = self.inner.write_frame(Frame::binary(Payload::Borrowed(&[]))).await {
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, err));
+        }
+        if let Err(err) = self.inner.flush().await {
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, err));
         }
 
         Ok(())
@@ -134,7 +278,10 @@ impl TunnelWrite for WebsocketTunnelWrite {
         while let Ok(frame) = self.pending_operations.try_recv() {
             debug!("received frame {:?}", frame.opcode);
             match frame.opcode {
+                // Answer of the close frame of the peer, unless this side closed first
+                OpCode::Close if self.close_sent => {}
                 OpCode::Close => {
+                    self.close_sent = true;
                     if self.inner.write_frame(frame).await.is_err() {
                         return Err(io::Error::new(ErrorKind::ConnectionAborted, "cannot send close frame"));
                     }
@@ -148,6 +295,15 @@ impl TunnelWrite for WebsocketTunnelWrite {
                 OpCode::Pong => {
                     debug!("received pong frame");
                     self.in_flight_ping.fetch_sub(1, Relaxed);
+                    if let (Some(sent_at), Some((side, tunnel_id))) = (self.ping_sent_at.take(), &self.tunnel)
+                        && let Some(stats) = STATS.get(*side, tunnel_id)
+                    {
+                        stats.set_rtt(sent_at.elapsed());
+                    }
+                    // The server answered the ping of the client
+                    if matches!(self.tunnel, Some((Side::Client, _))) {
+                        SERVER_REACHABILITY.connected();
+                    }
                 }
                 OpCode::Continuation | OpCode::Text | OpCode::Binary => unreachable!(),
             }
@@ -193,6 +349,9 @@ impl TunnelRead for WebsocketTunnelRead {
 
             trace!("receive ws frame {:?} {:?}", msg.opcode, msg.payload);
             match msg.opcode {
+                OpCode::Binary if msg.fin && msg.payload.is_empty() => {
+                    return Err(io::Error::new(ErrorKind::BrokenPipe, "half-closed by the peer"));
+                }
                 OpCode::Continuation | OpCode::Text | OpCode::Binary => {
                     return match writer.write_all(msg.payload.as_ref()).await {
                         Ok(_) => Ok(()),
@@ -205,6 +364,13 @@ impl TunnelRead for WebsocketTunnelRead {
                         .send(Frame::close(CloseCode::Normal.into(), &[]))
                         .await;
                     self.notify_pending_ops.notify_waiters();
+                    if let Some(reason) = CloseReason::decode_ws_close(&msg.payload) {
+                        return Err(reason.into_io_error());
+                    }
+                    if let Some(code) = ws_close_code(&msg.payload).filter(|code| *code != u16::from(CloseCode::Normal))
+                    {
+                        warn!("websocket closed by the peer with code {code}");
+                    }
                     return Err(io::Error::new(ErrorKind::NotConnected, "websocket close"));
                 }
                 OpCode::Ping => {
@@ -218,6 +384,8 @@ impl TunnelRead for WebsocketTunnelRead {
                     }
                     self.notify_pending_ops.notify_waiters();
                 }
+                // Our padding, echoed back by the peer
+                OpCode::Pong if !msg.payload.is_empty() => {}
                 OpCode::Pong => {
                     if self
                         .pending_operations
@@ -234,10 +402,44 @@ impl TunnelRead for WebsocketTunnelRead {
     }
 }
 
+/// Websocket handshake with the server. Unlike the one of fastwebsockets, it keeps the response of a server refusing the
+/// upgrade, to follow its redirects
+async fn handshake(
+    client: &WsClient<impl crate::TokioExecutorRef>,
+    req: Request<Empty<Bytes>>,
+    transport: TransportStream,
+) -> anyhow::Result<(WebSocket<TokioIo<Upgraded>>, Response<Incoming>)> {
+    let (mut request_sender, cnx) = hyper::client::conn::http1::handshake(TokioIo::new(transport)).await?;
+    client.executor.spawn(async move {
+        if let Err(err) = cnx.with_upgrades().await {
+            debug!("Error polling connection: {err}")
+        }
+    });
+
+    let mut response = request_sender.send_request(req).await?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(UpgradeRejected::from_response("Websocket", response).await.into());
+    }
+    let has_header = |name: HeaderName, value: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|header| header.to_str().ok())
+            .is_some_and(|header| header.eq_ignore_ascii_case(value))
+    };
+    if !has_header(UPGRADE, "websocket") || !has_header(CONNECTION, "upgrade") {
+        return Err(anyhow!("invalid websocket upgrade response: {:?}", response.headers()));
+    }
+
+    let upgraded = hyper::upgrade::on(&mut response).await?;
+    Ok((WebSocket::after_handshake(TokioIo::new(upgraded), Role::Client), response))
+}
+
 pub async fn connect(
     request_id: Uuid,
     client: &WsClient<impl crate::TokioExecutorRef>,
     dest_addr: &RemoteAddr,
+    early_data: &[u8],
 ) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts)> {
     let client_cfg = &client.config;
     let mut pooled_cnx = match client.cnx_pool.get().await {
@@ -245,17 +447,34 @@ pub async fn connect(
         Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
     }?;
 
+    let host = pooled_cnx
+        .as_ref()
+        .and_then(|transport| transport.host())
+        .unwrap_or(&client_cfg.http_header_host)
+        .clone();
+    let path = client_cfg.camouflage.upgrade_path(&client_cfg.http_upgrade_path_prefix);
+    let tunnel_token = tunnel_to_jwt_token(
+        request_id,
+        dest_addr,
+        client.label.as_deref(),
+        client.config.device_id.as_deref(),
+    );
+    let psk_proof = client_cfg
+        .psk
+        .as_ref()
+        .map(|psk| psk.client_proof(&path, &tunnel_token));
     let mut req = Request::builder()
         .method("GET")
-        .uri(format!("/{}/events", &client_cfg.http_upgrade_path_prefix))
-        .header(HOST, &client_cfg.http_header_host)
+        .uri(&path)
+        .header(HOST, &host)
         .header(UPGRADE, "websocket")
         .header(CONNECTION, "upgrade")
         .header(SEC_WEBSOCKET_KEY, fastwebsockets::handshake::generate_key())
         .header(SEC_WEBSOCKET_VERSION, "13")
+        .header(SEC_WEBSOCKET_PROTOCOL, format!("v1, {JWT_HEADER_PREFIX}{tunnel_token}"))
         .header(
-            SEC_WEBSOCKET_PROTOCOL,
-            format!("v1, {}{}", JWT_HEADER_PREFIX, tunnel_to_jwt_token(request_id, dest_addr)),
+            MAX_FRAME_SIZE_HEADER,
+            max_frame_size_header(client_cfg.websocket_max_frame_size),
         )
         .version(hyper::Version::HTTP_11);
 
@@ -273,12 +492,22 @@ pub async fn connect(
         let _ = headers.remove(k);
         headers.append(k, v.clone());
     }
+    headers.insert(CLIENT_VERSION_HEADER, client_version_header());
+    client.add_sticky_session(headers);
 
     if let Some(auth) = &client_cfg.http_upgrade_credentials {
         let _ = headers.remove(AUTHORIZATION);
         headers.append(AUTHORIZATION, auth.clone());
     }
 
+    if let Some(token_cache) = &client_cfg.oidc_token_cache {
+        let _ = headers.remove(AUTHORIZATION);
+        headers.append(
+            AUTHORIZATION,
+            oidc::bearer_from_cache(token_cache, &client_cfg.http_client_config()).await?,
+        );
+    }
+
     if let Some(headers_file_path) = &client_cfg.http_headers_file {
         let (host, headers_file) = headers_from_file(headers_file_path);
         for (k, v) in headers_file {
@@ -291,6 +520,25 @@ pub async fn connect(
         }
     }
 
+    let scheme = if client_cfg.remote_addr.tls().is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let origin = host.to_str().ok().map(|host| format!("{scheme}://{host}"));
+    client_cfg
+        .camouflage
+        .add_browser_headers(headers, origin.as_deref(), true);
+
+    if let Some(psk_proof) = &psk_proof {
+        headers.insert(PSK_HEADER, HeaderValue::from_str(psk_proof)?);
+    }
+    if let Some(early_data) = early_data::encode(early_data) {
+        headers.insert(EARLY_DATA_HEADER, early_data);
+    }
+    client.add_probe_interval(headers, dest_addr);
+    client.add_integrity_interval(headers, dest_addr);
+
     let req = req.body(Empty::<Bytes>::new()).with_context(|| {
         format!(
             "failed to build HTTP request to contact the server {:?}",
@@ -299,11 +547,29 @@ pub async fn connect(
     })?;
     debug!("with HTTP upgrade request {req:?}");
     let transport = pooled_cnx.deref_mut().take().unwrap();
-    let (ws, response) = fastwebsockets::handshake::client(&TokioExecutor::new(), req, transport)
+    client.mark_transport(&transport);
+    let (ws, response) = handshake(client, req, transport)
         .await
         .with_context(|| format!("failed to do websocket handshake with the server {:?}", client_cfg.remote_addr))?;
+    if let (Some(psk), Some(psk_proof)) = (&client_cfg.psk, &psk_proof) {
+        let server_proof = response
+            .headers()
+            .get(PSK_HEADER)
+            .and_then(|header| header.to_str().ok())
+            .unwrap_or_default();
+        psk.verify_server_proof(psk_proof, server_proof)?;
+    }
 
-    let (ws_rx, ws_tx) = mk_websocket_tunnel(ws, Role::Client, client_cfg.websocket_mask_frame)?;
+    let (ws_rx, ws_tx) = mk_websocket_tunnel(
+        ws,
+        Role::Client,
+        client_cfg.websocket_mask_frame,
+        client_cfg.websocket_max_frame_size,
+        peer_max_frame_size(response.headers()),
+    )?;
+    let ws_tx = ws_tx
+        .with_stats_of(Side::Client, request_id.to_string())
+        .with_close(client_cfg.websocket_close_timeout, client_cfg.websocket_close_codes);
     Ok((ws_rx, ws_tx, response.into_parts().0))
 }
 
@@ -311,6 +577,8 @@ pub fn mk_websocket_tunnel(
     ws: WebSocket<TokioIo<Upgraded>>,
     role: Role,
     mask_frame: bool,
+    max_frame_size: usize,
+    peer_max_frame_size: Option<usize>,
 ) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite)> {
     let mut ws = match role {
         Role::Client => {
@@ -324,7 +592,7 @@ pub fn mk_websocket_tunnel(
         }
         Role::Server => {
             let upgraded = ws.into_inner().into_inner();
-            match upgraded.downcast::<TokioIo<TlsStream<TcpStream>>>() {
+            match upgraded.downcast::<TokioIo<ServerTlsStream>>() {
                 Ok(stream) => {
                     let transport = TransportStream::from_server_tls(stream.io.into_inner(), stream.read_buf);
                     WebSocket::after_handshake(transport, role)
@@ -342,8 +610,50 @@ pub fn mk_websocket_tunnel(
     ws.set_auto_pong(false);
     ws.set_auto_close(false);
     ws.set_auto_apply_mask(mask_frame);
+    let (read_max_frame_size, write_max_frame_size) = frame_size_limits(max_frame_size, peer_max_frame_size);
+    // fastwebsockets rejects frames whose payload is equal or bigger than the max message size
+    ws.set_max_message_size(read_max_frame_size.saturating_add(1));
     let (ws_rx, ws_tx) = ws.split(|x| x.into_split());
+    debug!(
+        "websocket frames limited to {read_max_frame_size} bytes for reading and {write_max_frame_size} for writing"
+    );
 
     let (ws_rx, pending_ops) = WebsocketTunnelRead::new(ws_rx);
-    Ok((ws_rx, WebsocketTunnelWrite::new(ws_tx, pending_ops)))
+    Ok((ws_rx, WebsocketTunnelWrite::new(ws_tx, write_max_frame_size, pending_ops)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_size_negotiation() {
+        let headers = |max_frame_size: Option<usize>| {
+            let mut headers = HeaderMap::new();
+            if let Some(max_frame_size) = max_frame_size {
+                headers.insert(MAX_FRAME_SIZE_HEADER, max_frame_size_header(max_frame_size));
+            }
+            headers
+        };
+
+        // Both sides advertise, each one sends what the other accepts
+        let client = 64 * 1024;
+        let server = DEFAULT_MAX_FRAME_SIZE;
+        assert_eq!(
+            frame_size_limits(client, peer_max_frame_size(&headers(Some(server)))),
+            (client, client)
+        );
+        assert_eq!(
+            frame_size_limits(server, peer_max_frame_size(&headers(Some(client)))),
+            (server, client)
+        );
+
+        // An older peer does not advertise and keeps sending up to the default of fastwebsockets
+        assert_eq!(peer_max_frame_size(&headers(None)), None);
+        assert_eq!(frame_size_limits(client, None), (LEGACY_MAX_FRAME_SIZE, client));
+        assert_eq!(
+            frame_size_limits(2 * LEGACY_MAX_FRAME_SIZE, None),
+            (2 * LEGACY_MAX_FRAME_SIZE, LEGACY_MAX_FRAME_SIZE)
+        );
+    }
 }